@@ -0,0 +1,35 @@
+// Build script for CloudNexus native
+//
+// With the gen-header feature, generates the C header for the Flutter FFI
+// bindings from the Rust sources into OUT_DIR/cloudnexus_native.h.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "gen-header")]
+    generate_header();
+}
+
+#[cfg(feature = "gen-header")]
+fn generate_header() {
+    use std::path::PathBuf;
+
+    let crate_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR not set"));
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").expect("OUT_DIR not set"));
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+        .expect("failed to read cbindgen.toml");
+    let header = out_dir.join("cloudnexus_native.h");
+
+    // Parse from lib.rs rather than the crate so no `cargo metadata` run is needed
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(crate_dir.join("src").join("lib.rs"))
+        .generate()
+        .expect("failed to generate C header")
+        .write_to_file(&header);
+
+    println!("cargo:rustc-env=CLOUDNEXUS_NATIVE_HEADER={}", header.display());
+}
@@ -0,0 +1,367 @@
+// C ABI export registry for CloudNexus
+// The Dart bindings are maintained by hand against the C header, so every
+// exported function is listed in FFI_EXPORTS by module. The tests fail when a
+// #[no_mangle] function is missing from the list, and naming a function that
+// no longer exists fails to compile. `cargo build --features gen-header`
+// writes the generated header to OUT_DIR/cloudnexus_native.h (see build.rs).
+
+/// Declare the exported functions of each module
+///
+/// Platform-specific exports take their #[cfg] attribute; FFI_EXPORTS still
+/// lists them on every platform, matching the generated header.
+macro_rules! ffi_exports {
+    ($($($module:ident)::+ => [$($(#[$attr:meta])* $name:ident),* $(,)?]),* $(,)?) => {
+        /// Names of all exported C functions, in module order
+        pub const FFI_EXPORTS: &[&str] = &[$($(stringify!($name)),*),*];
+
+        /// (name, module, address) of the exports built for this platform
+        #[cfg(test)]
+        #[allow(deprecated)]
+        fn registered_exports() -> Vec<(&'static str, &'static str, usize)> {
+            let mut exports = Vec::new();
+            $({
+                #[allow(unused_imports)]
+                use $($module)::+ as module;
+                #[allow(unused_variables)]
+                let module_path = stringify!($($module)::+);
+                $(
+                    $(#[$attr])*
+                    exports.push((stringify!($name), module_path, module::$name as *const () as usize));
+                )*
+            })*
+            exports
+        }
+    };
+}
+
+ffi_exports! {
+    crate => [
+        encrypt_data, decrypt_data, encrypt_file_with_fek, decrypt_file_with_fek,
+        derive_key_from_password, free_buffer, encrypt_file_streaming, encrypt_file_streaming_ex,
+        encrypt_file_streaming_cb, decrypt_file_streaming, decrypt_file_streaming_ex,
+        decrypt_file_streaming_cb, encrypt_file_set_chunk_crc, verify_container_crc, encrypt_file,
+        decrypt_file, encrypt_file_init, encrypt_file_init_ex, encrypt_chunk,
+        encrypt_file_get_wrapped_fek, encrypt_file_get_preamble, encrypt_file_finalize,
+        decrypt_file_init, decrypt_chunk, decrypt_chunk_v2, decrypt_chunk_strict,
+        decryption_context_expected_index, decryption_context_reset_index, decrypt_file_finalize,
+    ],
+    crate::archive => [
+        zip_folder, zip_folder_encrypted, unzip_to_folder, list_zip_contents,
+    ],
+    crate::codec => [
+        base64_encode, base64_encode_into, base64_encoded_len, base64_decode, hex_encode,
+        hex_decode,
+    ],
+    crate::container_debug => [
+        debug_container_layout, debug_container_layout_data,
+    ],
+    crate::container_metadata => [
+        encrypt_file_set_metadata_json, read_encrypted_metadata_json, read_encrypted_metadata_json_data,
+    ],
+    crate::container_split => [
+        split_encrypted_file, join_encrypted_parts,
+    ],
+    crate::copy => [
+        copy_file_streaming, copy_file_streaming_with_options, copy_file,
+        folder_copy_init, folder_copy_init_with_options, folder_copy_next_file,
+        folder_copy_finalize, folder_copy_set_continue_on_error, folder_copy_set_keep_partial,
+        folder_copy_set_max_depth, folder_copy_set_recount_on_drift,
+        folder_copy_set_replace_invalid_names, folder_copy_set_sort_locale,
+        folder_copy_set_xattr_policy, folder_copy_set_rename_case_collisions,
+        folder_copy_set_dest_case_insensitive, folder_copy_get_depth_error_path,
+        folder_copy_set_manifest_spill_threshold, folder_copy_get_manifest_json,
+        folder_copy_set_use_trash, folder_copy_get_trash_manifest, folder_copy_set_allow_reflink,
+        folder_copy_set_use_event_stream, folder_copy_get_reflink_count, folder_copy_free,
+        folder_copy_get_operation_id, folder_copy_get_progress, copy_get_progress, create_directory,
+        path_exists, get_file_size, chunked_copy_init, chunked_copy_open_source,
+        chunked_copy_read_chunk, chunked_copy_write_chunk, chunked_copy_seek_dest,
+        chunked_copy_get_dest_offset, chunked_copy_reset, chunked_copy_set_rate_limit,
+        chunked_copy_set_keep_partial, chunked_copy_set_use_event_stream, chunked_copy_flush,
+        chunked_copy_flush_ex, chunked_copy_get_bytes_durable, chunked_copy_finalize,
+        chunked_copy_free, chunked_copy_abort, chunked_copy_get_operation_id,
+        chunked_copy_get_progress, chunked_copy_get_progress_ex, cloud_copy_init,
+        cloud_copy_process_chunk, cloud_copy_finalize, cloud_copy_free, cloud_copy_get_operation_id,
+        cloud_copy_set_use_event_stream, cloud_copy_get_progress,
+        #[cfg(unix)] copy_file_to_fd,
+        #[cfg(windows)] copy_file_to_handle,
+    ],
+    crate::dedup => [
+        chunk_reference_fingerprint, hash_file,
+    ],
+    crate::download => [
+        download_init, download_init_v2, download_init_with_size, download_append_chunk,
+        download_append_chunk_phased, download_append_decrypted, download_finalize,
+        download_finalize_with_progress, download_free, download_abort, download_get_bytes_written,
+        download_get_bytes_durable, download_flush, download_get_total_bytes,
+        download_get_operation_id, download_set_total_bytes, download_set_rate_limit,
+        download_set_keep_partial, download_get_partial_path, download_set_quarantine_dir,
+        download_get_failed_chunk_index, download_set_use_event_stream,
+        #[cfg(unix)] download_init_fd,
+        #[cfg(windows)] download_init_handle,
+    ],
+    crate::encrypt_copy => [
+        encrypt_copy_file, encrypt_copy_file_phased, encrypt_copy_folder, decrypt_copy_file,
+        #[cfg(unix)] encrypt_stream_fd,
+        #[cfg(unix)] decrypt_stream_fd,
+        #[cfg(windows)] encrypt_stream_handle,
+        #[cfg(windows)] decrypt_stream_handle,
+        decrypt_copy_folder,
+    ],
+    crate::errors => [
+        error_code_name, error_code_is_retryable, error_codes_revision,
+    ],
+    crate::escrow => [
+        recovery_key_generate, decrypt_file_init_with_recovery,
+    ],
+    crate::ffi_util => [
+        get_last_error_json, clear_last_error,
+    ],
+    crate::file_io => [
+        sanitize_path_json, get_available_space, is_filesystem_case_sensitive,
+    ],
+    crate::folder_upload => [
+        folder_upload_init, folder_upload_next_file, folder_upload_get_header,
+        folder_upload_process_chunk, folder_upload_get_progress, folder_upload_finalize,
+        folder_upload_free,
+    ],
+    crate::governor => [
+        set_speed_governor,
+    ],
+    crate::metadata_strip => [
+        strip_image_metadata,
+    ],
+    crate::metrics => [
+        metrics_snapshot_json, metrics_reset,
+    ],
+    crate::operations => [
+        list_active_operations_json, get_operation_progress, cancel_operation,
+    ],
+    crate::phase_progress => [
+        phase_aggregator_new, phase_aggregator_update, phase_aggregator_free,
+    ],
+    crate::progress_events => [
+        drain_progress_events_json,
+    ],
+    crate::quarantine => [
+        list_quarantine_json,
+    ],
+    crate::rng => [
+        #[cfg(feature = "insecure-test-rng")] set_insecure_test_rng,
+        #[cfg(feature = "insecure-test-rng")] clear_insecure_test_rng,
+    ],
+    crate::runtime => [
+        native_runtime_configure, native_runtime_configure_ex, native_runtime_stats_json,
+        native_runtime_shutdown, native_trim_memory,
+    ],
+    crate::scan => [
+        scan_folder_init, scan_folder_init_with_options, scan_folder_get_json,
+        scan_folder_get_json_v2, scan_folder_get_error, scan_folder_is_success,
+        scan_folder_get_file_count, scan_folder_get_folder_count, scan_folder_get_total_size,
+        scan_folder_get_duration_ms, scan_folder_get_item_count, scan_folder_is_spilled,
+        scan_folder_read_items, scan_folder_free_string, scan_folder_free, scan_folder_quick,
+        scan_folder_into_index, add_scan_result_to_index, add_scan_result_to_index_ex,
+        scan_folder_query, list_directory,
+    ],
+    crate::search => [
+        create_search_index, free_search_index, add_document_to_index, add_documents_batch,
+        add_documents_json, search_index, search_index_prefix, search_index_by_account,
+        search_index_ids, search_index_prefix_ids, search_index_by_account_ids,
+        search_index_multi_json, search_index_multi_json_ex, get_documents_by_ordinals_json,
+        free_search_results, get_index_count, clear_search_index, mark_index_document_deleted,
+        rename_document_in_index, move_document_in_index, purge_index_tombstones,
+        remove_account_from_index, get_tombstone_count, set_query_cache_size, reserve_search_index,
+        get_index_stats, set_term_boosts_json, explain_search_score_json,
+        explain_search_score_with_profile_json, find_duplicate_names_json, export_index_diagnostics,
+        fuzzy_match_strings, similarity_score, levenshtein, soundex_code, metaphone_code,
+        phonetic_codes_batch, free_c_string, build_path, build_path_ex, get_children_json,
+        get_ancestors_json, export_documents_json, create_batch_indexer, free_batch_indexer,
+        batch_indexer_commit, create_incremental_indexer, free_incremental_indexer,
+        incremental_indexer_mark_dirty, incremental_indexer_get_pending_count,
+        create_suggestion_engine, free_suggestion_engine, suggestion_engine_add_suggestion,
+        suggestion_engine_record_usage, suggestion_engine_get_suggestions,
+        suggestion_engine_get_suggestions_json, suggestion_engine_rebuild_from_index,
+        free_suggestion_results, suggestion_engine_set_max_persisted, suggestion_engine_export_json,
+        suggestion_engine_import_json, create_search_history, free_search_history,
+        search_history_add, search_history_get_recent, search_history_get_popular_decayed_json,
+        search_history_get_recent_for_scope_json, search_history_get_popular_for_scope_json,
+        search_history_clear,
+    ],
+    crate::temp => [
+        temp_file_create, temp_file_commit, temp_file_discard, temp_sweep,
+    ],
+    crate::thumbnail => [
+        generate_encrypted_thumbnail, decrypt_thumbnail,
+    ],
+    crate::transfer_queue => [
+        transfer_queue_create, transfer_queue_set_progress_callback, transfer_queue_add_copy,
+        transfer_queue_add_folder_copy, transfer_queue_reorder, transfer_queue_pause,
+        transfer_queue_resume, transfer_queue_get_status_json, transfer_queue_free,
+    ],
+    crate::trash => [
+        set_trash_directory, delete_path, restore_trash_operation, purge_trash,
+    ],
+    crate::tree_digest => [
+        folder_tree_digest, folder_tree_digest_ex,
+    ],
+    crate::unified_copy => [
+        unified_copy_init, unified_copy_init_ex, unified_copy_file, unified_copy_file_v2,
+        unified_copy_finalize, unified_copy_set_rate_limit, unified_copy_set_use_event_stream,
+        unified_copy_free, unified_copy_get_progress, unified_copy_get_bytes_copied,
+        unified_copy_get_total_bytes, unified_copy_get_files_processed,
+        unified_copy_get_total_files, unified_copy_get_operation_id,
+    ],
+    crate::upload => [
+        upload_init, upload_init_v2, upload_init_ex, upload_process_chunk,
+        upload_process_chunk_phased, upload_get_header, upload_export_session_json,
+        upload_restore_session_json, upload_finalize, upload_free, upload_get_total_bytes,
+        upload_get_bytes_processed, upload_get_operation_id, upload_set_rate_limit,
+        upload_set_use_mmap, upload_set_chunk_crc, upload_set_strip_metadata,
+        upload_set_tolerate_growth, upload_set_emit_base64, upload_set_output_alignment,
+        upload_flush_aligned, upload_set_use_event_stream,
+        #[cfg(unix)] upload_init_fd,
+        #[cfg(windows)] upload_init_handle,
+    ],
+    crate::vault => [
+        vault_create, calibrate_kdf, vault_open, vault_change_password, vault_export_recovery_code,
+        vault_clear_key, vault_free_recovery_code,
+    ],
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+    use std::mem::{align_of, offset_of, size_of};
+    use std::path::Path;
+
+    /// (module path, function name) of every #[no_mangle] function in `dir`
+    fn scan_no_mangle(dir: &Path, module: &str, found: &mut Vec<(String, String)>) {
+        let mut entries: Vec<_> = std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+        entries.sort();
+        for path in entries {
+            let stem = path.file_stem().unwrap().to_str().unwrap().to_string();
+            let child = if module == "crate" && stem == "lib" || stem == "mod" {
+                module.to_string()
+            } else {
+                format!("{}::{}", module, stem)
+            };
+            if path.is_dir() {
+                scan_no_mangle(&path, &child, found);
+                continue;
+            }
+            if path.extension().and_then(|e| e.to_str()) != Some("rs") {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            let lines: Vec<&str> = source.lines().collect();
+            for (i, line) in lines.iter().enumerate() {
+                if line.trim() != "#[no_mangle]" {
+                    continue;
+                }
+                let name = lines[i + 1..]
+                    .iter()
+                    .take(3)
+                    .find_map(|l| l.split("fn ").nth(1))
+                    .map(|rest| rest.split(|c: char| !(c.is_alphanumeric() || c == '_')).next().unwrap().to_string())
+                    .unwrap_or_else(|| panic!("no fn after #[no_mangle] at {}:{}", path.display(), i + 1));
+                found.push((child.clone(), name));
+            }
+        }
+    }
+
+    fn source_exports() -> Vec<(String, String)> {
+        let mut found = Vec::new();
+        scan_no_mangle(&Path::new(env!("CARGO_MANIFEST_DIR")).join("src"), "crate", &mut found);
+        found
+    }
+
+    #[test]
+    fn test_every_export_is_registered() {
+        let registered: HashSet<&str> = FFI_EXPORTS.iter().copied().collect();
+        assert_eq!(registered.len(), FFI_EXPORTS.len(), "FFI_EXPORTS lists a function twice");
+
+        let found = source_exports();
+        let missing: Vec<&str> = found
+            .iter()
+            .map(|(_, name)| name.as_str())
+            .filter(|name| !registered.contains(name))
+            .collect();
+        assert!(missing.is_empty(), "exported functions missing from FFI_EXPORTS in abi.rs: {:?}", missing);
+
+        let in_source: HashSet<&str> = found.iter().map(|(_, name)| name.as_str()).collect();
+        let stale: Vec<&&str> = FFI_EXPORTS.iter().filter(|name| !in_source.contains(**name)).collect();
+        assert!(stale.is_empty(), "FFI_EXPORTS lists functions that are not #[no_mangle]: {:?}", stale);
+    }
+
+    #[test]
+    fn test_exports_are_registered_under_their_module() {
+        let modules: HashMap<String, String> = source_exports().into_iter().map(|(module, name)| (name, module)).collect();
+        for (name, module, address) in registered_exports() {
+            assert_ne!(address, 0);
+            // Functions of private submodules (search::bridge) are registered
+            // under the parent that re-exports them
+            let module = module.replace(' ', "");
+            let source_module = &modules[name];
+            let parent = source_module.rsplit_once("::").map(|(parent, _)| parent).filter(|parent| *parent != "crate");
+            assert!(*source_module == module || parent == Some(module.as_str()),
+                    "{} is registered under {} but defined in {}", name, module, source_module);
+        }
+    }
+
+    #[cfg(feature = "gen-header")]
+    #[test]
+    fn test_generated_header_declares_every_export() {
+        let header = std::fs::read_to_string(env!("CLOUDNEXUS_NATIVE_HEADER")).unwrap();
+        let declared = |name: &str| [" ", "*"].iter().any(|before| header.contains(&format!("{}{}(", before, name)));
+        let missing: Vec<&&str> = FFI_EXPORTS.iter().filter(|name| !declared(name)).collect();
+        assert!(missing.is_empty(), "generated header does not declare {:?}", missing);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_search_struct_layouts_match_golden_values() {
+        use crate::search::{CSearchDocument, CSearchResult};
+
+        // Read field by field by the Dart ffi.Struct mirrors in rust_search_service.dart
+        assert_eq!((size_of::<CSearchResult>(), align_of::<CSearchResult>()), (40, 8));
+        assert_eq!(
+            [offset_of!(CSearchResult, node_id), offset_of!(CSearchResult, name), offset_of!(CSearchResult, score),
+             offset_of!(CSearchResult, account_id), offset_of!(CSearchResult, provider)],
+            [0, 8, 16, 24, 32]
+        );
+
+        assert_eq!((size_of::<CSearchDocument>(), align_of::<CSearchDocument>()), (56, 8));
+        assert_eq!(
+            [offset_of!(CSearchDocument, node_id), offset_of!(CSearchDocument, account_id),
+             offset_of!(CSearchDocument, provider), offset_of!(CSearchDocument, email),
+             offset_of!(CSearchDocument, name), offset_of!(CSearchDocument, is_folder),
+             offset_of!(CSearchDocument, parent_id)],
+            [0, 8, 16, 24, 32, 40, 48]
+        );
+        assert_eq!(size_of::<bool>(), 1, "is_folder is read as a Uint8");
+    }
+
+    /// Contexts are opaque to Dart, but their layout only changes when a
+    /// field is added or removed; update the golden values deliberately
+    #[cfg(all(unix, target_pointer_width = "64"))]
+    #[test]
+    fn test_context_layouts_match_golden_values() {
+        macro_rules! assert_layout {
+            ($($ty:path => ($size:expr, $align:expr)),* $(,)?) => {
+                $(assert_eq!((size_of::<$ty>(), align_of::<$ty>()), ($size, $align), "layout of {}", stringify!($ty));)*
+            };
+        }
+
+        assert_layout! {
+            crate::EncryptionContext => (80, 8),
+            crate::DecryptionContext => (32, 8),
+            crate::upload::UploadContext => (400, 8),
+            crate::download::DownloadContext => (408, 8),
+            crate::copy::CopyContext => (96, 8),
+            crate::copy::FolderCopyContext => (944, 8),
+            crate::copy::ChunkedCopyContext => (312, 8),
+            crate::copy::CloudCopyContext => (112, 8),
+            crate::unified_copy::UnifiedCopyContext => (136, 8),
+        }
+    }
+}
@@ -0,0 +1,864 @@
+/// ZIP archive support for CloudNexus
+/// Streams a local folder into a ZIP archive (optionally wrapped in the standard
+/// encrypted container), extracts archives back to disk with zip-slip protection
+/// and lists their contents from the central directory without extracting
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_void, CString};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::AtomicBool;
+
+use serde::Serialize;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::errors::checked;
+use crate::copy::CopyProgressCallback;
+use crate::file_io::{ProgressThrottler, ERROR_NULL_POINTER, ERROR_FILE_NOT_FOUND, ERROR_INVALID_PATH,
+                     ERROR_IO_FAILED, ERROR_CANCELLED, SUCCESS, c_str_to_path, is_cancelled,
+                     sanitize_relative_path, cleanup_partial_output, PartialOutputGuard};
+use crate::ffi_util::{json_envelope, ErrorEnvelope};
+use crate::scan::scan_folder_sync;
+use crate::temp::{create_temp_file_for, commit_temp_file};
+use crate::{encrypt_chunk_impl, encrypt_file_init, encrypt_file_finalize, DEFAULT_CHUNK_SIZE, KEY_SIZE};
+
+pub use crate::errors::{
+    ERROR_UNSAFE_ARCHIVE_ENTRY,
+    ERROR_INVALID_ARCHIVE,
+    ERROR_ARCHIVE_ENCRYPTION_FAILED,
+};
+
+/// Buffer size used when streaming file contents into or out of an archive
+const ARCHIVE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Most entries listed from one archive; larger central directories are refused
+pub const MAX_LISTED_ARCHIVE_ENTRIES: usize = 100_000;
+
+/// Local file header and empty-archive signatures a ZIP file starts with
+const ZIP_SIGNATURES: [[u8; 4]; 2] = [*b"PK\x03\x04", *b"PK\x05\x06"];
+
+/// Size of the end of central directory record, without its comment
+const EOCD_SIZE: usize = 22;
+
+/// Size of the ZIP64 end of central directory locator preceding that record
+const ZIP64_LOCATOR_SIZE: usize = 20;
+
+/// Size of the fixed part of the ZIP64 end of central directory record
+const ZIP64_EOCD_SIZE: usize = 56;
+
+/// A file that could not be added to an archive
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveEntryError {
+    pub path: String,
+    pub error: String,
+}
+
+/// Writer that encrypts everything written to it into the standard CNER container
+///
+/// Plaintext is buffered up to one chunk (DEFAULT_CHUNK_SIZE) and each full chunk
+/// is written out as an encrypted chunk record, so memory use stays bounded.
+struct EncryptingWriter<W: Write> {
+    inner: W,
+    context: *mut crate::EncryptionContext,
+    buffer: Vec<u8>,
+    chunk_index: u32,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    fn new(mut inner: W, master_key: &[u8]) -> io::Result<Self> {
+        let mut header_len = 0usize;
+        let context = encrypt_file_init(master_key.as_ptr(), master_key.len(), &mut header_len);
+        if context.is_null() {
+            return Err(io::Error::other("failed to initialize encryption"));
+        }
+
+        let written = inner.write_all(&unsafe { &*context }.preamble());
+        if let Err(e) = written {
+            encrypt_file_finalize(context);
+            return Err(e);
+        }
+
+        Ok(Self {
+            inner,
+            context,
+            buffer: Vec::with_capacity(DEFAULT_CHUNK_SIZE),
+            chunk_index: 0,
+        })
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        let ctx = unsafe { &*self.context };
+        let record = encrypt_chunk_impl(&self.buffer, &ctx.fek, self.chunk_index, ctx.chunk_crc)
+            .ok_or_else(|| io::Error::other("chunk encryption failed"))?;
+        self.inner.write_all(&record)?;
+        self.buffer.clear();
+        self.chunk_index += 1;
+        Ok(())
+    }
+
+    /// Encrypt any buffered tail and release the encryption context
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.write_chunk()?;
+        }
+        self.inner.flush()?;
+
+        encrypt_file_finalize(self.context);
+        self.context = ptr::null_mut();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let space = DEFAULT_CHUNK_SIZE - self.buffer.len();
+        let take = space.min(buf.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+        if self.buffer.len() == DEFAULT_CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Partial chunks are only emitted by finish(), flushing must not split chunks
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for EncryptingWriter<W> {
+    fn drop(&mut self) {
+        if !self.context.is_null() {
+            encrypt_file_finalize(self.context);
+        }
+    }
+}
+
+/// Build the file options for a compression level
+///
+/// 0 stores entries uncompressed, 1-9 deflate at that level, anything else uses
+/// the default deflate level
+fn zip_options(compression_level: i32) -> SimpleFileOptions {
+    let options = SimpleFileOptions::default().large_file(true);
+    match compression_level {
+        0 => options.compression_method(CompressionMethod::Stored),
+        1..=9 => options
+            .compression_method(CompressionMethod::Deflated)
+            .compression_level(Some(compression_level as i64)),
+        _ => options.compression_method(CompressionMethod::Deflated),
+    }
+}
+
+/// Stream the contents of a folder into a ZIP archive written to `output`
+///
+/// Entries are stored with forward-slash relative paths. Files that cannot be
+/// opened are skipped and recorded in `errors`.
+fn zip_folder_to<W: Write>(
+    source: &Path,
+    output: W,
+    compression_level: i32,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+    errors: &mut Vec<ArchiveEntryError>,
+) -> Result<W, i32> {
+    let source_str = source.to_str().ok_or(ERROR_INVALID_PATH)?;
+    let scan = scan_folder_sync(source_str, None).map_err(|_| ERROR_FILE_NOT_FOUND)?;
+
+    let mut items = scan.items;
+    items.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+
+    let total_bytes = scan.total_size as usize;
+    let total_files = scan.file_count as usize;
+    let options = zip_options(compression_level);
+
+    let mut writer = ZipWriter::new_stream(output);
+    let mut buffer = vec![0u8; ARCHIVE_BUFFER_SIZE];
+    let mut bytes_processed = 0usize;
+    let mut files_processed = 0usize;
+    let mut throttler = ProgressThrottler::new(500);
+
+    for item in &items {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err(ERROR_CANCELLED);
+        }
+
+        if item.is_folder {
+            writer.add_directory(format!("{}/", item.relative_path), options)
+                .map_err(|_| ERROR_IO_FAILED)?;
+            continue;
+        }
+
+        let mut file = match File::open(&item.absolute_path) {
+            Ok(f) => f,
+            Err(e) => {
+                errors.push(ArchiveEntryError {
+                    path: item.relative_path.clone(),
+                    error: e.to_string(),
+                });
+                files_processed += 1;
+                continue;
+            }
+        };
+
+        writer.start_file(item.relative_path.as_str(), options)
+            .map_err(|_| ERROR_IO_FAILED)?;
+
+        loop {
+            if unsafe { is_cancelled(cancel_flag) } {
+                return Err(ERROR_CANCELLED);
+            }
+
+            let n = file.read(&mut buffer).map_err(|_| ERROR_IO_FAILED)?;
+            if n == 0 {
+                break;
+            }
+
+            writer.write_all(&buffer[..n]).map_err(|_| ERROR_IO_FAILED)?;
+            bytes_processed += n;
+
+            if let Some(callback) = progress_callback {
+                if throttler.should_update(bytes_processed, total_bytes) {
+                    callback(bytes_processed, total_bytes, files_processed, total_files, user_data);
+                }
+            }
+        }
+
+        files_processed += 1;
+    }
+
+    let output = writer.finish().map_err(|_| ERROR_IO_FAILED)?.into_inner();
+
+    if let Some(callback) = progress_callback {
+        if throttler.finish() {
+            callback(bytes_processed, total_bytes, files_processed, total_files, user_data);
+        }
+    }
+
+    Ok(output)
+}
+
+/// Store the skipped-file list as a JSON string in `errors_out`, if requested
+fn write_errors_out(errors: &[ArchiveEntryError], errors_out: *mut *mut c_char) {
+    if errors_out.is_null() {
+        return;
+    }
+
+    let json = serde_json::to_string(errors).unwrap_or_else(|_| "[]".to_string());
+    unsafe {
+        *errors_out = match CString::new(json) {
+            Ok(s) => s.into_raw(),
+            Err(_) => ptr::null_mut(),
+        };
+    }
+}
+
+/// Create a ZIP archive from a folder
+///
+/// # Arguments
+/// * `source_folder` - Path to the folder to archive
+/// * `dest_zip_path` - Path of the ZIP file to create
+/// * `compression_level` - 0 to store, 1-9 for deflate level, negative for default
+/// * `progress_callback` - Optional progress callback (can be null)
+/// * `cancel_flag` - Pointer to atomic bool for cancellation (can be null)
+/// * `user_data` - User data to pass to progress callback
+/// * `errors_out` - Optional pointer receiving a JSON array of skipped files as
+///   `[{"path": ..., "error": ...}]` (caller must free with scan_folder_free_string)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[no_mangle]
+pub extern "C" fn zip_folder(
+    source_folder: *const c_char,
+    dest_zip_path: *const c_char,
+    compression_level: i32,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+    errors_out: *mut *mut c_char,
+) -> i32 {
+    checked(zip_folder_impl(source_folder, dest_zip_path, compression_level, None,
+                            progress_callback, cancel_flag, user_data, errors_out))
+}
+
+/// Create an encrypted ZIP archive from a folder
+///
+/// The ZIP stream is piped through the chunked encryptor, so the output is a
+/// standard CNER container whose plaintext is a ZIP archive.
+///
+/// # Arguments
+/// * `source_folder` - Path to the folder to archive
+/// * `dest_path` - Path of the encrypted archive to create
+/// * `compression_level` - 0 to store, 1-9 for deflate level, negative for default
+/// * `progress_callback` - Optional progress callback (can be null)
+/// * `cancel_flag` - Pointer to atomic bool for cancellation (can be null)
+/// * `user_data` - User data to pass to progress callback
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `errors_out` - Optional pointer receiving a JSON array of skipped files
+///   (caller must free with scan_folder_free_string)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn zip_folder_encrypted(
+    source_folder: *const c_char,
+    dest_path: *const c_char,
+    compression_level: i32,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+    master_key: *const u8,
+    master_key_len: usize,
+    errors_out: *mut *mut c_char,
+) -> i32 {
+    if master_key.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    if master_key_len != KEY_SIZE {
+        return ERROR_ARCHIVE_ENCRYPTION_FAILED;
+    }
+
+    let key = unsafe { std::slice::from_raw_parts(master_key, master_key_len) };
+    checked(zip_folder_impl(source_folder, dest_path, compression_level, Some(key),
+                            progress_callback, cancel_flag, user_data, errors_out))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn zip_folder_impl(
+    source_folder: *const c_char,
+    dest_path: *const c_char,
+    compression_level: i32,
+    master_key: Option<&[u8]>,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+    errors_out: *mut *mut c_char,
+) -> i32 {
+    if !errors_out.is_null() {
+        unsafe { *errors_out = ptr::null_mut(); }
+    }
+
+    let source = match unsafe { c_str_to_path(source_folder) } {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    let dest = match unsafe { c_str_to_path(dest_path) } {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+
+    if !source.is_dir() {
+        return ERROR_FILE_NOT_FOUND;
+    }
+
+    // Build the archive in a temp file so a partial archive never appears at dest
+    let (temp_path, file) = match create_temp_file_for(&dest) {
+        Ok((path, f)) => (path, BufWriter::new(f)),
+        Err(_) => return ERROR_IO_FAILED,
+    };
+
+    let mut errors = Vec::new();
+    let result = match master_key {
+        None => zip_folder_to(&source, file, compression_level, progress_callback,
+                              cancel_flag, user_data, &mut errors)
+            .and_then(|mut w| w.flush().map_err(|_| ERROR_IO_FAILED)),
+        Some(key) => {
+            let encryptor = match EncryptingWriter::new(file, key) {
+                Ok(w) => w,
+                Err(_) => {
+                    cleanup_partial_output(&temp_path, false);
+                    return ERROR_ARCHIVE_ENCRYPTION_FAILED;
+                }
+            };
+            zip_folder_to(&source, encryptor, compression_level, progress_callback,
+                          cancel_flag, user_data, &mut errors)
+                .and_then(|mut w| w.finish().map_err(|_| ERROR_ARCHIVE_ENCRYPTION_FAILED))
+        }
+    };
+
+    let result = result.and_then(|_| commit_temp_file(&temp_path, &dest).map_err(|_| ERROR_IO_FAILED));
+
+    match result {
+        Ok(()) => {
+            write_errors_out(&errors, errors_out);
+            SUCCESS
+        }
+        // A partial archive is never useful, so it is always deleted
+        Err(code) => {
+            cleanup_partial_output(&temp_path, false);
+            code
+        }
+    }
+}
+
+/// Extract a ZIP archive into `dest`
+///
+/// Every entry name is validated before anything is written, so an archive
+/// containing a single unsafe entry is rejected as a whole.
+fn unzip_from<R: Read + Seek>(
+    reader: R,
+    dest: &Path,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> Result<(), i32> {
+    let mut archive = ZipArchive::new(reader).map_err(|_| ERROR_INVALID_ARCHIVE)?;
+
+    let mut targets = Vec::with_capacity(archive.len());
+    let mut total_bytes = 0usize;
+    let mut total_files = 0usize;
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i).map_err(|_| ERROR_INVALID_ARCHIVE)?;
+        // Reject absolute paths, `..` components and names the platform cannot store (zip-slip)
+        let relative = sanitize_relative_path(entry.name()).map_err(|_| ERROR_UNSAFE_ARCHIVE_ENTRY)?;
+        let target = dest.join(relative);
+        if !entry.is_dir() {
+            total_bytes += entry.size() as usize;
+            total_files += 1;
+        }
+        targets.push(target);
+    }
+
+    fs::create_dir_all(dest).map_err(|_| ERROR_IO_FAILED)?;
+
+    let mut buffer = vec![0u8; ARCHIVE_BUFFER_SIZE];
+    let mut bytes_processed = 0usize;
+    let mut files_processed = 0usize;
+    let mut throttler = ProgressThrottler::new(500);
+
+    for (i, target) in targets.iter().enumerate() {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err(ERROR_CANCELLED);
+        }
+
+        let mut entry = archive.by_index(i).map_err(|_| ERROR_INVALID_ARCHIVE)?;
+
+        if entry.is_dir() {
+            fs::create_dir_all(target).map_err(|_| ERROR_IO_FAILED)?;
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|_| ERROR_IO_FAILED)?;
+        }
+
+        let file = File::create(target).map_err(|_| ERROR_IO_FAILED)?;
+        // Entries extracted before a cancellation are kept; the one in progress is not
+        let partial = PartialOutputGuard::new(target, false);
+        let mut output = BufWriter::new(file);
+        loop {
+            if unsafe { is_cancelled(cancel_flag) } {
+                return Err(ERROR_CANCELLED);
+            }
+
+            let n = entry.read(&mut buffer).map_err(|_| ERROR_INVALID_ARCHIVE)?;
+            if n == 0 {
+                break;
+            }
+
+            output.write_all(&buffer[..n]).map_err(|_| ERROR_IO_FAILED)?;
+            bytes_processed += n;
+
+            if let Some(callback) = progress_callback {
+                if throttler.should_update(bytes_processed, total_bytes) {
+                    callback(bytes_processed, total_bytes, files_processed, total_files, user_data);
+                }
+            }
+        }
+        output.flush().map_err(|_| ERROR_IO_FAILED)?;
+        partial.complete();
+        files_processed += 1;
+    }
+
+    if let Some(callback) = progress_callback {
+        if throttler.finish() {
+            callback(bytes_processed, total_bytes, files_processed, total_files, user_data);
+        }
+    }
+
+    Ok(())
+}
+
+/// Extract a ZIP archive into a folder
+///
+/// Entries with absolute paths or `..` components are rejected before any file
+/// is written.
+///
+/// # Arguments
+/// * `zip_path` - Path to the ZIP archive
+/// * `dest_folder` - Folder to extract into (created if missing)
+/// * `progress_callback` - Optional progress callback (can be null)
+/// * `cancel_flag` - Pointer to atomic bool for cancellation (can be null)
+/// * `user_data` - User data to pass to progress callback
+///
+/// # Returns
+/// 0 on success, ERROR_UNSAFE_ARCHIVE_ENTRY if an entry escapes the destination,
+/// other negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn unzip_to_folder(
+    zip_path: *const c_char,
+    dest_folder: *const c_char,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    let zip_path = match unsafe { c_str_to_path(zip_path) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+    let dest = match unsafe { c_str_to_path(dest_folder) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+
+    let file = match File::open(&zip_path) {
+        Ok(f) => BufReader::new(f),
+        Err(_) => return ERROR_FILE_NOT_FOUND,
+    };
+
+    match unzip_from(file, &dest, progress_callback, cancel_flag, user_data) {
+        Ok(()) => SUCCESS,
+        Err(code) => checked(code),
+    }
+}
+
+/// One entry of a ZIP archive, as read from its central directory
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ZipEntryInfo {
+    /// Path inside the archive ('/' separated, no trailing '/')
+    pub path: String,
+    pub is_folder: bool,
+    /// Uncompressed size in bytes (0 for folders)
+    pub size: u64,
+    /// Size of the stored data in bytes
+    pub compressed_size: u64,
+}
+
+/// Contents of a ZIP archive, as returned by list_zip_contents
+#[derive(Debug, Clone, Serialize)]
+pub struct ZipListing {
+    /// Entries sorted by path; folders implied by entry paths are included
+    pub entries: Vec<ZipEntryInfo>,
+    /// Uncompressed size of all files
+    pub total_size: u64,
+    /// Entries left out because their names are absolute or contain `..`
+    pub skipped_entries: u64,
+}
+
+/// Whether a file starts with a ZIP signature
+pub fn is_zip_file(path: &Path) -> bool {
+    let mut signature = [0u8; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut signature))
+        .is_ok_and(|()| ZIP_SIGNATURES.contains(&signature))
+}
+
+/// Number of entries the end of central directory record of a ZIP archive declares
+///
+/// Only the end of the file is read. ZIP64 archives declare their count in the
+/// ZIP64 record that the locator before the classic record points to.
+fn declared_zip_entries<R: Read + Seek>(reader: &mut R) -> io::Result<u64> {
+    let malformed = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let tail_len = file_len.min((ZIP64_LOCATOR_SIZE + EOCD_SIZE + u16::MAX as usize) as u64);
+    reader.seek(SeekFrom::Start(file_len - tail_len))?;
+    let mut tail = vec![0u8; tail_len as usize];
+    reader.read_exact(&mut tail)?;
+
+    let eocd = (0..tail.len().saturating_sub(EOCD_SIZE - 1))
+        .rev()
+        .find(|&i| tail[i..i + 4] == *b"PK\x05\x06")
+        .ok_or_else(|| malformed("no end of central directory record"))?;
+    let entries = u16::from_le_bytes([tail[eocd + 10], tail[eocd + 11]]);
+    if entries != u16::MAX {
+        return Ok(entries as u64);
+    }
+
+    let locator = match eocd.checked_sub(ZIP64_LOCATOR_SIZE) {
+        Some(locator) if tail[locator..locator + 4] == *b"PK\x06\x07" => locator,
+        // Exactly 65535 entries in a classic archive
+        _ => return Ok(entries as u64),
+    };
+    let record_offset = u64::from_le_bytes(tail[locator + 8..locator + 16].try_into().unwrap());
+    reader.seek(SeekFrom::Start(record_offset))?;
+    let mut record = [0u8; ZIP64_EOCD_SIZE];
+    reader.read_exact(&mut record)?;
+    if record[..4] != *b"PK\x06\x06" {
+        return Err(malformed("no ZIP64 end of central directory record"));
+    }
+    Ok(u64::from_le_bytes(record[32..40].try_into().unwrap()))
+}
+
+/// List the entries of a ZIP archive from its central directory
+///
+/// Nothing is decompressed; memory grows with the number of entries, which is
+/// capped at MAX_LISTED_ARCHIVE_ENTRIES: the count declared at the end of the
+/// file is checked before the central directory is read. Folders that only
+/// appear as parents of entries are listed too, so the listing can be browsed
+/// as a tree.
+pub fn read_zip_listing(path: &Path) -> Result<ZipListing, ErrorEnvelope> {
+    let context = path.to_string_lossy();
+    let file = File::open(path).map_err(|e| {
+        ErrorEnvelope::new(crate::encrypt_copy::io_error_code(&e), format!("Failed to open archive: {}", e))
+            .with_context(context.as_ref())
+    })?;
+    let invalid = |message: String| ErrorEnvelope::new(ERROR_INVALID_ARCHIVE, message).with_context(context.as_ref());
+    let mut reader = BufReader::new(file);
+    // Refuse large archives before the central directory is parsed into memory
+    let declared = declared_zip_entries(&mut reader).map_err(|e| invalid(format!("Invalid ZIP archive: {}", e)))?;
+    if declared > MAX_LISTED_ARCHIVE_ENTRIES as u64 {
+        return Err(invalid(format!("ZIP archive has {} entries, more than the {} that are listed",
+                                   declared, MAX_LISTED_ARCHIVE_ENTRIES)));
+    }
+    let mut archive = ZipArchive::new(reader).map_err(|e| invalid(format!("Invalid ZIP archive: {}", e)))?;
+
+    let mut entries: BTreeMap<String, ZipEntryInfo> = BTreeMap::new();
+    let mut skipped_entries = 0u64;
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i).map_err(|e| invalid(format!("Invalid ZIP entry {}: {}", i, e)))?;
+        let Ok(relative) = sanitize_relative_path(entry.name()) else {
+            skipped_entries += 1;
+            continue;
+        };
+        let entry_path = relative.to_string_lossy().replace('\\', "/");
+        if entry_path.is_empty() {
+            continue;
+        }
+
+        let mut parent = entry_path.as_str();
+        while let Some((folder, _)) = parent.rsplit_once('/') {
+            entries.entry(folder.to_string()).or_insert_with(|| ZipEntryInfo {
+                path: folder.to_string(),
+                is_folder: true,
+                size: 0,
+                compressed_size: 0,
+            });
+            parent = folder;
+        }
+        let is_folder = entry.is_dir();
+        entries.insert(entry_path.clone(), ZipEntryInfo {
+            path: entry_path,
+            is_folder,
+            size: if is_folder { 0 } else { entry.size() },
+            compressed_size: entry.compressed_size(),
+        });
+    }
+
+    let entries: Vec<ZipEntryInfo> = entries.into_values().collect();
+    let total_size = entries.iter().map(|entry| entry.size).sum();
+    Ok(ZipListing { entries, total_size, skipped_entries })
+}
+
+/// List the contents of a ZIP archive without extracting it
+///
+/// Only the central directory is read (see read_zip_listing). Entries whose
+/// names are absolute or contain `..` are left out and counted.
+///
+/// # Arguments
+/// * `zip_path` - Path to the ZIP archive
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope (see ffi_util.rs) whose `data` is {entries, total_size,
+/// skipped_entries}, each entry with path, is_folder, size and compressed_size;
+/// fails with ERROR_INVALID_ARCHIVE for a file that is not a readable ZIP
+/// archive. Caller must free with scan_folder_free_string.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn list_zip_contents(zip_path: *const c_char, out_len: *mut usize) -> *mut c_char {
+    let result = match unsafe { crate::ffi_util::ffi_str_in(zip_path, "zip_path") } {
+        Ok(zip_path) => read_zip_listing(Path::new(zip_path)),
+        Err(e) => Err(e.into()),
+    };
+    json_envelope(result, out_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::slice;
+
+    use crate::test_util::{c_path, temp_dir};
+    use crate::{decrypt_file, free_buffer};
+
+    fn build_tree(root: &Path) {
+        fs::create_dir_all(root.join("docs/nested")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("top.txt"), b"top level").unwrap();
+        fs::write(root.join("docs/readme.md"), b"# readme").unwrap();
+        let big: Vec<u8> = (0..(DEFAULT_CHUNK_SIZE + 4321)).map(|i| (i % 97) as u8).collect();
+        fs::write(root.join("docs/nested/big.bin"), big).unwrap();
+    }
+
+    fn assert_same_tree(expected: &Path, actual: &Path) {
+        let scan = scan_folder_sync(expected.to_str().unwrap(), None).unwrap();
+        for item in scan.items {
+            let other = actual.join(&item.relative_path);
+            if item.is_folder {
+                assert!(other.is_dir(), "missing dir {}", item.relative_path);
+            } else {
+                assert_eq!(fs::read(&item.absolute_path).unwrap(), fs::read(&other).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_zip_round_trip() {
+        let root = temp_dir("zip_round_trip");
+        let source = root.join("source");
+        let restored = root.join("restored");
+        let zip_path = root.join("archive.zip");
+        build_tree(&source);
+
+        let mut errors: *mut c_char = ptr::null_mut();
+        let result = zip_folder(c_path(&source).as_ptr(), c_path(&zip_path).as_ptr(), 6,
+                                None, ptr::null(), ptr::null_mut(), &mut errors);
+        assert_eq!(result, SUCCESS);
+        assert_eq!(unsafe { CString::from_raw(errors) }.to_str().unwrap(), "[]");
+
+        let names: Vec<String> = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap()
+            .file_names().map(|n| n.to_string()).collect();
+        assert!(names.contains(&"docs/nested/big.bin".to_string()));
+
+        let result = unzip_to_folder(c_path(&zip_path).as_ptr(), c_path(&restored).as_ptr(),
+                                     None, ptr::null(), ptr::null_mut());
+        assert_eq!(result, SUCCESS);
+        assert_same_tree(&source, &restored);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_zip_encrypted_round_trip() {
+        let root = temp_dir("zip_encrypted");
+        let source = root.join("source");
+        let restored = root.join("restored");
+        let archive_path = root.join("archive.zip.enc");
+        build_tree(&source);
+
+        let key = [3u8; 32];
+        let result = zip_folder_encrypted(c_path(&source).as_ptr(), c_path(&archive_path).as_ptr(), -1,
+                                          None, ptr::null(), ptr::null_mut(),
+                                          key.as_ptr(), key.len(), ptr::null_mut());
+        assert_eq!(result, SUCCESS);
+
+        let encrypted = fs::read(&archive_path).unwrap();
+        let mut zip_len = 0usize;
+        let zip_ptr = decrypt_file(encrypted.as_ptr(), encrypted.len(), key.as_ptr(), 32, &mut zip_len);
+        assert!(!zip_ptr.is_null());
+        let zip_bytes = unsafe { slice::from_raw_parts(zip_ptr, zip_len) }.to_vec();
+        free_buffer(zip_ptr);
+
+        unzip_from(Cursor::new(zip_bytes), &restored, None, ptr::null(), ptr::null_mut()).unwrap();
+        assert_same_tree(&source, &restored);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_unzip_rejects_parent_traversal() {
+        let root = temp_dir("zip_slip");
+        let zip_path = root.join("evil.zip");
+        let dest = root.join("dest");
+
+        let mut writer = ZipWriter::new(File::create(&zip_path).unwrap());
+        writer.start_file("safe.txt", SimpleFileOptions::default()).unwrap();
+        writer.write_all(b"fine").unwrap();
+        writer.start_file("../evil", SimpleFileOptions::default()).unwrap();
+        writer.write_all(b"pwned").unwrap();
+        writer.finish().unwrap();
+
+        let result = unzip_to_folder(c_path(&zip_path).as_ptr(), c_path(&dest).as_ptr(),
+                                     None, ptr::null(), ptr::null_mut());
+        assert_eq!(result, ERROR_UNSAFE_ARCHIVE_ENTRY);
+        assert!(!root.join("evil").exists());
+        assert!(!dest.join("safe.txt").exists());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_list_zip_contents_reads_central_directory() {
+        let root = temp_dir("zip_listing");
+        let zip_path = root.join("archive.zip");
+        let mut writer = ZipWriter::new(File::create(&zip_path).unwrap());
+        writer.start_file("a/b/deep.txt", zip_options(9)).unwrap();
+        writer.write_all(&[b'z'; 10_000]).unwrap();
+        writer.start_file("../evil", SimpleFileOptions::default()).unwrap();
+        writer.write_all(b"pwned").unwrap();
+        writer.finish().unwrap();
+        assert!(is_zip_file(&zip_path));
+
+        let mut len = 0usize;
+        let json = list_zip_contents(c_path(&zip_path).as_ptr(), &mut len);
+        let envelope: serde_json::Value = serde_json::from_str(&unsafe { CString::from_raw(json) }.into_string().unwrap()).unwrap();
+        assert_eq!(envelope["ok"], true);
+        let data = &envelope["data"];
+        let paths: Vec<&str> = data["entries"].as_array().unwrap().iter().map(|e| e["path"].as_str().unwrap()).collect();
+        assert_eq!(paths, ["a", "a/b", "a/b/deep.txt"]);
+        assert_eq!(data["entries"][2]["size"], 10_000);
+        assert!(data["entries"][2]["compressed_size"].as_u64().unwrap() < 10_000);
+        assert_eq!((data["total_size"].as_u64(), data["skipped_entries"].as_u64()), (Some(10_000), Some(1)));
+        // Listing extracts nothing
+        assert!(!root.join("evil").exists() && !root.join("a").exists());
+
+        let corrupt = root.join("corrupt.zip");
+        fs::write(&corrupt, b"PK\x03\x04 truncated").unwrap();
+        let json = list_zip_contents(c_path(&corrupt).as_ptr(), &mut len);
+        let envelope: serde_json::Value = serde_json::from_str(&unsafe { CString::from_raw(json) }.into_string().unwrap()).unwrap();
+        assert_eq!(envelope["ok"], false);
+        assert_eq!(envelope["error"]["code"], ERROR_INVALID_ARCHIVE);
+        assert!(!is_zip_file(&root.join("missing.zip")));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_zip_listing_refuses_large_declared_entry_counts() {
+        let root = temp_dir("zip_declared_entries");
+
+        // A ZIP64 end of central directory declaring more entries than are listed,
+        // with no central directory behind it
+        let declared = MAX_LISTED_ARCHIVE_ENTRIES as u64 + 1;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"PK\x06\x06");
+        bytes.extend_from_slice(&(ZIP64_EOCD_SIZE as u64 - 12).to_le_bytes());
+        bytes.extend_from_slice(&[45, 0, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&declared.to_le_bytes());
+        bytes.extend_from_slice(&declared.to_le_bytes());
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        bytes.extend_from_slice(b"PK\x06\x07");
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(b"PK\x05\x06");
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0]);
+        let huge = root.join("huge.zip");
+        fs::write(&huge, &bytes).unwrap();
+        let error = read_zip_listing(&huge).unwrap_err();
+        assert_eq!(error.code, ERROR_INVALID_ARCHIVE);
+        assert!(error.message.contains(&format!("{} entries", declared)), "{}", error.message);
+
+        // Ordinary archives declare their real count
+        let small = root.join("small.zip");
+        let mut writer = ZipWriter::new(File::create(&small).unwrap());
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            writer.start_file(name, SimpleFileOptions::default()).unwrap();
+        }
+        writer.set_comment("trailing comment");
+        writer.finish().unwrap();
+        assert_eq!(declared_zip_entries(&mut File::open(&small).unwrap()).unwrap(), 3);
+        assert_eq!(read_zip_listing(&small).unwrap().entries.len(), 3);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
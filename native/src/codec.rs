@@ -0,0 +1,382 @@
+/// Base64 and hex codecs for CloudNexus
+///
+/// Some provider SDKs take binary payloads (encrypted chunks) as base64 or hex
+/// strings. Encoding them here with SIMD-accelerated codecs avoids a slow pass
+/// on the Dart side. Output buffers are allocated with malloc and must be
+/// freed with free_buffer; the encoded strings are ASCII and not nul-terminated.
+use std::ptr;
+use std::slice;
+
+use base64_simd::{AsOut, Base64};
+
+use crate::errors::checked;
+use crate::file_io::{ERROR_BUFFER_ALLOC_FAILED, ERROR_NULL_POINTER, SUCCESS};
+
+pub use crate::errors::{ERROR_INVALID_BASE64, ERROR_INVALID_HEX, ERROR_OUTPUT_TOO_SMALL};
+
+/// Padded base64 engine for the standard ("+/") or URL-safe ("-_") alphabet
+pub fn base64_engine(url_safe: bool) -> &'static Base64 {
+    if url_safe { &base64_simd::URL_SAFE } else { &base64_simd::STANDARD }
+}
+
+/// Length of the padded base64 encoding of `len` bytes (same for both alphabets)
+pub fn base64_encoded_length(len: usize) -> usize {
+    base64_simd::STANDARD.encoded_length(len)
+}
+
+/// Encode `src` as padded base64 into the start of `dst`
+///
+/// Returns the encoded length, or None if `dst` is too small.
+pub fn encode_base64_into(src: &[u8], dst: &mut [u8], url_safe: bool) -> Option<usize> {
+    let encoded_len = base64_encoded_length(src.len());
+    if encoded_len > dst.len() {
+        return None;
+    }
+    Some(base64_engine(url_safe).encode(src, dst[..encoded_len].as_out()).len())
+}
+
+/// Engine able to decode `src`: the alphabet is detected from '-'/'_' and
+/// padding from a trailing '='
+fn decoding_engine(src: &[u8]) -> &'static Base64 {
+    let url_safe = src.iter().any(|&b| b == b'-' || b == b'_');
+    let padded = src.last() == Some(&b'=');
+    match (url_safe, padded) {
+        (false, true) => &base64_simd::STANDARD,
+        (false, false) => &base64_simd::STANDARD_NO_PAD,
+        (true, true) => &base64_simd::URL_SAFE,
+        (true, false) => &base64_simd::URL_SAFE_NO_PAD,
+    }
+}
+
+/// Decode base64 in either alphabet, padded or not
+pub fn decode_base64(src: &[u8]) -> Result<Vec<u8>, i32> {
+    decoding_engine(src).decode_to_vec(src).map_err(|_| ERROR_INVALID_BASE64)
+}
+
+/// Allocate a malloc'd buffer of `len` bytes (at least 1, so empty results are not null)
+/// and fill it with `fill`, which returns the number of bytes written
+fn malloc_filled<F>(len: usize, fill: F) -> Result<(*mut u8, usize), i32>
+where
+    F: FnOnce(&mut [u8]) -> Result<usize, i32>,
+{
+    let output = unsafe { libc::malloc(len.max(1)) as *mut u8 };
+    if output.is_null() {
+        return Err(ERROR_BUFFER_ALLOC_FAILED);
+    }
+
+    match fill(unsafe { slice::from_raw_parts_mut(output, len) }) {
+        Ok(written) => Ok((output, written)),
+        Err(code) => {
+            unsafe { libc::free(output as *mut libc::c_void); }
+            Err(code)
+        }
+    }
+}
+
+/// Input slice of a codec call; a null pointer is only allowed for empty input
+fn input<'a>(data: *const u8, len: usize) -> Result<&'a [u8], i32> {
+    if data.is_null() {
+        return if len == 0 { Ok(&[]) } else { Err(ERROR_NULL_POINTER) };
+    }
+    Ok(unsafe { slice::from_raw_parts(data, len) })
+}
+
+/// Report a codec result through `out_len` and `status_out` and unwrap its buffer
+fn codec_result(result: Result<(*mut u8, usize), i32>, out_len: *mut usize, status_out: *mut i32) -> *mut u8 {
+    let (output, len, status) = match result {
+        Ok((output, len)) => (output, len, SUCCESS),
+        Err(code) => (ptr::null_mut(), 0, code),
+    };
+    if !out_len.is_null() {
+        unsafe { *out_len = len; }
+    }
+    if !status_out.is_null() {
+        unsafe { *status_out = status; }
+    }
+    output
+}
+
+/// Encode bytes as padded base64
+///
+/// # Arguments
+/// * `data` - Pointer to the bytes to encode (can be null if `len` is 0)
+/// * `len` - Number of bytes
+/// * `url_safe` - 1 for the URL-safe alphabet ("-_"), 0 for the standard one ("+/")
+/// * `out_len` - Pointer to store the encoded length
+/// * `status_out` - Optional pointer receiving 0 or a negative error code
+///
+/// # Returns
+/// Pointer to the ASCII encoding (caller must free with free_buffer), or null on error
+#[no_mangle]
+pub extern "C" fn base64_encode(
+    data: *const u8,
+    len: usize,
+    url_safe: i32,
+    out_len: *mut usize,
+    status_out: *mut i32,
+) -> *mut u8 {
+    let result = input(data, len).and_then(|src| {
+        malloc_filled(base64_encoded_length(src.len()), |dst| {
+            encode_base64_into(src, dst, url_safe != 0).ok_or(ERROR_OUTPUT_TOO_SMALL)
+        })
+    });
+    codec_result(result, out_len, status_out)
+}
+
+/// Encode bytes as padded base64 into a caller-provided buffer
+///
+/// Avoids an allocation when the caller already owns a large enough buffer,
+/// such as an upload chunk buffer. base64_encoded_len gives the size needed.
+///
+/// # Arguments
+/// * `data` - Pointer to the bytes to encode (can be null if `len` is 0)
+/// * `len` - Number of bytes
+/// * `url_safe` - 1 for the URL-safe alphabet ("-_"), 0 for the standard one ("+/")
+/// * `out` - Buffer receiving the encoding
+/// * `out_capacity` - Size of `out`
+/// * `out_len` - Pointer to store the encoded length
+///
+/// # Returns
+/// 0 on success, ERROR_OUTPUT_TOO_SMALL if `out` can't hold the encoding, or another error code
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn base64_encode_into(
+    data: *const u8,
+    len: usize,
+    url_safe: i32,
+    out: *mut u8,
+    out_capacity: usize,
+    out_len: *mut usize,
+) -> i32 {
+    if out.is_null() || out_len.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+    let src = match input(data, len) {
+        Ok(src) => src,
+        Err(code) => return checked(code),
+    };
+
+    let dst = unsafe { slice::from_raw_parts_mut(out, out_capacity) };
+    match encode_base64_into(src, dst, url_safe != 0) {
+        Some(encoded_len) => {
+            unsafe { *out_len = encoded_len; }
+            SUCCESS
+        }
+        None => ERROR_OUTPUT_TOO_SMALL,
+    }
+}
+
+/// Size of the padded base64 encoding of `len` bytes
+#[no_mangle]
+pub extern "C" fn base64_encoded_len(len: usize) -> usize {
+    base64_encoded_length(len)
+}
+
+/// Decode base64
+///
+/// Both alphabets are accepted (detected from '-' or '_'), with or without
+/// padding. Whitespace is not allowed.
+///
+/// # Arguments
+/// * `str` - Pointer to the ASCII base64 text (can be null if `len` is 0)
+/// * `len` - Length of the text in bytes
+/// * `out_len` - Pointer to store the decoded length
+/// * `status_out` - Optional pointer receiving 0, ERROR_INVALID_BASE64 or another negative error code
+///
+/// # Returns
+/// Pointer to the decoded bytes (caller must free with free_buffer), or null on error
+#[no_mangle]
+pub extern "C" fn base64_decode(
+    str: *const u8,
+    len: usize,
+    out_len: *mut usize,
+    status_out: *mut i32,
+) -> *mut u8 {
+    let result = input(str, len).and_then(|src| {
+        let engine = decoding_engine(src);
+        let decoded_len = engine.decoded_length(src).map_err(|_| ERROR_INVALID_BASE64)?;
+        malloc_filled(decoded_len, |dst| {
+            engine.decode(src, dst.as_out()).map(|decoded| decoded.len()).map_err(|_| ERROR_INVALID_BASE64)
+        })
+    });
+    codec_result(result, out_len, status_out)
+}
+
+/// Encode bytes as lowercase hex
+///
+/// # Arguments
+/// * `data` - Pointer to the bytes to encode (can be null if `len` is 0)
+/// * `len` - Number of bytes
+/// * `out_len` - Pointer to store the encoded length (2 * len)
+/// * `status_out` - Optional pointer receiving 0 or a negative error code
+///
+/// # Returns
+/// Pointer to the ASCII encoding (caller must free with free_buffer), or null on error
+#[no_mangle]
+pub extern "C" fn hex_encode(
+    data: *const u8,
+    len: usize,
+    out_len: *mut usize,
+    status_out: *mut i32,
+) -> *mut u8 {
+    let result = input(data, len).and_then(|src| {
+        let encoded_len = src.len().checked_mul(2).ok_or(ERROR_BUFFER_ALLOC_FAILED)?;
+        malloc_filled(encoded_len, |dst| {
+            faster_hex::hex_encode(src, dst).map(|encoded| encoded.len()).map_err(|_| ERROR_OUTPUT_TOO_SMALL)
+        })
+    });
+    codec_result(result, out_len, status_out)
+}
+
+/// Decode hex (either case)
+///
+/// # Arguments
+/// * `str` - Pointer to the ASCII hex text (can be null if `len` is 0)
+/// * `len` - Length of the text in bytes (must be even)
+/// * `out_len` - Pointer to store the decoded length
+/// * `status_out` - Optional pointer receiving 0, ERROR_INVALID_HEX or another negative error code
+///
+/// # Returns
+/// Pointer to the decoded bytes (caller must free with free_buffer), or null on error
+#[no_mangle]
+pub extern "C" fn hex_decode(
+    str: *const u8,
+    len: usize,
+    out_len: *mut usize,
+    status_out: *mut i32,
+) -> *mut u8 {
+    let result = input(str, len).and_then(|src| {
+        if src.len() % 2 != 0 {
+            return Err(ERROR_INVALID_HEX);
+        }
+        malloc_filled(src.len() / 2, |dst| {
+            faster_hex::hex_decode(src, dst).map(|_| dst.len()).map_err(|_| ERROR_INVALID_HEX)
+        })
+    });
+    codec_result(result, out_len, status_out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::free_buffer;
+
+    /// Output of a codec call as a Vec, or its status code
+    fn take(call: impl FnOnce(*mut usize, *mut i32) -> *mut u8) -> Result<Vec<u8>, i32> {
+        let mut len = usize::MAX;
+        let mut status = 1;
+        let output = call(&mut len, &mut status);
+        if output.is_null() {
+            assert_ne!(status, SUCCESS);
+            return Err(status);
+        }
+        assert_eq!(status, SUCCESS);
+        let bytes = unsafe { slice::from_raw_parts(output, len) }.to_vec();
+        free_buffer(output);
+        Ok(bytes)
+    }
+
+    #[test]
+    fn test_base64_and_hex_round_trip() {
+        let data: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        for url_safe in [0, 1] {
+            let encoded = take(|l, s| base64_encode(data.as_ptr(), data.len(), url_safe, l, s)).unwrap();
+            assert_eq!(encoded.len(), base64_encoded_len(data.len()));
+            let alphabet_char = if url_safe == 1 { b'_' } else { b'/' };
+            assert!(encoded.contains(&alphabet_char));
+            let decoded = take(|l, s| base64_decode(encoded.as_ptr(), encoded.len(), l, s)).unwrap();
+            assert_eq!(decoded, data);
+        }
+
+        let encoded = take(|l, s| base64_encode(b"hello".as_ptr(), 5, 0, l, s)).unwrap();
+        assert_eq!(encoded, b"aGVsbG8=");
+        // Unpadded input decodes too
+        assert_eq!(take(|l, s| base64_decode(b"aGVsbG8".as_ptr(), 7, l, s)).unwrap(), b"hello");
+
+        let encoded = take(|l, s| hex_encode(data.as_ptr(), data.len(), l, s)).unwrap();
+        assert_eq!(&encoded[..6], b"000102");
+        assert_eq!(take(|l, s| hex_decode(encoded.as_ptr(), encoded.len(), l, s)).unwrap(), data);
+        assert_eq!(take(|l, s| hex_decode(b"ABcd".as_ptr(), 4, l, s)).unwrap(), vec![0xab, 0xcd]);
+
+        // Empty input gives an empty, non-null result
+        assert_eq!(take(|l, s| base64_encode(ptr::null(), 0, 0, l, s)).unwrap(), b"");
+        assert_eq!(take(|l, s| hex_decode(ptr::null(), 0, l, s)).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_malformed_input_errors() {
+        for bad in [&b"aGVsbG8*"[..], b"aGVsbG8=x", b"a", b"aGV=sbG8", b"aG-sbG/8"] {
+            assert_eq!(take(|l, s| base64_decode(bad.as_ptr(), bad.len(), l, s)), Err(ERROR_INVALID_BASE64), "{:?}", bad);
+        }
+        for bad in [&b"abc"[..], b"zz", b"0g"] {
+            assert_eq!(take(|l, s| hex_decode(bad.as_ptr(), bad.len(), l, s)), Err(ERROR_INVALID_HEX), "{:?}", bad);
+        }
+        assert_eq!(take(|l, s| hex_encode(ptr::null(), 3, l, s)), Err(ERROR_NULL_POINTER));
+
+        let mut out = [0u8; 7];
+        let mut out_len = 0usize;
+        assert_eq!(base64_encode_into(b"hello".as_ptr(), 5, 0, out.as_mut_ptr(), out.len(), &mut out_len),
+                   ERROR_OUTPUT_TOO_SMALL);
+        let mut out = [0u8; 8];
+        assert_eq!(base64_encode_into(b"hello".as_ptr(), 5, 0, out.as_mut_ptr(), out.len(), &mut out_len), SUCCESS);
+        assert_eq!(&out[..out_len], b"aGVsbG8=");
+    }
+
+    extern "C" fn collect_payload(data: *const u8, data_len: usize, _chunk_index: u32, user_data: *mut libc::c_void) {
+        let payloads = unsafe { &mut *(user_data as *mut Vec<Vec<u8>>) };
+        payloads.push(unsafe { slice::from_raw_parts(data, data_len) }.to_vec());
+    }
+
+    #[test]
+    fn test_upload_emits_base64_chunks() {
+        use crate::upload::{upload_free, upload_init, upload_process_chunk, upload_set_emit_base64};
+        use std::ffi::CString;
+
+        let path = std::env::temp_dir().join(format!("cloud_nexus_codec_upload_{}", std::process::id()));
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 256) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let path_c = CString::new(path.to_str().unwrap()).unwrap();
+        let chunk_size = 64 * 1024;
+        let mut buffer = vec![0u8; base64_encoded_length(chunk_size + 1024)];
+
+        // Plain upload: each chunk buffer decodes to exactly the source bytes it covers
+        let ctx = upload_init(path_c.as_ptr(), ptr::null(), 0, chunk_size, 0, None, None, ptr::null(), ptr::null_mut());
+        upload_set_emit_base64(ctx, 1, 1);
+        let mut offset = 0;
+        let mut payloads: Vec<Vec<u8>> = Vec::new();
+        let user_data = &mut payloads as *mut Vec<Vec<u8>> as *mut libc::c_void;
+        loop {
+            let n = upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_payload), user_data);
+            assert!(n >= 0);
+            if n == 0 {
+                break;
+            }
+            let n = n as usize;
+            let emitted = payloads.last().unwrap();
+            assert_eq!(emitted.len(), base64_encoded_length(n));
+            assert_eq!(&buffer[..emitted.len()], &emitted[..]);
+            assert_eq!(decode_base64(emitted).unwrap(), &data[offset..offset + n]);
+            offset += n;
+        }
+        assert_eq!(offset, data.len());
+        upload_free(ctx);
+
+        // Encrypted upload: the decoded payloads form a container that decrypts to the source
+        let key = [9u8; 32];
+        let ctx = upload_init(path_c.as_ptr(), key.as_ptr(), key.len(), chunk_size, 1, None, None, ptr::null(), ptr::null_mut());
+        upload_set_emit_base64(ctx, 1, 0);
+        let mut payloads: Vec<Vec<u8>> = Vec::new();
+        let user_data = &mut payloads as *mut Vec<Vec<u8>> as *mut libc::c_void;
+        while upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_payload), user_data) > 0 {}
+        upload_free(ctx);
+
+        let container: Vec<u8> = payloads.iter().flat_map(|payload| decode_base64(payload).unwrap()).collect();
+        let mut plain_len = 0usize;
+        let plain = crate::decrypt_file(container.as_ptr(), container.len(), key.as_ptr(), key.len(), &mut plain_len);
+        assert!(!plain.is_null());
+        assert_eq!(unsafe { slice::from_raw_parts(plain, plain_len) }, &data[..]);
+        free_buffer(plain);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
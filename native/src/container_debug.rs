@@ -0,0 +1,473 @@
+/// Container layout inspection for CloudNexus support tooling
+///
+/// Walks a CNER container and reports its structure without the master key:
+/// main header fields, the FEK region, and every chunk record with its offset,
+/// declared size and nonce. Files are read through a small buffer and chunk
+/// contents are skipped with seeks, so memory use does not grow with the file
+/// size (only the chunk list in the report does).
+///
+/// The main header does not record the chunk size, so the expected plaintext
+/// size is derived from the data chunk records (declared size minus the MAC).
+use std::ffi::c_char;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::slice;
+
+use serde::Serialize;
+
+use crate::escrow::parse_extension_sections;
+use crate::ffi_util::{envelope_str, json_envelope, ErrorEnvelope};
+use crate::file_io::{ERROR_FILE_NOT_FOUND, ERROR_IO_FAILED};
+use crate::{format_version_supported, CHUNK_BASE_HEADER_SIZE, CHUNK_CRC_FLAG, CHUNK_CRC_HEADER_SIZE,
+            CHUNK_REFERENCE_SIZE_FLAG, HEADER_FLAGS_OFFSET, HEADER_FLAG_CHUNK_CRC, HEADER_FLAG_EXTENSIONS, HEADER_SIZE,
+            MAC_SIZE, MAGIC, WRAPPED_FEK_SIZE};
+
+/// Largest FEK region whose extension sections are parsed
+const MAX_INSPECTED_FEK_REGION: usize = 64 * 1024;
+
+/// Main header fields
+#[derive(Debug, Clone, Serialize)]
+pub struct ContainerHeaderLayout {
+    /// Magic as stored, in hex ("434e4552" is "CNER" in little-endian order)
+    pub magic: String,
+    pub magic_valid: bool,
+    pub version: u8,
+    pub version_supported: bool,
+    /// Flags byte (first reserved byte)
+    pub flags: u8,
+    pub chunk_crc: bool,
+    pub extensions: bool,
+    /// Declared length of the FEK region (wrapped FEK plus extension sections)
+    pub fek_region_length: u32,
+}
+
+/// Extension section found in the FEK region
+#[derive(Debug, Clone, Serialize)]
+pub struct ContainerSectionLayout {
+    pub section_type: u8,
+    pub version: u8,
+    pub length: usize,
+}
+
+/// One chunk record
+#[derive(Debug, Clone, Serialize)]
+pub struct ContainerChunkLayout {
+    /// Position of the record in the container (0 for the first)
+    pub position: u64,
+    /// Chunk index stored in the record
+    pub index: u32,
+    /// Offset of the record from the start of the container
+    pub offset: u64,
+    /// "data" or "reference" (dedup record holding a fingerprint)
+    pub kind: &'static str,
+    pub header_length: usize,
+    /// Declared ciphertext (or fingerprint) length, flags removed
+    pub declared_size: u64,
+    /// Nonce in hex
+    pub nonce: String,
+    /// Stored CRC32C in hex, for containers written with chunk CRCs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crc32c: Option<String>,
+    /// Whether the declared size fits in the remaining bytes
+    pub consistent: bool,
+}
+
+/// Totals over the whole container
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ContainerLayoutSummary {
+    /// Complete chunk records
+    pub chunk_count: u64,
+    pub data_chunks: u64,
+    pub reference_chunks: u64,
+    /// Plaintext size implied by the data chunks; null if the container is
+    /// truncated or holds reference records
+    pub expected_plaintext_size: Option<u64>,
+    /// The last chunk (or the header) is cut short
+    pub truncated: bool,
+    /// Bytes after the last complete chunk that do not belong to a chunk
+    pub trailing_garbage_bytes: u64,
+    /// First structural problem found, if any
+    pub problem: Option<String>,
+}
+
+/// Structure of a container, as returned by debug_container_layout
+#[derive(Debug, Clone, Serialize)]
+pub struct ContainerLayout {
+    pub file_size: u64,
+    /// Null if the file is shorter than the main header
+    pub header: Option<ContainerHeaderLayout>,
+    /// Length of the master-key wrapped FEK inside the FEK region
+    pub wrapped_fek_length: usize,
+    pub extension_sections: Vec<ContainerSectionLayout>,
+    pub chunks: Vec<ContainerChunkLayout>,
+    pub summary: ContainerLayoutSummary,
+}
+
+/// Fill `buffer` as far as the reader allows, returning the bytes read
+fn read_up_to<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Walk the container in `reader`, which holds `file_size` bytes
+pub fn inspect_container<R: Read + Seek>(reader: &mut R, file_size: u64) -> std::io::Result<ContainerLayout> {
+    let mut layout = ContainerLayout {
+        file_size,
+        header: None,
+        wrapped_fek_length: 0,
+        extension_sections: Vec::new(),
+        chunks: Vec::new(),
+        summary: ContainerLayoutSummary::default(),
+    };
+
+    let mut header = [0u8; HEADER_SIZE];
+    if read_up_to(reader, &mut header)? < HEADER_SIZE {
+        layout.summary.truncated = true;
+        layout.summary.problem = Some("file is shorter than the main header".to_string());
+        return Ok(layout);
+    }
+
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let fek_region_length = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    let flags = header[HEADER_FLAGS_OFFSET];
+    let header_layout = ContainerHeaderLayout {
+        magic: faster_hex::hex_string(&header[0..4]),
+        magic_valid: magic == MAGIC,
+        version: header[4],
+        version_supported: format_version_supported(header[4]),
+        flags,
+        chunk_crc: flags & HEADER_FLAG_CHUNK_CRC != 0,
+        extensions: flags & HEADER_FLAG_EXTENSIONS != 0,
+        fek_region_length,
+    };
+    let (magic_valid, extensions) = (header_layout.magic_valid, header_layout.extensions);
+    layout.header = Some(header_layout);
+
+    // Without the magic the rest of the file cannot be interpreted
+    if !magic_valid {
+        layout.summary.problem = Some("magic is not CNER".to_string());
+        return Ok(layout);
+    }
+
+    let fek_region_length = fek_region_length as u64;
+    if HEADER_SIZE as u64 + fek_region_length > file_size {
+        layout.summary.truncated = true;
+        layout.summary.problem = Some("FEK region runs past the end of the file".to_string());
+        return Ok(layout);
+    }
+
+    layout.wrapped_fek_length = fek_region_length as usize;
+    if extensions && fek_region_length as usize >= WRAPPED_FEK_SIZE {
+        layout.wrapped_fek_length = WRAPPED_FEK_SIZE;
+        if fek_region_length as usize <= MAX_INSPECTED_FEK_REGION {
+            let mut region = vec![0u8; fek_region_length as usize];
+            reader.read_exact(&mut region)?;
+            match parse_extension_sections(&region[WRAPPED_FEK_SIZE..]) {
+                Ok(sections) => {
+                    layout.extension_sections = sections.iter()
+                        .map(|section| ContainerSectionLayout {
+                            section_type: section.section_type,
+                            version: section.version,
+                            length: section.payload.len(),
+                        })
+                        .collect();
+                }
+                Err(()) => layout.summary.problem = Some("extension sections are malformed".to_string()),
+            }
+        }
+    }
+    reader.seek(SeekFrom::Start(HEADER_SIZE as u64 + fek_region_length))?;
+
+    walk_chunks(reader, HEADER_SIZE as u64 + fek_region_length, file_size, &mut layout)?;
+    Ok(layout)
+}
+
+fn walk_chunks<R: Read + Seek>(reader: &mut R, mut offset: u64, file_size: u64,
+                               layout: &mut ContainerLayout) -> std::io::Result<()> {
+    let summary = &mut layout.summary;
+    let mut plaintext_size = 0u64;
+    let mut record = [0u8; CHUNK_CRC_HEADER_SIZE];
+
+    while offset < file_size {
+        let remaining = file_size - offset;
+        let position = layout.chunks.len() as u64;
+        let read = read_up_to(reader, &mut record[..CHUNK_BASE_HEADER_SIZE])?;
+
+        // Chunk indexes count up from 0, so a record continuing the sequence is
+        // a cut-short chunk rather than bytes appended after the container
+        let continues_sequence = read >= 4
+            && u32::from_le_bytes([record[0], record[1], record[2], record[3]]) as u64 == position;
+
+        if read < CHUNK_BASE_HEADER_SIZE {
+            mark_tail(summary, continues_sequence, remaining, "chunk header is cut short");
+            break;
+        }
+
+        let index = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+        let size_field = u32::from_le_bytes([record[4], record[5], record[6], record[7]]);
+        let reference = size_field & CHUNK_REFERENCE_SIZE_FLAG != 0;
+        let has_crc = !reference && size_field & CHUNK_CRC_FLAG != 0;
+        let declared_size = if reference {
+            (size_field & !CHUNK_REFERENCE_SIZE_FLAG) as u64
+        } else {
+            (size_field & !CHUNK_CRC_FLAG) as u64
+        };
+
+        let mut header_length = CHUNK_BASE_HEADER_SIZE;
+        let mut crc32c = None;
+        if has_crc {
+            if read_up_to(reader, &mut record[CHUNK_BASE_HEADER_SIZE..])? < CHUNK_CRC_HEADER_SIZE - CHUNK_BASE_HEADER_SIZE {
+                mark_tail(summary, continues_sequence, remaining, "chunk header is cut short");
+                break;
+            }
+            header_length = CHUNK_CRC_HEADER_SIZE;
+            let crc = u32::from_le_bytes([record[20], record[21], record[22], record[23]]);
+            crc32c = Some(format!("{:08x}", crc));
+        }
+
+        let consistent = header_length as u64 + declared_size <= remaining
+            && (reference || declared_size >= MAC_SIZE as u64);
+        layout.chunks.push(ContainerChunkLayout {
+            position,
+            index,
+            offset,
+            kind: if reference { "reference" } else { "data" },
+            header_length,
+            declared_size,
+            nonce: faster_hex::hex_string(&record[8..CHUNK_BASE_HEADER_SIZE]),
+            crc32c,
+            consistent,
+        });
+
+        if !consistent {
+            mark_tail(summary, continues_sequence, remaining, "declared chunk size does not fit the remaining bytes");
+            break;
+        }
+
+        summary.chunk_count += 1;
+        if reference {
+            summary.reference_chunks += 1;
+        } else {
+            summary.data_chunks += 1;
+            plaintext_size += declared_size - MAC_SIZE as u64;
+        }
+        offset += header_length as u64 + declared_size;
+        reader.seek(SeekFrom::Start(offset))?;
+    }
+
+    if !summary.truncated && summary.reference_chunks == 0 {
+        summary.expected_plaintext_size = Some(plaintext_size);
+    }
+    Ok(())
+}
+
+/// Classify the bytes from a record that could not be read completely
+fn mark_tail(summary: &mut ContainerLayoutSummary, continues_sequence: bool, remaining: u64, problem: &str) {
+    if continues_sequence {
+        summary.truncated = true;
+        summary.problem.get_or_insert_with(|| problem.to_string());
+    } else {
+        summary.trailing_garbage_bytes = remaining;
+        summary.problem.get_or_insert_with(|| format!("{} bytes after the last chunk", remaining));
+    }
+}
+
+fn inspect_container_path(path: *const c_char) -> Result<ContainerLayout, ErrorEnvelope> {
+    let path = unsafe { envelope_str(path, "path") }?;
+    let file = File::open(path)
+        .map_err(|e| ErrorEnvelope::new(ERROR_FILE_NOT_FOUND, e.to_string()).with_context("path"))?;
+    let file_size = file.metadata()
+        .map_err(|e| ErrorEnvelope::new(ERROR_IO_FAILED, e.to_string()).with_context("path"))?
+        .len();
+
+    inspect_container(&mut BufReader::new(file), file_size)
+        .map_err(|e| ErrorEnvelope::new(ERROR_IO_FAILED, format!("failed to read container: {}", e)).with_context("path"))
+}
+
+/// Describe the structure of an encrypted container file without the key
+///
+/// Returns a JSON envelope (see ffi_util.rs) whose `data` has file_size,
+/// header (magic, magic_valid, version, version_supported, flags, chunk_crc,
+/// extensions, fek_region_length; null if the file is shorter than the
+/// header), wrapped_fek_length, extension_sections, chunks (position, index,
+/// offset, kind, header_length, declared_size, nonce in hex, crc32c,
+/// consistent) and summary (chunk_count, data_chunks, reference_chunks,
+/// expected_plaintext_size, truncated, trailing_garbage_bytes, problem).
+///
+/// A damaged container is not an error: the walk stops at the first record
+/// that does not fit and the summary says why. A cut-short record whose index
+/// continues the chunk sequence counts as truncation; anything else after the
+/// last complete chunk counts as trailing garbage.
+///
+/// # Arguments
+/// * `path` - Path of the container
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope (caller must free with scan_folder_free_string), or null if it
+/// cannot be allocated
+#[no_mangle]
+pub extern "C" fn debug_container_layout(path: *const c_char, out_len: *mut usize) -> *mut c_char {
+    json_envelope(inspect_container_path(path), out_len)
+}
+
+/// Describe the structure of an encrypted container held in memory
+///
+/// Same report as debug_container_layout.
+///
+/// # Arguments
+/// * `data` - Pointer to the container bytes
+/// * `data_len` - Length of the container
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope (caller must free with scan_folder_free_string), or null if it
+/// cannot be allocated
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn debug_container_layout_data(data: *const u8, data_len: usize, out_len: *mut usize) -> *mut c_char {
+    if data.is_null() {
+        return json_envelope::<()>(Err(ErrorEnvelope::null_argument("data")), out_len);
+    }
+
+    let bytes = unsafe { slice::from_raw_parts(data, data_len) };
+    let result = inspect_container(&mut Cursor::new(bytes), data_len as u64)
+        .map_err(|e| ErrorEnvelope::new(ERROR_IO_FAILED, format!("failed to read container: {}", e)));
+    json_envelope(result, out_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_io::ERROR_NULL_POINTER;
+    use crate::{encrypt_file_streaming, free_buffer, KEY_SIZE, VERSION};
+    use std::ffi::CString;
+    use std::ptr;
+
+    const KEY: [u8; KEY_SIZE] = [9u8; KEY_SIZE];
+    const CONTENT_LEN: usize = 2 * 1024 * 1024 + 5000;
+
+    fn container() -> Vec<u8> {
+        let content = vec![0x5au8; CONTENT_LEN];
+        let mut len = 0usize;
+        let output = encrypt_file_streaming(content.as_ptr(), content.len(), KEY.as_ptr(), KEY_SIZE, &mut len,
+                                            None, ptr::null_mut());
+        assert!(!output.is_null());
+        let container = unsafe { slice::from_raw_parts(output, len) }.to_vec();
+        free_buffer(output);
+        container
+    }
+
+    fn layout_of_file(bytes: &[u8], name: &str) -> serde_json::Value {
+        let path = std::env::temp_dir().join(format!("cloud_nexus_layout_{}_{}.cner", name, std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        let path_c = CString::new(path.to_string_lossy().to_string()).unwrap();
+
+        let mut len = 0usize;
+        let json = debug_container_layout(path_c.as_ptr(), &mut len);
+        let text = unsafe { CString::from_raw(json) }.into_string().unwrap();
+        assert_eq!(text.len(), len);
+        let _ = std::fs::remove_file(&path);
+
+        let envelope: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(envelope["ok"], true, "{}", text);
+        envelope["data"].clone()
+    }
+
+    #[test]
+    fn test_layout_of_valid_container() {
+        let container = container();
+        let layout = layout_of_file(&container, "valid");
+
+        assert_eq!(layout["file_size"], container.len());
+        assert_eq!(layout["header"]["magic_valid"], true);
+        assert_eq!(layout["header"]["version"], VERSION);
+        assert_eq!(layout["wrapped_fek_length"], WRAPPED_FEK_SIZE);
+
+        let chunks = layout["chunks"].as_array().unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0]["offset"], HEADER_SIZE + WRAPPED_FEK_SIZE);
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk["index"], i);
+            assert_eq!(chunk["kind"], "data");
+            assert_eq!(chunk["consistent"], true);
+            assert_eq!(chunk["nonce"].as_str().unwrap().len(), 24);
+        }
+        assert_eq!(chunks[0]["nonce"],
+                   faster_hex::hex_string(&container[HEADER_SIZE + WRAPPED_FEK_SIZE + 8..HEADER_SIZE + WRAPPED_FEK_SIZE + 20]));
+
+        let summary = &layout["summary"];
+        assert_eq!(summary["chunk_count"], 3);
+        assert_eq!(summary["expected_plaintext_size"], CONTENT_LEN);
+        assert_eq!(summary["truncated"], false);
+        assert_eq!(summary["trailing_garbage_bytes"], 0);
+        assert!(summary["problem"].is_null());
+
+        // The in-memory variant reports the same layout
+        let mut len = 0usize;
+        let json = debug_container_layout_data(container.as_ptr(), container.len(), &mut len);
+        let envelope: serde_json::Value =
+            serde_json::from_str(&unsafe { CString::from_raw(json) }.into_string().unwrap()).unwrap();
+        assert_eq!(envelope["data"], layout);
+    }
+
+    #[test]
+    fn test_layout_of_truncated_and_padded_containers() {
+        let container = container();
+
+        let truncated = layout_of_file(&container[..container.len() - 1000], "truncated");
+        let chunks = truncated["chunks"].as_array().unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[2]["consistent"], false);
+        assert_eq!(truncated["summary"]["chunk_count"], 2);
+        assert_eq!(truncated["summary"]["truncated"], true);
+        assert!(truncated["summary"]["expected_plaintext_size"].is_null());
+        assert_eq!(truncated["summary"]["trailing_garbage_bytes"], 0);
+
+        let mut padded = container.clone();
+        padded.extend_from_slice(&[0xee; 100]);
+        let padded = layout_of_file(&padded, "padded");
+        assert_eq!(padded["summary"]["chunk_count"], 3);
+        assert_eq!(padded["summary"]["truncated"], false);
+        assert_eq!(padded["summary"]["trailing_garbage_bytes"], 100);
+        assert_eq!(padded["summary"]["expected_plaintext_size"], CONTENT_LEN);
+
+        // Fewer garbage bytes than a chunk header
+        let mut short_padded = container.clone();
+        short_padded.extend_from_slice(b"junk");
+        let short_padded = layout_of_file(&short_padded, "short_padded");
+        assert_eq!(short_padded["chunks"].as_array().unwrap().len(), 3);
+        assert_eq!(short_padded["summary"]["trailing_garbage_bytes"], 4);
+    }
+
+    #[test]
+    fn test_layout_of_non_containers() {
+        let layout = layout_of_file(b"short", "tiny");
+        assert!(layout["header"].is_null());
+        assert_eq!(layout["summary"]["truncated"], true);
+
+        let layout = layout_of_file(&[0x41u8; 64], "plain");
+        assert_eq!(layout["header"]["magic_valid"], false);
+        assert!(layout["chunks"].as_array().unwrap().is_empty());
+
+        let mut len = 0usize;
+        let json = debug_container_layout(ptr::null(), &mut len);
+        let envelope: serde_json::Value =
+            serde_json::from_str(&unsafe { CString::from_raw(json) }.into_string().unwrap()).unwrap();
+        assert_eq!(envelope["error"]["code"], ERROR_NULL_POINTER);
+
+        let missing = CString::new("/nonexistent/cloud_nexus_layout.cner").unwrap();
+        let json = debug_container_layout(missing.as_ptr(), &mut len);
+        let envelope: serde_json::Value =
+            serde_json::from_str(&unsafe { CString::from_raw(json) }.into_string().unwrap()).unwrap();
+        assert_eq!(envelope["error"]["code"], ERROR_FILE_NOT_FOUND);
+    }
+}
@@ -0,0 +1,552 @@
+/// Plaintext file metadata carried in an encrypted container's header
+///
+/// With filename encryption the cloud copy loses the original name, and its
+/// size and mtime describe the ciphertext. A container can carry the original
+/// name, plaintext size, mtime and MIME type in a metadata extension section
+/// (see escrow.rs for the section layout), which the master key opens without
+/// decrypting any chunk. Readers that don't know the section skip it, so the
+/// container version is unchanged and containers without it read as before.
+///
+/// The metadata payload (version 1) is:
+/// - nonce (12 bytes)
+/// - AES-256-GCM(entries) + MAC (16 bytes), keyed by the FEK
+///
+/// The associated data binds the payload to its container header: magic,
+/// version and FEK region length, followed by the master-key wrapped FEK. The
+/// flags byte is not bound, so encrypt_file_set_chunk_crc may still be called
+/// after the metadata is set.
+///
+/// The encrypted entries are a sequence of:
+/// - tag (1 byte)
+/// - length (2 bytes, little-endian)
+/// - value
+///
+/// Unknown tags are skipped. At most MAX_METADATA_SIZE bytes of entries are stored.
+use std::ffi::c_char;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::slice;
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use crate::errors::{checked, ERROR_INVALID_STATE};
+use crate::rng::fill_random;
+use serde::{Deserialize, Serialize};
+
+use crate::escrow::{parse_extension_sections, SECTION_HEADER_SIZE};
+use crate::ffi_util::{envelope_str, json_envelope, ErrorEnvelope, ERROR_INVALID_JSON};
+use crate::file_io::{ERROR_FILE_NOT_FOUND, ERROR_IO_FAILED, ERROR_NULL_POINTER, SUCCESS};
+use crate::{format_version_supported, parse_header, unwrap_fek, EncryptionContext, ERROR_DECRYPTION_FAILED,
+            ERROR_ENCRYPTION_FAILED, ERROR_INVALID_FORMAT, ERROR_INVALID_KEY_SIZE, FORMAT_VERSION_EXTENSIONS,
+            HEADER_FLAGS_OFFSET, HEADER_FLAG_EXTENSIONS, HEADER_SIZE, KEY_SIZE, MAC_SIZE, MAGIC, NONCE_SIZE, WRAPPED_FEK_SIZE};
+
+/// Extension section type holding encrypted file metadata
+pub const SECTION_TYPE_METADATA: u8 = 2;
+
+/// Current metadata section version
+pub const METADATA_SECTION_VERSION: u8 = 1;
+
+/// Largest encoded metadata (all entries, before encryption)
+pub const MAX_METADATA_SIZE: usize = 4 * 1024;
+
+pub use crate::errors::ERROR_METADATA_TOO_LARGE;
+
+const TAG_ORIGINAL_NAME: u8 = 1;
+const TAG_PLAINTEXT_SIZE: u8 = 2;
+const TAG_MTIME_MS: u8 = 3;
+const TAG_MIME_TYPE: u8 = 4;
+
+/// Entry header: tag (1) + length (2)
+const ENTRY_HEADER_SIZE: usize = 1 + 2;
+
+/// File metadata stored in a container; every field is optional
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContainerMetadata {
+    #[serde(default)]
+    pub original_name: Option<String>,
+    #[serde(default)]
+    pub plaintext_size: Option<u64>,
+    /// Modification time in milliseconds since the Unix epoch
+    #[serde(default)]
+    pub mtime_ms: Option<i64>,
+    #[serde(default)]
+    pub mime_type: Option<String>,
+}
+
+impl ContainerMetadata {
+    /// Encode the set fields as entries, or None if they exceed MAX_METADATA_SIZE
+    fn encode(&self) -> Option<Vec<u8>> {
+        let mut entries = Vec::new();
+        // A value too long for its length field also fails the size check below
+        let mut push = |tag: u8, value: &[u8]| {
+            entries.push(tag);
+            entries.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            entries.extend_from_slice(value);
+        };
+        if let Some(name) = &self.original_name {
+            push(TAG_ORIGINAL_NAME, name.as_bytes());
+        }
+        if let Some(size) = self.plaintext_size {
+            push(TAG_PLAINTEXT_SIZE, &size.to_le_bytes());
+        }
+        if let Some(mtime_ms) = self.mtime_ms {
+            push(TAG_MTIME_MS, &mtime_ms.to_le_bytes());
+        }
+        if let Some(mime_type) = &self.mime_type {
+            push(TAG_MIME_TYPE, mime_type.as_bytes());
+        }
+        (entries.len() <= MAX_METADATA_SIZE).then_some(entries)
+    }
+
+    /// Decode entries, skipping unknown tags; None if an entry is cut short or malformed
+    fn decode(mut entries: &[u8]) -> Option<Self> {
+        let mut metadata = ContainerMetadata::default();
+        while !entries.is_empty() {
+            let header = entries.get(..ENTRY_HEADER_SIZE)?;
+            let length = u16::from_le_bytes([header[1], header[2]]) as usize;
+            let value = entries[ENTRY_HEADER_SIZE..].get(..length)?;
+            match header[0] {
+                TAG_ORIGINAL_NAME => metadata.original_name = Some(String::from_utf8(value.to_vec()).ok()?),
+                TAG_PLAINTEXT_SIZE => metadata.plaintext_size = Some(u64::from_le_bytes(value.try_into().ok()?)),
+                TAG_MTIME_MS => metadata.mtime_ms = Some(i64::from_le_bytes(value.try_into().ok()?)),
+                TAG_MIME_TYPE => metadata.mime_type = Some(String::from_utf8(value.to_vec()).ok()?),
+                _ => {}
+            }
+            entries = &entries[ENTRY_HEADER_SIZE + length..];
+        }
+        Some(metadata)
+    }
+}
+
+/// Associated data of a metadata payload: magic, version and FEK region length
+/// from the main header, then the master-key wrapped FEK
+fn metadata_aad(header: &[u8], fek_region: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(4 + 1 + 4 + WRAPPED_FEK_SIZE);
+    aad.extend_from_slice(&header[..5]);
+    aad.extend_from_slice(&header[8..HEADER_SIZE]);
+    aad.extend_from_slice(&fek_region[..fek_region.len().min(WRAPPED_FEK_SIZE)]);
+    aad
+}
+
+/// Store `metadata` in an encryption context's FEK region, replacing any earlier metadata
+fn set_context_metadata(ctx: &mut EncryptionContext, metadata: &ContainerMetadata) -> Result<(), i32> {
+    let entries = metadata.encode().ok_or(ERROR_METADATA_TOO_LARGE)?;
+
+    // Keep the wrapped FEK and every other section, in order
+    let mut region = ctx.wrapped_fek[..WRAPPED_FEK_SIZE].to_vec();
+    let sections = parse_extension_sections(&ctx.wrapped_fek).map_err(|_| ERROR_ENCRYPTION_FAILED)?;
+    for section in sections.iter().filter(|s| s.section_type != SECTION_TYPE_METADATA) {
+        region.push(section.section_type);
+        region.push(section.version);
+        region.extend_from_slice(&(section.payload.len() as u32).to_le_bytes());
+        region.extend_from_slice(section.payload);
+    }
+
+    // The final region length is part of the associated data
+    let payload_len = NONCE_SIZE + entries.len() + MAC_SIZE;
+    let region_len = region.len() + SECTION_HEADER_SIZE + payload_len;
+    let mut header = ctx.header;
+    header[4] = header[4].max(FORMAT_VERSION_EXTENSIONS);
+    header[8..HEADER_SIZE].copy_from_slice(&(region_len as u32).to_le_bytes());
+
+    let mut nonce_bytes = [0u8; NONCE_SIZE];
+    fill_random(&mut nonce_bytes);
+    let cipher = Aes256Gcm::new_from_slice(&ctx.fek).map_err(|_| ERROR_ENCRYPTION_FAILED)?;
+    let aad = metadata_aad(&header, &region);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: &entries, aad: &aad })
+        .map_err(|_| ERROR_ENCRYPTION_FAILED)?;
+
+    region.push(SECTION_TYPE_METADATA);
+    region.push(METADATA_SECTION_VERSION);
+    region.extend_from_slice(&(payload_len as u32).to_le_bytes());
+    region.extend_from_slice(&nonce_bytes);
+    region.extend_from_slice(&ciphertext);
+
+    header[HEADER_FLAGS_OFFSET] |= HEADER_FLAG_EXTENSIONS;
+    ctx.header = header;
+    ctx.wrapped_fek = region;
+    Ok(())
+}
+
+/// Read the metadata of a container from its main header and FEK region
+///
+/// Ok(None) if the container carries no metadata section.
+pub fn read_container_metadata(header: &[u8], fek_region: &[u8], master_key: &[u8])
+    -> Result<Option<ContainerMetadata>, ErrorEnvelope> {
+    let invalid = |message: &str| ErrorEnvelope::new(ERROR_INVALID_FORMAT, message);
+    if header[HEADER_FLAGS_OFFSET] & HEADER_FLAG_EXTENSIONS == 0 {
+        return Ok(None);
+    }
+    let sections = parse_extension_sections(fek_region).map_err(|_| invalid("extension sections overrun the FEK region"))?;
+    let Some(section) = sections
+        .iter()
+        .find(|s| s.section_type == SECTION_TYPE_METADATA && s.version == METADATA_SECTION_VERSION)
+    else {
+        return Ok(None);
+    };
+    if section.payload.len() < NONCE_SIZE + MAC_SIZE {
+        return Err(invalid("metadata section is too short"));
+    }
+
+    let fek = unwrap_fek(header, fek_region, master_key)
+        .map_err(|_| ErrorEnvelope::new(ERROR_DECRYPTION_FAILED, "master key does not open the container"))?;
+    let cipher = Aes256Gcm::new_from_slice(&fek).map_err(|_| invalid("wrapped FEK has the wrong size"))?;
+    let (nonce, ciphertext) = section.payload.split_at(NONCE_SIZE);
+    let aad = metadata_aad(header, fek_region);
+    let entries = cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| ErrorEnvelope::new(ERROR_DECRYPTION_FAILED, "metadata failed authentication"))?;
+    ContainerMetadata::decode(&entries).map(Some).ok_or_else(|| invalid("metadata entries are malformed"))
+}
+
+/// Split a container prefix into its main header and FEK region
+fn split_container_prefix(data: &[u8]) -> Result<(&[u8], &[u8]), ErrorEnvelope> {
+    let too_short = || ErrorEnvelope::new(ERROR_INVALID_FORMAT, "container is shorter than its header");
+    let header = data.get(..HEADER_SIZE).ok_or_else(too_short)?;
+    let (magic, version, fek_length) =
+        parse_header(header).map_err(|code| ErrorEnvelope::new(code, "FEK region length is out of range"))?;
+    if magic != MAGIC || !format_version_supported(version) {
+        return Err(ErrorEnvelope::new(ERROR_INVALID_FORMAT, "not an encrypted container"));
+    }
+    let fek_region = data.get(HEADER_SIZE..HEADER_SIZE + fek_length).ok_or_else(too_short)?;
+    Ok((header, fek_region))
+}
+
+fn master_key_slice<'a>(master_key: *const u8, key_len: usize) -> Result<&'a [u8], ErrorEnvelope> {
+    if master_key.is_null() {
+        return Err(ErrorEnvelope::null_argument("master_key"));
+    }
+    if key_len != KEY_SIZE {
+        return Err(ErrorEnvelope::new(ERROR_INVALID_KEY_SIZE, "master key must be 32 bytes"));
+    }
+    Ok(unsafe { slice::from_raw_parts(master_key, key_len) })
+}
+
+/// Store plaintext file metadata in the container header
+///
+/// Must be called before the header and wrapped FEK are written and before the
+/// first chunk is encrypted: it grows the FEK region, so the header size
+/// reported by encrypt_file_init no longer applies (see
+/// encrypt_file_get_wrapped_fek for the new region). Once a chunk has been
+/// encrypted it fails with ERROR_INVALID_STATE and leaves the context
+/// unchanged. Calling it again before that replaces the metadata.
+///
+/// # Arguments
+/// * `context` - Pointer to EncryptionContext from encrypt_file_init()
+/// * `json` - JSON object with optional original_name, plaintext_size,
+///   mtime_ms (milliseconds since the Unix epoch) and mime_type
+///
+/// # Returns
+/// 0 on success, ERROR_INVALID_JSON (-33) for malformed JSON or unknown fields,
+/// ERROR_METADATA_TOO_LARGE if the encoded fields exceed 4 KB, ERROR_INVALID_STATE
+/// after the first chunk, other error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn encrypt_file_set_metadata_json(context: *mut EncryptionContext, json: *const c_char) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+    let json = match unsafe { envelope_str(json, "json") } {
+        Ok(json) => json,
+        Err(error) => return checked(error.code),
+    };
+    let metadata: ContainerMetadata = match serde_json::from_str(json) {
+        Ok(metadata) => metadata,
+        Err(_) => return ERROR_INVALID_JSON,
+    };
+    let ctx = unsafe { &mut *context };
+    if ctx.chunks_started {
+        return ERROR_INVALID_STATE;
+    }
+    match set_context_metadata(ctx, &metadata) {
+        Ok(()) => SUCCESS,
+        Err(code) => checked(code),
+    }
+}
+
+/// Read the plaintext file metadata of an encrypted container file
+///
+/// Only the header and FEK region are read; no chunk is decrypted.
+///
+/// # Arguments
+/// * `path` - Path of the container
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `key_len` - Length of master key (must be 32)
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope whose `data` is {original_name, plaintext_size, mtime_ms,
+/// mime_type} (absent fields are null), or null if the container carries no
+/// metadata. Fails with ERROR_DECRYPTION_FAILED (-4) for a wrong key or
+/// tampered metadata and ERROR_INVALID_FORMAT (-5) for a damaged container
+/// (caller must free with scan_folder_free_string)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn read_encrypted_metadata_json(
+    path: *const c_char,
+    master_key: *const u8,
+    key_len: usize,
+    out_len: *mut usize,
+) -> *mut c_char {
+    let result = (|| {
+        let path = unsafe { envelope_str(path, "path") }?;
+        let key = master_key_slice(master_key, key_len)?;
+        let io_error = |e: std::io::Error| {
+            let code = match e.kind() {
+                std::io::ErrorKind::NotFound => ERROR_FILE_NOT_FOUND,
+                std::io::ErrorKind::UnexpectedEof => ERROR_INVALID_FORMAT,
+                _ => ERROR_IO_FAILED,
+            };
+            ErrorEnvelope::new(code, e.to_string()).with_context(path)
+        };
+
+        let mut file = File::open(Path::new(path)).map_err(io_error)?;
+        let mut prefix = vec![0u8; HEADER_SIZE];
+        file.read_exact(&mut prefix).map_err(io_error)?;
+        let (_, _, fek_length) =
+            parse_header(&prefix).map_err(|code| ErrorEnvelope::new(code, "FEK region length is out of range"))?;
+        prefix.resize(HEADER_SIZE + fek_length, 0);
+        file.read_exact(&mut prefix[HEADER_SIZE..]).map_err(io_error)?;
+
+        let (header, fek_region) = split_container_prefix(&prefix)?;
+        read_container_metadata(header, fek_region, key)
+    })();
+    json_envelope(result, out_len)
+}
+
+/// Read the plaintext file metadata of an encrypted container held in memory
+///
+/// Same result as read_encrypted_metadata_json; `data` needs to hold only the
+/// header and FEK region.
+///
+/// # Arguments
+/// * `data` - Pointer to the container bytes
+/// * `data_len` - Length of the container bytes
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `key_len` - Length of master key (must be 32)
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope (caller must free with scan_folder_free_string)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn read_encrypted_metadata_json_data(
+    data: *const u8,
+    data_len: usize,
+    master_key: *const u8,
+    key_len: usize,
+    out_len: *mut usize,
+) -> *mut c_char {
+    let result = (|| {
+        if data.is_null() {
+            return Err(ErrorEnvelope::null_argument("data"));
+        }
+        let key = master_key_slice(master_key, key_len)?;
+        let data = unsafe { slice::from_raw_parts(data, data_len) };
+        let (header, fek_region) = split_container_prefix(data)?;
+        read_container_metadata(header, fek_region, key)
+    })();
+    json_envelope(result, out_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+
+    use crate::escrow::X25519_KEY_SIZE;
+    use crate::{decrypt_chunk, decrypt_file_finalize, decrypt_file_init, decrypt_file_init_with_recovery,
+                encrypt_chunk, encrypt_file_finalize, encrypt_file_get_wrapped_fek, encrypt_file_init,
+                encrypt_file_init_ex, free_buffer, recovery_key_generate, scan_folder_free_string};
+
+    const KEY: [u8; KEY_SIZE] = [8u8; KEY_SIZE];
+
+    /// Encrypt `content` as a single-chunk container, with metadata set before the header is taken
+    fn encrypt_container(content: &[u8], metadata: Option<&str>, recovery_public: Option<&[u8; X25519_KEY_SIZE]>)
+        -> Vec<u8> {
+        let mut header_len = 0usize;
+        let ctx = match recovery_public {
+            Some(public) => encrypt_file_init_ex(KEY.as_ptr(), KEY_SIZE, public.as_ptr(), X25519_KEY_SIZE, &mut header_len),
+            None => encrypt_file_init(KEY.as_ptr(), KEY_SIZE, &mut header_len),
+        };
+        assert!(!ctx.is_null());
+        if let Some(json) = metadata {
+            let json = CString::new(json).unwrap();
+            assert_eq!(encrypt_file_set_metadata_json(ctx, json.as_ptr()), SUCCESS);
+        }
+
+        let mut container = unsafe { (*ctx).header }.to_vec();
+        let mut fek_len = 0usize;
+        let fek = encrypt_file_get_wrapped_fek(ctx, &mut fek_len);
+        container.extend_from_slice(unsafe { slice::from_raw_parts(fek, fek_len) });
+        free_buffer(fek);
+
+        let mut chunk_len = 0usize;
+        let chunk = encrypt_chunk(ctx, content.as_ptr(), content.len(), 0, &mut chunk_len);
+        container.extend_from_slice(unsafe { slice::from_raw_parts(chunk, chunk_len) });
+        free_buffer(chunk);
+        encrypt_file_finalize(ctx);
+        container
+    }
+
+    fn decrypt_content(container: &[u8]) -> Vec<u8> {
+        let ctx = decrypt_file_init(container.as_ptr(), container.len(), KEY.as_ptr(), KEY_SIZE);
+        assert!(!ctx.is_null());
+        let fek_length = u32::from_le_bytes(container[8..12].try_into().unwrap()) as usize;
+        let chunk = &container[HEADER_SIZE + fek_length..];
+        let mut out_len = 0usize;
+        let plain = decrypt_chunk(ctx, chunk.as_ptr(), chunk.len(), &mut out_len);
+        assert!(!plain.is_null());
+        let result = unsafe { slice::from_raw_parts(plain, out_len) }.to_vec();
+        free_buffer(plain);
+        decrypt_file_finalize(ctx);
+        result
+    }
+
+    fn take_envelope(out: *mut c_char, len: usize) -> serde_json::Value {
+        assert!(!out.is_null());
+        let json = unsafe { CStr::from_ptr(out) }.to_str().unwrap().to_string();
+        scan_folder_free_string(out);
+        assert_eq!(json.len(), len);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn read_data(container: &[u8], key: &[u8; KEY_SIZE]) -> serde_json::Value {
+        let mut len = 0usize;
+        let out = read_encrypted_metadata_json_data(container.as_ptr(), container.len(), key.as_ptr(), KEY_SIZE, &mut len);
+        take_envelope(out, len)
+    }
+
+    #[test]
+    fn test_metadata_round_trips_without_decrypting_content() {
+        let content = b"minutes of the board meeting".to_vec();
+        let json = r#"{"original_name": "Board minutes.docx", "plaintext_size": 28, "mtime_ms": 1700000000123,
+                       "mime_type": "application/vnd.openxmlformats-officedocument.wordprocessingml.document"}"#;
+        let container = encrypt_container(&content, Some(json), None);
+        assert_ne!(container[HEADER_FLAGS_OFFSET] & HEADER_FLAG_EXTENSIONS, 0);
+        assert_eq!(container[4], FORMAT_VERSION_EXTENSIONS);
+        assert_eq!(decrypt_content(&container), content);
+
+        let expected: serde_json::Value = serde_json::from_str(json).unwrap();
+        let envelope = read_data(&container, &KEY);
+        assert_eq!(envelope["ok"], true);
+        assert_eq!(envelope["data"], expected);
+
+        // The header and FEK region are enough, read from memory or from a file
+        let fek_length = u32::from_le_bytes(container[8..12].try_into().unwrap()) as usize;
+        assert_eq!(read_data(&container[..HEADER_SIZE + fek_length], &KEY)["data"], expected);
+        let path = std::env::temp_dir().join(format!("cloud_nexus_metadata_{}.cner", std::process::id()));
+        std::fs::write(&path, &container).unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let mut len = 0usize;
+        let envelope = take_envelope(read_encrypted_metadata_json(c_path.as_ptr(), KEY.as_ptr(), KEY_SIZE, &mut len), len);
+        assert_eq!(envelope["data"], expected);
+        std::fs::write(&path, &container[..HEADER_SIZE + 4]).unwrap();
+        let envelope = take_envelope(read_encrypted_metadata_json(c_path.as_ptr(), KEY.as_ptr(), KEY_SIZE, &mut len), len);
+        assert_eq!(envelope["error"]["code"], ERROR_INVALID_FORMAT);
+        let _ = std::fs::remove_file(&path);
+
+        // Partial metadata leaves the other fields null
+        let container = encrypt_container(&content, Some(r#"{"original_name": "notes.txt"}"#), None);
+        let data = read_data(&container, &KEY)["data"].take();
+        assert_eq!(data["original_name"], "notes.txt");
+        assert!(data["plaintext_size"].is_null() && data["mtime_ms"].is_null() && data["mime_type"].is_null());
+    }
+
+    #[test]
+    fn test_containers_without_metadata_still_read() {
+        let content = b"plain old container".to_vec();
+        let container = encrypt_container(&content, None, None);
+        assert_eq!(container[HEADER_FLAGS_OFFSET] & HEADER_FLAG_EXTENSIONS, 0);
+        let envelope = read_data(&container, &KEY);
+        assert_eq!(envelope["ok"], true);
+        assert!(envelope["data"].is_null());
+
+        // An escrowed container has extensions but no metadata; both sections coexist
+        let mut public = [0u8; X25519_KEY_SIZE];
+        let mut private = [0u8; X25519_KEY_SIZE];
+        assert_eq!(recovery_key_generate(public.as_mut_ptr(), private.as_mut_ptr()), SUCCESS);
+        let escrowed = encrypt_container(&content, None, Some(&public));
+        assert!(read_data(&escrowed, &KEY)["data"].is_null());
+
+        let both = encrypt_container(&content, Some(r#"{"mime_type": "text/plain"}"#), Some(&public));
+        assert_eq!(read_data(&both, &KEY)["data"]["mime_type"], "text/plain");
+        assert_eq!(decrypt_content(&both), content);
+        let ctx = decrypt_file_init_with_recovery(both.as_ptr(), both.len(), private.as_ptr(), X25519_KEY_SIZE);
+        assert!(!ctx.is_null());
+        decrypt_file_finalize(ctx);
+    }
+
+    #[test]
+    fn test_metadata_is_authenticated_and_capped() {
+        let container = encrypt_container(b"x", Some(r#"{"original_name": "secret.pdf"}"#), None);
+        let fek_length = u32::from_le_bytes(container[8..12].try_into().unwrap()) as usize;
+        let region_end = HEADER_SIZE + fek_length;
+        assert!(!container[HEADER_SIZE..region_end].windows(10).any(|w| w == b"secret.pdf"));
+
+        // Wrong key, a flipped ciphertext byte or a swapped wrapped FEK all fail authentication
+        assert_eq!(read_data(&container, &[9u8; KEY_SIZE])["error"]["code"], ERROR_DECRYPTION_FAILED);
+        let mut tampered = container.clone();
+        tampered[region_end - 1] ^= 0x01;
+        assert_eq!(read_data(&tampered, &KEY)["error"]["code"], ERROR_DECRYPTION_FAILED);
+        let other = encrypt_container(b"x", None, None);
+        let mut swapped = container.clone();
+        swapped[HEADER_SIZE..HEADER_SIZE + WRAPPED_FEK_SIZE].copy_from_slice(&other[HEADER_SIZE..HEADER_SIZE + WRAPPED_FEK_SIZE]);
+        assert_eq!(read_data(&swapped, &KEY)["error"]["code"], ERROR_DECRYPTION_FAILED);
+
+        // Setting the metadata again replaces it
+        let mut header_len = 0usize;
+        let ctx = encrypt_file_init(KEY.as_ptr(), KEY_SIZE, &mut header_len);
+        for json in [r#"{"original_name": "a.txt"}"#, r#"{"original_name": "b.txt"}"#] {
+            let json = CString::new(json).unwrap();
+            assert_eq!(encrypt_file_set_metadata_json(ctx, json.as_ptr()), SUCCESS);
+        }
+        let sections = parse_extension_sections(unsafe { &(*ctx).wrapped_fek }).unwrap();
+        assert_eq!(sections.len(), 1);
+
+        // Over 4 KB of entries, unknown fields and bad JSON are rejected and leave the metadata alone
+        let too_large = CString::new(format!(r#"{{"original_name": "{}"}}"#, "n".repeat(MAX_METADATA_SIZE))).unwrap();
+        assert_eq!(encrypt_file_set_metadata_json(ctx, too_large.as_ptr()), ERROR_METADATA_TOO_LARGE);
+        let fits = CString::new(format!(r#"{{"original_name": "{}"}}"#, "n".repeat(MAX_METADATA_SIZE - ENTRY_HEADER_SIZE)))
+            .unwrap();
+        assert_eq!(encrypt_file_set_metadata_json(ctx, fits.as_ptr()), SUCCESS);
+        for invalid in [r#"{"mtime": 5}"#, "[1]", "{"] {
+            let invalid = CString::new(invalid).unwrap();
+            assert_eq!(encrypt_file_set_metadata_json(ctx, invalid.as_ptr()), ERROR_INVALID_JSON);
+        }
+        assert_eq!(encrypt_file_set_metadata_json(ptr::null_mut(), ptr::null()), ERROR_NULL_POINTER);
+        assert_eq!(encrypt_file_set_metadata_json(ctx, ptr::null()), ERROR_NULL_POINTER);
+
+        let mut container = unsafe { (*ctx).header }.to_vec();
+        container.extend_from_slice(unsafe { &(*ctx).wrapped_fek });
+        encrypt_file_finalize(ctx);
+        let name = read_data(&container, &KEY)["data"]["original_name"].as_str().unwrap().len();
+        assert_eq!(name, MAX_METADATA_SIZE - ENTRY_HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_metadata_is_rejected_after_the_first_chunk() {
+        let mut header_len = 0usize;
+        let ctx = encrypt_file_init(KEY.as_ptr(), KEY_SIZE, &mut header_len);
+        let header = unsafe { (*ctx).header };
+        let region = unsafe { (*ctx).wrapped_fek.clone() };
+
+        let mut chunk_len = 0usize;
+        let chunk = encrypt_chunk(ctx, b"x".as_ptr(), 1, 0, &mut chunk_len);
+        assert!(!chunk.is_null());
+        free_buffer(chunk);
+
+        // The header and FEK region already describe the chunks, so they stay as they are
+        let json = CString::new(r#"{"original_name": "late.txt"}"#).unwrap();
+        assert_eq!(encrypt_file_set_metadata_json(ctx, json.as_ptr()), ERROR_INVALID_STATE);
+        assert_eq!(unsafe { (*ctx).header }, header);
+        assert_eq!(unsafe { &(*ctx).wrapped_fek }, &region);
+        encrypt_file_finalize(ctx);
+    }
+}
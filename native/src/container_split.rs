@@ -0,0 +1,504 @@
+/// Encrypted container splitting for CloudNexus
+/// Some providers cap the size of a single object, so large containers are
+/// stored as several part files and concatenated again on download, without
+/// decrypting. Chunk records are self-contained, so parts are cut only at
+/// record boundaries: the first part holds the main header and FEK region,
+/// and every part holds whole chunk records. A manifest records each part's
+/// offset, length and chunk range so joining can check ordering and
+/// contiguity. Both directions stream through small buffers.
+use std::ffi::{c_char, CStr, CString};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::checked;
+use crate::encrypt_copy::{io_error_code, ERROR_CONTAINER_TRUNCATED};
+use crate::ffi_util::ERROR_INVALID_JSON;
+use crate::file_io::{c_str_to_path, ERROR_NULL_POINTER, SUCCESS};
+use crate::temp::{commit_temp_file, create_temp_file_for, discard_temp_file};
+use crate::{CHUNK_BASE_HEADER_SIZE, CHUNK_CRC_FLAG, CHUNK_CRC_HEADER_SIZE, CHUNK_REFERENCE_SIZE_FLAG,
+            ERROR_INVALID_FORMAT, HEADER_SIZE, MAGIC};
+
+pub use crate::errors::{ERROR_PART_LIMIT_TOO_SMALL, ERROR_MANIFEST_INVALID};
+
+/// Manifest format written by split_encrypted_file
+pub const SPLIT_MANIFEST_VERSION: u32 = 1;
+
+/// One part file of a split container
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SplitPart {
+    /// Position of the part (0 for the one holding the header)
+    pub index: u32,
+    pub path: String,
+    /// Offset of the part's first byte in the container
+    pub offset: u64,
+    pub length: u64,
+    /// Position of the part's first chunk record in the container
+    pub first_chunk: u64,
+    pub chunk_count: u64,
+}
+
+/// Parts of a split container, in order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SplitManifest {
+    pub version: u32,
+    pub source_size: u64,
+    /// Main header plus FEK region, all in part 0
+    pub header_length: u64,
+    pub chunk_count: u64,
+    pub parts: Vec<SplitPart>,
+}
+
+/// Part file being written
+struct OpenPart {
+    part: SplitPart,
+    writer: BufWriter<File>,
+}
+
+impl OpenPart {
+    fn create(dest_dir: &Path, base_name: &str, index: u32, offset: u64, first_chunk: u64,
+              created: &mut Vec<PathBuf>) -> Result<Self, i32> {
+        let path = dest_dir.join(format!("{}.part{:03}", base_name, index));
+        let file = File::create(&path).map_err(|e| io_error_code(&e))?;
+        created.push(path.clone());
+        Ok(OpenPart {
+            part: SplitPart {
+                index,
+                path: path.to_string_lossy().to_string(),
+                offset,
+                length: 0,
+                first_chunk,
+                chunk_count: 0,
+            },
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Write `prefix` followed by the next `rest` bytes of `reader`
+    fn append<R: Read>(&mut self, prefix: &[u8], reader: &mut R, rest: u64) -> Result<(), i32> {
+        self.writer.write_all(prefix).map_err(|e| io_error_code(&e))?;
+        let copied = io::copy(&mut reader.take(rest), &mut self.writer).map_err(|e| io_error_code(&e))?;
+        if copied != rest {
+            return Err(ERROR_CONTAINER_TRUNCATED);
+        }
+        self.part.length += prefix.len() as u64 + rest;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<SplitPart, i32> {
+        self.writer.flush().map_err(|e| io_error_code(&e))?;
+        self.writer.get_ref().sync_all().map_err(|e| io_error_code(&e))?;
+        Ok(self.part)
+    }
+}
+
+/// Split the container at `source` into part files of at most `max_part_bytes`
+/// in `dest_dir`, named "<source file name>.partNNN"
+///
+/// Part files already written are deleted if the split fails.
+pub fn split_container(source: &Path, dest_dir: &Path, max_part_bytes: u64) -> Result<SplitManifest, i32> {
+    let mut created = Vec::new();
+    let result = split_container_into(source, dest_dir, max_part_bytes, &mut created);
+    if result.is_err() {
+        for path in &created {
+            let _ = fs::remove_file(path);
+        }
+    }
+    result
+}
+
+fn split_container_into(source: &Path, dest_dir: &Path, max_part_bytes: u64,
+                        created: &mut Vec<PathBuf>) -> Result<SplitManifest, i32> {
+    let file = File::open(source).map_err(|e| io_error_code(&e))?;
+    let source_size = file.metadata().map_err(|e| io_error_code(&e))?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut header = [0u8; HEADER_SIZE];
+    if source_size < HEADER_SIZE as u64 {
+        return Err(ERROR_CONTAINER_TRUNCATED);
+    }
+    reader.read_exact(&mut header).map_err(|e| io_error_code(&e))?;
+    if u32::from_le_bytes([header[0], header[1], header[2], header[3]]) != MAGIC {
+        return Err(ERROR_INVALID_FORMAT);
+    }
+    let fek_region_length = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as u64;
+    let header_length = HEADER_SIZE as u64 + fek_region_length;
+    if header_length > source_size {
+        return Err(ERROR_CONTAINER_TRUNCATED);
+    }
+    if header_length > max_part_bytes {
+        return Err(ERROR_PART_LIMIT_TOO_SMALL);
+    }
+
+    let base_name = source.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "container".to_string());
+    fs::create_dir_all(dest_dir).map_err(|e| io_error_code(&e))?;
+
+    let mut parts = Vec::new();
+    let mut current = OpenPart::create(dest_dir, &base_name, 0, 0, 0, created)?;
+    current.append(&header, &mut reader, fek_region_length)?;
+
+    let mut offset = header_length;
+    let mut position = 0u64;
+    let mut record = [0u8; CHUNK_CRC_HEADER_SIZE];
+    while offset < source_size {
+        let remaining = source_size - offset;
+        if remaining < CHUNK_BASE_HEADER_SIZE as u64 {
+            return Err(ERROR_CONTAINER_TRUNCATED);
+        }
+        reader.read_exact(&mut record[..CHUNK_BASE_HEADER_SIZE]).map_err(|e| io_error_code(&e))?;
+
+        let index = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+        let size_field = u32::from_le_bytes([record[4], record[5], record[6], record[7]]);
+        if index as u64 != position {
+            return Err(ERROR_INVALID_FORMAT);
+        }
+        let (header_len, declared_size) = if size_field & CHUNK_REFERENCE_SIZE_FLAG != 0 {
+            (CHUNK_BASE_HEADER_SIZE, (size_field & !CHUNK_REFERENCE_SIZE_FLAG) as u64)
+        } else if size_field & CHUNK_CRC_FLAG != 0 {
+            (CHUNK_CRC_HEADER_SIZE, (size_field & !CHUNK_CRC_FLAG) as u64)
+        } else {
+            (CHUNK_BASE_HEADER_SIZE, size_field as u64)
+        };
+        let record_length = header_len as u64 + declared_size;
+        if record_length > remaining {
+            return Err(ERROR_CONTAINER_TRUNCATED);
+        }
+        if record_length > max_part_bytes {
+            return Err(ERROR_PART_LIMIT_TOO_SMALL);
+        }
+        if header_len > CHUNK_BASE_HEADER_SIZE {
+            reader.read_exact(&mut record[CHUNK_BASE_HEADER_SIZE..header_len]).map_err(|e| io_error_code(&e))?;
+        }
+
+        // Start a new part when this record would overflow the current one
+        if current.part.length + record_length > max_part_bytes {
+            let index = current.part.index + 1;
+            parts.push(current.finish()?);
+            current = OpenPart::create(dest_dir, &base_name, index, offset, position, created)?;
+        }
+        current.append(&record[..header_len], &mut reader, declared_size)?;
+        current.part.chunk_count += 1;
+
+        offset += record_length;
+        position += 1;
+    }
+    parts.push(current.finish()?);
+
+    Ok(SplitManifest {
+        version: SPLIT_MANIFEST_VERSION,
+        source_size,
+        header_length,
+        chunk_count: position,
+        parts,
+    })
+}
+
+/// Check that the manifest's parts are in order and contiguous
+fn check_manifest(manifest: &SplitManifest) -> Result<(), i32> {
+    if manifest.version != SPLIT_MANIFEST_VERSION || manifest.parts.is_empty() {
+        return Err(ERROR_MANIFEST_INVALID);
+    }
+
+    let (mut offset, mut chunk) = (0u64, 0u64);
+    for (i, part) in manifest.parts.iter().enumerate() {
+        let in_order = part.index as usize == i && part.offset == offset && part.first_chunk == chunk;
+        let holds_data = part.length > 0 && (i > 0 || part.length >= manifest.header_length);
+        if !in_order || !holds_data {
+            return Err(ERROR_MANIFEST_INVALID);
+        }
+        offset += part.length;
+        chunk += part.chunk_count;
+    }
+    if offset != manifest.source_size || chunk != manifest.chunk_count {
+        return Err(ERROR_MANIFEST_INVALID);
+    }
+    Ok(())
+}
+
+/// Concatenate the parts listed in `manifest` into `output`
+///
+/// Each part file must have the manifest's length, part 0 must start with the
+/// CNER magic, and every other part must start with the chunk record the
+/// manifest says it holds. The output is written to a temp file and only
+/// renamed into place once every part has been copied.
+pub fn join_container_parts(manifest: &SplitManifest, output: &Path) -> Result<(), i32> {
+    check_manifest(manifest)?;
+
+    let (temp_path, temp_file) = create_temp_file_for(output).map_err(|e| io_error_code(&e))?;
+    let mut writer = BufWriter::new(temp_file);
+    let result = manifest.parts.iter()
+        .try_for_each(|part| append_part(part, &mut writer))
+        .and_then(|_| writer.flush().map_err(|e| io_error_code(&e)));
+    drop(writer);
+
+    match result {
+        Ok(()) => commit_temp_file(&temp_path, output).map_err(|e| io_error_code(&e)),
+        Err(code) => {
+            let _ = discard_temp_file(&temp_path);
+            Err(code)
+        }
+    }
+}
+
+fn append_part<W: Write>(part: &SplitPart, writer: &mut W) -> Result<(), i32> {
+    let file = File::open(&part.path).map_err(|e| io_error_code(&e))?;
+    if file.metadata().map_err(|e| io_error_code(&e))?.len() != part.length {
+        return Err(ERROR_MANIFEST_INVALID);
+    }
+    let mut reader = BufReader::new(file);
+
+    // The leading bytes tell whether this is the part the manifest expects here
+    let mut lead = [0u8; 4];
+    let lead_len = (part.length as usize).min(lead.len());
+    reader.read_exact(&mut lead[..lead_len]).map_err(|e| io_error_code(&e))?;
+    let value = u32::from_le_bytes(lead);
+    if part.index == 0 {
+        if lead_len < 4 || value != MAGIC {
+            return Err(ERROR_INVALID_FORMAT);
+        }
+    } else if part.chunk_count > 0 && (lead_len < 4 || value as u64 != part.first_chunk) {
+        return Err(ERROR_MANIFEST_INVALID);
+    }
+
+    writer.write_all(&lead[..lead_len]).map_err(|e| io_error_code(&e))?;
+    let rest = part.length - lead_len as u64;
+    let copied = io::copy(&mut reader.take(rest), writer).map_err(|e| io_error_code(&e))?;
+    if copied != rest {
+        return Err(ERROR_MANIFEST_INVALID);
+    }
+    Ok(())
+}
+
+/// Split an encrypted container into part files at chunk boundaries
+///
+/// Parts are named "<source file name>.part000", ".part001", ... in `dest_dir`
+/// (created if missing). Part 0 holds the main header and FEK region; each
+/// part holds as many whole chunk records as fit in `max_part_bytes`.
+///
+/// The manifest is JSON with version, source_size, header_length, chunk_count
+/// and parts (index, path, offset, length, first_chunk, chunk_count). Pass it
+/// unchanged to join_encrypted_parts.
+///
+/// # Arguments
+/// * `source_path` - Path of the container to split
+/// * `dest_dir` - Directory for the part files
+/// * `max_part_bytes` - Largest part size in bytes
+/// * `out_manifest_json` - Receives the manifest (free with scan_folder_free_string)
+///
+/// # Returns
+/// 0 on success; ERROR_INVALID_FORMAT if the source is not a CNER container,
+/// ERROR_CONTAINER_TRUNCATED if it is cut short, ERROR_PART_LIMIT_TOO_SMALL if
+/// the header or a chunk record is larger than `max_part_bytes`, other error
+/// code on failure. No part files are left behind on failure.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn split_encrypted_file(
+    source_path: *const c_char,
+    dest_dir: *const c_char,
+    max_part_bytes: u64,
+    out_manifest_json: *mut *mut c_char,
+) -> i32 {
+    if out_manifest_json.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+    unsafe { *out_manifest_json = ptr::null_mut(); }
+
+    let source = match unsafe { c_str_to_path(source_path) } {
+        Ok(path) => path,
+        Err(code) => return checked(code),
+    };
+    let dest_dir = match unsafe { c_str_to_path(dest_dir) } {
+        Ok(path) => path,
+        Err(code) => return checked(code),
+    };
+
+    let manifest = match split_container(&source, &dest_dir, max_part_bytes) {
+        Ok(manifest) => manifest,
+        Err(code) => return checked(code),
+    };
+    let json = serde_json::to_string(&manifest).unwrap_or_default();
+    match CString::new(json) {
+        Ok(json) => unsafe { *out_manifest_json = json.into_raw(); },
+        Err(_) => return ERROR_INVALID_JSON,
+    }
+    SUCCESS
+}
+
+/// Join the part files of a split container back into one container
+///
+/// # Arguments
+/// * `manifest_json` - Manifest from split_encrypted_file
+/// * `output_path` - Path of the joined container (replaced only on success)
+///
+/// # Returns
+/// 0 on success; ERROR_INVALID_JSON if the manifest cannot be parsed,
+/// ERROR_MANIFEST_INVALID if its parts are out of order, not contiguous, or do
+/// not match the part files, ERROR_INVALID_FORMAT if part 0 is not a CNER
+/// container, other error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn join_encrypted_parts(manifest_json: *const c_char, output_path: *const c_char) -> i32 {
+    if manifest_json.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+    let manifest: SplitManifest = match unsafe { CStr::from_ptr(manifest_json) }.to_str()
+        .ok()
+        .and_then(|json| serde_json::from_str(json).ok())
+    {
+        Some(manifest) => manifest,
+        None => return ERROR_INVALID_JSON,
+    };
+    let output = match unsafe { c_str_to_path(output_path) } {
+        Ok(path) => path,
+        Err(code) => return checked(code),
+    };
+
+    match join_container_parts(&manifest, &output) {
+        Ok(()) => SUCCESS,
+        Err(code) => checked(code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_file_streaming, encrypt_file_streaming, free_buffer, KEY_SIZE};
+    use crate::test_util::{c_path, temp_dir};
+    use rand::RngCore;
+    use std::slice;
+
+    const KEY: [u8; KEY_SIZE] = [3u8; KEY_SIZE];
+
+    fn encrypt(data: &[u8]) -> Vec<u8> {
+        let mut len = 0usize;
+        let output = encrypt_file_streaming(data.as_ptr(), data.len(), KEY.as_ptr(), KEY_SIZE, &mut len,
+                                            None, ptr::null_mut());
+        assert!(!output.is_null());
+        let container = unsafe { slice::from_raw_parts(output, len) }.to_vec();
+        free_buffer(output);
+        container
+    }
+
+    fn split(source: &Path, dest_dir: &Path, max_part_bytes: u64) -> (i32, Option<String>) {
+        let mut manifest: *mut c_char = ptr::null_mut();
+        let result = split_encrypted_file(c_path(source).as_ptr(), c_path(dest_dir).as_ptr(), max_part_bytes,
+                                          &mut manifest);
+        if manifest.is_null() {
+            return (result, None);
+        }
+        let json = unsafe { CString::from_raw(manifest) }.into_string().unwrap();
+        (result, Some(json))
+    }
+
+    #[test]
+    fn test_split_join_round_trip() {
+        let dir = temp_dir("container_split");
+        let mut data = vec![0u8; 10 * 1024 * 1024];
+        rand::rngs::OsRng.fill_bytes(&mut data);
+        let container = encrypt(&data);
+        let source = dir.join("big.cner");
+        fs::write(&source, &container).unwrap();
+
+        let part_limit = 3 * 1024 * 1024;
+        let (result, json) = split(&source, &dir.join("parts"), part_limit);
+        assert_eq!(result, SUCCESS);
+        let json = json.unwrap();
+        let manifest: SplitManifest = serde_json::from_str(&json).unwrap();
+        assert_eq!(manifest.source_size, container.len() as u64);
+        assert_eq!(manifest.chunk_count, 10);
+        assert!(manifest.parts.len() >= 4);
+        for part in &manifest.parts {
+            let bytes = fs::read(&part.path).unwrap();
+            assert_eq!(bytes.len() as u64, part.length);
+            assert!(part.length <= part_limit);
+            assert_eq!(bytes, &container[part.offset as usize..(part.offset + part.length) as usize]);
+        }
+        assert!(manifest.parts[0].path.ends_with("big.cner.part000"));
+        assert_eq!(&fs::read(&manifest.parts[0].path).unwrap()[..4], &MAGIC.to_le_bytes());
+
+        let output = dir.join("joined.cner");
+        let manifest_c = CString::new(json.clone()).unwrap();
+        assert_eq!(join_encrypted_parts(manifest_c.as_ptr(), c_path(&output).as_ptr()), SUCCESS);
+        let joined = fs::read(&output).unwrap();
+        assert_eq!(joined, container);
+
+        let mut len = 0usize;
+        let plain = decrypt_file_streaming(joined.as_ptr(), joined.len(), KEY.as_ptr(), KEY_SIZE, &mut len,
+                                           None, ptr::null_mut());
+        assert!(!plain.is_null());
+        assert_eq!(blake3::hash(unsafe { slice::from_raw_parts(plain, len) }), blake3::hash(&data));
+        free_buffer(plain);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_join_rejects_out_of_order_parts() {
+        let dir = temp_dir("container_join_order");
+        let mut data = vec![0u8; 4 * 1024 * 1024];
+        rand::rngs::OsRng.fill_bytes(&mut data);
+        let source = dir.join("doc.cner");
+        fs::write(&source, encrypt(&data)).unwrap();
+
+        let (result, json) = split(&source, &dir, 2 * 1024 * 1024);
+        assert_eq!(result, SUCCESS);
+        let manifest: SplitManifest = serde_json::from_str(&json.unwrap()).unwrap();
+        assert!(manifest.parts.len() >= 3);
+        let output = dir.join("joined.cner");
+        let join = |manifest: &SplitManifest| {
+            let json = CString::new(serde_json::to_string(manifest).unwrap()).unwrap();
+            join_encrypted_parts(json.as_ptr(), c_path(&output).as_ptr())
+        };
+
+        // Listing the parts out of order
+        let mut reordered = manifest.clone();
+        reordered.parts.swap(1, 2);
+        assert_eq!(join(&reordered), ERROR_MANIFEST_INVALID);
+
+        // Renumbered consistently, but the part files hold other chunks
+        let mut swapped = manifest.clone();
+        let (first, second) = (swapped.parts[1].path.clone(), swapped.parts[2].path.clone());
+        swapped.parts[1].path = second;
+        swapped.parts[2].path = first;
+        assert_eq!(swapped.parts[1].length, swapped.parts[2].length);
+        assert_eq!(join(&swapped), ERROR_MANIFEST_INVALID);
+
+        // A gap between parts
+        let mut gap = manifest.clone();
+        gap.parts[1].offset += 1;
+        assert_eq!(join(&gap), ERROR_MANIFEST_INVALID);
+        assert!(!output.exists());
+
+        let invalid = CString::new("{not json").unwrap();
+        assert_eq!(join_encrypted_parts(invalid.as_ptr(), c_path(&output).as_ptr()), ERROR_INVALID_JSON);
+        assert_eq!(join(&manifest), SUCCESS);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_split_rejects_non_containers_and_small_limits() {
+        let dir = temp_dir("container_split_invalid");
+        let plain = dir.join("plain.txt");
+        fs::write(&plain, vec![b'x'; 4096]).unwrap();
+        assert_eq!(split(&plain, &dir.join("parts"), 1024), (ERROR_INVALID_FORMAT, None));
+
+        let source = dir.join("small.cner");
+        let container = encrypt(&vec![1u8; 300 * 1024]);
+        fs::write(&source, &container).unwrap();
+        // A single chunk record does not fit
+        assert_eq!(split(&source, &dir.join("parts"), 64 * 1024), (ERROR_PART_LIMIT_TOO_SMALL, None));
+        assert_eq!(fs::read_dir(dir.join("parts")).unwrap().count(), 0);
+
+        fs::write(&source, &container[..container.len() - 7]).unwrap();
+        assert_eq!(split(&source, &dir.join("parts"), 1024 * 1024), (ERROR_CONTAINER_TRUNCATED, None));
+        assert_eq!(fs::read_dir(dir.join("parts")).unwrap().count(), 0);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
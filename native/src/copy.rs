@@ -1,16 +1,45 @@
 /// Copy operations for CloudNexus
 /// Handles streaming file and folder copies with progress reporting and cancellation
-use std::fs::{self, File, DirBuilder};
-use std::io::{Read, Write, BufReader, BufWriter};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, DirBuilder, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom, BufReader, BufWriter};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicBool;
-use std::ffi::{c_char, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::ffi::{c_char, c_int, c_void, CString};
 use std::ptr;
 use std::slice;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{checked, ERROR_INVALID_OPTIONS};
 use crate::file_io::{ProgressThrottler, ERROR_NULL_POINTER, ERROR_FILE_NOT_FOUND, 
                      ERROR_PERMISSION_DENIED, ERROR_IO_FAILED, ERROR_CANCELLED, 
-                     ERROR_INVALID_PATH, SUCCESS, c_str_to_path, is_cancelled};
+                     ERROR_INVALID_PATH, ERROR_SOURCE_CHANGED, ERROR_MAX_DEPTH_EXCEEDED, SUCCESS, c_str_to_path, is_cancelled,
+                     sanitize_file_name_with, PathSanitizeMode, PathSanitizePolicy,
+                     PartialOutputGuard, cleanup_partial_output, available_space, filesystem_case_sensitive};
+#[cfg(unix)]
+use crate::file_io::file_from_fd;
+#[cfg(windows)]
+use crate::file_io::file_from_handle;
+use crate::trash::{TrashOperation, ERROR_TRASH_NOT_CONFIGURED, trash_directory};
+use crate::governor::TransferPacer;
+use crate::encrypt_copy::{decrypt_copy_file_impl, encrypt_copy_file_impl, is_encrypted_container, CopyTransform};
+use crate::temp::JsonLinesSpill;
+use crate::source_reader::SourceReader;
+use crate::operations::{Operation, OperationKind};
+use crate::ffi_util::{envelope_str, json_envelope, json_envelope_raw, set_last_error_detail, ErrorEnvelope,
+                      ERROR_RESULT_UNAVAILABLE};
+use crate::phase_progress::{PhaseProgressCallback, PhaseTracker, PHASE_COPYING, PHASE_VERIFYING};
+use crate::metrics;
+use crate::runtime::take_chunk_buffer;
+use crate::scan::file_identity;
+use crate::name_order::{SortLocale, ERROR_INVALID_SORT_LOCALE};
+use crate::dest_fs::{check_dest_file, detect_dest_filesystem, DestFilesystem, ERROR_FILE_TOO_LARGE_FOR_FS,
+                     ERROR_NAME_NOT_ALLOWED_ON_FS};
+use crate::open_files::{acquire_open_files, is_out_of_descriptors, open_error_code, open_limited, retry_open,
+                        OpenFilePermit, ERROR_TOO_MANY_OPEN_FILES};
+use crate::xattrs::{copy_attributes, XattrPolicy, ERROR_INVALID_XATTR_POLICY};
 
 /// Progress callback for copy operations
 /// For files: bytes_copied, total_bytes, user_data
@@ -21,6 +50,77 @@ pub type CopyProgressCallback = extern "C" fn(bytes_copied: usize, total_bytes:
 /// Returns the number of bytes read (0 for EOF, negative for error)
 pub type CopyDataCallback = extern "C" fn(data: *mut u8, data_len: usize, user_data: *mut c_void) -> isize;
 
+/// Progress values handed to a callback
+///
+/// Folder and chunked copies invoke their progress and data callbacks only once
+/// the work of the call is done and no reference to the context is held, so a
+/// callback may call back into the library. While it runs, the progress
+/// accessors (folder_copy_get_progress, chunked_copy_get_progress,
+/// chunked_copy_get_progress_ex) return the values the callback was given,
+/// the *_get_operation_id functions and get_operation_progress work as usual,
+/// and cancel_operation takes effect at the next file or chunk. A callback must
+/// not free the context or call a function that advances or configures it.
+#[derive(Debug, Clone, Copy, Default)]
+struct ProgressSnapshot {
+    bytes_read: usize,
+    bytes_done: usize,
+    total_bytes: usize,
+    files_done: usize,
+    total_files: usize,
+}
+
+/// Reentrancy guard of a context whose callbacks may call back into the library
+///
+/// `S` is the progress the context's accessors report.
+#[derive(Default)]
+pub(crate) struct CallbackGuard<S> {
+    in_callback: AtomicBool,
+    snapshot: S,
+}
+
+impl<S: Copy> CallbackGuard<S> {
+    /// Record the values the next callback reports
+    pub(crate) fn capture(&mut self, snapshot: S) -> S {
+        self.snapshot = snapshot;
+        snapshot
+    }
+
+    /// Progress for an accessor: the callback's snapshot while one runs, else `live`
+    pub(crate) fn read(&self, live: S) -> S {
+        if self.in_callback.load(Ordering::Acquire) {
+            self.snapshot
+        } else {
+            live
+        }
+    }
+
+    /// Run a callback with the guard raised
+    ///
+    /// # Safety
+    /// `guard` must point into a live context that nothing borrows mutably
+    /// while the callback runs.
+    pub(crate) unsafe fn invoke<T>(guard: *const Self, callback: impl FnOnce() -> T) -> T {
+        let in_callback = &(*guard).in_callback;
+        in_callback.store(true, Ordering::Release);
+        let result = callback();
+        in_callback.store(false, Ordering::Release);
+        result
+    }
+}
+
+impl CallbackGuard<ProgressSnapshot> {
+    /// Report progress through a copy progress callback with the guard raised
+    ///
+    /// # Safety
+    /// As for invoke.
+    unsafe fn report(guard: *const Self, callback: CopyProgressCallback, progress: ProgressSnapshot,
+                     user_data: *mut c_void) {
+        Self::invoke(guard, || {
+            callback(progress.bytes_done, progress.total_bytes, progress.files_done, progress.total_files, user_data)
+        })
+    }
+}
+
 /// Copy context for folder copy operations
 #[repr(C)]
 pub struct CopyContext {
@@ -49,6 +149,9 @@ impl CopyContext {
 
 /// Copy a single file with streaming
 ///
+/// A cancelled or failed copy deletes the partial destination file
+/// (see copy_file_streaming_with_options to keep it).
+///
 /// # Arguments
 /// * `source_path` - Source file path
 /// * `dest_path` - Destination file path
@@ -68,385 +171,2435 @@ pub extern "C" fn copy_file_streaming(
     cancel_flag: *const AtomicBool,
     user_data: *mut c_void,
 ) -> i32 {
-    if source_path.is_null() || dest_path.is_null() {
-        return ERROR_NULL_POINTER;
+    copy_file_streaming_with_options(source_path, dest_path, chunk_size, ptr::null(), progress_callback,
+                                     cancel_flag, user_data)
+}
+
+/// Options of copy_file_streaming_with_options
+///
+/// Zero-initialize, set `struct_size` to the size of the struct and fill in
+/// what differs from the defaults of copy_file_streaming. Fields are only ever
+/// appended; those past a caller's `struct_size` keep their defaults, so
+/// callers built against an older layout keep working. The out pointers are
+/// optional and only written when not null.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CCopyOptions {
+    /// Size of the caller's struct in bytes
+    pub struct_size: u32,
+    /// 1 to try a reflink before streaming, 0 to always stream
+    pub allow_reflink: u8,
+    /// 1 to memory-map the source when possible, 0 for buffered reads
+    pub use_mmap: u8,
+    /// 1 to leave a partial destination on disk after cancellation or failure
+    pub keep_partial: u8,
+    /// 1 to copy data appended during the copy, 0 to fail with ERROR_SOURCE_CHANGED
+    pub tolerate_growth: u8,
+    /// 1 to read the destination back and compare it with the source
+    pub verify: u8,
+    /// 1 to report what the copy would do without writing anything
+    pub dry_run: u8,
+    /// XATTR_POLICY_IGNORE, XATTR_POLICY_PRESERVE or XATTR_POLICY_STRIP_QUARANTINE_ONLY
+    pub xattr_policy: i32,
+    /// Phased progress callback of verified copies (null to use the progress callback)
+    pub phase_callback: Option<PhaseProgressCallback>,
+    /// Receives 1 if the reflink fast path was used, 0 otherwise
+    pub used_reflink: *mut u8,
+    /// Receives the number of bytes copied
+    pub final_size: *mut u64,
+    /// Receives the number of extended attributes that were not copied
+    pub xattr_failures: *mut u32,
+    /// Receives the dry run's JSON envelope; free with scan_folder_free_string
+    pub dry_run_report: *mut *mut c_char,
+}
+
+impl Default for CCopyOptions {
+    fn default() -> Self {
+        CCopyOptions {
+            struct_size: std::mem::size_of::<CCopyOptions>() as u32,
+            allow_reflink: 0,
+            use_mmap: 0,
+            keep_partial: 0,
+            tolerate_growth: 0,
+            verify: 0,
+            dry_run: 0,
+            xattr_policy: crate::xattrs::XATTR_POLICY_IGNORE,
+            phase_callback: None,
+            used_reflink: ptr::null_mut(),
+            final_size: ptr::null_mut(),
+            xattr_failures: ptr::null_mut(),
+            dry_run_report: ptr::null_mut(),
+        }
     }
+}
 
-    let src = match unsafe { c_str_to_path(source_path) } {
-        Ok(p) => p,
-        Err(_) => return ERROR_INVALID_PATH,
+impl CCopyOptions {
+    /// Copy the caller's options, defaulting fields past its `struct_size`
+    ///
+    /// Null options are the defaults; None if `struct_size` is too small to
+    /// hold itself.
+    ///
+    /// # Safety
+    /// `options` must be null or point to at least `struct_size` readable bytes.
+    unsafe fn read(options: *const CCopyOptions) -> Option<CCopyOptions> {
+        let mut read = CCopyOptions::default();
+        if options.is_null() {
+            return Some(read);
+        }
+        let size = ptr::read_unaligned(options as *const u32) as usize;
+        if size < std::mem::size_of::<u32>() {
+            return None;
+        }
+        ptr::copy_nonoverlapping(options as *const u8, &mut read as *mut CCopyOptions as *mut u8,
+                                 size.min(std::mem::size_of::<CCopyOptions>()));
+        Some(read)
+    }
+}
+
+/// Copy a single file with options
+///
+/// With `allow_reflink`, the copy is first attempted as a reflink (clonefile
+/// on APFS, FICLONE on Btrfs/XFS). When the filesystem does not support it,
+/// the paths are on different filesystems, or the destination already exists,
+/// the regular streaming copy is used instead. The progress callback is then
+/// called once with the full size.
+///
+/// With `use_mmap`, the streaming copy reads a memory map of the source. The
+/// map is skipped for network and removable volumes, and reads switch back to
+/// buffered I/O if the source changes size during the copy. Chunk sizes,
+/// progress and cancellation behave exactly as with buffered reads.
+///
+/// A source that shrinks during the copy, or is rewritten to a different size,
+/// fails with ERROR_SOURCE_CHANGED. A source that grows (e.g. a log file being
+/// appended to) also fails, unless `tolerate_growth` is set: the copy then
+/// reads on to the new end of the file, progress callbacks report the
+/// corrected total, and `final_size` receives the number of bytes copied.
+///
+/// If the copy is cancelled or fails after the destination was created, the
+/// partial destination is deleted unless `keep_partial` is set.
+///
+/// With `verify`, the source is hashed while it is copied; once the
+/// destination is synced to disk it is read back and its hash compared, and a
+/// mismatch fails with ERROR_COPY_VERIFY_FAILED. The destination is deleted on
+/// any failure, and reflinks, memory maps, `keep_partial` and
+/// `tolerate_growth` are not used. With a `phase_callback`, progress is
+/// reported as PHASE_COPYING while the destination is written, then
+/// PHASE_VERIFYING while it is read back, both against the file size;
+/// otherwise the progress callback counts both passes against twice the size.
+///
+/// After a successful copy, `xattr_policy` is applied: extended attributes
+/// (including the macOS resource fork) on Unix, named alternate data streams
+/// on Windows. Attributes that cannot be copied do not fail the copy; their
+/// number is stored in `xattr_failures`.
+///
+/// Before the destination is created, its filesystem is checked: a file of
+/// 4 GiB or more fails on FAT with ERROR_FILE_TOO_LARGE_FOR_FS, and a name FAT
+/// or exFAT cannot store fails with ERROR_NAME_NOT_ALLOWED_ON_FS;
+/// get_last_error_json has the details.
+///
+/// With `dry_run`, nothing is written: the destination is evaluated the way
+/// the copy would, as a new file, an existing file that would be overwritten,
+/// or a conflict that would make the copy fail (a directory in the way, or a
+/// missing parent directory). `dry_run_report` receives a JSON envelope (see
+/// ffi_util.rs) whose `data` is a CopyDryRunReport (see
+/// folder_copy_get_manifest_json), and the progress callback fires once with
+/// the full size. The other options are ignored.
+///
+/// # Arguments
+/// * `source_path` - Source file path
+/// * `dest_path` - Destination file path
+/// * `chunk_size` - Size of chunks in bytes
+/// * `options` - Copy options (see CCopyOptions; can be null for the defaults)
+/// * `progress_callback` - Progress callback
+/// * `cancel_flag` - Cancellation flag
+/// * `user_data` - User data (also passed to the phase callback)
+///
+/// # Returns
+/// 0 on success, ERROR_INVALID_OPTIONS for a `struct_size` below 4,
+/// ERROR_INVALID_XATTR_POLICY for an unknown policy, ERROR_SOURCE_CHANGED if the
+/// source changed size, ERROR_COPY_VERIFY_FAILED if the destination does not
+/// match the source, other error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn copy_file_streaming_with_options(
+    source_path: *const c_char,
+    dest_path: *const c_char,
+    chunk_size: usize,
+    options: *const CCopyOptions,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    let Some(options) = (unsafe { CCopyOptions::read(options) }) else {
+        return ERROR_INVALID_OPTIONS;
     };
+    if !options.used_reflink.is_null() {
+        unsafe { *options.used_reflink = 0; }
+    }
+    if !options.xattr_failures.is_null() {
+        unsafe { *options.xattr_failures = 0; }
+    }
 
-    let dst = match unsafe { c_str_to_path(dest_path) } {
-        Ok(p) => p,
-        Err(_) => return ERROR_INVALID_PATH,
+    if options.dry_run != 0 {
+        let report = copy_file_dry_run(source_path, dest_path, progress_callback, user_data);
+        let code = match &report {
+            Ok(_) => SUCCESS,
+            Err(envelope) => envelope.code,
+        };
+        if !options.dry_run_report.is_null() {
+            unsafe { *options.dry_run_report = json_envelope(report, ptr::null_mut()); }
+        }
+        return checked(code);
+    }
+
+    let Some(policy) = XattrPolicy::from_code(options.xattr_policy) else {
+        return ERROR_INVALID_XATTR_POLICY;
     };
 
-    // Get source file size
-    let metadata = match src.metadata() {
-        Ok(m) => m,
-        Err(_) => return ERROR_FILE_NOT_FOUND,
+    let result = if options.verify != 0 {
+        copy_file_verified(source_path, dest_path, chunk_size, options.phase_callback, progress_callback,
+                           cancel_flag, user_data)
+    } else {
+        copy_file_tolerant_impl(source_path, dest_path, chunk_size, options.allow_reflink != 0,
+                                options.use_mmap != 0, options.keep_partial != 0, options.tolerate_growth != 0,
+                                progress_callback, cancel_flag, user_data, options.used_reflink)
+    };
+    metrics::count_copy(result.map(|size| size as u64));
+    let size = match result {
+        Ok(size) => size,
+        Err(code) => return checked(code),
     };
+    if !options.final_size.is_null() {
+        unsafe { *options.final_size = size as u64; }
+    }
+
+    let src = unsafe { c_str_to_path(source_path) }.map_err(|_| ERROR_INVALID_PATH);
+    let dst = unsafe { c_str_to_path(dest_path) }.map_err(|_| ERROR_INVALID_PATH);
+    if let (Ok(src), Ok(dst)) = (src, dst) {
+        let failures = copy_attributes(&src, &dst, policy);
+        if !options.xattr_failures.is_null() {
+            unsafe { *options.xattr_failures = failures.len() as u32; }
+        }
+    }
+    SUCCESS
+}
+
+/// Copy a single file for copy_file_streaming_with_options, returning the size of the copy
+#[allow(clippy::too_many_arguments)]
+fn copy_file_tolerant_impl(
+    source_path: *const c_char,
+    dest_path: *const c_char,
+    chunk_size: usize,
+    allow_reflink: bool,
+    use_mmap: bool,
+    keep_partial: bool,
+    tolerate_growth: bool,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+    used_reflink: *mut u8,
+) -> Result<usize, i32> {
+    if source_path.is_null() || dest_path.is_null() {
+        return Err(ERROR_NULL_POINTER);
+    }
+
+    let src = unsafe { c_str_to_path(source_path) }.map_err(|_| ERROR_INVALID_PATH)?;
+    let dst = unsafe { c_str_to_path(dest_path) }.map_err(|_| ERROR_INVALID_PATH)?;
 
+    // Get source file size
+    let metadata = src.metadata().map_err(|_| ERROR_FILE_NOT_FOUND)?;
     if !metadata.is_file() {
-        return ERROR_INVALID_PATH;
+        return Err(ERROR_INVALID_PATH);
     }
 
     let total_bytes = metadata.len() as usize;
+
+    // Fail before creating the destination if its filesystem cannot hold the file
+    check_dest_file(&dst, total_bytes as u64)?;
+
+    if allow_reflink {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err(ERROR_CANCELLED);
+        }
+
+        if try_reflink(&src, &dst) {
+            if !used_reflink.is_null() {
+                unsafe { *used_reflink = 1; }
+            }
+            // The clone is a snapshot, whatever happened to the source meanwhile
+            let cloned_size = dst.metadata().map(|m| m.len() as usize).unwrap_or(total_bytes);
+            if let Some(cb) = progress_callback {
+                cb(cloned_size, cloned_size, 1, 1, user_data);
+            }
+            return Ok(cloned_size);
+        }
+    }
+
+    copy_file_streaming_impl(&src, &dst, total_bytes, chunk_size, use_mmap, keep_partial, tolerate_growth,
+                             progress_callback, cancel_flag, user_data)
+}
+
+fn copy_file_dry_run(
+    source_path: *const c_char,
+    dest_path: *const c_char,
+    progress_callback: Option<CopyProgressCallback>,
+    user_data: *mut c_void,
+) -> Result<CopyDryRunReport, ErrorEnvelope> {
+    let src = PathBuf::from(unsafe { envelope_str(source_path, "source_path") }?);
+    let dst = PathBuf::from(unsafe { envelope_str(dest_path, "dest_path") }?);
+
+    let metadata = src.metadata()
+        .map_err(|e| ErrorEnvelope::new(ERROR_FILE_NOT_FOUND, e.to_string()).with_context("source_path"))?;
+    if !metadata.is_file() {
+        return Err(ErrorEnvelope::new(ERROR_INVALID_PATH, "source is not a file").with_context("source_path"));
+    }
+
+    let mut report = CopyDryRunReport::default();
+    let dest_name = dst.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let parent_exists = dst.parent().is_some_and(|p| p.as_os_str().is_empty() || p.is_dir());
+    if !parent_exists && !dst.exists() {
+        report.conflicts.push(CopyConflict {
+            dest_relative_path: dest_name,
+            kind: CopyConflictKind::MissingParent,
+            resolution: CopyConflictResolution::Fail,
+        });
+        report.files_to_fail += 1;
+    } else {
+        // Conflicts are recorded in the report; the result only matters to folder copies
+        let _ = report.plan_file(&src, &dst, dest_name, false);
+    }
+    report.finish(&dst);
+
+    if let Some(cb) = progress_callback {
+        cb(metadata.len() as usize, metadata.len() as usize, 1, 1, user_data);
+    }
+    Ok(report)
+}
+
+/// Verified copy for copy_file_streaming_with_options, returning the size of the copy
+///
+/// Progress goes to `phase_callback` if set, else to `progress_callback`
+/// counting both passes against twice the size.
+fn copy_file_verified(
+    source_path: *const c_char,
+    dest_path: *const c_char,
+    chunk_size: usize,
+    phase_callback: Option<PhaseProgressCallback>,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> Result<usize, i32> {
+    if source_path.is_null() || dest_path.is_null() {
+        return Err(ERROR_NULL_POINTER);
+    }
+
+    let src = unsafe { c_str_to_path(source_path) }.map_err(|_| ERROR_INVALID_PATH)?;
+    let dst = unsafe { c_str_to_path(dest_path) }.map_err(|_| ERROR_INVALID_PATH)?;
+    if src == dst {
+        return Err(ERROR_INVALID_PATH);
+    }
+
+    let mut tracker = PhaseTracker::new();
     let mut throttler = ProgressThrottler::new(500);
-    let mut bytes_copied = 0;
+    let mut report = |phase: u32, done: usize, size: usize| {
+        if phase_callback.is_some() {
+            tracker.report(phase_callback, user_data, phase, done as u64, size as u64);
+        } else if let Some(cb) = progress_callback {
+            let verifying = phase == PHASE_VERIFYING;
+            let done = if verifying { size + done } else { done };
+            if throttler.should_update_with(done, size * 2, verifying && done == size) {
+                cb(done, size * 2, 1, 1, user_data);
+            }
+        }
+    };
+    copy_file_verified_impl(&src, &dst, chunk_size, cancel_flag, &mut report)
+}
+
+/// Copy `src` to `dst` while hashing it, then read `dst` back and compare
+///
+/// `progress` receives (phase, bytes done in the phase, file size) for
+/// PHASE_COPYING and then PHASE_VERIFYING, each starting with a report of 0.
+/// Returns the size of the file copied.
+fn copy_file_verified_impl(
+    src: &Path,
+    dst: &Path,
+    chunk_size: usize,
+    cancel_flag: *const AtomicBool,
+    progress: &mut dyn FnMut(u32, usize, usize),
+) -> Result<usize, i32> {
+    let metadata = src.metadata().map_err(|_| ERROR_FILE_NOT_FOUND)?;
+    if !metadata.is_file() {
+        return Err(ERROR_INVALID_PATH);
+    }
+    let total_bytes = metadata.len() as usize;
+
+    // The source stays open while the destination is written, then read back
+    let _open_files = acquire_open_files(2)?;
+    let mut reader = retry_open(|| SourceReader::open(src, false))
+        .map_err(|e| open_error_code(&e, ERROR_FILE_NOT_FOUND))?;
+    let dst_file = retry_open(|| File::create(dst)).map_err(|e| open_error_code(&e, ERROR_PERMISSION_DENIED))?;
+    let partial = PartialOutputGuard::new(dst, false);
+
+    // Copy pass, hashing exactly what is written
+    let mut writer = HashingWriter { inner: BufWriter::new(dst_file), hasher: blake3::Hasher::new() };
+    progress(PHASE_COPYING, 0, total_bytes);
+    stream_copy_with(&mut reader, &mut writer, total_bytes, false, chunk_size, cancel_flag,
+                     &mut |done, _| progress(PHASE_COPYING, done, total_bytes))?;
+    let source_hash = writer.hasher.finalize();
+    writer.inner.get_ref().sync_all().map_err(|_| ERROR_IO_FAILED)?;
+    drop(writer);
+
+    // Verification pass, reading back what actually reached the disk
+    progress(PHASE_VERIFYING, 0, total_bytes);
+    let mut dest_reader = retry_open(|| File::open(dst)).map_err(|e| open_error_code(&e, ERROR_IO_FAILED))?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; chunk_size.clamp(64 * 1024, 10 * 1024 * 1024)];
+    let mut verified = 0usize;
+    loop {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err(ERROR_CANCELLED);
+        }
+        let n = match dest_reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => return Err(ERROR_IO_FAILED),
+        };
+        hasher.update(&buffer[..n]);
+        verified += n;
+        progress(PHASE_VERIFYING, verified.min(total_bytes), total_bytes);
+    }
+    if verified != total_bytes || hasher.finalize() != source_hash {
+        return Err(ERROR_COPY_VERIFY_FAILED);
+    }
+
+    partial.complete();
+    Ok(total_bytes)
+}
+
+/// Writer that hashes everything passed through it
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Try to clone a file with a copy-on-write reflink
+///
+/// Returns false (leaving no destination behind) when reflinks are unsupported.
+fn try_reflink(src: &Path, dst: &Path) -> bool {
+    reflink_copy::reflink(src, dst).is_ok()
+}
 
+/// Streaming read/write copy used when no fast path applies
+///
+/// Returns the number of bytes copied.
+#[allow(clippy::too_many_arguments)]
+fn copy_file_streaming_impl(
+    src: &Path,
+    dst: &Path,
+    total_bytes: usize,
+    chunk_size: usize,
+    use_mmap: bool,
+    keep_partial: bool,
+    tolerate_growth: bool,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> Result<usize, i32> {
     // Open source file
-    let src_file = match File::open(&src) {
-        Ok(f) => f,
-        Err(_) => return ERROR_FILE_NOT_FOUND,
-    };
+    let _open_files = acquire_open_files(2)?;
+    let mut reader = retry_open(|| SourceReader::open(src, use_mmap))
+        .map_err(|e| open_error_code(&e, ERROR_FILE_NOT_FOUND))?;
 
     // Create destination file
-    let dst_file = match File::create(&dst) {
-        Ok(f) => f,
-        Err(_) => return ERROR_PERMISSION_DENIED,
-    };
+    let dst_file = retry_open(|| File::create(dst)).map_err(|e| open_error_code(&e, ERROR_PERMISSION_DENIED))?;
+    // Any early return below leaves a partial destination behind
+    let partial = PartialOutputGuard::new(dst, keep_partial);
 
-    let mut reader = BufReader::new(src_file);
     let mut writer = BufWriter::new(dst_file);
+    let copied = stream_copy(&mut reader, &mut writer, total_bytes, tolerate_growth, chunk_size,
+                             progress_callback, cancel_flag, user_data)?;
+    partial.complete();
+    Ok(copied)
+}
+
+/// Copy `reader` to `writer` in chunks with pacing, progress and cancellation
+///
+/// `total_bytes` is the source size when the copy started. Reaching the end of
+/// the file at any other size fails with ERROR_SOURCE_CHANGED, unless the file
+/// grew and `tolerate_growth` is set, in which case the copy continues to the
+/// new end and the progress total follows. Returns the number of bytes copied.
+#[allow(clippy::too_many_arguments)]
+fn stream_copy<W: Write>(
+    reader: &mut SourceReader,
+    writer: &mut W,
+    total_bytes: usize,
+    tolerate_growth: bool,
+    chunk_size: usize,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> Result<usize, i32> {
+    stream_copy_with(reader, writer, total_bytes, tolerate_growth, chunk_size, cancel_flag, &mut |done, total| {
+        // files_processed=1, total_files=1 for single file
+        if let Some(cb) = progress_callback {
+            cb(done, total, 1, 1, user_data);
+        }
+    })
+}
+
+/// stream_copy reporting throttled (bytes copied, total bytes) to a closure
+fn stream_copy_with<W: Write>(
+    reader: &mut SourceReader,
+    writer: &mut W,
+    total_bytes: usize,
+    tolerate_growth: bool,
+    chunk_size: usize,
+    cancel_flag: *const AtomicBool,
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<usize, i32> {
+    let mut throttler = ProgressThrottler::new(500);
+    let mut bytes_copied = 0;
+    let mut total_bytes = total_bytes;
     let chunk_size = chunk_size.max(64 * 1024).min(10 * 1024 * 1024); // 64KB to 10MB
 
-    let mut buffer = vec![0u8; chunk_size];
+    let mut buffer = take_chunk_buffer(chunk_size);
+    let mut pacer = TransferPacer::new();
     
     loop {
         // Check cancellation
         if unsafe { is_cancelled(cancel_flag) } {
-            return ERROR_CANCELLED;
+            return Err(ERROR_CANCELLED);
         }
 
         // Read chunk
         let bytes_read = match reader.read(&mut buffer) {
-            Ok(0) => break, // EOF
+            Ok(0) => {
+                // EOF: the file must still be exactly as long as what was read
+                let current = reader.current_len().map_err(|_| ERROR_IO_FAILED)? as usize;
+                if bytes_copied < total_bytes || current < bytes_copied || (current > bytes_copied && !tolerate_growth) {
+                    return Err(ERROR_SOURCE_CHANGED);
+                }
+                if current > bytes_copied {
+                    // Appended after we reached the old end; keep reading
+                    total_bytes = current;
+                    throttler = ProgressThrottler::new(500);
+                    continue;
+                }
+                break;
+            }
             Ok(n) => n,
-            Err(_) => return ERROR_IO_FAILED,
+            Err(_) => return Err(ERROR_IO_FAILED),
         };
 
         // Write chunk
-        if let Err(_) = writer.write_all(&buffer[..bytes_read]) {
-            return ERROR_IO_FAILED;
+        if writer.write_all(&buffer[..bytes_read]).is_err() {
+            return Err(ERROR_IO_FAILED);
         }
 
         bytes_copied += bytes_read;
 
-        // Progress callback (files_processed=1, total_files=1 for single file)
-        if let Some(cb) = progress_callback {
-            if throttler.should_update(bytes_copied, total_bytes) {
-                cb(bytes_copied, total_bytes, 1, 1, user_data);
+        // Reading past the initial size means the file grew
+        if bytes_copied > total_bytes {
+            if !tolerate_growth {
+                return Err(ERROR_SOURCE_CHANGED);
             }
+            total_bytes = reader.current_len().map(|len| len as usize).unwrap_or(0).max(bytes_copied);
+            // The old total may already have been reported as final
+            throttler = ProgressThrottler::new(500);
+        }
+
+        // Slow down under thermal/battery pressure
+        if !pacer.pace(bytes_read, cancel_flag) {
+            return Err(ERROR_CANCELLED);
+        }
+
+        if throttler.should_update(bytes_copied, total_bytes) {
+            progress(bytes_copied, total_bytes);
         }
     }
 
-    // Final progress update
-    if let Some(cb) = progress_callback {
-        cb(total_bytes, total_bytes, 1, 1, user_data);
+    // Final progress update (skipped if the last chunk already reported it)
+    if throttler.finish() {
+        progress(bytes_copied, bytes_copied);
     }
 
     // Flush writer
-    if let Err(_) = writer.flush() {
-        return ERROR_IO_FAILED;
+    if writer.flush().is_err() {
+        return Err(ERROR_IO_FAILED);
     }
 
-    SUCCESS
+    Ok(bytes_copied)
 }
 
-/// Alias for copy_file_streaming for FFI compatibility
-#[no_mangle]
-pub extern "C" fn copy_file(
+/// Copy a file into a destination the caller already opened
+///
+/// Shared by the descriptor and handle variants. The destination is written
+/// from its current position and flushed; a cancelled or failed copy leaves it
+/// as is, since only the caller knows how to discard it. A source that changes
+/// size fails with ERROR_SOURCE_CHANGED.
+fn copy_file_to_open_dest(
     source_path: *const c_char,
-    dest_path: *const c_char,
+    dest: std::io::Result<File>,
     chunk_size: usize,
     progress_callback: Option<CopyProgressCallback>,
     cancel_flag: *const AtomicBool,
     user_data: *mut c_void,
 ) -> i32 {
-    copy_file_streaming(
-        source_path,
-        dest_path,
-        chunk_size,
-        progress_callback,
-        cancel_flag,
-        user_data,
-    )
+    let result = copy_into_open_dest(source_path, dest, chunk_size, progress_callback, cancel_flag, user_data);
+    metrics::count_copy(result.map(|copied| copied as u64));
+    match result {
+        Ok(_) => SUCCESS,
+        Err(code) => code,
+    }
 }
 
-/// Copy context for folder copy
-#[repr(C)]
-pub struct FolderCopyContext {
-    source_root: PathBuf,
-    dest_root: PathBuf,
-    bytes_copied: usize,
-    total_bytes: usize,
-    files_processed: usize,
-    total_files: usize,
+/// Copy for copy_file_to_open_dest, returning the number of bytes copied
+fn copy_into_open_dest(
+    source_path: *const c_char,
+    dest: std::io::Result<File>,
+    chunk_size: usize,
+    progress_callback: Option<CopyProgressCallback>,
     cancel_flag: *const AtomicBool,
-    progress_throttler: ProgressThrottler,
-}
-
-impl FolderCopyContext {
-    pub fn new(source_root: PathBuf, dest_root: PathBuf, total_bytes: usize, 
-               total_files: usize, cancel_flag: *const AtomicBool) -> Self {
-        Self {
-            source_root,
-            dest_root,
-            bytes_copied: 0,
-            total_bytes,
-            files_processed: 0,
-            total_files,
-            cancel_flag,
-            progress_throttler: ProgressThrottler::new(500),
-        }
+    user_data: *mut c_void,
+) -> Result<usize, i32> {
+    if source_path.is_null() {
+        return Err(ERROR_NULL_POINTER);
     }
+
+    let src = unsafe { c_str_to_path(source_path) }.map_err(|_| ERROR_INVALID_PATH)?;
+
+    let total_bytes = match src.metadata() {
+        Ok(m) if m.is_file() => m.len() as usize,
+        Ok(_) => return Err(ERROR_INVALID_PATH),
+        Err(_) => return Err(ERROR_FILE_NOT_FOUND),
+    };
+
+    let (mut reader, _open_files) = open_limited(|| SourceReader::open(&src, false), ERROR_FILE_NOT_FOUND)?;
+    let dest = dest.map_err(|_| ERROR_INVALID_PATH)?;
+
+    // Dropping the writer closes our duplicate only
+    let mut writer = BufWriter::new(dest);
+    stream_copy(&mut reader, &mut writer, total_bytes, false, chunk_size, progress_callback, cancel_flag, user_data)
 }
 
-/// Initialize folder copy context
+/// Copy a file into an open file descriptor (e.g. an Android SAF destination)
+///
+/// The descriptor is duplicated, so the caller keeps ownership of `dest_fd` and
+/// must close it; this function never closes it. The duplicate shares the file
+/// offset, so data is written from the descriptor's current position.
 ///
 /// # Arguments
-/// * `source_folder` - Source folder path
-/// * `dest_folder` - Destination folder path
+/// * `source_path` - Source file path
+/// * `dest_fd` - Writable file descriptor owned by the caller
+/// * `chunk_size` - Size of chunks in bytes
+/// * `progress_callback` - Progress callback
 /// * `cancel_flag` - Cancellation flag
+/// * `user_data` - User data
 ///
 /// # Returns
-/// Pointer to FolderCopyContext, or null on error
+/// 0 on success, error code on failure
+#[cfg(unix)]
 #[no_mangle]
-pub extern "C" fn folder_copy_init(
-    source_folder: *const c_char,
-    dest_folder: *const c_char,
+pub extern "C" fn copy_file_to_fd(
+    source_path: *const c_char,
+    dest_fd: c_int,
+    chunk_size: usize,
+    progress_callback: Option<CopyProgressCallback>,
     cancel_flag: *const AtomicBool,
-) -> *mut FolderCopyContext {
-    if source_folder.is_null() || dest_folder.is_null() {
-        return ptr::null_mut();
-    }
+    user_data: *mut c_void,
+) -> i32 {
+    checked(copy_file_to_open_dest(source_path, file_from_fd(dest_fd), chunk_size,
+                                   progress_callback, cancel_flag, user_data))
+}
 
-    let src = match unsafe { c_str_to_path(source_folder) } {
-        Ok(p) => p,
-        Err(_) => return ptr::null_mut(),
-    };
+/// Copy a file into an open file HANDLE
+///
+/// Windows equivalent of copy_file_to_fd. The handle is duplicated, so the
+/// caller keeps ownership of `dest_handle` and must close it.
+///
+/// # Arguments
+/// * `source_path` - Source file path
+/// * `dest_handle` - Writable file HANDLE owned by the caller
+/// * `chunk_size` - Size of chunks in bytes
+/// * `progress_callback` - Progress callback
+/// * `cancel_flag` - Cancellation flag
+/// * `user_data` - User data
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[cfg(windows)]
+#[no_mangle]
+pub extern "C" fn copy_file_to_handle(
+    source_path: *const c_char,
+    dest_handle: *mut c_void,
+    chunk_size: usize,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    checked(copy_file_to_open_dest(source_path, file_from_handle(dest_handle), chunk_size,
+                                   progress_callback, cancel_flag, user_data))
+}
 
-    let dst = match unsafe { c_str_to_path(dest_folder) } {
-        Ok(p) => p,
-        Err(_) => return ptr::null_mut(),
-    };
+/// Alias for copy_file_streaming for FFI compatibility
+#[no_mangle]
+pub extern "C" fn copy_file(
+    source_path: *const c_char,
+    dest_path: *const c_char,
+    chunk_size: usize,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    copy_file_streaming(
+        source_path,
+        dest_path,
+        chunk_size,
+        progress_callback,
+        cancel_flag,
+        user_data,
+    )
+}
 
-    // Create destination folder if it doesn't exist
-    if let Err(_) = DirBuilder::new().create(&dst) {
-        return ptr::null_mut();
-    }
+/// Default number of manifest entries kept in memory before they move to a temp file
+pub const DEFAULT_COPY_MANIFEST_SPILL_THRESHOLD: usize = 10_000;
+
+/// Outcome of a single file in a folder copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FolderCopyFileStatus {
+    Copied,
+    /// The source file disappeared before it could be copied
+    Skipped,
+    Failed,
+}
 
-    // Count files and total size
-    let (total_files, total_bytes) = match count_files_and_size(&src) {
-        Ok(result) => result,
-        Err(_) => return ptr::null_mut(),
-    };
+/// Folder copy manifest entry describing one source file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FolderCopyManifestEntry {
+    /// Path relative to the source root ('/' separated)
+    pub relative_path: String,
+    /// Path relative to the destination root after name sanitization
+    pub dest_relative_path: String,
+    pub source_size: u64,
+    pub dest_size: u64,
+    /// Source modification time at copy time (ms since epoch)
+    pub source_mtime_ms: Option<u64>,
+    pub duration_ms: u64,
+    pub status: FolderCopyFileStatus,
+    pub error: Option<String>,
+    /// Destination path the file was renamed from because a file of the same
+    /// name, after sanitizing or up to case, was already written there by this copy
+    #[serde(default)]
+    pub renamed_from: Option<String>,
+    /// Time spent reading the source (0 for cloned, encrypted and decrypted copies)
+    #[serde(default)]
+    pub read_ms: u64,
+    /// Time spent writing the destination (0 for cloned, encrypted and decrypted copies)
+    #[serde(default)]
+    pub write_ms: u64,
+    /// Time spent flushing the destination after the last write
+    #[serde(default)]
+    pub flush_ms: u64,
+    /// Extended attributes or named streams that could not be copied ("name: reason")
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub xattr_errors: Vec<String>,
+}
 
-    let context = Box::new(FolderCopyContext::new(
-        src, dst, total_bytes, total_files, cancel_flag,
-    ));
+/// Time one file copy spent in each phase, measured by copy_single_file
+#[derive(Debug, Clone, Copy, Default)]
+struct CopyFileTiming {
+    read: Duration,
+    write: Duration,
+    flush: Duration,
+}
 
-    Box::leak(context) as *mut FolderCopyContext
+/// Where a folder copy spent its time, reported with the manifest
+///
+/// The totals add up the per-file manifest values; percentiles are over the
+/// per-file duration_ms (nearest rank).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FolderCopyTimingSummary {
+    /// Time spent listing the source tree to plan the copy
+    pub enumeration_ms: u64,
+    /// Sum of the per-file durations
+    pub transfer_ms: u64,
+    pub read_ms: u64,
+    pub write_ms: u64,
+    pub flush_ms: u64,
+    pub file_duration_p50_ms: u64,
+    pub file_duration_p95_ms: u64,
 }
 
-/// Count files and total size in a folder
-fn count_files_and_size(path: &Path) -> Result<(usize, usize), std::io::Error> {
-    let mut file_count = 0;
-    let mut total_size = 0;
+/// Manifest entries of a folder copy, spilled to a temp file past a threshold
+struct FolderCopyManifest {
+    entries: Vec<FolderCopyManifestEntry>,
+    spill: Option<JsonLinesSpill>,
+    spill_threshold: usize,
+    copied: usize,
+    skipped: usize,
+    failed: usize,
+    /// Per-file durations, kept apart from spilled entries for the percentiles
+    durations_ms: Vec<u64>,
+    timing: FolderCopyTimingSummary,
+    /// Source files created after the plan was built, which were not copied
+    appeared_during_copy: Vec<String>,
+    /// Filesystem of the destination
+    filesystem: DestFilesystem,
+}
 
-    if path.is_file() {
-        return Ok((1, path.metadata()?.len() as usize));
+impl FolderCopyManifest {
+    fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            spill: None,
+            spill_threshold: DEFAULT_COPY_MANIFEST_SPILL_THRESHOLD,
+            copied: 0,
+            skipped: 0,
+            failed: 0,
+            durations_ms: Vec::new(),
+            timing: FolderCopyTimingSummary::default(),
+            appeared_during_copy: Vec::new(),
+            filesystem: DestFilesystem::Other,
+        }
     }
 
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let entry_path = entry.path();
-        
-        if entry_path.is_file() {
-            file_count += 1;
-            total_size += entry_path.metadata()?.len() as usize;
-        } else if entry_path.is_dir() {
-            let (count, size) = count_files_and_size(&entry_path)?;
-            file_count += count;
-            total_size += size;
+    fn push(&mut self, entry: FolderCopyManifestEntry) -> std::io::Result<()> {
+        match entry.status {
+            FolderCopyFileStatus::Copied => self.copied += 1,
+            FolderCopyFileStatus::Skipped => self.skipped += 1,
+            FolderCopyFileStatus::Failed => self.failed += 1,
         }
+        self.durations_ms.push(entry.duration_ms);
+        self.timing.transfer_ms += entry.duration_ms;
+        self.timing.read_ms += entry.read_ms;
+        self.timing.write_ms += entry.write_ms;
+        self.timing.flush_ms += entry.flush_ms;
+
+        if let Some(spill) = self.spill.as_mut() {
+            return spill.push(&entry);
+        }
+
+        self.entries.push(entry);
+        if self.entries.len() > self.spill_threshold {
+            let mut spill = JsonLinesSpill::create(&std::env::temp_dir(), "copy-manifest")?;
+            for entry in self.entries.drain(..) {
+                spill.push(&entry)?;
+            }
+            self.entries.shrink_to_fit();
+            self.spill = Some(spill);
+        }
+        Ok(())
     }
 
-    Ok((file_count, total_size))
+    fn finish(&mut self) -> std::io::Result<()> {
+        match self.spill.as_mut() {
+            Some(spill) => spill.finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn to_json(&self, dry_run: Option<&CopyDryRunReport>) -> std::io::Result<String> {
+        let entries = match &self.spill {
+            Some(spill) => format!("[{}]", spill.read_json_lines(0, spill.len())?.join(",")),
+            None => serde_json::to_string(&self.entries)?,
+        };
+        Ok(format!(
+            "{{\"copied\":{},\"skipped\":{},\"failed\":{},\"entries\":{},\"timing\":{},\"appeared_during_copy\":{},\"filesystem\":{},\"dry_run\":{}}}",
+            self.copied, self.skipped, self.failed, entries, serde_json::to_string(&self.timing_summary())?,
+            serde_json::to_string(&self.appeared_during_copy)?, serde_json::to_string(&self.filesystem)?,
+            serde_json::to_string(&dry_run)?
+        ))
+    }
+
+    fn timing_summary(&self) -> FolderCopyTimingSummary {
+        let mut durations = self.durations_ms.clone();
+        durations.sort_unstable();
+        let percentile = |p: usize| match durations.len() {
+            0 => 0,
+            n => durations[(n * p).div_ceil(100).max(1) - 1],
+        };
+        FolderCopyTimingSummary {
+            file_duration_p50_ms: percentile(50),
+            file_duration_p95_ms: percentile(95),
+            ..self.timing.clone()
+        }
+    }
 }
 
-/// Copy next file in folder copy operation
-/// Returns 0 when done, 1 when more files to copy, negative on error
-///
-/// # Arguments
-/// * `context` - Pointer to FolderCopyContext
-/// * `progress_callback` - Progress callback
-/// * `user_data` - User data
-///
-/// # Returns
-/// 1 if more files to copy, 0 if done, negative error code
-#[no_mangle]
-pub extern "C" fn folder_copy_next_file(
-    context: *mut FolderCopyContext,
-    progress_callback: Option<CopyProgressCallback>,
-    user_data: *mut c_void,
-) -> i32 {
-    if context.is_null() {
-        return ERROR_NULL_POINTER;
+pub use crate::errors::{ERROR_DEST_IS_FILE, ERROR_DEST_NOT_EMPTY, ERROR_COPY_VERIFY_FAILED, ERROR_DEST_INSIDE_SOURCE,
+                        ERROR_SAME_PATH};
+
+/// What a dry run found at a destination path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyConflictKind {
+    /// A file exists where a file would be written
+    FileExists,
+    /// A directory exists where a file would be written
+    DirectoryExists,
+    /// A file exists where a directory would be created
+    FileInPath,
+    /// The parent directory of a single-file copy does not exist
+    MissingParent,
+}
+
+/// How the copy would resolve a conflict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CopyConflictResolution {
+    Overwrite,
+    /// The existing file is moved to the trash first (folder_copy_set_use_trash)
+    Trash,
+    Fail,
+}
+
+/// A destination path that already holds something
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CopyConflict {
+    /// Path relative to the destination root ('/' separated)
+    pub dest_relative_path: String,
+    pub kind: CopyConflictKind,
+    pub resolution: CopyConflictResolution,
+}
+
+/// What a copy would do, collected by a dry run without writing anything
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CopyDryRunReport {
+    /// Files written to a new destination path
+    pub files_to_copy: u64,
+    /// Files replacing an existing destination file
+    pub files_to_overwrite: u64,
+    /// Files whose source disappeared before they were evaluated
+    pub files_to_skip: u64,
+    /// Files that would fail because of a conflict
+    pub files_to_fail: u64,
+    /// Bytes written by the copy (source sizes; encrypted copies add container overhead)
+    pub bytes_required: u64,
+    /// Bytes of overwritten files released in place (trashed files are not counted)
+    pub bytes_freed: u64,
+    /// Free space on the destination volume, if it could be determined
+    pub available_bytes: Option<u64>,
+    /// Bytes missing on the destination volume (0 if the copy fits)
+    pub shortfall_bytes: u64,
+    pub conflicts: Vec<CopyConflict>,
+    /// Destination directories blocked by a file; everything below them fails
+    #[serde(skip)]
+    blocked_dirs: Vec<PathBuf>,
+}
+
+impl CopyDryRunReport {
+    /// Evaluate a directory the copy would create
+    fn plan_dir(&mut self, dest_path: &Path, dest_relative_path: String) {
+        if dest_path.exists() && !dest_path.is_dir() {
+            self.conflicts.push(CopyConflict {
+                dest_relative_path,
+                kind: CopyConflictKind::FileInPath,
+                resolution: CopyConflictResolution::Fail,
+            });
+            self.blocked_dirs.push(dest_path.to_path_buf());
+        }
     }
 
-    let ctx = unsafe { &mut *context };
+    /// Evaluate one file the same way copy_file_to would copy it
+    fn plan_file(&mut self, src_path: &Path, dest_path: &Path, dest_relative_path: String,
+                 use_trash: bool) -> Result<(), (i32, String)> {
+        let source_size = match src_path.metadata() {
+            Ok(m) if m.is_file() => m.len(),
+            _ => {
+                self.files_to_skip += 1;
+                return Err((ERROR_FILE_NOT_FOUND, "source file no longer exists".to_string()));
+            }
+        };
 
-    // Check if all files processed
-    if ctx.files_processed >= ctx.total_files {
-        return 0;
+        if self.blocked_dirs.iter().any(|dir| dest_path.starts_with(dir)) {
+            self.files_to_fail += 1;
+            return Err((ERROR_DEST_IS_FILE, "a file is in the way of a parent directory".to_string()));
+        }
+
+        let conflict = |kind, resolution| CopyConflict { dest_relative_path: dest_relative_path.clone(), kind, resolution };
+        match dest_path.metadata() {
+            Ok(m) if m.is_dir() => {
+                self.conflicts.push(conflict(CopyConflictKind::DirectoryExists, CopyConflictResolution::Fail));
+                self.files_to_fail += 1;
+                Err((ERROR_IO_FAILED, "a directory is in the way".to_string()))
+            }
+            Ok(m) => {
+                let resolution = if use_trash { CopyConflictResolution::Trash } else { CopyConflictResolution::Overwrite };
+                self.conflicts.push(conflict(CopyConflictKind::FileExists, resolution));
+                if !use_trash {
+                    self.bytes_freed += m.len();
+                }
+                self.files_to_overwrite += 1;
+                self.bytes_required += source_size;
+                Ok(())
+            }
+            Err(_) => {
+                self.files_to_copy += 1;
+                self.bytes_required += source_size;
+                Ok(())
+            }
+        }
     }
 
-    // Check cancellation
-    if unsafe { is_cancelled(ctx.cancel_flag) } {
-        return ERROR_CANCELLED;
+    /// Compare the space needed with the free space of the destination volume
+    fn finish(&mut self, dest_root: &Path) {
+        self.available_bytes = available_space(dest_root);
+        let needed = self.bytes_required.saturating_sub(self.bytes_freed);
+        self.shortfall_bytes = self.available_bytes.map(|a| needed.saturating_sub(a)).unwrap_or(0);
     }
+}
 
-    // Find and copy the next file
-    let mut files_copied_in_call = 0;
-    
-    // Use a simple approach: iterate through source directory
-    let result = copy_next_file_impl(ctx, progress_callback, user_data, &mut files_copied_in_call);
-    
-    result
+/// Names a folder copy wrote into one destination directory
+#[derive(Default)]
+struct WrittenNames {
+    exact: HashSet<String>,
+    folded: HashSet<String>,
 }
 
-fn copy_next_file_impl(
-    ctx: &mut FolderCopyContext,
-    progress_callback: Option<CopyProgressCallback>,
-    user_data: *mut c_void,
-    files_copied_in_call: &mut usize,
-) -> i32 {
-    let mut entries: Vec<_> = match fs::read_dir(&ctx.source_root) {
-        Ok(e) => e.filter_map(|e| e.ok()).collect(),
-        Err(_) => return ERROR_IO_FAILED,
-    };
+impl WrittenNames {
+    /// Whether `name` was written, or with `fold_case` a name differing only in case
+    fn contains(&self, name: &str, fold_case: bool) -> bool {
+        self.exact.contains(name) || (fold_case && self.folded.contains(&name.to_lowercase()))
+    }
 
-    // Sort to maintain consistent order
-    entries.sort_by_key(|e| e.file_name());
+    fn insert(&mut self, name: &str) {
+        self.exact.insert(name.to_string());
+        self.folded.insert(name.to_lowercase());
+    }
+}
 
-    for entry in entries {
-        // Check cancellation
-        if unsafe { is_cancelled(ctx.cancel_flag) } {
-            return ERROR_CANCELLED;
+/// Copy context for folder copy
+#[repr(C)]
+pub struct FolderCopyContext {
+    source_root: PathBuf,
+    dest_root: PathBuf,
+    bytes_copied: usize,
+    total_bytes: usize,
+    files_processed: usize,
+    total_files: usize,
+    cancel_flag: *const AtomicBool,
+    progress_throttler: ProgressThrottler,
+    use_trash: bool,
+    trash: Option<TrashOperation>,
+    allow_reflink: bool,
+    files_reflinked: usize,
+    plan: Option<VecDeque<FolderCopyStep>>,
+    continue_on_error: bool,
+    keep_partial: bool,
+    transform: Option<CopyTransform>,
+    manifest: FolderCopyManifest,
+    /// Set for dry runs, which evaluate every step without writing anything
+    dry_run: Option<CopyDryRunReport>,
+    /// Deepest directory level whose contents are copied (0 for unlimited)
+    max_depth: u64,
+    /// Source-relative directory that made the copy fail with ERROR_MAX_DEPTH_EXCEEDED
+    depth_error_path: Option<PathBuf>,
+    /// How entries are ordered within each directory of the copy plan
+    sort_locale: SortLocale,
+    /// Rename files whose name collides case-insensitively with one already written
+    rename_case_collisions: bool,
+    /// Whether the destination compares names case-insensitively (probed when None)
+    dest_case_insensitive: Option<bool>,
+    /// Names written by this copy, per destination directory (case-folded on a
+    /// case-insensitive destination)
+    written_names: HashMap<String, WrittenNames>,
+    /// Filesystem of the destination, detected at init
+    dest_fs: DestFilesystem,
+    /// Rewrite names the destination filesystem rejects instead of failing them
+    replace_invalid_names: bool,
+    /// What happens to the extended attributes of plain and cloned copies
+    xattr_policy: XattrPolicy,
+    /// Source-relative entries of the copy plan, to tell entries that appeared during the copy
+    planned: HashSet<PathBuf>,
+    /// A planned file vanished or changed size before it was copied
+    drift_detected: bool,
+    /// Re-enumerate the rest of the source once when drift is detected
+    recount_on_drift: bool,
+    recounted: bool,
+    callback_guard: CallbackGuard<ProgressSnapshot>,
+    operation: Operation,
+    is_finalized: bool,
+}
+
+impl FolderCopyContext {
+    pub fn new(source_root: PathBuf, dest_root: PathBuf, total_bytes: usize, 
+               total_files: usize, cancel_flag: *const AtomicBool) -> Self {
+        let operation = Operation::register(OperationKind::FolderCopy, &source_root, &dest_root,
+                                            total_bytes as u64, total_files as u64, cancel_flag);
+        Self {
+            source_root,
+            dest_root,
+            bytes_copied: 0,
+            total_bytes,
+            files_processed: 0,
+            total_files,
+            cancel_flag: operation.cancel_flag(),
+            progress_throttler: ProgressThrottler::new(500),
+            use_trash: false,
+            trash: None,
+            allow_reflink: false,
+            files_reflinked: 0,
+            plan: None,
+            continue_on_error: false,
+            keep_partial: false,
+            transform: None,
+            manifest: FolderCopyManifest::new(),
+            dry_run: None,
+            max_depth: 0,
+            depth_error_path: None,
+            sort_locale: SortLocale::Byte,
+            xattr_policy: XattrPolicy::Ignore,
+            rename_case_collisions: true,
+            dest_case_insensitive: None,
+            written_names: HashMap::new(),
+            dest_fs: DestFilesystem::Other,
+            replace_invalid_names: false,
+            planned: HashSet::new(),
+            drift_detected: false,
+            recount_on_drift: false,
+            recounted: false,
+            callback_guard: CallbackGuard::default(),
+            is_finalized: false,
+            operation,
         }
+    }
+
+    /// Progress, clamped to the totals so a source that changed cannot report over 100%
+    fn progress_snapshot(&self) -> ProgressSnapshot {
+        let bytes_done = self.bytes_copied.min(self.total_bytes);
+        ProgressSnapshot {
+            bytes_read: bytes_done,
+            bytes_done,
+            total_bytes: self.total_bytes,
+            files_done: self.files_processed.min(self.total_files),
+            total_files: self.total_files,
+        }
+    }
 
-        let src_path = entry.path();
-        let file_name = entry.file_name();
-        let dest_path = ctx.dest_root.join(&file_name);
+    /// Encrypt or decrypt files instead of copying them (see encrypt_copy_folder)
+    pub(crate) fn set_transform(&mut self, transform: CopyTransform) {
+        self.transform = Some(transform);
+    }
 
-        if src_path.is_file() {
-            // Copy file
-            if let Err(_) = copy_single_file(&src_path, &dest_path) {
-                return ERROR_IO_FAILED;
+    /// Destination path for a source-relative path, with every component sanitized
+    ///
+    /// On a FAT or exFAT destination, names that volume cannot store fail with
+    /// ERROR_NAME_NOT_ALLOWED_ON_FS (recorded as the last error) unless
+    /// replace_invalid_names is set.
+    fn dest_path_for(&self, rel: &Path) -> Result<PathBuf, (i32, String)> {
+        let fs_mode = match self.replace_invalid_names {
+            true => PathSanitizeMode::Replace,
+            false => PathSanitizeMode::Reject,
+        };
+        let mut dest_path = self.dest_root.clone();
+        for component in rel.components() {
+            // Rewrite names the destination platform cannot store instead of failing the copy.
+            // A component is one name: a `\` in it is an ordinary character on Unix.
+            let name = component.as_os_str().to_string_lossy();
+            let name = match sanitize_file_name_with(&name, &copy_name_policy()) {
+                Ok(name) => name,
+                Err(error) => return Err((ERROR_INVALID_PATH, error.to_string())),
+            };
+            match self.dest_fs.sanitize_name(&name, fs_mode) {
+                Ok(name) => dest_path.push(name),
+                Err(error) => {
+                    let message = self.dest_fs.name_error_message(&error);
+                    let context = rel.to_string_lossy().replace('\\', "/");
+                    set_last_error_detail(ErrorEnvelope::new(ERROR_NAME_NOT_ALLOWED_ON_FS, message.clone()).with_context(context));
+                    return Err((ERROR_NAME_NOT_ALLOWED_ON_FS, message));
+                }
             }
+        }
+        Ok(dest_path)
+    }
 
-            let metadata = src_path.metadata().unwrap();
-            let file_size = metadata.len() as usize;
+    /// Copy one planned file and record it in the manifest
+    ///
+    /// The plan is authoritative: a file that vanished since it was enumerated
+    /// is skipped, and one that grew or shrank replaces its planned size in the
+    /// byte total. Either counts as drift.
+    fn copy_plan_file(&mut self, rel: &Path, planned_size: u64) -> Result<(), i32> {
+        let started = Instant::now();
+        let mut timing = CopyFileTiming::default();
+        let src_path = self.source_root.join(rel);
+        let source_metadata = src_path.metadata().ok();
+        let vanished = source_metadata.is_none();
+        let source_size = source_metadata.as_ref().map(|m| m.len()).unwrap_or(0);
+        let source_mtime_ms = source_metadata
+            .and_then(|m| m.modified().ok())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64);
+
+        if vanished || source_size != planned_size {
+            self.drift_detected = true;
+            self.total_bytes = (self.total_bytes + source_size as usize).saturating_sub(planned_size as usize);
+            self.operation.set_total_bytes(self.total_bytes as u64);
+        }
 
-            ctx.bytes_copied += file_size;
-            ctx.files_processed += 1;
-            *files_copied_in_call += 1;
+        let mut renamed_from = None;
+        let dest_path = match vanished {
+            true => Err((ERROR_FILE_NOT_FOUND, "disappeared during copy, not copied".to_string())),
+            false => self.dest_path_for(rel).map(|p| self.avoid_name_collision(p, &mut renamed_from)),
+        };
+        let result = match &dest_path {
+            Err(error) => Err(error.clone()),
+            // Checked before anything is written, so the volume is not filled with a partial file
+            Ok(_) if self.dest_fs.exceeds_file_size(source_size) => {
+                Err((ERROR_FILE_TOO_LARGE_FOR_FS, self.dest_fs.file_too_large_message(source_size)))
+            }
+            Ok(dest_path) if self.dry_run.is_some() => {
+                let dest_relative_path = self.dest_relative_path(dest_path);
+                let use_trash = self.use_trash;
+                self.dry_run.as_mut().map_or(Ok(()), |report| report.plan_file(&src_path, dest_path, dest_relative_path, use_trash))
+            }
+            Ok(dest_path) => {
+                let result = self.copy_file_to(&src_path, dest_path, &mut timing);
+                metrics::count_copy(result.as_ref().map(|()| source_size).map_err(|(code, _)| *code));
+                result
+            }
+        };
 
-            // Progress callback
-            if let Some(cb) = progress_callback {
-                if ctx.progress_throttler.should_update(ctx.bytes_copied, ctx.total_bytes) {
-                    cb(ctx.bytes_copied, ctx.total_bytes, ctx.files_processed, ctx.total_files, user_data);
-                }
+        let (status, error) = match &result {
+            Ok(()) => {
+                self.bytes_copied += source_size as usize;
+                self.operation.set_bytes_done(self.bytes_copied as u64);
+                (FolderCopyFileStatus::Copied, None)
             }
+            Err((code, message)) if *code == ERROR_FILE_NOT_FOUND => (FolderCopyFileStatus::Skipped, Some(message.clone())),
+            Err((_, message)) => (FolderCopyFileStatus::Failed, Some(message.clone())),
+        };
 
-            // Return 1 to indicate more files may need to be copied
-            return 1;
-        } else if src_path.is_dir() {
-            // Create subdirectory
-            if let Err(_) = DirBuilder::new().create(&dest_path) {
-                return ERROR_PERMISSION_DENIED;
+        let dest_size = match (&result, &dest_path) {
+            // A dry run projects the size of a plain copy
+            (Ok(()), Ok(_)) if self.dry_run.is_some() => source_size,
+            (Ok(()), Ok(dest_path)) => dest_path.metadata().map(|m| m.len()).unwrap_or(0),
+            _ => 0,
+        };
+        // Encrypted and decrypted copies are new files, not clones of the source
+        let xattr_errors = match (&result, &dest_path) {
+            (Ok(()), Ok(dest_path)) if self.dry_run.is_none() && self.transform.is_none() => {
+                copy_attributes(&src_path, dest_path, self.xattr_policy)
             }
+            _ => Vec::new(),
+        };
+        let dest_relative_path = dest_path.map(|p| self.dest_relative_path(&p)).unwrap_or_default();
+
+        let entry = FolderCopyManifestEntry {
+            relative_path: rel.to_string_lossy().replace('\\', "/"),
+            dest_relative_path,
+            source_size,
+            dest_size,
+            source_mtime_ms,
+            duration_ms: started.elapsed().as_millis() as u64,
+            status,
+            error,
+            renamed_from,
+            read_ms: timing.read.as_millis() as u64,
+            write_ms: timing.write.as_millis() as u64,
+            flush_ms: timing.flush.as_millis() as u64,
+            xattr_errors,
+        };
+        if self.manifest.push(entry).is_err() {
+            return Err(ERROR_IO_FAILED);
+        }
+
+        match result {
+            // The copy goes on without a file that vanished after the plan was built
+            Err(_) if vanished => Ok(()),
+            result => result.map_err(|(code, _)| code),
+        }
+    }
+
+    /// Make a freshly built plan the copy's snapshot: its files and their sizes
+    /// become the totals, replacing the estimate counted by init
+    fn install_plan(&mut self, plan: VecDeque<FolderCopyStep>) {
+        self.planned = plan.iter().map(|step| step.rel().to_path_buf()).collect();
+        self.total_files = self.files_processed + plan_file_count(&plan);
+        self.total_bytes = self.bytes_copied + plan_byte_count(&plan);
+        self.operation.set_total_files(self.total_files as u64);
+        self.operation.set_total_bytes(self.total_bytes as u64);
+        self.plan = Some(plan);
+    }
 
-            // Save current state
-            let prev_source_root = ctx.source_root.clone();
-            let prev_dest_root = ctx.dest_root.clone();
+    /// Re-enumerate the source once after drift, replacing the rest of the plan
+    ///
+    /// Entries already handled are not planned again; entries that appeared since
+    /// the first enumeration join the plan. If the source cannot be walked again
+    /// the copy keeps its original plan.
+    fn recount(&mut self) {
+        self.recounted = true;
+        let Ok(fresh) = build_folder_copy_plan(&self.source_root, self.max_depth, self.sort_locale) else {
+            return;
+        };
+        let remaining: HashSet<PathBuf> = self.plan.iter().flatten().map(|step| step.rel().to_path_buf()).collect();
+        let plan: VecDeque<FolderCopyStep> = fresh
+            .into_iter()
+            .filter(|step| remaining.contains(step.rel()) || !self.planned.contains(step.rel()))
+            .collect();
+        self.planned.extend(plan.iter().map(|step| step.rel().to_path_buf()));
+        self.total_files = self.files_processed + plan_file_count(&plan);
+        self.total_bytes = self.bytes_copied + plan_byte_count(&plan);
+        self.operation.set_total_files(self.total_files as u64);
+        self.operation.set_total_bytes(self.total_bytes as u64);
+        self.plan = Some(plan);
+    }
 
-            // Update state for recursive copy
-            ctx.source_root = src_path.clone();
-            ctx.dest_root = dest_path;
+    /// Source files that are not part of the plan, sorted, with '/' separators
+    fn files_appeared_during_copy(&self) -> Vec<String> {
+        let Ok(current) = build_folder_copy_plan(&self.source_root, 0, SortLocale::Byte) else {
+            return Vec::new();
+        };
+        current
+            .into_iter()
+            .filter_map(|step| match step {
+                FolderCopyStep::File(rel, _) if !self.planned.contains(&rel) => {
+                    Some(rel.to_string_lossy().replace('\\', "/"))
+                }
+                _ => None,
+            })
+            .collect()
+    }
 
-            // Recursively copy subdirectory
-            let result = copy_next_file_impl(ctx, progress_callback, user_data, files_copied_in_call);
+    /// Destination path for a file, renamed with a numeric suffix when this copy
+    /// already wrote a file of that name into the same directory
+    ///
+    /// Distinct source names only meet when sanitizing rewrote or truncated one
+    /// of them, and such files are always renamed. On a case-insensitive
+    /// destination, names that differ only in case are renamed unless
+    /// rename_case_collisions is off. On a rename, `renamed_from` receives the
+    /// original destination-relative path.
+    fn avoid_name_collision(&mut self, dest_path: PathBuf, renamed_from: &mut Option<String>) -> PathBuf {
+        let case_insensitive = self.dest_is_case_insensitive();
+        let (Some(parent), Some(name)) = (dest_path.parent(), dest_path.file_name()) else {
+            return dest_path;
+        };
+        let dir_key = match case_insensitive {
+            true => self.dest_relative_path(parent).to_lowercase(),
+            false => self.dest_relative_path(parent),
+        };
+        let name = name.to_string_lossy().to_string();
+        let rename_case_collisions = case_insensitive && self.rename_case_collisions;
+        let written = self.written_names.entry(dir_key).or_default();
 
-            // Restore state
-            ctx.source_root = prev_source_root;
-            ctx.dest_root = prev_dest_root;
+        if !written.contains(&name, rename_case_collisions) {
+            written.insert(&name);
+            return dest_path;
+        }
 
-            if result < 0 {
-                return result; // Error
+        let stem = dest_path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        let extension = dest_path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+        let mut n = 1;
+        let renamed = loop {
+            let candidate = format!("{} ({}){}", stem, n, extension);
+            if !written.contains(&candidate, case_insensitive) {
+                written.insert(&candidate);
+                break parent.join(candidate);
             }
+            n += 1;
+        };
+        *renamed_from = Some(self.dest_relative_path(&dest_path));
+        renamed
+    }
 
-            // If result is 1, we copied something in subdirectory
-            // Continue to find more files
+    /// Whether destination names are compared case-insensitively, probing the volume once
+    fn dest_is_case_insensitive(&mut self) -> bool {
+        if self.dest_case_insensitive.is_none() {
+            self.dest_case_insensitive = Some(filesystem_case_sensitive(&self.dest_root).map(|s| !s).unwrap_or(false));
         }
+        self.dest_case_insensitive.unwrap_or(false)
     }
 
-    // No more files in this directory
-    0
-}
+    /// Destination path relative to the destination root, '/' separated
+    fn dest_relative_path(&self, dest_path: &Path) -> String {
+        dest_path.strip_prefix(&self.dest_root)
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default()
+    }
 
-fn copy_single_file(src: &Path, dst: &Path) -> Result<(), std::io::Error> {
-    let src_file = File::open(src)?;
-    let dst_file = File::create(dst)?;
+    /// Copy a single file, returning an error code and reason on failure
+    fn copy_file_to(&mut self, src_path: &Path, dest_path: &Path, timing: &mut CopyFileTiming) -> Result<(), (i32, String)> {
+        if !src_path.is_file() {
+            return Err((ERROR_FILE_NOT_FOUND, "source file no longer exists".to_string()));
+        }
 
-    let mut reader = BufReader::new(src_file);
-    let mut writer = BufWriter::new(dst_file);
-    let mut buffer = vec![0u8; 1024 * 1024]; // 1MB chunks
+        // Keep the file about to be overwritten in the trash
+        if self.use_trash && dest_path.is_file() {
+            self.trash_existing(dest_path)
+                .map_err(|code| (code, "failed to move the existing file to the trash".to_string()))?;
+        }
 
-    loop {
-        let bytes_read = reader.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
+        // Encrypted and decrypted copies never clone and always delete a failed destination;
+        // files that are not containers are copied verbatim when decrypting
+        match &self.transform {
+            Some(CopyTransform::Encrypt(options)) => {
+                return encrypt_copy_file_impl(src_path, dest_path, options, self.cancel_flag, &mut |_, _, _| {})
+                    .map_err(|code| (code, "failed to encrypt and verify the file".to_string()));
+            }
+            Some(CopyTransform::Decrypt { master_key }) if is_encrypted_container(src_path) => {
+                return decrypt_copy_file_impl(src_path, dest_path, master_key, self.cancel_flag, &mut |_, _| {})
+                    .map_err(|code| (code, "failed to decrypt the container".to_string()));
+            }
+            _ => {}
         }
-        writer.write_all(&buffer[..bytes_read])?;
+
+        // Copy file, cloning it when the fast path is enabled and available
+        if self.allow_reflink && !dest_path.exists() && try_reflink(src_path, dest_path) {
+            self.files_reflinked += 1;
+            return Ok(());
+        }
+        let _open_files = acquire_open_files(2)
+            .map_err(|code| (code, "no open file permit freed up in time".to_string()))?;
+        copy_single_file(src_path, dest_path, self.keep_partial, self.cancel_flag, timing).map_err(|e| {
+            let code = match e.kind() {
+                _ if is_out_of_descriptors(&e) => ERROR_TOO_MANY_OPEN_FILES,
+                std::io::ErrorKind::Interrupted => ERROR_CANCELLED,
+                std::io::ErrorKind::PermissionDenied => ERROR_PERMISSION_DENIED,
+                _ => ERROR_IO_FAILED,
+            };
+            (code, e.to_string())
+        })
+    }
+
+    /// Move an existing destination file into this copy's trash operation
+    fn trash_existing(&mut self, dest_path: &Path) -> Result<(), i32> {
+        if self.trash.is_none() {
+            self.trash = Some(TrashOperation::begin()?);
+        }
+        match self.trash.as_mut() {
+            Some(trash) => trash.move_to_trash(dest_path),
+            None => Err(ERROR_IO_FAILED),
+        }
+    }
+}
+
+/// Initialize folder copy context
+///
+/// The destination folder and any missing parents are created; an existing
+/// destination folder is reused, e.g. when retrying a partial copy. A
+/// destination that is the source itself or lies inside it (also through a
+/// symlink or junction) is refused before anything is created, with
+/// ERROR_SAME_PATH or ERROR_DEST_INSIDE_SOURCE; get_last_error_json names both.
+///
+/// # Arguments
+/// * `source_folder` - Source folder path
+/// * `dest_folder` - Destination folder path
+/// * `cancel_flag` - Cancellation flag
+///
+/// # Returns
+/// Pointer to FolderCopyContext, or null on error
+#[no_mangle]
+pub extern "C" fn folder_copy_init(
+    source_folder: *const c_char,
+    dest_folder: *const c_char,
+    cancel_flag: *const AtomicBool,
+) -> *mut FolderCopyContext {
+    folder_copy_init_with_options(source_folder, dest_folder, ptr::null(), cancel_flag, ptr::null_mut())
+}
+
+/// Options of folder_copy_init_with_options
+///
+/// Zero-initialize, set `struct_size` to the size of the struct and fill in
+/// what differs from the defaults of folder_copy_init. Fields are only ever
+/// appended; those past a caller's `struct_size` keep their defaults.
+/// Options that can change between files have folder_copy_set_* setters instead.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CFolderCopyOptions {
+    /// Size of the caller's struct in bytes
+    pub struct_size: u32,
+    /// 1 to fail with ERROR_DEST_NOT_EMPTY if the destination folder has entries
+    pub require_empty_dest: u8,
+    /// 1 to only report what the copy would do, 0 to copy
+    pub dry_run: u8,
+}
+
+impl Default for CFolderCopyOptions {
+    fn default() -> Self {
+        CFolderCopyOptions {
+            struct_size: std::mem::size_of::<CFolderCopyOptions>() as u32,
+            require_empty_dest: 0,
+            dry_run: 0,
+        }
+    }
+}
+
+impl CFolderCopyOptions {
+    /// Copy the caller's options, defaulting fields past its `struct_size`
+    ///
+    /// Null options are the defaults; None if `struct_size` is too small to
+    /// hold itself.
+    ///
+    /// # Safety
+    /// `options` must be null or point to at least `struct_size` readable bytes.
+    unsafe fn read(options: *const CFolderCopyOptions) -> Option<CFolderCopyOptions> {
+        let mut read = CFolderCopyOptions::default();
+        if options.is_null() {
+            return Some(read);
+        }
+        let size = ptr::read_unaligned(options as *const u32) as usize;
+        if size < std::mem::size_of::<u32>() {
+            return None;
+        }
+        ptr::copy_nonoverlapping(options as *const u8, &mut read as *mut CFolderCopyOptions as *mut u8,
+                                 size.min(std::mem::size_of::<CFolderCopyOptions>()));
+        Some(read)
+    }
+}
+
+/// Initialize folder copy context with options
+///
+/// Same as folder_copy_init. With `require_empty_dest`, a destination folder
+/// that has entries fails with ERROR_DEST_NOT_EMPTY.
+///
+/// A dry run walks the source with folder_copy_next_file exactly like a real copy
+/// and fires the same progress callbacks, but writes nothing: the destination
+/// folder is not created, and each file is evaluated against what is already at
+/// the destination (new, overwritten, trashed with folder_copy_set_use_trash, or
+/// failing because a file or directory is in the way). Conflicts are collected
+/// instead of stopping the walk. After folder_copy_finalize the report, including
+/// the projected free-space shortfall, is part of folder_copy_get_manifest_json.
+///
+/// # Arguments
+/// * `source_folder` - Source folder path
+/// * `dest_folder` - Destination folder path
+/// * `options` - Folder copy options (see CFolderCopyOptions; can be null for the defaults)
+/// * `cancel_flag` - Cancellation flag
+/// * `status` - Optional pointer receiving 0 or the error code (e.g. ERROR_DEST_IS_FILE,
+///   or ERROR_INVALID_OPTIONS for a `struct_size` below 4)
+///
+/// # Returns
+/// Pointer to FolderCopyContext, or null on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_init_with_options(
+    source_folder: *const c_char,
+    dest_folder: *const c_char,
+    options: *const CFolderCopyOptions,
+    cancel_flag: *const AtomicBool,
+    status: *mut i32,
+) -> *mut FolderCopyContext {
+    let fail = |code: i32| {
+        if !status.is_null() {
+            unsafe { *status = code; }
+        }
+        ptr::null_mut()
+    };
+
+    let Some(CFolderCopyOptions { require_empty_dest, dry_run, .. }) =
+        (unsafe { CFolderCopyOptions::read(options) }) else {
+        return fail(ERROR_INVALID_OPTIONS);
+    };
+
+    if source_folder.is_null() || dest_folder.is_null() {
+        return fail(ERROR_NULL_POINTER);
+    }
+
+    let src = match unsafe { c_str_to_path(source_folder) } {
+        Ok(p) => p,
+        Err(code) => return fail(code),
+    };
+
+    let dst = match unsafe { c_str_to_path(dest_folder) } {
+        Ok(p) => p,
+        Err(code) => return fail(code),
+    };
+
+    // A destination inside the source would copy its own output forever
+    if let Err(code) = check_not_nested(&src, &dst) {
+        return fail(code);
+    }
+
+    // Create the destination folder and its parents unless they already exist;
+    // a dry run only checks that no file is in the way
+    if dry_run != 0 {
+        if dst.ancestors().any(|p| p.is_file()) {
+            return fail(ERROR_DEST_IS_FILE);
+        }
+    } else if let Err(code) = ensure_dest_dir(&dst) {
+        return fail(code);
+    }
+    if require_empty_dest != 0 && (dry_run == 0 || dst.exists()) {
+        match fs::read_dir(&dst).map(|mut entries| entries.next().is_some()) {
+            Ok(true) => return fail(ERROR_DEST_NOT_EMPTY),
+            Ok(false) => {}
+            Err(_) => return fail(ERROR_IO_FAILED),
+        }
+    }
+
+    // Count files and total size
+    let (total_files, total_bytes) = match count_files_and_size(&src) {
+        Ok(result) => result,
+        Err(_) => return fail(ERROR_FILE_NOT_FOUND),
+    };
+
+    let dest_fs = detect_dest_filesystem(&dst);
+    let mut context = Box::new(FolderCopyContext::new(
+        src, dst, total_bytes, total_files, cancel_flag,
+    ));
+    context.dest_fs = dest_fs;
+    context.manifest.filesystem = dest_fs;
+    if dry_run != 0 {
+        context.dry_run = Some(CopyDryRunReport::default());
+    }
+
+    if !status.is_null() {
+        unsafe { *status = SUCCESS; }
+    }
+    Box::leak(context) as *mut FolderCopyContext
+}
+
+/// Create `path` and any missing parents as directories
+///
+/// Existing directories are fine; a file in the way (at `path` or one of its
+/// parents) is ERROR_DEST_IS_FILE.
+fn ensure_dest_dir(path: &Path) -> Result<(), i32> {
+    match fs::create_dir_all(path) {
+        Ok(()) => Ok(()),
+        Err(_) if path.ancestors().any(|p| p.is_file()) => Err(ERROR_DEST_IS_FILE),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Err(ERROR_PERMISSION_DENIED),
+        Err(_) => Err(ERROR_IO_FAILED),
+    }
+}
+
+/// Canonical form of `path`, which need not exist yet: its nearest existing
+/// ancestor is resolved and the missing components appended
+fn canonical_target(path: &Path) -> Option<PathBuf> {
+    let mut missing = Vec::new();
+    let mut existing = path;
+    let base = loop {
+        match fs::canonicalize(existing) {
+            Ok(base) => break base,
+            Err(_) => {
+                missing.push(existing.file_name()?);
+                existing = existing.parent()?;
+                if existing.as_os_str().is_empty() {
+                    existing = Path::new(".");
+                }
+            }
+        }
+    };
+    Some(missing.iter().rev().fold(base, |path, name| path.join(name)))
+}
+
+/// Check that copying or moving `source` to `dest` cannot reach itself
+///
+/// Both paths are canonicalized (`dest` need not exist yet), so symlinked
+/// aliases compare equal. The existing part of `dest` is also compared by
+/// (dev, inode) with the source root, which catches hard-linked roots, bind
+/// mounts and junctions that canonicalization does not see through. A source
+/// inside the destination is fine. A failure is recorded as the thread's last
+/// error naming both paths.
+///
+/// # Returns
+/// ERROR_SAME_PATH or ERROR_DEST_INSIDE_SOURCE on a conflict
+pub(crate) fn check_not_nested(source: &Path, dest: &Path) -> Result<(), i32> {
+    let (Ok(source_root), Some(dest_target)) = (fs::canonicalize(source), canonical_target(dest)) else {
+        // A missing source is reported by whatever reads it
+        return Ok(());
+    };
+    let source_identity = fs::metadata(&source_root).map(|m| file_identity(&m)).unwrap_or((0, 0));
+    let same_item = |path: &Path| {
+        source_identity != (0, 0)
+            && fs::metadata(path).is_ok_and(|m| file_identity(&m) == source_identity)
+    };
+
+    let code = if dest_target == source_root || same_item(&dest_target) {
+        ERROR_SAME_PATH
+    } else if dest_target.starts_with(&source_root) || dest_target.ancestors().skip(1).any(same_item) {
+        ERROR_DEST_INSIDE_SOURCE
+    } else {
+        return Ok(());
+    };
+    let message = if code == ERROR_SAME_PATH {
+        format!("{} and {} are the same item", dest.display(), source.display())
+    } else {
+        format!("{} is inside the source {}", dest.display(), source.display())
+    };
+    Err(set_last_error_detail(ErrorEnvelope::new(code, message).with_context(dest_target.to_string_lossy())))
+}
+
+/// Count files and total size in a folder
+///
+/// Walks the tree with an explicit stack, so depth is not limited by the call stack.
+fn count_files_and_size(path: &Path) -> Result<(usize, usize), std::io::Error> {
+    let mut file_count = 0;
+    let mut total_size = 0;
+
+    if path.is_file() {
+        return Ok((1, path.metadata()?.len() as usize));
+    }
+
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let entry_path = entry.path();
+            
+            if entry_path.is_file() {
+                file_count += 1;
+                total_size += entry_path.metadata()?.len() as usize;
+            } else if entry_path.is_dir() {
+                pending.push(entry_path);
+            }
+        }
+    }
+
+    Ok((file_count, total_size))
+}
+
+/// Copy next file in folder copy operation
+/// Returns 0 when done, 1 when more files to copy, negative on error
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `progress_callback` - Progress callback
+/// * `user_data` - User data
+///
+/// # Returns
+/// 1 if more files to copy, 0 if done, negative error code
+#[no_mangle]
+pub extern "C" fn folder_copy_next_file(
+    context: *mut FolderCopyContext,
+    progress_callback: Option<CopyProgressCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let (result, report) = {
+        let ctx = unsafe { &mut *context };
+
+        // Check cancellation
+        if unsafe { is_cancelled(ctx.cancel_flag) } {
+            return ERROR_CANCELLED;
+        }
+
+        // Find and copy the next file
+        copy_next_file_impl(ctx, progress_callback.is_some())
+    };
+
+    // Report once the context is no longer borrowed, so the callback may call back in
+    if let (Some(cb), Some(progress)) = (progress_callback, report) {
+        unsafe { CallbackGuard::report(ptr::addr_of!((*context).callback_guard), cb, progress, user_data) };
+    }
+    checked(result)
+}
+
+/// One step of a folder copy, as a path relative to the source root
+enum FolderCopyStep {
+    Dir(PathBuf),
+    /// A file and its size when the plan was built
+    File(PathBuf, u64),
+}
+
+impl FolderCopyStep {
+    fn rel(&self) -> &Path {
+        match self {
+            FolderCopyStep::Dir(rel) | FolderCopyStep::File(rel, _) => rel,
+        }
+    }
+}
+
+/// Why a folder copy plan could not be built
+enum FolderCopyPlanError {
+    Io,
+    /// A directory at max_depth has contents (path relative to the source root)
+    MaxDepth(PathBuf),
+}
+
+/// List the folder copy steps in copy order: entries sorted by name (in
+/// `sort_locale` order), each directory followed by its contents
+///
+/// Uses an explicit stack rather than recursion, so a pathologically deep tree
+/// cannot overflow the call stack. Entries of the root are at depth 0; with a
+/// `max_depth` (0 for unlimited), a directory at that depth must be empty.
+fn build_folder_copy_plan(root: &Path, max_depth: u64, sort_locale: SortLocale)
+    -> Result<VecDeque<FolderCopyStep>, FolderCopyPlanError> {
+    let max_depth = if max_depth == 0 { u64::MAX } else { max_depth };
+    let mut plan = VecDeque::new();
+
+    // Entries still to plan; children are pushed in reverse so they pop in
+    // sorted order, right after their directory
+    let mut stack: Vec<(PathBuf, u64)> = sorted_entries(root, Path::new(""), sort_locale)?
+        .into_iter()
+        .rev()
+        .map(|rel| (rel, 0))
+        .collect();
+
+    while let Some((rel, depth)) = stack.pop() {
+        let entry_path = root.join(&rel);
+        let metadata = entry_path.metadata().ok();
+        if let Some(metadata) = metadata.as_ref().filter(|m| m.is_file()) {
+            plan.push_back(FolderCopyStep::File(rel, metadata.len()));
+        } else if metadata.is_some_and(|m| m.is_dir()) {
+            let children = sorted_entries(root, &rel, sort_locale)?;
+            if depth >= max_depth && !children.is_empty() {
+                return Err(FolderCopyPlanError::MaxDepth(rel));
+            }
+            plan.push_back(FolderCopyStep::Dir(rel));
+            stack.extend(children.into_iter().rev().map(|child| (child, depth + 1)));
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Number of files in a folder copy plan
+fn plan_file_count(plan: &VecDeque<FolderCopyStep>) -> usize {
+    plan.iter().filter(|step| matches!(step, FolderCopyStep::File(..))).count()
+}
+
+/// Planned size of the files in a folder copy plan
+fn plan_byte_count(plan: &VecDeque<FolderCopyStep>) -> usize {
+    plan.iter()
+        .map(|step| match step {
+            FolderCopyStep::File(_, size) => *size as usize,
+            FolderCopyStep::Dir(_) => 0,
+        })
+        .sum()
+}
+
+/// Source-relative paths of a directory's entries, sorted by name
+fn sorted_entries(root: &Path, rel: &Path, sort_locale: SortLocale) -> Result<Vec<PathBuf>, FolderCopyPlanError> {
+    let entries = fs::read_dir(root.join(rel)).map_err(|_| FolderCopyPlanError::Io)?;
+    let mut names: Vec<_> = entries.filter_map(|e| e.ok()).map(|e| e.file_name()).collect();
+    names.sort_by(|a, b| sort_locale.compare(a, b));
+    Ok(names.into_iter().map(|name| rel.join(name)).collect())
+}
+
+/// Copy the next file of the plan
+///
+/// Returns the result of folder_copy_next_file and, when `wants_progress` and
+/// the throttler allows it, the progress to report.
+fn copy_next_file_impl(ctx: &mut FolderCopyContext, wants_progress: bool) -> (i32, Option<ProgressSnapshot>) {
+    if ctx.plan.is_none() {
+        let started = Instant::now();
+        let plan = build_folder_copy_plan(&ctx.source_root, ctx.max_depth, ctx.sort_locale);
+        ctx.manifest.timing.enumeration_ms = started.elapsed().as_millis() as u64;
+        match plan {
+            Ok(plan) => ctx.install_plan(plan),
+            Err(FolderCopyPlanError::Io) => return (ERROR_IO_FAILED, None),
+            Err(FolderCopyPlanError::MaxDepth(rel)) => {
+                ctx.depth_error_path = Some(rel);
+                return (ERROR_MAX_DEPTH_EXCEEDED, None);
+            }
+        }
+    }
+
+    while let Some(step) = ctx.plan.as_mut().and_then(|plan| plan.pop_front()) {
+        // Check cancellation
+        if unsafe { is_cancelled(ctx.cancel_flag) } {
+            return (ERROR_CANCELLED, None);
+        }
+
+        match step {
+            FolderCopyStep::Dir(rel) => {
+                let dest_path = match ctx.dest_path_for(&rel) {
+                    Ok(p) => p,
+                    Err((code, _)) => return (code, None),
+                };
+                if ctx.dry_run.is_some() {
+                    let dest_relative_path = ctx.dest_relative_path(&dest_path);
+                    if let Some(report) = ctx.dry_run.as_mut() {
+                        report.plan_dir(&dest_path, dest_relative_path);
+                    }
+                    continue;
+                }
+                // Create subdirectory (already present when retrying a partial copy)
+                if let Err(code) = ensure_dest_dir(&dest_path) {
+                    return (code, None);
+                }
+            }
+            FolderCopyStep::File(rel, planned_size) => {
+                let result = ctx.copy_plan_file(&rel, planned_size);
+
+                ctx.files_processed += 1;
+                ctx.operation.set_files_done(ctx.files_processed as u64);
+                if ctx.drift_detected && ctx.recount_on_drift && !ctx.recounted {
+                    ctx.recount();
+                }
+
+                // Progress for the callback
+                let report = if wants_progress && ctx.progress_throttler.should_update(ctx.bytes_copied, ctx.total_bytes) {
+                    let progress = ctx.progress_snapshot();
+                    Some(ctx.callback_guard.capture(progress))
+                } else {
+                    None
+                };
+
+                let code = match result {
+                    // Return 1 to indicate more files may need to be copied
+                    Ok(()) => 1,
+                    // Cancellation always stops the copy, even with continue-on-error
+                    Err(ERROR_CANCELLED) => ERROR_CANCELLED,
+                    // A dry run reports failures in its report instead of stopping
+                    Err(_) if ctx.continue_on_error || ctx.dry_run.is_some() => 1,
+                    Err(code) => code,
+                };
+                return (code, report);
+            }
+        }
+    }
+
+    // No more files to copy
+    (0, None)
+}
+
+/// Sanitization policy for names copied from a local source folder
+fn copy_name_policy() -> PathSanitizePolicy {
+    PathSanitizePolicy {
+        mode: PathSanitizeMode::Replace,
+        ..Default::default()
+    }
+}
+
+/// Copy one file of a folder copy, checking the cancel flag once per chunk
+///
+/// A cancelled or failed copy deletes the partial destination unless
+/// `keep_partial` is set; cancellation is reported as ErrorKind::Interrupted.
+/// Read, write and flush time is added to `timing`, taking two clock readings per chunk.
+fn copy_single_file(src: &Path, dst: &Path, keep_partial: bool, cancel_flag: *const AtomicBool,
+                    timing: &mut CopyFileTiming) -> Result<(), std::io::Error> {
+    let src_file = retry_open(|| File::open(src))?;
+    let dst_file = retry_open(|| File::create(dst))?;
+    let partial = PartialOutputGuard::new(dst, keep_partial);
+
+    let mut reader = BufReader::new(src_file);
+    let mut writer = BufWriter::new(dst_file);
+    let mut buffer = vec![0u8; 1024 * 1024]; // 1MB chunks
+
+    let mut mark = Instant::now();
+    loop {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "cancelled"));
+        }
+        let bytes_read = reader.read(&mut buffer)?;
+        let read_done = Instant::now();
+        timing.read += read_done - mark;
+        if bytes_read == 0 {
+            mark = read_done;
+            break;
+        }
+        writer.write_all(&buffer[..bytes_read])?;
+        mark = Instant::now();
+        timing.write += mark - read_done;
+    }
+
+    writer.flush()?;
+    timing.flush += mark.elapsed();
+    partial.complete();
+    Ok(())
+}
+
+/// Finalize folder copy
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `progress_callback` - Progress callback
+/// * `user_data` - User data
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[no_mangle]
+pub extern "C" fn folder_copy_finalize(
+    context: *mut FolderCopyContext,
+    progress_callback: Option<CopyProgressCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    // Final progress update (skipped if the last file already reported it)
+    if let Some(cb) = progress_callback {
+        let report = {
+            let ctx = unsafe { &mut *context };
+            let progress = ctx.progress_snapshot();
+            ctx.progress_throttler.finish().then(|| ctx.callback_guard.capture(progress))
+        };
+        if let Some(progress) = report {
+            unsafe { CallbackGuard::report(ptr::addr_of!((*context).callback_guard), cb, progress, user_data) };
+        }
+    }
+
+    let ctx = unsafe { &mut *context };
+    if ctx.manifest.finish().is_err() {
+        return ERROR_IO_FAILED;
+    }
+    if ctx.plan.is_some() {
+        ctx.manifest.appeared_during_copy = ctx.files_appeared_during_copy();
+    }
+    let dest_root = ctx.dest_root.clone();
+    if let Some(report) = ctx.dry_run.as_mut() {
+        report.finish(&dest_root);
+    }
+    ctx.is_finalized = true;
+    ctx.operation.complete();
+
+    SUCCESS
+}
+
+/// Keep copying the remaining files when one file fails
+///
+/// Failed and skipped files are recorded in the manifest with their reason
+/// (see folder_copy_get_manifest_json) instead of aborting the copy.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `continue_on_error` - 1 to continue past failed files, 0 to stop at the first failure
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_set_continue_on_error(context: *mut FolderCopyContext, continue_on_error: u8) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    ctx.continue_on_error = continue_on_error != 0;
+    SUCCESS
+}
+
+/// Keep the partially copied file when the folder copy is cancelled or a file fails
+///
+/// By default the file being copied at that moment is deleted; files copied
+/// completely before are always kept.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `keep_partial` - 1 to leave partial files on disk, 0 to delete them
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_set_keep_partial(context: *mut FolderCopyContext, keep_partial: u8) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    ctx.keep_partial = keep_partial != 0;
+    SUCCESS
+}
+
+/// Limit how deep a folder copy descends
+///
+/// Entries of the source folder are at depth 0. When a directory at `max_depth`
+/// has contents, folder_copy_next_file fails with ERROR_MAX_DEPTH_EXCEEDED before
+/// copying anything, and folder_copy_get_depth_error_path names the directory.
+/// Must be called before the first folder_copy_next_file.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `max_depth` - Deepest directory level whose contents are copied (0 for unlimited, the default)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_set_max_depth(context: *mut FolderCopyContext, max_depth: u32) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    ctx.max_depth = max_depth as u64;
+    SUCCESS
+}
+
+/// Re-enumerate the source once when it changes during a folder copy
+///
+/// A folder copy copies exactly the files it enumerated on the first
+/// folder_copy_next_file. By default a planned file that vanished is skipped,
+/// and files created later are only listed in the manifest's
+/// appeared_during_copy. With recounting, the first vanished or resized file
+/// makes the copy walk the source again: new files are copied too and the
+/// totals are recomputed. Later changes are handled as without recounting.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `recount_on_drift` - 1 to re-enumerate once on drift, 0 to keep the first enumeration (the default)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_set_recount_on_drift(context: *mut FolderCopyContext, recount_on_drift: u8) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    ctx.recount_on_drift = recount_on_drift != 0;
+    SUCCESS
+}
+
+/// Rewrite names the destination filesystem cannot store
+///
+/// On a FAT or exFAT destination, a name with a character such as `:` or `?`,
+/// or ending in a dot or space, fails its file with ERROR_NAME_NOT_ALLOWED_ON_FS
+/// by default; get_last_error_json and the manifest entry name the offending
+/// character. With replacement, the character becomes `_` and trailing dots and
+/// spaces are dropped. Other destinations are not affected.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `replace` - 1 to rewrite rejected names, 0 to fail them (the default)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_set_replace_invalid_names(context: *mut FolderCopyContext, replace: u8) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    ctx.replace_invalid_names = replace != 0;
+    SUCCESS
+}
+
+/// Set what a folder copy does with extended attributes and named streams
+///
+/// Applies to plain and cloned copies; encrypted and decrypted copies never
+/// carry attributes. Attributes that cannot be copied are listed in the
+/// manifest entry of their file (xattr_errors) without failing it.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `xattr_policy` - XATTR_POLICY_IGNORE (the default), XATTR_POLICY_PRESERVE or XATTR_POLICY_STRIP_QUARANTINE_ONLY
+///
+/// # Returns
+/// 0 on success, ERROR_INVALID_XATTR_POLICY for an unknown code, other negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_set_xattr_policy(context: *mut FolderCopyContext, xattr_policy: i32) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    match XattrPolicy::from_code(xattr_policy) {
+        Some(xattr_policy) => {
+            ctx.xattr_policy = xattr_policy;
+            SUCCESS
+        }
+        None => ERROR_INVALID_XATTR_POLICY,
+    }
+}
+
+/// Set the order in which a folder copy visits the entries of each directory
+///
+/// Must be called before the first folder_copy_next_file.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `sort_locale` - SORT_LOCALE_BYTE (the default), SORT_LOCALE_CASE_INSENSITIVE or SORT_LOCALE_NATURAL
+///
+/// # Returns
+/// 0 on success, ERROR_INVALID_SORT_LOCALE for an unknown code, other negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_set_sort_locale(context: *mut FolderCopyContext, sort_locale: i32) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    match SortLocale::from_code(sort_locale) {
+        Some(sort_locale) => {
+            ctx.sort_locale = sort_locale;
+            SUCCESS
+        }
+        None => ERROR_INVALID_SORT_LOCALE,
+    }
+}
+
+/// Choose what happens when a folder copy writes two files whose names differ only in case
+///
+/// On a case-insensitive destination, "Report.pdf" and "report.pdf" from a
+/// case-sensitive source would be the same file. By default the second one is
+/// renamed with a numeric suffix ("report (1).pdf") and its manifest entry
+/// records the original name in `renamed_from`; otherwise the second file
+/// replaces the first like any existing destination file. Files whose names
+/// only meet after sanitizing are renamed either way.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `rename` - 1 to rename colliding files (the default), 0 to let them replace each other
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_set_rename_case_collisions(context: *mut FolderCopyContext, rename: u8) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    ctx.rename_case_collisions = rename != 0;
+    SUCCESS
+}
+
+/// Override whether a folder copy treats the destination as case-insensitive
+///
+/// By default the destination volume is probed (see is_filesystem_case_sensitive)
+/// before the first file is copied. Forcing case-insensitive names is useful
+/// for a destination that is later synced to Windows or macOS.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `case_insensitive` - 1 to detect case-only collisions, 0 to treat names as case-sensitive
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_set_dest_case_insensitive(context: *mut FolderCopyContext, case_insensitive: u8) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    ctx.dest_case_insensitive = Some(case_insensitive != 0);
+    SUCCESS
+}
+
+/// Get the directory that made a folder copy fail with ERROR_MAX_DEPTH_EXCEEDED
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `out_path` - Receives the directory path relative to the source folder ('/'
+///   separated; caller must free with scan_folder_free_string), or null if the
+///   copy did not hit the depth limit
+///
+/// # Returns
+/// ERROR_MAX_DEPTH_EXCEEDED if the copy hit the depth limit, 0 if not, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_get_depth_error_path(context: *mut FolderCopyContext, out_path: *mut *mut c_char) -> i32 {
+    if context.is_null() || out_path.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &*context };
+    unsafe { *out_path = ptr::null_mut(); }
+    let rel = match &ctx.depth_error_path {
+        Some(rel) => rel.to_string_lossy().replace('\\', "/"),
+        None => return SUCCESS,
+    };
+    match CString::new(rel) {
+        Ok(s) => {
+            unsafe { *out_path = s.into_raw(); }
+            ERROR_MAX_DEPTH_EXCEEDED
+        }
+        Err(_) => ERROR_INVALID_PATH,
+    }
+}
+
+/// Set how many manifest entries are kept in memory before they move to a temp file
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `threshold` - Maximum in-memory entries (0 for the default of 10000)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_set_manifest_spill_threshold(context: *mut FolderCopyContext, threshold: u64) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    ctx.manifest.spill_threshold = if threshold == 0 {
+        DEFAULT_COPY_MANIFEST_SPILL_THRESHOLD
+    } else {
+        threshold as usize
+    };
+    SUCCESS
+}
+
+/// Get the per-file manifest of a finalized folder copy
+///
+/// Returns a JSON envelope (see ffi_util.rs) whose `data` has the form
+/// `{"copied": n, "skipped": n, "failed": n, "entries": [...], "timing": {...},
+/// "appeared_during_copy": [...], "filesystem": "fat", "dry_run": null}`
+/// where each entry holds relative_path, dest_relative_path, source_size, dest_size,
+/// source_mtime_ms, duration_ms, read_ms, write_ms, flush_ms, status ("copied",
+/// "skipped" or "failed") and error. A file that vanished from the source before
+/// it was copied is "skipped". `timing` is the FolderCopyTimingSummary:
+/// enumeration_ms, transfer_ms, read_ms, write_ms, flush_ms,
+/// file_duration_p50_ms and file_duration_p95_ms. `appeared_during_copy` lists
+/// the source-relative paths of files created after the copy enumerated the
+/// source, which were not copied. `filesystem` is the destination filesystem
+/// detected at init: "fat", "exfat" or "other".
+/// For a dry run (see folder_copy_init_with_options) the entries describe the projected
+/// outcome and `dry_run` holds the CopyDryRunReport: files_to_copy,
+/// files_to_overwrite, files_to_skip, files_to_fail, bytes_required, bytes_freed,
+/// available_bytes, shortfall_bytes and conflicts.
+/// Before finalize it fails with ERROR_RESULT_UNAVAILABLE.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope (caller must free with scan_folder_free_string), or null if it
+/// cannot be allocated
+#[no_mangle]
+pub extern "C" fn folder_copy_get_manifest_json(context: *mut FolderCopyContext, out_len: *mut usize) -> *mut c_char {
+    json_envelope_raw(manifest_json(context), out_len)
+}
+
+fn manifest_json(context: *mut FolderCopyContext) -> Result<String, ErrorEnvelope> {
+    if context.is_null() {
+        return Err(ErrorEnvelope::null_argument("context"));
+    }
+
+    let ctx = unsafe { &*context };
+    if !ctx.is_finalized {
+        return Err(ErrorEnvelope::new(ERROR_RESULT_UNAVAILABLE, "folder copy has not been finalized"));
+    }
+
+    ctx.manifest.to_json(ctx.dry_run.as_ref())
+        .map_err(|e| ErrorEnvelope::new(ERROR_IO_FAILED, format!("failed to read manifest: {}", e)))
+}
+
+/// Move destination files that would be overwritten into the trash
+///
+/// All files replaced by this folder copy are collected in a single trash
+/// operation (see set_trash_directory).
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `use_trash` - 1 to trash overwritten files, 0 to overwrite in place
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_set_use_trash(context: *mut FolderCopyContext, use_trash: u8) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    if use_trash != 0 && trash_directory().is_none() {
+        return ERROR_TRASH_NOT_CONFIGURED;
+    }
+
+    ctx.use_trash = use_trash != 0;
+    SUCCESS
+}
+
+/// Get the manifest path of the trash operation holding overwritten files
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+///
+/// # Returns
+/// Manifest path (caller must free with scan_folder_free_string), or null if
+/// nothing was overwritten
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_get_trash_manifest(context: *mut FolderCopyContext) -> *mut c_char {
+    if context.is_null() {
+        return ptr::null_mut();
     }
 
-    writer.flush()?;
-    Ok(())
+    let ctx = unsafe { &*context };
+    match &ctx.trash {
+        Some(trash) => CString::new(trash.manifest_path().to_string_lossy().to_string())
+            .map(|s| s.into_raw())
+            .unwrap_or(ptr::null_mut()),
+        None => ptr::null_mut(),
+    }
 }
 
-/// Finalize folder copy
+/// Try copy-on-write reflinks for each file before falling back to streaming
 ///
 /// # Arguments
 /// * `context` - Pointer to FolderCopyContext
-/// * `progress_callback` - Progress callback
-/// * `user_data` - User data
+/// * `allow_reflink` - 1 to enable the fast path, 0 to always stream
 ///
 /// # Returns
-/// 0 on success, error code on failure
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
-pub extern "C" fn folder_copy_finalize(
-    context: *mut FolderCopyContext,
-    progress_callback: Option<CopyProgressCallback>,
-    user_data: *mut c_void,
-) -> i32 {
+pub extern "C" fn folder_copy_set_allow_reflink(context: *mut FolderCopyContext, allow_reflink: u8) -> i32 {
     if context.is_null() {
         return ERROR_NULL_POINTER;
     }
 
     let ctx = unsafe { &mut *context };
+    ctx.allow_reflink = allow_reflink != 0;
+    SUCCESS
+}
 
-    // Final progress update
-    if let Some(cb) = progress_callback {
-        cb(ctx.bytes_copied, ctx.total_bytes, ctx.files_processed, ctx.total_files, user_data);
+/// Report this folder copy's progress into the polled event stream
+///
+/// Progress records go to drain_progress_events_json in addition to the
+/// progress callback (pass no callback to use the stream instead). folder_copy_finalize
+/// pushes the terminal "completed" record; freeing an unfinished folder copy pushes
+/// "failed" or "cancelled".
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `use_event_stream` - 1 to push progress events, 0 to stop
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_set_use_event_stream(context: *mut FolderCopyContext, use_event_stream: u8) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
     }
 
+    unsafe { (*context).operation.set_use_event_stream(use_event_stream != 0); }
     SUCCESS
 }
 
+/// Get the number of files copied through the reflink fast path
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+///
+/// # Returns
+/// Number of reflinked files, or 0 if context is null
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_get_reflink_count(context: *mut FolderCopyContext) -> usize {
+    if context.is_null() {
+        return 0;
+    }
+
+    let ctx = unsafe { &*context };
+    ctx.files_reflinked
+}
+
 /// Free folder copy context
 #[no_mangle]
 pub extern "C" fn folder_copy_free(context: *mut FolderCopyContext) {
@@ -457,10 +2610,71 @@ pub extern "C" fn folder_copy_free(context: *mut FolderCopyContext) {
     }
 }
 
+/// Get the active operation id of a folder copy
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+///
+/// # Returns
+/// Operation id for get_operation_progress and cancel_operation, or 0 if invalid
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_get_operation_id(context: *mut FolderCopyContext) -> u64 {
+    if context.is_null() {
+        return 0;
+    }
+    unsafe { (&*context).operation.id() }
+}
+
+/// Get folder copy progress
+///
+/// Safe to call from the folder copy's progress callback, where it reports the
+/// values passed to the callback. The totals counted by init are replaced by
+/// those of the copy plan on the first folder_copy_next_file, and follow files
+/// that vanish or change size while the copy runs.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderCopyContext
+/// * `bytes_copied` - Pointer to store bytes copied (can be null)
+/// * `total_bytes` - Pointer to store total bytes (can be null)
+/// * `files_processed` - Pointer to store files processed (can be null)
+/// * `total_files` - Pointer to store total files (can be null)
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_copy_get_progress(
+    context: *mut FolderCopyContext,
+    bytes_copied: *mut usize,
+    total_bytes: *mut usize,
+    files_processed: *mut usize,
+    total_files: *mut usize,
+) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &*context };
+    let progress = ctx.callback_guard.read(ctx.progress_snapshot());
+    let outputs = [
+        (bytes_copied, progress.bytes_done),
+        (total_bytes, progress.total_bytes),
+        (files_processed, progress.files_done),
+        (total_files, progress.total_files),
+    ];
+    for (out, value) in outputs {
+        if !out.is_null() {
+            unsafe { *out = value; }
+        }
+    }
+    SUCCESS
+}
+
 /// Get copy progress
 ///
 /// # Arguments
-/// * `context` - Pointer to CopyContext or FolderCopyContext
+/// * `context` - Pointer to CopyContext (use folder_copy_get_progress for a FolderCopyContext)
 /// * `bytes_copied` - Pointer to store bytes copied
 /// * `total_bytes` - Pointer to store total bytes
 /// * `files_processed` - Pointer to store files processed
@@ -579,31 +2793,161 @@ pub extern "C" fn get_file_size(path: *const c_char) -> usize {
 pub struct ChunkedCopyContext {
     source_file: Option<File>,
     dest_file: Option<File>,
+    /// Open file permits for source_file and dest_file, taken together by
+    /// whichever opens first and returned once both are closed
+    open_files: Option<OpenFilePermit>,
     source_path: PathBuf,
     dest_path: PathBuf,
     chunk_size: usize,
-    bytes_copied: usize,
+    bytes_read: usize,
+    bytes_written: usize,
+    /// Bytes of bytes_written as of the last chunked_copy_flush_ex or finalize
+    bytes_durable: usize,
     total_bytes: usize,
+    dest_offset: u64,
     cancel_flag: *const AtomicBool,
     progress_throttler: ProgressThrottler,
+    pacer: TransferPacer,
+    keep_partial: bool,
     is_open: bool,
+    /// Length the destination had when this copy opened it; None if this copy created it
+    dest_existing_len: Option<u64>,
+    /// This copy opened the destination (since init or the last reset)
+    dest_opened: bool,
+    /// Finalized or aborted; free leaves the destination alone
+    is_finalized: bool,
+    aborted: bool,
+    callback_guard: CallbackGuard<ProgressSnapshot>,
+    operation: Operation,
 }
 
 impl ChunkedCopyContext {
     pub fn new(source_path: PathBuf, dest_path: PathBuf, chunk_size: usize, 
                total_bytes: usize, cancel_flag: *const AtomicBool) -> Self {
+        let operation = Operation::register(OperationKind::ChunkedCopy, &source_path, &dest_path,
+                                            total_bytes as u64, 1, cancel_flag);
         Self {
             source_file: None,
             dest_file: None,
+            open_files: None,
             source_path,
             dest_path,
             chunk_size,
-            bytes_copied: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            bytes_durable: 0,
             total_bytes,
-            cancel_flag,
+            dest_offset: 0,
+            cancel_flag: operation.cancel_flag(),
             progress_throttler: ProgressThrottler::new(500),
+            pacer: TransferPacer::new(),
+            keep_partial: false,
             is_open: false,
+            dest_existing_len: None,
+            dest_opened: false,
+            is_finalized: false,
+            aborted: false,
+            callback_guard: CallbackGuard::default(),
+            operation,
+        }
+    }
+
+    /// Open the destination for writing, recording whether it already existed
+    fn open_dest(&mut self, truncate: bool) -> Result<(), i32> {
+        let existing_len = match truncate {
+            true => None,
+            false => self.dest_path.metadata().ok().filter(|m| m.is_file()).map(|m| m.len()),
+        };
+        self.acquire_open_files()?;
+        let opened = retry_open(|| OpenOptions::new().write(true).create(true).truncate(truncate).open(&self.dest_path));
+        let file = match opened {
+            Ok(file) => file,
+            Err(e) => {
+                self.release_open_files();
+                return Err(open_error_code(&e, ERROR_PERMISSION_DENIED));
+            }
+        };
+        self.dest_file = Some(file);
+        self.dest_existing_len = existing_len;
+        self.dest_opened = true;
+        Ok(())
+    }
+
+    /// Take the permits for both files, unless this copy holds them already
+    ///
+    /// Both are taken in one call, so two copies under a limit of two can never
+    /// each hold the permit of one file while waiting for the other.
+    fn acquire_open_files(&mut self) -> Result<(), i32> {
+        if self.open_files.is_none() {
+            self.open_files = Some(acquire_open_files(2)?);
         }
+        Ok(())
+    }
+
+    /// Return the permits once neither file is open
+    fn release_open_files(&mut self) {
+        if self.source_file.is_none() && self.dest_file.is_none() {
+            self.open_files = None;
+        }
+    }
+
+    /// Undo what this copy did to the destination and release its files
+    ///
+    /// A destination this copy created is deleted; one it opened to resume
+    /// into is cut back to the length it had. Nothing is touched if the
+    /// destination was never opened.
+    fn abort(&mut self) {
+        self.source_file = None;
+        drop(self.dest_file.take());
+        self.release_open_files();
+        if self.dest_opened {
+            match self.dest_existing_len {
+                None => {
+                    let _ = crate::temp::discard_temp_file(&self.dest_path);
+                }
+                Some(len) => {
+                    if let Ok(file) = OpenOptions::new().write(true).open(&self.dest_path) {
+                        if file.metadata().is_ok_and(|m| m.len() > len) {
+                            let _ = file.set_len(len);
+                        }
+                    }
+                }
+            }
+        }
+        self.dest_opened = false;
+        self.is_open = false;
+        self.is_finalized = true;
+        self.aborted = true;
+    }
+
+    fn progress_snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            bytes_read: self.bytes_read,
+            bytes_done: self.bytes_written,
+            total_bytes: self.total_bytes,
+            files_done: 1,
+            total_files: 1,
+        }
+    }
+
+    /// Close the destination and apply the partial output policy after a cancellation
+    ///
+    /// Nothing is deleted if this copy has not opened the destination yet.
+    fn cancel(&mut self) -> i32 {
+        if let Some(file) = self.dest_file.take() {
+            drop(file);
+            self.release_open_files();
+            cleanup_partial_output(&self.dest_path, self.keep_partial);
+        }
+        ERROR_CANCELLED
+    }
+}
+
+/// Size of the regular file at `path`, as used for chunked copy totals
+fn chunked_source_size(path: &Path) -> Option<usize> {
+    match path.metadata() {
+        Ok(m) if m.is_file() => Some(m.len() as usize),
+        _ => None,
     }
 }
 
@@ -645,16 +2989,11 @@ pub extern "C" fn chunked_copy_init(
     };
 
     // Get source file size
-    let metadata = match src.metadata() {
-        Ok(m) => m,
-        Err(_) => return ptr::null_mut(),
+    let total_bytes = match chunked_source_size(&src) {
+        Some(size) => size,
+        None => return ptr::null_mut(),
     };
 
-    if !metadata.is_file() {
-        return ptr::null_mut();
-    }
-
-    let total_bytes = metadata.len() as usize;
     let chunk_size = chunk_size.max(64 * 1024).min(10 * 1024 * 1024);
 
     let context = Box::new(ChunkedCopyContext::new(
@@ -680,11 +3019,18 @@ pub extern "C" fn chunked_copy_open_source(context: *mut ChunkedCopyContext) ->
 
     let ctx = unsafe { &mut *context };
 
-    let src_file = match File::open(&ctx.source_path) {
-        Ok(f) => f,
+    // Permits for the destination too, taken now along with the source's
+    if let Err(code) = ctx.acquire_open_files() {
+        eprintln!("[RUST] ❌ chunked_copy_open_source: no open file permit freed up in time");
+        return checked(code);
+    }
+    let src_file = match retry_open(|| File::open(&ctx.source_path)) {
+        Ok(file) => file,
         Err(e) => {
-            eprintln!("[RUST] ❌ chunked_copy_open_source: failed to open source: {}", e);
-            return ERROR_FILE_NOT_FOUND;
+            let code = open_error_code(&e, ERROR_FILE_NOT_FOUND);
+            eprintln!("[RUST] ❌ chunked_copy_open_source: failed to open source: {}", code);
+            ctx.release_open_files();
+            return checked(code);
         }
     };
 
@@ -719,38 +3065,43 @@ pub extern "C" fn chunked_copy_read_chunk(
         return ERROR_NULL_POINTER as isize;
     }
 
-    let ctx = unsafe { &mut *context };
+    let n = {
+        let ctx = unsafe { &mut *context };
 
-    // Check cancellation
-    if unsafe { is_cancelled(ctx.cancel_flag) } {
-        return ERROR_CANCELLED as isize;
-    }
+        // Check cancellation
+        if unsafe { is_cancelled(ctx.cancel_flag) } {
+            return ctx.cancel() as isize;
+        }
 
-    let file = match &mut ctx.source_file {
-        Some(f) => f,
-        None => return ERROR_FILE_NOT_FOUND as isize,
-    };
+        let file = match &mut ctx.source_file {
+            Some(f) => f,
+            None => return ERROR_FILE_NOT_FOUND as isize,
+        };
 
-    // Read into buffer
-    let buffer_slice = unsafe { slice::from_raw_parts_mut(buffer, buffer_size) };
-    
-    match file.read(buffer_slice) {
-        Ok(0) => 0, // EOF
-        Ok(n) => {
-            ctx.bytes_copied += n;
-            
-            // Call data callback if provided
-            if let Some(cb) = data_callback {
-                let written = cb(buffer, n, user_data);
-                if written < 0 {
-                    return written; // Error from callback
-                }
+        // Read into buffer
+        let buffer_slice = unsafe { slice::from_raw_parts_mut(buffer, buffer_size) };
+
+        match file.read(buffer_slice) {
+            Ok(0) => return 0, // EOF
+            Ok(n) => {
+                ctx.bytes_read += n;
+                let progress = ctx.progress_snapshot();
+                ctx.callback_guard.capture(progress);
+                n
             }
+            Err(_) => return ERROR_IO_FAILED as isize,
+        }
+    };
 
-            n as isize
+    // Call data callback if provided, once the context is no longer borrowed
+    if let Some(cb) = data_callback {
+        let written = unsafe { CallbackGuard::invoke(ptr::addr_of!((*context).callback_guard), || cb(buffer, n, user_data)) };
+        if written < 0 {
+            return written; // Error from callback
         }
-        Err(_) => ERROR_IO_FAILED as isize,
     }
+
+    n as isize
 }
 
 /// Write chunk to destination file
@@ -777,20 +3128,36 @@ pub extern "C" fn chunked_copy_write_chunk(
         return ERROR_NULL_POINTER;
     }
 
-    let ctx = unsafe { &mut *context };
+    let report = match write_chunk_impl(unsafe { &mut *context }, data, data_len, progress_callback.is_some()) {
+        Ok(report) => report,
+        Err(code) => return checked(code),
+    };
+
+    // Report once the context is no longer borrowed, so the callback may call back in
+    if let (Some(cb), Some(progress)) = (progress_callback, report) {
+        unsafe { CallbackGuard::report(ptr::addr_of!((*context).callback_guard), cb, progress, user_data) };
+    }
+
+    SUCCESS
+}
+
+/// Write one chunk, returning the progress to report when `wants_progress`
+/// and the throttler allows it
+fn write_chunk_impl(ctx: &mut ChunkedCopyContext, data: *const u8, data_len: usize,
+                    wants_progress: bool) -> Result<Option<ProgressSnapshot>, i32> {
+    // An aborted copy never recreates its destination
+    if ctx.aborted {
+        return Err(ERROR_CANCELLED);
+    }
 
     // Check cancellation
     if unsafe { is_cancelled(ctx.cancel_flag) } {
-        return ERROR_CANCELLED;
+        return Err(ctx.cancel());
     }
 
     // Open destination file on first write
     if ctx.dest_file.is_none() {
-        let dst_file = match File::create(&ctx.dest_path) {
-            Ok(f) => f,
-            Err(_) => return ERROR_PERMISSION_DENIED,
-        };
-        ctx.dest_file = Some(dst_file);
+        ctx.open_dest(true)?;
     }
 
     let file = ctx.dest_file.as_mut().unwrap();
@@ -798,16 +3165,214 @@ pub extern "C" fn chunked_copy_write_chunk(
 
     match file.write_all(data_slice) {
         Ok(_) => {}
-        Err(_) => return ERROR_IO_FAILED,
+        Err(_) => return Err(ERROR_IO_FAILED),
     }
+    ctx.dest_offset += data_len as u64;
+    ctx.bytes_written += data_len;
+    ctx.operation.set_bytes_done(ctx.bytes_written as u64);
 
-    // Progress callback
-    if let Some(cb) = progress_callback {
-        if ctx.progress_throttler.should_update(ctx.bytes_copied, ctx.total_bytes) {
-            cb(ctx.bytes_copied, ctx.total_bytes, 1, 1, user_data);
+    // Slow down under thermal/battery pressure or an explicit rate limit
+    if !ctx.pacer.pace(data_len, ctx.cancel_flag) {
+        return Err(ctx.cancel());
+    }
+
+    // Progress (only bytes that actually reached the destination)
+    if wants_progress && ctx.progress_throttler.should_update(ctx.bytes_written, ctx.total_bytes) {
+        let progress = ctx.progress_snapshot();
+        return Ok(Some(ctx.callback_guard.capture(progress)));
+    }
+    Ok(None)
+}
+
+/// Move the destination write position
+///
+/// Opens the destination without truncating it if no chunk has been written
+/// yet, so resumed or out-of-order ranges can be written into an existing file.
+///
+/// # Arguments
+/// * `context` - Pointer to ChunkedCopyContext
+/// * `offset` - Byte offset for the next chunked_copy_write_chunk
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chunked_copy_seek_dest(context: *mut ChunkedCopyContext, offset: u64) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    if ctx.aborted {
+        return ERROR_CANCELLED;
+    }
+
+    if ctx.dest_file.is_none() {
+        if let Err(code) = ctx.open_dest(false) {
+            return checked(code);
+        }
+    }
+
+    let file = ctx.dest_file.as_mut().unwrap();
+    if file.seek(SeekFrom::Start(offset)).is_err() {
+        return ERROR_IO_FAILED;
+    }
+    ctx.dest_offset = offset;
+
+    SUCCESS
+}
+
+/// Get the offset the next chunk will be written at
+///
+/// # Arguments
+/// * `context` - Pointer to ChunkedCopyContext
+///
+/// # Returns
+/// Logical destination offset in bytes, 0 if context is null
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chunked_copy_get_dest_offset(context: *mut ChunkedCopyContext) -> u64 {
+    if context.is_null() {
+        return 0;
+    }
+
+    unsafe { (*context).dest_offset }
+}
+
+/// Reuse a chunked copy context for the next file in a batch
+///
+/// Flushes and closes the current files, then points the context at the new
+/// source and destination. Call chunked_copy_open_source again before reading.
+///
+/// # Arguments
+/// * `context` - Pointer to ChunkedCopyContext
+/// * `new_source_path` - Next source file path
+/// * `new_dest_path` - Next destination file path
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chunked_copy_reset(
+    context: *mut ChunkedCopyContext,
+    new_source_path: *const c_char,
+    new_dest_path: *const c_char,
+) -> i32 {
+    if context.is_null() || new_source_path.is_null() || new_dest_path.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+
+    let src = match unsafe { c_str_to_path(new_source_path) } {
+        Ok(p) => p,
+        Err(code) => return checked(code),
+    };
+    let dst = match unsafe { c_str_to_path(new_dest_path) } {
+        Ok(p) => p,
+        Err(code) => return checked(code),
+    };
+    let total_bytes = match chunked_source_size(&src) {
+        Some(size) => size,
+        None => return ERROR_FILE_NOT_FOUND,
+    };
+
+    if let Some(mut file) = ctx.dest_file.take() {
+        let flushed = file.flush();
+        drop(file);
+        ctx.release_open_files();
+        if flushed.is_err() {
+            return ERROR_IO_FAILED;
         }
     }
+    ctx.source_file = None;
+    ctx.release_open_files();
+
+    ctx.source_path = src;
+    ctx.dest_path = dst;
+    ctx.total_bytes = total_bytes;
+    ctx.bytes_read = 0;
+    ctx.bytes_written = 0;
+    ctx.bytes_durable = 0;
+    ctx.dest_offset = 0;
+    ctx.progress_throttler = ProgressThrottler::new(500);
+    ctx.is_open = false;
+    ctx.dest_existing_len = None;
+    ctx.dest_opened = false;
+    ctx.is_finalized = false;
+    ctx.aborted = false;
+    ctx.operation.set_bytes_done(0);
+    ctx.operation.set_total_bytes(total_bytes as u64);
+
+    SUCCESS
+}
+
+/// Limit the write speed of a chunked copy
+///
+/// Combined with the speed governor (see set_speed_governor), the lower speed wins.
+///
+/// # Arguments
+/// * `context` - Pointer to ChunkedCopyContext
+/// * `bytes_per_sec` - Maximum speed in bytes per second (0 for unlimited)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chunked_copy_set_rate_limit(context: *mut ChunkedCopyContext, bytes_per_sec: u64) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    unsafe { (*context).pacer.set_rate_limit(bytes_per_sec); }
+    SUCCESS
+}
+
+/// Keep the partially written destination when the chunked copy is cancelled
+///
+/// By default a cancelled chunk call deletes the destination it has written to,
+/// and so does chunked_copy_free for a copy that was never finalized. Set this
+/// when resuming into an existing file with chunked_copy_seek_dest, or to keep
+/// partials across a free. chunked_copy_abort deletes them regardless.
+///
+/// # Arguments
+/// * `context` - Pointer to ChunkedCopyContext
+/// * `keep_partial` - 1 to leave the partial destination on disk, 0 to delete it
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chunked_copy_set_keep_partial(context: *mut ChunkedCopyContext, keep_partial: u8) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    unsafe { (*context).keep_partial = keep_partial != 0; }
+    SUCCESS
+}
+
+/// Report this chunked copy's progress into the polled event stream
+///
+/// Progress records go to drain_progress_events_json in addition to the
+/// progress callback (pass no callback to use the stream instead). chunked_copy_finalize
+/// pushes the terminal "completed" record; freeing an unfinished chunked copy pushes
+/// "failed" or "cancelled".
+///
+/// # Arguments
+/// * `context` - Pointer to ChunkedCopyContext
+/// * `use_event_stream` - 1 to push progress events, 0 to stop
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chunked_copy_set_use_event_stream(context: *mut ChunkedCopyContext, use_event_stream: u8) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
 
+    unsafe { (*context).operation.set_use_event_stream(use_event_stream != 0); }
     SUCCESS
 }
 
@@ -835,6 +3400,56 @@ pub extern "C" fn chunked_copy_flush(context: *mut ChunkedCopyContext) -> i32 {
     SUCCESS
 }
 
+/// Flush the destination file, optionally syncing it to storage
+///
+/// Chunks are written to the destination unbuffered, so without
+/// `sync_to_disk` every written byte already counts as durable against an
+/// app crash, just not against the device losing power.
+///
+/// # Arguments
+/// * `context` - Pointer to ChunkedCopyContext
+/// * `sync_to_disk` - Non-zero to also sync the file data to storage
+///
+/// # Returns
+/// Durable byte count (as chunked_copy_get_bytes_durable), or negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chunked_copy_flush_ex(context: *mut ChunkedCopyContext, sync_to_disk: i32) -> i64 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER as i64;
+    }
+
+    let ctx = unsafe { &mut *context };
+
+    if let Some(ref mut file) = ctx.dest_file {
+        if file.flush().is_err() {
+            return ERROR_IO_FAILED as i64;
+        }
+        if sync_to_disk != 0 && file.sync_data().is_err() {
+            return ERROR_IO_FAILED as i64;
+        }
+        ctx.bytes_durable = ctx.bytes_written;
+    }
+
+    ctx.bytes_durable as i64
+}
+
+/// Get bytes written to the destination as of the last chunked_copy_flush_ex or finalize
+///
+/// # Arguments
+/// * `context` - Pointer to ChunkedCopyContext
+///
+/// # Returns
+/// Durable bytes, or 0 if context is null
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chunked_copy_get_bytes_durable(context: *mut ChunkedCopyContext) -> usize {
+    if context.is_null() {
+        return 0;
+    }
+    unsafe { (*context).bytes_durable }
+}
+
 /// Close and finalize chunked copy
 ///
 /// # Arguments
@@ -853,43 +3468,109 @@ pub extern "C" fn chunked_copy_finalize(
     if context.is_null() {
         return ERROR_NULL_POINTER;
     }
+    if unsafe { (*context).aborted } {
+        return ERROR_CANCELLED;
+    }
 
-    let ctx = unsafe { &mut *context };
-
-    // Final progress update
+    // Final progress update (skipped if the last chunk already reported it)
     if let Some(cb) = progress_callback {
-        cb(ctx.bytes_copied, ctx.total_bytes, 1, 1, user_data);
+        let report = {
+            let ctx = unsafe { &mut *context };
+            let progress = ctx.progress_snapshot();
+            ctx.progress_throttler.finish().then(|| ctx.callback_guard.capture(progress))
+        };
+        if let Some(progress) = report {
+            unsafe { CallbackGuard::report(ptr::addr_of!((*context).callback_guard), cb, progress, user_data) };
+        }
     }
 
+    let ctx = unsafe { &mut *context };
+
     // Flush destination
     if let Some(ref mut file) = ctx.dest_file {
         if let Err(_) = file.flush() {
             return ERROR_IO_FAILED;
         }
+        ctx.bytes_durable = ctx.bytes_written;
     }
 
     ctx.is_open = false;
+    ctx.is_finalized = true;
+    ctx.operation.complete();
+    SUCCESS
+}
+
+/// Abandon a chunked copy and remove what it wrote
+///
+/// Closes both files and deletes a destination this copy created, or cuts
+/// one it opened through chunked_copy_seek_dest back to its original length.
+/// The context counts as finalized afterwards: further writes fail with
+/// ERROR_CANCELLED and chunked_copy_free does nothing more. Aborting a
+/// finalized copy does nothing.
+///
+/// # Arguments
+/// * `context` - Pointer to ChunkedCopyContext
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chunked_copy_abort(context: *mut ChunkedCopyContext) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    if !ctx.is_finalized {
+        ctx.abort();
+    }
     SUCCESS
 }
 
 /// Free chunked copy context
 ///
+/// A copy that was neither finalized nor aborted is aborted first, unless
+/// keep_partial is set (see chunked_copy_set_keep_partial).
+///
 /// # Arguments
 /// * `context` - Pointer to ChunkedCopyContext
 #[no_mangle]
 pub extern "C" fn chunked_copy_free(context: *mut ChunkedCopyContext) {
     if !context.is_null() {
         unsafe {
+            let ctx = &mut *context;
+            if !ctx.is_finalized && !ctx.keep_partial {
+                ctx.abort();
+            }
             let _ = Box::from_raw(context);
         }
     }
 }
 
+/// Get the active operation id of a chunked copy
+///
+/// # Arguments
+/// * `context` - Pointer to ChunkedCopyContext
+///
+/// # Returns
+/// Operation id for get_operation_progress and cancel_operation, or 0 if invalid
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chunked_copy_get_operation_id(context: *mut ChunkedCopyContext) -> u64 {
+    if context.is_null() {
+        return 0;
+    }
+    unsafe { (&*context).operation.id() }
+}
+
 /// Get chunked copy progress
 ///
+/// Safe to call from the copy's progress and data callbacks, where it reports
+/// the progress as of the callback.
+///
 /// # Arguments
 /// * `context` - Pointer to ChunkedCopyContext
-/// * `bytes_copied` - Pointer to store bytes copied
+/// * `bytes_copied` - Pointer to store bytes written to the destination
 /// * `total_bytes` - Pointer to store total bytes
 #[no_mangle]
 pub extern "C" fn chunked_copy_get_progress(
@@ -902,12 +3583,48 @@ pub extern "C" fn chunked_copy_get_progress(
     }
 
     let ctx = unsafe { &*context };
+    let progress = ctx.callback_guard.read(ctx.progress_snapshot());
     
     if !bytes_copied.is_null() {
-        unsafe { *bytes_copied = ctx.bytes_copied; }
+        unsafe { *bytes_copied = progress.bytes_done; }
     }
     if !total_bytes.is_null() {
-        unsafe { *total_bytes = ctx.total_bytes; }
+        unsafe { *total_bytes = progress.total_bytes; }
+    }
+}
+
+/// Get chunked copy progress with read and write sides reported separately
+///
+/// Safe to call from the copy's callbacks, like chunked_copy_get_progress.
+///
+/// # Arguments
+/// * `context` - Pointer to ChunkedCopyContext
+/// * `bytes_read` - Pointer to store bytes read from the source (can be null)
+/// * `bytes_written` - Pointer to store bytes written to the destination (can be null)
+/// * `total_bytes` - Pointer to store total bytes (can be null)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chunked_copy_get_progress_ex(
+    context: *mut ChunkedCopyContext,
+    bytes_read: *mut usize,
+    bytes_written: *mut usize,
+    total_bytes: *mut usize,
+) {
+    if context.is_null() {
+        return;
+    }
+
+    let ctx = unsafe { &*context };
+    let progress = ctx.callback_guard.read(ctx.progress_snapshot());
+
+    if !bytes_read.is_null() {
+        unsafe { *bytes_read = progress.bytes_read; }
+    }
+    if !bytes_written.is_null() {
+        unsafe { *bytes_written = progress.bytes_done; }
+    }
+    if !total_bytes.is_null() {
+        unsafe { *total_bytes = progress.total_bytes; }
     }
 }
 
@@ -923,16 +3640,20 @@ pub struct CloudCopyContext {
     total_bytes: usize,
     cancel_flag: *const AtomicBool,
     progress_throttler: ProgressThrottler,
+    operation: Operation,
 }
 
 impl CloudCopyContext {
     pub fn new(chunk_size: usize, total_bytes: usize, cancel_flag: *const AtomicBool) -> Self {
+        let operation = Operation::register(OperationKind::CloudCopy, Path::new(""), Path::new(""),
+                                            total_bytes as u64, 1, cancel_flag);
         Self {
             chunk_size,
             bytes_copied: 0,
             total_bytes,
-            cancel_flag,
+            cancel_flag: operation.cancel_flag(),
             progress_throttler: ProgressThrottler::new(500),
+            operation,
         }
     }
 }
@@ -1047,6 +3768,7 @@ pub extern "C" fn cloud_copy_process_chunk(
     }
     
     ctx.bytes_copied += bytes_read as usize;
+    ctx.operation.set_bytes_done(ctx.bytes_copied as u64);
     
     // Progress callback via stderr (Dart handles UI updates separately)
     if ctx.progress_throttler.should_update(ctx.bytes_copied, ctx.total_bytes.max(ctx.bytes_copied)) {
@@ -1075,6 +3797,7 @@ pub extern "C" fn cloud_copy_finalize(context: *mut CloudCopyContext) -> i32 {
     let ctx = unsafe { &mut *context };
     
     eprintln!("[RUST] ✅ cloud_copy_finalize: total bytes copied={}", ctx.bytes_copied);
+    ctx.operation.complete();
     
     SUCCESS
 }
@@ -1090,6 +3813,40 @@ pub extern "C" fn cloud_copy_free(context: *mut CloudCopyContext) {
     }
 }
 
+/// Get the active operation id of a cloud copy
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn cloud_copy_get_operation_id(context: *mut CloudCopyContext) -> u64 {
+    if context.is_null() {
+        return 0;
+    }
+    unsafe { (&*context).operation.id() }
+}
+
+/// Report this cloud copy's progress into the polled event stream
+///
+/// Progress records go to drain_progress_events_json in addition to the
+/// progress callback (pass no callback to use the stream instead). cloud_copy_finalize
+/// pushes the terminal "completed" record; freeing an unfinished cloud copy pushes
+/// "failed" or "cancelled".
+///
+/// # Arguments
+/// * `context` - Pointer to CloudCopyContext
+/// * `use_event_stream` - 1 to push progress events, 0 to stop
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn cloud_copy_set_use_event_stream(context: *mut CloudCopyContext, use_event_stream: u8) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    unsafe { (*context).operation.set_use_event_stream(use_event_stream != 0); }
+    SUCCESS
+}
+
 /// Get cloud copy progress
 #[no_mangle]
 pub extern "C" fn cloud_copy_get_progress(
@@ -1109,4 +3866,1165 @@ pub extern "C" fn cloud_copy_get_progress(
     if !total_bytes.is_null() {
         unsafe { *total_bytes = ctx.total_bytes; }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use crate::test_util::{c_path, temp_dir};
+    use crate::xattrs::{XATTR_POLICY_IGNORE, XATTR_POLICY_PRESERVE, XATTR_POLICY_STRIP_QUARANTINE_ONLY};
+
+    extern "C" fn record_progress(bytes: usize, total: usize, _files: usize, _total_files: usize, user_data: *mut c_void) {
+        let calls = unsafe { &mut *(user_data as *mut Vec<(usize, usize)>) };
+        calls.push((bytes, total));
+    }
+
+    #[test]
+    fn test_reflink_copy_matches_streaming_copy() {
+        let root = temp_dir("reflink");
+        let src = root.join("source.bin");
+        let content: Vec<u8> = (0..300_000).map(|i| (i % 253) as u8).collect();
+        fs::write(&src, &content).unwrap();
+
+        // Whether or not the filesystem supports clones, the result must be identical
+        let dst = root.join("clone.bin");
+        let mut calls: Vec<(usize, usize)> = Vec::new();
+        let mut used_reflink = 2u8;
+        let options = CCopyOptions { allow_reflink: 1, used_reflink: &mut used_reflink, ..Default::default() };
+        let result = copy_file_streaming_with_options(
+            c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024, &options,
+            Some(record_progress), ptr::null(), &mut calls as *mut _ as *mut c_void,
+        );
+        assert_eq!(result, SUCCESS);
+        assert!(used_reflink <= 1);
+        assert_eq!(fs::read(&dst).unwrap(), content);
+        assert_eq!(calls.last(), Some(&(content.len(), content.len())));
+        if used_reflink == 1 {
+            assert_eq!(calls.len(), 1);
+        }
+
+        // An existing destination always falls back to the streaming copy
+        fs::write(&dst, b"stale").unwrap();
+        let options = CCopyOptions { allow_reflink: 1, used_reflink: &mut used_reflink, ..Default::default() };
+        let result = copy_file_streaming_with_options(
+            c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024, &options,
+            None, ptr::null(), ptr::null_mut(),
+        );
+        assert_eq!(result, SUCCESS);
+        assert_eq!(used_reflink, 0);
+        assert_eq!(fs::read(&dst).unwrap(), content);
+
+        // Folder copies use the same fast path per file
+        let src_folder = root.join("folder");
+        fs::create_dir_all(&src_folder).unwrap();
+        fs::write(src_folder.join("file.bin"), &content).unwrap();
+        let dst_folder = root.join("folder_copy");
+
+        let ctx = folder_copy_init(c_path(&src_folder).as_ptr(), c_path(&dst_folder).as_ptr(), ptr::null());
+        assert!(!ctx.is_null());
+        assert_eq!(folder_copy_set_allow_reflink(ctx, 1), SUCCESS);
+        while folder_copy_next_file(ctx, None, ptr::null_mut()) > 0 {}
+        assert!(folder_copy_get_reflink_count(ctx) <= 1);
+        folder_copy_free(ctx);
+        assert_eq!(fs::read(dst_folder.join("file.bin")).unwrap(), content);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    /// Drive one chunked copy through the FFI read/write loop
+    fn pump_chunked_copy(ctx: *mut ChunkedCopyContext) {
+        assert_eq!(chunked_copy_open_source(ctx), SUCCESS);
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let n = chunked_copy_read_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, ptr::null_mut());
+            assert!(n >= 0);
+            if n == 0 {
+                break;
+            }
+            assert_eq!(chunked_copy_write_chunk(ctx, buffer.as_ptr(), n as usize, None, ptr::null_mut()), SUCCESS);
+        }
+        assert_eq!(chunked_copy_finalize(ctx, None, ptr::null_mut()), SUCCESS);
+    }
+
+    #[test]
+    fn test_chunked_copy_seek_dest_interleaved_ranges() {
+        let root = temp_dir("chunked_seek");
+        let src = root.join("source.bin");
+        fs::write(&src, b"unused").unwrap();
+        let dst = root.join("assembled.bin");
+
+        let ctx = chunked_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 0, ptr::null());
+        assert!(!ctx.is_null());
+
+        // Second half first, then the first half
+        let second = b"world!";
+        let first = b"hello ";
+        assert_eq!(chunked_copy_seek_dest(ctx, first.len() as u64), SUCCESS);
+        assert_eq!(chunked_copy_write_chunk(ctx, second.as_ptr(), second.len(), None, ptr::null_mut()), SUCCESS);
+        assert_eq!(chunked_copy_get_dest_offset(ctx), 12);
+
+        assert_eq!(chunked_copy_seek_dest(ctx, 0), SUCCESS);
+        assert_eq!(chunked_copy_get_dest_offset(ctx), 0);
+        assert_eq!(chunked_copy_write_chunk(ctx, first.as_ptr(), first.len(), None, ptr::null_mut()), SUCCESS);
+        assert_eq!(chunked_copy_get_dest_offset(ctx), 6);
+        assert_eq!(chunked_copy_get_bytes_durable(ctx), 0);
+        assert_eq!(chunked_copy_flush_ex(ctx, 1), 12);
+        assert_eq!(fs::metadata(&dst).unwrap().len(), 12);
+        assert_eq!(chunked_copy_finalize(ctx, None, ptr::null_mut()), SUCCESS);
+        chunked_copy_free(ctx);
+
+        assert_eq!(fs::read(&dst).unwrap(), b"hello world!");
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_chunked_copy_abort_removes_destination() {
+        let root = temp_dir("chunked_abort");
+        let src = root.join("source.bin");
+        let content: Vec<u8> = (0..512 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &content).unwrap();
+        let dst = root.join("dest.bin");
+        let mut buffer = vec![0u8; 64 * 1024];
+
+        // Cancelled mid-copy with keep_partial, then aborted
+        let cancel = AtomicBool::new(false);
+        let ctx = chunked_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024, &cancel);
+        assert_eq!(chunked_copy_set_keep_partial(ctx, 1), SUCCESS);
+        assert_eq!(chunked_copy_open_source(ctx), SUCCESS);
+        let n = chunked_copy_read_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, ptr::null_mut());
+        assert_eq!(chunked_copy_write_chunk(ctx, buffer.as_ptr(), n as usize, None, ptr::null_mut()), SUCCESS);
+        cancel.store(true, Ordering::SeqCst);
+        assert_eq!(chunked_copy_read_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, ptr::null_mut()),
+                   ERROR_CANCELLED as isize);
+        assert!(dst.exists());
+        assert_eq!(chunked_copy_abort(ctx), SUCCESS);
+        assert!(!dst.exists());
+        cancel.store(false, Ordering::SeqCst);
+        assert_eq!(chunked_copy_write_chunk(ctx, buffer.as_ptr(), n as usize, None, ptr::null_mut()), ERROR_CANCELLED);
+        assert_eq!(chunked_copy_finalize(ctx, None, ptr::null_mut()), ERROR_CANCELLED);
+        chunked_copy_free(ctx);
+        assert!(!dst.exists());
+
+        // Freed without finalize or abort: aborted by free
+        let ctx = chunked_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024, ptr::null());
+        assert_eq!(chunked_copy_open_source(ctx), SUCCESS);
+        let n = chunked_copy_read_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, ptr::null_mut());
+        assert_eq!(chunked_copy_write_chunk(ctx, buffer.as_ptr(), n as usize, None, ptr::null_mut()), SUCCESS);
+        chunked_copy_free(ctx);
+        assert!(!dst.exists());
+
+        // Resuming into an existing file: abort cuts it back instead of deleting it
+        fs::write(&dst, b"first part").unwrap();
+        let ctx = chunked_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024, ptr::null());
+        assert_eq!(chunked_copy_seek_dest(ctx, 10), SUCCESS);
+        assert_eq!(chunked_copy_write_chunk(ctx, buffer.as_ptr(), n as usize, None, ptr::null_mut()), SUCCESS);
+        assert_eq!(chunked_copy_abort(ctx), SUCCESS);
+        chunked_copy_free(ctx);
+        assert_eq!(fs::read(&dst).unwrap(), b"first part");
+
+        // A finalized copy is kept by both
+        let ctx = chunked_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024, ptr::null());
+        pump_chunked_copy(ctx);
+        assert_eq!(chunked_copy_abort(ctx), SUCCESS);
+        chunked_copy_free(ctx);
+        assert_eq!(fs::read(&dst).unwrap(), content);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_chunked_copy_reset_reuses_context() {
+        let root = temp_dir("chunked_reset");
+        let src_a = root.join("a.bin");
+        let src_b = root.join("b.bin");
+        let content_a: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let content_b: Vec<u8> = (0..70_000).map(|i| (i % 13) as u8).collect();
+        fs::write(&src_a, &content_a).unwrap();
+        fs::write(&src_b, &content_b).unwrap();
+        let dst_a = root.join("a_copy.bin");
+        let dst_b = root.join("b_copy.bin");
+
+        let ctx = chunked_copy_init(c_path(&src_a).as_ptr(), c_path(&dst_a).as_ptr(), 0, ptr::null());
+        assert!(!ctx.is_null());
+        pump_chunked_copy(ctx);
+        assert_eq!(chunked_copy_get_dest_offset(ctx), content_a.len() as u64);
+
+        assert_eq!(chunked_copy_reset(ctx, c_path(&src_b).as_ptr(), c_path(&dst_b).as_ptr()), SUCCESS);
+        assert_eq!(chunked_copy_get_dest_offset(ctx), 0);
+        pump_chunked_copy(ctx);
+
+        let mut copied = 0usize;
+        let mut total = 0usize;
+        chunked_copy_get_progress(ctx, &mut copied, &mut total);
+        assert_eq!((copied, total), (content_b.len(), content_b.len()));
+        chunked_copy_free(ctx);
+
+        assert_eq!(fs::read(&dst_a).unwrap(), content_a);
+        assert_eq!(fs::read(&dst_b).unwrap(), content_b);
+
+        // Resetting onto a missing source leaves the error to the caller
+        let missing = root.join("missing.bin");
+        let ctx = chunked_copy_init(c_path(&src_a).as_ptr(), c_path(&dst_a).as_ptr(), 0, ptr::null());
+        assert_eq!(chunked_copy_reset(ctx, c_path(&missing).as_ptr(), c_path(&dst_b).as_ptr()), ERROR_FILE_NOT_FOUND);
+        chunked_copy_free(ctx);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_chunked_copy_progress_counts_written_bytes() {
+        let root = temp_dir("chunked_progress");
+        let src = root.join("source.bin");
+        let chunk = 64 * 1024;
+        fs::write(&src, vec![7u8; chunk * 4]).unwrap();
+        let dst = root.join("dest.bin");
+
+        let ctx = chunked_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), chunk, ptr::null());
+        assert!(!ctx.is_null());
+        assert_eq!(chunked_copy_open_source(ctx), SUCCESS);
+
+        // Read three chunks but only write two of them (the third is dropped)
+        let mut buffer = vec![0u8; chunk];
+        let mut calls: Vec<(usize, usize)> = Vec::new();
+        for i in 0..3 {
+            let n = chunked_copy_read_chunk(ctx, buffer.as_mut_ptr(), chunk, None, ptr::null_mut());
+            assert_eq!(n, chunk as isize);
+            if i < 2 {
+                let result = chunked_copy_write_chunk(
+                    ctx, buffer.as_ptr(), chunk, Some(record_progress),
+                    &mut calls as *mut _ as *mut c_void,
+                );
+                assert_eq!(result, SUCCESS);
+            }
+        }
+
+        let (mut read, mut written, mut total) = (0usize, 0usize, 0usize);
+        chunked_copy_get_progress_ex(ctx, &mut read, &mut written, &mut total);
+        assert_eq!((read, written, total), (chunk * 3, chunk * 2, chunk * 4));
+
+        let mut copied = 0usize;
+        chunked_copy_get_progress(ctx, &mut copied, ptr::null_mut());
+        assert_eq!(copied, chunk * 2);
+        assert!(calls.iter().all(|&(bytes, _)| bytes <= chunk * 2));
+
+        // The final callback reports written bytes, too
+        calls.clear();
+        assert_eq!(chunked_copy_finalize(ctx, Some(record_progress), &mut calls as *mut _ as *mut c_void), SUCCESS);
+        assert_eq!(calls, vec![(chunk * 2, chunk * 4)]);
+
+        // Reset clears both sides
+        assert_eq!(chunked_copy_reset(ctx, c_path(&src).as_ptr(), c_path(&dst).as_ptr()), SUCCESS);
+        chunked_copy_get_progress_ex(ctx, &mut read, &mut written, ptr::null_mut());
+        assert_eq!((read, written), (0, 0));
+        chunked_copy_free(ctx);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    /// Context and operation a reentrant callback calls back into
+    struct Reentry<C> {
+        ctx: *mut C,
+        operation_id: u64,
+        /// Progress passed to each callback next to what the accessor returned during it
+        seen: Vec<([usize; 2], [usize; 2])>,
+    }
+
+    extern "C" fn folder_reentrant_progress(bytes: usize, total: usize, files: usize, total_files: usize, user_data: *mut c_void) {
+        let reentry = unsafe { &mut *(user_data as *mut Reentry<FolderCopyContext>) };
+        let (mut b, mut t, mut f, mut tf) = (0usize, 0usize, 0usize, 0usize);
+        assert_eq!(folder_copy_get_progress(reentry.ctx, &mut b, &mut t, &mut f, &mut tf), SUCCESS);
+        assert_eq!((t, tf), (total, total_files));
+        reentry.seen.push(([bytes, files], [b, f]));
+        assert_eq!(crate::operations::cancel_operation(reentry.operation_id), SUCCESS);
+    }
+
+    extern "C" fn chunked_reentrant_progress(bytes: usize, total: usize, _files: usize, _total_files: usize, user_data: *mut c_void) {
+        let reentry = unsafe { &mut *(user_data as *mut Reentry<ChunkedCopyContext>) };
+        let (mut written, mut t) = (0usize, 0usize);
+        chunked_copy_get_progress(reentry.ctx, &mut written, &mut t);
+        assert_eq!(t, total);
+        reentry.seen.push(([bytes, 0], [written, 0]));
+        assert_eq!(crate::operations::cancel_operation(reentry.operation_id), SUCCESS);
+    }
+
+    extern "C" fn chunked_reentrant_data(_data: *mut u8, data_len: usize, user_data: *mut c_void) -> isize {
+        let reentry = unsafe { &mut *(user_data as *mut Reentry<ChunkedCopyContext>) };
+        let mut read = 0usize;
+        chunked_copy_get_progress_ex(reentry.ctx, &mut read, ptr::null_mut(), ptr::null_mut());
+        reentry.seen.push(([data_len, 0], [read, 0]));
+        data_len as isize
+    }
+
+    #[test]
+    fn test_callbacks_reenter_and_cancel() {
+        let root = temp_dir("copy_reentry");
+        let src = root.join("source");
+        fs::create_dir_all(&src).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(src.join(name), vec![7u8; 4096]).unwrap();
+        }
+
+        // Folder copy: the callback reads progress and cancels; the next file is not copied
+        let dst = root.join("dest");
+        let ctx = folder_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), ptr::null());
+        unsafe { (*ctx).progress_throttler = ProgressThrottler::with_percent_delta(1); }
+        let mut reentry = Reentry { ctx, operation_id: folder_copy_get_operation_id(ctx), seen: Vec::new() };
+        let user_data = &mut reentry as *mut Reentry<FolderCopyContext> as *mut c_void;
+        assert_eq!(folder_copy_next_file(ctx, Some(folder_reentrant_progress), user_data), 1);
+        assert_eq!(reentry.seen, vec![([4096, 1], [4096, 1])]);
+        assert_eq!(folder_copy_next_file(ctx, Some(folder_reentrant_progress), user_data), ERROR_CANCELLED);
+        assert_eq!(reentry.seen.len(), 1);
+        assert!(dst.join("a.txt").exists() && !dst.join("b.txt").exists());
+        folder_copy_free(ctx);
+
+        // Chunked copy: the data callback sees the chunk it was handed, the
+        // progress callback cancels and the next chunk fails
+        let file = src.join("a.txt");
+        let ctx = chunked_copy_init(c_path(&file).as_ptr(), c_path(&root.join("chunked.txt")).as_ptr(), 1024, ptr::null());
+        unsafe { (*ctx).progress_throttler = ProgressThrottler::with_percent_delta(1); }
+        assert_eq!(chunked_copy_open_source(ctx), SUCCESS);
+        let mut reentry = Reentry { ctx, operation_id: chunked_copy_get_operation_id(ctx), seen: Vec::new() };
+        let user_data = &mut reentry as *mut Reentry<ChunkedCopyContext> as *mut c_void;
+        let mut buffer = vec![0u8; 1024];
+        let n = chunked_copy_read_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), Some(chunked_reentrant_data), user_data);
+        assert_eq!(n, 1024);
+        assert_eq!(chunked_copy_write_chunk(ctx, buffer.as_ptr(), 1024, Some(chunked_reentrant_progress), user_data), SUCCESS);
+        assert_eq!(reentry.seen, vec![([1024, 0], [1024, 0]), ([1024, 0], [1024, 0])]);
+        assert_eq!(chunked_copy_read_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, ptr::null_mut()), ERROR_CANCELLED as isize);
+        assert_eq!(chunked_copy_write_chunk(ctx, buffer.as_ptr(), 1024, None, ptr::null_mut()), ERROR_CANCELLED);
+        chunked_copy_free(ctx);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_folder_copy_source_drift() {
+        let root = temp_dir("copy_drift");
+        for recount in [false, true] {
+            let src = root.join(format!("source_{}", recount));
+            let dst = root.join(format!("dest_{}", recount));
+            fs::create_dir_all(&src).unwrap();
+            for (name, size) in [("a.bin", 100), ("b.bin", 200), ("c.bin", 300), ("d.bin", 400)] {
+                fs::write(src.join(name), vec![1u8; size]).unwrap();
+            }
+
+            let ctx = folder_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), ptr::null());
+            assert_eq!(folder_copy_set_recount_on_drift(ctx, recount as u8), SUCCESS);
+            assert_eq!(folder_copy_next_file(ctx, None, ptr::null_mut()), 1);
+
+            // Change the source between calls: one file grows, one vanishes, one appears
+            fs::write(src.join("b.bin"), vec![2u8; 500]).unwrap();
+            fs::remove_file(src.join("c.bin")).unwrap();
+            fs::write(src.join("e.bin"), vec![3u8; 50]).unwrap();
+
+            let mut calls = 1;
+            loop {
+                let result = folder_copy_next_file(ctx, None, ptr::null_mut());
+                assert!(result >= 0, "next_file failed with {}", result);
+                let (mut bytes, mut total_bytes, mut files, mut total_files) = (0, 0, 0, 0);
+                assert_eq!(folder_copy_get_progress(ctx, &mut bytes, &mut total_bytes, &mut files, &mut total_files), SUCCESS);
+                assert!(bytes <= total_bytes && files <= total_files);
+                if result == 0 {
+                    // Complete per the snapshot: everything that was planned is accounted for
+                    assert_eq!((bytes, total_bytes), (100 + 500 + 400 + if recount { 50 } else { 0 }, bytes));
+                    assert_eq!(files, total_files);
+                    break;
+                }
+                calls += 1;
+                assert!(calls <= 5);
+            }
+            assert_eq!(folder_copy_finalize(ctx, None, ptr::null_mut()), SUCCESS);
+            let json = unsafe { CString::from_raw(folder_copy_get_manifest_json(ctx, ptr::null_mut())) };
+            folder_copy_free(ctx);
+
+            let envelope: serde_json::Value = serde_json::from_str(json.to_str().unwrap()).unwrap();
+            let manifest = &envelope["data"];
+            let statuses: Vec<(String, String)> = manifest["entries"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|e| (e["relative_path"].as_str().unwrap().to_string(), e["status"].as_str().unwrap().to_string()))
+                .collect();
+            assert_eq!(fs::read(dst.join("b.bin")).unwrap(), vec![2u8; 500]);
+            assert!(!dst.join("c.bin").exists());
+            if recount {
+                // The second walk no longer lists c.bin and picks up e.bin
+                let expected = [("a.bin", "copied"), ("b.bin", "copied"), ("d.bin", "copied"), ("e.bin", "copied")];
+                assert_eq!(statuses, expected.map(|(p, s)| (p.to_string(), s.to_string())));
+                assert_eq!(manifest["appeared_during_copy"], serde_json::json!([]));
+                assert!(dst.join("e.bin").exists());
+            } else {
+                let expected = [("a.bin", "copied"), ("b.bin", "copied"), ("c.bin", "skipped"), ("d.bin", "copied")];
+                assert_eq!(statuses, expected.map(|(p, s)| (p.to_string(), s.to_string())));
+                assert!(manifest["entries"][2]["error"].as_str().unwrap().contains("disappeared"));
+                assert_eq!(manifest["appeared_during_copy"], serde_json::json!(["e.bin"]));
+                assert!(!dst.join("e.bin").exists());
+                assert_eq!((manifest["copied"].as_u64(), manifest["skipped"].as_u64()), (Some(3), Some(1)));
+            }
+        }
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_folder_copy_manifest_timing() {
+        let root = temp_dir("copy_timing");
+        let src = root.join("source");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        for i in 0..12 {
+            let dir = if i % 3 == 0 { src.join("sub") } else { src.clone() };
+            fs::write(dir.join(format!("f{}.bin", i)), vec![i as u8; i * 300_000]).unwrap();
+        }
+
+        for (name, spill_threshold) in [("dest_memory", 0u64), ("dest_spilled", 1u64)] {
+            let dst = root.join(name);
+            let ctx = folder_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), ptr::null());
+            assert_eq!(folder_copy_set_manifest_spill_threshold(ctx, spill_threshold), SUCCESS);
+            while folder_copy_next_file(ctx, None, ptr::null_mut()) > 0 {}
+            assert_eq!(folder_copy_finalize(ctx, None, ptr::null_mut()), SUCCESS);
+            let json = unsafe { CString::from_raw(folder_copy_get_manifest_json(ctx, ptr::null_mut())) };
+            folder_copy_free(ctx);
+
+            let envelope: serde_json::Value = serde_json::from_str(json.to_str().unwrap()).unwrap();
+            let manifest = &envelope["data"];
+            let entries = manifest["entries"].as_array().unwrap();
+            assert_eq!(entries.len(), 12);
+
+            let field = |entry: &serde_json::Value, key: &str| entry[key].as_u64().unwrap_or_else(|| panic!("missing {}", key));
+            let mut durations = Vec::new();
+            let (mut read, mut write, mut flush) = (0, 0, 0);
+            for entry in entries {
+                let phases = field(entry, "read_ms") + field(entry, "write_ms") + field(entry, "flush_ms");
+                assert!(phases <= field(entry, "duration_ms"));
+                read += field(entry, "read_ms");
+                write += field(entry, "write_ms");
+                flush += field(entry, "flush_ms");
+                durations.push(field(entry, "duration_ms"));
+            }
+
+            let timing = &manifest["timing"];
+            assert!(timing["enumeration_ms"].as_u64().is_some());
+            assert_eq!(timing["transfer_ms"].as_u64(), Some(durations.iter().sum()));
+            assert_eq!((timing["read_ms"].as_u64(), timing["write_ms"].as_u64(), timing["flush_ms"].as_u64()),
+                       (Some(read), Some(write), Some(flush)));
+            let p50 = timing["file_duration_p50_ms"].as_u64().unwrap();
+            let p95 = timing["file_duration_p95_ms"].as_u64().unwrap();
+            assert!(p50 <= p95 && p95 <= *durations.iter().max().unwrap());
+            assert!(durations.contains(&p50) && durations.contains(&p95));
+            durations.sort_unstable();
+            assert_eq!((p50, p95), (durations[5], durations[11]));
+        }
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_folder_copy_manifest_with_failure() {
+        let root = temp_dir("copy_manifest");
+        let src = root.join("source");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        fs::write(src.join("b.txt"), b"bravo").unwrap();
+        fs::write(src.join("c.txt"), b"charlie").unwrap();
+        fs::write(src.join("sub/d.txt"), b"delta!").unwrap();
+
+        for (name, spill_threshold) in [("dest_memory", 0u64), ("dest_spilled", 1u64)] {
+            let dst = root.join(name);
+            let ctx = folder_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), ptr::null());
+            assert!(!ctx.is_null());
+            assert_eq!(folder_copy_set_continue_on_error(ctx, 1), SUCCESS);
+            assert_eq!(folder_copy_set_manifest_spill_threshold(ctx, spill_threshold), SUCCESS);
+
+            // Copy a.txt, then make b.txt fail (a directory is in the way) and c.txt vanish
+            assert_eq!(folder_copy_next_file(ctx, None, ptr::null_mut()), 1);
+            fs::create_dir_all(dst.join("b.txt")).unwrap();
+            let c_content = fs::read(src.join("c.txt")).unwrap();
+            fs::remove_file(src.join("c.txt")).unwrap();
+
+            while folder_copy_next_file(ctx, None, ptr::null_mut()) > 0 {}
+            fs::write(src.join("c.txt"), &c_content).unwrap();
+
+            let mut len = 0usize;
+            let pending = folder_copy_get_manifest_json(ctx, &mut len);
+            let pending: serde_json::Value =
+                serde_json::from_str(&unsafe { CString::from_raw(pending) }.into_string().unwrap()).unwrap();
+            assert_eq!(pending["ok"], false);
+            assert_eq!(pending["error"]["code"], ERROR_RESULT_UNAVAILABLE);
+            assert_eq!(folder_copy_finalize(ctx, None, ptr::null_mut()), SUCCESS);
+            let json_ptr = folder_copy_get_manifest_json(ctx, &mut len);
+            assert!(!json_ptr.is_null());
+            let json = unsafe { CString::from_raw(json_ptr) }.into_string().unwrap();
+            assert_eq!(json.len(), len);
+            folder_copy_free(ctx);
+
+            let envelope: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(envelope["ok"], true);
+            let manifest = &envelope["data"];
+            assert_eq!((manifest["copied"].as_u64(), manifest["skipped"].as_u64(), manifest["failed"].as_u64()),
+                       (Some(2), Some(1), Some(1)));
+            let entries = manifest["entries"].as_array().unwrap();
+            let summary: Vec<(&str, &str)> = entries.iter()
+                .map(|e| (e["relative_path"].as_str().unwrap(), e["status"].as_str().unwrap()))
+                .collect();
+            assert_eq!(summary, vec![("a.txt", "copied"), ("b.txt", "failed"), ("c.txt", "skipped"), ("sub/d.txt", "copied")]);
+
+            assert_eq!(entries[0]["source_size"], 5);
+            assert_eq!(entries[0]["dest_size"], 5);
+            assert!(entries[0]["source_mtime_ms"].as_u64().unwrap() > 0);
+            assert!(entries[0]["error"].is_null());
+            assert!(entries[1]["error"].as_str().is_some());
+            assert_eq!(entries[3]["dest_relative_path"], "sub/d.txt");
+            assert_eq!(fs::read(dst.join("sub/d.txt")).unwrap(), b"delta!");
+        }
+
+        // Without continue-on-error the failure stops the copy
+        let dst = root.join("dest_strict");
+        let ctx = folder_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), ptr::null());
+        assert_eq!(folder_copy_next_file(ctx, None, ptr::null_mut()), 1);
+        fs::create_dir_all(dst.join("b.txt")).unwrap();
+        assert!(folder_copy_next_file(ctx, None, ptr::null_mut()) < 0);
+        folder_copy_free(ctx);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_preserves_xattrs_by_policy() {
+        let root = temp_dir("copy_xattrs");
+        let src = root.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        // Filesystems without user attributes (tmpfs on older kernels) have nothing to preserve
+        if xattr::set(src.join("a.txt"), "user.cloud_nexus.tag", b"red").is_err() {
+            let _ = fs::remove_dir_all(&root);
+            return;
+        }
+
+        let mut failures = u32::MAX;
+        for (policy, name, expected) in [(XATTR_POLICY_IGNORE, "ignored.txt", None),
+                                         (XATTR_POLICY_PRESERVE, "preserved.txt", Some(b"red".to_vec()))] {
+            let dst = root.join(name);
+            let options = CCopyOptions { xattr_policy: policy, xattr_failures: &mut failures, ..Default::default() };
+            assert_eq!(copy_file_streaming_with_options(c_path(&src.join("a.txt")).as_ptr(), c_path(&dst).as_ptr(),
+                                                        4, &options, None, ptr::null(), ptr::null_mut()), SUCCESS);
+            assert_eq!(failures, 0);
+            assert_eq!(xattr::get(&dst, "user.cloud_nexus.tag").unwrap(), expected);
+        }
+        let options = CCopyOptions { xattr_policy: 7, xattr_failures: &mut failures, ..Default::default() };
+        assert_eq!(copy_file_streaming_with_options(c_path(&src.join("a.txt")).as_ptr(),
+                                                    c_path(&root.join("bad.txt")).as_ptr(), 4, &options, None,
+                                                    ptr::null(), ptr::null_mut()),
+                   ERROR_INVALID_XATTR_POLICY);
+        assert!(!root.join("bad.txt").exists());
+
+        let dst = root.join("dst");
+        let mut status = -1;
+        let ctx = folder_copy_init_with_options(c_path(&src).as_ptr(), c_path(&dst).as_ptr(),
+                                                ptr::null(), ptr::null(), &mut status);
+        assert_eq!(folder_copy_set_xattr_policy(ctx, 9), ERROR_INVALID_XATTR_POLICY);
+        assert_eq!(folder_copy_set_xattr_policy(ctx, XATTR_POLICY_STRIP_QUARANTINE_ONLY), SUCCESS);
+        while folder_copy_next_file(ctx, None, ptr::null_mut()) > 0 {}
+        assert_eq!(folder_copy_finalize(ctx, None, ptr::null_mut()), SUCCESS);
+        assert_eq!(xattr::get(dst.join("a.txt"), "user.cloud_nexus.tag").unwrap(), Some(b"red".to_vec()));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_folder_copy_refuses_nested_destination() {
+        let root = temp_dir("copy_nested_dest");
+        let src = root.join("photos");
+        fs::create_dir_all(src.join("2024")).unwrap();
+        fs::write(src.join("2024/a.jpg"), b"alpha").unwrap();
+
+        // Into the source, also through a relative spelling, before anything is created
+        let mut status = -1;
+        for dst in [src.join("backup"), src.join("2024/../backup/deeper")] {
+            let ctx = folder_copy_init_with_options(c_path(&src).as_ptr(), c_path(&dst).as_ptr(),
+                                                    ptr::null(), ptr::null(), &mut status);
+            assert!(ctx.is_null());
+            assert_eq!(status, ERROR_DEST_INSIDE_SOURCE);
+            assert!(!src.join("backup").exists());
+        }
+        let ctx = folder_copy_init_with_options(c_path(&src).as_ptr(), c_path(&src.join("2024")).as_ptr(),
+                                                &CFolderCopyOptions { dry_run: 1, ..Default::default() },
+                                                ptr::null(), &mut status);
+        assert!(ctx.is_null());
+        assert_eq!(status, ERROR_DEST_INSIDE_SOURCE);
+
+        // Onto itself
+        let ctx = folder_copy_init_with_options(c_path(&src).as_ptr(), c_path(&src).as_ptr(),
+                                                ptr::null(), ptr::null(), &mut status);
+        assert!(ctx.is_null());
+        assert_eq!(status, ERROR_SAME_PATH);
+
+        // Through a symlinked alias of the source
+        #[cfg(unix)]
+        {
+            let alias = root.join("alias");
+            std::os::unix::fs::symlink(&src, &alias).unwrap();
+            let ctx = folder_copy_init_with_options(c_path(&alias).as_ptr(), c_path(&src).as_ptr(),
+                                                    ptr::null(), ptr::null(), &mut status);
+            assert!(ctx.is_null());
+            assert_eq!(status, ERROR_SAME_PATH);
+            let ctx = folder_copy_init_with_options(c_path(&src).as_ptr(), c_path(&alias.join("backup")).as_ptr(),
+                                                    ptr::null(), ptr::null(), &mut status);
+            assert!(ctx.is_null());
+            assert_eq!(status, ERROR_DEST_INSIDE_SOURCE);
+            assert!(!src.join("backup").exists());
+        }
+
+        // A source inside the destination is an ordinary copy
+        let ctx = folder_copy_init_with_options(c_path(&src.join("2024")).as_ptr(), c_path(&src).as_ptr(),
+                                                ptr::null(), ptr::null(), &mut status);
+        assert!(!ctx.is_null());
+        assert_eq!(status, SUCCESS);
+        while folder_copy_next_file(ctx, None, ptr::null_mut()) > 0 {}
+        assert_eq!(folder_copy_finalize(ctx, None, ptr::null_mut()), SUCCESS);
+        folder_copy_free(ctx);
+        assert_eq!(fs::read(src.join("a.jpg")).unwrap(), b"alpha");
+
+        // Moves use the same check
+        assert_eq!(crate::trash::move_path(&src, &src.join("2024/moved")), Err(ERROR_DEST_INSIDE_SOURCE));
+        assert!(src.join("2024/a.jpg").is_file());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_folder_copy_into_existing_destination() {
+        let root = temp_dir("copy_existing_dest");
+        let src = root.join("source");
+        fs::create_dir_all(src.join("sub/deeper")).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        fs::write(src.join("sub/b.txt"), b"bravo").unwrap();
+        fs::write(src.join("sub/deeper/c.txt"), b"charlie").unwrap();
+
+        // A partial earlier attempt left some directories and a stale file behind
+        let dst = root.join("missing_parent/dest");
+        fs::create_dir_all(dst.join("sub")).unwrap();
+        fs::write(dst.join("sub/b.txt"), b"stale").unwrap();
+
+        let mut status = -1;
+        let ctx = folder_copy_init_with_options(c_path(&src).as_ptr(), c_path(&dst).as_ptr(),
+                                                &CFolderCopyOptions { require_empty_dest: 1, ..Default::default() },
+                                                ptr::null(), &mut status);
+        assert!(ctx.is_null());
+        assert_eq!(status, ERROR_DEST_NOT_EMPTY);
+
+        let ctx = folder_copy_init_with_options(c_path(&src).as_ptr(), c_path(&dst).as_ptr(),
+                                                ptr::null(), ptr::null(), &mut status);
+        assert!(!ctx.is_null());
+        assert_eq!(status, SUCCESS);
+        while folder_copy_next_file(ctx, None, ptr::null_mut()) > 0 {}
+        assert_eq!(folder_copy_finalize(ctx, None, ptr::null_mut()), SUCCESS);
+        folder_copy_free(ctx);
+        assert_eq!(fs::read(dst.join("sub/b.txt")).unwrap(), b"bravo");
+        assert_eq!(fs::read(dst.join("sub/deeper/c.txt")).unwrap(), b"charlie");
+
+        // Missing parents of the destination root are created
+        let nested = root.join("x/y/z");
+        let ctx = folder_copy_init(c_path(&src).as_ptr(), c_path(&nested).as_ptr(), ptr::null());
+        assert!(!ctx.is_null());
+        folder_copy_free(ctx);
+        assert!(nested.is_dir());
+
+        // A file where the destination root should be
+        let taken = root.join("taken");
+        fs::write(&taken, b"file").unwrap();
+        let ctx = folder_copy_init_with_options(c_path(&src).as_ptr(), c_path(&taken).as_ptr(),
+                                                ptr::null(), ptr::null(), &mut status);
+        assert!(ctx.is_null());
+        assert_eq!(status, ERROR_DEST_IS_FILE);
+
+        // A file where a subdirectory should be
+        let blocked = root.join("blocked");
+        fs::create_dir_all(&blocked).unwrap();
+        fs::write(blocked.join("sub"), b"file").unwrap();
+        let ctx = folder_copy_init(c_path(&src).as_ptr(), c_path(&blocked).as_ptr(), ptr::null());
+        let mut result = 1;
+        while result > 0 {
+            result = folder_copy_next_file(ctx, None, ptr::null_mut());
+        }
+        assert_eq!(result, ERROR_DEST_IS_FILE);
+        folder_copy_free(ctx);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    /// Every path under `root` with its contents (None for directories)
+    fn snapshot_tree(root: &Path) -> std::collections::BTreeMap<PathBuf, Option<Vec<u8>>> {
+        let mut tree = std::collections::BTreeMap::new();
+        let mut pending = vec![root.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                let rel = path.strip_prefix(root).unwrap().to_path_buf();
+                if path.is_dir() {
+                    tree.insert(rel, None);
+                    pending.push(path);
+                } else {
+                    tree.insert(rel, Some(fs::read(&path).unwrap()));
+                }
+            }
+        }
+        tree
+    }
+
+    fn finished_manifest(ctx: *mut FolderCopyContext, progress: Option<&mut Vec<(usize, usize)>>) -> serde_json::Value {
+        let (callback, user_data): (Option<CopyProgressCallback>, *mut c_void) = match progress {
+            Some(calls) => (Some(record_progress), calls as *mut _ as *mut c_void),
+            None => (None, ptr::null_mut()),
+        };
+        assert_eq!(folder_copy_finalize(ctx, callback, user_data), SUCCESS);
+        let json = unsafe { CString::from_raw(folder_copy_get_manifest_json(ctx, ptr::null_mut())) };
+        folder_copy_free(ctx);
+        let envelope: serde_json::Value = serde_json::from_str(json.to_str().unwrap()).unwrap();
+        envelope["data"].clone()
+    }
+
+    #[test]
+    fn test_folder_copy_dry_run_writes_nothing() {
+        let root = temp_dir("copy_dry_run");
+        let src = root.join("source");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::create_dir_all(src.join("blocked")).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        fs::write(src.join("b.txt"), b"bravo").unwrap();
+        fs::write(src.join("c.txt"), b"charlie").unwrap();
+        fs::write(src.join("sub/d.txt"), b"delta!").unwrap();
+        fs::write(src.join("blocked/e.txt"), b"echo").unwrap();
+
+        // b.txt is overwritten, a directory is in the way of c.txt and a file in the way of blocked/
+        let dst = root.join("dest");
+        fs::create_dir_all(dst.join("c.txt")).unwrap();
+        fs::write(dst.join("b.txt"), b"old").unwrap();
+        fs::write(dst.join("blocked"), b"file").unwrap();
+        let before = snapshot_tree(&dst);
+
+        let mut status = -1;
+        let ctx = folder_copy_init_with_options(c_path(&src).as_ptr(), c_path(&dst).as_ptr(),
+                                                &CFolderCopyOptions { dry_run: 1, ..Default::default() },
+                                                ptr::null(), &mut status);
+        assert_eq!(status, SUCCESS);
+        let mut progress: Vec<(usize, usize)> = Vec::new();
+        while folder_copy_next_file(ctx, Some(record_progress), &mut progress as *mut _ as *mut c_void) > 0 {}
+        let dry = finished_manifest(ctx, Some(&mut progress));
+        assert_eq!(snapshot_tree(&dst), before);
+        // Only files the copy would write count towards the bytes
+        assert_eq!(progress.last(), Some(&(16, 16 + 4 + 7)));
+
+        let report = &dry["dry_run"];
+        assert_eq!(report["files_to_copy"], 2);
+        assert_eq!(report["files_to_overwrite"], 1);
+        assert_eq!(report["files_to_fail"], 2);
+        assert_eq!(report["bytes_required"], 5 + 5 + 6);
+        assert_eq!(report["bytes_freed"], 3);
+        assert!(report["available_bytes"].as_u64().unwrap() > 0);
+        assert_eq!(report["shortfall_bytes"], 0);
+        let conflicts: Vec<(&str, &str, &str)> = report["conflicts"].as_array().unwrap().iter()
+            .map(|c| (c["dest_relative_path"].as_str().unwrap(), c["kind"].as_str().unwrap(), c["resolution"].as_str().unwrap()))
+            .collect();
+        assert_eq!(conflicts, vec![("b.txt", "file_exists", "overwrite"), ("blocked", "file_in_path", "fail"),
+                                   ("c.txt", "directory_exists", "fail")]);
+
+        // A real run with continue-on-error ends up with the projected entries
+        let ctx = folder_copy_init_with_options(c_path(&src).as_ptr(), c_path(&dst).as_ptr(),
+                                                ptr::null(), ptr::null(), &mut status);
+        assert_eq!(folder_copy_set_continue_on_error(ctx, 1), SUCCESS);
+        let mut result = 1;
+        while result > 0 {
+            result = folder_copy_next_file(ctx, None, ptr::null_mut());
+        }
+        // The real copy stops at the blocked directory; a dry run lists everything it would hit
+        assert_eq!(result, ERROR_DEST_IS_FILE);
+        let real = finished_manifest(ctx, None);
+        assert!(real["dry_run"].is_null());
+        let outcome = |manifest: &serde_json::Value| -> Vec<(String, String)> {
+            manifest["entries"].as_array().unwrap().iter()
+                .map(|e| (e["relative_path"].as_str().unwrap().to_string(), e["status"].as_str().unwrap().to_string()))
+                .collect()
+        };
+        let projected = outcome(&dry);
+        assert_eq!(outcome(&real), projected[..real["entries"].as_array().unwrap().len()].to_vec());
+        assert_eq!(real["copied"], 2);
+        assert_eq!(fs::read(dst.join("b.txt")).unwrap(), b"bravo");
+
+        // Dry runs do not create the destination root
+        let fresh = root.join("fresh/dest");
+        let ctx = folder_copy_init_with_options(c_path(&src).as_ptr(), c_path(&fresh).as_ptr(),
+                                                &CFolderCopyOptions { require_empty_dest: 1, dry_run: 1,
+                                                                      ..Default::default() },
+                                                ptr::null(), &mut status);
+        assert_eq!(status, SUCCESS);
+        while folder_copy_next_file(ctx, None, ptr::null_mut()) > 0 {}
+        let dry = finished_manifest(ctx, None);
+        assert_eq!(dry["dry_run"]["files_to_copy"], 5);
+        assert!(!root.join("fresh").exists());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_copy_file_dry_run() {
+        let root = temp_dir("copy_file_dry_run");
+        fs::create_dir_all(&root).unwrap();
+        let src = root.join("a.bin");
+        fs::write(&src, vec![7u8; 1000]).unwrap();
+
+        let dry_run_of = |src: &Path, dst: &Path| -> (i32, serde_json::Value) {
+            let mut report: *mut c_char = ptr::null_mut();
+            let options = CCopyOptions { dry_run: 1, dry_run_report: &mut report, ..Default::default() };
+            let result = copy_file_streaming_with_options(c_path(src).as_ptr(), c_path(dst).as_ptr(), 0, &options,
+                                                          None, ptr::null(), ptr::null_mut());
+            (result, serde_json::from_str(unsafe { CString::from_raw(report) }.to_str().unwrap()).unwrap())
+        };
+        let dry_run = |dst: &Path| -> serde_json::Value {
+            let (result, report) = dry_run_of(&src, dst);
+            assert_eq!(result, SUCCESS);
+            report
+        };
+
+        let new = dry_run(&root.join("b.bin"));
+        assert_eq!(new["data"]["files_to_copy"], 1);
+        assert_eq!(new["data"]["bytes_required"], 1000);
+        assert!(!root.join("b.bin").exists());
+
+        fs::write(root.join("b.bin"), b"old").unwrap();
+        let overwrite = dry_run(&root.join("b.bin"));
+        assert_eq!(overwrite["data"]["files_to_overwrite"], 1);
+        assert_eq!(overwrite["data"]["conflicts"][0]["kind"], "file_exists");
+        assert_eq!(fs::read(root.join("b.bin")).unwrap(), b"old");
+
+        let missing = dry_run(&root.join("missing/b.bin"));
+        assert_eq!(missing["data"]["files_to_fail"], 1);
+        assert_eq!(missing["data"]["conflicts"][0]["kind"], "missing_parent");
+
+        let (result, no_source) = dry_run_of(&root.join("nope"), &root.join("c"));
+        assert_eq!(result, ERROR_FILE_NOT_FOUND);
+        assert_eq!(no_source["error"]["code"], ERROR_FILE_NOT_FOUND);
+        assert!(!root.join("c").exists());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    /// Writer that runs `on_first_write` on another thread before the first chunk lands
+    struct ChangingSourceWriter<F: FnOnce() + Send + 'static> {
+        written: Vec<u8>,
+        on_first_write: Option<F>,
+    }
+
+    impl<F: FnOnce() + Send + 'static> Write for ChangingSourceWriter<F> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if let Some(change) = self.on_first_write.take() {
+                std::thread::spawn(change).join().unwrap();
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stream_copy_detects_source_size_changes() {
+        let root = temp_dir("copy_source_changed");
+        let src = root.join("growing.log");
+        let original: Vec<u8> = (0..300 * 1024u32).map(|i| i as u8).collect();
+
+        let run = |tolerate_growth: bool, change: fn(PathBuf)| {
+            fs::write(&src, &original).unwrap();
+            let mut reader = SourceReader::open(&src, false).unwrap();
+            let path = src.clone();
+            let mut writer = ChangingSourceWriter { written: Vec::new(), on_first_write: Some(move || change(path)) };
+            let mut progress: Vec<(usize, usize)> = Vec::new();
+            let result = stream_copy(&mut reader, &mut writer, original.len(), tolerate_growth, 64 * 1024,
+                                     Some(record_progress), ptr::null(), &mut progress as *mut _ as *mut c_void);
+            (result, writer.written, progress)
+        };
+        fn append(path: PathBuf) {
+            OpenOptions::new().append(true).open(path).unwrap().write_all(&[0xAB; 100 * 1024]).unwrap();
+        }
+        fn shrink(path: PathBuf) {
+            OpenOptions::new().write(true).open(path).unwrap().set_len(100 * 1024).unwrap();
+        }
+
+        let (result, _, _) = run(false, append);
+        assert_eq!(result, Err(ERROR_SOURCE_CHANGED));
+
+        let (result, written, progress) = run(true, append);
+        assert_eq!(result, Ok(400 * 1024));
+        assert_eq!(written, fs::read(&src).unwrap());
+        assert_eq!(progress.last(), Some(&(400 * 1024, 400 * 1024)));
+
+        // Shrinking is never tolerated
+        let (result, _, _) = run(true, shrink);
+        assert_eq!(result, Err(ERROR_SOURCE_CHANGED));
+
+        // An unchanged file copies as before and reports its size
+        fs::write(&src, &original).unwrap();
+        let dst = root.join("copy.log");
+        let mut final_size = 0u64;
+        let options = CCopyOptions { tolerate_growth: 1, final_size: &mut final_size, ..Default::default() };
+        assert_eq!(copy_file_streaming_with_options(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024, &options,
+                                                    None, ptr::null(), ptr::null_mut()),
+                   SUCCESS);
+        assert_eq!(final_size, original.len() as u64);
+        assert_eq!(fs::read(&dst).unwrap(), original);
+
+        // Fields past a shorter struct_size keep their defaults
+        let mut unread = 7u64;
+        let short = CCopyOptions { struct_size: std::mem::offset_of!(CCopyOptions, final_size) as u32,
+                                   final_size: &mut unread, ..Default::default() };
+        assert_eq!(copy_file_streaming_with_options(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024, &short,
+                                                    None, ptr::null(), ptr::null_mut()), SUCCESS);
+        assert_eq!(unread, 7);
+        let invalid = CCopyOptions { struct_size: 0, ..Default::default() };
+        assert_eq!(copy_file_streaming_with_options(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024, &invalid,
+                                                    None, ptr::null(), ptr::null_mut()), ERROR_INVALID_OPTIONS);
+        let mut status = 0;
+        let invalid = CFolderCopyOptions { struct_size: 0, ..Default::default() };
+        assert!(folder_copy_init_with_options(c_path(&root).as_ptr(), c_path(&root.join("out")).as_ptr(), &invalid,
+                                              ptr::null(), &mut status).is_null());
+        assert_eq!(status, ERROR_INVALID_OPTIONS);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    extern "C" fn record_phase(phase: u32, bytes_done: u64, bytes_total: u64, user_data: *mut c_void) {
+        let calls = unsafe { &mut *(user_data as *mut Vec<(u32, u64, u64)>) };
+        calls.push((phase, bytes_done, bytes_total));
+        // A slow first report lets the throttler pass the next one through
+        if bytes_done == 0 {
+            std::thread::sleep(std::time::Duration::from_millis(550));
+        }
+    }
+
+    #[test]
+    fn test_verified_copy_reports_phases_in_order() {
+        let root = temp_dir("verified_copy");
+        let src = root.join("source.bin");
+        let dst = root.join("copy.bin");
+        let content: Vec<u8> = (0..1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &content).unwrap();
+        let size = content.len() as u64;
+
+        let mut calls: Vec<(u32, u64, u64)> = Vec::new();
+        let phased = CCopyOptions { verify: 1, phase_callback: Some(record_phase), ..Default::default() };
+        assert_eq!(copy_file_streaming_with_options(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024,
+                                                    &phased, None, ptr::null(),
+                                                    &mut calls as *mut _ as *mut c_void), SUCCESS);
+        assert_eq!(fs::read(&dst).unwrap(), content);
+
+        // Copying, then verifying, each from 0 to the file size without going backwards
+        let mut phases: Vec<u32> = calls.iter().map(|c| c.0).collect();
+        phases.dedup();
+        assert_eq!(phases, vec![PHASE_COPYING, PHASE_VERIFYING]);
+        for phase in phases {
+            let done: Vec<u64> = calls.iter().filter(|c| c.0 == phase).map(|c| c.1).collect();
+            assert!(calls.iter().filter(|c| c.0 == phase).all(|c| c.2 == size));
+            assert_eq!((done.first(), done.last()), (Some(&0), Some(&size)));
+            assert!(done.windows(2).all(|w| w[0] < w[1]), "{:?}", done);
+        }
+        // The slow start of the verify pass lets an intermediate report through
+        assert!(calls.iter().filter(|c| c.0 == PHASE_VERIFYING).count() > 2);
+
+        // The aggregate climbs to 100% with copying weighted at a quarter
+        let weighted = [PHASE_COPYING, PHASE_VERIFYING];
+        let aggregator = crate::phase_aggregator_new(weighted.as_ptr(), [1.0, 3.0].as_ptr(), 2);
+        let mut percents = Vec::new();
+        for &(phase, done, total) in &calls {
+            let mut percent = 0.0;
+            assert_eq!(crate::phase_aggregator_update(aggregator, phase, done, total, &mut percent), SUCCESS);
+            percents.push(percent);
+        }
+        crate::phase_aggregator_free(aggregator);
+        assert!(percents.windows(2).all(|w| w[0] <= w[1]));
+        assert!(percents.contains(&25.0));
+        assert_eq!(percents.last(), Some(&100.0));
+
+        // Without a phase callback both passes count against twice the size
+        fs::remove_file(&dst).unwrap();
+        let mut progress: Vec<(usize, usize)> = Vec::new();
+        let plain = CCopyOptions { verify: 1, ..Default::default() };
+        assert_eq!(copy_file_streaming_with_options(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024,
+                                                    &plain, Some(record_progress), ptr::null(),
+                                                    &mut progress as *mut _ as *mut c_void), SUCCESS);
+        assert!(progress.windows(2).all(|w| w[0].0 <= w[1].0));
+        assert!(progress.contains(&(content.len(), content.len() * 2)));
+        assert_eq!(progress.last(), Some(&(content.len() * 2, content.len() * 2)));
+
+        // A cancelled copy leaves no destination behind
+        fs::remove_file(&dst).unwrap();
+        let cancel = AtomicBool::new(true);
+        assert_eq!(copy_file_streaming_with_options(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024,
+                                                    &plain, None, &cancel, ptr::null_mut()), ERROR_CANCELLED);
+        assert!(!dst.exists());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_folder_copy_plan_follows_sort_locale() {
+        let root = temp_dir("copy_sort_locale");
+        fs::create_dir_all(root.join("src/Photos")).unwrap();
+        for name in ["img10.jpg", "img2.jpg", "Photos/b.jpg", "Notes.txt"] {
+            fs::write(root.join("src").join(name), b"x").unwrap();
+        }
+        let plan_order = |sort_locale| -> Vec<String> {
+            let Ok(plan) = build_folder_copy_plan(&root.join("src"), 0, sort_locale) else { panic!("plan failed") };
+            plan.into_iter()
+                .map(|step| match step {
+                    FolderCopyStep::Dir(rel) | FolderCopyStep::File(rel, _) => rel.to_string_lossy().replace('\\', "/"),
+                })
+                .collect()
+        };
+
+        assert_eq!(plan_order(SortLocale::Byte), ["Notes.txt", "Photos", "Photos/b.jpg", "img10.jpg", "img2.jpg"]);
+        assert_eq!(plan_order(SortLocale::Natural), ["img2.jpg", "img10.jpg", "Notes.txt", "Photos", "Photos/b.jpg"]);
+
+        let ctx = folder_copy_init(c_path(&root.join("src")).as_ptr(), c_path(&root.join("dst")).as_ptr(), ptr::null());
+        assert_eq!(folder_copy_set_sort_locale(ctx, crate::name_order::SORT_LOCALE_NATURAL), SUCCESS);
+        assert_eq!(folder_copy_set_sort_locale(ctx, 42), ERROR_INVALID_SORT_LOCALE);
+        assert_eq!(unsafe { &*ctx }.sort_locale, SortLocale::Natural);
+        folder_copy_free(ctx);
+        assert_eq!(folder_copy_set_sort_locale(ptr::null_mut(), 0), ERROR_NULL_POINTER);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_deep_folder_copy_respects_max_depth() {
+        let root = temp_dir("copy_deep");
+        let src = root.join("source");
+
+        // 200 nested directories with a file at the bottom, built iteratively
+        fs::create_dir(&src).unwrap();
+        let mut deepest = src.clone();
+        for _ in 0..200 {
+            deepest.push("d");
+            fs::create_dir(&deepest).unwrap();
+        }
+        fs::write(deepest.join("bottom.txt"), b"deep").unwrap();
+        let deepest_rel = deepest.strip_prefix(&src).unwrap().to_path_buf();
+
+        // Unlimited and a limit above the tree's depth both copy everything
+        for (max_depth, dest_name) in [(0, "dest_unlimited"), (250, "dest_limited")] {
+            let dst = root.join(dest_name);
+            let ctx = folder_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), ptr::null());
+            assert_eq!(folder_copy_set_max_depth(ctx, max_depth), SUCCESS);
+            assert_eq!(folder_copy_next_file(ctx, None, ptr::null_mut()), 1);
+            assert_eq!(folder_copy_next_file(ctx, None, ptr::null_mut()), 0);
+            assert_eq!(folder_copy_finalize(ctx, None, ptr::null_mut()), SUCCESS);
+            let mut path: *mut c_char = ptr::null_mut();
+            assert_eq!(folder_copy_get_depth_error_path(ctx, &mut path), SUCCESS);
+            assert!(path.is_null());
+            folder_copy_free(ctx);
+            assert_eq!(fs::read(dst.join(&deepest_rel).join("bottom.txt")).unwrap(), b"deep");
+        }
+
+        // Beyond the limit the copy fails before writing and names the directory
+        let dst = root.join("dest_too_deep");
+        let ctx = folder_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), ptr::null());
+        assert_eq!(folder_copy_set_max_depth(ctx, 100), SUCCESS);
+        assert_eq!(folder_copy_next_file(ctx, None, ptr::null_mut()), ERROR_MAX_DEPTH_EXCEEDED);
+        let mut path: *mut c_char = ptr::null_mut();
+        assert_eq!(folder_copy_get_depth_error_path(ctx, &mut path), ERROR_MAX_DEPTH_EXCEEDED);
+        let path = unsafe { CString::from_raw(path) }.into_string().unwrap();
+        assert_eq!(path, vec!["d"; 101].join("/"));
+        folder_copy_free(ctx);
+        assert!(!dst.join("d").exists());
+
+        assert_eq!(folder_copy_set_max_depth(ptr::null_mut(), 1), ERROR_NULL_POINTER);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_folder_copy_renames_case_collisions() {
+        let root = temp_dir("copy_case_collisions");
+        let src = root.join("source");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("Report.pdf"), b"upper").unwrap();
+        fs::write(src.join("report.pdf"), b"lower").unwrap();
+        fs::write(src.join("notes.txt"), b"notes").unwrap();
+
+        // The probe answers for the volume the test runs on
+        let sensitive = crate::file_io::is_filesystem_case_sensitive(c_path(&root).as_ptr());
+        assert!(sensitive == 0 || sensitive == 1);
+        assert_eq!(crate::file_io::is_filesystem_case_sensitive(ptr::null()), ERROR_NULL_POINTER);
+        if sensitive == 0 {
+            // Both sources cannot exist side by side on this volume
+            let _ = fs::remove_dir_all(&root);
+            return;
+        }
+
+        let copy = |dest_name: &str, case_insensitive: u8| {
+            let ctx = folder_copy_init(c_path(&src).as_ptr(), c_path(&root.join(dest_name)).as_ptr(), ptr::null());
+            assert_eq!(folder_copy_set_dest_case_insensitive(ctx, case_insensitive), SUCCESS);
+            while folder_copy_next_file(ctx, None, ptr::null_mut()) == 1 {}
+            finished_manifest(ctx, None)
+        };
+
+        // Treated as case-insensitive, the second variant lands under a new name
+        let manifest = copy("dest_insensitive", 1);
+        let dst = root.join("dest_insensitive");
+        assert_eq!(fs::read(dst.join("Report.pdf")).unwrap(), b"upper");
+        assert_eq!(fs::read(dst.join("report (1).pdf")).unwrap(), b"lower");
+        assert_eq!(fs::read(dst.join("notes.txt")).unwrap(), b"notes");
+        assert_eq!(manifest["copied"], 3);
+        let entries = manifest["entries"].as_array().unwrap();
+        let entry = |rel: &str| entries.iter().find(|e| e["relative_path"] == rel).unwrap().clone();
+        assert_eq!(entry("report.pdf")["dest_relative_path"], "report (1).pdf");
+        assert_eq!(entry("report.pdf")["renamed_from"], "report.pdf");
+        assert!(entry("Report.pdf")["renamed_from"].is_null());
+        assert!(entry("notes.txt")["renamed_from"].is_null());
+
+        // A case-sensitive destination keeps both names as they are
+        let manifest = copy("dest_sensitive", 0);
+        assert_eq!(fs::read(root.join("dest_sensitive/report.pdf")).unwrap(), b"lower");
+        assert!(!root.join("dest_sensitive/report (1).pdf").exists());
+        assert!(manifest["entries"].as_array().unwrap().iter().all(|e| e["renamed_from"].is_null()));
+
+        assert_eq!(folder_copy_set_dest_case_insensitive(ptr::null_mut(), 1), ERROR_NULL_POINTER);
+        assert_eq!(folder_copy_set_rename_case_collisions(ptr::null_mut(), 1), ERROR_NULL_POINTER);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_folder_copy_renames_names_that_meet_after_sanitizing() {
+        let root = temp_dir("copy_sanitized_collisions");
+        let src = root.join("source");
+        fs::create_dir_all(&src).unwrap();
+        // A backslash is an ordinary character of a Unix name
+        fs::write(src.join("back\\slash.txt"), b"backslash").unwrap();
+        // The tab is replaced, giving the name of the next file
+        fs::write(src.join("tab\tname.txt"), b"tab").unwrap();
+        fs::write(src.join("tab_name.txt"), b"underscore").unwrap();
+
+        let ctx = folder_copy_init(c_path(&src).as_ptr(), c_path(&root.join("dest")).as_ptr(), ptr::null());
+        assert_eq!(folder_copy_set_dest_case_insensitive(ctx, 0), SUCCESS);
+        while folder_copy_next_file(ctx, None, ptr::null_mut()) == 1 {}
+        let manifest = finished_manifest(ctx, None);
+
+        let dst = root.join("dest");
+        assert_eq!(fs::read(dst.join("back\\slash.txt")).unwrap(), b"backslash");
+        assert!(!dst.join("back").exists());
+        assert_eq!(fs::read(dst.join("tab_name.txt")).unwrap(), b"tab");
+        assert_eq!(fs::read(dst.join("tab_name (1).txt")).unwrap(), b"underscore");
+        assert_eq!(manifest["copied"], 3);
+        let entries = manifest["entries"].as_array().unwrap();
+        let entry = |rel: &str| entries.iter().find(|e| e["relative_path"] == rel).unwrap().clone();
+        assert_eq!(entry("tab_name.txt")["dest_relative_path"], "tab_name (1).txt");
+        assert_eq!(entry("tab_name.txt")["renamed_from"], "tab_name.txt");
+        assert!(entry("tab\tname.txt")["renamed_from"].is_null());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
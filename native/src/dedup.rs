@@ -0,0 +1,280 @@
+/// Chunk deduplication support for CloudNexus
+/// Plaintext chunk fingerprints and "reference" chunk records for block-level sync
+///
+/// When an upload is fingerprinted, chunks the server already holds are replaced in
+/// the encrypted stream by a reference record instead of ciphertext:
+/// - chunk_index (4 bytes, little-endian)
+/// - chunk_size (4 bytes, little-endian) = CHUNK_REFERENCE_FLAG | FINGERPRINT_SIZE
+/// - nonce slot (12 bytes, zero)
+/// - BLAKE3 fingerprint of the plaintext chunk (32 bytes)
+///
+/// Containers that may contain reference records carry FORMAT_VERSION_DEDUP in the
+/// main header so version-1 readers refuse them instead of misparsing.
+use std::ffi::c_char;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+
+use crate::errors::checked;
+use crate::file_io::{ERROR_CANCELLED, ERROR_FILE_NOT_FOUND, ERROR_IO_FAILED, ERROR_NULL_POINTER,
+                     SUCCESS, c_str_to_path, is_cancelled};
+use crate::source_reader::SourceReader;
+use crate::NONCE_SIZE;
+
+/// Size of a chunk fingerprint in bytes
+pub const FINGERPRINT_SIZE: usize = 32;
+
+/// Main header version for containers that may contain reference chunks
+pub const FORMAT_VERSION_DEDUP: u8 = 2;
+
+/// High bit of the chunk size field marks a reference record
+pub const CHUNK_REFERENCE_FLAG: u32 = 0x8000_0000;
+
+/// Size of a chunk record header: index (4) + size (4) + nonce (12)
+const CHUNK_RECORD_HEADER_SIZE: usize = 4 + 4 + NONCE_SIZE;
+
+/// Read size used when hashing whole files
+const HASH_FILE_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Fingerprint callback return value: upload the chunk normally
+pub const CHUNK_FINGERPRINT_UPLOAD: i32 = 0;
+/// Fingerprint callback return value: the server already has the chunk, skip it
+pub const CHUNK_FINGERPRINT_SKIP: i32 = 1;
+
+/// Compute the BLAKE3 fingerprint of a plaintext chunk
+pub fn chunk_fingerprint(data: &[u8]) -> [u8; FINGERPRINT_SIZE] {
+    *blake3::hash(data).as_bytes()
+}
+
+/// Build a reference record for a skipped chunk
+pub fn build_reference_record(chunk_index: u32, fingerprint: &[u8; FINGERPRINT_SIZE]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(CHUNK_RECORD_HEADER_SIZE + FINGERPRINT_SIZE);
+    record.extend_from_slice(&chunk_index.to_le_bytes());
+    record.extend_from_slice(&(CHUNK_REFERENCE_FLAG | FINGERPRINT_SIZE as u32).to_le_bytes());
+    record.extend_from_slice(&[0u8; NONCE_SIZE]);
+    record.extend_from_slice(fingerprint);
+    record
+}
+
+/// Parse a chunk record
+///
+/// Returns Ok(Some((chunk_index, fingerprint))) for a reference record, Ok(None) for a
+/// regular encrypted chunk, Err(()) if the record is malformed
+pub fn parse_reference_record(record: &[u8]) -> Result<Option<(u32, [u8; FINGERPRINT_SIZE])>, ()> {
+    if record.len() < CHUNK_RECORD_HEADER_SIZE {
+        return Err(());
+    }
+
+    let chunk_index = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+    let size_field = u32::from_le_bytes([record[4], record[5], record[6], record[7]]);
+
+    if size_field & CHUNK_REFERENCE_FLAG == 0 {
+        return Ok(None);
+    }
+
+    if (size_field & !CHUNK_REFERENCE_FLAG) as usize != FINGERPRINT_SIZE
+        || record.len() < CHUNK_RECORD_HEADER_SIZE + FINGERPRINT_SIZE {
+        return Err(());
+    }
+
+    let mut fingerprint = [0u8; FINGERPRINT_SIZE];
+    fingerprint.copy_from_slice(&record[CHUNK_RECORD_HEADER_SIZE..CHUNK_RECORD_HEADER_SIZE + FINGERPRINT_SIZE]);
+    Ok(Some((chunk_index, fingerprint)))
+}
+
+/// Check whether an encrypted chunk record is a dedup reference
+///
+/// # Arguments
+/// * `chunk` - Pointer to the chunk record (including its 20-byte header)
+/// * `chunk_len` - Length of the chunk record
+/// * `fingerprint_out` - Buffer of at least 32 bytes receiving the fingerprint (can be null)
+///
+/// # Returns
+/// 1 if the record is a reference, 0 if it is a regular encrypted chunk, negative error code
+/// if the record is malformed
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chunk_reference_fingerprint(
+    chunk: *const u8,
+    chunk_len: usize,
+    fingerprint_out: *mut u8,
+) -> i32 {
+    if chunk.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let record = unsafe { std::slice::from_raw_parts(chunk, chunk_len) };
+
+    match parse_reference_record(record) {
+        Ok(Some((_, fingerprint))) => {
+            if !fingerprint_out.is_null() {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(fingerprint.as_ptr(), fingerprint_out, FINGERPRINT_SIZE);
+                }
+            }
+            1
+        }
+        Ok(None) => SUCCESS,
+        Err(_) => ERROR_IO_FAILED,
+    }
+}
+
+/// Hash a whole local file with BLAKE3, one chunk per cancellation check
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn hash_file_impl(path: &Path, use_mmap: bool, cancel_flag: *const AtomicBool) -> Result<[u8; FINGERPRINT_SIZE], i32> {
+    let mut reader = SourceReader::open(path, use_mmap).map_err(|_| ERROR_FILE_NOT_FOUND)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; HASH_FILE_CHUNK_SIZE];
+
+    loop {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err(ERROR_CANCELLED);
+        }
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update(&buffer[..n]);
+            }
+            Err(_) => return Err(ERROR_IO_FAILED),
+        }
+    }
+
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Compute the BLAKE3 hash of a whole local file
+///
+/// Produces the same digest as chunk_fingerprint over the full file contents,
+/// so the host can detect unchanged files before fingerprinting their chunks.
+///
+/// # Arguments
+/// * `path` - Path to the local file
+/// * `use_mmap` - 1 to memory-map the file when possible, 0 for buffered reads
+/// * `hash_out` - Buffer of at least 32 bytes receiving the hash
+/// * `cancel_flag` - Cancellation flag (checked once per 1MB read)
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn hash_file(
+    path: *const c_char,
+    use_mmap: u8,
+    hash_out: *mut u8,
+    cancel_flag: *const AtomicBool,
+) -> i32 {
+    if path.is_null() || hash_out.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let path = match unsafe { c_str_to_path(path) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+
+    match hash_file_impl(&path, use_mmap != 0, cancel_flag) {
+        Ok(hash) => {
+            unsafe { std::ptr::copy_nonoverlapping(hash.as_ptr(), hash_out, FINGERPRINT_SIZE); }
+            SUCCESS
+        }
+        Err(e) => checked(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::ffi::{c_void, CString};
+    use std::fs;
+    use std::ptr;
+    use std::slice;
+
+    use crate::upload::{upload_init_ex, upload_get_header, upload_process_chunk, upload_free};
+    use crate::{decrypt_file_init, decrypt_chunk, decrypt_file_finalize, free_buffer};
+
+    struct Server {
+        known: HashMap<[u8; FINGERPRINT_SIZE], Vec<u8>>,
+        records: Vec<Vec<u8>>,
+    }
+
+    extern "C" fn every_other_chunk(chunk_index: u32, hash: *const u8, _user_data: *mut c_void) -> i32 {
+        assert!(!hash.is_null());
+        if chunk_index % 2 == 1 { CHUNK_FINGERPRINT_SKIP } else { CHUNK_FINGERPRINT_UPLOAD }
+    }
+
+    extern "C" fn collect_record(data: *const u8, data_len: usize, _chunk_index: u32, user_data: *mut c_void) {
+        let server = unsafe { &mut *(user_data as *mut Server) };
+        server.records.push(unsafe { slice::from_raw_parts(data, data_len) }.to_vec());
+    }
+
+    #[test]
+    fn test_reference_record_round_trip() {
+        let fingerprint = chunk_fingerprint(b"hello");
+        let record = build_reference_record(7, &fingerprint);
+        assert_eq!(parse_reference_record(&record), Ok(Some((7, fingerprint))));
+        assert!(parse_reference_record(&record[..10]).is_err());
+    }
+
+    #[test]
+    fn test_skipped_chunks_reconstruct_file() {
+        let chunk_size = 64 * 1024;
+        let content: Vec<u8> = (0..(chunk_size * 5 + 123)).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("cloud_nexus_{}_dedup.bin", std::process::id()));
+        fs::write(&path, &content).unwrap();
+
+        // The server already has every other chunk
+        let mut server = Server { known: HashMap::new(), records: Vec::new() };
+        for (i, chunk) in content.chunks(chunk_size).enumerate() {
+            if i % 2 == 1 {
+                server.known.insert(chunk_fingerprint(chunk), chunk.to_vec());
+            }
+        }
+
+        let key = [5u8; 32];
+        let path_c = CString::new(path.to_string_lossy().to_string()).unwrap();
+        let ctx = upload_init_ex(
+            path_c.as_ptr(), key.as_ptr(), 32, chunk_size, 1,
+            Some(every_other_chunk), ptr::null_mut(), ptr::null(),
+        );
+        assert!(!ctx.is_null());
+
+        let mut header = [0u8; 12];
+        let mut fek = [0u8; 256];
+        let mut fek_len = 0usize;
+        assert_eq!(upload_get_header(ctx, header.as_mut_ptr(), fek.as_mut_ptr(), fek.len(), &mut fek_len), SUCCESS);
+        assert_eq!(header[4], FORMAT_VERSION_DEDUP);
+
+        let mut buffer = vec![0u8; chunk_size + 64];
+        let user_data = &mut server as *mut Server as *mut c_void;
+        while upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_record), user_data) > 0 {}
+        upload_free(ctx);
+        assert_eq!(server.records.len(), 6);
+
+        // Reassemble: decrypt regular chunks, fetch referenced chunks from the server
+        let mut prefix = header.to_vec();
+        prefix.extend_from_slice(&fek[..fek_len]);
+        let dec = decrypt_file_init(prefix.as_ptr(), prefix.len(), key.as_ptr(), 32);
+        assert!(!dec.is_null());
+
+        let mut rebuilt = Vec::new();
+        for record in &server.records {
+            let mut fingerprint = [0u8; FINGERPRINT_SIZE];
+            match chunk_reference_fingerprint(record.as_ptr(), record.len(), fingerprint.as_mut_ptr()) {
+                1 => rebuilt.extend_from_slice(&server.known[&fingerprint]),
+                0 => {
+                    let mut out_len = 0usize;
+                    let plain = decrypt_chunk(dec, record.as_ptr(), record.len(), &mut out_len);
+                    assert!(!plain.is_null());
+                    rebuilt.extend_from_slice(unsafe { slice::from_raw_parts(plain, out_len) });
+                    free_buffer(plain);
+                }
+                e => panic!("malformed record: {}", e),
+            }
+        }
+        decrypt_file_finalize(dec);
+
+        assert_eq!(rebuilt, content);
+        let _ = fs::remove_file(&path);
+    }
+}
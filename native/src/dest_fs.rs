@@ -0,0 +1,347 @@
+// Destination filesystem limits for CloudNexus
+// SD cards and USB sticks are usually FAT32 or exFAT. FAT32 cannot store a
+// file of 4 GiB or more, and neither accepts the characters Windows reserves
+// (`"*/:<>?\|`) or names ending in a dot or space. Writing such a file fails
+// midway with a generic I/O error, so copies and downloads detect the
+// destination filesystem up front and fail with a specific error instead.
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::ffi_util::{set_last_error_detail, ErrorEnvelope};
+use crate::file_io::{
+    sanitize_file_name_with, PathError, PathSanitizeMode, PathSanitizePolicy, DEFAULT_MAX_COMPONENT_LENGTH,
+};
+
+pub use crate::errors::{ERROR_FILE_TOO_LARGE_FOR_FS, ERROR_NAME_NOT_ALLOWED_ON_FS};
+
+/// Largest file a FAT volume can hold (4 GiB - 1)
+pub const FAT_MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+/// Filesystem of a copy or download destination, as far as its limits matter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DestFilesystem {
+    /// FAT12/16/32: 4 GiB file size limit and Windows naming rules
+    Fat,
+    /// exFAT: Windows naming rules
+    ExFat,
+    /// Any other filesystem, or one that could not be detected
+    #[default]
+    Other,
+}
+
+impl DestFilesystem {
+    /// Largest file the filesystem can hold, None when there is no practical limit
+    pub fn max_file_size(self) -> Option<u64> {
+        match self {
+            DestFilesystem::Fat => Some(FAT_MAX_FILE_SIZE),
+            DestFilesystem::ExFat | DestFilesystem::Other => None,
+        }
+    }
+
+    /// Whether a file of `size` bytes is too large for the filesystem
+    pub fn exceeds_file_size(self, size: u64) -> bool {
+        self.max_file_size().is_some_and(|max| size > max)
+    }
+
+    /// Name rules the filesystem imposes beyond the platform's, None for none
+    pub fn name_policy(self, mode: PathSanitizeMode) -> Option<PathSanitizePolicy> {
+        match self {
+            DestFilesystem::Fat | DestFilesystem::ExFat => Some(PathSanitizePolicy {
+                mode,
+                windows_compatible: true,
+                max_component_length: DEFAULT_MAX_COMPONENT_LENGTH,
+                ..Default::default()
+            }),
+            DestFilesystem::Other => None,
+        }
+    }
+
+    /// Apply the filesystem's rules to a single file or directory name
+    pub fn sanitize_name(self, name: &str, mode: PathSanitizeMode) -> Result<String, PathError> {
+        match self.name_policy(mode) {
+            Some(policy) => sanitize_file_name_with(name, &policy),
+            None => Ok(name.to_string()),
+        }
+    }
+
+    /// Check a single file or directory name against the filesystem's rules
+    pub fn check_name(self, name: &str) -> Result<(), PathError> {
+        self.sanitize_name(name, PathSanitizeMode::Reject).map(|_| ())
+    }
+
+    /// Message for a file too large for the filesystem
+    pub fn file_too_large_message(self, size: u64) -> String {
+        format!("{} bytes exceed the {} byte file size limit of a {} volume", size,
+                self.max_file_size().unwrap_or(u64::MAX), self.label())
+    }
+
+    /// Message for a name the filesystem rejects
+    pub fn name_error_message(self, error: &PathError) -> String {
+        format!("{} on a {} volume", error, self.label())
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            DestFilesystem::Fat => "FAT",
+            DestFilesystem::ExFat => "exFAT",
+            DestFilesystem::Other => "local",
+        }
+    }
+}
+
+/// Check a destination file before anything is written to it
+///
+/// Detects the filesystem of `dest`, then checks the file name and `size`
+/// (0 when unknown) against its limits. A failure is recorded as the thread's
+/// last error with the offending character or size.
+pub fn check_dest_file(dest: &Path, size: u64) -> Result<DestFilesystem, i32> {
+    let filesystem = detect_dest_filesystem(dest);
+    if let Some(name) = dest.file_name() {
+        if let Err(error) = filesystem.check_name(&name.to_string_lossy()) {
+            return Err(set_last_error_detail(
+                ErrorEnvelope::new(ERROR_NAME_NOT_ALLOWED_ON_FS, filesystem.name_error_message(&error))
+                    .with_context(name.to_string_lossy()),
+            ));
+        }
+    }
+    if filesystem.exceeds_file_size(size) {
+        return Err(set_last_error_detail(
+            ErrorEnvelope::new(ERROR_FILE_TOO_LARGE_FOR_FS, filesystem.file_too_large_message(size))
+                .with_context(dest.to_string_lossy()),
+        ));
+    }
+    Ok(filesystem)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Filesystem reported by detect_dest_filesystem on this thread, for tests
+    static DETECT_OVERRIDE: std::cell::Cell<Option<DestFilesystem>> = const { std::cell::Cell::new(None) };
+}
+
+/// Make detect_dest_filesystem report `filesystem` on this thread (None to detect again)
+#[cfg(test)]
+pub(crate) fn override_dest_filesystem(filesystem: Option<DestFilesystem>) {
+    DETECT_OVERRIDE.with(|cell| cell.set(filesystem));
+}
+
+/// Detect the filesystem a destination path is (or will be) written to
+///
+/// A path that does not exist yet is looked up through its nearest existing
+/// ancestor. Anything that cannot be detected is DestFilesystem::Other.
+pub fn detect_dest_filesystem(path: &Path) -> DestFilesystem {
+    #[cfg(test)]
+    if let Some(filesystem) = DETECT_OVERRIDE.with(|cell| cell.get()) {
+        return filesystem;
+    }
+
+    match path.ancestors().find(|p| !p.as_os_str().is_empty() && p.exists()) {
+        Some(existing) => volume_filesystem(existing),
+        None => volume_filesystem(Path::new(".")),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn volume_filesystem(path: &Path) -> DestFilesystem {
+    use std::os::unix::ffi::OsStrExt;
+
+    const MSDOS_SUPER_MAGIC: u32 = 0x4D44;
+    const EXFAT_SUPER_MAGIC: u32 = 0x2011_BAB0;
+
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return DestFilesystem::Other;
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return DestFilesystem::Other;
+    }
+    match stat.f_type as u32 {
+        MSDOS_SUPER_MAGIC => DestFilesystem::Fat,
+        EXFAT_SUPER_MAGIC => DestFilesystem::ExFat,
+        _ => DestFilesystem::Other,
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn volume_filesystem(path: &Path) -> DestFilesystem {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return DestFilesystem::Other;
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return DestFilesystem::Other;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    match name.to_bytes() {
+        b"msdos" => DestFilesystem::Fat,
+        b"exfat" => DestFilesystem::ExFat,
+        _ => DestFilesystem::Other,
+    }
+}
+
+#[cfg(windows)]
+fn volume_filesystem(path: &Path) -> DestFilesystem {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetVolumePathNameW(file_name: *const u16, volume_path: *mut u16, length: u32) -> i32;
+        fn GetVolumeInformationW(root: *const u16, volume_name: *mut u16, volume_name_size: u32,
+                                 serial: *mut u32, max_component_length: *mut u32, flags: *mut u32,
+                                 fs_name: *mut u16, fs_name_size: u32) -> i32;
+    }
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+    let mut root = [0u16; 261];
+    if unsafe { GetVolumePathNameW(wide.as_ptr(), root.as_mut_ptr(), root.len() as u32) } == 0 {
+        return DestFilesystem::Other;
+    }
+    let mut fs_name = [0u16; 32];
+    let ok = unsafe {
+        GetVolumeInformationW(root.as_ptr(), ptr::null_mut(), 0, ptr::null_mut(), ptr::null_mut(),
+                              ptr::null_mut(), fs_name.as_mut_ptr(), fs_name.len() as u32)
+    };
+    if ok == 0 {
+        return DestFilesystem::Other;
+    }
+    let len = fs_name.iter().position(|&c| c == 0).unwrap_or(fs_name.len());
+    match String::from_utf16_lossy(&fs_name[..len]).to_ascii_uppercase().as_str() {
+        "FAT" | "FAT32" => DestFilesystem::Fat,
+        "EXFAT" => DestFilesystem::ExFat,
+        _ => DestFilesystem::Other,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", windows)))]
+fn volume_filesystem(_path: &Path) -> DestFilesystem {
+    DestFilesystem::Other
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::fs::{self, File};
+    use std::ptr;
+
+    use crate::copy::{copy_file_streaming_with_options, folder_copy_finalize, folder_copy_free, folder_copy_get_manifest_json,
+                      folder_copy_init, folder_copy_next_file, folder_copy_set_continue_on_error,
+                      folder_copy_set_replace_invalid_names};
+    use crate::download::{download_append_chunk, download_free, download_init_v2, download_set_total_bytes};
+    use crate::ffi_util::{clear_last_error, get_last_error_json};
+    use crate::test_util::{c_path, temp_dir};
+    use crate::SUCCESS;
+
+    fn last_error() -> serde_json::Value {
+        let json = unsafe { CString::from_raw(get_last_error_json(ptr::null_mut())) };
+        let envelope: serde_json::Value = serde_json::from_str(json.to_str().unwrap()).unwrap();
+        envelope["data"].clone()
+    }
+
+    fn copy(src: &Path, dst: &Path) -> i32 {
+        copy_file_streaming_with_options(c_path(src).as_ptr(), c_path(dst).as_ptr(), 0, ptr::null(), None, ptr::null(),
+                                         ptr::null_mut())
+    }
+
+    #[test]
+    fn test_fat_limits_checked_before_writing() {
+        let root = temp_dir("single");
+        let big = root.join("video.mp4");
+        // Sparse, so the test does not write 5 GB
+        File::create(&big).unwrap().set_len(5 << 30).unwrap();
+        let small = root.join("notes.txt");
+        fs::write(&small, b"notes").unwrap();
+
+        override_dest_filesystem(Some(DestFilesystem::Fat));
+        clear_last_error();
+        let dst = root.join("card").join("video.mp4");
+        fs::create_dir_all(dst.parent().unwrap()).unwrap();
+        assert_eq!(copy(&big, &dst), ERROR_FILE_TOO_LARGE_FOR_FS);
+        assert!(!dst.exists());
+        assert_eq!(last_error()["code"], ERROR_FILE_TOO_LARGE_FOR_FS);
+
+        let bad_name = root.join("card").join("a:b.txt");
+        assert_eq!(copy(&small, &bad_name), ERROR_NAME_NOT_ALLOWED_ON_FS);
+        let error = last_error();
+        assert!(error["message"].as_str().unwrap().contains(':'), "{}", error);
+        assert_eq!(copy(&small, &root.join("card").join("trailing.")), ERROR_NAME_NOT_ALLOWED_ON_FS);
+        assert_eq!(copy(&small, &root.join("card").join("notes.txt")), SUCCESS);
+
+        // exFAT has no 4 GiB limit but the same name rules
+        override_dest_filesystem(Some(DestFilesystem::ExFat));
+        assert!(!DestFilesystem::ExFat.exceeds_file_size(5 << 30));
+        assert_eq!(copy(&small, &bad_name), ERROR_NAME_NOT_ALLOWED_ON_FS);
+
+        // Downloads check the name at init and the size before the first write
+        override_dest_filesystem(Some(DestFilesystem::Fat));
+        let mut status = 0;
+        let ctx = download_init_v2(c_path(&bad_name).as_ptr(), ptr::null(), 0, crate::ENCRYPTION_MODE_NONE, ptr::null(),
+                                   &mut status);
+        assert!(ctx.is_null());
+        assert_eq!(status, ERROR_NAME_NOT_ALLOWED_ON_FS);
+
+        let download = root.join("card").join("movie.mkv");
+        let ctx = download_init_v2(c_path(&download).as_ptr(), ptr::null(), 0, crate::ENCRYPTION_MODE_NONE, ptr::null(),
+                                   &mut status);
+        assert_eq!(status, SUCCESS);
+        download_set_total_bytes(ctx, 5 << 30);
+        let data = [7u8; 16];
+        assert_eq!(download_append_chunk(ctx, data.as_ptr(), data.len(), None, ptr::null_mut()),
+                   ERROR_FILE_TOO_LARGE_FOR_FS);
+        download_set_total_bytes(ctx, data.len());
+        assert_eq!(download_append_chunk(ctx, data.as_ptr(), data.len(), None, ptr::null_mut()), SUCCESS);
+        download_free(ctx);
+
+        override_dest_filesystem(None);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    fn folder_manifest(src: &Path, dst: &Path, replace: bool) -> serde_json::Value {
+        let ctx = folder_copy_init(c_path(src).as_ptr(), c_path(dst).as_ptr(), ptr::null());
+        assert_eq!(folder_copy_set_continue_on_error(ctx, 1), SUCCESS);
+        assert_eq!(folder_copy_set_replace_invalid_names(ctx, replace as u8), SUCCESS);
+        while folder_copy_next_file(ctx, None, ptr::null_mut()) > 0 {}
+        assert_eq!(folder_copy_finalize(ctx, None, ptr::null_mut()), SUCCESS);
+        let json = unsafe { CString::from_raw(folder_copy_get_manifest_json(ctx, ptr::null_mut())) };
+        folder_copy_free(ctx);
+        let envelope: serde_json::Value = serde_json::from_str(json.to_str().unwrap()).unwrap();
+        envelope["data"].clone()
+    }
+
+    #[test]
+    fn test_fat_name_policy_in_folder_copy() {
+        let root = temp_dir("folder");
+        let src = root.join("source");
+        fs::create_dir_all(&src).unwrap();
+        for name in ["ok.txt", "what?.txt", "draft."] {
+            fs::write(src.join(name), name.as_bytes()).unwrap();
+        }
+
+        override_dest_filesystem(Some(DestFilesystem::Fat));
+        let manifest = folder_manifest(&src, &root.join("rejected"), false);
+        assert_eq!(manifest["filesystem"], "fat");
+        assert_eq!((manifest["copied"].as_u64(), manifest["failed"].as_u64()), (Some(1), Some(2)));
+        let errors: Vec<&str> = manifest["entries"].as_array().unwrap().iter()
+            .filter_map(|entry| entry["error"].as_str())
+            .collect();
+        assert!(errors.iter().any(|error| error.contains("'?'")), "{:?}", errors);
+        assert!(errors.iter().any(|error| error.contains("ends with a dot")), "{:?}", errors);
+
+        let manifest = folder_manifest(&src, &root.join("replaced"), true);
+        assert_eq!(manifest["copied"].as_u64(), Some(3));
+        for name in ["ok.txt", "what_.txt", "draft"] {
+            assert!(root.join("replaced").join(name).is_file(), "{} missing", name);
+        }
+
+        override_dest_filesystem(None);
+        let manifest = folder_manifest(&src, &root.join("local"), false);
+        assert_ne!(manifest["filesystem"], serde_json::Value::Null);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -1,17 +1,40 @@
 /// Download operations for CloudNexus
 /// Handles streaming file downloads with optional decryption and progress reporting
 use std::fs::File;
-use std::io::{Write, BufWriter};
-use std::path::PathBuf;
+use std::io::{Write, BufWriter, Seek};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
-use std::ffi::{c_char, c_void, CStr};
+use std::ffi::{c_char, c_void, CStr, CString};
+#[cfg(unix)]
+use std::ffi::c_int;
 use std::ptr;
 use std::slice;
 
+use crate::errors::checked;
 use crate::file_io::{ProgressThrottler, ERROR_NULL_POINTER, ERROR_FILE_NOT_FOUND,
                      ERROR_PERMISSION_DENIED, ERROR_IO_FAILED, ERROR_CANCELLED,
-                     ERROR_INVALID_PATH, ERROR_DISK_FULL, SUCCESS, c_str_to_path, is_cancelled};
-use crate::{DecryptionContext, decrypt_chunk, decrypt_file_init, decrypt_file_finalize};
+                     ERROR_DISK_FULL, SUCCESS, c_str_to_path, is_cancelled,
+                     cleanup_partial_output};
+#[cfg(unix)]
+use crate::file_io::file_from_fd;
+#[cfg(windows)]
+use crate::file_io::file_from_handle;
+use crate::governor::TransferPacer;
+use crate::operations::{Operation, OperationKind};
+use crate::temp::{create_temp_file_for, commit_temp_file, discard_temp_file};
+use crate::open_files::{open_limited, OpenFilePermit};
+use crate::encrypt_copy::MAX_CHUNK_CONTENT;
+use crate::phase_progress::{PhaseProgressCallback, PhaseTracker, PHASE_DECRYPTING, PHASE_DOWNLOADING};
+use crate::metrics::{self, Counter};
+use crate::copy::CallbackGuard;
+use crate::quarantine::{quarantine_partial_output, ChunkFailure};
+use crate::dest_fs::{check_dest_file, DestFilesystem, ERROR_FILE_TOO_LARGE_FOR_FS};
+use crate::ffi_util::{set_last_error_detail, ErrorEnvelope};
+use crate::{DecryptionContext, decrypt_chunk_strict, decrypt_file_init, decrypt_file_finalize, parse_chunk_header,
+            decryption_context_expected_index, legacy_encryption_mode, master_key_for_mode, master_key_error,
+            ERROR_CHUNK_CRC_MISMATCH, ERROR_CHUNK_OUT_OF_ORDER, ERROR_DECRYPTION_FAILED, ERROR_MALFORMED_CONTAINER, HEADER_SIZE, MAGIC, MAX_FEK_REGION_LENGTH};
+
+pub use crate::errors::{ERROR_NEED_MORE_DATA, ERROR_DECRYPTION_FAILED_AT_CHUNK};
 
 /// Progress callback for download operations
 pub type DownloadProgressCallback = extern "C" fn(bytes_written: usize, total_bytes: usize, user_data: *mut c_void);
@@ -20,39 +43,274 @@ pub type DownloadProgressCallback = extern "C" fn(bytes_written: usize, total_by
 #[repr(C)]
 pub struct DownloadContext {
     output_file: *mut BufWriter<File>,
+    /// Open file permit of output_file, when it is this download's temp file
+    output_permit: Option<OpenFilePermit>,
     file_path: PathBuf,
+    temp_path: PathBuf,
     decryption_context: Option<*mut DecryptionContext>,
     master_key: Vec<u8>,
     bytes_written: usize,
+    /// Bytes of bytes_written flushed out of the write buffer by download_flush or finalize
+    bytes_durable: usize,
     total_bytes: usize,
     should_decrypt: bool,
     cancel_flag: *const AtomicBool,
     progress_throttler: ProgressThrottler,
+    phase_tracker: PhaseTracker,
+    /// Bytes passed to download_append_chunk, before any decryption
+    bytes_received: usize,
+    pacer: TransferPacer,
+    keep_partial: bool,
+    /// Output goes straight to a caller-provided descriptor; there is no temp file
+    direct_output: bool,
+    /// Position in the caller-provided descriptor where the download started writing
+    direct_start: u64,
+    /// Finalized or aborted; free leaves the output alone
     is_finalized: bool,
+    aborted: bool,
     header_written: bool,
+    /// Received bytes not yet forming a complete header or encrypted chunk
+    pending: Vec<u8>,
+    /// Bytes of the encrypted stream decrypted so far (header included)
+    stream_consumed: u64,
+    /// Where partial output goes when a chunk fails to decrypt; None deletes it
+    quarantine_dir: Option<PathBuf>,
+    /// Index of the chunk that failed to decrypt, once one has
+    failed_chunk: Option<u32>,
+    /// Filesystem of the destination, detected at init
+    dest_fs: DestFilesystem,
+    callback_guard: CallbackGuard<DownloadProgress>,
+    operation: Operation,
+}
+
+/// Progress of a download, as download_get_bytes_written and download_get_total_bytes report it
+///
+/// download_append_chunk, download_append_chunk_phased, download_append_decrypted
+/// and download_finalize_with_progress invoke their callbacks only while no
+/// reference to the context is held, so a callback may call back into the
+/// library. While it runs, download_get_bytes_written and download_get_total_bytes
+/// return the values the progress callback was given (for phase callbacks, the
+/// progress when the phase was reported), download_get_bytes_durable,
+/// download_get_operation_id and get_operation_progress work as usual, and
+/// cancel_operation takes effect at the next append. A callback must not free
+/// the context or call a function that advances or configures it.
+#[derive(Debug, Clone, Copy, Default)]
+struct DownloadProgress {
+    bytes_written: usize,
+    total_bytes: usize,
 }
 
 impl DownloadContext {
-    pub fn new(file_path: PathBuf, total_bytes: usize, should_decrypt: bool,
+    pub fn new(file_path: PathBuf, temp_path: PathBuf, total_bytes: usize, should_decrypt: bool,
                master_key: Vec<u8>, cancel_flag: *const AtomicBool) -> Self {
+        let operation = Operation::register(OperationKind::Download, Path::new(""), &file_path,
+                                            total_bytes as u64, 1, cancel_flag);
         Self {
             output_file: ptr::null_mut(),
+            output_permit: None,
             file_path,
+            temp_path,
             decryption_context: None,
             master_key,
             bytes_written: 0,
+            bytes_durable: 0,
             total_bytes,
             should_decrypt,
-            cancel_flag,
+            cancel_flag: operation.cancel_flag(),
             progress_throttler: ProgressThrottler::new(500),
+            phase_tracker: PhaseTracker::new(),
+            bytes_received: 0,
+            pacer: TransferPacer::new(),
+            keep_partial: false,
+            direct_output: false,
+            direct_start: 0,
             is_finalized: false,
+            aborted: false,
             header_written: false,
+            pending: Vec::new(),
+            stream_consumed: 0,
+            quarantine_dir: None,
+            failed_chunk: None,
+            dest_fs: DestFilesystem::Other,
+            callback_guard: CallbackGuard::default(),
+            operation,
+        }
+    }
+
+    /// Fail with ERROR_FILE_TOO_LARGE_FOR_FS, before anything more is written,
+    /// when the expected size or `additional` more bytes exceed what the
+    /// destination filesystem can hold
+    fn check_fs_room(&self, additional: usize) -> Result<(), i32> {
+        let size = (self.bytes_written + additional).max(self.total_bytes) as u64;
+        if !self.dest_fs.exceeds_file_size(size) {
+            return Ok(());
+        }
+        Err(set_last_error_detail(
+            ErrorEnvelope::new(ERROR_FILE_TOO_LARGE_FOR_FS, self.dest_fs.file_too_large_message(size))
+                .with_context(self.file_path.to_string_lossy()),
+        ))
+    }
+
+    /// Close the output and apply the partial output policy after a cancellation
+    fn cancel(&mut self) -> i32 {
+        if !self.output_file.is_null() {
+            let writer = unsafe { Box::from_raw(self.output_file) };
+            self.output_file = ptr::null_mut();
+            self.output_permit = None;
+            // Flushed so a kept partial file holds everything written so far
+            drop(writer);
+            if !self.direct_output {
+                cleanup_partial_output(&self.temp_path, self.keep_partial);
+            }
+        }
+        ERROR_CANCELLED
+    }
+
+    /// Discard everything this download wrote and release its resources
+    ///
+    /// The temp file is deleted; a caller-provided descriptor that is still
+    /// open is cut back to where the download started writing.
+    fn abort(&mut self) {
+        if let Some(dec_ctx) = self.decryption_context.take() {
+            decrypt_file_finalize(dec_ctx);
+        }
+        if !self.output_file.is_null() {
+            let writer = unsafe { Box::from_raw(self.output_file) };
+            self.output_file = ptr::null_mut();
+            self.output_permit = None;
+            // Buffered bytes are dropped instead of written out
+            let (file, _) = writer.into_parts();
+            if self.direct_output && file.metadata().is_ok_and(|m| m.is_file() && m.len() > self.direct_start) {
+                let _ = file.set_len(self.direct_start);
+            }
+        }
+        if !self.direct_output {
+            cleanup_partial_output(&self.temp_path, false);
+        }
+        self.pending.clear();
+        self.is_finalized = true;
+        self.aborted = true;
+    }
+
+    /// Close the output after a chunk failed to decrypt and take the partial
+    /// output away from where it could be opened
+    ///
+    /// The temp file is moved into the quarantine directory, or deleted when
+    /// none is set. A caller-provided descriptor is only flushed; the caller
+    /// owns that file and decides what to do with it.
+    fn quarantine(&mut self, chunk_index: u32, error_code: i32) -> i32 {
+        self.failed_chunk = Some(chunk_index);
+        if !self.output_file.is_null() {
+            drop(unsafe { Box::from_raw(self.output_file) });
+            self.output_file = ptr::null_mut();
+            self.output_permit = None;
+        }
+
+        if !self.direct_output {
+            if let Some(dir) = &self.quarantine_dir {
+                let failure = ChunkFailure {
+                    chunk_index,
+                    stream_offset: self.stream_consumed,
+                    bytes_written: self.bytes_written as u64,
+                    error_code,
+                };
+                let _ = quarantine_partial_output(&self.temp_path, &self.file_path, dir, &failure);
+            }
+            // Unregisters a quarantined temp file, deletes it otherwise (or if the move failed)
+            let _ = discard_temp_file(&self.temp_path);
+        }
+        ERROR_DECRYPTION_FAILED_AT_CHUNK
+    }
+
+    /// Decrypt the container header and every complete encrypted chunk in `pending`
+    ///
+    /// Incomplete trailing bytes stay buffered for the next append.
+    fn decrypt_pending(&mut self) -> Result<(), i32> {
+        let mut consumed = 0;
+
+        if self.decryption_context.is_none() {
+            if self.pending.len() >= 4 && self.pending[..4] != MAGIC.to_le_bytes() {
+                return Err(ERROR_IO_FAILED);
+            }
+            if self.pending.len() < HEADER_SIZE {
+                return Ok(());
+            }
+            let fek_len = u32::from_le_bytes([self.pending[8], self.pending[9], self.pending[10], self.pending[11]]) as usize;
+            // Reject an oversized FEK region now rather than buffering toward it
+            if fek_len > MAX_FEK_REGION_LENGTH {
+                return Err(ERROR_MALFORMED_CONTAINER);
+            }
+            if self.pending.len() < HEADER_SIZE + fek_len {
+                return Ok(());
+            }
+
+            let dec_ctx = unsafe {
+                decrypt_file_init(self.pending.as_ptr(), HEADER_SIZE + fek_len,
+                                  self.master_key.as_ptr(), self.master_key.len())
+            };
+            if dec_ctx.is_null() {
+                return Err(ERROR_IO_FAILED);
+            }
+            self.decryption_context = Some(dec_ctx);
+            self.header_written = true;
+            consumed = HEADER_SIZE + fek_len;
+        }
+
+        // One append may complete several chunks; each call reports where the next starts
+        let dec_ctx = self.decryption_context.unwrap();
+        while consumed < self.pending.len() {
+            let rest = &self.pending[consumed..];
+            let mut decrypted_size: usize = 0;
+            let mut chunk_len: usize = 0;
+            let mut status = SUCCESS;
+            let decrypted = decrypt_chunk_strict(dec_ctx, rest.as_ptr(), rest.len(), &mut decrypted_size,
+                                                 &mut chunk_len, &mut status);
+            if status == ERROR_NEED_MORE_DATA {
+                // A corrupt size field would otherwise buffer the rest of the stream
+                match parse_chunk_header(rest) {
+                    Some((_, _, content_len)) if content_len > MAX_CHUNK_CONTENT => return Err(ERROR_IO_FAILED),
+                    _ => break,
+                }
+            }
+            if status == ERROR_MALFORMED_CONTAINER || status == ERROR_CHUNK_OUT_OF_ORDER {
+                return Err(status);
+            }
+            if status == ERROR_DECRYPTION_FAILED || status == ERROR_CHUNK_CRC_MISMATCH {
+                self.stream_consumed += consumed as u64;
+                self.pending.clear();
+                return Err(self.quarantine(decryption_context_expected_index(dec_ctx), status));
+            }
+            if decrypted.is_null() {
+                return Err(ERROR_IO_FAILED);
+            }
+            if let Err(code) = self.check_fs_room(decrypted_size) {
+                unsafe { libc::free(decrypted as *mut c_void); }
+                return Err(code);
+            }
+
+            let writer = unsafe { &mut *self.output_file };
+            let decrypted_slice = unsafe { slice::from_raw_parts(decrypted, decrypted_size) };
+            let written = writer.write_all(decrypted_slice);
+            unsafe { libc::free(decrypted as *mut c_void); }
+            if written.is_err() {
+                return Err(ERROR_IO_FAILED);
+            }
+
+            self.bytes_written += decrypted_size;
+            consumed += chunk_len;
         }
+
+        self.pending.drain(..consumed);
+        self.stream_consumed += consumed as u64;
+        Ok(())
     }
 }
 
 /// Initialize download context
 ///
+/// With `should_decrypt` set, a null master key or one that is not 32 bytes
+/// long fails the init instead of writing the container undecrypted.
+///
 /// # Arguments
 /// * `local_file_path` - Path where the downloaded file will be saved
 /// * `master_key` - Pointer to 32-byte master decryption key (can be null for no decryption)
@@ -74,41 +332,216 @@ pub extern "C" fn download_init(
     cancel_flag: *const AtomicBool,
     user_data: *mut c_void,
 ) -> *mut DownloadContext {
+    download_init_v2(
+        local_file_path,
+        master_key,
+        master_key_len,
+        legacy_encryption_mode(should_decrypt),
+        cancel_flag,
+        ptr::null_mut(),
+    )
+}
+
+/// Initialize download context with an explicit encryption mode
+///
+/// The master key is checked before anything is created on disk:
+/// ENCRYPTION_MODE_REQUIRED fails with ERROR_MASTER_KEY_REQUIRED unless a 32-byte
+/// key is given, and ENCRYPTION_MODE_OPTIONAL writes the stream as is only when
+/// the key is null or empty.
+///
+/// # Arguments
+/// * `local_file_path` - Path where the downloaded file will be saved
+/// * `master_key` - Pointer to 32-byte master decryption key (can be null)
+/// * `master_key_len` - Length of master key
+/// * `encryption_mode` - ENCRYPTION_MODE_NONE, ENCRYPTION_MODE_REQUIRED or ENCRYPTION_MODE_OPTIONAL
+/// * `cancel_flag` - Pointer to atomic bool for cancellation
+/// * `status` - Optional pointer receiving 0, or the error code when null is returned
+///   (ERROR_MASTER_KEY_REQUIRED, ERROR_INVALID_ENCRYPTION_MODE, ERROR_PERMISSION_DENIED,
+///   ERROR_NAME_NOT_ALLOWED_ON_FS for a file name a FAT or exFAT destination cannot store, ...)
+///
+/// # Returns
+/// Pointer to DownloadContext, or null on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn download_init_v2(
+    local_file_path: *const c_char,
+    master_key: *const u8,
+    master_key_len: usize,
+    encryption_mode: i32,
+    cancel_flag: *const AtomicBool,
+    status: *mut i32,
+) -> *mut DownloadContext {
+    // `report` for failures already recorded as the last error, `fail` for the rest
+    let report = |code: i32| {
+        if !status.is_null() {
+            unsafe { *status = code; }
+        }
+        ptr::null_mut()
+    };
+    let fail = |error: ErrorEnvelope| report(set_last_error_detail(error));
+
+    let key = match master_key_for_mode(master_key, master_key_len, encryption_mode) {
+        Ok(key) => key,
+        Err(code) => return fail(master_key_error(code)),
+    };
+
     if local_file_path.is_null() {
-        return ptr::null_mut();
+        return fail(ErrorEnvelope::null_argument("local_file_path"));
     }
 
     // Convert path
     let path = match unsafe { c_str_to_path(local_file_path) } {
         Ok(p) => p,
-        Err(e) => return ptr::null_mut(),
+        Err(code) => return fail(ErrorEnvelope::new(code, "invalid destination path").with_context("local_file_path")),
     };
 
-    // Create output file
-    let file = match File::create(&path) {
-        Ok(f) => f,
-        Err(_) => return ptr::null_mut(),
+    // A name the destination filesystem cannot store would only fail at finalize
+    let dest_fs = match check_dest_file(&path, 0) {
+        Ok(dest_fs) => dest_fs,
+        Err(code) => return report(code),
     };
 
-    // Get master key
-    let key = if !master_key.is_null() && master_key_len == 32 {
-        unsafe { slice::from_raw_parts(master_key, 32).to_vec() }
-    } else {
-        Vec::new()
+    // Write into a temp file next to the destination; download_finalize renames it into place
+    let (temp_path, file) = match create_temp_file_for(&path) {
+        Ok(t) => t,
+        Err(e) => {
+            return fail(ErrorEnvelope::new(ERROR_PERMISSION_DENIED, format!("cannot create the download file: {}", e))
+                .with_context(path.to_string_lossy()));
+        }
     };
 
     // Create context
-    let context = Box::new(DownloadContext::new(
+    let should_decrypt = !key.is_empty();
+    let mut context = Box::new(DownloadContext::new(
         path,
+        temp_path,
+        0, // Unknown total bytes initially
+        should_decrypt,
+        key,
+        cancel_flag,
+    ));
+    context.dest_fs = dest_fs;
+    context.output_file = Box::into_raw(Box::new(BufWriter::new(file)));
+
+    if !status.is_null() {
+        unsafe { *status = SUCCESS; }
+    }
+    Box::leak(context) as *mut DownloadContext
+}
+
+/// Cut `file` off at its current position if it extends beyond it
+///
+/// Streams without a position (pipes, sockets) are left alone.
+fn truncate_to_position(file: &mut File) -> std::io::Result<()> {
+    let position = match file.stream_position() {
+        Ok(position) => position,
+        Err(_) => return Ok(()),
+    };
+    let metadata = file.metadata()?;
+    if metadata.is_file() && metadata.len() > position {
+        file.set_len(position)?;
+    }
+    Ok(())
+}
+
+/// Create a download context that writes into a caller-opened file
+fn download_init_direct(
+    file: std::io::Result<File>,
+    master_key: *const u8,
+    master_key_len: usize,
+    should_decrypt: i32,
+    cancel_flag: *const AtomicBool,
+) -> *mut DownloadContext {
+    let key = match master_key_for_mode(master_key, master_key_len, legacy_encryption_mode(should_decrypt)) {
+        Ok(key) => key,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    let mut file = match file {
+        Ok(f) => f,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    let should_decrypt = !key.is_empty();
+    let mut context = Box::new(DownloadContext::new(
+        PathBuf::new(),
+        PathBuf::new(),
         0, // Unknown total bytes initially
-        should_decrypt == 1,
+        should_decrypt,
         key,
         cancel_flag,
     ));
+    context.direct_output = true;
+    context.direct_start = file.stream_position().unwrap_or(0);
+    context.output_file = Box::into_raw(Box::new(BufWriter::new(file)));
 
     Box::leak(context) as *mut DownloadContext
 }
 
+/// Initialize a download into an open file descriptor (e.g. an Android SAF destination)
+///
+/// The descriptor is duplicated, so the caller keeps ownership of `dest_fd` and
+/// must close it; download_finalize flushes and closes only the duplicate. Data is
+/// written from the descriptor's current position, without a temp file, so a
+/// cancelled or failed download leaves whatever was written for the caller to
+/// discard, unless download_abort cuts it off. download_finalize truncates the file after the last byte written, so
+/// a destination that held a longer file does not keep its stale tail.
+///
+/// # Arguments
+/// * `dest_fd` - Writable file descriptor owned by the caller
+/// * `master_key` - Pointer to 32-byte master decryption key (can be null for no decryption)
+/// * `master_key_len` - Length of master key (must be 0 or 32)
+/// * `should_decrypt` - 1 if decryption should be used, 0 otherwise
+/// * `progress_callback` - Optional progress callback
+/// * `cancel_flag` - Pointer to atomic bool for cancellation
+/// * `user_data` - User data pointer passed to callbacks
+///
+/// # Returns
+/// Pointer to DownloadContext, or null on error
+#[cfg(unix)]
+#[no_mangle]
+pub extern "C" fn download_init_fd(
+    dest_fd: c_int,
+    master_key: *const u8,
+    master_key_len: usize,
+    should_decrypt: i32,
+    _progress_callback: Option<DownloadProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    _user_data: *mut c_void,
+) -> *mut DownloadContext {
+    download_init_direct(file_from_fd(dest_fd), master_key, master_key_len, should_decrypt, cancel_flag)
+}
+
+/// Initialize a download into an open file HANDLE
+///
+/// Windows equivalent of download_init_fd. The handle is duplicated, so the
+/// caller keeps ownership of `dest_handle` and must close it.
+///
+/// # Arguments
+/// * `dest_handle` - Writable file HANDLE owned by the caller
+/// * `master_key` - Pointer to 32-byte master decryption key (can be null for no decryption)
+/// * `master_key_len` - Length of master key (must be 0 or 32)
+/// * `should_decrypt` - 1 if decryption should be used, 0 otherwise
+/// * `progress_callback` - Optional progress callback
+/// * `cancel_flag` - Pointer to atomic bool for cancellation
+/// * `user_data` - User data pointer passed to callbacks
+///
+/// # Returns
+/// Pointer to DownloadContext, or null on error
+#[cfg(windows)]
+#[no_mangle]
+pub extern "C" fn download_init_handle(
+    dest_handle: *mut c_void,
+    master_key: *const u8,
+    master_key_len: usize,
+    should_decrypt: i32,
+    _progress_callback: Option<DownloadProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    _user_data: *mut c_void,
+) -> *mut DownloadContext {
+    download_init_direct(file_from_handle(dest_handle), master_key, master_key_len, should_decrypt, cancel_flag)
+}
+
 /// Initialize download with known total size
 #[no_mangle]
 pub extern "C" fn download_init_with_size(
@@ -132,7 +565,7 @@ pub extern "C" fn download_init_with_size(
     );
 
     if !context.is_null() {
-        unsafe { (&mut *context).total_bytes = total_bytes; }
+        download_set_total_bytes(context, total_bytes);
     }
 
     context
@@ -141,6 +574,9 @@ pub extern "C" fn download_init_with_size(
 /// Append encrypted chunk to download stream
 /// Decrypts if needed and writes to file
 ///
+/// When decrypting, chunks may split the container anywhere (even inside the
+/// header); bytes are buffered until a whole encrypted chunk has arrived.
+///
 /// # Arguments
 /// * `context` - Pointer to DownloadContext
 /// * `encrypted_data` - Pointer to encrypted chunk data
@@ -149,7 +585,10 @@ pub extern "C" fn download_init_with_size(
 /// * `user_data` - User data
 ///
 /// # Returns
-/// 0 on success, error code on failure
+/// 0 on success, error code on failure (ERROR_MALFORMED_CONTAINER when a header
+/// or chunk size field is out of range, ERROR_CHUNK_OUT_OF_ORDER when a chunk's
+/// header index shows it reordered, dropped or replayed; the index is not
+/// authenticated, see decrypt_chunk_strict)
 #[no_mangle]
 pub extern "C" fn download_append_chunk(
     context: *mut DownloadContext,
@@ -158,161 +597,206 @@ pub extern "C" fn download_append_chunk(
     progress_callback: Option<DownloadProgressCallback>,
     user_data: *mut c_void,
 ) -> i32 {
-    if context.is_null() {
-        return ERROR_NULL_POINTER;
-    }
+    checked(counted(download_append_chunk_impl(context, encrypted_data, data_len, progress_callback, None, user_data)))
+}
 
-    let ctx = unsafe { &mut *context };
+/// Append a chunk of the download stream, reporting progress per phase
+///
+/// Same as download_append_chunk, with progress reported through a
+/// PhaseProgressCallback. PHASE_DOWNLOADING counts the bytes appended so far;
+/// its total is the download_set_total_bytes value for plain downloads and
+/// unknown (0) for decrypting ones, whose container is larger than the file.
+/// Decrypting downloads also report PHASE_DECRYPTING, counting plaintext
+/// written against the download_set_total_bytes value.
+///
+/// # Arguments
+/// * `context` - Pointer to DownloadContext
+/// * `encrypted_data` - Pointer to encrypted chunk data
+/// * `data_len` - Length of encrypted data
+/// * `phase_callback` - Phased progress callback
+/// * `user_data` - User data
+///
+/// # Returns
+/// 0 on success, error code on failure (as download_append_chunk)
+#[no_mangle]
+pub extern "C" fn download_append_chunk_phased(
+    context: *mut DownloadContext,
+    encrypted_data: *const u8,
+    data_len: usize,
+    phase_callback: Option<PhaseProgressCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    checked(counted(download_append_chunk_impl(context, encrypted_data, data_len, None, phase_callback, user_data)))
+}
 
-    // Check cancellation
-    if unsafe { is_cancelled(ctx.cancel_flag) } {
-        return ERROR_CANCELLED;
+/// Count a download_append_chunk error code in the metrics
+fn counted(result: i32) -> i32 {
+    if result < 0 {
+        metrics::count_error(result);
     }
+    result
+}
 
-    // Open file on first call
-    if ctx.output_file.is_null() {
-        let file = match File::create(&ctx.file_path) {
-            Ok(f) => f,
-            Err(_) => return ERROR_PERMISSION_DENIED,
-        };
-        ctx.output_file = Box::into_raw(Box::new(BufWriter::new(file)));
-    }
+/// The callbacks of one append call
+#[derive(Clone, Copy)]
+struct AppendCallbacks {
+    progress: Option<DownloadProgressCallback>,
+    phase: Option<PhaseProgressCallback>,
+    user_data: *mut c_void,
+}
 
-    let encrypted_slice = unsafe { slice::from_raw_parts(encrypted_data, data_len) };
+/// A callback due while the context is borrowed, invoked once it no longer is
+enum PendingCallback {
+    Progress,
+    Phase(u32, u64, u64),
+}
 
-    // Initialize decryption on first chunk if needed
-    if ctx.should_decrypt && ctx.decryption_context.is_none() && !ctx.master_key.is_empty() {
-        // First chunk should contain header + wrapped FEK + first encrypted chunk
-        // We need at least 12 bytes for header + wrapped FEK length
-        if data_len < 12 {
-            return ERROR_INVALID_PATH;
-        }
-
-        // Parse header to get wrapped FEK length
-        let fek_len = u32::from_le_bytes([
-            encrypted_slice[8],
-            encrypted_slice[9],
-            encrypted_slice[10],
-            encrypted_slice[11],
-        ]) as usize;
-
-        // We need header + wrapped FEK for decryption init
-        if data_len < 12 + fek_len {
-            return ERROR_INVALID_PATH;
-        }
-
-        // Initialize decryption with header + wrapped FEK
-        let dec_ctx = unsafe {
-            decrypt_file_init(
-                encrypted_data,
-                12 + fek_len,
-                ctx.master_key.as_ptr(),
-                ctx.master_key.len(),
-            )
-        };
+/// Pending callbacks, each with the progress the accessors report while it runs
+type Pending = Vec<(DownloadProgress, PendingCallback)>;
 
-        if dec_ctx.is_null() {
-            return ERROR_IO_FAILED;
-        }
+/// Invoke pending callbacks in order, with the guard raised
+///
+/// # Safety
+/// `context` must be live and not borrowed.
+unsafe fn deliver(context: *mut DownloadContext, callbacks: AppendCallbacks, pending: Pending) {
+    let user_data = callbacks.user_data;
+    for (progress, pending) in pending {
+        (*context).callback_guard.capture(progress);
+        CallbackGuard::invoke(ptr::addr_of!((*context).callback_guard), || match pending {
+            PendingCallback::Progress => {
+                if let Some(cb) = callbacks.progress {
+                    cb(progress.bytes_written, progress.total_bytes, user_data);
+                }
+            }
+            PendingCallback::Phase(phase, done, total) => {
+                if let Some(cb) = callbacks.phase {
+                    cb(phase, done, total, user_data);
+                }
+            }
+        });
+    }
+}
 
-        ctx.decryption_context = Some(dec_ctx);
+impl DownloadContext {
+    /// Progress as the accessors report it outside callbacks
+    fn progress(&self) -> DownloadProgress {
+        DownloadProgress { bytes_written: self.bytes_written, total_bytes: self.total_bytes }
+    }
 
-        // Write header and wrapped FEK to file
-        let writer = unsafe { &mut *ctx.output_file };
-        let header_and_fek = unsafe { slice::from_raw_parts(encrypted_data, 12 + fek_len) };
-        if let Err(_) = writer.write_all(header_and_fek) {
-            return ERROR_IO_FAILED;
+    /// Queue the phase reports due for `done` of `total` bytes
+    fn queue_phase(&mut self, pending: &mut Pending, callbacks: AppendCallbacks, phase: u32, done: u64, total: u64) {
+        if callbacks.phase.is_some() {
+            for (phase, done, total) in self.phase_tracker.due(phase, done, total) {
+                pending.push((self.progress(), PendingCallback::Phase(phase, done, total)));
+            }
         }
+    }
 
-        ctx.header_written = true;
-        ctx.bytes_written = 12 + fek_len;
-
-        // Decrypt and write the first data chunk if present
-        let data_start = 12 + fek_len;
-        if data_len > data_start {
-            let first_chunk = &encrypted_slice[data_start..];
-            let decrypted = unsafe {
-                decrypt_chunk(
-                    dec_ctx,
-                    first_chunk.as_ptr(),
-                    first_chunk.len(),
-                    &data_len as *const usize as *mut usize,
-                )
-            };
-
-            if decrypted.is_null() {
-                return ERROR_IO_FAILED;
-            }
+    /// Count `data_len` appended bytes, pace the download and queue the progress update
+    fn count_appended(&mut self, data_len: usize, callbacks: AppendCallbacks, pending: &mut Pending) -> i32 {
+        self.operation.set_bytes_done(self.bytes_written as u64);
 
-            let decrypted_size = unsafe { *(&data_len as *const usize as *const usize) };
-            let writer = unsafe { &mut *ctx.output_file };
-            let decrypted_data = unsafe { slice::from_raw_parts(decrypted, decrypted_size) };
-            if let Err(_) = writer.write_all(decrypted_data) {
-                unsafe { libc::free(decrypted as *mut c_void); }
-                return ERROR_IO_FAILED;
-            }
-    
-            unsafe { libc::free(decrypted as *mut c_void); }
-            ctx.bytes_written += decrypted_size;
+        // Slow down under thermal/battery pressure or an explicit rate limit
+        if !self.pacer.pace(data_len, self.cancel_flag) {
+            return self.cancel();
         }
 
         // Progress callback
-        if let Some(cb) = progress_callback {
-            if ctx.progress_throttler.should_update(ctx.bytes_written, ctx.total_bytes) {
-                cb(ctx.bytes_written, ctx.total_bytes, user_data);
-            }
+        if callbacks.progress.is_some() && self.progress_throttler.should_update(self.bytes_written, self.total_bytes) {
+            pending.push((self.progress(), PendingCallback::Progress));
         }
 
-        return SUCCESS;
+        SUCCESS
     }
+}
 
-    // Normal chunk processing (not first chunk, or no decryption)
-    if ctx.should_decrypt && ctx.decryption_context.is_some() {
-        // Decrypt chunk
-        let dec_ctx = ctx.decryption_context.unwrap();
-        let output_len: usize = 0;
-        let decrypted = unsafe {
-            decrypt_chunk(
-                dec_ctx,
-                encrypted_data,
-                data_len,
-                &output_len as *const usize as *mut usize,
-            )
-        };
+// Callbacks run once the context is no longer borrowed (see DownloadProgress)
+fn download_append_chunk_impl(
+    context: *mut DownloadContext,
+    encrypted_data: *const u8,
+    data_len: usize,
+    progress_callback: Option<DownloadProgressCallback>,
+    phase_callback: Option<PhaseProgressCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
 
-        if decrypted.is_null() {
-            return ERROR_IO_FAILED;
-        }
+    let callbacks = AppendCallbacks { progress: progress_callback, phase: phase_callback, user_data };
+    let mut pending = Vec::new();
+    let result = download_write_chunk(unsafe { &mut *context }, encrypted_data, data_len, callbacks, &mut pending);
+    unsafe { deliver(context, callbacks, pending) };
+    if let Err(code) = result {
+        return code;
+    }
 
-        let decrypted_size = unsafe { *(&output_len as *const usize as *const usize) };
+    report_appended(context, data_len, callbacks)
+}
 
-        // Write to file
-        let writer = unsafe { &mut *ctx.output_file };
-        let decrypted_slice = unsafe { std::slice::from_raw_parts(decrypted, decrypted_size) };
-        if let Err(_) = writer.write_all(decrypted_slice) {
-            unsafe { libc::free(decrypted as *mut c_void); }
-            return ERROR_IO_FAILED;
-        }
+/// Count appended bytes as DownloadContext::count_appended, then deliver the progress update
+fn report_appended(context: *mut DownloadContext, data_len: usize, callbacks: AppendCallbacks) -> i32 {
+    let mut pending = Vec::new();
+    let result = unsafe { &mut *context }.count_appended(data_len, callbacks, &mut pending);
+    unsafe { deliver(context, callbacks, pending) };
+    result
+}
+
+/// Write (or buffer and decrypt) one received chunk
+fn download_write_chunk(
+    ctx: &mut DownloadContext,
+    encrypted_data: *const u8,
+    data_len: usize,
+    callbacks: AppendCallbacks,
+    pending: &mut Pending,
+) -> Result<(), i32> {
+    // An aborted download never recreates its output
+    if ctx.aborted {
+        return Err(ERROR_CANCELLED);
+    }
+
+    // Check cancellation
+    if unsafe { is_cancelled(ctx.cancel_flag) } {
+        return Err(ctx.cancel());
+    }
+
+    // The partial output is gone; do not start a new one
+    if ctx.failed_chunk.is_some() {
+        return Err(ERROR_DECRYPTION_FAILED_AT_CHUNK);
+    }
+
+    // Decrypted chunks are checked as they are written
+    let decrypting = ctx.should_decrypt && !ctx.master_key.is_empty();
+    ctx.check_fs_room(if decrypting { 0 } else { data_len })?;
+
+    // Open file on first call
+    if ctx.output_file.is_null() {
+        let (file, permit) = open_limited(|| File::create(&ctx.temp_path), ERROR_PERMISSION_DENIED)?;
+        ctx.output_file = Box::into_raw(Box::new(BufWriter::new(file)));
+        ctx.output_permit = Some(permit);
+    }
+
+    let encrypted_slice = unsafe { slice::from_raw_parts(encrypted_data, data_len) };
+    ctx.bytes_received += data_len;
+    let received_total = if decrypting { 0 } else { ctx.total_bytes };
+    ctx.queue_phase(pending, callbacks, PHASE_DOWNLOADING, ctx.bytes_received as u64, received_total as u64);
 
-        unsafe { libc::free(decrypted as *mut c_void); }
-        ctx.bytes_written += decrypted_size;
+    if decrypting {
+        // Network chunks need not line up with the container: buffer them and
+        // decrypt the header and each encrypted chunk once it is complete
+        ctx.pending.extend_from_slice(encrypted_slice);
+        ctx.decrypt_pending()?;
+        ctx.queue_phase(pending, callbacks, PHASE_DECRYPTING, ctx.bytes_written as u64, ctx.total_bytes as u64);
     } else {
         // No decryption - write raw data
         let writer = unsafe { &mut *ctx.output_file };
         if let Err(_) = writer.write_all(encrypted_slice) {
-            return ERROR_IO_FAILED;
+            return Err(ERROR_IO_FAILED);
         }
         ctx.bytes_written += data_len;
     }
 
-    // Progress callback
-    if let Some(cb) = progress_callback {
-        if ctx.progress_throttler.should_update(ctx.bytes_written, ctx.total_bytes) {
-            cb(ctx.bytes_written, ctx.total_bytes, user_data);
-        }
-    }
-
-    SUCCESS
+    Ok(())
 }
 
 /// Append decrypted data directly (bypasses decryption in Rust)
@@ -341,18 +825,33 @@ pub extern "C" fn download_append_decrypted(
 
     let ctx = unsafe { &mut *context };
 
-    // Check cancellation
-    if unsafe { is_cancelled(ctx.cancel_flag) } {
+    // An aborted download never recreates its output
+    if ctx.aborted {
         return ERROR_CANCELLED;
     }
 
+    // Check cancellation
+    if unsafe { is_cancelled(ctx.cancel_flag) } {
+        return ctx.cancel();
+    }
+
+    // The partial output is gone; do not start a new one
+    if ctx.failed_chunk.is_some() {
+        return ERROR_DECRYPTION_FAILED_AT_CHUNK;
+    }
+
+    if let Err(code) = ctx.check_fs_room(data_len) {
+        return checked(code);
+    }
+
     // Open file on first call
     if ctx.output_file.is_null() {
-        let file = match File::create(&ctx.file_path) {
-            Ok(f) => f,
-            Err(_) => return ERROR_PERMISSION_DENIED,
+        let (file, permit) = match open_limited(|| File::create(&ctx.temp_path), ERROR_PERMISSION_DENIED) {
+            Ok(opened) => opened,
+            Err(code) => return checked(code),
         };
         ctx.output_file = Box::into_raw(Box::new(BufWriter::new(file)));
+        ctx.output_permit = Some(permit);
     }
 
     let data_slice = unsafe { slice::from_raw_parts(data, data_len) };
@@ -365,14 +864,7 @@ pub extern "C" fn download_append_decrypted(
 
     ctx.bytes_written += data_len;
 
-    // Progress callback
-    if let Some(cb) = progress_callback {
-        if ctx.progress_throttler.should_update(ctx.bytes_written, ctx.total_bytes) {
-            cb(ctx.bytes_written, ctx.total_bytes, user_data);
-        }
-    }
-
-    SUCCESS
+    report_appended(context, data_len, AppendCallbacks { progress: progress_callback, phase: None, user_data })
 }
 
 /// Finalize download and clean up resources
@@ -381,7 +873,8 @@ pub extern "C" fn download_append_decrypted(
 /// * `context` - Pointer to DownloadContext
 ///
 /// # Returns
-/// 0 on success, error code on failure
+/// 0 on success, ERROR_NEED_MORE_DATA if a decrypted stream stopped inside the
+/// header or a chunk (the download stays open for more data), other error code on failure
 #[no_mangle]
 pub extern "C" fn download_finalize(context: *mut DownloadContext) -> i32 {
     if context.is_null() {
@@ -389,6 +882,18 @@ pub extern "C" fn download_finalize(context: *mut DownloadContext) -> i32 {
     }
 
     let ctx = unsafe { &mut *context };
+    if ctx.aborted {
+        return ERROR_CANCELLED;
+    }
+    if ctx.failed_chunk.is_some() {
+        return ERROR_DECRYPTION_FAILED_AT_CHUNK;
+    }
+
+    // A decrypted stream must end on a chunk boundary, after at least the header
+    let decrypting = ctx.should_decrypt && !ctx.master_key.is_empty();
+    if decrypting && (ctx.decryption_context.is_none() || !ctx.pending.is_empty()) {
+        return ERROR_NEED_MORE_DATA;
+    }
 
     // Finalize decryption context
     if let Some(dec_ctx) = ctx.decryption_context {
@@ -401,30 +906,113 @@ pub extern "C" fn download_finalize(context: *mut DownloadContext) -> i32 {
         if let Err(_) = writer.flush() {
             return ERROR_IO_FAILED;
         }
+        ctx.bytes_durable = ctx.bytes_written;
+        // A reused destination may be longer than the download; drop its stale tail
+        if ctx.direct_output && truncate_to_position(writer.get_mut()).is_err() {
+            return ERROR_IO_FAILED;
+        }
         unsafe {
             let _ = Box::from_raw(ctx.output_file);
         }
         ctx.output_file = ptr::null_mut();
+        ctx.output_permit = None;
+    }
+
+    // Move the completed download into place
+    if !ctx.direct_output && commit_temp_file(&ctx.temp_path, &ctx.file_path).is_err() {
+        return ERROR_IO_FAILED;
     }
 
+    if !ctx.is_finalized {
+        metrics::count(Counter::DownloadsCompleted, 1);
+        metrics::count(Counter::DownloadBytes, ctx.bytes_written as u64);
+    }
     ctx.is_finalized = true;
+    ctx.operation.set_files_done(1);
+    ctx.operation.complete();
 
     SUCCESS
 }
 
+/// Finalize download and deliver the terminal progress update
+///
+/// Same as download_finalize, but also invokes the progress callback with the
+/// final byte count if the last written chunk did not already report it
+/// (e.g. when the total was unknown or counted ciphertext bytes).
+///
+/// # Arguments
+/// * `context` - Pointer to DownloadContext
+/// * `progress_callback` - Optional progress callback
+/// * `user_data` - User data to pass to callback
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn download_finalize_with_progress(
+    context: *mut DownloadContext,
+    progress_callback: Option<DownloadProgressCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    let result = download_finalize(context);
+    if result != SUCCESS {
+        return checked(result);
+    }
+
+    let ctx = unsafe { &mut *context };
+    if progress_callback.is_some() && ctx.progress_throttler.finish() {
+        let progress = DownloadProgress { bytes_written: ctx.bytes_written,
+                                          total_bytes: ctx.total_bytes.max(ctx.bytes_written) };
+        let callbacks = AppendCallbacks { progress: progress_callback, phase: None, user_data };
+        unsafe { deliver(context, callbacks, vec![(progress, PendingCallback::Progress)]) };
+    }
+
+    SUCCESS
+}
+
+/// Abandon a download and remove what it wrote
+///
+/// Releases the decryption context, deletes the temp file, and cuts a
+/// caller-provided descriptor (see download_init_fd) back to the position the
+/// download started writing at. The context counts as finalized afterwards:
+/// further appends fail with ERROR_CANCELLED and download_free does nothing
+/// more. Aborting a finalized download does nothing.
+///
+/// # Arguments
+/// * `context` - Pointer to DownloadContext
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn download_abort(context: *mut DownloadContext) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    if !ctx.is_finalized {
+        ctx.abort();
+    }
+    SUCCESS
+}
+
 /// Free download context
 ///
+/// A download that was neither finalized nor aborted is aborted first, unless
+/// keep_partial is set (see download_set_keep_partial). A caller-provided
+/// descriptor is only flushed; what was written stays for the caller to discard.
+///
 /// # Arguments
 /// * `context` - Pointer to DownloadContext to free
 #[no_mangle]
 pub extern "C" fn download_free(context: *mut DownloadContext) {
     if !context.is_null() {
         unsafe {
-            // Finalize first if not done
-            if !context.is_null() {
-                let ctx = &mut *context;
-                if !ctx.is_finalized {
-                    if let Some(dec_ctx) = ctx.decryption_context {
+            let ctx = &mut *context;
+            if !ctx.is_finalized {
+                if ctx.keep_partial || ctx.direct_output {
+                    if let Some(dec_ctx) = ctx.decryption_context.take() {
                         decrypt_file_finalize(dec_ctx);
                     }
                     if !ctx.output_file.is_null() {
@@ -432,6 +1020,9 @@ pub extern "C" fn download_free(context: *mut DownloadContext) {
                         let _ = writer.flush();
                         let _ = Box::from_raw(ctx.output_file);
                     }
+                } else {
+                    // Incomplete download, never expose it at the destination path
+                    ctx.abort();
                 }
             }
             let _ = Box::from_raw(context);
@@ -441,6 +1032,8 @@ pub extern "C" fn download_free(context: *mut DownloadContext) {
 
 /// Get bytes written for download
 ///
+/// Includes bytes still held in the write buffer; see download_get_bytes_durable.
+///
 /// # Arguments
 /// * `context` - Pointer to DownloadContext
 ///
@@ -451,7 +1044,62 @@ pub extern "C" fn download_get_bytes_written(context: *mut DownloadContext) -> u
     if context.is_null() {
         return 0;
     }
-    unsafe { (&*context).bytes_written }
+    let ctx = unsafe { &*context };
+    ctx.callback_guard.read(ctx.progress()).bytes_written
+}
+
+/// Get bytes of the download known to have reached the output file
+///
+/// Only advances on download_flush and download_finalize, so it can be
+/// persisted as a resume checkpoint.
+///
+/// # Arguments
+/// * `context` - Pointer to DownloadContext
+///
+/// # Returns
+/// Durable bytes, or 0 if invalid
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn download_get_bytes_durable(context: *mut DownloadContext) -> usize {
+    if context.is_null() {
+        return 0;
+    }
+    unsafe { (&*context).bytes_durable }
+}
+
+/// Flush buffered download output to the output file
+///
+/// Without `sync_to_disk` the flushed bytes survive the app crashing but not
+/// the device losing power. Bytes of a decrypted stream still waiting for the
+/// rest of their chunk are not counted.
+///
+/// # Arguments
+/// * `context` - Pointer to DownloadContext
+/// * `sync_to_disk` - Non-zero to also sync the file data to storage
+///
+/// # Returns
+/// Durable byte count (as download_get_bytes_durable), or negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn download_flush(context: *mut DownloadContext, sync_to_disk: i32) -> i64 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER as i64;
+    }
+
+    let ctx = unsafe { &mut *context };
+
+    if !ctx.output_file.is_null() {
+        let writer = unsafe { &mut *ctx.output_file };
+        if writer.flush().is_err() {
+            return ERROR_IO_FAILED as i64;
+        }
+        if sync_to_disk != 0 && writer.get_ref().sync_data().is_err() {
+            return ERROR_IO_FAILED as i64;
+        }
+        ctx.bytes_durable = ctx.bytes_written;
+    }
+
+    ctx.bytes_durable as i64
 }
 
 /// Get total bytes for download
@@ -466,17 +1114,684 @@ pub extern "C" fn download_get_total_bytes(context: *mut DownloadContext) -> usi
     if context.is_null() {
         return 0;
     }
-    unsafe { (&*context).total_bytes }
+    let ctx = unsafe { &*context };
+    ctx.callback_guard.read(ctx.progress()).total_bytes
+}
+
+/// Get the active operation id of a download
+///
+/// # Arguments
+/// * `context` - Pointer to DownloadContext
+///
+/// # Returns
+/// Operation id for get_operation_progress and cancel_operation, or 0 if invalid
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn download_get_operation_id(context: *mut DownloadContext) -> u64 {
+    if context.is_null() {
+        return 0;
+    }
+    unsafe { (&*context).operation.id() }
 }
 
 /// Set total bytes for download (for progress tracking)
 ///
+/// On a FAT destination, a total of 4 GiB or more makes the next append fail
+/// with ERROR_FILE_TOO_LARGE_FOR_FS before anything is written; appends that
+/// would grow the file past that limit fail the same way.
+///
 /// # Arguments
 /// * `context` - Pointer to DownloadContext
 /// * `total_bytes` - Total bytes expected
 #[no_mangle]
 pub extern "C" fn download_set_total_bytes(context: *mut DownloadContext, total_bytes: usize) {
     if !context.is_null() {
-        unsafe { (&mut *context).total_bytes = total_bytes; }
+        let ctx = unsafe { &mut *context };
+        ctx.total_bytes = total_bytes;
+        ctx.operation.set_total_bytes(total_bytes as u64);
+    }
+}
+
+/// Limit the download speed of this context
+///
+/// Combined with the speed governor (see set_speed_governor), the lower speed wins.
+///
+/// # Arguments
+/// * `context` - Pointer to DownloadContext
+/// * `bytes_per_sec` - Maximum speed in bytes per second (0 for unlimited)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn download_set_rate_limit(context: *mut DownloadContext, bytes_per_sec: u64) {
+    if !context.is_null() {
+        unsafe { (&mut *context).pacer.set_rate_limit(bytes_per_sec); }
+    }
+}
+
+/// Keep the partial download on disk when it is cancelled or freed unfinished
+///
+/// By default the partial temp file is deleted as soon as a chunk call observes
+/// cancellation. With keep_partial set it stays next to the destination; its
+/// path is available from download_get_partial_path. download_abort deletes it
+/// regardless.
+///
+/// # Arguments
+/// * `context` - Pointer to DownloadContext
+/// * `keep_partial` - 1 to keep the partial file, 0 to delete it
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn download_set_keep_partial(context: *mut DownloadContext, keep_partial: u8) {
+    if !context.is_null() {
+        unsafe { (&mut *context).keep_partial = keep_partial != 0; }
+    }
+}
+
+/// Set where partial output goes when an encrypted chunk fails to decrypt
+///
+/// On such a failure download_append_chunk returns
+/// ERROR_DECRYPTION_FAILED_AT_CHUNK and the partial temp file is moved into
+/// `dir` as `<name>.corrupt-<timestamp>`, with an entry in the directory's
+/// manifest (see list_quarantine_json). Without a quarantine directory, the
+/// default, the partial file is deleted. Downloads to a caller-provided
+/// descriptor leave the file to the caller.
+///
+/// # Arguments
+/// * `context` - Pointer to DownloadContext
+/// * `dir` - Quarantine directory, created when first needed (null disables quarantine)
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn download_set_quarantine_dir(context: *mut DownloadContext, dir: *const c_char) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+    let ctx = unsafe { &mut *context };
+    if dir.is_null() {
+        ctx.quarantine_dir = None;
+        return SUCCESS;
     }
-}
\ No newline at end of file
+    match unsafe { c_str_to_path(dir) } {
+        Ok(path) => {
+            ctx.quarantine_dir = Some(path);
+            SUCCESS
+        }
+        Err(code) => checked(code),
+    }
+}
+
+/// Get the index of the encrypted chunk that failed to decrypt
+///
+/// # Arguments
+/// * `context` - Pointer to DownloadContext
+///
+/// # Returns
+/// The chunk index after ERROR_DECRYPTION_FAILED_AT_CHUNK, -1 if no chunk
+/// failed or context is null
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn download_get_failed_chunk_index(context: *mut DownloadContext) -> i64 {
+    if context.is_null() {
+        return -1;
+    }
+    unsafe { (&*context).failed_chunk.map_or(-1, i64::from) }
+}
+
+/// Get the path of the temp file the download is written to
+///
+/// # Arguments
+/// * `context` - Pointer to DownloadContext
+///
+/// # Returns
+/// Temp file path (caller must free with scan_folder_free_string), or null if invalid
+/// or the download writes to a caller-provided descriptor
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn download_get_partial_path(context: *mut DownloadContext) -> *mut c_char {
+    if context.is_null() {
+        return ptr::null_mut();
+    }
+
+    let ctx = unsafe { &*context };
+    if ctx.direct_output {
+        return ptr::null_mut();
+    }
+    match CString::new(ctx.temp_path.to_string_lossy().to_string()) {
+        Ok(s) => s.into_raw(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Report this download's progress into the polled event stream
+///
+/// Progress records go to drain_progress_events_json in addition to the
+/// progress callback (pass no callback to use the stream instead). download_finalize pushes the terminal "completed" record; freeing an unfinished download pushes "failed" or "cancelled".
+///
+/// # Arguments
+/// * `context` - Pointer to DownloadContext
+/// * `use_event_stream` - 1 to push progress events, 0 to stop
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn download_set_use_event_stream(context: *mut DownloadContext, use_event_stream: u8) {
+    if !context.is_null() {
+        unsafe { (&*context).operation.set_use_event_stream(use_event_stream != 0); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::errors::ERROR_INVALID_CHUNK_SIZE;
+    use crate::test_util::c_path;
+    use crate::upload::{upload_free, upload_get_header, upload_init, upload_init_v2, upload_process_chunk, UploadContext};
+    use crate::{decrypt_file_streaming, free_buffer, ENCRYPTION_MODE_NONE, ENCRYPTION_MODE_OPTIONAL, ENCRYPTION_MODE_REQUIRED,
+                list_quarantine_impl, ERROR_INVALID_ENCRYPTION_MODE, ERROR_MASTER_KEY_REQUIRED, MAX_CHUNK_SIZE_FIELD, NONCE_SIZE};
+
+    const KEY: [u8; 32] = [5u8; 32];
+
+    extern "C" fn collect_stream(data: *const u8, data_len: usize, _chunk_index: u32, user_data: *mut c_void) {
+        let stream = unsafe { &mut *(user_data as *mut Vec<u8>) };
+        stream.extend_from_slice(unsafe { slice::from_raw_parts(data, data_len) });
+    }
+
+    /// Encrypt `content` through the upload path, with or without upload_get_header
+    fn upload_stream(dir: &std::path::Path, content: &[u8], fetch_header: bool) -> Vec<u8> {
+        let source = dir.join(format!("source_{}_{}.bin", content.len(), fetch_header));
+        fs::write(&source, content).unwrap();
+        let ctx = upload_init(c_path(&source).as_ptr(), KEY.as_ptr(), 32, 64 * 1024, 1, None, None,
+                              ptr::null(), ptr::null_mut());
+        assert!(!ctx.is_null());
+
+        let mut stream = Vec::new();
+        if fetch_header {
+            let mut header = [0u8; HEADER_SIZE];
+            let mut fek = [0u8; 256];
+            let mut fek_len = 0usize;
+            assert_eq!(upload_get_header(ctx, header.as_mut_ptr(), fek.as_mut_ptr(), fek.len(), &mut fek_len), SUCCESS);
+            stream.extend_from_slice(&header);
+            stream.extend_from_slice(&fek[..fek_len]);
+        }
+
+        let mut buffer = vec![0u8; 64 * 1024 + 64];
+        let user_data = &mut stream as *mut Vec<u8> as *mut c_void;
+        loop {
+            let n = upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_stream), user_data);
+            assert!(n >= 0);
+            if n == 0 {
+                break;
+            }
+        }
+        upload_free(ctx);
+        stream
+    }
+
+    /// Download a reentrant callback calls back into
+    struct Reentry {
+        ctx: *mut DownloadContext,
+        operation_id: u64,
+        /// Callback next to what download_get_bytes_written returned during it
+        seen: Vec<(&'static str, usize)>,
+    }
+
+    extern "C" fn reentrant_phase(_phase: u32, _done: u64, total: u64, user_data: *mut c_void) {
+        let reentry = unsafe { &mut *(user_data as *mut Reentry) };
+        assert_eq!(download_get_total_bytes(reentry.ctx) as u64, total);
+        reentry.seen.push(("phase", download_get_bytes_written(reentry.ctx)));
+    }
+
+    extern "C" fn reentrant_progress(bytes_written: usize, total_bytes: usize, user_data: *mut c_void) {
+        let reentry = unsafe { &mut *(user_data as *mut Reentry) };
+        assert_eq!(download_get_total_bytes(reentry.ctx), total_bytes);
+        reentry.seen.push(("progress", download_get_bytes_written(reentry.ctx)));
+        assert_eq!(reentry.seen.last().unwrap().1, bytes_written);
+        assert_eq!(crate::operations::cancel_operation(reentry.operation_id), SUCCESS);
+    }
+
+    #[test]
+    fn test_callbacks_reenter_and_cancel() {
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_download_reentry_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("out.bin");
+        let ctx = download_init(c_path(&dest).as_ptr(), ptr::null(), 0, 0, None, ptr::null(), ptr::null_mut());
+        assert!(!ctx.is_null());
+        download_set_total_bytes(ctx, 3 * 1024);
+        unsafe { (*ctx).progress_throttler = ProgressThrottler::with_percent_delta(1); }
+        let mut reentry = Reentry { ctx, operation_id: download_get_operation_id(ctx), seen: Vec::new() };
+        let user_data = &mut reentry as *mut Reentry as *mut c_void;
+
+        // Phase reports see the progress before the chunk, the progress
+        // callback the bytes it was given; it cancels and the next append fails
+        let chunk = [9u8; 1024];
+        assert_eq!(download_append_chunk_phased(ctx, chunk.as_ptr(), chunk.len(), Some(reentrant_phase), user_data), SUCCESS);
+        assert!(!reentry.seen.is_empty());
+        assert!(reentry.seen.iter().all(|&seen| seen == ("phase", 0)));
+        reentry.seen.clear();
+        assert_eq!(download_append_chunk(ctx, chunk.as_ptr(), chunk.len(), Some(reentrant_progress), user_data), SUCCESS);
+        assert_eq!(reentry.seen, vec![("progress", 2048)]);
+        assert_eq!(download_append_chunk(ctx, chunk.as_ptr(), chunk.len(), Some(reentrant_progress), user_data),
+                   ERROR_CANCELLED);
+        assert_eq!(reentry.seen.len(), 1);
+        download_free(ctx);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Feed `stream` to a decrypting download in pieces of the given sizes (cycled)
+    fn download_pieces(dest: &std::path::Path, stream: &[u8], sizes: &[usize]) -> *mut DownloadContext {
+        let ctx = download_init(c_path(dest).as_ptr(), KEY.as_ptr(), 32, 1, None, ptr::null(), ptr::null_mut());
+        assert!(!ctx.is_null());
+        let mut offset = 0;
+        for &size in sizes.iter().cycle() {
+            if offset >= stream.len() {
+                break;
+            }
+            let end = (offset + size).min(stream.len());
+            let piece = &stream[offset..end];
+            assert_eq!(download_append_chunk(ctx, piece.as_ptr(), piece.len(), None, ptr::null_mut()), SUCCESS);
+            offset = end;
+        }
+        ctx
+    }
+
+    #[test]
+    fn test_tiny_file_round_trips() {
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_tiny_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        for len in [0usize, 1, 11, 64 * 1024 + 1] {
+            let content: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+            for fetch_header in [false, true] {
+                let stream = upload_stream(&dir, &content, fetch_header);
+                assert_eq!(&stream[..4], &MAGIC.to_le_bytes());
+
+                let mut plain_len = 0usize;
+                let plain = decrypt_file_streaming(stream.as_ptr(), stream.len(), KEY.as_ptr(), 32, &mut plain_len,
+                                                   None, ptr::null_mut());
+                assert!(!plain.is_null());
+                assert_eq!(unsafe { slice::from_raw_parts(plain, plain_len) }, &content[..]);
+                free_buffer(plain);
+
+                for sizes in [&[1usize][..], &[5, 7], &[11, 1, 13], &[100_000]] {
+                    let dest = dir.join(format!("dest_{}.bin", len));
+                    let ctx = download_pieces(&dest, &stream, sizes);
+                    assert_eq!(download_finalize(ctx), SUCCESS);
+                    assert_eq!(download_get_bytes_written(ctx), len);
+                    download_free(ctx);
+                    assert_eq!(fs::read(&dest).unwrap(), content);
+                    fs::remove_file(&dest).unwrap();
+                }
+            }
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_truncated_stream_needs_more_data() {
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_truncated_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let content = vec![42u8; 11];
+        let stream = upload_stream(&dir, &content, false);
+        let dest = dir.join("dest.bin");
+
+        // Stopping inside the header, then inside the chunk, leaves the download open
+        for cut in [7, stream.len() - 1] {
+            let ctx = download_pieces(&dest, &stream[..cut], &[3]);
+            assert_eq!(download_finalize(ctx), ERROR_NEED_MORE_DATA);
+
+            let rest = &stream[cut..];
+            assert_eq!(download_append_chunk(ctx, rest.as_ptr(), rest.len(), None, ptr::null_mut()), SUCCESS);
+            assert_eq!(download_finalize(ctx), SUCCESS);
+            download_free(ctx);
+            assert_eq!(fs::read(&dest).unwrap(), content);
+            fs::remove_file(&dest).unwrap();
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_out_of_range_size_fields_rejected_early() {
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_malformed_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("dest.bin");
+
+        let stream = upload_stream(&dir, &[5u8; 100], false);
+        let fek_len = u32::from_le_bytes([stream[8], stream[9], stream[10], stream[11]]) as usize;
+
+        // A header claiming a huge FEK region fails on its own, without the region
+        let mut header = stream[..HEADER_SIZE].to_vec();
+        header[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let ctx = download_init(c_path(&dest).as_ptr(), KEY.as_ptr(), 32, 1, None, ptr::null(), ptr::null_mut());
+        assert_eq!(download_append_chunk(ctx, header.as_ptr(), header.len(), None, ptr::null_mut()),
+                   ERROR_MALFORMED_CONTAINER);
+        download_free(ctx);
+
+        // So does the first chunk header claiming more than MAX_CHUNK_SIZE_FIELD
+        let size_offset = HEADER_SIZE + fek_len + 4;
+        let mut prefix = stream[..size_offset + 4 + NONCE_SIZE].to_vec();
+        prefix[size_offset..size_offset + 4].copy_from_slice(&(MAX_CHUNK_SIZE_FIELD as u32 + 1).to_le_bytes());
+        let ctx = download_init(c_path(&dest).as_ptr(), KEY.as_ptr(), 32, 1, None, ptr::null(), ptr::null_mut());
+        assert_eq!(download_append_chunk(ctx, prefix.as_ptr(), prefix.len(), None, ptr::null_mut()),
+                   ERROR_MALFORMED_CONTAINER);
+        download_free(ctx);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_reordered_chunks_rejected() {
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_reordered_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("dest.bin");
+
+        let content: Vec<u8> = (0..3 * 64 * 1024).map(|i| (i % 239) as u8).collect();
+        let stream = upload_stream(&dir, &content, true);
+        let fek_len = u32::from_le_bytes([stream[8], stream[9], stream[10], stream[11]]) as usize;
+        let first = HEADER_SIZE + fek_len;
+        let record_len = 4 + 4 + NONCE_SIZE + 64 * 1024 + 16;
+        assert_eq!(stream.len(), first + 3 * record_len);
+
+        // Swap chunks 1 and 2; each still authenticates on its own
+        let mut swapped = stream[..first + record_len].to_vec();
+        swapped.extend_from_slice(&stream[first + 2 * record_len..]);
+        swapped.extend_from_slice(&stream[first + record_len..first + 2 * record_len]);
+
+        let ctx = download_init(c_path(&dest).as_ptr(), KEY.as_ptr(), 32, 1, None, ptr::null(), ptr::null_mut());
+        assert_eq!(download_append_chunk(ctx, swapped.as_ptr(), swapped.len(), None, ptr::null_mut()),
+                   ERROR_CHUNK_OUT_OF_ORDER);
+        download_free(ctx);
+        assert!(!dest.exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_corrupt_chunk_quarantines_partial_output() {
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_quarantine_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("report.pdf");
+        let quarantine = dir.join("quarantine");
+
+        let content: Vec<u8> = (0..3 * 64 * 1024).map(|i| (i % 241) as u8).collect();
+        let stream = upload_stream(&dir, &content, true);
+        let fek_len = u32::from_le_bytes([stream[8], stream[9], stream[10], stream[11]]) as usize;
+        let record_len = 4 + 4 + NONCE_SIZE + 64 * 1024 + 16;
+        let second = HEADER_SIZE + fek_len + record_len;
+
+        // Flip a ciphertext byte in the middle chunk
+        let mut corrupted = stream.clone();
+        corrupted[second + 4 + 4 + NONCE_SIZE + 100] ^= 0x01;
+
+        let ctx = download_init(c_path(&dest).as_ptr(), KEY.as_ptr(), 32, 1, None, ptr::null(), ptr::null_mut());
+        assert_eq!(download_set_quarantine_dir(ctx, c_path(&quarantine).as_ptr()), SUCCESS);
+        assert_eq!(download_get_failed_chunk_index(ctx), -1);
+        assert_eq!(download_append_chunk(ctx, corrupted.as_ptr(), corrupted.len(), None, ptr::null_mut()),
+                   ERROR_DECRYPTION_FAILED_AT_CHUNK);
+        assert_eq!(download_get_failed_chunk_index(ctx), 1);
+        assert_eq!(download_finalize(ctx), ERROR_DECRYPTION_FAILED_AT_CHUNK);
+        download_free(ctx);
+        assert!(!dest.exists());
+
+        let entries = list_quarantine_impl(&quarantine).unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(entry.chunk_index, 1);
+        assert_eq!(entry.stream_offset, second as u64);
+        assert_eq!(entry.bytes_written, 64 * 1024);
+        assert_eq!(entry.original_path, dest.to_string_lossy());
+        let quarantined = std::path::Path::new(&entry.quarantine_path);
+        assert!(quarantined.starts_with(&quarantine));
+        assert!(quarantined.file_name().unwrap().to_string_lossy().starts_with("report.pdf.corrupt-"));
+        assert_eq!(fs::read(quarantined).unwrap(), &content[..64 * 1024]);
+
+        // Without a quarantine directory the partial output is deleted
+        let ctx = download_init(c_path(&dest).as_ptr(), KEY.as_ptr(), 32, 1, None, ptr::null(), ptr::null_mut());
+        assert_eq!(download_append_chunk(ctx, corrupted.as_ptr(), corrupted.len(), None, ptr::null_mut()),
+                   ERROR_DECRYPTION_FAILED_AT_CHUNK);
+        let partial = download_get_partial_path(ctx);
+        let partial_path = unsafe { CStr::from_ptr(partial) }.to_string_lossy().to_string();
+        crate::scan_folder_free_string(partial);
+        download_free(ctx);
+        assert!(!dest.exists());
+        assert!(!std::path::Path::new(&partial_path).exists());
+        assert_eq!(list_quarantine_impl(&quarantine).unwrap().len(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fd_download_truncates_stale_tail() {
+        use std::os::fd::AsRawFd;
+
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_fd_truncate_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // The destination still holds a longer earlier version
+        let dest = dir.join("dest.bin");
+        fs::write(&dest, vec![9u8; 4096]).unwrap();
+        let file = fs::OpenOptions::new().write(true).open(&dest).unwrap();
+
+        let ctx = download_init_fd(file.as_raw_fd(), ptr::null(), 0, 0, None, ptr::null(), ptr::null_mut());
+        assert!(!ctx.is_null());
+        let content = b"short new content";
+        assert_eq!(download_append_chunk(ctx, content.as_ptr(), content.len(), None, ptr::null_mut()), SUCCESS);
+        assert_eq!(download_finalize(ctx), SUCCESS);
+        download_free(ctx);
+        drop(file);
+
+        assert_eq!(fs::read(&dest).unwrap(), content);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_flush_makes_written_bytes_durable() {
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_flush_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("dest.bin");
+
+        let ctx = download_init(c_path(&dest).as_ptr(), ptr::null(), 0, 0, None, ptr::null(), ptr::null_mut());
+        assert!(!ctx.is_null());
+        assert_eq!(download_flush(ctx, 1), 0);
+        for i in 0..3u8 {
+            let chunk = [i; 1000];
+            assert_eq!(download_append_chunk(ctx, chunk.as_ptr(), chunk.len(), None, ptr::null_mut()), SUCCESS);
+        }
+        let partial_ptr = download_get_partial_path(ctx);
+        let partial = std::path::PathBuf::from(unsafe { CStr::from_ptr(partial_ptr) }.to_str().unwrap());
+        crate::scan_folder_free_string(partial_ptr);
+
+        // Small chunks are still buffered: written, but not yet durable
+        assert_eq!(download_get_bytes_written(ctx), 3000);
+        assert_eq!(download_get_bytes_durable(ctx), 0);
+        assert_eq!(fs::metadata(&partial).unwrap().len(), 0);
+
+        assert_eq!(download_flush(ctx, 1), 3000);
+        assert_eq!(download_get_bytes_durable(ctx), 3000);
+        assert_eq!(fs::metadata(&partial).unwrap().len(), 3000);
+        assert!(!dest.exists());
+
+        assert_eq!(download_finalize(ctx), SUCCESS);
+        assert_eq!(download_get_bytes_durable(ctx), 3000);
+        download_free(ctx);
+        assert_eq!(fs::read(&dest).unwrap().len(), 3000);
+        assert_eq!(download_flush(ptr::null_mut(), 0), ERROR_NULL_POINTER as i64);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_truncated_master_key_fails_init() {
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_short_key_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        fs::write(&source, b"secret contents").unwrap();
+        let dest = dir.join("dest.bin");
+        let (source_c, dest_c) = (c_path(&source), c_path(&dest));
+
+        // Legacy initializers refuse a short key or a null one when asked to encrypt
+        for (key, key_len) in [(KEY.as_ptr(), 31), (ptr::null(), 32), (ptr::null(), 0)] {
+            let upload = upload_init(source_c.as_ptr(), key, key_len, 64 * 1024, 1, None, None, ptr::null(), ptr::null_mut());
+            assert!(upload.is_null());
+            let download = download_init(dest_c.as_ptr(), key, key_len, 1, None, ptr::null(), ptr::null_mut());
+            assert!(download.is_null());
+        }
+
+        // The v2 initializers report why; optional mode still rejects a truncated key
+        for mode in [ENCRYPTION_MODE_REQUIRED, ENCRYPTION_MODE_OPTIONAL] {
+            let mut status = SUCCESS;
+            let upload = upload_init_v2(source_c.as_ptr(), KEY.as_ptr(), 31, 0, mode, ptr::null(), &mut status);
+            assert!(upload.is_null());
+            assert_eq!(status, ERROR_MASTER_KEY_REQUIRED);
+            let mut status = SUCCESS;
+            let download = download_init_v2(dest_c.as_ptr(), KEY.as_ptr(), 31, mode, ptr::null(), &mut status);
+            assert!(download.is_null());
+            assert_eq!(status, ERROR_MASTER_KEY_REQUIRED);
+        }
+        let mut status = SUCCESS;
+        assert!(upload_init_v2(source_c.as_ptr(), KEY.as_ptr(), 32, 0, 7, ptr::null(), &mut status).is_null());
+        assert_eq!(status, ERROR_INVALID_ENCRYPTION_MODE);
+
+        // Failures are also recorded as the last error, by the legacy initializers too
+        let last_error = || {
+            let json = unsafe { CString::from_raw(crate::ffi_util::get_last_error_json(ptr::null_mut())) };
+            serde_json::from_str::<serde_json::Value>(json.to_str().unwrap()).unwrap()["data"].clone()
+        };
+        let missing = c_path(&dir.join("missing.bin"));
+        assert!(upload_init(missing.as_ptr(), ptr::null(), 0, 0, 0, None, None, ptr::null(), ptr::null_mut()).is_null());
+        let last = last_error();
+        assert_eq!(last["code"], ERROR_FILE_NOT_FOUND);
+        assert!(last["context"].as_str().unwrap().ends_with("missing.bin"));
+        assert!(download_init(dest_c.as_ptr(), KEY.as_ptr(), 31, 1, None, ptr::null(), ptr::null_mut()).is_null());
+        assert_eq!(last_error()["code"], ERROR_MASTER_KEY_REQUIRED);
+
+        // Chunk sizes outside 64KB..10MB are refused rather than adjusted
+        for chunk_size in [1, 64 * 1024 - 1, 10 * 1024 * 1024 + 1] {
+            assert!(upload_init(source_c.as_ptr(), ptr::null(), 0, chunk_size, 0, None, None, ptr::null(),
+                                ptr::null_mut()).is_null());
+            assert_eq!(last_error()["code"], ERROR_INVALID_CHUNK_SIZE);
+            let mut status = SUCCESS;
+            assert!(upload_init_v2(source_c.as_ptr(), ptr::null(), 0, chunk_size, ENCRYPTION_MODE_NONE, ptr::null(),
+                                   &mut status).is_null());
+            assert_eq!(status, ERROR_INVALID_CHUNK_SIZE);
+        }
+
+        // Nothing was created next to the destination
+        let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, vec![std::ffi::OsString::from("source.bin")]);
+
+        // Optional mode without a key and none mode with one both upload in plaintext
+        for (key, key_len, mode) in [(ptr::null(), 0, ENCRYPTION_MODE_OPTIONAL), (KEY.as_ptr(), 32, ENCRYPTION_MODE_NONE)] {
+            let mut status = -1;
+            let ctx = upload_init_v2(source_c.as_ptr(), key, key_len, 0, mode, ptr::null(), &mut status);
+            assert!(!ctx.is_null());
+            assert_eq!(status, SUCCESS);
+            let mut stream = Vec::new();
+            let mut buffer = vec![0u8; 64 * 1024 + 64];
+            let user_data = &mut stream as *mut Vec<u8> as *mut c_void;
+            while upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_stream), user_data) > 0 {}
+            upload_free(ctx);
+            assert_eq!(stream, b"secret contents");
+        }
+
+        // A context asked to encrypt without a key emits neither a header nor plaintext
+        let ctx = Box::into_raw(Box::new(UploadContext::new(source.clone(), 15, true, Vec::new(), ptr::null())));
+        let mut header = [0xAAu8; HEADER_SIZE];
+        let mut fek = [0u8; 256];
+        let mut fek_len = 0usize;
+        assert_eq!(upload_get_header(ctx, header.as_mut_ptr(), fek.as_mut_ptr(), fek.len(), &mut fek_len),
+                   ERROR_MASTER_KEY_REQUIRED);
+        assert_eq!(header, [0xAAu8; HEADER_SIZE]);
+        let mut stream = Vec::new();
+        let mut buffer = vec![0u8; 64 * 1024 + 64];
+        let user_data = &mut stream as *mut Vec<u8> as *mut c_void;
+        assert_eq!(upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_stream), user_data),
+                   ERROR_MASTER_KEY_REQUIRED as isize);
+        assert!(stream.is_empty());
+        upload_free(ctx);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_download_abort_removes_partial_output() {
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_download_abort_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("movie.bin");
+        let data = vec![3u8; 100_000];
+
+        // Cancelled with keep_partial: the partial stays until aborted
+        let cancel = AtomicBool::new(false);
+        let ctx = download_init(c_path(&dest).as_ptr(), ptr::null(), 0, 0, None, &cancel, ptr::null_mut());
+        download_set_keep_partial(ctx, 1);
+        assert_eq!(download_append_chunk(ctx, data.as_ptr(), data.len(), None, ptr::null_mut()), SUCCESS);
+        cancel.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(download_append_chunk(ctx, data.as_ptr(), data.len(), None, ptr::null_mut()), ERROR_CANCELLED);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(download_abort(ctx), SUCCESS);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // Nothing is recreated after an abort, and free has nothing left to do
+        cancel.store(false, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(download_append_chunk(ctx, data.as_ptr(), data.len(), None, ptr::null_mut()), ERROR_CANCELLED);
+        assert_eq!(download_finalize(ctx), ERROR_CANCELLED);
+        download_free(ctx);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // A decrypting download freed mid-stream is aborted by free
+        let stream = upload_stream(&dir, &vec![5u8; 300_000], true);
+        fs::remove_file(dir.join("source_300000_true.bin")).unwrap();
+        let ctx = download_init(c_path(&dest).as_ptr(), KEY.as_ptr(), 32, 1, None, ptr::null(), ptr::null_mut());
+        assert_eq!(download_append_chunk(ctx, stream.as_ptr(), stream.len() / 2, None, ptr::null_mut()), SUCCESS);
+        assert!(download_get_bytes_written(ctx) > 0);
+        download_free(ctx);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // Aborting a finished download keeps it
+        let ctx = download_init(c_path(&dest).as_ptr(), ptr::null(), 0, 0, None, ptr::null(), ptr::null_mut());
+        assert_eq!(download_append_chunk(ctx, data.as_ptr(), data.len(), None, ptr::null_mut()), SUCCESS);
+        assert_eq!(download_finalize(ctx), SUCCESS);
+        assert_eq!(download_abort(ctx), SUCCESS);
+        download_free(ctx);
+        assert_eq!(fs::read(&dest).unwrap(), data);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_download_abort_cuts_descriptor_back() {
+        use std::os::unix::io::AsRawFd;
+
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_download_abort_fd_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("saf.bin");
+        fs::write(&dest, b"existing").unwrap();
+
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(&dest).unwrap();
+        file.seek(std::io::SeekFrom::End(0)).unwrap();
+        let ctx = download_init_fd(file.as_raw_fd(), ptr::null(), 0, 0, None, ptr::null(), ptr::null_mut());
+        let data = vec![9u8; 200_000];
+        assert_eq!(download_append_chunk(ctx, data.as_ptr(), data.len(), None, ptr::null_mut()), SUCCESS);
+        assert_eq!(download_abort(ctx), SUCCESS);
+        download_free(ctx);
+        drop(file);
+        assert_eq!(fs::read(&dest).unwrap(), b"existing");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,1262 @@
+/// Encrypt-while-copying for CloudNexus
+/// Reads a local plaintext file, writes the encrypted container to a local
+/// destination, decrypts it back to verify it against a hash of the plaintext,
+/// and only then (optionally) deletes the source. Used by "encrypt this folder
+/// in place"; the folder driver runs on the folder copy work queue.
+/// The decrypt counterpart exports containers back to plaintext files, and the
+/// stream variants pipe data through the encryptor between file descriptors.
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::ffi::{c_char, c_void};
+#[cfg(unix)]
+use std::ffi::c_int;
+use std::slice;
+
+use crate::errors::checked;
+use crate::copy::{folder_copy_finalize, folder_copy_free, folder_copy_init, folder_copy_next_file, CopyProgressCallback,
+                  FolderCopyContext};
+use crate::file_io::{ProgressThrottler, ERROR_CANCELLED, ERROR_FILE_NOT_FOUND, ERROR_INVALID_PATH, ERROR_IO_FAILED,
+                     ERROR_NULL_POINTER, ERROR_PERMISSION_DENIED, SUCCESS, c_str_to_path, is_cancelled,
+                     PartialOutputGuard};
+#[cfg(unix)]
+use crate::file_io::file_from_fd;
+#[cfg(windows)]
+use crate::file_io::file_from_handle;
+use crate::source_reader::SourceReader;
+use crate::open_files::{acquire_open_files, is_out_of_descriptors, retry_open, ERROR_TOO_MANY_OPEN_FILES};
+use crate::phase_progress::{PhaseProgressCallback, PhaseTracker, PHASE_ENCRYPTING, PHASE_VERIFYING};
+use crate::{decrypt_chunk_impl, encrypt_chunk_impl, encrypt_file_finalize, encrypt_file_init, format_version_supported,
+            parse_chunk_header, parse_header, unwrap_fek, CHUNK_BASE_HEADER_SIZE, CHUNK_CRC_HEADER_SIZE,
+            CHUNK_REFERENCE_SIZE_FLAG, DEFAULT_CHUNK_SIZE, ERROR_DECRYPTION_FAILED, ERROR_INVALID_FORMAT,
+            FORMAT_VERSION_DEDUP, HEADER_SIZE, KEY_SIZE, MAC_SIZE, MAGIC, ProgressCallback};
+
+pub use crate::errors::{
+    ERROR_ENCRYPT_COPY_FAILED,
+    ERROR_ENCRYPT_VERIFY_FAILED,
+    ERROR_CONTAINER_TRUNCATED,
+    ERROR_UNSUPPORTED_VERSION,
+};
+
+/// Largest chunk content (ciphertext + MAC) accepted when decrypting, so
+/// memory stays bounded even for a damaged size field
+pub(crate) const MAX_CHUNK_CONTENT: usize = 10 * 1024 * 1024 + MAC_SIZE;
+
+/// Settings for encrypting the files of a folder copy
+#[derive(Clone)]
+pub(crate) struct EncryptCopyOptions {
+    pub master_key: Vec<u8>,
+    pub chunk_size: usize,
+    pub delete_source: bool,
+}
+
+/// What a folder copy does with each file instead of copying it
+#[derive(Clone)]
+pub(crate) enum CopyTransform {
+    /// Encrypt and verify every file
+    Encrypt(EncryptCopyOptions),
+    /// Decrypt every container; other files are copied verbatim
+    Decrypt { master_key: Vec<u8> },
+}
+
+/// Map an I/O error while reading the source or writing the destination
+pub(crate) fn io_error_code(e: &std::io::Error) -> i32 {
+    match e.kind() {
+        _ if is_out_of_descriptors(e) => ERROR_TOO_MANY_OPEN_FILES,
+        std::io::ErrorKind::NotFound => ERROR_FILE_NOT_FOUND,
+        std::io::ErrorKind::PermissionDenied => ERROR_PERMISSION_DENIED,
+        _ => ERROR_IO_FAILED,
+    }
+}
+
+/// Encrypt `src` into `dst`, verify it, then optionally delete `src`
+///
+/// `progress` receives (phase, bytes done in the phase, file size) for
+/// PHASE_ENCRYPTING and then PHASE_VERIFYING, each starting with a report of
+/// 0. The destination is deleted on any failure, and the source is only
+/// deleted after a successful verification.
+pub(crate) fn encrypt_copy_file_impl(
+    src: &Path,
+    dst: &Path,
+    options: &EncryptCopyOptions,
+    cancel_flag: *const AtomicBool,
+    progress: &mut dyn FnMut(u32, usize, usize),
+) -> Result<(), i32> {
+    if options.master_key.len() != KEY_SIZE {
+        return Err(ERROR_ENCRYPT_COPY_FAILED);
+    }
+
+    let source_size = src.metadata().map_err(|e| io_error_code(&e))?.len() as usize;
+    // The source stays open while the container is written, then read back
+    let _open_files = acquire_open_files(2)?;
+    let mut reader = retry_open(|| SourceReader::open(src, false)).map_err(|e| io_error_code(&e))?;
+
+    let dest_file = retry_open(|| File::create(dst)).map_err(|e| io_error_code(&e))?;
+    let partial = PartialOutputGuard::new(dst, false);
+    let mut writer = BufWriter::new(dest_file);
+
+    // Encryption pass, hashing the plaintext as it goes
+    progress(PHASE_ENCRYPTING, 0, source_size);
+    let source_hash = encrypt_pass(&mut reader, &mut writer, options, cancel_flag, &mut |done| {
+        progress(PHASE_ENCRYPTING, done, source_size);
+    })?;
+    writer.flush().map_err(|e| io_error_code(&e))?;
+    writer.get_ref().sync_all().map_err(|e| io_error_code(&e))?;
+    drop(writer);
+
+    // Verification pass, reading back what actually reached the disk
+    progress(PHASE_VERIFYING, 0, source_size);
+    let dest_hash = verify_pass(dst, &options.master_key, cancel_flag, &mut |done| {
+        progress(PHASE_VERIFYING, done, source_size);
+    })?;
+    if dest_hash != source_hash {
+        return Err(ERROR_ENCRYPT_VERIFY_FAILED);
+    }
+    partial.complete();
+
+    if options.delete_source {
+        fs::remove_file(src).map_err(|e| io_error_code(&e))?;
+    }
+    Ok(())
+}
+
+/// Write the encrypted container for `reader` and return the plaintext hash
+fn encrypt_pass<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    options: &EncryptCopyOptions,
+    cancel_flag: *const AtomicBool,
+    progress: &mut dyn FnMut(usize),
+) -> Result<[u8; 32], i32> {
+    let mut header_len = 0usize;
+    let context = encrypt_file_init(options.master_key.as_ptr(), options.master_key.len(), &mut header_len);
+    if context.is_null() {
+        return Err(ERROR_ENCRYPT_COPY_FAILED);
+    }
+
+    let result = (|| {
+        let ctx = unsafe { &*context };
+        writer.write_all(&ctx.header).map_err(|e| io_error_code(&e))?;
+        writer.write_all(&ctx.wrapped_fek).map_err(|e| io_error_code(&e))?;
+
+        let chunk_size = if options.chunk_size == 0 { DEFAULT_CHUNK_SIZE } else { options.chunk_size.clamp(64 * 1024, 10 * 1024 * 1024) };
+        let mut buffer = vec![0u8; chunk_size];
+        let mut hasher = blake3::Hasher::new();
+        let mut chunk_index: u32 = 0;
+        let mut done = 0usize;
+
+        loop {
+            if unsafe { is_cancelled(cancel_flag) } {
+                return Err(ERROR_CANCELLED);
+            }
+
+            let n = read_full(reader, &mut buffer).map_err(|e| io_error_code(&e))?;
+            if n == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..n]);
+            let record = encrypt_chunk_impl(&buffer[..n], &ctx.fek, chunk_index, ctx.chunk_crc)
+                .ok_or(ERROR_ENCRYPT_COPY_FAILED)?;
+            writer.write_all(&record).map_err(|e| io_error_code(&e))?;
+
+            chunk_index += 1;
+            done += n;
+            progress(done);
+        }
+
+        Ok(*hasher.finalize().as_bytes())
+    })();
+
+    encrypt_file_finalize(context);
+    result
+}
+
+/// Decrypt the container at `path` and return the hash of its plaintext
+///
+/// Any malformed header or chunk, or a chunk that fails authentication, is
+/// reported as ERROR_ENCRYPT_VERIFY_FAILED.
+fn verify_pass(
+    path: &Path,
+    master_key: &[u8],
+    cancel_flag: *const AtomicBool,
+    progress: &mut dyn FnMut(usize),
+) -> Result<[u8; 32], i32> {
+    let file = retry_open(|| File::open(path)).map_err(|e| io_error_code(&e))?;
+    let mut reader = BufReader::new(file);
+
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header).map_err(|_| ERROR_ENCRYPT_VERIFY_FAILED)?;
+    let (magic, version, fek_length) = parse_header(&header).map_err(|_| ERROR_ENCRYPT_VERIFY_FAILED)?;
+    if magic != MAGIC || !format_version_supported(version) || version == FORMAT_VERSION_DEDUP {
+        return Err(ERROR_ENCRYPT_VERIFY_FAILED);
+    }
+
+    let mut wrapped_fek = vec![0u8; fek_length];
+    reader.read_exact(&mut wrapped_fek).map_err(|_| ERROR_ENCRYPT_VERIFY_FAILED)?;
+    let fek = unwrap_fek(&header, &wrapped_fek, master_key).map_err(|_| ERROR_ENCRYPT_VERIFY_FAILED)?;
+
+    let mut hasher = blake3::Hasher::new();
+    let mut done = 0usize;
+    let mut record = Vec::new();
+    let mut prefix = [0u8; CHUNK_CRC_HEADER_SIZE];
+
+    loop {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err(ERROR_CANCELLED);
+        }
+
+        let (header_len, content_len) = match read_chunk_header(&mut reader, &mut prefix) {
+            Ok(Some(lengths)) => lengths,
+            Ok(None) => break,
+            Err(code) if code == ERROR_IO_FAILED => return Err(code),
+            Err(_) => return Err(ERROR_ENCRYPT_VERIFY_FAILED),
+        };
+
+        record.clear();
+        record.extend_from_slice(&prefix[..header_len]);
+        record.resize(header_len + content_len, 0);
+        reader.read_exact(&mut record[header_len..]).map_err(|_| ERROR_ENCRYPT_VERIFY_FAILED)?;
+
+        let (plaintext, _) = decrypt_chunk_impl(&record, &fek).map_err(|_| ERROR_ENCRYPT_VERIFY_FAILED)?;
+        hasher.update(&plaintext);
+        done += plaintext.len();
+        progress(done);
+    }
+
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Read the next chunk header into `prefix`
+///
+/// Returns (header length, content length), or None at a clean end of the
+/// container. A header cut short is ERROR_CONTAINER_TRUNCATED; dedup
+/// reference records and oversized chunks are ERROR_INVALID_FORMAT.
+fn read_chunk_header<R: Read>(reader: &mut R, prefix: &mut [u8; CHUNK_CRC_HEADER_SIZE]) -> Result<Option<(usize, usize)>, i32> {
+    // Base chunk header first; a CRC header carries 4 more bytes
+    let n = read_full(reader, &mut prefix[..CHUNK_BASE_HEADER_SIZE]).map_err(|_| ERROR_IO_FAILED)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n < CHUNK_BASE_HEADER_SIZE {
+        return Err(ERROR_CONTAINER_TRUNCATED);
+    }
+
+    let size_field = u32::from_le_bytes([prefix[4], prefix[5], prefix[6], prefix[7]]);
+    if size_field & CHUNK_REFERENCE_SIZE_FLAG != 0 {
+        return Err(ERROR_INVALID_FORMAT);
+    }
+    let header_len = if parse_chunk_header(&prefix[..CHUNK_BASE_HEADER_SIZE]).is_some() {
+        CHUNK_BASE_HEADER_SIZE
+    } else {
+        let n = read_full(reader, &mut prefix[CHUNK_BASE_HEADER_SIZE..]).map_err(|_| ERROR_IO_FAILED)?;
+        if n < CHUNK_CRC_HEADER_SIZE - CHUNK_BASE_HEADER_SIZE {
+            return Err(ERROR_CONTAINER_TRUNCATED);
+        }
+        CHUNK_CRC_HEADER_SIZE
+    };
+
+    match parse_chunk_header(&prefix[..header_len]) {
+        Some((_, _, content_len)) if (MAC_SIZE..=MAX_CHUNK_CONTENT).contains(&content_len) => Ok(Some((header_len, content_len))),
+        _ => Err(ERROR_INVALID_FORMAT),
+    }
+}
+
+/// Whether the file at `path` starts with the container magic
+pub(crate) fn is_encrypted_container(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    match File::open(path) {
+        Ok(mut file) => file.read_exact(&mut magic).is_ok() && u32::from_le_bytes(magic) == MAGIC,
+        Err(_) => false,
+    }
+}
+
+/// Read and validate the main header and wrapped key, returning the unwrapped FEK
+fn read_container_key<R: Read>(reader: &mut R, master_key: &[u8]) -> Result<Vec<u8>, i32> {
+    let mut header = [0u8; HEADER_SIZE];
+    let n = read_full(reader, &mut header).map_err(|e| io_error_code(&e))?;
+    if n < 4 || u32::from_le_bytes([header[0], header[1], header[2], header[3]]) != MAGIC {
+        return Err(ERROR_INVALID_FORMAT);
+    }
+    if n < HEADER_SIZE {
+        return Err(ERROR_CONTAINER_TRUNCATED);
+    }
+
+    let (_, version, fek_length) = parse_header(&header)?;
+    if !format_version_supported(version) || version == FORMAT_VERSION_DEDUP {
+        return Err(ERROR_UNSUPPORTED_VERSION);
+    }
+
+    let mut wrapped_fek = vec![0u8; fek_length];
+    let n = read_full(reader, &mut wrapped_fek).map_err(|e| io_error_code(&e))?;
+    if n < fek_length {
+        return Err(ERROR_CONTAINER_TRUNCATED);
+    }
+    unwrap_fek(&header, &wrapped_fek, master_key).map_err(|_| ERROR_DECRYPTION_FAILED)
+}
+
+/// Walk the chunk headers from the current position and sum their plaintext sizes
+///
+/// Only headers are read; chunk contents are skipped by seeking, so this also
+/// detects a truncated container before anything is written.
+fn scan_plaintext_size(file: &mut File, file_len: u64) -> Result<usize, i32> {
+    let mut prefix = [0u8; CHUNK_CRC_HEADER_SIZE];
+    let mut total = 0usize;
+
+    while let Some((_, content_len)) = read_chunk_header(file, &mut prefix)? {
+        let position = file.seek(SeekFrom::Current(content_len as i64)).map_err(|e| io_error_code(&e))?;
+        if position > file_len {
+            return Err(ERROR_CONTAINER_TRUNCATED);
+        }
+        total += content_len - MAC_SIZE;
+    }
+    Ok(total)
+}
+
+/// Decrypt the container at `src` into the plaintext file `dst`
+///
+/// The container is streamed one chunk at a time. `progress` receives
+/// (plaintext bytes written, plaintext size predicted from the chunk headers).
+/// The destination is only created once the header and key are valid, and is
+/// deleted on any later failure.
+pub(crate) fn decrypt_copy_file_impl(
+    src: &Path,
+    dst: &Path,
+    master_key: &[u8],
+    cancel_flag: *const AtomicBool,
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<(), i32> {
+    if master_key.len() != KEY_SIZE {
+        return Err(ERROR_DECRYPTION_FAILED);
+    }
+
+    let _open_files = acquire_open_files(2)?;
+    let mut file = retry_open(|| File::open(src)).map_err(|e| io_error_code(&e))?;
+    let file_len = file.metadata().map_err(|e| io_error_code(&e))?.len();
+    let fek = read_container_key(&mut file, master_key)?;
+
+    let data_start = file.stream_position().map_err(|e| io_error_code(&e))?;
+    let total = scan_plaintext_size(&mut file, file_len)?;
+    file.seek(SeekFrom::Start(data_start)).map_err(|e| io_error_code(&e))?;
+    let mut reader = BufReader::new(file);
+
+    let dest_file = retry_open(|| File::create(dst)).map_err(|e| io_error_code(&e))?;
+    let partial = PartialOutputGuard::new(dst, false);
+    let mut writer = BufWriter::new(dest_file);
+
+    progress(0, total);
+    decrypt_records(&mut reader, &mut writer, &fek, cancel_flag, &mut |done| progress(done, total))?;
+
+    writer.flush().map_err(|e| io_error_code(&e))?;
+    drop(writer);
+    partial.complete();
+    Ok(())
+}
+
+/// Decrypt the chunk records from `reader` until it ends, writing the plaintext
+///
+/// `progress` receives the plaintext bytes written so far.
+fn decrypt_records<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    fek: &[u8],
+    cancel_flag: *const AtomicBool,
+    progress: &mut dyn FnMut(usize),
+) -> Result<(), i32> {
+    let mut done = 0usize;
+    let mut record = Vec::new();
+    let mut prefix = [0u8; CHUNK_CRC_HEADER_SIZE];
+
+    loop {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err(ERROR_CANCELLED);
+        }
+
+        let (header_len, content_len) = match read_chunk_header(reader, &mut prefix)? {
+            Some(lengths) => lengths,
+            None => break,
+        };
+
+        record.clear();
+        record.extend_from_slice(&prefix[..header_len]);
+        record.resize(header_len + content_len, 0);
+        let n = read_full(reader, &mut record[header_len..]).map_err(|e| io_error_code(&e))?;
+        if n < content_len {
+            return Err(ERROR_CONTAINER_TRUNCATED);
+        }
+
+        let (plaintext, _) = decrypt_chunk_impl(&record, fek)?;
+        writer.write_all(&plaintext).map_err(|e| io_error_code(&e))?;
+        done += plaintext.len();
+        progress(done);
+    }
+    Ok(())
+}
+
+/// Encrypt everything `input` delivers until EOF into a container on `output`
+///
+/// For pipes and other streams of unknown length: reads may return short
+/// counts, and only EOF ends the input. `progress` receives (plaintext bytes
+/// read, 0).
+fn encrypt_stream_files(
+    input: std::io::Result<File>,
+    output: std::io::Result<File>,
+    options: &EncryptCopyOptions,
+    cancel_flag: *const AtomicBool,
+    progress: &mut dyn FnMut(usize),
+) -> Result<(), i32> {
+    let (mut input, output) = match (input, output) {
+        (Ok(input), Ok(output)) => (input, output),
+        _ => return Err(ERROR_INVALID_PATH),
+    };
+    let mut writer = BufWriter::new(output);
+    encrypt_pass(&mut input, &mut writer, options, cancel_flag, progress)?;
+    writer.flush().map_err(|e| io_error_code(&e))
+}
+
+/// Decrypt a container read from `input` until EOF into plaintext on `output`
+fn decrypt_stream_files(
+    input: std::io::Result<File>,
+    output: std::io::Result<File>,
+    master_key: &[u8],
+    cancel_flag: *const AtomicBool,
+    progress: &mut dyn FnMut(usize),
+) -> Result<(), i32> {
+    let (input, output) = match (input, output) {
+        (Ok(input), Ok(output)) => (input, output),
+        _ => return Err(ERROR_INVALID_PATH),
+    };
+    let mut reader = BufReader::new(input);
+    let fek = read_container_key(&mut reader, master_key)?;
+    let mut writer = BufWriter::new(output);
+    decrypt_records(&mut reader, &mut writer, &fek, cancel_flag, progress)?;
+    writer.flush().map_err(|e| io_error_code(&e))
+}
+
+/// Report stream progress with an unknown total
+fn stream_progress(progress_callback: Option<ProgressCallback>, user_data: *mut c_void) -> impl FnMut(usize) {
+    let user_data = user_data as usize;
+    move |done| {
+        if let Some(cb) = progress_callback {
+            cb(done, 0, user_data as *mut c_void);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn encrypt_stream(
+    input: std::io::Result<File>,
+    output: std::io::Result<File>,
+    master_key: *const u8,
+    master_key_len: usize,
+    chunk_size: usize,
+    progress_callback: Option<ProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    let options = match master_key_from(master_key, master_key_len, ERROR_ENCRYPT_COPY_FAILED) {
+        Ok(master_key) => EncryptCopyOptions { master_key, chunk_size, delete_source: false },
+        Err(code) => return code,
+    };
+
+    let mut progress = stream_progress(progress_callback, user_data);
+    match encrypt_stream_files(input, output, &options, cancel_flag, &mut progress) {
+        Ok(()) => SUCCESS,
+        Err(code) => code,
+    }
+}
+
+fn decrypt_stream(
+    input: std::io::Result<File>,
+    output: std::io::Result<File>,
+    master_key: *const u8,
+    master_key_len: usize,
+    progress_callback: Option<ProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    let master_key = match master_key_from(master_key, master_key_len, ERROR_DECRYPTION_FAILED) {
+        Ok(master_key) => master_key,
+        Err(code) => return code,
+    };
+
+    let mut progress = stream_progress(progress_callback, user_data);
+    match decrypt_stream_files(input, output, &master_key, cancel_flag, &mut progress) {
+        Ok(()) => SUCCESS,
+        Err(code) => code,
+    }
+}
+
+/// Fill `buffer` as far as the reader allows, returning the bytes read (0 at EOF)
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Copy the master key, failing with `invalid_code` if it has the wrong size
+fn master_key_from(master_key: *const u8, master_key_len: usize, invalid_code: i32) -> Result<Vec<u8>, i32> {
+    if master_key.is_null() {
+        return Err(ERROR_NULL_POINTER);
+    }
+    if master_key_len != KEY_SIZE {
+        return Err(invalid_code);
+    }
+    Ok(unsafe { slice::from_raw_parts(master_key, master_key_len) }.to_vec())
+}
+
+/// Encrypt a local file into a local container, verify it, and optionally delete the source
+///
+/// The container is decrypted back and compared with a hash of the plaintext
+/// taken while encrypting; the source is deleted only if they match. The
+/// destination is removed on failure or cancellation.
+///
+/// # Arguments
+/// * `source_path` - Plaintext source file path
+/// * `dest_path` - Destination path for the encrypted container
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `chunk_size` - Plaintext chunk size in bytes (0 for default)
+/// * `delete_source_after_verify` - 1 to delete the source after a successful verification
+/// * `progress_callback` - Progress callback (bytes count the encryption and verification passes)
+/// * `cancel_flag` - Cancellation flag
+/// * `user_data` - User data
+///
+/// # Returns
+/// 0 on success, ERROR_ENCRYPT_VERIFY_FAILED if verification failed, or another error code
+#[allow(clippy::too_many_arguments, clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn encrypt_copy_file(
+    source_path: *const c_char,
+    dest_path: *const c_char,
+    master_key: *const u8,
+    master_key_len: usize,
+    chunk_size: usize,
+    delete_source_after_verify: i32,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    if source_path.is_null() || dest_path.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let src = match unsafe { c_str_to_path(source_path) } {
+        Ok(p) => p,
+        Err(_) => return ERROR_INVALID_PATH,
+    };
+    let dst = match unsafe { c_str_to_path(dest_path) } {
+        Ok(p) => p,
+        Err(_) => return ERROR_INVALID_PATH,
+    };
+    if !src.is_file() {
+        return ERROR_FILE_NOT_FOUND;
+    }
+    if src == dst {
+        return ERROR_INVALID_PATH;
+    }
+
+    let options = match master_key_from(master_key, master_key_len, ERROR_ENCRYPT_COPY_FAILED) {
+        Ok(master_key) => EncryptCopyOptions {
+            master_key,
+            chunk_size,
+            delete_source: delete_source_after_verify == 1,
+        },
+        Err(code) => return checked(code),
+    };
+
+    // Both passes count the plaintext size once, so the total is twice the file size
+    let mut throttler = ProgressThrottler::new(500);
+    let mut report = |phase: u32, done: usize, size: usize| {
+        let verifying = phase == PHASE_VERIFYING;
+        let done = if verifying { size + done } else { done };
+        if let Some(cb) = progress_callback {
+            if throttler.should_update_with(done, size * 2, verifying && done == size) {
+                cb(done, size * 2, 1, 1, user_data);
+            }
+        }
+    };
+
+    match encrypt_copy_file_impl(&src, &dst, &options, cancel_flag, &mut report) {
+        Ok(()) => SUCCESS,
+        Err(code) => checked(code),
+    }
+}
+
+/// Encrypt a local file into a local container and verify it, reporting progress per phase
+///
+/// Same as encrypt_copy_file, with progress reported through a
+/// PhaseProgressCallback: PHASE_ENCRYPTING while the container is written,
+/// then PHASE_VERIFYING while it is decrypted back. Both count plaintext bytes
+/// against the source size.
+///
+/// # Arguments
+/// * `source_path` - Plaintext source file path
+/// * `dest_path` - Destination path for the encrypted container
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `chunk_size` - Plaintext chunk size in bytes (0 for default)
+/// * `delete_source_after_verify` - 1 to delete the source after a successful verification
+/// * `phase_callback` - Phased progress callback
+/// * `cancel_flag` - Cancellation flag
+/// * `user_data` - User data
+///
+/// # Returns
+/// 0 on success, ERROR_ENCRYPT_VERIFY_FAILED if verification failed, or another error code
+#[allow(clippy::too_many_arguments, clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn encrypt_copy_file_phased(
+    source_path: *const c_char,
+    dest_path: *const c_char,
+    master_key: *const u8,
+    master_key_len: usize,
+    chunk_size: usize,
+    delete_source_after_verify: i32,
+    phase_callback: Option<PhaseProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    if source_path.is_null() || dest_path.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let src = match unsafe { c_str_to_path(source_path) } {
+        Ok(p) => p,
+        Err(_) => return ERROR_INVALID_PATH,
+    };
+    let dst = match unsafe { c_str_to_path(dest_path) } {
+        Ok(p) => p,
+        Err(_) => return ERROR_INVALID_PATH,
+    };
+    if !src.is_file() {
+        return ERROR_FILE_NOT_FOUND;
+    }
+    if src == dst {
+        return ERROR_INVALID_PATH;
+    }
+
+    let options = match master_key_from(master_key, master_key_len, ERROR_ENCRYPT_COPY_FAILED) {
+        Ok(master_key) => EncryptCopyOptions {
+            master_key,
+            chunk_size,
+            delete_source: delete_source_after_verify == 1,
+        },
+        Err(code) => return checked(code),
+    };
+
+    let mut tracker = PhaseTracker::new();
+    let mut report = |phase: u32, done: usize, size: usize| {
+        tracker.report(phase_callback, user_data, phase, done as u64, size as u64);
+    };
+
+    match encrypt_copy_file_impl(&src, &dst, &options, cancel_flag, &mut report) {
+        Ok(()) => SUCCESS,
+        Err(code) => checked(code),
+    }
+}
+
+/// Encrypt every file of a local folder into a destination folder
+///
+/// Runs the folder copy work queue (same ordering, name sanitization and
+/// progress reporting as folder_copy_next_file) with each file encrypted and
+/// verified as in encrypt_copy_file. With `delete_source_after_verify`, each
+/// source file is deleted once its container verified; directories are kept.
+/// Stops at the first failed file.
+///
+/// # Arguments
+/// * `source_folder` - Plaintext source folder
+/// * `dest_folder` - Destination folder (must not exist yet)
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `chunk_size` - Plaintext chunk size in bytes (0 for default)
+/// * `delete_source_after_verify` - 1 to delete each source file after verification
+/// * `progress_callback` - Progress callback
+/// * `cancel_flag` - Cancellation flag
+/// * `user_data` - User data
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::too_many_arguments)]
+#[no_mangle]
+pub extern "C" fn encrypt_copy_folder(
+    source_folder: *const c_char,
+    dest_folder: *const c_char,
+    master_key: *const u8,
+    master_key_len: usize,
+    chunk_size: usize,
+    delete_source_after_verify: i32,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    let options = match master_key_from(master_key, master_key_len, ERROR_ENCRYPT_COPY_FAILED) {
+        Ok(master_key) => EncryptCopyOptions {
+            master_key,
+            chunk_size,
+            delete_source: delete_source_after_verify == 1,
+        },
+        Err(code) => return checked(code),
+    };
+
+    let context = folder_copy_init(source_folder, dest_folder, cancel_flag);
+    if context.is_null() {
+        return ERROR_IO_FAILED;
+    }
+    checked(run_folder_transform(context, CopyTransform::Encrypt(options), progress_callback, user_data))
+}
+
+/// Decrypt a local container file into a plaintext file
+///
+/// The container is streamed from disk one chunk at a time and never loaded
+/// whole. Progress counts plaintext bytes against the size predicted from the
+/// chunk headers, which are scanned before anything is written.
+///
+/// # Arguments
+/// * `source_encrypted_path` - Encrypted container file path
+/// * `dest_path` - Destination path for the plaintext
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `progress_callback` - Progress callback
+/// * `cancel_flag` - Cancellation flag
+/// * `user_data` - User data
+///
+/// # Returns
+/// 0 on success, or an error code: ERROR_DECRYPTION_FAILED for a wrong key or
+/// tampered chunk, ERROR_CONTAINER_TRUNCATED, ERROR_UNSUPPORTED_VERSION,
+/// ERROR_INVALID_FORMAT if the file is not a container
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn decrypt_copy_file(
+    source_encrypted_path: *const c_char,
+    dest_path: *const c_char,
+    master_key: *const u8,
+    master_key_len: usize,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    if source_encrypted_path.is_null() || dest_path.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let src = match unsafe { c_str_to_path(source_encrypted_path) } {
+        Ok(p) => p,
+        Err(_) => return ERROR_INVALID_PATH,
+    };
+    let dst = match unsafe { c_str_to_path(dest_path) } {
+        Ok(p) => p,
+        Err(_) => return ERROR_INVALID_PATH,
+    };
+    if !src.is_file() {
+        return ERROR_FILE_NOT_FOUND;
+    }
+    if src == dst {
+        return ERROR_INVALID_PATH;
+    }
+
+    let master_key = match master_key_from(master_key, master_key_len, ERROR_DECRYPTION_FAILED) {
+        Ok(master_key) => master_key,
+        Err(code) => return checked(code),
+    };
+
+    let mut throttler = ProgressThrottler::new(500);
+    let mut report = |done: usize, total: usize| {
+        if let Some(cb) = progress_callback {
+            if throttler.should_update(done, total) {
+                cb(done, total, 1, 1, user_data);
+            }
+        }
+    };
+
+    match decrypt_copy_file_impl(&src, &dst, &master_key, cancel_flag, &mut report) {
+        Ok(()) => SUCCESS,
+        Err(code) => checked(code),
+    }
+}
+
+/// Encrypt a stream read from a file descriptor into a container on another
+///
+/// Reads until EOF, so pipes and sockets work (short reads are fine); the
+/// total size does not need to be known. The output is the standard streaming
+/// container. Both descriptors stay owned by the caller, who must close them;
+/// closing the write end of a pipe afterwards signals EOF to its reader.
+///
+/// # Arguments
+/// * `input_fd` - Readable file descriptor with the plaintext
+/// * `output_fd` - Writable file descriptor for the container
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `chunk_size` - Plaintext chunk size in bytes (0 for default)
+/// * `progress_callback` - Progress callback (bytes read so far, total 0)
+/// * `cancel_flag` - Cancellation flag
+/// * `user_data` - User data
+///
+/// # Returns
+/// 0 on success, ERROR_INVALID_PATH for an invalid descriptor, or another error code
+#[cfg(unix)]
+#[allow(clippy::too_many_arguments)]
+#[no_mangle]
+pub extern "C" fn encrypt_stream_fd(
+    input_fd: c_int,
+    output_fd: c_int,
+    master_key: *const u8,
+    master_key_len: usize,
+    chunk_size: usize,
+    progress_callback: Option<ProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    checked(encrypt_stream(file_from_fd(input_fd), file_from_fd(output_fd), master_key, master_key_len, chunk_size,
+                           progress_callback, cancel_flag, user_data))
+}
+
+/// Decrypt a container read from a file descriptor into plaintext on another
+///
+/// Counterpart of encrypt_stream_fd: reads the container until EOF. A stream
+/// that ends inside a chunk fails with ERROR_CONTAINER_TRUNCATED; plaintext
+/// of the chunks before it has already been written.
+///
+/// # Arguments
+/// * `input_fd` - Readable file descriptor with the container
+/// * `output_fd` - Writable file descriptor for the plaintext
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `progress_callback` - Progress callback (plaintext bytes written, total 0)
+/// * `cancel_flag` - Cancellation flag
+/// * `user_data` - User data
+///
+/// # Returns
+/// 0 on success, or an error code as for decrypt_copy_file
+#[cfg(unix)]
+#[no_mangle]
+pub extern "C" fn decrypt_stream_fd(
+    input_fd: c_int,
+    output_fd: c_int,
+    master_key: *const u8,
+    master_key_len: usize,
+    progress_callback: Option<ProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    checked(decrypt_stream(file_from_fd(input_fd), file_from_fd(output_fd), master_key, master_key_len,
+                           progress_callback, cancel_flag, user_data))
+}
+
+/// Encrypt a stream read from a HANDLE into a container on another
+///
+/// Windows equivalent of encrypt_stream_fd. Both handles stay owned by the caller.
+///
+/// # Arguments
+/// * `input_handle` - Readable HANDLE with the plaintext
+/// * `output_handle` - Writable HANDLE for the container
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `chunk_size` - Plaintext chunk size in bytes (0 for default)
+/// * `progress_callback` - Progress callback (bytes read so far, total 0)
+/// * `cancel_flag` - Cancellation flag
+/// * `user_data` - User data
+///
+/// # Returns
+/// 0 on success, ERROR_INVALID_PATH for an invalid handle, or another error code
+#[cfg(windows)]
+#[allow(clippy::too_many_arguments)]
+#[no_mangle]
+pub extern "C" fn encrypt_stream_handle(
+    input_handle: *mut c_void,
+    output_handle: *mut c_void,
+    master_key: *const u8,
+    master_key_len: usize,
+    chunk_size: usize,
+    progress_callback: Option<ProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    checked(encrypt_stream(file_from_handle(input_handle), file_from_handle(output_handle), master_key, master_key_len,
+                           chunk_size, progress_callback, cancel_flag, user_data))
+}
+
+/// Decrypt a container read from a HANDLE into plaintext on another
+///
+/// Windows equivalent of decrypt_stream_fd. Both handles stay owned by the caller.
+///
+/// # Arguments
+/// * `input_handle` - Readable HANDLE with the container
+/// * `output_handle` - Writable HANDLE for the plaintext
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `progress_callback` - Progress callback (plaintext bytes written, total 0)
+/// * `cancel_flag` - Cancellation flag
+/// * `user_data` - User data
+///
+/// # Returns
+/// 0 on success, or an error code as for decrypt_copy_file
+#[cfg(windows)]
+#[no_mangle]
+pub extern "C" fn decrypt_stream_handle(
+    input_handle: *mut c_void,
+    output_handle: *mut c_void,
+    master_key: *const u8,
+    master_key_len: usize,
+    progress_callback: Option<ProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    checked(decrypt_stream(file_from_handle(input_handle), file_from_handle(output_handle), master_key, master_key_len,
+                           progress_callback, cancel_flag, user_data))
+}
+
+/// Decrypt every container of a local folder into a destination folder
+///
+/// Runs the folder copy work queue like encrypt_copy_folder. Files starting
+/// with the container magic are decrypted; every other file is copied
+/// verbatim. Stops at the first failed file.
+///
+/// # Arguments
+/// * `source_folder` - Folder holding encrypted containers
+/// * `dest_folder` - Destination folder (must not exist yet)
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `progress_callback` - Progress callback
+/// * `cancel_flag` - Cancellation flag
+/// * `user_data` - User data
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[no_mangle]
+pub extern "C" fn decrypt_copy_folder(
+    source_folder: *const c_char,
+    dest_folder: *const c_char,
+    master_key: *const u8,
+    master_key_len: usize,
+    progress_callback: Option<CopyProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i32 {
+    let master_key = match master_key_from(master_key, master_key_len, ERROR_DECRYPTION_FAILED) {
+        Ok(master_key) => master_key,
+        Err(code) => return checked(code),
+    };
+
+    let context = folder_copy_init(source_folder, dest_folder, cancel_flag);
+    if context.is_null() {
+        return ERROR_IO_FAILED;
+    }
+    checked(run_folder_transform(context, CopyTransform::Decrypt { master_key }, progress_callback, user_data))
+}
+
+/// Drive a folder copy with `transform` applied to every file, then free it
+fn run_folder_transform(
+    context: *mut FolderCopyContext,
+    transform: CopyTransform,
+    progress_callback: Option<CopyProgressCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    unsafe { (*context).set_transform(transform); }
+
+    let mut result = loop {
+        match folder_copy_next_file(context, progress_callback, user_data) {
+            1 => continue,
+            0 => break SUCCESS,
+            code => break code,
+        }
+    };
+    if result == SUCCESS {
+        result = folder_copy_finalize(context, progress_callback, user_data);
+    }
+
+    folder_copy_free(context);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::c_int;
+    use std::io::{Seek, SeekFrom};
+    use std::path::PathBuf;
+    use std::ptr;
+
+    use crate::test_util::{c_path, temp_dir};
+    use crate::{decrypt_file_streaming_ex, free_buffer};
+
+    const KEY: [u8; KEY_SIZE] = [7u8; KEY_SIZE];
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn decrypt(container: &[u8]) -> Vec<u8> {
+        let mut output_len = 0usize;
+        let mut status: c_int = 1;
+        let output = decrypt_file_streaming_ex(container.as_ptr(), container.len(), KEY.as_ptr(), KEY_SIZE,
+                                               &mut output_len, None, ptr::null_mut(), ptr::null(), &mut status);
+        assert_eq!(status, 0);
+        let plaintext = unsafe { slice::from_raw_parts(output, output_len) }.to_vec();
+        free_buffer(output);
+        plaintext
+    }
+
+    /// Flip a byte in the last chunk of the container once encryption finished
+    extern "C" fn corrupt_after_encryption(bytes: usize, total: usize, _files: usize, _total_files: usize, user_data: *mut c_void) {
+        let (dest, corrupted) = unsafe { &mut *(user_data as *mut (PathBuf, bool)) };
+        if !*corrupted && bytes >= total / 2 {
+            let mut file = fs::OpenOptions::new().read(true).write(true).open(&*dest).unwrap();
+            let offset = file.metadata().unwrap().len() - 10;
+            let mut byte = [0u8; 1];
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.read_exact(&mut byte).unwrap();
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(&[byte[0] ^ 0xFF]).unwrap();
+            *corrupted = true;
+        }
+    }
+
+    #[test]
+    fn test_encrypt_copy_deletes_source_after_verify() {
+        let root = temp_dir("encrypt_copy_file");
+        let src = root.join("plain.bin");
+        let dst = root.join("plain.bin.enc");
+        let plaintext = content(200 * 1024 + 17);
+        fs::write(&src, &plaintext).unwrap();
+
+        let result = encrypt_copy_file(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), KEY.as_ptr(), KEY_SIZE,
+                                       64 * 1024, 1, None, ptr::null(), ptr::null_mut());
+        assert_eq!(result, SUCCESS);
+        assert!(!src.exists());
+        assert_eq!(decrypt(&fs::read(&dst).unwrap()), plaintext);
+
+        // Wrong key size and identical paths are rejected up front
+        fs::write(&src, &plaintext).unwrap();
+        assert_eq!(encrypt_copy_file(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), KEY.as_ptr(), 16,
+                                     0, 1, None, ptr::null(), ptr::null_mut()), ERROR_ENCRYPT_COPY_FAILED);
+        assert_eq!(encrypt_copy_file(c_path(&src).as_ptr(), c_path(&src).as_ptr(), KEY.as_ptr(), KEY_SIZE,
+                                     0, 1, None, ptr::null(), ptr::null_mut()), ERROR_INVALID_PATH);
+        assert_eq!(fs::read(&src).unwrap(), plaintext);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_encrypt_copy_corrupted_output_keeps_source() {
+        let root = temp_dir("encrypt_copy_corrupt");
+        let src = root.join("plain.bin");
+        let dst = root.join("plain.bin.enc");
+        let plaintext = content(3 * 64 * 1024 + 100);
+        fs::write(&src, &plaintext).unwrap();
+
+        let mut state = (dst.clone(), false);
+        let result = encrypt_copy_file(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), KEY.as_ptr(), KEY_SIZE,
+                                       64 * 1024, 1, Some(corrupt_after_encryption), ptr::null(),
+                                       &mut state as *mut (PathBuf, bool) as *mut c_void);
+        assert!(state.1);
+        assert_eq!(result, ERROR_ENCRYPT_VERIFY_FAILED);
+        assert!(!dst.exists());
+        assert_eq!(fs::read(&src).unwrap(), plaintext);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    extern "C" fn record_progress(bytes: usize, total: usize, _files: usize, _total_files: usize, user_data: *mut c_void) {
+        let calls = unsafe { &mut *(user_data as *mut Vec<(usize, usize)>) };
+        calls.push((bytes, total));
+    }
+
+    fn decrypt_copy(src: &Path, dst: &Path, key: &[u8]) -> i32 {
+        decrypt_copy_file(c_path(src).as_ptr(), c_path(dst).as_ptr(), key.as_ptr(), key.len(),
+                          None, ptr::null(), ptr::null_mut())
+    }
+
+    #[test]
+    fn test_decrypt_copy_round_trip_and_errors() {
+        let root = temp_dir("decrypt_copy_file");
+        let src = root.join("plain.bin");
+        let container = root.join("plain.bin.enc");
+        let dst = root.join("restored.bin");
+        let plaintext = content(3 * 64 * 1024 + 5);
+        fs::write(&src, &plaintext).unwrap();
+        assert_eq!(encrypt_copy_file(c_path(&src).as_ptr(), c_path(&container).as_ptr(), KEY.as_ptr(), KEY_SIZE,
+                                     64 * 1024, 0, None, ptr::null(), ptr::null_mut()), SUCCESS);
+
+        let mut calls: Vec<(usize, usize)> = Vec::new();
+        let result = decrypt_copy_file(c_path(&container).as_ptr(), c_path(&dst).as_ptr(), KEY.as_ptr(), KEY_SIZE,
+                                       Some(record_progress), ptr::null(),
+                                       &mut calls as *mut Vec<(usize, usize)> as *mut c_void);
+        assert_eq!(result, SUCCESS);
+        assert_eq!(fs::read(&dst).unwrap(), plaintext);
+        assert!(calls.iter().all(|&(_, total)| total == plaintext.len()));
+        assert_eq!(calls.last(), Some(&(plaintext.len(), plaintext.len())));
+        fs::remove_file(&dst).unwrap();
+
+        // Wrong key: nothing is written
+        assert_eq!(decrypt_copy(&container, &dst, &[8u8; KEY_SIZE]), ERROR_DECRYPTION_FAILED);
+        assert!(!dst.exists());
+
+        // Truncated in the middle of the last chunk
+        let bytes = fs::read(&container).unwrap();
+        let damaged = root.join("damaged.enc");
+        fs::write(&damaged, &bytes[..bytes.len() - 7]).unwrap();
+        assert_eq!(decrypt_copy(&damaged, &dst, &KEY), ERROR_CONTAINER_TRUNCATED);
+        assert!(!dst.exists());
+
+        // Newer format version
+        let mut newer = bytes.clone();
+        newer[4] = crate::FORMAT_VERSION_LATEST + 1;
+        fs::write(&damaged, &newer).unwrap();
+        assert_eq!(decrypt_copy(&damaged, &dst, &KEY), ERROR_UNSUPPORTED_VERSION);
+
+        // Not a container at all
+        assert_eq!(decrypt_copy(&src, &dst, &KEY), ERROR_INVALID_FORMAT);
+        assert!(!dst.exists());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_decrypt_copy_folder_passes_plain_files_through() {
+        let root = temp_dir("decrypt_copy_folder");
+        let plain = root.join("plain");
+        let encrypted = root.join("encrypted");
+        let restored = root.join("restored");
+        fs::create_dir_all(plain.join("sub")).unwrap();
+        fs::write(plain.join("a.txt"), content(1000)).unwrap();
+        fs::write(plain.join("sub/b.bin"), content(130 * 1024)).unwrap();
+        assert_eq!(encrypt_copy_folder(c_path(&plain).as_ptr(), c_path(&encrypted).as_ptr(), KEY.as_ptr(), KEY_SIZE,
+                                       64 * 1024, 0, None, ptr::null(), ptr::null_mut()), SUCCESS);
+
+        // A plaintext file mixed in with the containers
+        fs::write(encrypted.join("sub/notes.txt"), b"not encrypted").unwrap();
+
+        let result = decrypt_copy_folder(c_path(&encrypted).as_ptr(), c_path(&restored).as_ptr(), KEY.as_ptr(), KEY_SIZE,
+                                         None, ptr::null(), ptr::null_mut());
+        assert_eq!(result, SUCCESS);
+        assert_eq!(fs::read(restored.join("a.txt")).unwrap(), content(1000));
+        assert_eq!(fs::read(restored.join("sub/b.bin")).unwrap(), content(130 * 1024));
+        assert_eq!(fs::read(restored.join("sub/notes.txt")).unwrap(), b"not encrypted");
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_encrypt_copy_folder() {
+        let root = temp_dir("encrypt_copy_folder");
+        let src = root.join("source");
+        let dst = root.join("dest");
+        fs::create_dir_all(src.join("sub/deeper")).unwrap();
+        let files = [("a.txt", content(10)), ("sub/b.bin", content(70 * 1024)), ("sub/deeper/c.txt", Vec::new())];
+        for (rel, data) in &files {
+            fs::write(src.join(rel), data).unwrap();
+        }
+
+        let result = encrypt_copy_folder(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), KEY.as_ptr(), KEY_SIZE,
+                                         64 * 1024, 1, None, ptr::null(), ptr::null_mut());
+        assert_eq!(result, SUCCESS);
+        for (rel, data) in &files {
+            assert!(!src.join(rel).exists());
+            assert_eq!(&decrypt(&fs::read(dst.join(rel)).unwrap()), data);
+        }
+        assert!(src.join("sub/deeper").is_dir());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    extern "C" fn record_stream_progress(bytes: usize, total: usize, user_data: *mut c_void) {
+        let seen = unsafe { &mut *(user_data as *mut (usize, usize)) };
+        *seen = (bytes, seen.1.max(total));
+    }
+
+    #[cfg(unix)]
+    fn pipe() -> (c_int, c_int) {
+        let mut fds = [0 as c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        (fds[0], fds[1])
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stream_fd_round_trip_through_pipes() {
+        use rand::RngCore;
+        use std::os::fd::{FromRawFd, IntoRawFd};
+
+        let mut data = vec![0u8; 10 * 1024 * 1024];
+        rand::rngs::OsRng.fill_bytes(&mut data);
+        let (plain_read, plain_write) = pipe();
+        let (cipher_read, cipher_write) = pipe();
+
+        // Feed the plaintext pipe in small writes, then close it to signal EOF
+        let feed = data.clone();
+        let feeder = std::thread::spawn(move || {
+            let mut pipe = unsafe { File::from_raw_fd(plain_write) };
+            for piece in feed.chunks(7000) {
+                pipe.write_all(piece).unwrap();
+            }
+        });
+        let encryptor = std::thread::spawn(move || {
+            let mut seen = (0usize, 0usize);
+            let result = encrypt_stream_fd(plain_read, cipher_write, KEY.as_ptr(), KEY_SIZE, 256 * 1024,
+                                           Some(record_stream_progress), ptr::null(),
+                                           &mut seen as *mut (usize, usize) as *mut c_void);
+            unsafe {
+                libc::close(plain_read);
+                libc::close(cipher_write);
+            }
+            (result, seen)
+        });
+
+        let dir = temp_dir("stream_fd");
+        let output_path = dir.join("plain.bin");
+        let output_fd = File::create(&output_path).unwrap().into_raw_fd();
+        let mut seen = (0usize, 0usize);
+        let result = decrypt_stream_fd(cipher_read, output_fd, KEY.as_ptr(), KEY_SIZE, Some(record_stream_progress),
+                                       ptr::null(), &mut seen as *mut (usize, usize) as *mut c_void);
+        unsafe {
+            libc::close(cipher_read);
+            libc::close(output_fd);
+        }
+
+        feeder.join().unwrap();
+        let (encrypt_result, encrypt_seen) = encryptor.join().unwrap();
+        assert_eq!(encrypt_result, SUCCESS);
+        assert_eq!(result, SUCCESS);
+        // Progress counts bytes with an unknown total
+        assert_eq!(encrypt_seen, (data.len(), 0));
+        assert_eq!(seen, (data.len(), 0));
+
+        let output = fs::read(&output_path).unwrap();
+        assert_eq!(blake3::hash(&output), blake3::hash(&data));
+
+        // A container cut short by the writer is reported, not silently accepted
+        let (cipher_read, cipher_write) = pipe();
+        let container = {
+            let mut len = 0usize;
+            let output = crate::encrypt_file_streaming(data.as_ptr(), 300_000, KEY.as_ptr(), KEY_SIZE, &mut len,
+                                                       None, ptr::null_mut());
+            let container = unsafe { slice::from_raw_parts(output, len) }.to_vec();
+            free_buffer(output);
+            container
+        };
+        let writer = std::thread::spawn(move || {
+            let mut pipe = unsafe { File::from_raw_fd(cipher_write) };
+            pipe.write_all(&container[..container.len() - 10]).unwrap();
+        });
+        let sink = File::create(dir.join("truncated.bin")).unwrap().into_raw_fd();
+        assert_eq!(decrypt_stream_fd(cipher_read, sink, KEY.as_ptr(), KEY_SIZE, None, ptr::null(), ptr::null_mut()),
+                   ERROR_CONTAINER_TRUNCATED);
+        writer.join().unwrap();
+        unsafe {
+            libc::close(cipher_read);
+            libc::close(sink);
+        }
+
+        assert_eq!(encrypt_stream_fd(-1, 1, KEY.as_ptr(), KEY_SIZE, 0, None, ptr::null(), ptr::null_mut()),
+                   ERROR_INVALID_PATH);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,10 +1,10 @@
 /// Encryption operations for CloudNexus
 /// AES-256-GCM encryption with streaming support
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit},
     Aes256Gcm, Nonce,
 };
-use rand::RngCore;
+use crate::rng::fill_random;
 use std::ffi::c_void;
 use std::slice;
 use std::ptr;
@@ -46,7 +46,7 @@ pub struct DecryptionContext {
 pub fn wrap_key(key: &[u8], master_key: &[u8]) -> Vec<u8> {
     let cipher = Aes256Gcm::new_from_slice(master_key).unwrap();
     let mut nonce_bytes = [0u8; NONCE_SIZE];
-    OsRng.fill_bytes(&mut nonce_bytes);
+    fill_random(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
 
     match cipher.encrypt(nonce, key.as_ref()) {
@@ -108,7 +108,7 @@ pub fn parse_header(header: &[u8]) -> Result<(u32, u8, usize), ()> {
 pub fn encrypt_chunk_impl(data: &[u8], fek: &[u8], chunk_index: u32) -> Option<Vec<u8>> {
     // Generate nonce for this chunk
     let mut nonce_bytes = [0u8; NONCE_SIZE];
-    OsRng.fill_bytes(&mut nonce_bytes);
+    fill_random(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
 
     // Encrypt chunk
@@ -0,0 +1,314 @@
+// Error codes for CloudNexus
+// Every status code returned across the C ABI is declared once in this
+// registry, with its name and whether retrying the call can succeed. The
+// modules re-export the codes they return, so existing paths such as
+// crate::copy::ERROR_DEST_IS_FILE keep working. Values are never reused.
+// Error code revision 2 renumbered the encryption codes, which used to share
+// -2 to -6 with the file codes; ERROR_ALLOCATION_FAILED (-6) became
+// ERROR_BUFFER_ALLOC_FAILED.
+
+use std::ffi::c_char;
+use std::ptr;
+
+use crate::ffi_util::ffi_string_out;
+
+/// Revision of the error code numbering, raised whenever an existing value changes
+pub const ERROR_CODES_REVISION: u32 = 2;
+
+/// A registered status code
+pub struct ErrorCodeInfo {
+    pub code: i32,
+    pub name: &'static str,
+    /// Retrying the same call can succeed (transient I/O, a source that was
+    /// still being written, a stalled destination, ...)
+    pub retryable: bool,
+}
+
+/// Declare the status codes and the registry listing them
+macro_rules! error_codes {
+    ($($(#[doc = $doc:expr])* $name:ident = $code:expr, $retryable:expr;)*) => {
+        $(
+            $(#[doc = $doc])*
+            pub const $name: i32 = $code;
+        )*
+
+        /// Every status code, by decreasing value
+        pub const ERROR_CODES: &[ErrorCodeInfo] = &[
+            $(ErrorCodeInfo { code: $name, name: stringify!($name), retryable: $retryable }),*
+        ];
+    };
+}
+
+error_codes! {
+    /// The call succeeded
+    SUCCESS = 0, false;
+    /// A required pointer argument is null
+    ERROR_NULL_POINTER = -1, false;
+    /// The file or folder does not exist
+    ERROR_FILE_NOT_FOUND = -2, false;
+    /// The operating system denied access
+    ERROR_PERMISSION_DENIED = -3, false;
+    /// The destination volume is out of space
+    ERROR_DISK_FULL = -4, false;
+    /// A path argument is not valid UTF-8 or cannot be used, or a setting was
+    /// changed after the operation started
+    ERROR_INVALID_PATH = -5, false;
+    /// Reading or writing failed
+    ERROR_IO_FAILED = -6, true;
+    /// The operation was cancelled through its cancel flag or cancel_operation
+    ERROR_CANCELLED = -7, false;
+    /// A buffer could not be allocated
+    ERROR_BUFFER_ALLOC_FAILED = -8, true;
+    /// The file is not an image format the decoder supports
+    ERROR_UNSUPPORTED_IMAGE_FORMAT = -11, false;
+    /// The image could not be decoded or re-encoded
+    ERROR_IMAGE_PROCESSING_FAILED = -12, false;
+    /// Encrypting or decrypting the thumbnail failed
+    ERROR_THUMBNAIL_CRYPTO_FAILED = -13, false;
+    /// The archive contains an entry that would be written outside the destination folder
+    ERROR_UNSAFE_ARCHIVE_ENTRY = -14, false;
+    /// The archive is not a valid ZIP file
+    ERROR_INVALID_ARCHIVE = -15, false;
+    /// The master key is invalid or encrypting the archive stream failed
+    ERROR_ARCHIVE_ENCRYPTION_FAILED = -16, false;
+    /// No trash directory has been configured with set_trash_directory
+    ERROR_TRASH_NOT_CONFIGURED = -17, false;
+    /// Restoring would replace a directory that now exists at the original location
+    ERROR_RESTORE_CONFLICT = -18, false;
+    /// The password does not unlock the vault
+    ERROR_VAULT_WRONG_PASSWORD = -19, false;
+    /// Not a vault file, unsupported version, corrupted, or invalid KDF parameters
+    ERROR_INVALID_VAULT = -20, false;
+    /// vault_create refuses to replace an existing vault
+    ERROR_VAULT_EXISTS = -21, false;
+    /// A chunk's ciphertext does not match its stored CRC32C
+    ERROR_CHUNK_CRC_MISMATCH = -22, true;
+    /// The master key is invalid or the container could not be encrypted
+    ERROR_ENCRYPT_COPY_FAILED = -23, false;
+    /// The written container did not decrypt back to the source plaintext
+    ERROR_ENCRYPT_VERIFY_FAILED = -24, true;
+    /// The container ends in the middle of its header or of a chunk
+    ERROR_CONTAINER_TRUNCATED = -25, true;
+    /// The container was written by a newer format version
+    ERROR_UNSUPPORTED_VERSION = -26, false;
+    /// No active operation has the given id
+    ERROR_OPERATION_NOT_FOUND = -27, false;
+    /// The stream ended inside the container header or an encrypted chunk
+    ERROR_NEED_MORE_DATA = -28, true;
+    /// No pending job has the given id
+    ERROR_TRANSFER_JOB_NOT_FOUND = -29, false;
+    /// A file is in the way where folder copy needs a destination directory
+    ERROR_DEST_IS_FILE = -30, false;
+    /// The destination folder has entries and an empty one was required
+    ERROR_DEST_NOT_EMPTY = -31, false;
+    /// Unknown digest mode
+    ERROR_INVALID_DIGEST_MODE = -32, false;
+    /// A JSON argument could not be parsed
+    ERROR_INVALID_JSON = -33, false;
+    /// The requested result is not available (not finalized yet, or spilled to disk)
+    ERROR_RESULT_UNAVAILABLE = -34, false;
+    /// The requested item does not exist
+    ERROR_NOT_FOUND = -35, false;
+    /// Unknown metadata strip policy
+    ERROR_INVALID_STRIP_POLICY = -36, false;
+    /// The source file grew or shrank while it was being read
+    ERROR_SOURCE_CHANGED = -37, true;
+    /// The destination accepted no bytes MAX_STALLED_WRITES times in a row
+    ERROR_WRITE_STALLED = -38, true;
+    /// The input is not valid base64 (bad character, padding or length)
+    ERROR_INVALID_BASE64 = -39, false;
+    /// The input is not valid hex (bad character or odd length)
+    ERROR_INVALID_HEX = -40, false;
+    /// The output buffer is too small for the encoded data
+    ERROR_OUTPUT_TOO_SMALL = -41, false;
+    /// The runtime pool is already running and can no longer be configured
+    ERROR_RUNTIME_STARTED = -42, false;
+    /// The header, or a single chunk record, is larger than the part size limit
+    ERROR_PART_LIMIT_TOO_SMALL = -43, false;
+    /// The manifest's parts are out of order, not contiguous, or do not match the part files
+    ERROR_MANIFEST_INVALID = -44, false;
+    /// A header or chunk field is out of range or a chunk is out of order
+    ERROR_MALFORMED_CONTAINER = -45, false;
+    /// The destination read back after a verified copy does not match the source
+    ERROR_COPY_VERIFY_FAILED = -46, true;
+    /// A `_cb` streaming sink returned non-zero
+    ERROR_SINK_ABORTED = -47, false;
+    /// A chunk's embedded index is not the one the decryption context expects next
+    ERROR_CHUNK_OUT_OF_ORDER = -48, false;
+    /// A folder tree goes deeper than the max_depth set for a copy or strict scan
+    ERROR_MAX_DEPTH_EXCEEDED = -49, false;
+    /// Encryption was requested but the master key is missing or not 32 bytes long
+    ERROR_MASTER_KEY_REQUIRED = -50, false;
+    /// An encryption_mode argument is not one of the ENCRYPTION_MODE_* values
+    ERROR_INVALID_ENCRYPTION_MODE = -51, false;
+    /// Unknown sort locale
+    ERROR_INVALID_SORT_LOCALE = -52, false;
+    /// An encrypted chunk failed authentication; the partial output was quarantined
+    /// or deleted and the chunk index is available from download_get_failed_chunk_index
+    ERROR_DECRYPTION_FAILED_AT_CHUNK = -53, false;
+    /// A search_multi batch holds more than MAX_MULTI_SEARCH_QUERIES queries
+    ERROR_TOO_MANY_QUERIES = -54, false;
+    /// The encoded metadata is larger than MAX_METADATA_SIZE
+    ERROR_METADATA_TOO_LARGE = -55, false;
+    /// A key argument has the wrong length (-2 before error code revision 2)
+    ERROR_INVALID_KEY_SIZE = -56, false;
+    /// Encrypting or wrapping a key failed (-3 before error code revision 2)
+    ERROR_ENCRYPTION_FAILED = -57, false;
+    /// Data failed authentication: wrong key or tampered ciphertext (-4 before
+    /// error code revision 2)
+    ERROR_DECRYPTION_FAILED = -58, false;
+    /// The data is not an encrypted container or blob (-5 before error code revision 2)
+    ERROR_INVALID_FORMAT = -59, false;
+    /// The file is larger than the destination filesystem can hold (4 GiB on FAT)
+    ERROR_FILE_TOO_LARGE_FOR_FS = -60, false;
+    /// The destination filesystem does not accept a character or the form of a
+    /// name; get_last_error_json names it
+    ERROR_NAME_NOT_ALLOWED_ON_FS = -61, false;
+    /// A scoring profile is unknown, malformed, or has a weight outside [0, 10]
+    ERROR_INVALID_SCORING_PROFILE = -62, false;
+    /// The process ran out of file descriptors, or no open file permit freed
+    /// up in time (native_runtime_configure_ex)
+    ERROR_TOO_MANY_OPEN_FILES = -63, true;
+    /// The destination of a copy or move is inside the source folder (also
+    /// through a symlink, junction or bind mount); get_last_error_json names both
+    ERROR_DEST_INSIDE_SOURCE = -64, false;
+    /// The source and destination of a copy or move are the same item
+    ERROR_SAME_PATH = -65, false;
+    /// An extended attribute policy code is not one of the XATTR_POLICY_* values
+    ERROR_INVALID_XATTR_POLICY = -66, false;
+    /// A chunk_size argument is outside MIN_UPLOAD_CHUNK_SIZE..=MAX_UPLOAD_CHUNK_SIZE
+    ERROR_INVALID_CHUNK_SIZE = -67, false;
+    /// The `struct_size` of an options struct is too small to hold the field itself
+    ERROR_INVALID_OPTIONS = -68, false;
+    /// A call that is only valid at one stage of an operation came too late,
+    /// e.g. setting container metadata after a chunk was encrypted
+    ERROR_INVALID_STATE = -69, false;
+}
+
+/// Registry entry of a status code
+pub fn error_code_info(code: i32) -> Option<&'static ErrorCodeInfo> {
+    ERROR_CODES.iter().find(|info| info.code == code)
+}
+
+/// Pass a status code through, asserting in debug builds that it is registered
+///
+/// Exported functions returning a status wrap every code they did not name
+/// themselves (one passed up from a helper or another call) in this, so the
+/// tests fail on any code missing from the registry. Positive values are
+/// counts or flags, not status codes, and are not checked.
+pub(crate) fn checked(code: i32) -> i32 {
+    debug_assert!(code > 0 || error_code_info(code).is_some(), "unregistered error code {}", code);
+    code
+}
+
+/// Get the name of a status code
+///
+/// # Arguments
+/// * `code` - Status code returned by any function of the library
+///
+/// # Returns
+/// Name such as "ERROR_IO_FAILED" (caller must free with free_c_string), or
+/// null if the code is not registered
+#[no_mangle]
+pub extern "C" fn error_code_name(code: i32) -> *mut c_char {
+    match error_code_info(code) {
+        Some(info) => ffi_string_out(info.name),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Check whether retrying a call that failed with a status code can succeed
+///
+/// # Arguments
+/// * `code` - Status code returned by any function of the library
+///
+/// # Returns
+/// 1 if the failure may be transient, 0 otherwise (including unregistered codes)
+#[no_mangle]
+pub extern "C" fn error_code_is_retryable(code: i32) -> i32 {
+    error_code_info(code).is_some_and(|info| info.retryable) as i32
+}
+
+/// Get the revision of the error code numbering
+///
+/// Bindings built against an older revision must update their code mapping.
+///
+/// # Returns
+/// ERROR_CODES_REVISION
+#[no_mangle]
+pub extern "C" fn error_codes_revision() -> u32 {
+    ERROR_CODES_REVISION
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::ffi::CString;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_error_codes_are_unique() {
+        let mut codes = HashSet::new();
+        let mut names = HashSet::new();
+        for info in ERROR_CODES {
+            assert!(codes.insert(info.code), "duplicate error code {}", info.code);
+            assert!(names.insert(info.name), "duplicate error name {}", info.name);
+            assert!(info.code <= 0, "{} is positive", info.name);
+        }
+        assert!(ERROR_CODES.windows(2).all(|pair| pair[0].code > pair[1].code));
+        // The metrics error table has one slot per code
+        assert!(ERROR_CODES.iter().all(|info| info.code > -96));
+    }
+
+    #[test]
+    fn test_error_code_lookup() {
+        let name = error_code_name(ERROR_IO_FAILED);
+        assert_eq!(unsafe { CString::from_raw(name) }.to_str().unwrap(), "ERROR_IO_FAILED");
+        let name = error_code_name(ERROR_DECRYPTION_FAILED);
+        assert_eq!(unsafe { CString::from_raw(name) }.to_str().unwrap(), "ERROR_DECRYPTION_FAILED");
+        assert!(error_code_name(-9).is_null());
+        assert!(error_code_name(1).is_null());
+
+        assert_eq!(error_code_is_retryable(ERROR_IO_FAILED), 1);
+        assert_eq!(error_code_is_retryable(ERROR_WRITE_STALLED), 1);
+        assert_eq!(error_code_is_retryable(ERROR_CANCELLED), 0);
+        assert_eq!(error_code_is_retryable(ERROR_VAULT_WRONG_PASSWORD), 0);
+        assert_eq!(error_code_is_retryable(-1000), 0);
+        assert_eq!(error_codes_revision(), ERROR_CODES_REVISION);
+
+        // The module paths the codes used to be declared at still resolve
+        assert_eq!(crate::copy::ERROR_DEST_IS_FILE, ERROR_DEST_IS_FILE);
+        assert_eq!(crate::file_io::ERROR_IO_FAILED, ERROR_IO_FAILED);
+        assert_eq!(checked(ERROR_SOURCE_CHANGED), ERROR_SOURCE_CHANGED);
+    }
+
+    /// Collect the lines of the crate sources that declare a status code
+    fn code_declarations(dir: &Path, found: &mut Vec<String>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                code_declarations(&path, found);
+            } else if path.extension().is_some_and(|ext| ext == "rs") && !path.ends_with("errors.rs") {
+                let source = fs::read_to_string(&path).unwrap();
+                for line in source.lines() {
+                    let line = line.trim_start();
+                    let declaration = line.split("const ").nth(1).unwrap_or("");
+                    if (declaration.starts_with("ERROR_") || declaration.starts_with("SUCCESS:"))
+                        && (declaration.contains(": i32") || declaration.contains(": c_int"))
+                    {
+                        found.push(format!("{}: {}", path.display(), line));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_codes_declared_only_here() {
+        let mut found = Vec::new();
+        code_declarations(&Path::new(env!("CARGO_MANIFEST_DIR")).join("src"), &mut found);
+        assert!(found.is_empty(), "status codes declared outside errors.rs: {:#?}", found);
+    }
+}
@@ -0,0 +1,388 @@
+/// FEK escrow for CloudNexus enterprise recovery
+///
+/// A file's FEK can additionally be wrapped to an organisation's X25519 recovery
+/// public key, so the file stays decryptable if the user loses their master key.
+///
+/// Escrowed containers set HEADER_FLAG_EXTENSIONS, carry FORMAT_VERSION_EXTENSIONS
+/// and append extension sections after the master-key wrapped FEK, inside the
+/// FEK region:
+/// - section type (1 byte)
+/// - section version (1 byte)
+/// - payload length (4 bytes, little-endian)
+/// - payload
+///
+/// Readers skip sections whose type or version they don't understand. The escrow
+/// payload (version 1) is ECIES-style:
+/// - ephemeral X25519 public key (32 bytes)
+/// - nonce (12 bytes)
+/// - AES-256-GCM(FEK) + MAC (48 bytes), keyed by HKDF-SHA256 of the shared secret
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use hkdf::Hkdf;
+use crate::rng::fill_random;
+use sha2::Sha256;
+use std::ptr;
+use std::slice;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::file_io::{ERROR_NULL_POINTER, SUCCESS};
+use crate::{format_version_supported, parse_header, DecryptionContext, HEADER_FLAGS_OFFSET, HEADER_FLAG_EXTENSIONS,
+            HEADER_SIZE, KEY_SIZE, MAC_SIZE, MAGIC, NONCE_SIZE, WRAPPED_FEK_SIZE};
+
+/// Size of X25519 private keys, public keys and shared secrets
+pub const X25519_KEY_SIZE: usize = 32;
+
+/// Extension section type holding an escrowed FEK
+pub const SECTION_TYPE_ESCROW: u8 = 1;
+
+/// Current escrow section version
+pub const ESCROW_SECTION_VERSION: u8 = 1;
+
+/// Extension section header: type (1) + version (1) + length (4)
+pub(crate) const SECTION_HEADER_SIZE: usize = 1 + 1 + 4;
+
+/// Escrow payload: ephemeral public key + nonce + encrypted FEK + MAC
+const ESCROW_PAYLOAD_SIZE: usize = X25519_KEY_SIZE + NONCE_SIZE + KEY_SIZE + MAC_SIZE;
+
+/// HKDF info string binding derived keys to this construction
+const ESCROW_KDF_INFO: &[u8] = b"CloudNexus FEK escrow v1";
+
+/// Extension section parsed from a FEK region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderSection<'a> {
+    pub section_type: u8,
+    pub version: u8,
+    pub payload: &'a [u8],
+}
+
+/// Split the extension sections that follow the wrapped FEK
+///
+/// Returns Err if a section header or length runs past the end of the region.
+pub(crate) fn parse_extension_sections(fek_region: &[u8]) -> Result<Vec<HeaderSection<'_>>, ()> {
+    let mut sections = Vec::new();
+    let mut rest = fek_region.get(WRAPPED_FEK_SIZE..).unwrap_or(&[]);
+
+    while !rest.is_empty() {
+        if rest.len() < SECTION_HEADER_SIZE {
+            return Err(());
+        }
+        let length = u32::from_le_bytes([rest[2], rest[3], rest[4], rest[5]]) as usize;
+        let payload = rest[SECTION_HEADER_SIZE..].get(..length).ok_or(())?;
+        sections.push(HeaderSection { section_type: rest[0], version: rest[1], payload });
+        rest = &rest[SECTION_HEADER_SIZE + length..];
+    }
+
+    Ok(sections)
+}
+
+/// Key wrapping the FEK for one (ephemeral, recipient) pair, or None for a
+/// low-order point
+///
+/// HKDF-SHA256 of the X25519 shared secret, salted with both public keys.
+fn escrow_key(secret: &StaticSecret, peer_public: &PublicKey, ephemeral_public: &[u8], recipient_public: &[u8])
+    -> Option<Zeroizing<[u8; KEY_SIZE]>> {
+    let shared = secret.diffie_hellman(peer_public);
+    if !shared.was_contributory() {
+        return None;
+    }
+    let mut salt = Vec::with_capacity(2 * X25519_KEY_SIZE);
+    salt.extend_from_slice(ephemeral_public);
+    salt.extend_from_slice(recipient_public);
+
+    let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes()).expand(ESCROW_KDF_INFO, &mut key[..]).ok()?;
+    Some(key)
+}
+
+/// Build an escrow extension section wrapping `fek` to a recovery public key
+pub(crate) fn build_escrow_section(fek: &[u8; KEY_SIZE], recovery_public_key: &[u8; X25519_KEY_SIZE]) -> Option<Vec<u8>> {
+    let mut ephemeral_bytes = Zeroizing::new([0u8; X25519_KEY_SIZE]);
+    fill_random(&mut ephemeral_bytes[..]);
+    let ephemeral_private = StaticSecret::from(*ephemeral_bytes);
+    let ephemeral_public = PublicKey::from(&ephemeral_private).to_bytes();
+    let recipient = PublicKey::from(*recovery_public_key);
+    let key = escrow_key(&ephemeral_private, &recipient, &ephemeral_public, recovery_public_key)?;
+
+    let mut nonce_bytes = [0u8; NONCE_SIZE];
+    fill_random(&mut nonce_bytes);
+    let cipher = Aes256Gcm::new_from_slice(&key[..]).ok()?;
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: fek, aad: &ephemeral_public })
+        .ok()?;
+
+    let mut section = Vec::with_capacity(SECTION_HEADER_SIZE + ESCROW_PAYLOAD_SIZE);
+    section.push(SECTION_TYPE_ESCROW);
+    section.push(ESCROW_SECTION_VERSION);
+    section.extend_from_slice(&(ESCROW_PAYLOAD_SIZE as u32).to_le_bytes());
+    section.extend_from_slice(&ephemeral_public);
+    section.extend_from_slice(&nonce_bytes);
+    section.extend_from_slice(&ciphertext);
+    Some(section)
+}
+
+/// Recover the FEK from an escrow payload with the recovery private key
+fn open_escrow_payload(payload: &[u8], recovery_private_key: &[u8; X25519_KEY_SIZE]) -> Result<Zeroizing<Vec<u8>>, ()> {
+    if payload.len() != ESCROW_PAYLOAD_SIZE {
+        return Err(());
+    }
+    let ephemeral_public: [u8; X25519_KEY_SIZE] = payload[..X25519_KEY_SIZE].try_into().map_err(|_| ())?;
+    let nonce = &payload[X25519_KEY_SIZE..X25519_KEY_SIZE + NONCE_SIZE];
+    let ciphertext = &payload[X25519_KEY_SIZE + NONCE_SIZE..];
+
+    let recovery_private = StaticSecret::from(*recovery_private_key);
+    let recipient_public = PublicKey::from(&recovery_private).to_bytes();
+    let key = escrow_key(&recovery_private, &PublicKey::from(ephemeral_public), &ephemeral_public, &recipient_public)
+        .ok_or(())?;
+
+    let cipher = Aes256Gcm::new_from_slice(&key[..]).map_err(|_| ())?;
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &ephemeral_public })
+        .map(Zeroizing::new)
+        .map_err(|_| ())
+}
+
+/// Generate an X25519 recovery key pair
+///
+/// # Arguments
+/// * `public_key_out` - Buffer of at least 32 bytes receiving the public key
+/// * `private_key_out` - Buffer of at least 32 bytes receiving the private key
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn recovery_key_generate(public_key_out: *mut u8, private_key_out: *mut u8) -> i32 {
+    if public_key_out.is_null() || private_key_out.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let mut private_bytes = Zeroizing::new([0u8; X25519_KEY_SIZE]);
+    fill_random(&mut private_bytes[..]);
+    let private_key = StaticSecret::from(*private_bytes);
+    let public_key = PublicKey::from(&private_key);
+
+    unsafe {
+        ptr::copy_nonoverlapping(public_key.as_bytes().as_ptr(), public_key_out, X25519_KEY_SIZE);
+        ptr::copy_nonoverlapping(private_key.as_bytes().as_ptr(), private_key_out, X25519_KEY_SIZE);
+    }
+    SUCCESS
+}
+
+/// Initialize decryption context through the FEK escrow instead of the master key
+///
+/// # Arguments
+/// * `encrypted_data` - Pointer to encrypted file data (must include header and FEK region)
+/// * `encrypted_len` - Length of encrypted data
+/// * `recovery_private_key` - Pointer to the 32-byte X25519 recovery private key
+/// * `key_len` - Length of the recovery private key (must be 32)
+///
+/// # Returns
+/// Pointer to DecryptionContext (free with decrypt_file_finalize), or null if the
+/// container has no escrow section or the key does not open it
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn decrypt_file_init_with_recovery(
+    encrypted_data: *const u8,
+    encrypted_len: usize,
+    recovery_private_key: *const u8,
+    key_len: usize,
+) -> *mut DecryptionContext {
+    if encrypted_data.is_null() || recovery_private_key.is_null() {
+        return ptr::null_mut();
+    }
+
+    if key_len != X25519_KEY_SIZE || encrypted_len < HEADER_SIZE {
+        return ptr::null_mut();
+    }
+
+    let encrypted_slice = unsafe { slice::from_raw_parts(encrypted_data, encrypted_len) };
+    let mut private_key = Zeroizing::new([0u8; X25519_KEY_SIZE]);
+    private_key.copy_from_slice(unsafe { slice::from_raw_parts(recovery_private_key, key_len) });
+
+    let (magic, version, fek_length) = match parse_header(&encrypted_slice[..HEADER_SIZE]) {
+        Ok(result) => result,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    if magic != MAGIC || !format_version_supported(version) {
+        return ptr::null_mut();
+    }
+
+    if encrypted_slice[HEADER_FLAGS_OFFSET] & HEADER_FLAG_EXTENSIONS == 0 || encrypted_len < HEADER_SIZE + fek_length {
+        return ptr::null_mut();
+    }
+
+    let sections = match parse_extension_sections(&encrypted_slice[HEADER_SIZE..HEADER_SIZE + fek_length]) {
+        Ok(sections) => sections,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    let fek = sections
+        .iter()
+        .filter(|s| s.section_type == SECTION_TYPE_ESCROW && s.version == ESCROW_SECTION_VERSION)
+        .find_map(|s| open_escrow_payload(s.payload, &private_key).ok());
+
+    // Move the FEK into the context rather than copying it out of the Zeroizing buffer
+    match fek {
+        Some(mut fek) => {
+            let fek = std::mem::take(&mut *fek);
+            Box::leak(Box::new(DecryptionContext { fek, chunk_index: 0 })) as *mut DecryptionContext
+        }
+        None => ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decrypt_chunk, decrypt_file_finalize, decrypt_file_init, encrypt_chunk, encrypt_file_finalize,
+                encrypt_file_get_wrapped_fek, encrypt_file_init, encrypt_file_init_ex, free_buffer};
+
+    /// Encrypt `content` as a single-chunk container
+    fn encrypt_container(content: &[u8], master_key: &[u8; KEY_SIZE], recovery_public: Option<&[u8; X25519_KEY_SIZE]>) -> Vec<u8> {
+        let mut header_len = 0usize;
+        let ctx = match recovery_public {
+            Some(public) => encrypt_file_init_ex(master_key.as_ptr(), KEY_SIZE, public.as_ptr(), X25519_KEY_SIZE, &mut header_len),
+            None => encrypt_file_init(master_key.as_ptr(), KEY_SIZE, &mut header_len),
+        };
+        assert!(!ctx.is_null());
+
+        let mut container = unsafe { (*ctx).header }.to_vec();
+        let mut fek_len = 0usize;
+        let fek = encrypt_file_get_wrapped_fek(ctx, &mut fek_len);
+        container.extend_from_slice(unsafe { slice::from_raw_parts(fek, fek_len) });
+        free_buffer(fek);
+        assert_eq!(container.len(), header_len);
+
+        let mut chunk_len = 0usize;
+        let chunk = encrypt_chunk(ctx, content.as_ptr(), content.len(), 0, &mut chunk_len);
+        container.extend_from_slice(unsafe { slice::from_raw_parts(chunk, chunk_len) });
+        free_buffer(chunk);
+        encrypt_file_finalize(ctx);
+        container
+    }
+
+    fn decrypt_first_chunk(ctx: *mut DecryptionContext, container: &[u8]) -> Vec<u8> {
+        assert!(!ctx.is_null());
+        let fek_length = u32::from_le_bytes(container[8..12].try_into().unwrap()) as usize;
+        let chunk = &container[HEADER_SIZE + fek_length..];
+        let mut out_len = 0usize;
+        let plain = decrypt_chunk(ctx, chunk.as_ptr(), chunk.len(), &mut out_len);
+        assert!(!plain.is_null());
+        let result = unsafe { slice::from_raw_parts(plain, out_len) }.to_vec();
+        free_buffer(plain);
+        decrypt_file_finalize(ctx);
+        result
+    }
+
+    #[test]
+    fn test_escrowed_file_opens_with_recovery_key_only() {
+        let mut public = [0u8; X25519_KEY_SIZE];
+        let mut private = [0u8; X25519_KEY_SIZE];
+        assert_eq!(recovery_key_generate(public.as_mut_ptr(), private.as_mut_ptr()), SUCCESS);
+
+        let master_key = [3u8; KEY_SIZE];
+        let content = b"quarterly numbers".to_vec();
+        let container = encrypt_container(&content, &master_key, Some(&public));
+        assert_ne!(container[HEADER_FLAGS_OFFSET] & HEADER_FLAG_EXTENSIONS, 0);
+        assert_eq!(container[4], crate::FORMAT_VERSION_EXTENSIONS);
+
+        // The recovery path needs nothing but the recovery private key
+        let ctx = decrypt_file_init_with_recovery(container.as_ptr(), container.len(), private.as_ptr(), X25519_KEY_SIZE);
+        assert_eq!(decrypt_first_chunk(ctx, &container), content);
+
+        // The master key still works and skips the escrow section
+        let ctx = decrypt_file_init(container.as_ptr(), container.len(), master_key.as_ptr(), KEY_SIZE);
+        assert_eq!(decrypt_first_chunk(ctx, &container), content);
+
+        // Another recovery key cannot open it
+        let mut other_public = [0u8; X25519_KEY_SIZE];
+        let mut other_private = [0u8; X25519_KEY_SIZE];
+        recovery_key_generate(other_public.as_mut_ptr(), other_private.as_mut_ptr());
+        let ctx = decrypt_file_init_with_recovery(container.as_ptr(), container.len(), other_private.as_ptr(), X25519_KEY_SIZE);
+        assert!(ctx.is_null());
+    }
+
+    #[test]
+    fn test_rfc7748_key_agreement() {
+        let hex32 = |s: &str| -> [u8; X25519_KEY_SIZE] {
+            let mut out = [0u8; X25519_KEY_SIZE];
+            for (i, byte) in out.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap();
+            }
+            out
+        };
+        let alice = StaticSecret::from(hex32("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a"));
+        let bob = StaticSecret::from(hex32("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb"));
+        let alice_public = PublicKey::from(&alice);
+        let bob_public = PublicKey::from(&bob);
+        assert_eq!(alice_public.to_bytes(), hex32("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"));
+        assert_eq!(bob_public.to_bytes(), hex32("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f"));
+        assert_eq!(alice.diffie_hellman(&bob_public).to_bytes(),
+                   hex32("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742"));
+
+        // A low-order recipient key yields no escrow key
+        assert!(build_escrow_section(&[1u8; KEY_SIZE], &[0u8; X25519_KEY_SIZE]).is_none());
+    }
+
+    #[test]
+    fn test_recovery_fails_cleanly_without_escrow() {
+        let mut public = [0u8; X25519_KEY_SIZE];
+        let mut private = [0u8; X25519_KEY_SIZE];
+        recovery_key_generate(public.as_mut_ptr(), private.as_mut_ptr());
+
+        let container = encrypt_container(b"no escrow here", &[9u8; KEY_SIZE], None);
+        assert_eq!(container[HEADER_FLAGS_OFFSET] & HEADER_FLAG_EXTENSIONS, 0);
+        let ctx = decrypt_file_init_with_recovery(container.as_ptr(), container.len(), private.as_ptr(), X25519_KEY_SIZE);
+        assert!(ctx.is_null());
+        assert!(decrypt_file_init_with_recovery(container.as_ptr(), 4, private.as_ptr(), X25519_KEY_SIZE).is_null());
+    }
+
+    #[test]
+    fn test_oversized_fek_region_needs_extension_flag() {
+        let master_key = [5u8; KEY_SIZE];
+        let content = b"strict fek region".to_vec();
+        let container = encrypt_container(&content, &master_key, None);
+
+        // Trailing bytes after the wrapped FEK, sized into the region but unflagged
+        let mut padded = container[..HEADER_SIZE + WRAPPED_FEK_SIZE].to_vec();
+        padded.extend_from_slice(&[0x7F, 1, 0, 0, 0, 0]);
+        padded[8..12].copy_from_slice(&((WRAPPED_FEK_SIZE + SECTION_HEADER_SIZE) as u32).to_le_bytes());
+        padded.extend_from_slice(&container[HEADER_SIZE + WRAPPED_FEK_SIZE..]);
+        assert!(decrypt_file_init(padded.as_ptr(), padded.len(), master_key.as_ptr(), KEY_SIZE).is_null());
+
+        // The same region is a valid extension section once flagged
+        padded[HEADER_FLAGS_OFFSET] |= HEADER_FLAG_EXTENSIONS;
+        let ctx = decrypt_file_init(padded.as_ptr(), padded.len(), master_key.as_ptr(), KEY_SIZE);
+        assert_eq!(decrypt_first_chunk(ctx, &padded), content);
+    }
+
+    #[test]
+    fn test_unknown_sections_are_skipped() {
+        let mut public = [0u8; X25519_KEY_SIZE];
+        let mut private = [0u8; X25519_KEY_SIZE];
+        recovery_key_generate(public.as_mut_ptr(), private.as_mut_ptr());
+
+        let fek = [7u8; KEY_SIZE];
+        let mut region = vec![0u8; WRAPPED_FEK_SIZE];
+        // A future section type and a future escrow version precede the real one
+        region.extend_from_slice(&[0x7F, 1, 3, 0, 0, 0, 1, 2, 3]);
+        region.extend_from_slice(&[SECTION_TYPE_ESCROW, 9, 0, 0, 0, 0]);
+        region.extend_from_slice(&build_escrow_section(&fek, &public).unwrap());
+
+        let sections = parse_extension_sections(&region).unwrap();
+        assert_eq!(sections.len(), 3);
+        let opened = sections
+            .iter()
+            .filter(|s| s.section_type == SECTION_TYPE_ESCROW && s.version == ESCROW_SECTION_VERSION)
+            .find_map(|s| open_escrow_payload(s.payload, &private).ok());
+        assert_eq!(opened.as_deref(), Some(&fek.to_vec()));
+
+        // A length running past the region is rejected
+        region.extend_from_slice(&[0x10, 1, 200, 0, 0, 0]);
+        assert!(parse_extension_sections(&region).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+/// Shared helpers for JSON-returning FFI functions
+///
+/// Every `*_json` function returns a JSON envelope, never null except when the
+/// string cannot be allocated:
+/// - success: `{"ok": true, "data": <payload>}`
+/// - failure: `{"ok": false, "error": {"code": <i32>, "message": "...", "context": "..."}, "data": null}`
+///
+/// `context` is optional and names the argument, path or error kind involved.
+/// A successful envelope of a call that stopped early (cancelled, or out of
+/// its time budget) carries `"truncated": true` next to its partial `data`.
+///
+/// Stable error codes used in envelopes (the same values the i32 FFI functions return):
+/// - -1 ERROR_NULL_POINTER: a required pointer argument was null
+/// - -2 ERROR_FILE_NOT_FOUND: a path or scan root does not exist
+/// - -5 ERROR_INVALID_PATH: a path or string argument is not valid UTF-8 or is unsafe
+/// - -6 ERROR_IO_FAILED: reading or serializing the result failed
+/// - -33 ERROR_INVALID_JSON: a JSON argument could not be parsed
+/// - -34 ERROR_RESULT_UNAVAILABLE: the result is not available (e.g. before finalize, or spilled to disk)
+/// - -35 ERROR_NOT_FOUND: the requested item does not exist
+///
+/// Functions that return plain codes or pointers read their string arguments
+/// with ffi_str_in / ffi_opt_str_in and return strings with ffi_string_out. A
+/// string argument that cannot be read is recorded as the thread's last error,
+/// which get_last_error_json reports with the argument name as `context`.
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::file_io::{ERROR_INVALID_PATH, ERROR_IO_FAILED, ERROR_NULL_POINTER};
+
+pub use crate::errors::{ERROR_INVALID_JSON, ERROR_RESULT_UNAVAILABLE, ERROR_NOT_FOUND};
+
+/// Error carried by a failed JSON envelope
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorEnvelope {
+    /// Stable error code (see module documentation)
+    pub code: i32,
+    /// Human-readable message
+    pub message: String,
+    /// Argument, path or error kind involved, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+}
+
+impl ErrorEnvelope {
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self { code: crate::errors::checked(code), message: message.into(), context: None }
+    }
+
+    pub fn with_context(mut self, context: impl Into<String>) -> Self {
+        self.context = Some(context.into());
+        self
+    }
+
+    /// A required pointer argument was null
+    pub fn null_argument(name: &str) -> Self {
+        Self::new(ERROR_NULL_POINTER, format!("{} must not be null", name)).with_context(name)
+    }
+
+    /// A string argument was not valid UTF-8
+    pub fn invalid_string(name: &str) -> Self {
+        Self::new(ERROR_INVALID_PATH, format!("{} is not valid UTF-8", name)).with_context(name)
+    }
+
+    /// A JSON argument could not be parsed
+    pub fn invalid_json(name: &str, error: impl std::fmt::Display) -> Self {
+        Self::new(ERROR_INVALID_JSON, format!("{} is not valid JSON: {}", name, error)).with_context(name)
+    }
+}
+
+/// Why a C string argument could not be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FfiError {
+    /// A required argument was null
+    NullArgument(&'static str),
+    /// The argument is not valid UTF-8; the bytes before `valid_up_to` are
+    InvalidUtf8 { argument: &'static str, valid_up_to: usize },
+}
+
+impl FfiError {
+    /// Error code the i32 FFI functions use for this failure
+    pub fn code(&self) -> i32 {
+        match self {
+            FfiError::NullArgument(_) => ERROR_NULL_POINTER,
+            FfiError::InvalidUtf8 { .. } => ERROR_INVALID_PATH,
+        }
+    }
+
+    /// Name of the offending argument
+    pub fn argument(&self) -> &'static str {
+        match self {
+            FfiError::NullArgument(argument) | FfiError::InvalidUtf8 { argument, .. } => argument,
+        }
+    }
+}
+
+impl fmt::Display for FfiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FfiError::NullArgument(argument) => write!(f, "{} must not be null", argument),
+            FfiError::InvalidUtf8 { argument, valid_up_to } => {
+                write!(f, "{} is not valid UTF-8 (invalid byte at offset {})", argument, valid_up_to)
+            }
+        }
+    }
+}
+
+impl From<FfiError> for ErrorEnvelope {
+    fn from(error: FfiError) -> Self {
+        ErrorEnvelope::new(error.code(), error.to_string()).with_context(error.argument())
+    }
+}
+
+thread_local! {
+    /// Most recent argument conversion failure, or failure detail, on this thread
+    static LAST_ERROR: RefCell<Option<ErrorEnvelope>> = const { RefCell::new(None) };
+}
+
+/// Record `error` as the thread's last error, returning it
+pub fn set_last_error(error: FfiError) -> FfiError {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(error.clone().into()));
+    error
+}
+
+/// Record the detail of a failure an i32 code cannot carry (such as the
+/// offending character of a name) as the thread's last error, returning its code
+pub fn set_last_error_detail(error: ErrorEnvelope) -> i32 {
+    let code = error.code;
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(error));
+    code
+}
+
+/// Read a required C string argument, recording a failure as the last error
+///
+/// # Safety
+/// `value` must be null or point to a nul-terminated string that outlives the result.
+pub unsafe fn ffi_str_in<'a>(value: *const c_char, name: &'static str) -> Result<&'a str, FfiError> {
+    match ffi_opt_str_in(value, name)? {
+        Some(s) => Ok(s),
+        None => Err(set_last_error(FfiError::NullArgument(name))),
+    }
+}
+
+/// Read an optional C string argument (None for null), recording invalid UTF-8 as the last error
+///
+/// # Safety
+/// `value` must be null or point to a nul-terminated string that outlives the result.
+pub unsafe fn ffi_opt_str_in<'a>(value: *const c_char, name: &'static str) -> Result<Option<&'a str>, FfiError> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(value)
+        .to_str()
+        .map(Some)
+        .map_err(|e| set_last_error(FfiError::InvalidUtf8 { argument: name, valid_up_to: e.valid_up_to() }))
+}
+
+/// Convert a string for the caller, replacing NUL bytes with U+FFFD
+pub fn ffi_cstring(s: &str) -> CString {
+    let s = if s.contains('\0') { s.replace('\0', "\u{FFFD}") } else { s.to_string() };
+    // Every NUL was replaced above
+    CString::new(s).unwrap_or_default()
+}
+
+/// Hand a string to the caller (free with free_c_string or scan_folder_free_string)
+///
+/// NUL bytes, which cannot be represented in a C string, become U+FFFD; this
+/// never fails or panics.
+pub fn ffi_string_out(s: &str) -> *mut c_char {
+    ffi_cstring(s).into_raw()
+}
+
+/// Get the last argument conversion error of the calling thread
+///
+/// Functions returning plain codes or pointers record why a string argument
+/// could not be read (null or invalid UTF-8) before failing, and some record
+/// details their code cannot carry, such as the character a destination
+/// filesystem rejected. Successful calls do not clear it.
+///
+/// # Arguments
+/// * `out_len` - Receives the JSON length in bytes (can be null)
+///
+/// # Returns
+/// A JSON envelope whose data is {code, message, context} with the argument
+/// name as context, or null if no error was recorded (caller must free with
+/// scan_folder_free_string)
+#[no_mangle]
+pub extern "C" fn get_last_error_json(out_len: *mut usize) -> *mut c_char {
+    let error = LAST_ERROR.with(|last| last.borrow().clone());
+    json_envelope(Ok(error), out_len)
+}
+
+/// Forget the last argument conversion error of the calling thread
+#[no_mangle]
+pub extern "C" fn clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+/// Envelope as seen by callers; used to parse envelopes in tests and host tooling
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonEnvelope<T> {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorEnvelope>,
+    pub data: Option<T>,
+    /// The data is partial; omitted when false
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+/// Read a required C string argument
+///
+/// # Safety
+/// `value` must be null or point to a nul-terminated string.
+pub unsafe fn envelope_str<'a>(value: *const c_char, name: &str) -> Result<&'a str, ErrorEnvelope> {
+    if value.is_null() {
+        return Err(ErrorEnvelope::null_argument(name));
+    }
+    CStr::from_ptr(value).to_str().map_err(|_| ErrorEnvelope::invalid_string(name))
+}
+
+/// Serialize a result into a JSON envelope C string
+///
+/// `out_len` (can be null) receives the JSON length in bytes. Returns null only if
+/// the string cannot be allocated.
+pub fn json_envelope<T: Serialize>(result: Result<T, ErrorEnvelope>, out_len: *mut usize) -> *mut c_char {
+    json_envelope_partial(result, false, out_len)
+}
+
+/// JSON envelope like json_envelope, flagging successful `data` as `truncated`
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn json_envelope_partial<T: Serialize>(result: Result<T, ErrorEnvelope>, truncated: bool,
+                                           out_len: *mut usize) -> *mut c_char {
+    let json = match result {
+        Ok(data) => serde_json::to_string(&JsonEnvelope { ok: true, error: None, data: Some(data), truncated })
+            .unwrap_or_else(|e| error_json(ErrorEnvelope::new(ERROR_IO_FAILED, format!("failed to serialize result: {}", e)))),
+        Err(error) => error_json(error),
+    };
+
+    if !out_len.is_null() {
+        unsafe { *out_len = json.len(); }
+    }
+    // serde_json escapes control characters, so the JSON never contains a nul byte
+    ffi_string_out(&json)
+}
+
+/// Wrap already serialized JSON in an envelope without re-parsing it
+///
+/// For large payloads streamed from disk (e.g. spilled copy manifests).
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn json_envelope_raw(result: Result<String, ErrorEnvelope>, out_len: *mut usize) -> *mut c_char {
+    let json = match result {
+        Ok(data) => format!("{{\"ok\":true,\"data\":{}}}", data),
+        Err(error) => error_json(error),
+    };
+
+    if !out_len.is_null() {
+        unsafe { *out_len = json.len(); }
+    }
+    ffi_string_out(&json)
+}
+
+fn error_json(error: ErrorEnvelope) -> String {
+    let envelope: JsonEnvelope<()> = JsonEnvelope { ok: false, error: Some(error), data: None, truncated: false };
+    serde_json::to_string(&envelope).unwrap_or_else(|_| "{\"ok\":false,\"data\":null}".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    fn take(ptr: *mut c_char, len: usize) -> serde_json::Value {
+        assert!(!ptr.is_null());
+        let json = unsafe { CString::from_raw(ptr) }.into_string().unwrap();
+        assert_eq!(json.len(), len);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_envelope_shapes() {
+        let mut len = 0usize;
+        let ptr = json_envelope(Ok(vec![1, 2]), &mut len);
+        assert_eq!(unsafe { CStr::from_ptr(ptr) }.to_str().unwrap(), r#"{"ok":true,"data":[1,2]}"#);
+        let ok = take(ptr, len);
+        assert_eq!(ok, serde_json::json!({ "ok": true, "data": [1, 2] }));
+
+        let err = take(json_envelope::<()>(Err(ErrorEnvelope::null_argument("index_ptr")), &mut len), len);
+        assert_eq!(err["ok"], false);
+        assert_eq!(err["data"], serde_json::Value::Null);
+        assert_eq!(err["error"]["code"], ERROR_NULL_POINTER);
+        assert_eq!(err["error"]["context"], "index_ptr");
+
+        let parsed: JsonEnvelope<Vec<i32>> =
+            serde_json::from_str(r#"{"ok":false,"error":{"code":-33,"message":"bad"},"data":null}"#).unwrap();
+        assert_eq!(parsed.error, Some(ErrorEnvelope::new(ERROR_INVALID_JSON, "bad")));
+        assert!(parsed.data.is_none());
+
+        let raw = take(json_envelope_raw(Ok(r#"{"a":1}"#.to_string()), &mut len), len);
+        assert_eq!(raw, serde_json::json!({ "ok": true, "data": { "a": 1 } }));
+
+        // A null out_len is allowed
+        let ptr = json_envelope(Ok("x"), ptr::null_mut());
+        unsafe { drop(CString::from_raw(ptr)) };
+    }
+
+    #[test]
+    fn test_string_conversions_never_panic() {
+        clear_last_error();
+        let valid = CString::new("héllo").unwrap();
+        assert_eq!(unsafe { ffi_str_in(valid.as_ptr(), "name") }, Ok("héllo"));
+        assert_eq!(unsafe { ffi_opt_str_in(ptr::null(), "name") }, Ok(None));
+        let mut len = 0usize;
+        assert!(take(get_last_error_json(&mut len), len)["data"].is_null());
+
+        let invalid = CString::new(vec![b'a', b'b', 0xff, b'c']).unwrap();
+        let error = unsafe { ffi_str_in(invalid.as_ptr(), "name") }.unwrap_err();
+        assert_eq!(error, FfiError::InvalidUtf8 { argument: "name", valid_up_to: 2 });
+        let last = take(get_last_error_json(&mut len), len);
+        assert_eq!(last["data"]["code"], ERROR_INVALID_PATH);
+        assert_eq!(last["data"]["context"], "name");
+
+        assert_eq!(unsafe { ffi_str_in(ptr::null(), "query") }, Err(FfiError::NullArgument("query")));
+        let last = take(get_last_error_json(&mut len), len);
+        assert_eq!(last["data"]["code"], ERROR_NULL_POINTER);
+        assert_eq!(last["data"]["context"], "query");
+        clear_last_error();
+
+        let out = ffi_string_out("a\0b");
+        assert_eq!(unsafe { CString::from_raw(out) }.into_string().unwrap(), "a\u{FFFD}b");
+    }
+}
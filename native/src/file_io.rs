@@ -1,35 +1,48 @@
 /// File I/O operations for CloudNexus
 /// Handles upload, download, and copy operations with progress tracking and cancellation support
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Seek, SeekFrom, BufReader, BufWriter};
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Instant;
-use std::ffi::{c_char, c_void, CStr};
+use std::ffi::{c_char, c_int, CStr};
+#[cfg(windows)]
+use std::ffi::c_void;
 use std::ptr;
 
+use crate::errors::checked;
 use crate::encryption::{EncryptionContext, DecryptionContext};
 
 // Error codes
-pub const SUCCESS: i32 = 0;
-pub const ERROR_NULL_POINTER: i32 = -1;
-pub const ERROR_FILE_NOT_FOUND: i32 = -2;
-pub const ERROR_PERMISSION_DENIED: i32 = -3;
-pub const ERROR_DISK_FULL: i32 = -4;
-pub const ERROR_INVALID_PATH: i32 = -5;
-pub const ERROR_IO_FAILED: i32 = -6;
-pub const ERROR_CANCELLED: i32 = -7;
-pub const ERROR_BUFFER_ALLOC_FAILED: i32 = -8;
+pub use crate::errors::{
+    SUCCESS,
+    ERROR_NULL_POINTER,
+    ERROR_FILE_NOT_FOUND,
+    ERROR_PERMISSION_DENIED,
+    ERROR_DISK_FULL,
+    ERROR_INVALID_PATH,
+    ERROR_IO_FAILED,
+    ERROR_CANCELLED,
+    ERROR_BUFFER_ALLOC_FAILED,
+    ERROR_SOURCE_CHANGED,
+    ERROR_MAX_DEPTH_EXCEEDED,
+};
 
 const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024; // 1MB chunks
 const PROGRESS_UPDATE_INTERVAL_MS: u64 = 500; // 500ms = 2 updates/second
 
 /// Progress throttler to limit callback frequency
+///
+/// Intermediate updates fire when the configured policy allows it (time
+/// interval or percentage delta). The terminal update (bytes == total) always
+/// fires, but only once; `finish` delivers it for consumers whose last
+/// intermediate update did not reach the total.
 pub struct ProgressThrottler {
     last_update_time: Instant,
     update_interval_ms: u64,
+    percent_delta: Option<u32>,
     last_bytes_processed: usize,
-    last_bytes_transferred: usize,
+    final_reported: bool,
 }
 
 impl ProgressThrottler {
@@ -37,30 +50,68 @@ impl ProgressThrottler {
         Self {
             last_update_time: Instant::now(),
             update_interval_ms: interval_ms,
+            percent_delta: None,
             last_bytes_processed: 0,
-            last_bytes_transferred: 0,
+            final_reported: false,
         }
     }
-    
+
+    /// Throttle by progress instead of time: update whenever at least
+    /// `percent` of the total has been processed since the last update.
+    /// Falls back to the default interval while the total is unknown.
+    pub fn with_percent_delta(percent: u32) -> Self {
+        Self {
+            percent_delta: Some(percent.clamp(1, 100)),
+            ..Self::new(PROGRESS_UPDATE_INTERVAL_MS)
+        }
+    }
+
     /// Check if progress should be reported
-    /// Returns true if should update, and the bytes to report
-    pub fn should_update(&mut self, bytes_processed: usize, bytes_transferred: usize) -> bool {
-        let now = Instant::now();
-        let elapsed = now.duration_since(self.last_update_time).as_millis();
-        
-        // Update on interval OR if operation complete
-        let should_update = elapsed >= self.update_interval_ms as u128 ||
-                            bytes_processed == 0 || // Force update on completion
-                            self.last_bytes_processed != bytes_processed;
-        
+    pub fn should_update(&mut self, bytes_processed: usize, total_bytes: usize) -> bool {
+        self.should_update_at(Instant::now(), bytes_processed, total_bytes, false)
+    }
+
+    /// Check if progress should be reported, bypassing the policy when `force` is set
+    pub fn should_update_with(&mut self, bytes_processed: usize, total_bytes: usize, force: bool) -> bool {
+        self.should_update_at(Instant::now(), bytes_processed, total_bytes, force)
+    }
+
+    fn should_update_at(&mut self, now: Instant, bytes_processed: usize, total_bytes: usize, force: bool) -> bool {
+        let is_final = total_bytes > 0 && bytes_processed >= total_bytes;
+
+        let should_update = if is_final {
+            !self.final_reported
+        } else if force {
+            true
+        } else {
+            match self.percent_delta {
+                Some(percent) if total_bytes > 0 => {
+                    let delta = bytes_processed.saturating_sub(self.last_bytes_processed) as u128;
+                    delta * 100 >= percent as u128 * total_bytes as u128
+                }
+                _ => {
+                    let elapsed = now.duration_since(self.last_update_time).as_millis();
+                    elapsed >= self.update_interval_ms as u128
+                }
+            }
+        };
+
         if should_update {
             self.last_update_time = now;
             self.last_bytes_processed = bytes_processed;
-            self.last_bytes_transferred = bytes_transferred;
+            self.final_reported |= is_final;
         }
-        
+
         should_update
     }
+
+    /// Mark the operation complete
+    ///
+    /// Returns true if the terminal update has not been reported yet, i.e. the
+    /// caller must deliver the final callback now.
+    pub fn finish(&mut self) -> bool {
+        !std::mem::replace(&mut self.final_reported, true)
+    }
 }
 
 /// Upload context for streaming uploads
@@ -163,6 +214,140 @@ pub unsafe fn is_cancelled(cancel_flag: *const AtomicBool) -> bool {
     (*cancel_flag).load(Ordering::Relaxed)
 }
 
+/// Duplicate a caller-owned file descriptor into a File we own
+///
+/// Dropping the returned File closes only the duplicate; the caller keeps its
+/// descriptor open. Both share the file offset.
+#[cfg(unix)]
+pub fn file_from_fd(fd: c_int) -> io::Result<File> {
+    use std::os::fd::BorrowedFd;
+
+    if fd < 0 {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    Ok(File::from(borrowed.try_clone_to_owned()?))
+}
+
+/// Duplicate a caller-owned file HANDLE into a File we own
+///
+/// Dropping the returned File closes only the duplicate; the caller keeps its
+/// handle open.
+#[cfg(windows)]
+pub fn file_from_handle(handle: *mut c_void) -> io::Result<File> {
+    use std::os::windows::io::BorrowedHandle;
+
+    if handle.is_null() || handle as isize == -1 {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    let borrowed = unsafe { BorrowedHandle::borrow_raw(handle) };
+    Ok(File::from(borrowed.try_clone_to_owned()?))
+}
+
+/// Bytes available to this process on the volume holding `path`
+///
+/// `path` does not need to exist; its nearest existing ancestor is queried, so
+/// the space of a destination can be checked before it is created.
+pub fn available_space(path: &Path) -> Option<u64> {
+    let existing = path.ancestors().find(|p| !p.as_os_str().is_empty() && p.exists())
+        .unwrap_or(Path::new("."));
+    volume_available_space(existing)
+}
+
+#[cfg(unix)]
+fn volume_available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+fn volume_available_space(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceExW(directory: *const u16, free_to_caller: *mut u64,
+                               total: *mut u64, total_free: *mut u64) -> i32;
+    }
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+    let mut available = 0u64;
+    if unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, ptr::null_mut(), ptr::null_mut()) } == 0 {
+        return None;
+    }
+    Some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn volume_available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+// ============================================================================
+// PARTIAL OUTPUT CLEANUP
+// ============================================================================
+//
+// Every long-running operation takes a cancel flag, checks it at least once per
+// chunk and returns ERROR_CANCELLED. What happens to the output it was writing
+// is decided here:
+// - one-shot operations (file copy, archive create/extract) delete the file they
+//   were writing when they are cancelled or fail midway;
+// - context-based operations (download, chunked copy, folder copy) delete the
+//   partial file as soon as a chunk call observes cancellation;
+// - outputs that were completed before the cancellation are never touched;
+// - with keep_partial set, the partial file is left on disk so the caller can
+//   resume it (see download_get_partial_path and chunked_copy_seek_dest).
+
+/// Apply the partial output policy to `path`
+///
+/// Returns true if the file was deleted (or was already gone).
+pub fn cleanup_partial_output(path: &Path, keep_partial: bool) -> bool {
+    if keep_partial {
+        return false;
+    }
+    crate::temp::discard_temp_file(path).is_ok()
+}
+
+/// Deletes a partially written output on drop unless it was marked complete
+///
+/// Create the guard right after the output file is created, so every early
+/// return (cancellation or error) cleans up through cleanup_partial_output.
+pub struct PartialOutputGuard {
+    path: PathBuf,
+    keep_partial: bool,
+    completed: bool,
+}
+
+impl PartialOutputGuard {
+    pub fn new(path: &Path, keep_partial: bool) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            keep_partial,
+            completed: false,
+        }
+    }
+
+    /// The output is complete; keep it
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for PartialOutputGuard {
+    fn drop(&mut self) {
+        if !self.completed {
+            cleanup_partial_output(&self.path, self.keep_partial);
+        }
+    }
+}
+
 /// Convert string path to native char pointer
 pub unsafe fn string_to_c_char(s: &str) -> *mut c_char {
     // Allocate with null terminator
@@ -180,4 +365,948 @@ pub unsafe fn string_to_c_char(s: &str) -> *mut c_char {
     ptr.add(len).write(0);
     
     ptr as *mut c_char
-}
\ No newline at end of file
+}
+// ============================================================================
+// PATH SANITIZATION
+// ============================================================================
+
+/// Maximum length in bytes of a single path component on common filesystems
+pub const DEFAULT_MAX_COMPONENT_LENGTH: usize = 255;
+
+/// Device names Windows reserves regardless of extension
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters Windows does not allow in file names
+const WINDOWS_ILLEGAL_CHARS: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+
+/// Reason a relative path was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    /// Nothing is left of the path after normalization
+    Empty,
+    /// The path contains a `..` component
+    ParentTraversal,
+    /// The path is absolute or has a drive/UNC prefix
+    AbsolutePath,
+    /// A component is a reserved Windows device name
+    ReservedName(String),
+    /// A component contains a character that is not allowed
+    IllegalCharacter(char),
+    /// A component ends with a dot or space (stripped silently by Windows)
+    TrailingDotOrSpace(String),
+    /// A component is longer than the maximum component length
+    ComponentTooLong(String),
+    /// The policy itself could rewrite names into separators, traversal or empty names
+    InvalidPolicy(String),
+}
+
+impl PathError {
+    /// Short machine-readable identifier for the error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PathError::Empty => "empty",
+            PathError::ParentTraversal => "parent_traversal",
+            PathError::AbsolutePath => "absolute_path",
+            PathError::ReservedName(_) => "reserved_name",
+            PathError::IllegalCharacter(_) => "illegal_character",
+            PathError::TrailingDotOrSpace(_) => "trailing_dot_or_space",
+            PathError::ComponentTooLong(_) => "component_too_long",
+            PathError::InvalidPolicy(_) => "invalid_policy",
+        }
+    }
+}
+
+impl std::fmt::Display for PathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathError::Empty => write!(f, "path is empty"),
+            PathError::ParentTraversal => write!(f, "path contains a parent directory component"),
+            PathError::AbsolutePath => write!(f, "path is absolute"),
+            PathError::ReservedName(name) => write!(f, "'{}' is a reserved device name", name),
+            PathError::IllegalCharacter(c) => write!(f, "character {:?} is not allowed", c),
+            PathError::TrailingDotOrSpace(name) => write!(f, "'{}' ends with a dot or space", name),
+            PathError::ComponentTooLong(name) => write!(f, "'{}' is too long", name),
+            PathError::InvalidPolicy(reason) => write!(f, "invalid policy: {}", reason),
+        }
+    }
+}
+
+/// How sanitize_relative_path treats invalid input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathSanitizeMode {
+    /// Return an error for anything that is not already safe
+    Reject,
+    /// Drop traversal/absolute components and rewrite illegal names
+    Replace,
+}
+
+/// Policy for sanitize_relative_path
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct PathSanitizePolicy {
+    pub mode: PathSanitizeMode,
+    /// Character substituted for illegal characters in Replace mode; must not be
+    /// a separator, `.`, a control character or a character Windows forbids
+    pub replacement: char,
+    /// Apply Windows naming rules (reserved names, illegal characters, trailing dots)
+    pub windows_compatible: bool,
+    /// Maximum length of a component in bytes (at least 1)
+    pub max_component_length: usize,
+}
+
+impl PathSanitizePolicy {
+    /// Check that the policy cannot itself produce a separator, a `.` or `..`
+    /// component or an empty component
+    pub fn validate(&self) -> Result<(), PathError> {
+        let c = self.replacement;
+        if matches!(c, '/' | '\\' | '.') || c.is_control() || WINDOWS_ILLEGAL_CHARS.contains(&c) {
+            return Err(PathError::InvalidPolicy(format!("replacement {:?} is not allowed", c)));
+        }
+        if self.max_component_length == 0 {
+            return Err(PathError::InvalidPolicy("max_component_length must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl Default for PathSanitizePolicy {
+    fn default() -> Self {
+        Self {
+            mode: PathSanitizeMode::Reject,
+            replacement: '_',
+            windows_compatible: cfg!(windows),
+            max_component_length: DEFAULT_MAX_COMPONENT_LENGTH,
+        }
+    }
+}
+
+fn is_windows_reserved(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap_or("").trim_end();
+    WINDOWS_RESERVED_NAMES.iter().any(|name| name.eq_ignore_ascii_case(stem))
+}
+
+fn is_illegal_char(c: char, policy: &PathSanitizePolicy) -> bool {
+    c.is_control() || (policy.windows_compatible && WINDOWS_ILLEGAL_CHARS.contains(&c))
+}
+
+/// Truncate a string to at most max_len bytes without splitting a character
+fn truncate_to_char_boundary(s: &str, max_len: usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Sanitize a single component that is neither `.` nor `..`
+fn sanitize_component(component: &str, policy: &PathSanitizePolicy) -> Result<Option<String>, PathError> {
+    let replace = policy.mode == PathSanitizeMode::Replace;
+    let mut name = String::with_capacity(component.len());
+
+    for c in component.chars() {
+        if is_illegal_char(c, policy) {
+            if !replace {
+                return Err(PathError::IllegalCharacter(c));
+            }
+            name.push(policy.replacement);
+        } else {
+            name.push(c);
+        }
+    }
+
+    if policy.windows_compatible && (name.ends_with('.') || name.ends_with(' ')) {
+        if !replace {
+            return Err(PathError::TrailingDotOrSpace(name));
+        }
+        name = name.trim_end_matches(['.', ' ']).to_string();
+        if name.is_empty() {
+            return Ok(None);
+        }
+    }
+
+    if policy.windows_compatible && is_windows_reserved(&name) {
+        if !replace {
+            return Err(PathError::ReservedName(name));
+        }
+        // "CON.txt" -> "CON_.txt"
+        let split = name.find('.').unwrap_or(name.len());
+        name.insert(split, policy.replacement);
+    }
+
+    if name.len() > policy.max_component_length {
+        if !replace {
+            return Err(PathError::ComponentTooLong(name));
+        }
+        name = truncate_to_char_boundary(&name, policy.max_component_length).to_string();
+    }
+
+    // Truncation can leave nothing, or only dots ("..etc" cut to two bytes)
+    match name.as_str() {
+        "" => Ok(None),
+        "." => Err(PathError::Empty),
+        ".." => Err(PathError::ParentTraversal),
+        _ => Ok(Some(name)),
+    }
+}
+
+/// Sanitize a single file or directory name
+///
+/// Unlike sanitize_relative_path_with, a `:` after the first letter is an
+/// ordinary character of the name rather than a drive prefix.
+pub fn sanitize_file_name_with(name: &str, policy: &PathSanitizePolicy) -> Result<String, PathError> {
+    policy.validate()?;
+    match name {
+        "" | "." => Err(PathError::Empty),
+        ".." => Err(PathError::ParentTraversal),
+        _ => sanitize_component(name, policy)?.ok_or(PathError::Empty),
+    }
+}
+
+/// Sanitize a user- or cloud-provided relative path before joining it onto a local root
+///
+/// Both `/` and `\` are treated as separators. In Reject mode any `..`, absolute
+/// prefix, illegal character, reserved name or over-long component is an error;
+/// in Replace mode they are dropped or rewritten instead. A policy that fails
+/// PathSanitizePolicy::validate is an error in either mode.
+pub fn sanitize_relative_path_with(rel: &str, policy: &PathSanitizePolicy) -> Result<PathBuf, PathError> {
+    policy.validate()?;
+    let replace = policy.mode == PathSanitizeMode::Replace;
+    let normalized = rel.replace('\\', "/");
+
+    // Absolute paths, UNC paths and drive prefixes ("C:") are never relative
+    let has_drive = {
+        let bytes = normalized.as_bytes();
+        bytes.len() >= 2 && bytes[1] == b':' && bytes[0].is_ascii_alphabetic()
+    };
+    if (normalized.starts_with('/') || has_drive) && !replace {
+        return Err(PathError::AbsolutePath);
+    }
+
+    let mut result = PathBuf::new();
+    for (i, component) in normalized.split('/').enumerate() {
+        let component = if i == 0 && has_drive { &component[2..] } else { component };
+
+        match component {
+            "" | "." => continue,
+            ".." => {
+                if !replace {
+                    return Err(PathError::ParentTraversal);
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        if let Some(name) = sanitize_component(component, policy)? {
+            result.push(name);
+        }
+    }
+
+    if result.as_os_str().is_empty() {
+        return Err(PathError::Empty);
+    }
+
+    Ok(result)
+}
+
+/// Sanitize a relative path with the default (reject) policy
+pub fn sanitize_relative_path(rel: &str) -> Result<PathBuf, PathError> {
+    sanitize_relative_path_with(rel, &PathSanitizePolicy::default())
+}
+
+/// Sanitize a relative path for use under a local destination root
+///
+/// # Arguments
+/// * `rel` - Relative path to sanitize
+/// * `policy_json` - Optional JSON policy, e.g. `{"mode": "replace", "replacement": "_",
+///   "windows_compatible": true, "max_component_length": 255}` (can be null for defaults)
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope (see ffi_util.rs) with `data` `{"path": "..."}`; a rejected path fails
+/// with ERROR_INVALID_PATH and the rejection kind (e.g. "parent_traversal") as context;
+/// a policy that fails PathSanitizePolicy::validate fails with ERROR_INVALID_JSON.
+/// Caller must free with scan_folder_free_string; null only if allocation fails.
+#[no_mangle]
+pub extern "C" fn sanitize_path_json(
+    rel: *const c_char,
+    policy_json: *const c_char,
+    out_len: *mut usize,
+) -> *mut c_char {
+    crate::ffi_util::json_envelope(sanitize_path_envelope(rel, policy_json), out_len)
+}
+
+fn sanitize_path_envelope(
+    rel: *const c_char,
+    policy_json: *const c_char,
+) -> Result<serde_json::Value, crate::ffi_util::ErrorEnvelope> {
+    use crate::ffi_util::{envelope_str, ErrorEnvelope, ERROR_INVALID_JSON};
+
+    let rel = unsafe { envelope_str(rel, "rel") }?;
+
+    let policy = if policy_json.is_null() {
+        PathSanitizePolicy::default()
+    } else {
+        let json = unsafe { envelope_str(policy_json, "policy_json") }?;
+        let policy: PathSanitizePolicy =
+            serde_json::from_str(json).map_err(|e| ErrorEnvelope::invalid_json("policy_json", e))?;
+        policy.validate()
+            .map_err(|e| ErrorEnvelope::new(ERROR_INVALID_JSON, e.to_string()).with_context("policy_json"))?;
+        policy
+    };
+
+    match sanitize_relative_path_with(rel, &policy) {
+        Ok(path) => Ok(serde_json::json!({ "path": path.to_string_lossy().replace('\\', "/") })),
+        Err(e) => Err(ErrorEnvelope::new(ERROR_INVALID_PATH, e.to_string()).with_context(e.kind())),
+    }
+}
+
+/// Get the free space available for writing at a path
+///
+/// The path does not need to exist yet; the volume of its nearest existing
+/// ancestor is queried.
+///
+/// # Arguments
+/// * `path` - File or folder path on the volume to query
+/// * `out_bytes` - Pointer receiving the available bytes
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn get_available_space(path: *const c_char, out_bytes: *mut u64) -> i32 {
+    if path.is_null() || out_bytes.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let path = match unsafe { c_str_to_path(path) } {
+        Ok(p) => p,
+        Err(code) => return checked(code),
+    };
+
+    match available_space(&path) {
+        Some(bytes) => {
+            unsafe { *out_bytes = bytes; }
+            SUCCESS
+        }
+        None => ERROR_IO_FAILED,
+    }
+}
+
+/// Whether the volume holding `path` keeps names that differ only in case apart
+///
+/// Probed by creating a mixed-case temporary file in `path` (or its nearest
+/// existing ancestor directory) and looking it up with the case flipped.
+pub fn filesystem_case_sensitive(path: &Path) -> io::Result<bool> {
+    let dir = path.ancestors().find(|p| !p.as_os_str().is_empty() && p.is_dir())
+        .unwrap_or(Path::new("."));
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let probe = dir.join(format!(".CaseProbe-{}-{}", std::process::id(), nanos));
+    OpenOptions::new().write(true).create_new(true).open(&probe)?;
+    let flipped = dir.join(format!(".cASEpROBE-{}-{}", std::process::id(), nanos));
+    let case_sensitive = fs::symlink_metadata(&flipped).is_err();
+    let _ = fs::remove_file(&probe);
+    Ok(case_sensitive)
+}
+
+/// Check whether a destination volume treats "Report.pdf" and "report.pdf" as different files
+///
+/// # Arguments
+/// * `path` - File or folder path on the volume to probe (need not exist; the
+///   nearest existing ancestor directory must be writable)
+///
+/// # Returns
+/// 1 if the file system is case-sensitive, 0 if it is case-insensitive, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn is_filesystem_case_sensitive(path: *const c_char) -> i32 {
+    if path.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let path = match unsafe { c_str_to_path(path) } {
+        Ok(p) => p,
+        Err(code) => return checked(code),
+    };
+
+    match filesystem_case_sensitive(&path) {
+        Ok(case_sensitive) => case_sensitive as i32,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => ERROR_PERMISSION_DENIED,
+        Err(_) => ERROR_IO_FAILED,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::c_void;
+    use std::time::Duration;
+
+    #[test]
+    fn test_throttler_limits_fast_producer() {
+        let start = Instant::now();
+        let mut throttler = ProgressThrottler::new(PROGRESS_UPDATE_INTERVAL_MS);
+        throttler.last_update_time = start;
+        let total = 10_000_000;
+
+        // One update per millisecond for 10 seconds, never reaching the total
+        let mut callbacks = 0;
+        for ms in 1..=10_000u64 {
+            let now = start + Duration::from_millis(ms);
+            if throttler.should_update_at(now, ms as usize * 100, total, false) {
+                callbacks += 1;
+            }
+        }
+        assert!(callbacks <= 21, "got {} callbacks in 10s", callbacks);
+        assert!(callbacks >= 19, "got {} callbacks in 10s", callbacks);
+    }
+
+    #[test]
+    fn test_throttler_single_terminal_callback() {
+        let start = Instant::now();
+        let mut throttler = ProgressThrottler::new(PROGRESS_UPDATE_INTERVAL_MS);
+        throttler.last_update_time = start;
+
+        // Final value fires immediately, even inside the interval, but only once
+        let mut terminal = 0;
+        for ms in 1..5u64 {
+            let now = start + Duration::from_millis(ms);
+            if throttler.should_update_at(now, 100, 100, false) {
+                terminal += 1;
+            }
+            if throttler.should_update_at(now, 100, 100, true) {
+                terminal += 1;
+            }
+        }
+        if throttler.finish() {
+            terminal += 1;
+        }
+        assert_eq!(terminal, 1);
+
+        // Consumers that never reached the total get the terminal update from finish()
+        let mut unknown_total = ProgressThrottler::new(PROGRESS_UPDATE_INTERVAL_MS);
+        assert!(unknown_total.finish());
+        assert!(!unknown_total.finish());
+    }
+
+    #[test]
+    fn test_throttler_force_and_percent_delta() {
+        let start = Instant::now();
+        let mut throttler = ProgressThrottler::new(PROGRESS_UPDATE_INTERVAL_MS);
+        throttler.last_update_time = start;
+        assert!(!throttler.should_update_at(start, 10, 100, false));
+        assert!(throttler.should_update_at(start, 10, 100, true));
+
+        let mut throttler = ProgressThrottler::with_percent_delta(1);
+        throttler.last_update_time = start;
+        let total = 1000;
+        let callbacks = (1..total)
+            .filter(|&bytes| throttler.should_update_at(start, bytes, total, false))
+            .count();
+        // Every 10 bytes (1%) below the total
+        assert_eq!(callbacks, 99);
+        assert!(throttler.should_update_at(start, total, total, false));
+        assert!(!throttler.finish());
+    }
+
+    fn reject(windows: bool) -> PathSanitizePolicy {
+        PathSanitizePolicy { windows_compatible: windows, ..Default::default() }
+    }
+
+    fn replace(windows: bool) -> PathSanitizePolicy {
+        PathSanitizePolicy { mode: PathSanitizeMode::Replace, windows_compatible: windows, ..Default::default() }
+    }
+
+    fn ok(rel: &str, policy: &PathSanitizePolicy) -> String {
+        sanitize_relative_path_with(rel, policy).unwrap().to_string_lossy().replace('\\', "/")
+    }
+
+    #[test]
+    fn test_sanitize_plain_paths() {
+        let policy = reject(true);
+        assert_eq!(ok("docs/report.pdf", &policy), "docs/report.pdf");
+        assert_eq!(ok("docs\\nested\\file.txt", &policy), "docs/nested/file.txt");
+        assert_eq!(ok("./a//b/./c", &policy), "a/b/c");
+        assert_eq!(ok(".hidden", &policy), ".hidden");
+        assert_eq!(ok("a..b/..c/d..", &reject(false)), "a..b/..c/d..");
+    }
+
+    #[test]
+    fn test_sanitize_traversal_attempts() {
+        let policy = reject(false);
+        for rel in ["..", "../evil", "a/../../evil", "a/b/..", "..\\evil", "a\\..\\..\\evil"] {
+            assert_eq!(sanitize_relative_path_with(rel, &policy), Err(PathError::ParentTraversal), "{}", rel);
+        }
+        for rel in ["/etc/passwd", "\\\\server\\share\\x", "C:\\Windows\\system32", "c:relative"] {
+            assert_eq!(sanitize_relative_path_with(rel, &policy), Err(PathError::AbsolutePath), "{}", rel);
+        }
+
+        let policy = replace(false);
+        assert_eq!(ok("../../evil", &policy), "evil");
+        assert_eq!(ok("/etc/passwd", &policy), "etc/passwd");
+        assert_eq!(ok("C:\\Windows\\..\\x", &policy), "Windows/x");
+        assert_eq!(sanitize_relative_path_with("../..", &policy), Err(PathError::Empty));
+        assert_eq!(sanitize_relative_path_with("", &policy), Err(PathError::Empty));
+    }
+
+    #[test]
+    fn test_sanitize_windows_names() {
+        let policy = reject(true);
+        for rel in ["CON", "con.txt", "dir/NUL", "Lpt1.log", "aux .tar.gz", "COM9"] {
+            assert!(matches!(sanitize_relative_path_with(rel, &policy), Err(PathError::ReservedName(_))), "{}", rel);
+        }
+        assert_eq!(ok("CONSOLE.txt", &policy), "CONSOLE.txt");
+        assert_eq!(ok("COM10", &policy), "COM10");
+        assert_eq!(sanitize_relative_path_with("a<b", &policy), Err(PathError::IllegalCharacter('<')));
+        assert_eq!(sanitize_relative_path_with("what?", &policy), Err(PathError::IllegalCharacter('?')));
+        assert!(matches!(sanitize_relative_path_with("name.", &policy), Err(PathError::TrailingDotOrSpace(_))));
+        assert!(matches!(sanitize_relative_path_with("dir /x", &policy), Err(PathError::TrailingDotOrSpace(_))));
+
+        // The same names are fine when Windows rules are off
+        assert_eq!(ok("CON/a<b/name.", &reject(false)), "CON/a<b/name.");
+
+        let policy = replace(true);
+        assert_eq!(ok("CON", &policy), "CON_");
+        assert_eq!(ok("nul.txt", &policy), "nul_.txt");
+        assert_eq!(ok("a<b>c:d|e?f*g\"h", &policy), "a_b_c_d_e_f_g_h");
+        assert_eq!(ok("trailing. . /x", &policy), "trailing/x");
+        assert_eq!(sanitize_relative_path_with("...", &policy), Err(PathError::Empty));
+    }
+
+    #[test]
+    fn test_sanitize_control_and_unicode() {
+        let policy = reject(false);
+        assert_eq!(sanitize_relative_path_with("a\0b", &policy), Err(PathError::IllegalCharacter('\0')));
+        assert_eq!(sanitize_relative_path_with("tab\there", &policy), Err(PathError::IllegalCharacter('\t')));
+        assert_eq!(ok("a\0b", &replace(false)), "a_b");
+
+        assert_eq!(ok("фото/日本語/emoji 🎉.png", &policy), "фото/日本語/emoji 🎉.png");
+        assert_eq!(ok("e\u{301}clair", &policy), "e\u{301}clair");
+        // Full-width look-alikes are ordinary characters, not separators
+        assert_eq!(ok("a\u{FF0F}b", &policy), "a\u{FF0F}b");
+        assert_eq!(ok("\u{2025}/x", &policy), "\u{2025}/x");
+    }
+
+    #[test]
+    fn test_sanitize_component_length() {
+        let long = "a".repeat(300);
+        assert!(matches!(sanitize_relative_path_with(&long, &reject(false)), Err(PathError::ComponentTooLong(_))));
+        assert_eq!(ok(&long, &replace(false)).len(), DEFAULT_MAX_COMPONENT_LENGTH);
+
+        // Truncation never splits a multi-byte character
+        let wide = "日".repeat(100); // 300 bytes
+        let truncated = ok(&wide, &replace(false));
+        assert_eq!(truncated.len(), 255);
+        assert!(truncated.chars().all(|c| c == '日'));
+
+        let policy = PathSanitizePolicy { max_component_length: 4, ..reject(false) };
+        assert_eq!(ok("abcd/efgh", &policy), "abcd/efgh");
+        assert!(sanitize_relative_path_with("abcde", &policy).is_err());
+
+        // A component truncated to a dot or two is refused rather than becoming traversal
+        let policy = PathSanitizePolicy { max_component_length: 2, ..replace(false) };
+        assert_eq!(sanitize_relative_path_with("a/..etc", &policy), Err(PathError::ParentTraversal));
+        assert_eq!(sanitize_relative_path_with("a/.x", &PathSanitizePolicy { max_component_length: 1, ..policy.clone() }),
+                   Err(PathError::Empty));
+        assert_eq!(sanitize_file_name_with("..etc", &policy), Err(PathError::ParentTraversal));
+        // A multi-byte character that does not fit leaves nothing, which is dropped
+        assert_eq!(ok("a/日/b", &PathSanitizePolicy { max_component_length: 2, ..replace(false) }), "a/b");
+    }
+
+    #[test]
+    fn test_sanitize_policy_is_validated() {
+        // Replacements that would create separators or traversal out of illegal characters
+        for replacement in ['.', '/', '\\', '\u{1}', '\0', ':', '*', '?'] {
+            let policy = PathSanitizePolicy { replacement, ..replace(false) };
+            assert!(matches!(sanitize_relative_path_with("\u{1}\u{1}", &policy), Err(PathError::InvalidPolicy(_))),
+                    "{:?}", replacement);
+            assert!(matches!(sanitize_file_name_with("x", &policy), Err(PathError::InvalidPolicy(_))));
+        }
+        let policy = PathSanitizePolicy { max_component_length: 0, ..replace(false) };
+        assert!(matches!(sanitize_relative_path_with("a/b", &policy), Err(PathError::InvalidPolicy(_))));
+        assert!(matches!(sanitize_relative_path_with("a/b", &PathSanitizePolicy { max_component_length: 0, ..reject(false) }),
+                         Err(PathError::InvalidPolicy(_))));
+
+        // Any other replacement is used as given
+        let policy = PathSanitizePolicy { replacement: '-', ..replace(false) };
+        assert_eq!(ok("\u{1}\u{1}/x", &policy), "--/x");
+    }
+
+    #[test]
+    fn test_sanitize_path_json() {
+        use std::ffi::CString;
+
+        let mut len = 0usize;
+        let rel = CString::new("../evil").unwrap();
+        let out = sanitize_path_json(rel.as_ptr(), ptr::null(), &mut len);
+        let value: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(out) }.to_str().unwrap()).unwrap();
+        assert_eq!(value["ok"], false);
+        assert_eq!(value["error"]["code"], ERROR_INVALID_PATH);
+        assert_eq!(value["error"]["context"], "parent_traversal");
+        assert!(value["data"].is_null());
+        unsafe { drop(CString::from_raw(out)); }
+
+        let bad_policy = CString::new("{mode").unwrap();
+        let out = sanitize_path_json(rel.as_ptr(), bad_policy.as_ptr(), &mut len);
+        let value: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(out) }.to_str().unwrap()).unwrap();
+        assert_eq!(value["error"]["code"], crate::ffi_util::ERROR_INVALID_JSON);
+        assert_eq!(value["error"]["context"], "policy_json");
+        unsafe { drop(CString::from_raw(out)); }
+
+        for policy in [r#"{"mode": "replace", "replacement": "."}"#, r#"{"mode": "replace", "replacement": "/"}"#,
+                       r#"{"mode": "replace", "max_component_length": 0}"#] {
+            let policy = CString::new(policy).unwrap();
+            let rel = CString::new("\u{1}\u{1}").unwrap();
+            let out = sanitize_path_json(rel.as_ptr(), policy.as_ptr(), &mut len);
+            let value: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(out) }.to_str().unwrap()).unwrap();
+            assert_eq!(value["error"]["code"], crate::ffi_util::ERROR_INVALID_JSON);
+            assert_eq!(value["error"]["context"], "policy_json");
+            unsafe { drop(CString::from_raw(out)); }
+        }
+
+        let policy = CString::new(r#"{"mode": "replace", "windows_compatible": true}"#).unwrap();
+        let rel = CString::new("docs\\..\\CON.txt").unwrap();
+        let out = sanitize_path_json(rel.as_ptr(), policy.as_ptr(), &mut len);
+        let value: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(out) }.to_str().unwrap()).unwrap();
+        assert_eq!(value["ok"], true);
+        assert_eq!(value["data"]["path"], "docs/CON_.txt");
+        unsafe { drop(CString::from_raw(out)); }
+    }
+
+    mod cancellation {
+        use super::*;
+        use std::fs;
+        use std::sync::atomic::AtomicBool;
+
+        use crate::archive::{unzip_to_folder, zip_folder};
+        use crate::copy::{chunked_copy_free, chunked_copy_init, chunked_copy_open_source, chunked_copy_read_chunk,
+                          chunked_copy_set_keep_partial, chunked_copy_write_chunk, copy_file_streaming,
+                          copy_file_streaming_with_options, folder_copy_free, folder_copy_init, folder_copy_next_file,
+                          CCopyOptions};
+        use crate::download::{download_append_chunk, download_free, download_get_partial_path, download_init,
+                              download_set_keep_partial};
+        use crate::scan::scan_folder_free_string;
+        use crate::unified_copy::{unified_copy_file, unified_copy_free, unified_copy_init};
+        use crate::test_util::{c_path, temp_dir};
+        use crate::{decrypt_file_streaming_ex, encrypt_file_streaming_ex, free_buffer};
+
+        fn content(len: usize) -> Vec<u8> {
+            (0..len).map(|i| (i % 241) as u8).collect()
+        }
+
+        fn has_temp_files(dir: &Path) -> bool {
+            fs::read_dir(dir).unwrap()
+                .any(|e| e.unwrap().file_name().to_string_lossy().ends_with(crate::temp::TEMP_FILE_SUFFIX))
+        }
+
+        /// Progress callback that requests cancellation on its first invocation
+        extern "C" fn cancel_on_progress(_processed: usize, _total: usize, user_data: *mut c_void) {
+            unsafe { (*(user_data as *const AtomicBool)).store(true, Ordering::SeqCst); }
+        }
+
+        #[test]
+        fn test_cancel_file_copy() {
+            let dir = temp_dir("copy");
+            let src = dir.join("source.bin");
+            fs::write(&src, content(3 * 1024 * 1024)).unwrap();
+            let cancel = AtomicBool::new(true);
+
+            let dst = dir.join("dest.bin");
+            let result = copy_file_streaming(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024,
+                                             None, &cancel, ptr::null_mut());
+            assert_eq!(result, ERROR_CANCELLED);
+            assert!(!dst.exists());
+
+            let kept = dir.join("kept.bin");
+            let options = CCopyOptions { keep_partial: 1, ..Default::default() };
+            let result = copy_file_streaming_with_options(c_path(&src).as_ptr(), c_path(&kept).as_ptr(), 64 * 1024,
+                                                          &options, None, &cancel, ptr::null_mut());
+            assert_eq!(result, ERROR_CANCELLED);
+            assert!(kept.exists());
+
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        #[test]
+        fn test_cancel_streaming_encryption() {
+            let data = content(3 * 1024 * 1024 + 17);
+            let key = [9u8; 32];
+            let mut out_len = 0usize;
+            let mut status = 0;
+
+            let cancel = AtomicBool::new(false);
+            let out = encrypt_file_streaming_ex(data.as_ptr(), data.len(), key.as_ptr(), 32, &mut out_len,
+                                                Some(cancel_on_progress), &cancel as *const AtomicBool as *mut c_void,
+                                                &cancel, &mut status);
+            assert!(out.is_null());
+            assert_eq!(status, ERROR_CANCELLED);
+
+            let encrypted = encrypt_file_streaming_ex(data.as_ptr(), data.len(), key.as_ptr(), 32, &mut out_len,
+                                                      None, ptr::null_mut(), ptr::null(), &mut status);
+            assert!(!encrypted.is_null());
+            assert_eq!(status, SUCCESS);
+
+            let cancel = AtomicBool::new(false);
+            let mut plain_len = 0usize;
+            let out = decrypt_file_streaming_ex(encrypted, out_len, key.as_ptr(), 32, &mut plain_len,
+                                                Some(cancel_on_progress), &cancel as *const AtomicBool as *mut c_void,
+                                                &cancel, &mut status);
+            assert!(out.is_null());
+            assert_eq!(status, ERROR_CANCELLED);
+            free_buffer(encrypted);
+        }
+
+        #[test]
+        fn test_cancel_download() {
+            let dir = temp_dir("download");
+            let chunk = content(64 * 1024);
+
+            for keep_partial in [0u8, 1] {
+                let dest = dir.join(format!("download_{}.bin", keep_partial));
+                let cancel = AtomicBool::new(false);
+                let ctx = download_init(c_path(&dest).as_ptr(), ptr::null(), 0, 0, None, &cancel, ptr::null_mut());
+                assert!(!ctx.is_null());
+                download_set_keep_partial(ctx, keep_partial);
+
+                assert_eq!(download_append_chunk(ctx, chunk.as_ptr(), chunk.len(), None, ptr::null_mut()), SUCCESS);
+                cancel.store(true, Ordering::SeqCst);
+                assert_eq!(download_append_chunk(ctx, chunk.as_ptr(), chunk.len(), None, ptr::null_mut()), ERROR_CANCELLED);
+
+                let partial_ptr = download_get_partial_path(ctx);
+                let partial = PathBuf::from(unsafe { CStr::from_ptr(partial_ptr) }.to_string_lossy().to_string());
+                scan_folder_free_string(partial_ptr);
+                download_free(ctx);
+
+                assert!(!dest.exists());
+                if keep_partial == 1 {
+                    assert_eq!(fs::read(&partial).unwrap(), chunk);
+                    fs::remove_file(&partial).unwrap();
+                } else {
+                    assert!(!partial.exists());
+                }
+            }
+            assert!(!has_temp_files(&dir));
+
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        #[test]
+        fn test_cancel_chunked_copy() {
+            let dir = temp_dir("chunked");
+            let src = dir.join("source.bin");
+            fs::write(&src, content(512 * 1024)).unwrap();
+
+            for keep_partial in [0u8, 1] {
+                let dst = dir.join(format!("dest_{}.bin", keep_partial));
+                let cancel = AtomicBool::new(false);
+                let ctx = chunked_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), 64 * 1024, &cancel);
+                assert!(!ctx.is_null());
+                assert_eq!(chunked_copy_set_keep_partial(ctx, keep_partial), SUCCESS);
+                assert_eq!(chunked_copy_open_source(ctx), SUCCESS);
+
+                let mut buffer = vec![0u8; 64 * 1024];
+                let n = chunked_copy_read_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, ptr::null_mut());
+                assert_eq!(n, 64 * 1024);
+                assert_eq!(chunked_copy_write_chunk(ctx, buffer.as_ptr(), n as usize, None, ptr::null_mut()), SUCCESS);
+
+                cancel.store(true, Ordering::SeqCst);
+                let n = chunked_copy_read_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, ptr::null_mut());
+                assert_eq!(n, ERROR_CANCELLED as isize);
+                chunked_copy_free(ctx);
+
+                if keep_partial == 1 {
+                    assert_eq!(fs::metadata(&dst).unwrap().len(), 64 * 1024);
+                } else {
+                    assert!(!dst.exists());
+                }
+            }
+
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        #[test]
+        fn test_cancel_folder_copy() {
+            let dir = temp_dir("cancel_folder");
+            let src = dir.join("src");
+            fs::create_dir_all(&src).unwrap();
+            fs::write(src.join("a.txt"), b"first").unwrap();
+            fs::write(src.join("b.txt"), b"second").unwrap();
+            let dst = dir.join("dst");
+
+            let cancel = AtomicBool::new(false);
+            let ctx = folder_copy_init(c_path(&src).as_ptr(), c_path(&dst).as_ptr(), &cancel);
+            assert!(!ctx.is_null());
+            assert_eq!(folder_copy_next_file(ctx, None, ptr::null_mut()), 1);
+            cancel.store(true, Ordering::SeqCst);
+            assert_eq!(folder_copy_next_file(ctx, None, ptr::null_mut()), ERROR_CANCELLED);
+            folder_copy_free(ctx);
+
+            // Files completed before the cancellation are kept
+            assert_eq!(fs::read(dst.join("a.txt")).unwrap(), b"first");
+            assert!(!dst.join("b.txt").exists());
+
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        #[test]
+        fn test_cancel_archives() {
+            let dir = temp_dir("archive");
+            let src = dir.join("src");
+            fs::create_dir_all(&src).unwrap();
+            fs::write(src.join("a.bin"), content(200 * 1024)).unwrap();
+            let zip_path = dir.join("out.zip");
+
+            let cancel = AtomicBool::new(true);
+            let result = zip_folder(c_path(&src).as_ptr(), c_path(&zip_path).as_ptr(), -1, None, &cancel,
+                                    ptr::null_mut(), ptr::null_mut());
+            assert_eq!(result, ERROR_CANCELLED);
+            assert!(!zip_path.exists());
+            assert!(!has_temp_files(&dir));
+
+            let result = zip_folder(c_path(&src).as_ptr(), c_path(&zip_path).as_ptr(), -1, None, ptr::null(),
+                                    ptr::null_mut(), ptr::null_mut());
+            assert_eq!(result, SUCCESS);
+
+            let extracted = dir.join("extracted");
+            let result = unzip_to_folder(c_path(&zip_path).as_ptr(), c_path(&extracted).as_ptr(), None, &cancel,
+                                         ptr::null_mut());
+            assert_eq!(result, ERROR_CANCELLED);
+            assert!(!extracted.join("a.bin").exists());
+
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        extern "C" fn read_then_cancel(buffer: *mut u8, buffer_size: usize, _offset: u64, user_data: *mut c_void) -> isize {
+            unsafe {
+                ptr::write_bytes(buffer, 1, buffer_size);
+                (*(user_data as *const AtomicBool)).store(true, Ordering::SeqCst);
+            }
+            buffer_size as isize
+        }
+
+        extern "C" fn discard_write(_data: *const u8, _data_len: usize, _offset: u64, _user_data: *mut c_void) -> i32 {
+            0
+        }
+
+        #[test]
+        fn test_cancel_unified_copy_uses_shared_code() {
+            let cancel = AtomicBool::new(false);
+            let chunk = 64 * 1024;
+            let mut buffer = vec![0u8; chunk];
+            let ctx = unified_copy_init(chunk as u64 * 4, 1, chunk, &cancel);
+            let result = unified_copy_file(ctx, buffer.as_mut_ptr(), chunk, chunk as u64 * 4,
+                                           Some(read_then_cancel), Some(discard_write), None,
+                                           &cancel as *const AtomicBool as *mut c_void);
+            unified_copy_free(ctx);
+            assert_eq!(result, ERROR_CANCELLED);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    mod descriptors {
+        use super::*;
+        use std::ffi::CString;
+        use std::io::{Read, Seek, SeekFrom};
+        use std::os::unix::io::FromRawFd;
+
+        use crate::copy::copy_file_to_fd;
+        use crate::download::{download_append_chunk, download_finalize, download_free, download_init_fd};
+        use crate::upload::{upload_finalize, upload_free, upload_init_fd, upload_process_chunk};
+
+        fn memfd(name: &str) -> c_int {
+            let name = CString::new(name).unwrap();
+            let fd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+            assert!(fd >= 0);
+            fd
+        }
+
+        /// Reads the caller's descriptor back from the start, proving it is still open
+        fn read_back(fd: c_int) -> Vec<u8> {
+            assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1);
+            let mut file = unsafe { File::from_raw_fd(fd) };
+            file.seek(SeekFrom::Start(0)).unwrap();
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+            data
+        }
+
+        fn content(len: usize) -> Vec<u8> {
+            (0..len).map(|i| (i % 251) as u8).collect()
+        }
+
+        #[test]
+        fn test_copy_file_to_fd() {
+            let dir = std::env::temp_dir().join(format!("cloud_nexus_fd_copy_{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let source = dir.join("source.bin");
+            let data = content(300_000);
+            std::fs::write(&source, &data).unwrap();
+            let source_c = CString::new(source.to_string_lossy().to_string()).unwrap();
+
+            let fd = memfd("copy_dest");
+            let result = copy_file_to_fd(source_c.as_ptr(), fd, 64 * 1024, None, ptr::null(), ptr::null_mut());
+            assert_eq!(result, SUCCESS);
+            assert!(read_back(fd) == data);
+
+            assert_eq!(copy_file_to_fd(source_c.as_ptr(), -1, 0, None, ptr::null(), ptr::null_mut()), ERROR_INVALID_PATH);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn test_download_init_fd() {
+            let fd = memfd("download_dest");
+            let ctx = download_init_fd(fd, ptr::null(), 0, 0, None, ptr::null(), ptr::null_mut());
+            assert!(!ctx.is_null());
+
+            let data = content(200_000);
+            for chunk in data.chunks(50_000) {
+                assert_eq!(download_append_chunk(ctx, chunk.as_ptr(), chunk.len(), None, ptr::null_mut()), SUCCESS);
+            }
+            assert_eq!(download_finalize(ctx), SUCCESS);
+            download_free(ctx);
+
+            assert!(read_back(fd) == data);
+            assert!(download_init_fd(-1, ptr::null(), 0, 0, None, ptr::null(), ptr::null_mut()).is_null());
+        }
+
+        extern "C" fn collect_chunk(data: *const u8, data_len: usize, _chunk_index: u32, user_data: *mut c_void) {
+            let out = unsafe { &mut *(user_data as *mut Vec<u8>) };
+            out.extend_from_slice(unsafe { std::slice::from_raw_parts(data, data_len) });
+        }
+
+        #[test]
+        fn test_upload_init_fd() {
+            let fd = memfd("upload_source");
+            let data = content(150_000);
+            {
+                // Leave the offset at the end; the upload still reads from the start
+                let mut file = std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+                file.write_all(&data).unwrap();
+            }
+
+            let ctx = upload_init_fd(fd, ptr::null(), 0, 64 * 1024, 0, None, None, ptr::null(), ptr::null_mut());
+            assert!(!ctx.is_null());
+
+            let mut uploaded: Vec<u8> = Vec::new();
+            let mut buffer = vec![0u8; 64 * 1024 + 64];
+            let user_data = &mut uploaded as *mut Vec<u8> as *mut c_void;
+            while upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_chunk), user_data) > 0 {}
+            assert_eq!(upload_finalize(ctx), SUCCESS);
+            upload_free(ctx);
+
+            assert!(uploaded == data);
+            assert!(read_back(fd) == data);
+        }
+    }
+}
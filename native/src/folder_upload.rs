@@ -0,0 +1,570 @@
+/// Folder upload orchestration for CloudNexus
+/// Scans a local folder once and walks its files through per-file upload contexts,
+/// reporting aggregate progress across the whole folder
+use std::collections::HashSet;
+use std::ffi::{c_char, c_void, CString};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::atomic::AtomicBool;
+
+use crate::file_io::{ProgressThrottler, ERROR_NULL_POINTER, ERROR_CANCELLED, ERROR_INVALID_PATH,
+                     ERROR_IO_FAILED, SUCCESS, c_str_to_path, is_cancelled};
+use crate::ffi_util::{set_last_error_detail, ErrorEnvelope};
+use crate::scan::{scan_folder_each, FolderScanItem, ScanTraversal};
+use crate::{legacy_encryption_mode, master_key_for_mode};
+use crate::upload::{UploadContext, UploadDataCallback, check_chunk_size, upload_process_chunk, upload_get_header,
+                    upload_get_bytes_processed, upload_finalize, upload_free};
+
+/// Returned by folder_upload_next_file when the next entry is a file to upload
+pub const FOLDER_UPLOAD_ENTRY_FILE: i32 = 1;
+/// Returned by folder_upload_next_file when the next entry is an empty directory
+pub const FOLDER_UPLOAD_ENTRY_DIRECTORY: i32 = 2;
+
+/// Progress callback for folder uploads
+/// Parameters: bytes_processed, total_bytes, files_processed, total_files, user_data
+pub type FolderUploadProgressCallback = extern "C" fn(
+    bytes_processed: usize,
+    total_bytes: usize,
+    files_processed: usize,
+    total_files: usize,
+    user_data: *mut c_void,
+);
+
+/// Folder upload context
+///
+/// Holds the ordered list of entries produced by the initial scan and the
+/// upload context of the file currently being streamed.
+pub struct FolderUploadContext {
+    entries: Vec<FolderScanItem>,
+    next_entry: usize,
+    current_upload: *mut UploadContext,
+    current_completed: bool,
+    master_key: Vec<u8>,
+    should_encrypt: bool,
+    chunk_size: usize,
+    cancel_flag: *const AtomicBool,
+    completed_bytes: usize,
+    total_bytes: usize,
+    files_processed: usize,
+    total_files: usize,
+    progress_throttler: ProgressThrottler,
+}
+
+impl FolderUploadContext {
+    pub fn new(entries: Vec<FolderScanItem>, master_key: Vec<u8>, should_encrypt: bool,
+               chunk_size: usize, cancel_flag: *const AtomicBool) -> Self {
+        let total_files = entries.iter().filter(|e| !e.is_folder).count();
+        let total_bytes = entries.iter().map(|e| e.size as usize).sum();
+
+        Self {
+            entries,
+            next_entry: 0,
+            current_upload: ptr::null_mut(),
+            current_completed: false,
+            master_key,
+            should_encrypt,
+            chunk_size,
+            cancel_flag,
+            completed_bytes: 0,
+            total_bytes,
+            files_processed: 0,
+            total_files,
+            progress_throttler: ProgressThrottler::new(500),
+        }
+    }
+
+    /// Bytes uploaded so far across all files, including the current one
+    fn bytes_processed(&self) -> usize {
+        if self.current_upload.is_null() {
+            self.completed_bytes
+        } else {
+            self.completed_bytes + upload_get_bytes_processed(self.current_upload)
+        }
+    }
+
+    /// Release the upload context of the current file, if any
+    fn release_current(&mut self) {
+        if !self.current_upload.is_null() {
+            self.completed_bytes += upload_get_bytes_processed(self.current_upload);
+            upload_finalize(self.current_upload);
+            upload_free(self.current_upload);
+            self.current_upload = ptr::null_mut();
+        }
+    }
+}
+
+impl Drop for FolderUploadContext {
+    fn drop(&mut self) {
+        self.release_current();
+    }
+}
+
+/// Build the ordered upload plan from a scan: every file plus every directory
+/// that has no children (so it can be created remotely), sorted by relative path
+fn build_upload_entries(items: Vec<FolderScanItem>) -> Vec<FolderScanItem> {
+    let mut non_empty_dirs: HashSet<String> = HashSet::new();
+    for item in &items {
+        let mut path = item.relative_path.as_str();
+        while let Some(pos) = path.rfind('/') {
+            path = &path[..pos];
+            if !non_empty_dirs.insert(path.to_string()) {
+                break;
+            }
+        }
+    }
+
+    let mut entries: Vec<FolderScanItem> = items
+        .into_iter()
+        .filter(|item| !item.is_folder || !non_empty_dirs.contains(&item.relative_path))
+        .collect();
+
+    entries.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+    entries
+}
+
+/// Initialize a folder upload
+///
+/// Scans the folder synchronously and prepares the ordered list of files
+/// (and empty directories) to upload. With `should_encrypt` set, a null
+/// master key or one that is not 32 bytes long fails the init. Setting the
+/// cancel flag during the scan stops it and fails the init with
+/// ERROR_CANCELLED as the last error.
+///
+/// # Arguments
+/// * `root_path` - Path to the local folder to upload
+/// * `master_key` - Pointer to 32-byte master encryption key (can be null for no encryption)
+/// * `master_key_len` - Length of master key (must be 0 or 32)
+/// * `should_encrypt` - 1 if encryption should be used, 0 otherwise
+/// * `chunk_size` - Size of plaintext chunks in bytes (0 for default, else 64KB to 10MB)
+/// * `cancel_flag` - Pointer to atomic bool for cancellation
+///
+/// # Returns
+/// Pointer to FolderUploadContext, or null on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_upload_init(
+    root_path: *const c_char,
+    master_key: *const u8,
+    master_key_len: usize,
+    should_encrypt: i32,
+    chunk_size: usize,
+    cancel_flag: *const AtomicBool,
+) -> *mut FolderUploadContext {
+    // A missing or truncated key must not turn into a plaintext upload
+    let key = match master_key_for_mode(master_key, master_key_len, legacy_encryption_mode(should_encrypt)) {
+        Ok(key) => key,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    if let Err(error) = check_chunk_size(chunk_size) {
+        set_last_error_detail(error);
+        return ptr::null_mut();
+    }
+
+    let root: PathBuf = match unsafe { c_str_to_path(root_path) } {
+        Ok(p) => p,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    // Checked on every entry, so cancelling a scan of a huge folder takes effect at once
+    let mut items = Vec::new();
+    let scan = scan_folder_each(&root.to_string_lossy(), None, ScanTraversal::default(), |item| {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err("Folder upload cancelled".to_string());
+        }
+        items.push(item);
+        Ok(())
+    });
+    if unsafe { is_cancelled(cancel_flag) } {
+        set_last_error_detail(ErrorEnvelope::new(ERROR_CANCELLED, "Folder upload cancelled during the scan")
+            .with_context(root.to_string_lossy()));
+        return ptr::null_mut();
+    }
+    if scan.is_err() {
+        return ptr::null_mut();
+    }
+
+    let should_encrypt = !key.is_empty();
+    let context = Box::new(FolderUploadContext::new(
+        build_upload_entries(items),
+        key,
+        should_encrypt,
+        chunk_size,
+        cancel_flag,
+    ));
+
+    Box::leak(context) as *mut FolderUploadContext
+}
+
+/// Advance to the next entry of the folder upload
+///
+/// Releases the upload context of the previous file and prepares the next one.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderUploadContext
+/// * `out_relative_path` - Receives the entry's relative path (free with scan_folder_free_string)
+/// * `out_file_size` - Receives the file size in bytes (0 for directories)
+///
+/// # Returns
+/// FOLDER_UPLOAD_ENTRY_FILE (1) for a file, FOLDER_UPLOAD_ENTRY_DIRECTORY (2) for an
+/// empty directory, 0 when all entries are done, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_upload_next_file(
+    context: *mut FolderUploadContext,
+    out_relative_path: *mut *mut c_char,
+    out_file_size: *mut u64,
+) -> i32 {
+    if context.is_null() || out_relative_path.is_null() || out_file_size.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+
+    ctx.release_current();
+
+    unsafe {
+        *out_relative_path = ptr::null_mut();
+        *out_file_size = 0;
+    }
+
+    if unsafe { is_cancelled(ctx.cancel_flag) } {
+        return ERROR_CANCELLED;
+    }
+
+    let entry = match ctx.entries.get(ctx.next_entry) {
+        Some(e) => e.clone(),
+        None => return 0,
+    };
+    ctx.next_entry += 1;
+
+    let relative_path = match CString::new(entry.relative_path.clone()) {
+        Ok(s) => s,
+        Err(_) => return ERROR_INVALID_PATH,
+    };
+
+    if entry.is_folder {
+        unsafe { *out_relative_path = relative_path.into_raw(); }
+        return FOLDER_UPLOAD_ENTRY_DIRECTORY;
+    }
+
+    let mut upload = Box::new(UploadContext::new(
+        PathBuf::from(&entry.absolute_path),
+        entry.size as usize,
+        ctx.should_encrypt,
+        ctx.master_key.clone(),
+        ctx.cancel_flag,
+    ));
+    upload.set_chunk_size(ctx.chunk_size);
+
+    ctx.current_upload = Box::into_raw(upload);
+    ctx.current_completed = false;
+
+    unsafe {
+        *out_relative_path = relative_path.into_raw();
+        *out_file_size = entry.size;
+    }
+
+    FOLDER_UPLOAD_ENTRY_FILE
+}
+
+/// Get the header and wrapped FEK for the current file
+///
+/// Same contract as upload_get_header, applied to the file selected by the last
+/// folder_upload_next_file call.
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_upload_get_header(
+    context: *mut FolderUploadContext,
+    header_buffer: *mut u8,
+    fek_buffer: *mut u8,
+    fek_buffer_size: usize,
+    fek_len: *mut usize,
+) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    if ctx.current_upload.is_null() {
+        return ERROR_IO_FAILED;
+    }
+
+    upload_get_header(ctx.current_upload, header_buffer, fek_buffer, fek_buffer_size, fek_len)
+}
+
+/// Process the next chunk of the current file
+///
+/// Delegates to upload_process_chunk and reports aggregate folder progress.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderUploadContext
+/// * `buffer` - Buffer to store chunk data
+/// * `buffer_size` - Size of buffer
+/// * `progress_callback` - Optional folder progress callback
+/// * `data_callback` - Optional callback receiving each emitted chunk
+/// * `user_data` - User data passed to callbacks
+///
+/// # Returns
+/// Number of bytes in chunk (0 when the current file is done), or negative error code
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_upload_process_chunk(
+    context: *mut FolderUploadContext,
+    buffer: *mut u8,
+    buffer_size: usize,
+    progress_callback: Option<FolderUploadProgressCallback>,
+    data_callback: Option<UploadDataCallback>,
+    user_data: *mut c_void,
+) -> isize {
+    if context.is_null() || buffer.is_null() {
+        return ERROR_NULL_POINTER as isize;
+    }
+
+    let ctx = unsafe { &mut *context };
+    if ctx.current_upload.is_null() {
+        return 0;
+    }
+
+    let result = upload_process_chunk(ctx.current_upload, buffer, buffer_size, None, data_callback, user_data);
+    if result < 0 {
+        return result;
+    }
+
+    let file_done = result == 0 && !ctx.current_completed;
+    if file_done {
+        ctx.current_completed = true;
+        ctx.files_processed += 1;
+    }
+
+    if let Some(cb) = progress_callback {
+        let bytes_processed = ctx.bytes_processed();
+        if ctx.progress_throttler.should_update_with(bytes_processed, ctx.total_bytes, file_done) {
+            cb(bytes_processed, ctx.total_bytes, ctx.files_processed, ctx.total_files, user_data);
+        }
+    }
+
+    result
+}
+
+/// Get aggregate folder upload progress
+///
+/// # Arguments
+/// * `context` - Pointer to FolderUploadContext
+/// * `bytes_processed` - Pointer to store bytes processed
+/// * `total_bytes` - Pointer to store total bytes
+/// * `files_processed` - Pointer to store files processed
+/// * `total_files` - Pointer to store total files
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_upload_get_progress(
+    context: *mut FolderUploadContext,
+    bytes_processed: *mut usize,
+    total_bytes: *mut usize,
+    files_processed: *mut usize,
+    total_files: *mut usize,
+) {
+    if context.is_null() {
+        return;
+    }
+
+    let ctx = unsafe { &*context };
+
+    if !bytes_processed.is_null() {
+        unsafe { *bytes_processed = ctx.bytes_processed(); }
+    }
+    if !total_bytes.is_null() {
+        unsafe { *total_bytes = ctx.total_bytes; }
+    }
+    if !files_processed.is_null() {
+        unsafe { *files_processed = ctx.files_processed; }
+    }
+    if !total_files.is_null() {
+        unsafe { *total_files = ctx.total_files; }
+    }
+}
+
+/// Finalize folder upload and release the current file
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_upload_finalize(context: *mut FolderUploadContext) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &mut *context };
+    ctx.release_current();
+
+    SUCCESS
+}
+
+/// Free folder upload context
+///
+/// # Arguments
+/// * `context` - Pointer to FolderUploadContext to free
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_upload_free(context: *mut FolderUploadContext) {
+    if !context.is_null() {
+        unsafe {
+            let _ = Box::from_raw(context);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::ffi::CStr;
+    use std::fs;
+    use std::slice;
+
+    struct Collector {
+        current: String,
+        chunks: HashMap<String, Vec<u8>>,
+    }
+
+    extern "C" fn collect_chunk(data: *const u8, data_len: usize, _chunk_index: u32, user_data: *mut c_void) {
+        let collector = unsafe { &mut *(user_data as *mut Collector) };
+        let bytes = unsafe { slice::from_raw_parts(data, data_len) };
+        collector.chunks.entry(collector.current.clone()).or_default().extend_from_slice(bytes);
+    }
+
+    fn make_tree(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs/nested")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("a.txt"), b"alpha").unwrap();
+        fs::write(root.join("docs/b.txt"), vec![7u8; 200 * 1024]).unwrap();
+        fs::write(root.join("docs/nested/c.bin"), b"").unwrap();
+        root
+    }
+
+    fn drive(root: &PathBuf, key: Option<&[u8; 32]>) -> (Vec<(i32, String, u64)>, Collector) {
+        let root_c = CString::new(root.to_string_lossy().to_string()).unwrap();
+        let (key_ptr, key_len) = match key {
+            Some(k) => (k.as_ptr(), 32),
+            None => (ptr::null(), 0),
+        };
+        let ctx = folder_upload_init(root_c.as_ptr(), key_ptr, key_len, key.is_some() as i32, 64 * 1024, ptr::null());
+        assert!(!ctx.is_null());
+
+        let mut collector = Collector { current: String::new(), chunks: HashMap::new() };
+        let mut entries = Vec::new();
+        let mut buffer = vec![0u8; 256 * 1024];
+
+        loop {
+            let mut rel: *mut c_char = ptr::null_mut();
+            let mut size = 0u64;
+            let kind = folder_upload_next_file(ctx, &mut rel, &mut size);
+            assert!(kind >= 0);
+            if kind == 0 {
+                break;
+            }
+            let rel_str = unsafe { CStr::from_ptr(rel) }.to_str().unwrap().to_string();
+            crate::scan::scan_folder_free_string(rel);
+            entries.push((kind, rel_str.clone(), size));
+
+            if kind == FOLDER_UPLOAD_ENTRY_FILE {
+                collector.current = rel_str;
+                loop {
+                    let n = folder_upload_process_chunk(
+                        ctx,
+                        buffer.as_mut_ptr(),
+                        buffer.len(),
+                        None,
+                        Some(collect_chunk),
+                        &mut collector as *mut Collector as *mut c_void,
+                    );
+                    assert!(n >= 0);
+                    if n == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let (mut bytes, mut total, mut files, mut total_files) = (0, 0, 0, 0);
+        folder_upload_get_progress(ctx, &mut bytes, &mut total, &mut files, &mut total_files);
+        assert_eq!(bytes, total);
+        assert_eq!(files, total_files);
+        assert_eq!(total_files, 3);
+
+        assert_eq!(folder_upload_finalize(ctx), SUCCESS);
+        folder_upload_free(ctx);
+        (entries, collector)
+    }
+
+    #[test]
+    fn test_folder_upload_plain_pipeline() {
+        let root = make_tree("folder_upload_plain");
+        let (entries, collector) = drive(&root, None);
+
+        let names: Vec<(i32, &str)> = entries.iter().map(|(k, p, _)| (*k, p.as_str())).collect();
+        assert_eq!(names, vec![
+            (FOLDER_UPLOAD_ENTRY_FILE, "a.txt"),
+            (FOLDER_UPLOAD_ENTRY_FILE, "docs/b.txt"),
+            (FOLDER_UPLOAD_ENTRY_FILE, "docs/nested/c.bin"),
+            (FOLDER_UPLOAD_ENTRY_DIRECTORY, "empty"),
+        ]);
+
+        assert_eq!(collector.chunks["a.txt"], b"alpha");
+        assert_eq!(collector.chunks["docs/b.txt"], vec![7u8; 200 * 1024]);
+        assert!(!collector.chunks.contains_key("docs/nested/c.bin"));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_folder_upload_init_stops_when_cancelled() {
+        let root = make_tree("folder_upload_cancelled");
+        let root_c = CString::new(root.to_string_lossy().to_string()).unwrap();
+        let last_error_code = || {
+            let json = unsafe { CString::from_raw(crate::ffi_util::get_last_error_json(ptr::null_mut())) };
+            serde_json::from_str::<serde_json::Value>(json.to_str().unwrap()).unwrap()["data"]["code"].clone()
+        };
+
+        let cancel = AtomicBool::new(true);
+        assert!(folder_upload_init(root_c.as_ptr(), ptr::null(), 0, 0, 0, &cancel).is_null());
+        assert_eq!(last_error_code(), ERROR_CANCELLED);
+
+        // Out-of-range chunk sizes fail before the scan
+        cancel.store(false, std::sync::atomic::Ordering::Relaxed);
+        assert!(folder_upload_init(root_c.as_ptr(), ptr::null(), 0, 0, 1, &cancel).is_null());
+        assert_eq!(last_error_code(), crate::errors::ERROR_INVALID_CHUNK_SIZE);
+        let ctx = folder_upload_init(root_c.as_ptr(), ptr::null(), 0, 0, 0, &cancel);
+        assert!(!ctx.is_null());
+        folder_upload_free(ctx);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_folder_upload_encrypted_pipeline() {
+        let root = make_tree("folder_upload_encrypted");
+        let key = [3u8; 32];
+        let (entries, collector) = drive(&root, Some(&key));
+        assert_eq!(entries.len(), 4);
+
+        // Without folder_upload_get_header the stream opens with header + wrapped FEK,
+        // then 200KB at 64KB chunks -> 4 chunks, each with 20-byte header + 16-byte MAC
+        let encrypted = &collector.chunks["docs/b.txt"];
+        let fek_len = u32::from_le_bytes([encrypted[8], encrypted[9], encrypted[10], encrypted[11]]) as usize;
+        let prefix = 12 + fek_len;
+        assert_eq!(encrypted.len(), prefix + 200 * 1024 + 4 * 36);
+        assert_ne!(&encrypted[prefix + 36..prefix + 100], &[7u8; 64][..]);
+
+        // The empty file still gets its header and one explicit empty chunk
+        assert_eq!(collector.chunks["docs/nested/c.bin"].len(), prefix + 36);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
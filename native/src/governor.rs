@@ -0,0 +1,293 @@
+/// Transfer speed governor for CloudNexus
+/// Lets the host app slow down native transfers under thermal pressure or low
+/// battery. A single process-wide callback reports the allowed speed as a
+/// percentage; chunk loops pace themselves through a TransferPacer, which
+/// combines that percentage with an optional per-context byte rate limit.
+use std::ffi::c_void;
+use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::file_io::is_cancelled;
+
+/// Governor callback: returns the allowed transfer speed in percent (0-100, 0 pauses)
+pub type SpeedGovernorCallback = extern "C" fn(user_data: *mut c_void) -> u32;
+
+/// The governor is sampled at most this often; loops reuse the last value in between
+const GOVERNOR_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a paused loop wakes up to check cancellation and the governor
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct GovernorState {
+    callback: Option<SpeedGovernorCallback>,
+    // Stored as an address so the state can live in a static
+    user_data: usize,
+    /// Bumped by set_speed_governor so a sample from a replaced callback is dropped
+    generation: u64,
+    last_sample: Option<Instant>,
+    percent: u32,
+}
+
+static SPEED_GOVERNOR: Mutex<GovernorState> = Mutex::new(GovernorState {
+    callback: None,
+    user_data: 0,
+    generation: 0,
+    last_sample: None,
+    percent: 100,
+});
+
+/// Current allowed speed in percent, sampling the governor if the last sample is stale
+///
+/// The callback runs without the lock held, so it may take its time or call
+/// back into the library; loops that need a value meanwhile reuse the last one.
+pub fn governor_percent() -> u32 {
+    let (callback, user_data, generation) = {
+        let mut state = match SPEED_GOVERNOR.lock() {
+            Ok(state) => state,
+            Err(_) => return 100,
+        };
+        let callback = match state.callback {
+            Some(cb) => cb,
+            None => return 100,
+        };
+        if state.last_sample.is_some_and(|t| t.elapsed() < GOVERNOR_SAMPLE_INTERVAL) {
+            return state.percent;
+        }
+        // Claim this sample so concurrent loops don't call the governor too
+        state.last_sample = Some(Instant::now());
+        (callback, state.user_data, state.generation)
+    };
+
+    let percent = callback(user_data as *mut c_void).min(100);
+
+    match SPEED_GOVERNOR.lock() {
+        Ok(mut state) if state.generation == generation => {
+            state.percent = percent;
+            percent
+        }
+        Ok(state) => state.percent,
+        Err(_) => 100,
+    }
+}
+
+/// Sleep for `duration`, waking up regularly to check the cancel flag
+///
+/// Returns false if the operation was cancelled while waiting.
+fn sleep_cancellable(duration: Duration, cancel_flag: *const AtomicBool) -> bool {
+    let deadline = Instant::now() + duration;
+    loop {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return false;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        std::thread::sleep(remaining.min(PAUSE_POLL_INTERVAL));
+    }
+}
+
+/// Paces a chunk loop according to the speed governor and an optional rate limit
+pub struct TransferPacer {
+    /// Explicit limit in bytes per second (0 for unlimited)
+    rate_limit: u64,
+    /// End of the previous pace() call; the time since then is the chunk's work time
+    last_mark: Instant,
+}
+
+impl TransferPacer {
+    pub fn new() -> Self {
+        Self {
+            rate_limit: 0,
+            last_mark: Instant::now(),
+        }
+    }
+
+    /// Set the explicit rate limit in bytes per second (0 for unlimited)
+    pub fn set_rate_limit(&mut self, bytes_per_sec: u64) {
+        self.rate_limit = bytes_per_sec;
+    }
+
+    /// Start timing a new run of chunks (e.g. at the beginning of a file)
+    pub fn reset(&mut self) {
+        self.last_mark = Instant::now();
+    }
+
+    /// Account for `bytes` just transferred and sleep as needed
+    ///
+    /// At N% the loop sleeps so the chunk takes 100/N times its work time; with a
+    /// rate limit it also sleeps until the chunk fits the limit. The longer of the
+    /// two delays wins, i.e. the lower of the two speeds. A governor at 0% blocks
+    /// until it reports a positive value again.
+    ///
+    /// Returns false if the operation was cancelled while waiting.
+    pub fn pace(&mut self, bytes: usize, cancel_flag: *const AtomicBool) -> bool {
+        let work = self.last_mark.elapsed();
+
+        let mut percent = governor_percent();
+        while percent == 0 {
+            if !sleep_cancellable(PAUSE_POLL_INTERVAL, cancel_flag) {
+                return false;
+            }
+            percent = governor_percent();
+        }
+
+        let completed = sleep_cancellable(self.delay(bytes, work, percent), cancel_flag);
+        self.last_mark = Instant::now();
+        completed
+    }
+
+    /// How long to sleep after `bytes` took `work` to transfer at `percent` speed
+    fn delay(&self, bytes: usize, work: Duration, percent: u32) -> Duration {
+        let governor_delay = if percent < 100 {
+            work.mul_f64(100.0 / percent as f64 - 1.0)
+        } else {
+            Duration::ZERO
+        };
+        let limit_delay = if self.rate_limit > 0 {
+            Duration::from_secs_f64(bytes as f64 / self.rate_limit as f64).saturating_sub(work)
+        } else {
+            Duration::ZERO
+        };
+        governor_delay.max(limit_delay)
+    }
+}
+
+impl Default for TransferPacer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Install or clear the process-wide speed governor
+///
+/// The callback is sampled at most once per second by the copy, upload and
+/// download chunk loops. It returns the allowed speed in percent (values above
+/// 100 are treated as 100); 0 pauses transfers until it returns a positive value.
+///
+/// # Arguments
+/// * `callback` - Governor callback (null to remove the governor)
+/// * `user_data` - User data passed to the callback
+#[no_mangle]
+pub extern "C" fn set_speed_governor(callback: Option<SpeedGovernorCallback>, user_data: *mut c_void) {
+    if let Ok(mut state) = SPEED_GOVERNOR.lock() {
+        state.callback = callback;
+        state.user_data = user_data as usize;
+        state.generation += 1;
+        state.last_sample = None;
+        state.percent = 100;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use crate::unified_copy::{unified_copy_file, unified_copy_free, unified_copy_init};
+
+    static GOVERNOR_CALLS: AtomicU32 = AtomicU32::new(0);
+
+    extern "C" fn governor(user_data: *mut c_void) -> u32 {
+        GOVERNOR_CALLS.fetch_add(1, Ordering::SeqCst);
+        unsafe { *(user_data as *const u32) }
+    }
+
+    struct MemoryCopy {
+        source: Vec<u8>,
+        dest: Vec<u8>,
+    }
+
+    extern "C" fn read_chunk(buffer: *mut u8, buffer_size: usize, offset: u64, user_data: *mut c_void) -> isize {
+        let copy = unsafe { &*(user_data as *const MemoryCopy) };
+        // Simulate a slow source so there is measurable work to scale
+        std::thread::sleep(Duration::from_millis(4));
+        let start = offset as usize;
+        let n = buffer_size.min(copy.source.len() - start);
+        unsafe { std::ptr::copy_nonoverlapping(copy.source[start..].as_ptr(), buffer, n) };
+        n as isize
+    }
+
+    extern "C" fn write_chunk(data: *const u8, data_len: usize, _offset: u64, user_data: *mut c_void) -> i32 {
+        let copy = unsafe { &mut *(user_data as *mut MemoryCopy) };
+        copy.dest.extend_from_slice(unsafe { std::slice::from_raw_parts(data, data_len) });
+        0
+    }
+
+    fn timed_memory_copy() -> Duration {
+        let chunk = 64 * 1024;
+        let mut copy = MemoryCopy { source: vec![3u8; chunk * 25], dest: Vec::new() };
+        let mut buffer = vec![0u8; chunk];
+        let ctx = unified_copy_init(copy.source.len() as u64, 1, chunk, std::ptr::null());
+
+        let start = Instant::now();
+        let result = unified_copy_file(
+            ctx, buffer.as_mut_ptr(), chunk, copy.source.len() as u64,
+            Some(read_chunk), Some(write_chunk), None, &mut copy as *mut MemoryCopy as *mut c_void,
+        );
+        let elapsed = start.elapsed();
+
+        unified_copy_free(ctx);
+        assert_eq!(result, 0);
+        assert_eq!(copy.dest, copy.source);
+        elapsed
+    }
+
+    #[test]
+    fn test_governor_scales_copy_speed() {
+        // At N% a chunk's work time is stretched to 100/N times
+        let work = Duration::from_millis(10);
+        let pacer = TransferPacer::new();
+        assert_eq!(pacer.delay(64 * 1024, work, 100), Duration::ZERO);
+        assert_eq!(pacer.delay(64 * 1024, work, 50), work);
+        assert_eq!(pacer.delay(64 * 1024, work, 25), work * 3);
+
+        // With a rate limit the lower of the two speeds wins
+        let mut pacer = TransferPacer::new();
+        pacer.set_rate_limit(1024 * 1024);
+        assert_eq!(pacer.delay(64 * 1024, work, 100), Duration::from_micros(62_500) - work);
+        assert_eq!(pacer.delay(64 * 1024, work, 10), work * 9);
+
+        // The copy loop consults the governor, sampling it at most once per second
+        let mut percent: u32 = 50;
+        set_speed_governor(Some(governor), &mut percent as *mut u32 as *mut c_void);
+        GOVERNOR_CALLS.store(0, Ordering::SeqCst);
+        let elapsed = timed_memory_copy();
+        let calls = GOVERNOR_CALLS.load(Ordering::SeqCst);
+        assert_eq!(governor_percent(), 50);
+        set_speed_governor(None, std::ptr::null_mut());
+        assert_eq!(governor_percent(), 100);
+
+        assert!(calls >= 1);
+        assert!(calls as u64 <= elapsed.as_secs() + 1, "{} samples in {:?}", calls, elapsed);
+
+        // The callback runs without the lock held, so it may query the governor itself
+        set_speed_governor(Some(reentrant_governor), std::ptr::null_mut());
+        assert_eq!(governor_percent(), 30);
+        set_speed_governor(None, std::ptr::null_mut());
+    }
+
+    extern "C" fn reentrant_governor(_user_data: *mut c_void) -> u32 {
+        // Still the previous value: this sample is in progress
+        assert_eq!(governor_percent(), 100);
+        30
+    }
+
+    #[test]
+    fn test_pacer_rate_limit() {
+        let mut pacer = TransferPacer::new();
+        pacer.set_rate_limit(1024 * 1024);
+        let start = Instant::now();
+        for _ in 0..4 {
+            assert!(pacer.pace(64 * 1024, std::ptr::null()));
+        }
+        // 256KB at 1MB/s
+        assert!(start.elapsed() >= Duration::from_millis(240));
+
+        // Cancellation interrupts the wait
+        let cancel = AtomicBool::new(true);
+        pacer.set_rate_limit(1);
+        assert!(!pacer.pace(1024, &cancel));
+    }
+}
@@ -1,14 +1,24 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit},
     Aes256Gcm, Nonce,
 };
 use pbkdf2::pbkdf2_hmac;
-use rand::RngCore;
+use rng::fill_random;
 use sha2::Sha256;
 use std::ffi::{c_char, c_void, CStr};
 use std::os::raw::c_int;
 use std::ptr;
 use std::slice;
+use std::sync::atomic::AtomicBool;
+
+// Include error code registry module
+mod errors;
+pub use errors::*;
+
+// Include randomness source module
+mod rng;
+#[cfg(feature = "insecure-test-rng")]
+pub use rng::{clear_insecure_test_rng, set_insecure_test_rng};
 
 // Include the encryption module (re-export for consistency)
 mod encryption;
@@ -30,6 +40,14 @@ pub use file_io::*;
 mod upload;
 pub use upload::*;
 
+// Include chunk deduplication module
+mod dedup;
+pub use dedup::*;
+
+// Include folder upload orchestration module
+mod folder_upload;
+pub use folder_upload::*;
+
 // Include download module
 mod download;
 pub use download::*;
@@ -42,6 +60,123 @@ pub use copy::*;
 mod unified_copy;
 pub use unified_copy::*;
 
+// Include encrypted thumbnail sidecar module
+mod thumbnail;
+pub use thumbnail::*;
+
+// Include ZIP archive module
+mod archive;
+pub use archive::*;
+
+// Include trash/undo module
+mod trash;
+pub use trash::*;
+
+// Include temp file management module
+mod temp;
+pub use temp::*;
+
+// Include master key vault module
+mod vault;
+pub use vault::*;
+
+// Include transfer speed governor module
+mod governor;
+pub use governor::*;
+
+// Include source file reader module
+mod source_reader;
+pub use source_reader::*;
+
+// Include encrypt-while-copying module
+mod encrypt_copy;
+pub use encrypt_copy::*;
+
+// Include active operation registry module
+mod operations;
+pub use operations::*;
+
+// Include transfer queue module
+mod transfer_queue;
+pub use transfer_queue::*;
+
+// Include folder tree digest module
+mod tree_digest;
+pub use tree_digest::*;
+
+// Include FEK escrow module
+mod escrow;
+pub use escrow::*;
+
+// Include shared FFI JSON envelope module
+mod ffi_util;
+pub use ffi_util::*;
+
+// Include image metadata stripping module
+mod metadata_strip;
+pub use metadata_strip::*;
+
+// Include base64/hex codec module
+mod codec;
+pub use codec::*;
+
+// Include native runtime worker pool module
+mod runtime;
+pub use runtime::*;
+
+// Include container layout debugging module
+mod container_debug;
+pub use container_debug::*;
+
+// Include polled progress event stream module
+mod progress_events;
+pub use progress_events::*;
+
+// Include encrypted container split/join module
+mod container_split;
+pub use container_split::*;
+
+// Include phased progress reporting module
+mod phase_progress;
+pub use phase_progress::*;
+
+// Include C ABI export registry module
+mod abi;
+pub use abi::*;
+
+// Include operation metrics module
+mod metrics;
+pub use metrics::*;
+use metrics::Counter;
+
+// Include file name ordering module
+mod name_order;
+pub use name_order::*;
+
+// Include download quarantine module
+mod quarantine;
+pub use quarantine::*;
+
+// Include container metadata module
+mod container_metadata;
+pub use container_metadata::*;
+
+// Include destination filesystem limits module
+mod dest_fs;
+pub use dest_fs::*;
+
+// Include open file handle limit module
+mod open_files;
+pub use open_files::*;
+
+// Include extended attribute copy module
+mod xattrs;
+pub use xattrs::*;
+
+// Include shared unit test fixtures
+#[cfg(test)]
+mod test_util;
+
 // Constants
 const MAGIC: u32 = 0x434E4552; // "CNER"
 const VERSION: u8 = 1;
@@ -49,17 +184,55 @@ const NONCE_SIZE: usize = 12;
 const MAC_SIZE: usize = 16;
 const KEY_SIZE: usize = 32;
 const HEADER_SIZE: usize = 4 + 1 + 3 + 4; // magic + version + reserved + fek_length
+const WRAPPED_FEK_SIZE: usize = NONCE_SIZE + KEY_SIZE + MAC_SIZE; // wrap_key output for a FEK
 const CHUNK_HEADER_SIZE: usize = 4 + 4 + 12 + 16; // index + size + nonce + mac
 const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024; // 1MB chunks
 
-// Error codes
-const SUCCESS: c_int = 0;
-const ERROR_NULL_POINTER: c_int = -1;
-const ERROR_INVALID_KEY_SIZE: c_int = -2;
-const ERROR_ENCRYPTION_FAILED: c_int = -3;
-const ERROR_DECRYPTION_FAILED: c_int = -4;
-const ERROR_INVALID_FORMAT: c_int = -5;
-const ERROR_ALLOCATION_FAILED: c_int = -6;
+/// Encryption mode of upload_init_v2/download_init_v2: plain transfer, any key is ignored
+pub const ENCRYPTION_MODE_NONE: i32 = 0;
+/// Encryption mode: encrypt (decrypt) with the master key, which must be 32 bytes
+pub const ENCRYPTION_MODE_REQUIRED: i32 = 1;
+/// Encryption mode: encrypt (decrypt) when a master key is given, plain when the
+/// key is null or empty; a key of any other length is still rejected
+pub const ENCRYPTION_MODE_OPTIONAL: i32 = 2;
+
+/// Encryption mode of the legacy `should_encrypt`/`should_decrypt` flags
+pub(crate) fn legacy_encryption_mode(flag: i32) -> i32 {
+    if flag == 1 { ENCRYPTION_MODE_REQUIRED } else { ENCRYPTION_MODE_NONE }
+}
+
+/// Copy the master key a transfer in `mode` uses; empty for a plain transfer
+///
+/// Fails with ERROR_MASTER_KEY_REQUIRED when encryption is requested and the
+/// key is missing or truncated, so such a transfer never silently runs in plaintext.
+pub(crate) fn master_key_for_mode(master_key: *const u8, master_key_len: usize, mode: i32) -> Result<Vec<u8>, c_int> {
+    let has_key = !master_key.is_null() && master_key_len == KEY_SIZE;
+    let no_key = master_key.is_null() || master_key_len == 0;
+    match mode {
+        ENCRYPTION_MODE_NONE => Ok(Vec::new()),
+        ENCRYPTION_MODE_REQUIRED | ENCRYPTION_MODE_OPTIONAL if has_key => {
+            Ok(unsafe { slice::from_raw_parts(master_key, KEY_SIZE) }.to_vec())
+        }
+        ENCRYPTION_MODE_OPTIONAL if no_key => Ok(Vec::new()),
+        ENCRYPTION_MODE_REQUIRED | ENCRYPTION_MODE_OPTIONAL => Err(ERROR_MASTER_KEY_REQUIRED),
+        _ => Err(ERROR_INVALID_ENCRYPTION_MODE),
+    }
+}
+
+/// Last-error detail for a master_key_for_mode failure
+pub(crate) fn master_key_error(code: c_int) -> ErrorEnvelope {
+    if code == ERROR_MASTER_KEY_REQUIRED {
+        ErrorEnvelope::new(code, format!("encryption needs a {}-byte master key", KEY_SIZE)).with_context("master_key")
+    } else {
+        ErrorEnvelope::new(code, "unknown encryption mode").with_context("encryption_mode")
+    }
+}
+
+/// Largest FEK region accepted from a main header (wrapped key plus extension
+/// sections; room for an escrow section and the largest metadata section)
+pub const MAX_FEK_REGION_LENGTH: usize = 8 * 1024;
+/// Largest chunk size field accepted from a chunk header (ciphertext plus MAC)
+pub const MAX_CHUNK_SIZE_FIELD: usize = 64 * 1024 * 1024;
 
 // ============================================================================
 // TRUE STREAMING ENCRYPTION CONTEXTS
@@ -73,6 +246,9 @@ pub struct EncryptionContext {
     wrapped_fek: Vec<u8>,
     header: [u8; HEADER_SIZE],
     chunk_index: u32,
+    chunk_crc: bool,
+    /// Set by the first encrypt_chunk; the header and FEK region are final from then on
+    chunks_started: bool,
 }
 
 /// Decryption context for streaming decryption
@@ -80,6 +256,7 @@ pub struct EncryptionContext {
 #[repr(C)]
 pub struct DecryptionContext {
     fek: Vec<u8>,
+    /// Index the next chunk must carry; only decrypt_chunk_strict reads and advances it
     chunk_index: u32,
 }
 
@@ -119,7 +296,7 @@ pub extern "C" fn encrypt_data(
 
     // Generate nonce
     let mut nonce_bytes = [0u8; NONCE_SIZE];
-    OsRng.fill_bytes(&mut nonce_bytes);
+    fill_random(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
 
     // Encrypt
@@ -127,6 +304,7 @@ pub extern "C" fn encrypt_data(
         Ok(ct) => ct,
         Err(_) => return ptr::null_mut(),
     };
+    metrics::count(Counter::BytesEncrypted, data_slice.len() as u64);
 
     // Allocate output buffer: nonce + ciphertext
     let output_size = NONCE_SIZE + ciphertext.len();
@@ -197,8 +375,12 @@ pub extern "C" fn decrypt_data(
     // Decrypt
     let plaintext = match cipher.decrypt(nonce, ciphertext.as_ref()) {
         Ok(pt) => pt,
-        Err(_) => return ptr::null_mut(),
+        Err(_) => {
+            metrics::count_decrypt(Err(ERROR_DECRYPTION_FAILED));
+            return ptr::null_mut();
+        }
     };
+    metrics::count(Counter::BytesDecrypted, plaintext.len() as u64);
 
     // Allocate output buffer
     let output = unsafe {
@@ -263,13 +445,14 @@ pub extern "C" fn encrypt_file_with_fek(
     // Encrypt file content with FEK
     let cipher = Aes256Gcm::new_from_slice(fek_slice).unwrap();
     let mut nonce_bytes = [0u8; NONCE_SIZE];
-    OsRng.fill_bytes(&mut nonce_bytes);
+    fill_random(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
 
     let encrypted_content = match cipher.encrypt(nonce, file_slice.as_ref()) {
         Ok(ct) => ct,
         Err(_) => return ptr::null_mut(),
     };
+    metrics::count(Counter::BytesEncrypted, file_slice.len() as u64);
 
     // Build header
     let header = build_header(wrapped_fek.len() as u32);
@@ -367,7 +550,7 @@ pub extern "C" fn decrypt_file_with_fek(
     let wrapped_fek = &encrypted_slice[HEADER_SIZE..HEADER_SIZE + fek_length];
 
     // Unwrap FEK
-    let fek = match unwrap_key(wrapped_fek, master_key_slice) {
+    let fek = match unwrap_fek(encrypted_slice, wrapped_fek, master_key_slice) {
         Ok(key) => key,
         Err(_) => return ptr::null_mut(),
     };
@@ -384,8 +567,12 @@ pub extern "C" fn decrypt_file_with_fek(
     let cipher = Aes256Gcm::new_from_slice(&fek).unwrap();
     let plaintext = match cipher.decrypt(nonce, encrypted_content.as_ref()) {
         Ok(pt) => pt,
-        Err(_) => return ptr::null_mut(),
+        Err(_) => {
+            metrics::count_decrypt(Err(ERROR_DECRYPTION_FAILED));
+            return ptr::null_mut();
+        }
     };
+    metrics::count(Counter::BytesDecrypted, plaintext.len() as u64);
 
     // Allocate output buffer
     let output = unsafe {
@@ -464,7 +651,7 @@ pub extern "C" fn free_buffer(buffer: *mut u8) {
 fn wrap_key(key: &[u8], master_key: &[u8]) -> Vec<u8> {
     let cipher = Aes256Gcm::new_from_slice(master_key).unwrap();
     let mut nonce_bytes = [0u8; NONCE_SIZE];
-    OsRng.fill_bytes(&mut nonce_bytes);
+    fill_random(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
 
     match cipher.encrypt(nonce, key.as_ref()) {
@@ -490,6 +677,21 @@ fn unwrap_key(wrapped_key: &[u8], master_key: &[u8]) -> Result<Vec<u8>, ()> {
     cipher.decrypt(nonce, ciphertext.as_ref()).map_err(|_| ())
 }
 
+/// Unwrap the FEK from a container's FEK region
+///
+/// With HEADER_FLAG_EXTENSIONS set in `header`, the region holds the wrapped FEK
+/// followed by extension sections; otherwise it must be exactly one wrapped FEK.
+fn unwrap_fek(header: &[u8], fek_region: &[u8], master_key: &[u8]) -> Result<Vec<u8>, ()> {
+    let wrapped_fek = if header.get(HEADER_FLAGS_OFFSET).is_some_and(|flags| flags & HEADER_FLAG_EXTENSIONS != 0) {
+        fek_region.get(..WRAPPED_FEK_SIZE).ok_or(())?
+    } else if fek_region.len() == WRAPPED_FEK_SIZE {
+        fek_region
+    } else {
+        return Err(());
+    };
+    unwrap_key(wrapped_fek, master_key)
+}
+
 fn build_header(fek_length: u32) -> [u8; HEADER_SIZE] {
     let mut header = [0u8; HEADER_SIZE];
     
@@ -507,14 +709,22 @@ fn build_header(fek_length: u32) -> [u8; HEADER_SIZE] {
     header
 }
 
-fn parse_header(header: &[u8]) -> Result<(u32, u8, usize), ()> {
+/// Parse the main header into (magic, version, fek_length)
+///
+/// Returns ERROR_INVALID_FORMAT for a short header and ERROR_MALFORMED_CONTAINER
+/// when the FEK region is larger than MAX_FEK_REGION_LENGTH, so callers never
+/// slice or allocate a length taken from an untrusted header.
+fn parse_header(header: &[u8]) -> Result<(u32, u8, usize), c_int> {
     if header.len() < HEADER_SIZE {
-        return Err(());
+        return Err(ERROR_INVALID_FORMAT);
     }
 
     let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
     let version = header[4];
     let fek_length = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+    if fek_length > MAX_FEK_REGION_LENGTH {
+        return Err(ERROR_MALFORMED_CONTAINER);
+    }
 
     Ok((magic, version, fek_length))
 }
@@ -570,46 +780,204 @@ pub extern "C" fn encrypt_file_streaming(
     progress_callback: Option<ProgressCallback>,
     user_data: *mut c_void,
 ) -> *mut u8 {
+    encrypt_file_streaming_ex(file_data, file_len, master_key, master_key_len, output_len,
+                              progress_callback, user_data, ptr::null(), ptr::null_mut())
+}
+
+/// Encrypt a file using streaming encryption, with cancellation
+///
+/// Same as encrypt_file_streaming, but checks `cancel_flag` before every chunk,
+/// so setting it from the progress callback stops the call before the next one.
+/// A cancelled call frees everything it produced, returns null and records
+/// ERROR_CANCELLED as the thread's last error (get_last_error_json).
+///
+/// # Arguments
+/// * `file_data` - Pointer to file data to encrypt
+/// * `file_len` - Length of file data
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `output_len` - Pointer to store output length
+/// * `progress_callback` - Optional progress callback (can be null)
+/// * `user_data` - User data to pass to progress callback
+/// * `cancel_flag` - Pointer to atomic bool for cancellation (can be null)
+/// * `status_out` - Optional pointer receiving 0, ERROR_CANCELLED or another negative error code
+///
+/// # Returns
+/// Pointer to encrypted file data (caller must free with free_buffer), or null on failure
+#[no_mangle]
+pub extern "C" fn encrypt_file_streaming_ex(
+    file_data: *const u8,
+    file_len: usize,
+    master_key: *const u8,
+    master_key_len: usize,
+    output_len: *mut usize,
+    progress_callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+    cancel_flag: *const AtomicBool,
+    status_out: *mut c_int,
+) -> *mut u8 {
+    let result = encrypt_file_streaming_impl(file_data, file_len, master_key, master_key_len, output_len,
+                                             progress_callback, user_data, cancel_flag);
+    streaming_result(result, status_out)
+}
+
+/// Report the status of a streaming call and unwrap its output pointer
+///
+/// A cancelled call and a chunk CRC mismatch are also recorded as the thread's
+/// last error, so callers that pass no `status_out` can tell them from other failures.
+fn streaming_result(result: Result<*mut u8, c_int>, status_out: *mut c_int) -> *mut u8 {
+    let (output, status) = match result {
+        Ok(output) => (output, SUCCESS),
+        Err(ERROR_CANCELLED) => {
+            set_last_error_detail(ErrorEnvelope::new(ERROR_CANCELLED, "cancelled"));
+            (ptr::null_mut(), ERROR_CANCELLED)
+        }
+        Err(ERROR_CHUNK_CRC_MISMATCH) => {
+            set_last_error_detail(ErrorEnvelope::new(ERROR_CHUNK_CRC_MISMATCH, "chunk CRC32C does not match its ciphertext"));
+            (ptr::null_mut(), ERROR_CHUNK_CRC_MISMATCH)
+        }
+        Err(code) => (ptr::null_mut(), code),
+    };
+    if !status_out.is_null() {
+        unsafe { *status_out = status; }
+    }
+    output
+}
+
+#[allow(clippy::too_many_arguments)]
+fn encrypt_file_streaming_impl(
+    file_data: *const u8,
+    file_len: usize,
+    master_key: *const u8,
+    master_key_len: usize,
+    output_len: *mut usize,
+    progress_callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+    cancel_flag: *const AtomicBool,
+) -> Result<*mut u8, c_int> {
     if file_data.is_null() || master_key.is_null() || output_len.is_null() {
-        return ptr::null_mut();
+        return Err(ERROR_NULL_POINTER);
     }
 
     if master_key_len != KEY_SIZE {
-        return ptr::null_mut();
+        return Err(ERROR_INVALID_KEY_SIZE);
+    }
+
+    let file_slice = unsafe { slice::from_raw_parts(file_data, file_len) };
+    let master_key_slice = unsafe { slice::from_raw_parts(master_key, master_key_len) };
+
+    // Nothing is allocated for a call cancelled before it starts
+    if unsafe { is_cancelled(cancel_flag) } {
+        return Err(ERROR_CANCELLED);
+    }
+
+    // The container size is known up front, so chunks go straight into the output
+    let mut output = MallocOutput::new(streaming_encrypted_size(file_len))?;
+    encrypt_stream_to(file_slice, master_key_slice, progress_callback, user_data, cancel_flag,
+                      &mut |data| output.write(data))?;
+
+    unsafe {
+        *output_len = output.len;
+    }
+
+    Ok(output.into_raw())
+}
+
+/// Encrypt a file using streaming encryption, handing the output to a sink
+///
+/// Produces the same container as encrypt_file_streaming without holding it in
+/// memory: the main header and wrapped FEK, then each chunk record, are passed
+/// to `sink` as soon as they are produced. Only one chunk is in memory at a time.
+///
+/// # Arguments
+/// * `file_data` - Pointer to file data to encrypt
+/// * `file_len` - Length of file data
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `sink` - Receives the container bytes in order; returns 0 to continue
+/// * `progress_callback` - Optional progress callback (can be null)
+/// * `cancel_flag` - Pointer to atomic bool for cancellation (can be null)
+/// * `user_data` - User data passed to the sink and progress callback
+///
+/// # Returns
+/// Total bytes passed to the sink, ERROR_SINK_ABORTED if the sink returned
+/// non-zero, ERROR_CANCELLED, or another negative error code
+#[allow(clippy::too_many_arguments, clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn encrypt_file_streaming_cb(
+    file_data: *const u8,
+    file_len: usize,
+    master_key: *const u8,
+    master_key_len: usize,
+    sink: Option<StreamSinkCallback>,
+    progress_callback: Option<ProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i64 {
+    let sink = match sink {
+        Some(sink) => sink,
+        None => return ERROR_NULL_POINTER as i64,
+    };
+    if file_data.is_null() || master_key.is_null() {
+        return ERROR_NULL_POINTER as i64;
+    }
+    if master_key_len != KEY_SIZE {
+        return ERROR_INVALID_KEY_SIZE as i64;
     }
 
     let file_slice = unsafe { slice::from_raw_parts(file_data, file_len) };
     let master_key_slice = unsafe { slice::from_raw_parts(master_key, master_key_len) };
+    let result = encrypt_stream_to(file_slice, master_key_slice, progress_callback, user_data, cancel_flag,
+                                   &mut |data| call_sink(sink, data, user_data));
+    match result {
+        Ok(total) => total as i64,
+        Err(code) => code as i64,
+    }
+}
 
+/// Encrypt `file` chunk by chunk, passing the main header and wrapped FEK and
+/// then each chunk record to `sink` as soon as it is produced
+///
+/// Returns the number of bytes passed to `sink`.
+fn encrypt_stream_to(
+    file: &[u8],
+    master_key: &[u8],
+    progress_callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+    cancel_flag: *const AtomicBool,
+    sink: &mut dyn FnMut(&[u8]) -> Result<(), c_int>,
+) -> Result<usize, c_int> {
     // Generate and wrap File Encryption Key (FEK)
     let mut fek = [0u8; KEY_SIZE];
-    OsRng.fill_bytes(&mut fek);
-    let wrapped_fek = wrap_key(&fek, master_key_slice);
+    fill_random(&mut fek);
+    let wrapped_fek = wrap_key(&fek, master_key);
     if wrapped_fek.is_empty() {
-        return ptr::null_mut();
+        return Err(ERROR_ENCRYPTION_FAILED);
     }
 
-    // Build main header
-    let main_header = build_header(wrapped_fek.len() as u32);
+    // Main header and wrapped FEK
+    let mut prefix = build_header(wrapped_fek.len() as u32).to_vec();
+    prefix.extend_from_slice(&wrapped_fek);
+    sink(&prefix)?;
+    let mut total = prefix.len();
 
     // Encrypt file in chunks
-    let mut chunks: Vec<Vec<u8>> = Vec::new();
-    let mut total_encrypted_size = 0;
+    let file_len = file.len();
     let mut chunk_index: u32 = 0;
-
     let mut offset = 0;
     while offset < file_len {
+        // Check cancellation once per chunk
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err(ERROR_CANCELLED);
+        }
+
         let chunk_end = std::cmp::min(offset + DEFAULT_CHUNK_SIZE, file_len);
-        let chunk_data = &file_slice[offset..chunk_end];
 
         // Encrypt chunk with incrementing index
-        match encrypt_chunk_impl(chunk_data, &fek, chunk_index) {
-            Some(encrypted_chunk) => {
-                total_encrypted_size += encrypted_chunk.len();
-                chunks.push(encrypted_chunk);
-            }
-            None => return ptr::null_mut(),
-        }
+        let encrypted_chunk = encrypt_chunk_impl(&file[offset..chunk_end], &fek, chunk_index, false)
+            .ok_or(ERROR_ENCRYPTION_FAILED)?;
+        sink(&encrypted_chunk)?;
+        total += encrypted_chunk.len();
 
         // Call progress callback if provided
         if let Some(callback) = progress_callback {
@@ -620,40 +988,67 @@ pub extern "C" fn encrypt_file_streaming(
         offset = chunk_end;
     }
 
-    // Calculate total output size
-    let total_size = HEADER_SIZE + wrapped_fek.len() + total_encrypted_size;
+    Ok(total)
+}
 
-    // Allocate output buffer
-    let output = unsafe {
-        let ptr = libc::malloc(total_size) as *mut u8;
-        if ptr.is_null() {
-            return ptr::null_mut();
-        }
-        ptr
-    };
+/// Size of the container encrypt_file_streaming produces for `file_len` bytes
+fn streaming_encrypted_size(file_len: usize) -> usize {
+    let chunks = file_len.div_ceil(DEFAULT_CHUNK_SIZE);
+    HEADER_SIZE + NONCE_SIZE + KEY_SIZE + MAC_SIZE + file_len + chunks * CHUNK_HEADER_SIZE
+}
 
-    // Copy main header
-    let mut write_offset = 0;
-    unsafe {
-        ptr::copy_nonoverlapping(main_header.as_ptr(), output.add(write_offset), HEADER_SIZE);
-        write_offset += HEADER_SIZE;
+/// Sink receiving the output of the `_cb` streaming functions
+/// Parameters: data, data length, user_data; returns 0 to continue, anything
+/// else aborts the call with ERROR_SINK_ABORTED
+pub type StreamSinkCallback = extern "C" fn(data: *const u8, data_len: usize, user_data: *mut c_void) -> i32;
+
+fn call_sink(sink: StreamSinkCallback, data: &[u8], user_data: *mut c_void) -> Result<(), c_int> {
+    match sink(data.as_ptr(), data.len(), user_data) {
+        0 => Ok(()),
+        _ => Err(ERROR_SINK_ABORTED),
+    }
+}
+
+/// libc-allocated output buffer of a known capacity, filled by a streaming sink
+///
+/// Owns the allocation until into_raw, so an error or cancellation frees it.
+struct MallocOutput {
+    ptr: *mut u8,
+    capacity: usize,
+    len: usize,
+}
 
-        // Copy wrapped FEK
-        ptr::copy_nonoverlapping(wrapped_fek.as_ptr(), output.add(write_offset), wrapped_fek.len());
-        write_offset += wrapped_fek.len();
+impl MallocOutput {
+    fn new(capacity: usize) -> Result<Self, c_int> {
+        let ptr = unsafe { libc::malloc(capacity) as *mut u8 };
+        if ptr.is_null() {
+            return Err(ERROR_BUFFER_ALLOC_FAILED);
+        }
+        Ok(MallocOutput { ptr, capacity, len: 0 })
+    }
 
-        // Copy all chunks
-        for chunk in &chunks {
-            ptr::copy_nonoverlapping(chunk.as_ptr(), output.add(write_offset), chunk.len());
-            write_offset += chunk.len();
+    /// Append `data`; the capacity comes from the container, so running past it
+    /// means the container is inconsistent
+    fn write(&mut self, data: &[u8]) -> Result<(), c_int> {
+        if data.len() > self.capacity - self.len {
+            return Err(ERROR_INVALID_FORMAT);
         }
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(self.len), data.len()); }
+        self.len += data.len();
+        Ok(())
     }
 
-    unsafe {
-        *output_len = total_size;
+    fn into_raw(self) -> *mut u8 {
+        let ptr = self.ptr;
+        std::mem::forget(self);
+        ptr
     }
+}
 
-    output
+impl Drop for MallocOutput {
+    fn drop(&mut self) {
+        unsafe { libc::free(self.ptr as *mut c_void); }
+    }
 }
 
 /// Decrypt a file encrypted with streaming encryption (Option 2)
@@ -679,183 +1074,546 @@ pub extern "C" fn decrypt_file_streaming(
     progress_callback: Option<ProgressCallback>,
     user_data: *mut c_void,
 ) -> *mut u8 {
+    decrypt_file_streaming_ex(encrypted_data, encrypted_len, master_key, master_key_len, output_len,
+                              progress_callback, user_data, ptr::null(), ptr::null_mut())
+}
+
+/// Decrypt a file encrypted with streaming encryption, with cancellation
+///
+/// Same as decrypt_file_streaming, but checks `cancel_flag` before every chunk,
+/// so setting it from the progress callback stops the call before the next one.
+/// A cancelled call frees everything it produced, returns null and records
+/// ERROR_CANCELLED as the thread's last error (get_last_error_json).
+///
+/// # Arguments
+/// * `encrypted_data` - Pointer to encrypted file data
+/// * `encrypted_len` - Length of encrypted data
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `output_len` - Pointer to store output length
+/// * `progress_callback` - Optional progress callback (can be null)
+/// * `user_data` - User data to pass to progress callback
+/// * `cancel_flag` - Pointer to atomic bool for cancellation (can be null)
+/// * `status_out` - Optional pointer receiving 0, ERROR_CANCELLED, ERROR_MALFORMED_CONTAINER
+///   (out-of-range FEK region or chunk size, or chunks out of order) or another negative error code
+///
+/// # Returns
+/// Pointer to decrypted file data (caller must free with free_buffer), or null on failure
+#[no_mangle]
+pub extern "C" fn decrypt_file_streaming_ex(
+    encrypted_data: *const u8,
+    encrypted_len: usize,
+    master_key: *const u8,
+    master_key_len: usize,
+    output_len: *mut usize,
+    progress_callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+    cancel_flag: *const AtomicBool,
+    status_out: *mut c_int,
+) -> *mut u8 {
+    let result = decrypt_file_streaming_impl(encrypted_data, encrypted_len, master_key, master_key_len,
+                                             output_len, progress_callback, user_data, cancel_flag);
+    streaming_result(result, status_out)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn decrypt_file_streaming_impl(
+    encrypted_data: *const u8,
+    encrypted_len: usize,
+    master_key: *const u8,
+    master_key_len: usize,
+    output_len: *mut usize,
+    progress_callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+    cancel_flag: *const AtomicBool,
+) -> Result<*mut u8, c_int> {
     if encrypted_data.is_null() || master_key.is_null() || output_len.is_null() {
-        return ptr::null_mut();
+        return Err(ERROR_NULL_POINTER);
     }
 
     if master_key_len != KEY_SIZE {
-        return ptr::null_mut();
+        return Err(ERROR_INVALID_KEY_SIZE);
     }
 
-    if encrypted_len < HEADER_SIZE {
-        return ptr::null_mut();
+    let encrypted_slice = unsafe { slice::from_raw_parts(encrypted_data, encrypted_len) };
+    let master_key_slice = unsafe { slice::from_raw_parts(master_key, master_key_len) };
+
+    // Nothing is allocated for a call cancelled before it starts
+    if unsafe { is_cancelled(cancel_flag) } {
+        return Err(ERROR_CANCELLED);
+    }
+
+    // Size the output from the chunk headers so plaintext chunks go straight into it
+    let mut output = MallocOutput::new(streaming_plaintext_size(encrypted_slice))?;
+    decrypt_stream_to(encrypted_slice, master_key_slice, progress_callback, user_data, cancel_flag,
+                      &mut |data| output.write(data))?;
+
+    unsafe {
+        *output_len = output.len;
+    }
+
+    Ok(output.into_raw())
+}
+
+/// Decrypt a file encrypted with streaming encryption, handing the plaintext to a sink
+///
+/// Each chunk is decrypted and passed to `sink` before the next one is read,
+/// so only one chunk of plaintext is in memory at a time. Plaintext already
+/// passed to the sink is not taken back when a later chunk fails.
+///
+/// # Arguments
+/// * `encrypted_data` - Pointer to encrypted file data
+/// * `encrypted_len` - Length of encrypted data
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `sink` - Receives the plaintext in order; returns 0 to continue
+/// * `progress_callback` - Optional progress callback (can be null)
+/// * `cancel_flag` - Pointer to atomic bool for cancellation (can be null)
+/// * `user_data` - User data passed to the sink and progress callback
+///
+/// # Returns
+/// Total plaintext bytes passed to the sink, ERROR_SINK_ABORTED if the sink
+/// returned non-zero, ERROR_CANCELLED, ERROR_MALFORMED_CONTAINER or another
+/// negative error code
+#[allow(clippy::too_many_arguments, clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn decrypt_file_streaming_cb(
+    encrypted_data: *const u8,
+    encrypted_len: usize,
+    master_key: *const u8,
+    master_key_len: usize,
+    sink: Option<StreamSinkCallback>,
+    progress_callback: Option<ProgressCallback>,
+    cancel_flag: *const AtomicBool,
+    user_data: *mut c_void,
+) -> i64 {
+    let sink = match sink {
+        Some(sink) => sink,
+        None => return ERROR_NULL_POINTER as i64,
+    };
+    if encrypted_data.is_null() || master_key.is_null() {
+        return ERROR_NULL_POINTER as i64;
+    }
+    if master_key_len != KEY_SIZE {
+        return ERROR_INVALID_KEY_SIZE as i64;
     }
 
     let encrypted_slice = unsafe { slice::from_raw_parts(encrypted_data, encrypted_len) };
     let master_key_slice = unsafe { slice::from_raw_parts(master_key, master_key_len) };
+    let result = decrypt_stream_to(encrypted_slice, master_key_slice, progress_callback, user_data, cancel_flag,
+                                   &mut |data| call_sink(sink, data, user_data));
+    match result {
+        Ok(total) => total as i64,
+        Err(code) => code as i64,
+    }
+}
+
+/// Decrypt a streaming container chunk by chunk, passing each chunk's
+/// plaintext to `sink` as soon as it is decrypted
+///
+/// Returns the number of plaintext bytes passed to `sink`.
+fn decrypt_stream_to(
+    encrypted: &[u8],
+    master_key: &[u8],
+    progress_callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+    cancel_flag: *const AtomicBool,
+    sink: &mut dyn FnMut(&[u8]) -> Result<(), c_int>,
+) -> Result<usize, c_int> {
+    let encrypted_len = encrypted.len();
+    if encrypted_len < HEADER_SIZE {
+        return Err(ERROR_INVALID_FORMAT);
+    }
 
     // Parse main header
-    let (magic, version, fek_length) = match parse_header(&encrypted_slice[..HEADER_SIZE]) {
-        Ok(result) => result,
-        Err(_) => return ptr::null_mut(),
-    };
+    let (magic, version, fek_length) = parse_header(&encrypted[..HEADER_SIZE])?;
 
-    // Validate magic and version
-    if magic != MAGIC || version != VERSION {
-        return ptr::null_mut();
+    // Validate magic and version (reference records of dedup containers can't be
+    // decrypted here)
+    if magic != MAGIC || !format_version_supported(version) || version == FORMAT_VERSION_DEDUP {
+        return Err(ERROR_INVALID_FORMAT);
     }
 
     // Validate total size
     if encrypted_len < HEADER_SIZE + fek_length {
-        return ptr::null_mut();
+        return Err(ERROR_INVALID_FORMAT);
     }
 
-    // Extract wrapped FEK
-    let wrapped_fek = &encrypted_slice[HEADER_SIZE..HEADER_SIZE + fek_length];
-
-    // Unwrap FEK
-    let fek = match unwrap_key(wrapped_fek, master_key_slice) {
+    // Extract and unwrap FEK
+    let wrapped_fek = &encrypted[HEADER_SIZE..HEADER_SIZE + fek_length];
+    let fek = match unwrap_fek(encrypted, wrapped_fek, master_key) {
         Ok(key) => key,
-        Err(_) => return ptr::null_mut(),
+        Err(_) => return Err(ERROR_DECRYPTION_FAILED),
     };
 
     // Decrypt chunks
-    let mut plaintext_chunks: Vec<Vec<u8>> = Vec::new();
-    let mut total_plaintext_size = 0;
     let mut offset = HEADER_SIZE + fek_length;
     let mut total_decrypted_bytes = 0;
 
     while offset < encrypted_len {
-        // Check if we have enough data for chunk header
-        if offset + 20 > encrypted_len {
-            return ptr::null_mut();
+        // Check cancellation once per chunk
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err(ERROR_CANCELLED);
         }
 
-        // Read chunk header to get chunk size
-        let chunk_size = u32::from_le_bytes([
-            encrypted_slice[offset + 4],
-            encrypted_slice[offset + 5],
-            encrypted_slice[offset + 6],
-            encrypted_slice[offset + 7],
-        ]) as usize;
+        // Read chunk header to get the chunk's header and content sizes
+        let (chunk_header_len, _stored_crc, chunk_size) = match parse_chunk_header(&encrypted[offset..]) {
+            Some(header) => header,
+            None => return Err(ERROR_INVALID_FORMAT),
+        };
 
         // Check if we have enough data for the entire chunk
-        if offset + 20 + chunk_size > encrypted_len {
-            return ptr::null_mut();
+        let chunk_end = offset
+            .checked_add(chunk_record_len(chunk_header_len, chunk_size)?)
+            .ok_or(ERROR_MALFORMED_CONTAINER)?;
+        if chunk_end > encrypted_len {
+            return Err(ERROR_INVALID_FORMAT);
         }
 
         // Pass only this chunk to decrypt_chunk_impl
-        let chunk_data = &encrypted_slice[offset..offset + 20 + chunk_size];
-        match decrypt_chunk_impl(chunk_data, &fek) {
-            Some((plaintext, _chunk_len)) => {
-                let plaintext_len = plaintext.len();
-                total_plaintext_size += plaintext_len;
-                plaintext_chunks.push(plaintext);
-                offset += 20 + chunk_size;
-                
-                // Call progress callback if provided
-                if let Some(callback) = progress_callback {
-                    total_decrypted_bytes += plaintext_len;
-                    callback(total_decrypted_bytes, total_plaintext_size, user_data);
-                }
-            }
-            None => return ptr::null_mut(),
+        let (plaintext, chunk_len) = decrypt_chunk_impl(&encrypted[offset..chunk_end], &fek)?;
+        sink(&plaintext)?;
+        total_decrypted_bytes += plaintext.len();
+        offset += chunk_len;
+
+        // Call progress callback if provided
+        if let Some(callback) = progress_callback {
+            callback(total_decrypted_bytes, total_decrypted_bytes, user_data);
         }
     }
 
-    // Allocate output buffer
-    let output = unsafe {
-        let ptr = libc::malloc(total_plaintext_size) as *mut u8;
-        if ptr.is_null() {
-            return ptr::null_mut();
-        }
-        ptr
-    };
+    Ok(total_decrypted_bytes)
+}
 
-    // Copy all plaintext chunks
-    let mut write_offset = 0;
-    for chunk in &plaintext_chunks {
-        unsafe {
-            ptr::copy_nonoverlapping(chunk.as_ptr(), output.add(write_offset), chunk.len());
-            write_offset += chunk.len();
-        }
-    }
+/// Plaintext size of a streaming container, summed from its chunk headers
+///
+/// Stops at the first header that does not parse; decrypt_stream_to reports
+/// that error, so the sum only has to bound well-formed containers.
+fn streaming_plaintext_size(encrypted: &[u8]) -> usize {
+    let fek_length = match parse_header(encrypted) {
+        Ok((_, _, fek_length)) => fek_length,
+        Err(_) => return 0,
+    };
 
-    unsafe {
-        *output_len = total_plaintext_size;
+    let mut offset = HEADER_SIZE + fek_length;
+    let mut total = 0;
+    while offset < encrypted.len() {
+        let (header_len, _, chunk_size) = match parse_chunk_header(&encrypted[offset..]) {
+            Some(header) => header,
+            None => break,
+        };
+        let record_end = match chunk_record_len(header_len, chunk_size).ok().and_then(|len| offset.checked_add(len)) {
+            Some(end) if end <= encrypted.len() => end,
+            _ => break,
+        };
+        total += chunk_size.saturating_sub(MAC_SIZE);
+        offset = record_end;
     }
-
-    output
+    total
 }
 
 // Helper functions for streaming encryption
 
-fn encrypt_chunk_impl(data: &[u8], fek: &[u8], chunk_index: u32) -> Option<Vec<u8>> {
+fn encrypt_chunk_impl(data: &[u8], fek: &[u8], chunk_index: u32, with_crc: bool) -> Option<Vec<u8>> {
     // Generate nonce for this chunk
     let mut nonce_bytes = [0u8; NONCE_SIZE];
-    OsRng.fill_bytes(&mut nonce_bytes);
+    fill_random(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
 
     // Encrypt chunk
     let cipher = Aes256Gcm::new_from_slice(fek).ok()?;
     let ciphertext = cipher.encrypt(nonce, data).ok()?;
 
-    // Build chunk header: index (4) + size (4) + nonce (12)
-    // Total header: 20 bytes
-    let mut chunk = Vec::with_capacity(20 + ciphertext.len());
+    // Build chunk header: index (4) + size (4) + nonce (12) [+ crc32c (4)]
+    // Total header: 20 bytes, or 24 with a CRC
+    let header_len = if with_crc { CHUNK_CRC_HEADER_SIZE } else { CHUNK_BASE_HEADER_SIZE };
+    let mut chunk = Vec::with_capacity(header_len + ciphertext.len());
 
     // Chunk index (incrementing for each chunk)
     chunk.extend_from_slice(&chunk_index.to_le_bytes());
     
     // Chunk size (encrypted data INCLUDING MAC, as stored in encrypted_content)
     // encrypted_content is ciphertext + MAC tag from AES-GCM
-    chunk.extend_from_slice(&(ciphertext.len() as u32).to_le_bytes());
+    let size_field = if with_crc { ciphertext.len() as u32 | CHUNK_CRC_FLAG } else { ciphertext.len() as u32 };
+    chunk.extend_from_slice(&size_field.to_le_bytes());
     
     // Nonce
     chunk.extend_from_slice(&nonce_bytes);
+
+    // CRC32C of the ciphertext, checkable without the key
+    if with_crc {
+        chunk.extend_from_slice(&crc32c::crc32c(&ciphertext).to_le_bytes());
+    }
     
     // Encrypted data (ciphertext which includes MAC tag)
     chunk.extend_from_slice(&ciphertext);
 
+    metrics::count(Counter::ChunksEncrypted, 1);
+    metrics::count(Counter::BytesEncrypted, data.len() as u64);
     Some(chunk)
 }
 
-fn decrypt_chunk_impl(encrypted_data: &[u8], fek: &[u8]) -> Option<(Vec<u8>, usize)> {
-    if encrypted_data.len() < 20 {
-        return None;
-    }
+fn decrypt_chunk_impl(encrypted_data: &[u8], fek: &[u8]) -> Result<(Vec<u8>, usize), c_int> {
+    let result = decrypt_chunk_record(encrypted_data, fek);
+    metrics::count_decrypt(result.as_ref().map(|(plaintext, _)| plaintext.len() as u64).map_err(|code| *code));
+    result
+}
 
+fn decrypt_chunk_record(encrypted_data: &[u8], fek: &[u8]) -> Result<(Vec<u8>, usize), c_int> {
     // Parse chunk header
+    let (header_len, stored_crc, chunk_size) = match parse_chunk_header(encrypted_data) {
+        Some(header) => header,
+        None => return Err(ERROR_INVALID_FORMAT),
+    };
+    if chunk_record_len(header_len, chunk_size)? > encrypted_data.len() {
+        return Err(ERROR_INVALID_FORMAT);
+    }
+
     let _chunk_index = u32::from_le_bytes([
         encrypted_data[0], encrypted_data[1], encrypted_data[2], encrypted_data[3],
     ]);
     
-    let _chunk_size = u32::from_le_bytes([
-        encrypted_data[4], encrypted_data[5], encrypted_data[6], encrypted_data[7],
-    ]) as usize;
-    
     let nonce_bytes = &encrypted_data[8..20];
     
-    // Encrypted data starts at position 20
-    let encrypted_content = &encrypted_data[20..];
+    // Encrypted data starts after the header; bytes past the declared size
+    // belong to the next chunk
+    let encrypted_content = &encrypted_data[header_len..header_len + chunk_size];
     
     // Validate chunk size
     if encrypted_content.len() < MAC_SIZE {
-        return None;
+        return Err(ERROR_INVALID_FORMAT);
+    }
+
+    // A CRC mismatch is cheaper to detect than a GCM failure and tells the caller
+    // the stored bytes are damaged rather than the key being wrong
+    if let Some(crc) = stored_crc {
+        if crc32c::crc32c(encrypted_content) != crc {
+            return Err(ERROR_CHUNK_CRC_MISMATCH);
+        }
     }
 
     // Extract nonce
     let nonce = Nonce::from_slice(nonce_bytes);
 
     // Decrypt
-    let cipher = Aes256Gcm::new_from_slice(fek).ok()?;
-    let plaintext = cipher.decrypt(nonce, encrypted_content.as_ref()).ok()?;
+    let cipher = Aes256Gcm::new_from_slice(fek).map_err(|_| ERROR_DECRYPTION_FAILED)?;
+    let plaintext = cipher.decrypt(nonce, encrypted_content.as_ref()).map_err(|_| ERROR_DECRYPTION_FAILED)?;
 
-    // Calculate total chunk length (header 20 + encrypted_content which includes MAC)
+    // Calculate total chunk length (header + encrypted_content which includes MAC)
     // This is the size of the chunk in the encrypted file
-    let chunk_len = 20 + encrypted_content.len();
+    let chunk_len = header_len + encrypted_content.len();
+
+    Ok((plaintext, chunk_len))
+}
+
+// ============================================================================
+// CHUNK CRC32C
+// ============================================================================
+
+/// Chunk size flag: a CRC32C of the chunk's ciphertext follows the nonce
+///
+/// Chunk layout with the flag set:
+/// - chunk_index (4 bytes, little-endian)
+/// - chunk_size (4 bytes, little-endian) = CHUNK_CRC_FLAG | ciphertext length
+/// - nonce (12 bytes)
+/// - crc32c (4 bytes, little-endian) of the ciphertext including the MAC
+/// - ciphertext + MAC
+///
+/// Containers written with CRCs also set HEADER_FLAG_CHUNK_CRC in the first
+/// reserved header byte, so verify_container_crc can tell an unprotected
+/// container from a chunk whose flag bit was damaged, and carry
+/// FORMAT_VERSION_CHUNK_CRC so version-1 readers refuse them instead of
+/// reading the flagged size field as a length.
+pub const CHUNK_CRC_FLAG: u32 = 0x4000_0000;
+
+/// Main header version for containers whose chunks carry CRC32Cs
+///
+/// Format versions are cumulative: a container carries the highest version any
+/// of its features needs, and a reader accepts every version up to the newest
+/// it implements.
+pub const FORMAT_VERSION_CHUNK_CRC: u8 = 3;
+
+/// Main header version for containers with extension sections in their FEK
+/// region (HEADER_FLAG_EXTENSIONS)
+pub const FORMAT_VERSION_EXTENSIONS: u8 = 4;
+
+/// Newest main header version this library reads
+pub(crate) const FORMAT_VERSION_LATEST: u8 = FORMAT_VERSION_EXTENSIONS;
+
+/// Whether a main header version is one this library reads
+pub(crate) fn format_version_supported(version: u8) -> bool {
+    (VERSION..=FORMAT_VERSION_LATEST).contains(&version)
+}
+
+/// Main header flag (byte 5): every data chunk carries a CRC32C
+pub const HEADER_FLAG_CHUNK_CRC: u8 = 0x01;
+
+/// Main header flag (byte 5): extension sections follow the wrapped FEK inside
+/// the FEK region (see escrow.rs for the section layout); such containers carry
+/// FORMAT_VERSION_EXTENSIONS so older readers refuse them as an unsupported version
+pub const HEADER_FLAG_EXTENSIONS: u8 = 0x02;
+
+/// Offset of the flags byte in the main header (first reserved byte)
+const HEADER_FLAGS_OFFSET: usize = 5;
+
+/// Chunk header without CRC: index + size + nonce
+const CHUNK_BASE_HEADER_SIZE: usize = 4 + 4 + NONCE_SIZE;
+
+/// Chunk header with CRC: index + size + nonce + crc32c
+const CHUNK_CRC_HEADER_SIZE: usize = CHUNK_BASE_HEADER_SIZE + 4;
+
+/// Size field flag of dedup reference records (see dedup.rs)
+const CHUNK_REFERENCE_SIZE_FLAG: u32 = 0x8000_0000;
+
+/// verify_container_crc result when every chunk's CRC matches
+pub const CONTAINER_CRC_INTACT: i64 = i64::MAX;
+
+/// Parse a chunk header
+///
+/// Returns (header length, stored CRC32C if present, content length from the
+/// size field), or None if the slice is too short for the header
+/// Length of a chunk record (header plus content) from its parsed header
+///
+/// Rejects a size field above MAX_CHUNK_SIZE_FIELD with ERROR_MALFORMED_CONTAINER,
+/// which also keeps offset arithmetic from overflowing on 32-bit targets.
+fn chunk_record_len(header_len: usize, chunk_size: usize) -> Result<usize, c_int> {
+    if chunk_size > MAX_CHUNK_SIZE_FIELD {
+        return Err(ERROR_MALFORMED_CONTAINER);
+    }
+    header_len.checked_add(chunk_size).ok_or(ERROR_MALFORMED_CONTAINER)
+}
+
+fn parse_chunk_header(chunk: &[u8]) -> Option<(usize, Option<u32>, usize)> {
+    if chunk.len() < CHUNK_BASE_HEADER_SIZE {
+        return None;
+    }
+
+    let size_field = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+    if size_field & CHUNK_CRC_FLAG == 0 {
+        return Some((CHUNK_BASE_HEADER_SIZE, None, size_field as usize));
+    }
+
+    if chunk.len() < CHUNK_CRC_HEADER_SIZE {
+        return None;
+    }
+    let crc = u32::from_le_bytes([chunk[20], chunk[21], chunk[22], chunk[23]]);
+    Some((CHUNK_CRC_HEADER_SIZE, Some(crc), (size_field & !CHUNK_CRC_FLAG) as usize))
+}
+
+/// Enable or disable per-chunk CRC32C for an encryption context
+///
+/// Must be called before the header is written and before the first chunk is
+/// encrypted. Sets HEADER_FLAG_CHUNK_CRC in the context's header and raises its
+/// format version to FORMAT_VERSION_CHUNK_CRC.
+///
+/// # Arguments
+/// * `context` - Pointer to EncryptionContext from encrypt_file_init()
+/// * `enabled` - 1 to store a CRC32C in every chunk header, 0 to disable
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn encrypt_file_set_chunk_crc(context: *mut EncryptionContext, enabled: u8) {
+    if context.is_null() {
+        return;
+    }
+
+    let ctx = unsafe { &mut *context };
+    ctx.chunk_crc = enabled != 0;
+    if ctx.chunk_crc {
+        ctx.header[HEADER_FLAGS_OFFSET] |= HEADER_FLAG_CHUNK_CRC;
+        ctx.set_format_version(FORMAT_VERSION_CHUNK_CRC);
+    } else {
+        ctx.header[HEADER_FLAGS_OFFSET] &= !HEADER_FLAG_CHUNK_CRC;
+        if ctx.header[4] == FORMAT_VERSION_CHUNK_CRC {
+            ctx.header[4] = VERSION;
+        }
+    }
+}
+
+/// Verify the per-chunk CRC32Cs of an encrypted container without the key
+///
+/// Walks the chunk records after the header and wrapped FEK and checks each
+/// chunk's ciphertext against its stored CRC32C. A chunk whose header is
+/// truncated, lacks the CRC flag or overruns the buffer counts as corrupt.
+/// Dedup reference records carry no ciphertext and are skipped.
+///
+/// # Arguments
+/// * `encrypted_data` - Pointer to the whole encrypted container
+/// * `encrypted_len` - Length of encrypted data
+/// * `progress_callback` - Optional progress callback (bytes verified, total bytes)
+/// * `user_data` - User data to pass to progress callback
+///
+/// # Returns
+/// Index (record position) of the first corrupt chunk, CONTAINER_CRC_INTACT if
+/// all chunks match, or a negative error code (ERROR_INVALID_FORMAT if the
+/// container was not written with chunk CRCs)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn verify_container_crc(
+    encrypted_data: *const u8,
+    encrypted_len: usize,
+    progress_callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+) -> i64 {
+    if encrypted_data.is_null() {
+        return ERROR_NULL_POINTER as i64;
+    }
 
-    Some((plaintext, chunk_len))
+    if encrypted_len < HEADER_SIZE {
+        return ERROR_INVALID_FORMAT as i64;
+    }
+
+    let encrypted_slice = unsafe { slice::from_raw_parts(encrypted_data, encrypted_len) };
+    let (magic, _version, fek_length) = match parse_header(&encrypted_slice[..HEADER_SIZE]) {
+        Ok(result) => result,
+        Err(code) => return code as i64,
+    };
+
+    if magic != MAGIC
+        || encrypted_slice[HEADER_FLAGS_OFFSET] & HEADER_FLAG_CHUNK_CRC == 0
+        || encrypted_len < HEADER_SIZE + fek_length {
+        return ERROR_INVALID_FORMAT as i64;
+    }
+
+    let mut offset = HEADER_SIZE + fek_length;
+    let mut chunk_index: i64 = 0;
+    while offset < encrypted_len {
+        let record = &encrypted_slice[offset..];
+
+        // Reference records hold a fingerprint instead of ciphertext
+        if record.len() >= CHUNK_BASE_HEADER_SIZE {
+            let size_field = u32::from_le_bytes([record[4], record[5], record[6], record[7]]);
+            if size_field & CHUNK_REFERENCE_SIZE_FLAG != 0 {
+                let record_len = match chunk_record_len(CHUNK_BASE_HEADER_SIZE, (size_field & !CHUNK_REFERENCE_SIZE_FLAG) as usize) {
+                    Ok(len) if len <= record.len() => len,
+                    _ => return chunk_index,
+                };
+                offset += record_len;
+                chunk_index += 1;
+                continue;
+            }
+        }
+
+        let (header_len, stored_crc, content_len) = match parse_chunk_header(record) {
+            Some((header_len, Some(crc), content_len)) => (header_len, crc, content_len),
+            _ => return chunk_index,
+        };
+        let record_len = match chunk_record_len(header_len, content_len) {
+            Ok(len) if len <= record.len() => len,
+            _ => return chunk_index,
+        };
+        if crc32c::crc32c(&record[header_len..record_len]) != stored_crc {
+            return chunk_index;
+        }
+
+        offset += record_len;
+        chunk_index += 1;
+
+        if let Some(callback) = progress_callback {
+            callback(offset, encrypted_len, user_data);
+        }
+    }
+
+    CONTAINER_CRC_INTACT
 }
 
 /// Simple wrapper for encrypting a file (backward compatible name)
@@ -911,6 +1669,34 @@ pub extern "C" fn encrypt_file_init(
     master_key: *const u8,
     master_key_len: usize,
     output_len: *mut usize,
+) -> *mut EncryptionContext {
+    encrypt_file_init_ex(master_key, master_key_len, ptr::null(), 0, output_len)
+}
+
+/// Initialize encryption context with an optional FEK escrow to a recovery key
+///
+/// When a recovery public key is given, the FEK is additionally wrapped to it
+/// (see escrow.rs) and stored as an extension section after the master-key
+/// wrapped FEK. The section is part of the FEK region, so chunk offsets and
+/// readers that only use the master key are unaffected.
+///
+/// # Arguments
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `recovery_public_key` - Pointer to a 32-byte X25519 recovery public key (can be null)
+/// * `recovery_key_len` - Length of the recovery public key (must be 32 when given)
+/// * `output_len` - Pointer to store header size
+///
+/// # Returns
+/// Pointer to EncryptionContext, or null on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn encrypt_file_init_ex(
+    master_key: *const u8,
+    master_key_len: usize,
+    recovery_public_key: *const u8,
+    recovery_key_len: usize,
+    output_len: *mut usize,
 ) -> *mut EncryptionContext {
     if master_key.is_null() || output_len.is_null() {
         return ptr::null_mut();
@@ -920,21 +1706,41 @@ pub extern "C" fn encrypt_file_init(
         return ptr::null_mut();
     }
 
+    if !recovery_public_key.is_null() && recovery_key_len != X25519_KEY_SIZE {
+        return ptr::null_mut();
+    }
+
     let master_key_slice = unsafe { slice::from_raw_parts(master_key, master_key_len) };
 
     // Generate File Encryption Key (FEK)
     let mut fek = [0u8; KEY_SIZE];
-    OsRng.fill_bytes(&mut fek);
+    fill_random(&mut fek);
 
     // Wrap FEK with master key
-    let wrapped_fek = wrap_key(&fek, master_key_slice);
-    let wrapped_fek_len = wrapped_fek.len();
+    let mut wrapped_fek = wrap_key(&fek, master_key_slice);
     if wrapped_fek.is_empty() {
         return ptr::null_mut();
     }
 
+    // Escrow the FEK to the recovery key
+    let mut flags = 0u8;
+    if !recovery_public_key.is_null() {
+        let mut recipient = [0u8; X25519_KEY_SIZE];
+        recipient.copy_from_slice(unsafe { slice::from_raw_parts(recovery_public_key, recovery_key_len) });
+        match build_escrow_section(&fek, &recipient) {
+            Some(section) => wrapped_fek.extend_from_slice(&section),
+            None => return ptr::null_mut(),
+        }
+        flags |= HEADER_FLAG_EXTENSIONS;
+    }
+    let wrapped_fek_len = wrapped_fek.len();
+
     // Build header
-    let header = build_header(wrapped_fek.len() as u32);
+    let mut header = build_header(wrapped_fek.len() as u32);
+    header[HEADER_FLAGS_OFFSET] = flags;
+    if flags & HEADER_FLAG_EXTENSIONS != 0 {
+        header[4] = FORMAT_VERSION_EXTENSIONS;
+    }
 
     // Create encryption context
     let context = Box::new(EncryptionContext {
@@ -942,6 +1748,8 @@ pub extern "C" fn encrypt_file_init(
         wrapped_fek,
         header,
         chunk_index: 0,
+        chunk_crc: false,
+        chunks_started: false,
     });
 
     // Return header size
@@ -988,9 +1796,10 @@ pub extern "C" fn encrypt_chunk(
 
     // Update chunk index in context
     ctx.chunk_index = chunk_index;
+    ctx.chunks_started = true;
 
     // Encrypt chunk
-    let encrypted = match encrypt_chunk_impl(chunk_slice, &ctx.fek, chunk_index) {
+    let encrypted = match encrypt_chunk_impl(chunk_slice, &ctx.fek, chunk_index, ctx.chunk_crc) {
         Some(data) => data,
         None => return ptr::null_mut(),
     };
@@ -1056,6 +1865,45 @@ pub extern "C" fn encrypt_file_get_wrapped_fek(
     output
 }
 
+/// Write the container preamble of the encryption context into a caller buffer
+///
+/// The preamble is the main header immediately followed by the wrapped FEK
+/// region, i.e. the first bytes of the container, reflecting every change made
+/// to the context so far (chunk CRCs, escrow, metadata). Pass a null buffer
+/// with `buf_len` 0 to query the size.
+///
+/// # Arguments
+/// * `context` - Pointer to EncryptionContext from encrypt_file_init()
+/// * `out_buf` - Buffer receiving the preamble (can be null if `buf_len` is 0)
+/// * `buf_len` - Size of `out_buf`
+/// * `written` - Pointer to store the preamble length, also set when the buffer is too small
+///
+/// # Returns
+/// 0 on success, ERROR_OUTPUT_TOO_SMALL if `out_buf` can't hold the preamble, or another error code
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn encrypt_file_get_preamble(
+    context: *mut EncryptionContext,
+    out_buf: *mut u8,
+    buf_len: usize,
+    written: *mut usize,
+) -> c_int {
+    if context.is_null() || written.is_null() || (out_buf.is_null() && buf_len > 0) {
+        return ERROR_NULL_POINTER;
+    }
+
+    let ctx = unsafe { &*context };
+    let preamble_len = ctx.preamble_len();
+    unsafe { *written = preamble_len; }
+    if buf_len < preamble_len {
+        return ERROR_OUTPUT_TOO_SMALL;
+    }
+
+    let out = unsafe { slice::from_raw_parts_mut(out_buf, buf_len) };
+    ctx.write_preamble(&mut out[..preamble_len]);
+    SUCCESS
+}
+
 /// Finalize encryption context and free memory
 ///
 /// # Arguments
@@ -1070,6 +1918,99 @@ pub extern "C" fn encrypt_file_finalize(context: *mut EncryptionContext) {
     }
 }
 
+/// State of an EncryptionContext carried across an export, with the FEK only
+/// in its master-key wrapped form
+#[derive(serde::Serialize, serde::Deserialize)]
+struct EncryptionContextState {
+    wrapped_fek: Vec<u8>,
+    header: Vec<u8>,
+    chunk_index: u32,
+    chunk_crc: bool,
+}
+
+impl EncryptionContext {
+    /// Length of the main header and wrapped FEK region
+    pub(crate) fn preamble_len(&self) -> usize {
+        HEADER_SIZE + self.wrapped_fek.len()
+    }
+
+    /// Write the main header and wrapped FEK region into `out`, which must be
+    /// exactly preamble_len() bytes
+    pub(crate) fn write_preamble(&self, out: &mut [u8]) {
+        out[..HEADER_SIZE].copy_from_slice(&self.header);
+        out[HEADER_SIZE..].copy_from_slice(&self.wrapped_fek);
+    }
+
+    /// Main header followed by the wrapped FEK region
+    pub(crate) fn preamble(&self) -> Vec<u8> {
+        let mut preamble = vec![0u8; self.preamble_len()];
+        self.write_preamble(&mut preamble);
+        preamble
+    }
+
+    /// Raise the container's format version to at least `version` (e.g.
+    /// FORMAT_VERSION_DEDUP for containers with chunk reference records)
+    pub(crate) fn set_format_version(&mut self, version: u8) {
+        self.header[4] = self.header[4].max(version);
+    }
+
+    /// Export the context so a later process can continue encrypting with it
+    ///
+    /// The state is sealed with `master_key` (nonce + AES-256-GCM ciphertext),
+    /// so it can neither be read nor altered without the key. Fails with
+    /// ERROR_DECRYPTION_FAILED if `master_key` is not the key the FEK is wrapped with.
+    pub(crate) fn export_sealed(&self, master_key: &[u8]) -> Result<Vec<u8>, c_int> {
+        if master_key.len() != KEY_SIZE {
+            return Err(ERROR_INVALID_KEY_SIZE);
+        }
+        if unwrap_fek(&self.header, &self.wrapped_fek, master_key).is_err() {
+            return Err(ERROR_DECRYPTION_FAILED);
+        }
+        let state = EncryptionContextState {
+            wrapped_fek: self.wrapped_fek.clone(),
+            header: self.header.to_vec(),
+            chunk_index: self.chunk_index,
+            chunk_crc: self.chunk_crc,
+        };
+        let plain = serde_json::to_vec(&state).map_err(|_| ERROR_ENCRYPTION_FAILED)?;
+        match wrap_key(&plain, master_key) {
+            sealed if sealed.is_empty() => Err(ERROR_ENCRYPTION_FAILED),
+            sealed => Ok(sealed),
+        }
+    }
+
+    /// Rebuild a context from export_sealed output, unwrapping its FEK with `master_key`
+    ///
+    /// Fails with ERROR_DECRYPTION_FAILED for the wrong key or altered state, and
+    /// ERROR_INVALID_FORMAT for state that does not describe a context.
+    pub(crate) fn import_sealed(sealed: &[u8], master_key: &[u8]) -> Result<Self, c_int> {
+        if master_key.len() != KEY_SIZE {
+            return Err(ERROR_INVALID_KEY_SIZE);
+        }
+        if sealed.len() < NONCE_SIZE + MAC_SIZE {
+            return Err(ERROR_INVALID_FORMAT);
+        }
+        let cipher = Aes256Gcm::new_from_slice(master_key).map_err(|_| ERROR_INVALID_KEY_SIZE)?;
+        let plain = cipher
+            .decrypt(Nonce::from_slice(&sealed[..NONCE_SIZE]), &sealed[NONCE_SIZE..])
+            .map_err(|_| ERROR_DECRYPTION_FAILED)?;
+        let state: EncryptionContextState = serde_json::from_slice(&plain).map_err(|_| ERROR_INVALID_FORMAT)?;
+
+        let fek = unwrap_fek(&state.header, &state.wrapped_fek, master_key).map_err(|_| ERROR_DECRYPTION_FAILED)?;
+        let fek: [u8; KEY_SIZE] = fek.try_into().map_err(|_| ERROR_INVALID_FORMAT)?;
+        let header: [u8; HEADER_SIZE] = state.header.try_into().map_err(|_| ERROR_INVALID_FORMAT)?;
+        // An exported context may have sent its header already, so it is treated as started
+        Ok(Self {
+            fek,
+            wrapped_fek: state.wrapped_fek,
+            header,
+            chunk_index: state.chunk_index,
+            chunk_crc: state.chunk_crc,
+            chunks_started: true,
+        })
+    }
+}
+
 /// Initialize decryption context for streaming decryption
 ///
 /// This function parses the encrypted file header and unwraps the FEK.
@@ -1115,8 +2056,9 @@ pub extern "C" fn decrypt_file_init(
         Err(_) => return ptr::null_mut(),
     };
 
-    // Validate magic and version
-    if magic != MAGIC || version != VERSION {
+    // Validate magic and version (dedup containers share the same header layout;
+    // their reference chunks are detected per chunk with chunk_reference_fingerprint)
+    if magic != MAGIC || !format_version_supported(version) {
         return ptr::null_mut();
     }
 
@@ -1129,7 +2071,7 @@ pub extern "C" fn decrypt_file_init(
     let wrapped_fek = &encrypted_slice[HEADER_SIZE..HEADER_SIZE + fek_length];
 
     // Unwrap FEK
-    let fek = match unwrap_key(wrapped_fek, master_key_slice) {
+    let fek = match unwrap_fek(encrypted_slice, wrapped_fek, master_key_slice) {
         Ok(key) => key,
         Err(_) => return ptr::null_mut(),
     };
@@ -1149,6 +2091,8 @@ pub extern "C" fn decrypt_file_init(
 /// This function decrypts one chunk at a time, allowing true streaming decryption
 /// with minimal memory usage.
 ///
+/// Only the first chunk in the input is decrypted; bytes after it are ignored.
+///
 /// # Arguments
 /// * `context` - Pointer to DecryptionContext from decrypt_file_init()
 /// * `encrypted_chunk` - Pointer to encrypted chunk data (must include chunk header)
@@ -1164,26 +2108,166 @@ pub extern "C" fn decrypt_chunk(
     chunk_len: usize,
     output_len: *mut usize,
 ) -> *mut u8 {
-    if context.is_null() || encrypted_chunk.is_null() || output_len.is_null() {
-        return ptr::null_mut();
+    decrypt_chunk_v2(context, encrypted_chunk, chunk_len, output_len, ptr::null_mut(), ptr::null_mut())
+}
+
+/// Decrypt the first chunk of a buffer and report how many bytes it used
+///
+/// The input may hold several concatenated chunks (e.g. one network read);
+/// only the first is decrypted and `consumed` tells where the next one starts.
+///
+/// # Arguments
+/// * `context` - Pointer to DecryptionContext from decrypt_file_init()
+/// * `encrypted_data` - Pointer to encrypted data starting at a chunk header
+/// * `data_len` - Length of encrypted data
+/// * `output_len` - Pointer to store output length
+/// * `consumed` - Optional pointer receiving the length of the decrypted chunk (header included)
+/// * `status_out` - Optional pointer receiving 0, ERROR_NEED_MORE_DATA if the input
+///   ends before the first chunk does, or another negative error code
+///
+/// # Returns
+/// Pointer to decrypted chunk (caller must free with free_buffer), or null on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn decrypt_chunk_v2(
+    context: *mut DecryptionContext,
+    encrypted_data: *const u8,
+    data_len: usize,
+    output_len: *mut usize,
+    consumed: *mut usize,
+    status_out: *mut c_int,
+) -> *mut u8 {
+    let result = decrypt_first_chunk(context, encrypted_data, data_len, output_len, false).map(|(output, chunk_len)| {
+        if !consumed.is_null() {
+            unsafe { *consumed = chunk_len; }
+        }
+        output
+    });
+    streaming_result(result, status_out)
+}
+
+/// Decrypt the first chunk of a buffer, requiring it to be the next chunk in order
+///
+/// Same as decrypt_chunk_v2, except the chunk's embedded index must equal the
+/// context's expected index, which advances by one after each chunk decrypted.
+/// A reordered, dropped or replayed chunk fails with ERROR_CHUNK_OUT_OF_ORDER
+/// and leaves the expected index unchanged.
+///
+/// The index is read from the chunk header and is not authenticated: chunks
+/// are encrypted without associated data, so nothing binds a chunk to its
+/// position. This catches chunks a transport or cache mixed up, not a
+/// deliberate swap by someone who can rewrite the headers of a container.
+///
+/// # Arguments
+/// * `context` - Pointer to DecryptionContext from decrypt_file_init()
+/// * `encrypted_data` - Pointer to encrypted data starting at a chunk header
+/// * `data_len` - Length of encrypted data
+/// * `output_len` - Pointer to store output length
+/// * `consumed` - Optional pointer receiving the length of the decrypted chunk (header included)
+/// * `status_out` - Optional pointer receiving 0, ERROR_NEED_MORE_DATA,
+///   ERROR_CHUNK_OUT_OF_ORDER, or another negative error code
+///
+/// # Returns
+/// Pointer to decrypted chunk (caller must free with free_buffer), or null on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn decrypt_chunk_strict(
+    context: *mut DecryptionContext,
+    encrypted_data: *const u8,
+    data_len: usize,
+    output_len: *mut usize,
+    consumed: *mut usize,
+    status_out: *mut c_int,
+) -> *mut u8 {
+    let result = decrypt_first_chunk(context, encrypted_data, data_len, output_len, true).map(|(output, chunk_len)| {
+        if !consumed.is_null() {
+            unsafe { *consumed = chunk_len; }
+        }
+        output
+    });
+    streaming_result(result, status_out)
+}
+
+/// Index the next chunk passed to decrypt_chunk_strict must carry
+///
+/// # Arguments
+/// * `context` - Pointer to DecryptionContext from decrypt_file_init()
+///
+/// # Returns
+/// The expected chunk index, or 0 if context is null
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn decryption_context_expected_index(context: *const DecryptionContext) -> u32 {
+    if context.is_null() {
+        return 0;
+    }
+    unsafe { (*context).chunk_index }
+}
+
+/// Set the index the next strict chunk must carry
+///
+/// Used when resuming a download part-way through a container: after seeking
+/// to chunk N, reset the index to N before feeding it.
+///
+/// # Arguments
+/// * `context` - Pointer to DecryptionContext from decrypt_file_init()
+/// * `index` - Index of the next chunk to be decrypted
+///
+/// # Returns
+/// 0 on success, ERROR_NULL_POINTER if context is null
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn decryption_context_reset_index(context: *mut DecryptionContext, index: u32) -> c_int {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+    unsafe { (*context).chunk_index = index; }
+    SUCCESS
+}
+
+fn decrypt_first_chunk(
+    context: *mut DecryptionContext,
+    encrypted_data: *const u8,
+    data_len: usize,
+    output_len: *mut usize,
+    strict: bool,
+) -> Result<(*mut u8, usize), c_int> {
+    if context.is_null() || encrypted_data.is_null() || output_len.is_null() {
+        return Err(ERROR_NULL_POINTER);
     }
 
     let ctx = unsafe { &mut *context };
-    let encrypted_slice = unsafe { slice::from_raw_parts(encrypted_chunk, chunk_len) };
+    let encrypted_slice = unsafe { slice::from_raw_parts(encrypted_data, data_len) };
 
-    // Decrypt chunk
-    let (plaintext, _chunk_len) = match decrypt_chunk_impl(encrypted_slice, &ctx.fek) {
-        Some(result) => result,
-        None => return ptr::null_mut(),
+    // Tell a chunk still arriving apart from a damaged one; an out-of-range size
+    // field is rejected before the caller buffers toward it
+    match parse_chunk_header(encrypted_slice) {
+        Some((header_len, _, chunk_size)) if chunk_record_len(header_len, chunk_size)? <= data_len => {}
+        _ => return Err(ERROR_NEED_MORE_DATA),
+    }
+
+    let next_index = if strict {
+        let chunk_index = u32::from_le_bytes([
+            encrypted_slice[0], encrypted_slice[1], encrypted_slice[2], encrypted_slice[3],
+        ]);
+        if chunk_index != ctx.chunk_index {
+            return Err(ERROR_CHUNK_OUT_OF_ORDER);
+        }
+        Some(chunk_index.checked_add(1).ok_or(ERROR_MALFORMED_CONTAINER)?)
+    } else {
+        None
     };
 
+    // Decrypt chunk
+    let (plaintext, chunk_len) = decrypt_chunk_impl(encrypted_slice, &ctx.fek)?;
+
     let output_size = plaintext.len();
 
     // Allocate output buffer
     let output = unsafe {
         let ptr = libc::malloc(output_size) as *mut u8;
         if ptr.is_null() {
-            return ptr::null_mut();
+            return Err(ERROR_BUFFER_ALLOC_FAILED);
         }
         ptr
     };
@@ -1194,7 +2278,12 @@ pub extern "C" fn decrypt_chunk(
         *output_len = output_size;
     }
 
-    output
+    // Advance only once the chunk is delivered, so a damaged chunk can be refetched
+    if let Some(index) = next_index {
+        ctx.chunk_index = index;
+    }
+
+    Ok((output, chunk_len))
 }
 
 /// Finalize decryption context and free memory
@@ -1217,3 +2306,585 @@ pub extern "C" fn decrypt_file_finalize(context: *mut DecryptionContext) {
 
 // Re-export all folder scanning FFI functions
 // These are defined in scan.rs and made available for FFI calls
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    use crate::upload::{upload_free, upload_get_header, upload_init, upload_process_chunk, upload_set_chunk_crc};
+
+    extern "C" fn collect_record(data: *const u8, data_len: usize, _chunk_index: u32, user_data: *mut c_void) {
+        let container = unsafe { &mut *(user_data as *mut Vec<u8>) };
+        container.extend_from_slice(unsafe { slice::from_raw_parts(data, data_len) });
+    }
+
+    /// Encrypt `content` through the upload path into a complete container
+    fn upload_container(content: &[u8], key: &[u8; KEY_SIZE], chunk_size: usize, chunk_crc: u8) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("cloud_nexus_crc_{}_{}.bin", std::process::id(), chunk_crc));
+        std::fs::write(&path, content).unwrap();
+        let path_c = CString::new(path.to_string_lossy().to_string()).unwrap();
+
+        let ctx = upload_init(path_c.as_ptr(), key.as_ptr(), KEY_SIZE, chunk_size, 1, None, None, ptr::null(), ptr::null_mut());
+        assert!(!ctx.is_null());
+        upload_set_chunk_crc(ctx, chunk_crc);
+
+        let mut header = [0u8; HEADER_SIZE];
+        let mut fek = [0u8; 256];
+        let mut fek_len = 0usize;
+        assert_eq!(upload_get_header(ctx, header.as_mut_ptr(), fek.as_mut_ptr(), fek.len(), &mut fek_len), SUCCESS);
+
+        let mut container = header.to_vec();
+        container.extend_from_slice(&fek[..fek_len]);
+        let mut buffer = vec![0u8; chunk_size + 64];
+        let user_data = &mut container as *mut Vec<u8> as *mut c_void;
+        while upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_record), user_data) > 0 {}
+        upload_free(ctx);
+        let _ = std::fs::remove_file(&path);
+        container
+    }
+
+    /// Offset of the ciphertext of chunk `index` in a container
+    fn ciphertext_offset(container: &[u8], index: usize) -> usize {
+        let fek_len = u32::from_le_bytes([container[8], container[9], container[10], container[11]]) as usize;
+        let mut offset = HEADER_SIZE + fek_len;
+        for _ in 0..index {
+            let (header_len, _, content_len) = parse_chunk_header(&container[offset..]).unwrap();
+            offset += header_len + content_len;
+        }
+        offset + parse_chunk_header(&container[offset..]).unwrap().0
+    }
+
+    fn decrypt_status(container: &[u8], key: &[u8; KEY_SIZE]) -> c_int {
+        let mut output_len = 0usize;
+        let mut status: c_int = 1;
+        let output = decrypt_file_streaming_ex(container.as_ptr(), container.len(), key.as_ptr(), KEY_SIZE,
+                                               &mut output_len, None, ptr::null_mut(), ptr::null(), &mut status);
+        if !output.is_null() {
+            free_buffer(output);
+        }
+        status
+    }
+
+    fn verify(container: &[u8]) -> i64 {
+        verify_container_crc(container.as_ptr(), container.len(), None, ptr::null_mut())
+    }
+
+    #[test]
+    fn test_keyless_verifier_finds_corrupt_chunk() {
+        let key = [9u8; KEY_SIZE];
+        let chunk_size = 64 * 1024;
+        let content: Vec<u8> = (0..chunk_size * 4 + 100).map(|i| (i % 253) as u8).collect();
+        let container = upload_container(&content, &key, chunk_size, 1);
+        assert_eq!(container[HEADER_FLAGS_OFFSET], HEADER_FLAG_CHUNK_CRC);
+        assert_eq!(container[4], FORMAT_VERSION_CHUNK_CRC);
+
+        // Intact container verifies and decrypts
+        assert_eq!(verify(&container), CONTAINER_CRC_INTACT);
+        assert_eq!(decrypt_status(&container, &key), SUCCESS);
+
+        for corrupt_index in [0usize, 2, 4] {
+            let mut damaged = container.clone();
+            let offset = ciphertext_offset(&damaged, corrupt_index) + 37;
+            damaged[offset] ^= 0x10;
+
+            assert_eq!(verify(&damaged), corrupt_index as i64);
+            assert_eq!(decrypt_status(&damaged, &key), ERROR_CHUNK_CRC_MISMATCH);
+        }
+
+        // decrypt_chunk has no status out, so the mismatch is the last error
+        let mut damaged = container.clone();
+        let chunk_start = ciphertext_offset(&damaged, 0) - CHUNK_CRC_HEADER_SIZE;
+        damaged[chunk_start + CHUNK_CRC_HEADER_SIZE + 5] ^= 0x10;
+        clear_last_error();
+        let ctx = decrypt_file_init(damaged.as_ptr(), damaged.len(), key.as_ptr(), KEY_SIZE);
+        let mut out_len = 0usize;
+        let chunk = &damaged[chunk_start..];
+        assert!(decrypt_chunk(ctx, chunk.as_ptr(), chunk.len(), &mut out_len).is_null());
+        decrypt_file_finalize(ctx);
+        let json = unsafe { CString::from_raw(get_last_error_json(ptr::null_mut())) };
+        let envelope: serde_json::Value = serde_json::from_str(json.to_str().unwrap()).unwrap();
+        assert_eq!(envelope["data"]["code"].as_i64(), Some(ERROR_CHUNK_CRC_MISMATCH as i64));
+
+        // A damaged MAC byte at the end of a chunk is caught as well
+        let mut damaged = container.clone();
+        let last_mac = ciphertext_offset(&damaged, 2) - CHUNK_CRC_HEADER_SIZE - 1;
+        damaged[last_mac] ^= 0x01;
+        assert_eq!(verify(&damaged), 1);
+    }
+
+    #[test]
+    fn test_containers_without_crc() {
+        let key = [3u8; KEY_SIZE];
+        let content = vec![7u8; 150_000];
+        let container = upload_container(&content, &key, 64 * 1024, 0);
+        assert_eq!(container[HEADER_FLAGS_OFFSET], 0);
+        assert_eq!(container[4], VERSION);
+
+        // Nothing to verify without the key, but decryption is unchanged
+        assert_eq!(verify(&container), ERROR_INVALID_FORMAT as i64);
+        assert_eq!(decrypt_status(&container, &key), SUCCESS);
+
+        let mut damaged = container.clone();
+        let offset = ciphertext_offset(&damaged, 1);
+        damaged[offset] ^= 0x10;
+        assert_eq!(decrypt_status(&damaged, &key), ERROR_DECRYPTION_FAILED);
+    }
+
+    #[test]
+    fn test_decrypt_chunk_reports_consumed_bytes() {
+        let key = [5u8; KEY_SIZE];
+        let chunk_size = 1000;
+        let content: Vec<u8> = (0..chunk_size * 2 + 345).map(|i| (i % 251) as u8).collect();
+        let mut header_len = 0usize;
+        let enc_ctx = encrypt_file_init(key.as_ptr(), KEY_SIZE, &mut header_len);
+        let mut fek_len = 0usize;
+        let fek = encrypt_file_get_wrapped_fek(enc_ctx, &mut fek_len);
+        let mut container = unsafe { (*enc_ctx).header }.to_vec();
+        container.extend_from_slice(unsafe { slice::from_raw_parts(fek, fek_len) });
+        free_buffer(fek);
+
+        // All three chunks in one buffer, as a single network read would deliver them
+        let mut chunks = Vec::new();
+        for (index, piece) in content.chunks(chunk_size).enumerate() {
+            let mut len = 0usize;
+            let chunk = encrypt_chunk(enc_ctx, piece.as_ptr(), piece.len(), index as u32, &mut len);
+            chunks.extend_from_slice(unsafe { slice::from_raw_parts(chunk, len) });
+            free_buffer(chunk);
+        }
+        encrypt_file_finalize(enc_ctx);
+
+        let ctx = decrypt_file_init(container.as_ptr(), header_len, key.as_ptr(), KEY_SIZE);
+        assert!(!ctx.is_null());
+        let mut offset = 0;
+        let mut plaintext = Vec::new();
+        while offset < chunks.len() {
+            let (mut out_len, mut consumed, mut status) = (0usize, 0usize, 1 as c_int);
+            let output = decrypt_chunk_v2(ctx, chunks[offset..].as_ptr(), chunks.len() - offset, &mut out_len,
+                                          &mut consumed, &mut status);
+            assert_eq!(status, SUCCESS);
+            plaintext.extend_from_slice(unsafe { slice::from_raw_parts(output, out_len) });
+            free_buffer(output);
+            offset += consumed;
+        }
+        assert_eq!(offset, chunks.len());
+        assert_eq!(plaintext, content);
+
+        // decrypt_chunk ignores the chunks after the first as well
+        let mut out_len = 0usize;
+        let output = decrypt_chunk(ctx, chunks.as_ptr(), chunks.len(), &mut out_len);
+        assert_eq!(unsafe { slice::from_raw_parts(output, out_len) }, &content[..chunk_size]);
+        free_buffer(output);
+
+        // A chunk cut short needs more data rather than failing
+        let (mut out_len, mut consumed, mut status) = (0usize, 0usize, 1 as c_int);
+        let output = decrypt_chunk_v2(ctx, chunks.as_ptr(), 500, &mut out_len, &mut consumed, &mut status);
+        assert!(output.is_null());
+        assert_eq!((status, consumed), (ERROR_NEED_MORE_DATA, 0));
+
+        decrypt_file_finalize(ctx);
+    }
+
+    #[test]
+    fn test_decrypt_chunk_strict_enforces_order_and_resumes_after_reset() {
+        let key = [0x48u8; KEY_SIZE];
+        let pieces: [&[u8]; 3] = [b"chunk zero", b"chunk one", b"chunk two"];
+        let (container, offsets) = small_chunk_container(&key, &pieces, false);
+        let record = |i: usize| {
+            let end = offsets.get(i + 1).copied().unwrap_or(container.len());
+            &container[offsets[i]..end]
+        };
+        let strict = |ctx: *mut DecryptionContext, data: &[u8]| -> (c_int, Vec<u8>) {
+            let (mut out_len, mut consumed, mut status) = (0usize, 0usize, 1 as c_int);
+            let output = decrypt_chunk_strict(ctx, data.as_ptr(), data.len(), &mut out_len, &mut consumed, &mut status);
+            if output.is_null() {
+                return (status, Vec::new());
+            }
+            assert_eq!(consumed, data.len());
+            let plaintext = unsafe { slice::from_raw_parts(output, out_len) }.to_vec();
+            free_buffer(output);
+            (status, plaintext)
+        };
+
+        let ctx = decrypt_file_init(container.as_ptr(), offsets[0], key.as_ptr(), KEY_SIZE);
+        assert!(!ctx.is_null());
+        assert_eq!(decryption_context_expected_index(ctx), 0);
+
+        // Chunks 0, 2, 1: the skip is rejected without advancing, then 1 follows 0
+        assert_eq!(strict(ctx, record(0)), (SUCCESS, pieces[0].to_vec()));
+        assert_eq!(strict(ctx, record(2)).0, ERROR_CHUNK_OUT_OF_ORDER);
+        assert_eq!(decryption_context_expected_index(ctx), 1);
+        assert_eq!(strict(ctx, record(1)), (SUCCESS, pieces[1].to_vec()));
+
+        // A replay of chunk 1 is out of order too
+        assert_eq!(strict(ctx, record(1)).0, ERROR_CHUNK_OUT_OF_ORDER);
+        decrypt_file_finalize(ctx);
+
+        // A resumed download seeks to chunk 2 and resets the index before feeding it
+        let ctx = decrypt_file_init(container.as_ptr(), offsets[0], key.as_ptr(), KEY_SIZE);
+        assert_eq!(strict(ctx, record(2)).0, ERROR_CHUNK_OUT_OF_ORDER);
+        assert_eq!(decryption_context_reset_index(ctx, 2), SUCCESS);
+        assert_eq!(strict(ctx, record(2)), (SUCCESS, pieces[2].to_vec()));
+        assert_eq!(decryption_context_expected_index(ctx), 3);
+
+        // decrypt_chunk_v2 stays unordered and leaves the index alone
+        let (mut out_len, mut status) = (0usize, 1 as c_int);
+        let output = decrypt_chunk_v2(ctx, record(0).as_ptr(), record(0).len(), &mut out_len, ptr::null_mut(), &mut status);
+        assert_eq!(status, SUCCESS);
+        free_buffer(output);
+        assert_eq!(decryption_context_expected_index(ctx), 3);
+        decrypt_file_finalize(ctx);
+
+        assert_eq!(decryption_context_expected_index(ptr::null()), 0);
+        assert_eq!(decryption_context_reset_index(ptr::null_mut(), 0), ERROR_NULL_POINTER);
+    }
+
+    /// Build a container in memory from small chunks, returning it with its chunk offsets
+    pub(crate) fn small_chunk_container(key: &[u8; KEY_SIZE], chunks: &[&[u8]], with_crc: bool) -> (Vec<u8>, Vec<usize>) {
+        let mut header_len = 0usize;
+        let ctx = encrypt_file_init(key.as_ptr(), KEY_SIZE, &mut header_len);
+        assert!(!ctx.is_null());
+        let (mut container, fek) = {
+            let ctx = unsafe { &*ctx };
+            let mut container = ctx.header.to_vec();
+            if with_crc {
+                container[4] = FORMAT_VERSION_CHUNK_CRC;
+                container[HEADER_FLAGS_OFFSET] |= HEADER_FLAG_CHUNK_CRC;
+            }
+            container.extend_from_slice(&ctx.wrapped_fek);
+            (container, ctx.fek)
+        };
+        encrypt_file_finalize(ctx);
+
+        let mut offsets = Vec::new();
+        for (index, plaintext) in chunks.iter().enumerate() {
+            offsets.push(container.len());
+            container.extend(encrypt_chunk_impl(plaintext, &fek, index as u32, with_crc).unwrap());
+        }
+        (container, offsets)
+    }
+
+    #[test]
+    fn test_corrupt_containers_fail_without_panicking() {
+        use rand::{Rng, SeedableRng};
+
+        let key = [0x79u8; KEY_SIZE];
+        let chunks: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; if i == 3 { 100 } else { 512 }]).collect();
+        let chunk_refs: Vec<&[u8]> = chunks.iter().map(|c| c.as_slice()).collect();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1179);
+
+        // An error for corrupt input
+        let rejects = |container: &[u8]| -> c_int {
+            let status = decrypt_status(container, &key);
+            assert_ne!(status, SUCCESS);
+            status
+        };
+
+        for with_crc in [false, true] {
+            let (container, offsets) = small_chunk_container(&key, &chunk_refs, with_crc);
+            let fek_end = offsets[0];
+            // The intact container decrypts
+            assert_eq!(decrypt_status(&container, &key), SUCCESS);
+
+            // Random buffers, half of them behind a valid magic and version
+            for round in 0..200 {
+                let mut buffer = vec![0u8; rng.gen_range(0..2048)];
+                rng.fill(buffer.as_mut_slice());
+                if round % 2 == 0 && buffer.len() >= HEADER_SIZE {
+                    buffer[..4].copy_from_slice(&MAGIC.to_le_bytes());
+                    buffer[4] = VERSION;
+                }
+                rejects(&buffer);
+            }
+
+            // Single bit flips anywhere but the version (a flip can land on another
+            // supported version), flags and reserved header bytes, and the chunk
+            // indexes, which decryption does not check
+            for _ in 0..500 {
+                let mut damaged = container.clone();
+                let offset = loop {
+                    let offset = rng.gen_range(0..damaged.len());
+                    let in_chunk_index = offsets.iter().any(|&start| (start..start + 4).contains(&offset));
+                    if !(4..8).contains(&offset) && !in_chunk_index {
+                        break offset;
+                    }
+                };
+                damaged[offset] ^= 1 << rng.gen_range(0..8);
+                rejects(&damaged);
+            }
+
+            // Truncation anywhere except a chunk boundary, which leaves a shorter valid container
+            for cut in 0..container.len() {
+                if cut != fek_end && !offsets.contains(&cut) {
+                    rejects(&container[..cut]);
+                }
+            }
+
+            // Oversized FEK regions are refused before anything is sliced
+            for fek_len in [MAX_FEK_REGION_LENGTH as u32 + 1, 0x7FFF_FFFF, u32::MAX] {
+                let mut damaged = container.clone();
+                damaged[8..12].copy_from_slice(&fek_len.to_le_bytes());
+                assert_eq!(rejects(&damaged), ERROR_MALFORMED_CONTAINER);
+                assert!(decrypt_file_init(damaged.as_ptr(), damaged.len(), key.as_ptr(), KEY_SIZE).is_null());
+                assert_eq!(verify(&damaged), ERROR_MALFORMED_CONTAINER as i64);
+            }
+
+            // Oversized chunk size fields, with and without the CRC flag
+            let dec_ctx = decrypt_file_init(container.as_ptr(), fek_end, key.as_ptr(), KEY_SIZE);
+            assert!(!dec_ctx.is_null());
+            for size_field in [u32::MAX, MAX_CHUNK_SIZE_FIELD as u32 + 1, (MAX_CHUNK_SIZE_FIELD as u32 + 1) | CHUNK_CRC_FLAG,
+                               CHUNK_REFERENCE_SIZE_FLAG, 0x7FFF_FFFF] {
+                for &chunk_offset in &offsets {
+                    let mut damaged = container.clone();
+                    damaged[chunk_offset + 4..chunk_offset + 8].copy_from_slice(&size_field.to_le_bytes());
+                    assert_eq!(rejects(&damaged), ERROR_MALFORMED_CONTAINER);
+
+                    // A streaming reader learns this at once instead of waiting for more data
+                    let record = &damaged[chunk_offset..];
+                    let mut output_len = 0usize;
+                    let mut status: c_int = 1;
+                    let output = decrypt_chunk_v2(dec_ctx, record.as_ptr(), record.len(), &mut output_len,
+                                                  ptr::null_mut(), &mut status);
+                    assert!(output.is_null());
+                    assert_eq!(status, ERROR_MALFORMED_CONTAINER);
+                }
+            }
+            decrypt_file_finalize(dec_ctx);
+        }
+    }
+
+    /// Sink state for the `_cb` streaming tests
+    #[derive(Default)]
+    struct CountingSink {
+        /// Keep the bytes passed to the sink (off to measure retention)
+        collect: bool,
+        output: Vec<u8>,
+        calls: usize,
+        bytes: usize,
+        /// 'S' per sink call and 'P' per progress report, in call order
+        events: String,
+        /// Return non-zero from this sink call (1-based)
+        abort_at: Option<usize>,
+        /// Set `cancel` from the first progress report
+        cancel_on_progress: bool,
+        cancel: AtomicBool,
+    }
+
+    extern "C" fn counting_sink(data: *const u8, data_len: usize, user_data: *mut c_void) -> i32 {
+        let sink = unsafe { &mut *(user_data as *mut CountingSink) };
+        sink.calls += 1;
+        sink.bytes += data_len;
+        sink.events.push('S');
+        if sink.collect {
+            sink.output.extend_from_slice(unsafe { slice::from_raw_parts(data, data_len) });
+        }
+        if sink.abort_at == Some(sink.calls) { 1 } else { 0 }
+    }
+
+    extern "C" fn counting_progress(_done: usize, _total: usize, user_data: *mut c_void) {
+        let sink = unsafe { &mut *(user_data as *mut CountingSink) };
+        sink.events.push('P');
+        if sink.cancel_on_progress {
+            sink.cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn encrypt_cb(plaintext: &[u8], key: &[u8; KEY_SIZE], sink: &mut CountingSink) -> i64 {
+        encrypt_file_streaming_cb(plaintext.as_ptr(), plaintext.len(), key.as_ptr(), KEY_SIZE, Some(counting_sink),
+                                  Some(counting_progress), ptr::null(), sink as *mut CountingSink as *mut c_void)
+    }
+
+    fn decrypt_cb(container: &[u8], key: &[u8; KEY_SIZE], sink: &mut CountingSink) -> i64 {
+        decrypt_file_streaming_cb(container.as_ptr(), container.len(), key.as_ptr(), KEY_SIZE, Some(counting_sink),
+                                  Some(counting_progress), ptr::null(), sink as *mut CountingSink as *mut c_void)
+    }
+
+    fn buffer_round_trip(output: *mut u8, output_len: usize) -> Vec<u8> {
+        assert!(!output.is_null());
+        let bytes = unsafe { slice::from_raw_parts(output, output_len) }.to_vec();
+        free_buffer(output);
+        bytes
+    }
+
+    #[test]
+    fn test_streaming_cb_matches_buffer_functions() {
+        let key = [5u8; KEY_SIZE];
+        let plaintext: Vec<u8> = (0..DEFAULT_CHUNK_SIZE * 5 / 2).map(|i| (i * 31 % 251) as u8).collect();
+
+        // Sink-encrypted containers decrypt with the buffer function
+        let mut encrypted = CountingSink { collect: true, ..Default::default() };
+        let total = encrypt_cb(&plaintext, &key, &mut encrypted);
+        assert_eq!(total, encrypted.output.len() as i64);
+        assert_eq!(encrypted.output.len(), streaming_encrypted_size(plaintext.len()));
+        assert_eq!(encrypted.calls, 4);
+        let mut output_len = 0usize;
+        let output = decrypt_file_streaming(encrypted.output.as_ptr(), encrypted.output.len(), key.as_ptr(), KEY_SIZE,
+                                            &mut output_len, None, ptr::null_mut());
+        assert_eq!(buffer_round_trip(output, output_len), plaintext);
+
+        // Buffer-encrypted containers decrypt through the sink
+        let output = encrypt_file_streaming(plaintext.as_ptr(), plaintext.len(), key.as_ptr(), KEY_SIZE,
+                                            &mut output_len, None, ptr::null_mut());
+        let container = buffer_round_trip(output, output_len);
+        assert_eq!(container.len(), encrypted.output.len());
+        let mut decrypted = CountingSink { collect: true, ..Default::default() };
+        assert_eq!(decrypt_cb(&container, &key, &mut decrypted), plaintext.len() as i64);
+        assert_eq!(decrypted.output, plaintext);
+        assert_eq!(decrypted.calls, 3);
+
+        // Empty input still produces a header-only container
+        let mut empty = CountingSink { collect: true, ..Default::default() };
+        assert_eq!(encrypt_cb(&[], &key, &mut empty), (HEADER_SIZE + NONCE_SIZE + KEY_SIZE + MAC_SIZE) as i64);
+        let mut empty_plain = CountingSink::default();
+        assert_eq!(decrypt_cb(&empty.output, &key, &mut empty_plain), 0);
+        assert_eq!(empty_plain.calls, 0);
+
+        // A non-zero sink return aborts; nothing after it is produced
+        let mut aborting = CountingSink { abort_at: Some(2), ..Default::default() };
+        assert_eq!(decrypt_cb(&container, &key, &mut aborting), ERROR_SINK_ABORTED as i64);
+        assert_eq!(aborting.calls, 2);
+        let mut aborting = CountingSink { abort_at: Some(1), ..Default::default() };
+        assert_eq!(encrypt_cb(&plaintext, &key, &mut aborting), ERROR_SINK_ABORTED as i64);
+        assert_eq!((aborting.calls, aborting.events.as_str()), (1, "S"));
+
+        // Errors from the container and arguments are reported as before
+        let mut wrong_key = CountingSink::default();
+        assert_eq!(decrypt_cb(&container, &[6u8; KEY_SIZE], &mut wrong_key), ERROR_DECRYPTION_FAILED as i64);
+        let mut truncated = CountingSink::default();
+        assert_eq!(decrypt_cb(&container[..container.len() - 1], &key, &mut truncated), ERROR_INVALID_FORMAT as i64);
+        assert_eq!(truncated.calls, 2);
+        assert_eq!(decrypt_file_streaming_cb(container.as_ptr(), container.len(), key.as_ptr(), KEY_SIZE, None, None,
+                                             ptr::null(), ptr::null_mut()), ERROR_NULL_POINTER as i64);
+    }
+
+    #[test]
+    fn test_streaming_cb_hands_over_each_chunk_without_retaining_it() {
+        let key = [8u8; KEY_SIZE];
+        let chunks = 8;
+        let plaintext = vec![0x5au8; DEFAULT_CHUNK_SIZE * chunks];
+        let container = {
+            let mut sink = CountingSink { collect: true, ..Default::default() };
+            encrypt_cb(&plaintext, &key, &mut sink);
+            sink.output
+        };
+
+        // Each chunk reaches the sink before the next one is decrypted
+        let mut counting = CountingSink::default();
+        let total = decrypt_cb(&container, &key, &mut counting);
+        assert_eq!(total, plaintext.len() as i64);
+        assert_eq!((counting.calls, counting.bytes), (chunks, plaintext.len()));
+        assert_eq!(counting.events, "SP".repeat(chunks));
+
+        let mut counting = CountingSink::default();
+        let total = encrypt_cb(&plaintext, &key, &mut counting);
+        assert_eq!(total, container.len() as i64);
+        assert_eq!(counting.events, format!("S{}", "SP".repeat(chunks)));
+    }
+
+    #[test]
+    fn test_streaming_stops_when_cancelled_from_progress() {
+        let key = [9u8; KEY_SIZE];
+        let plaintext = vec![0x3cu8; DEFAULT_CHUNK_SIZE * 8];
+        let container = {
+            let mut sink = CountingSink { collect: true, ..Default::default() };
+            encrypt_cb(&plaintext, &key, &mut sink);
+            sink.output
+        };
+        let last_error_code = || {
+            let json = unsafe { CString::from_raw(get_last_error_json(ptr::null_mut())) };
+            let envelope: serde_json::Value = serde_json::from_str(json.to_str().unwrap()).unwrap();
+            envelope["data"]["code"].as_i64()
+        };
+
+        // The buffer functions stop after the first chunk and hand back nothing
+        for decrypt in [false, true] {
+            clear_last_error();
+            let mut state = CountingSink { cancel_on_progress: true, ..Default::default() };
+            let cancel = &state.cancel as *const AtomicBool;
+            let user_data = &mut state as *mut CountingSink as *mut c_void;
+            let (mut output_len, mut status) = (0usize, 0);
+            let output = if decrypt {
+                decrypt_file_streaming_ex(container.as_ptr(), container.len(), key.as_ptr(), KEY_SIZE, &mut output_len,
+                                          Some(counting_progress), user_data, cancel, &mut status)
+            } else {
+                encrypt_file_streaming_ex(plaintext.as_ptr(), plaintext.len(), key.as_ptr(), KEY_SIZE, &mut output_len,
+                                          Some(counting_progress), user_data, cancel, &mut status)
+            };
+            assert!(output.is_null());
+            assert_eq!((status, output_len), (ERROR_CANCELLED, 0));
+            assert_eq!(state.events, "P");
+            assert_eq!(last_error_code(), Some(ERROR_CANCELLED as i64));
+        }
+
+        // Already cancelled: no chunk and no progress report
+        let mut state = CountingSink { cancel: AtomicBool::new(true), ..Default::default() };
+        let cancel = &state.cancel as *const AtomicBool;
+        let mut status = 0;
+        let output = encrypt_file_streaming_ex(plaintext.as_ptr(), plaintext.len(), key.as_ptr(), KEY_SIZE,
+                                               &mut 0usize, Some(counting_progress),
+                                               &mut state as *mut CountingSink as *mut c_void, cancel, &mut status);
+        assert!(output.is_null());
+        assert_eq!((status, state.events.as_str()), (ERROR_CANCELLED, ""));
+
+        // The sink functions return ERROR_CANCELLED, not a byte count or ERROR_SINK_ABORTED
+        let mut state = CountingSink { cancel_on_progress: true, ..Default::default() };
+        let cancel = &state.cancel as *const AtomicBool;
+        let result = encrypt_file_streaming_cb(plaintext.as_ptr(), plaintext.len(), key.as_ptr(), KEY_SIZE,
+                                               Some(counting_sink), Some(counting_progress), cancel,
+                                               &mut state as *mut CountingSink as *mut c_void);
+        assert_eq!(result, ERROR_CANCELLED as i64);
+        assert_eq!(state.events, "SSP");
+
+        let mut state = CountingSink { cancel_on_progress: true, ..Default::default() };
+        let cancel = &state.cancel as *const AtomicBool;
+        let result = decrypt_file_streaming_cb(container.as_ptr(), container.len(), key.as_ptr(), KEY_SIZE,
+                                               Some(counting_sink), Some(counting_progress), cancel,
+                                               &mut state as *mut CountingSink as *mut c_void);
+        assert_eq!(result, ERROR_CANCELLED as i64);
+        assert_eq!(state.events, "SP");
+    }
+
+    #[test]
+    fn test_preamble_matches_streaming_output() {
+        let key = [4u8; KEY_SIZE];
+        let plaintext = vec![7u8; 1000];
+
+        // Same seed, so the same FEK and wrapping nonce as the streaming call
+        let container = crate::rng::with_seeded_rng(1218, || {
+            let mut output_len = 0usize;
+            let output = encrypt_file_streaming(plaintext.as_ptr(), plaintext.len(), key.as_ptr(), KEY_SIZE,
+                                                &mut output_len, None, ptr::null_mut());
+            buffer_round_trip(output, output_len)
+        });
+        let (ctx, header_len) = crate::rng::with_seeded_rng(1218, || {
+            let mut header_len = 0usize;
+            (encrypt_file_init(key.as_ptr(), KEY_SIZE, &mut header_len), header_len)
+        });
+        assert!(!ctx.is_null());
+
+        // Size query and short buffers report the required length
+        let mut written = 0usize;
+        assert_eq!(encrypt_file_get_preamble(ctx, ptr::null_mut(), 0, &mut written), ERROR_OUTPUT_TOO_SMALL);
+        assert_eq!(written, header_len);
+        let mut preamble = vec![0u8; header_len + 8];
+        assert_eq!(encrypt_file_get_preamble(ctx, preamble.as_mut_ptr(), header_len - 1, &mut written),
+                   ERROR_OUTPUT_TOO_SMALL);
+        assert_eq!(encrypt_file_get_preamble(ctx, preamble.as_mut_ptr(), preamble.len(), &mut written), SUCCESS);
+        assert_eq!(written, header_len);
+        assert_eq!(&preamble[..written], &container[..header_len]);
+
+        let mut fek_len = 0usize;
+        let fek = encrypt_file_get_wrapped_fek(ctx, &mut fek_len);
+        assert_eq!(&preamble[HEADER_SIZE..written], unsafe { slice::from_raw_parts(fek, fek_len) });
+        free_buffer(fek);
+
+        // Later changes to the context show up in the preamble
+        encrypt_file_set_chunk_crc(ctx, 1);
+        assert_eq!(encrypt_file_get_preamble(ctx, preamble.as_mut_ptr(), preamble.len(), &mut written), SUCCESS);
+        assert_eq!(preamble[HEADER_FLAGS_OFFSET], HEADER_FLAG_CHUNK_CRC);
+        assert_eq!(&preamble[HEADER_SIZE..written], &container[HEADER_SIZE..header_len]);
+        assert_eq!(encrypt_file_get_preamble(ptr::null_mut(), preamble.as_mut_ptr(), preamble.len(), &mut written),
+                   ERROR_NULL_POINTER);
+        encrypt_file_finalize(ctx);
+    }
+}
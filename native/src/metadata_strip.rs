@@ -0,0 +1,676 @@
+/// Image metadata stripping for CloudNexus uploads
+/// Removes location and camera metadata from JPEG and PNG files by editing their
+/// metadata segments in place. Compressed image data is copied byte for byte and
+/// never re-encoded; files that are not JPEG or PNG pass through unchanged.
+use std::ffi::c_char;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::errors::checked;
+use crate::file_io::{ERROR_NULL_POINTER, ERROR_FILE_NOT_FOUND, ERROR_IO_FAILED, SUCCESS, c_str_to_path};
+use crate::temp::{commit_temp_file, create_temp_file_for, discard_temp_file};
+use crate::thumbnail::ERROR_IMAGE_PROCESSING_FAILED;
+
+/// Keep all metadata
+pub const STRIP_METADATA_NONE: i32 = 0;
+/// Remove GPS tags only (the Exif GPS IFD and XMP packets carrying GPS data)
+pub const STRIP_METADATA_GPS_ONLY: i32 = 1;
+/// Remove Exif, XMP and IPTC metadata and PNG text chunks; the JPEG orientation is kept
+pub const STRIP_METADATA_ALL_EXIF: i32 = 2;
+
+pub use crate::errors::ERROR_INVALID_STRIP_POLICY;
+
+const JPEG_SIGNATURE: [u8; 3] = [0xFF, 0xD8, 0xFF];
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+const EXIF_IDENTIFIER: &[u8] = b"Exif\0\0";
+const XMP_IDENTIFIER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_EXTENSION_IDENTIFIER: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_GPS_IFD: u16 = 0x8825;
+
+/// Image formats whose metadata can be stripped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StripFormat {
+    Jpeg,
+    Png,
+}
+
+/// Whether a policy value is one of the STRIP_METADATA_* constants
+pub fn is_valid_strip_policy(policy: i32) -> bool {
+    matches!(policy, STRIP_METADATA_NONE | STRIP_METADATA_GPS_ONLY | STRIP_METADATA_ALL_EXIF)
+}
+
+/// Detect a strippable image from the first bytes of a file
+pub fn detect_strip_format(path: &Path) -> io::Result<Option<StripFormat>> {
+    let mut magic = [0u8; 8];
+    let mut file = File::open(path)?;
+    let mut filled = 0;
+    while filled < magic.len() {
+        match file.read(&mut magic[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+
+    Ok(if magic[..filled].starts_with(&JPEG_SIGNATURE) {
+        Some(StripFormat::Jpeg)
+    } else if magic[..filled] == PNG_SIGNATURE {
+        Some(StripFormat::Png)
+    } else {
+        None
+    })
+}
+
+/// Strip metadata from an in-memory image
+///
+/// Returns ERROR_IMAGE_PROCESSING_FAILED if the metadata structure is corrupt.
+pub fn strip_metadata_bytes(data: &[u8], format: StripFormat, policy: i32) -> Result<Vec<u8>, i32> {
+    if !is_valid_strip_policy(policy) {
+        return Err(ERROR_INVALID_STRIP_POLICY);
+    }
+    if policy == STRIP_METADATA_NONE {
+        return Ok(data.to_vec());
+    }
+
+    match format {
+        StripFormat::Jpeg => strip_jpeg(data, policy),
+        StripFormat::Png => strip_png(data, policy),
+    }
+    .ok_or(ERROR_IMAGE_PROCESSING_FAILED)
+}
+
+/// Write a stripped copy of `source` to `dest`
+///
+/// Returns Ok(false) without writing anything if `source` is not a JPEG or PNG.
+/// `dest` is written through a temp file, so a failure never leaves partial output.
+pub fn strip_image_file(source: &Path, dest: &Path, policy: i32) -> Result<bool, i32> {
+    if !is_valid_strip_policy(policy) {
+        return Err(ERROR_INVALID_STRIP_POLICY);
+    }
+
+    let format = match detect_strip_format(source).map_err(|_| ERROR_FILE_NOT_FOUND)? {
+        Some(format) => format,
+        None => return Ok(false),
+    };
+    let data = fs::read(source).map_err(|_| ERROR_IO_FAILED)?;
+    let stripped = strip_metadata_bytes(&data, format, policy)?;
+
+    let (temp_path, mut file) = create_temp_file_for(dest).map_err(|_| ERROR_IO_FAILED)?;
+    let written = file.write_all(&stripped).and_then(|_| file.flush());
+    drop(file);
+    if written.and_then(|_| commit_temp_file(&temp_path, dest)).is_err() {
+        let _ = discard_temp_file(&temp_path);
+        return Err(ERROR_IO_FAILED);
+    }
+    Ok(true)
+}
+
+/// Copy an image with its metadata removed
+///
+/// JPEG APP segments and PNG ancillary chunks are edited without re-encoding the
+/// image data. Files that are not JPEG or PNG are copied unchanged. A corrupt
+/// image fails without creating or truncating `dest_path`.
+///
+/// # Arguments
+/// * `source_path` - Path to the image to read
+/// * `dest_path` - Path to write the stripped copy to (can equal `source_path`)
+/// * `policy` - STRIP_METADATA_GPS_ONLY or STRIP_METADATA_ALL_EXIF (STRIP_METADATA_NONE copies unchanged)
+///
+/// # Returns
+/// 0 on success, error code on failure (ERROR_IMAGE_PROCESSING_FAILED for corrupt images)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn strip_image_metadata(source_path: *const c_char, dest_path: *const c_char, policy: i32) -> i32 {
+    if source_path.is_null() || dest_path.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let source = match unsafe { c_str_to_path(source_path) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+    let dest = match unsafe { c_str_to_path(dest_path) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+
+    match strip_image_file(&source, &dest, policy) {
+        Ok(true) => SUCCESS,
+        Ok(false) => {
+            if source == dest {
+                return SUCCESS;
+            }
+            let (temp_path, _) = match create_temp_file_for(&dest) {
+                Ok(t) => t,
+                Err(_) => return ERROR_IO_FAILED,
+            };
+            if fs::copy(&source, &temp_path).and_then(|_| commit_temp_file(&temp_path, &dest)).is_err() {
+                let _ = discard_temp_file(&temp_path);
+                return ERROR_IO_FAILED;
+            }
+            SUCCESS
+        }
+        Err(code) => checked(code),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// JPEG
+// ---------------------------------------------------------------------------
+
+fn strip_jpeg(data: &[u8], policy: i32) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&data[..2]);
+    let mut pos = 2;
+
+    loop {
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        // Any number of 0xFF fill bytes may precede a marker
+        let mut marker_pos = pos + 1;
+        while *data.get(marker_pos)? == 0xFF {
+            marker_pos += 1;
+        }
+        let marker = data[marker_pos];
+
+        match marker {
+            // EOI: anything after the image is dropped
+            0xD9 => {
+                output.extend_from_slice(&[0xFF, 0xD9]);
+                return Some(output);
+            }
+            // Standalone markers carry no length
+            0x01 | 0xD0..=0xD7 => {
+                output.extend_from_slice(&[0xFF, marker]);
+                pos = marker_pos + 1;
+                continue;
+            }
+            0x00 => return None,
+            _ => {}
+        }
+
+        let length = u16::from_be_bytes([*data.get(marker_pos + 1)?, *data.get(marker_pos + 2)?]) as usize;
+        if length < 2 {
+            return None;
+        }
+        let payload_start = marker_pos + 3;
+        let segment_end = marker_pos + 1 + length;
+        if segment_end > data.len() {
+            return None;
+        }
+        let payload = &data[payload_start..segment_end];
+
+        // SOS: the entropy-coded data and everything after it is copied verbatim,
+        // provided the image is not cut off before its EOI marker
+        if marker == 0xDA {
+            if !data[segment_end..].windows(2).any(|w| w == [0xFF, 0xD9]) {
+                return None;
+            }
+            output.extend_from_slice(&[0xFF, marker]);
+            output.extend_from_slice(&data[marker_pos + 1..]);
+            return Some(output);
+        }
+
+        match (marker, policy) {
+            (0xE1, _) if payload.starts_with(EXIF_IDENTIFIER) => {
+                let tiff = &payload[EXIF_IDENTIFIER.len()..];
+                if policy == STRIP_METADATA_ALL_EXIF {
+                    // Keep the orientation so the image still displays upright
+                    if let Some(orientation) = tiff_orientation(tiff).filter(|&o| o != 1) {
+                        write_jpeg_segment(&mut output, 0xE1, &orientation_only_exif(orientation))?;
+                    }
+                } else {
+                    let mut tiff = tiff.to_vec();
+                    remove_gps_ifd(&mut tiff)?;
+                    let mut stripped = EXIF_IDENTIFIER.to_vec();
+                    stripped.extend_from_slice(&tiff);
+                    write_jpeg_segment(&mut output, 0xE1, &stripped)?;
+                }
+            }
+            (0xE1, STRIP_METADATA_ALL_EXIF)
+                if payload.starts_with(XMP_IDENTIFIER) || payload.starts_with(XMP_EXTENSION_IDENTIFIER) => {}
+            (0xE1, STRIP_METADATA_GPS_ONLY)
+                if (payload.starts_with(XMP_IDENTIFIER) || payload.starts_with(XMP_EXTENSION_IDENTIFIER))
+                    && xmp_mentions_gps(payload) => {}
+            // APP13 holds Photoshop IPTC records (captions, locations, authors)
+            (0xED, STRIP_METADATA_ALL_EXIF) => {}
+            _ => output.extend_from_slice(&data[pos..segment_end]),
+        }
+
+        pos = segment_end;
+    }
+}
+
+fn write_jpeg_segment(output: &mut Vec<u8>, marker: u8, payload: &[u8]) -> Option<()> {
+    let length = u16::try_from(payload.len() + 2).ok()?;
+    output.extend_from_slice(&[0xFF, marker]);
+    output.extend_from_slice(&length.to_be_bytes());
+    output.extend_from_slice(payload);
+    Some(())
+}
+
+fn xmp_mentions_gps(packet: &[u8]) -> bool {
+    packet.windows(7).any(|w| w == b"exif:GP")
+}
+
+// ---------------------------------------------------------------------------
+// PNG
+// ---------------------------------------------------------------------------
+
+fn strip_png(data: &[u8], policy: i32) -> Option<Vec<u8>> {
+    if !data.starts_with(&PNG_SIGNATURE) {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&PNG_SIGNATURE);
+    let mut pos = PNG_SIGNATURE.len();
+
+    loop {
+        let header = data.get(pos..pos + 8)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let chunk_type: [u8; 4] = [header[4], header[5], header[6], header[7]];
+        let chunk_end = pos.checked_add(12)?.checked_add(length)?;
+        if chunk_end > data.len() {
+            return None;
+        }
+        let chunk_data = &data[pos + 8..pos + 8 + length];
+
+        match (&chunk_type, policy) {
+            (b"tEXt" | b"zTXt" | b"iTXt" | b"eXIf", STRIP_METADATA_ALL_EXIF) => {}
+            (b"iTXt", STRIP_METADATA_GPS_ONLY)
+                if chunk_data.starts_with(PNG_XMP_KEYWORD) && xmp_mentions_gps(chunk_data) => {}
+            (b"eXIf", STRIP_METADATA_GPS_ONLY) => {
+                let mut tiff = chunk_data.to_vec();
+                remove_gps_ifd(&mut tiff)?;
+                let mut crc = crc32fast::Hasher::new();
+                crc.update(&chunk_type);
+                crc.update(&tiff);
+                output.extend_from_slice(&(tiff.len() as u32).to_be_bytes());
+                output.extend_from_slice(&chunk_type);
+                output.extend_from_slice(&tiff);
+                output.extend_from_slice(&crc.finalize().to_be_bytes());
+            }
+            _ => output.extend_from_slice(&data[pos..chunk_end]),
+        }
+
+        pos = chunk_end;
+        if &chunk_type == b"IEND" {
+            return Some(output);
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// TIFF (Exif) structure
+// ---------------------------------------------------------------------------
+
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        let little_endian = match data.get(..2)? {
+            b"II" => true,
+            b"MM" => false,
+            _ => return None,
+        };
+        let tiff = Self { data, little_endian };
+        if tiff.u16_at(2)? != 42 {
+            return None;
+        }
+        Some(tiff)
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = [*self.data.get(offset)?, *self.data.get(offset + 1)?];
+        Some(if self.little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    }
+
+    fn ifd0_offset(&self) -> Option<usize> {
+        self.u32_at(4).map(|o| o as usize)
+    }
+
+    /// Entry count of the IFD at `offset`, checking that its entries and next pointer fit
+    fn ifd_entry_count(&self, offset: usize) -> Option<usize> {
+        let count = self.u16_at(offset)? as usize;
+        if offset + 2 + count * 12 + 4 > self.data.len() {
+            return None;
+        }
+        Some(count)
+    }
+
+    /// Byte offset of the entry with `tag` in the IFD at `offset`
+    fn find_entry(&self, offset: usize, tag: u16) -> Option<Option<usize>> {
+        let count = self.ifd_entry_count(offset)?;
+        Some((0..count).map(|i| offset + 2 + i * 12).find(|&entry| self.u16_at(entry) == Some(tag)))
+    }
+}
+
+fn tiff_type_size(field_type: u16) -> Option<usize> {
+    match field_type {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 | 13 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    }
+}
+
+fn tiff_orientation(data: &[u8]) -> Option<u16> {
+    let tiff = Tiff::parse(data)?;
+    let entry = tiff.find_entry(tiff.ifd0_offset()?, TAG_ORIENTATION)??;
+    if tiff.u16_at(entry + 2)? != 3 {
+        return None;
+    }
+    tiff.u16_at(entry + 8)
+}
+
+/// Minimal little-endian Exif payload holding only an orientation tag
+fn orientation_only_exif(orientation: u16) -> Vec<u8> {
+    let mut payload = EXIF_IDENTIFIER.to_vec();
+    payload.extend_from_slice(b"II");
+    payload.extend_from_slice(&42u16.to_le_bytes());
+    payload.extend_from_slice(&8u32.to_le_bytes());
+    payload.extend_from_slice(&1u16.to_le_bytes());
+    payload.extend_from_slice(&TAG_ORIENTATION.to_le_bytes());
+    payload.extend_from_slice(&3u16.to_le_bytes());
+    payload.extend_from_slice(&1u32.to_le_bytes());
+    payload.extend_from_slice(&orientation.to_le_bytes());
+    payload.extend_from_slice(&[0, 0]);
+    payload.extend_from_slice(&0u32.to_le_bytes());
+    payload
+}
+
+/// Remove the GPS IFD pointer from IFD0 and zero the GPS IFD and its values
+///
+/// Offsets of every other IFD and value stay valid because nothing moves except
+/// the IFD0 entries after the removed pointer, which shift down by one slot.
+fn remove_gps_ifd(data: &mut [u8]) -> Option<()> {
+    let (ifd0, pointer_entry, ranges) = {
+        let tiff = Tiff::parse(data)?;
+        let ifd0 = tiff.ifd0_offset()?;
+        let entry = match tiff.find_entry(ifd0, TAG_GPS_IFD)? {
+            Some(entry) => entry,
+            None => return Some(()),
+        };
+        let gps_offset = tiff.u32_at(entry + 8)? as usize;
+
+        // Ranges holding GPS data; a GPS IFD that does not parse is still unlinked
+        let mut ranges = Vec::new();
+        if let Some(count) = tiff.ifd_entry_count(gps_offset) {
+            ranges.push((gps_offset, gps_offset + 2 + count * 12 + 4));
+            for i in 0..count {
+                let gps_entry = gps_offset + 2 + i * 12;
+                let size = tiff_type_size(tiff.u16_at(gps_entry + 2)?)
+                    .and_then(|s| s.checked_mul(tiff.u32_at(gps_entry + 4)? as usize));
+                if let Some(size) = size.filter(|&s| s > 4) {
+                    let value_offset = tiff.u32_at(gps_entry + 8)? as usize;
+                    if value_offset.checked_add(size).is_some_and(|end| end <= data.len()) {
+                        ranges.push((value_offset, value_offset + size));
+                    }
+                }
+            }
+        }
+        (ifd0, entry, ranges)
+    };
+
+    for (start, end) in ranges {
+        data[start..end].fill(0);
+    }
+
+    // Shift the following entries and the next-IFD pointer over the removed entry
+    let (count, little_endian) = {
+        let tiff = Tiff::parse(data)?;
+        (tiff.ifd_entry_count(ifd0)?, tiff.little_endian)
+    };
+    let ifd_end = ifd0 + 2 + count * 12 + 4;
+    data.copy_within(pointer_entry + 12..ifd_end, pointer_entry);
+    data[ifd_end - 12..ifd_end].fill(0);
+    let new_count = (count - 1) as u16;
+    data[ifd0..ifd0 + 2].copy_from_slice(&if little_endian { new_count.to_le_bytes() } else { new_count.to_be_bytes() });
+
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    /// Big-endian TIFF with IFD0 (Make, Orientation, GPS pointer) and a GPS IFD
+    /// holding a latitude reference and a latitude (three rationals stored out of line)
+    fn exif_tiff_with_gps() -> Vec<u8> {
+        let mut tiff = b"MM".to_vec();
+        tiff.extend_from_slice(&42u16.to_be_bytes());
+        tiff.extend_from_slice(&8u32.to_be_bytes());
+
+        // IFD0 at 8: 3 entries, ends at 8 + 2 + 36 + 4 = 50
+        let gps_ifd: u32 = 50;
+        tiff.extend_from_slice(&3u16.to_be_bytes());
+        // Make: ASCII "Cam\0" inline
+        tiff.extend_from_slice(&0x010Fu16.to_be_bytes());
+        tiff.extend_from_slice(&2u16.to_be_bytes());
+        tiff.extend_from_slice(&4u32.to_be_bytes());
+        tiff.extend_from_slice(b"Cam\0");
+        // Orientation: 6 (rotate 90)
+        tiff.extend_from_slice(&TAG_ORIENTATION.to_be_bytes());
+        tiff.extend_from_slice(&3u16.to_be_bytes());
+        tiff.extend_from_slice(&1u32.to_be_bytes());
+        tiff.extend_from_slice(&[0, 6, 0, 0]);
+        // GPS IFD pointer
+        tiff.extend_from_slice(&TAG_GPS_IFD.to_be_bytes());
+        tiff.extend_from_slice(&4u16.to_be_bytes());
+        tiff.extend_from_slice(&1u32.to_be_bytes());
+        tiff.extend_from_slice(&gps_ifd.to_be_bytes());
+        tiff.extend_from_slice(&0u32.to_be_bytes());
+
+        // GPS IFD at 50: 2 entries, ends at 50 + 2 + 24 + 4 = 80
+        let latitude_values: u32 = 80;
+        tiff.extend_from_slice(&2u16.to_be_bytes());
+        // GPSLatitudeRef "N\0"
+        tiff.extend_from_slice(&1u16.to_be_bytes());
+        tiff.extend_from_slice(&2u16.to_be_bytes());
+        tiff.extend_from_slice(&2u32.to_be_bytes());
+        tiff.extend_from_slice(b"N\0\0\0");
+        // GPSLatitude: 3 rationals out of line
+        tiff.extend_from_slice(&2u16.to_be_bytes());
+        tiff.extend_from_slice(&5u16.to_be_bytes());
+        tiff.extend_from_slice(&3u32.to_be_bytes());
+        tiff.extend_from_slice(&latitude_values.to_be_bytes());
+        tiff.extend_from_slice(&0u32.to_be_bytes());
+        for value in [47u32, 1, 22, 1, 3069, 100] {
+            tiff.extend_from_slice(&value.to_be_bytes());
+        }
+        tiff
+    }
+
+    /// SOI, JFIF APP0, Exif APP1, DQT, then SOS with entropy data (including a
+    /// stuffed 0xFF00 and a restart marker) and EOI
+    fn fixture_jpeg() -> (Vec<u8>, Vec<u8>) {
+        let mut jpeg = vec![0xFF, 0xD8];
+        write_jpeg_segment(&mut jpeg, 0xE0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0").unwrap();
+        let mut exif = EXIF_IDENTIFIER.to_vec();
+        exif.extend_from_slice(&exif_tiff_with_gps());
+        write_jpeg_segment(&mut jpeg, 0xE1, &exif).unwrap();
+        let mut xmp = XMP_IDENTIFIER.to_vec();
+        xmp.extend_from_slice(b"<x:xmpmeta><exif:GPSLatitude>47,22N</exif:GPSLatitude></x:xmpmeta>");
+        write_jpeg_segment(&mut jpeg, 0xE1, &xmp).unwrap();
+        write_jpeg_segment(&mut jpeg, 0xDB, &[0u8; 65]).unwrap();
+
+        let mut pixels = vec![0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00];
+        pixels.extend_from_slice(&[0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56, 0x78]);
+        pixels.extend_from_slice(&[0xFF, 0xD9]);
+        jpeg.extend_from_slice(&pixels);
+        (jpeg, pixels)
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("cloud_nexus_strip_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_strip_jpeg_gps_only() {
+        let (jpeg, pixels) = fixture_jpeg();
+        let latitude: Vec<u8> = [47u32, 1, 22, 1].iter().flat_map(|v| v.to_be_bytes()).collect();
+        assert!(contains(&jpeg, &latitude));
+
+        let stripped = strip_metadata_bytes(&jpeg, StripFormat::Jpeg, STRIP_METADATA_GPS_ONLY).unwrap();
+        assert!(stripped.ends_with(&pixels));
+        assert!(!contains(&stripped, &latitude));
+        assert!(!contains(&stripped, b"GPSLatitude"));
+        assert!(contains(&stripped, b"JFIF"));
+
+        // The rest of IFD0 survives and no longer points at a GPS IFD
+        let exif_start = stripped.windows(6).position(|w| w == EXIF_IDENTIFIER).unwrap() + 6;
+        let tiff = Tiff::parse(&stripped[exif_start..]).unwrap();
+        let ifd0 = tiff.ifd0_offset().unwrap();
+        assert_eq!(tiff.ifd_entry_count(ifd0), Some(2));
+        assert_eq!(tiff.find_entry(ifd0, TAG_GPS_IFD), Some(None));
+        assert!(tiff.find_entry(ifd0, 0x010F).unwrap().is_some());
+        assert_eq!(tiff_orientation(&stripped[exif_start..]), Some(6));
+        assert_eq!(tiff.u32_at(ifd0 + 2 + 2 * 12), Some(0));
+    }
+
+    #[test]
+    fn test_strip_jpeg_all_exif_keeps_orientation() {
+        let (jpeg, pixels) = fixture_jpeg();
+        let stripped = strip_metadata_bytes(&jpeg, StripFormat::Jpeg, STRIP_METADATA_ALL_EXIF).unwrap();
+        assert!(stripped.ends_with(&pixels));
+        assert!(!contains(&stripped, b"Cam\0"));
+        assert!(!contains(&stripped, b"xmpmeta"));
+
+        let exif_start = stripped.windows(6).position(|w| w == EXIF_IDENTIFIER).unwrap() + 6;
+        assert_eq!(tiff_orientation(&stripped[exif_start..]), Some(6));
+    }
+
+    #[test]
+    fn test_strip_png_chunks() {
+        fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+            let mut out = (data.len() as u32).to_be_bytes().to_vec();
+            out.extend_from_slice(kind);
+            out.extend_from_slice(data);
+            let mut crc = crc32fast::Hasher::new();
+            crc.update(kind);
+            crc.update(data);
+            out.extend_from_slice(&crc.finalize().to_be_bytes());
+            out
+        }
+
+        let idat = chunk(b"IDAT", &[1, 2, 3, 4]);
+        let mut png = PNG_SIGNATURE.to_vec();
+        png.extend(chunk(b"IHDR", &[0; 13]));
+        png.extend(chunk(b"tEXt", b"Comment\0secret"));
+        png.extend(chunk(b"eXIf", &exif_tiff_with_gps()));
+        png.extend(&idat);
+        png.extend(chunk(b"IEND", &[]));
+
+        let gps_only = strip_metadata_bytes(&png, StripFormat::Png, STRIP_METADATA_GPS_ONLY).unwrap();
+        assert!(contains(&gps_only, b"secret"));
+        assert!(contains(&gps_only, &idat));
+        let exif_pos = gps_only.windows(4).position(|w| w == b"eXIf").unwrap();
+        let len = u32::from_be_bytes(gps_only[exif_pos - 4..exif_pos].try_into().unwrap()) as usize;
+        let exif = &gps_only[exif_pos + 4..exif_pos + 4 + len];
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(b"eXIf");
+        crc.update(exif);
+        assert_eq!(gps_only[exif_pos + 4 + len..exif_pos + 8 + len], crc.finalize().to_be_bytes());
+        let tiff = Tiff::parse(exif).unwrap();
+        assert_eq!(tiff.find_entry(8, TAG_GPS_IFD), Some(None));
+
+        let all = strip_metadata_bytes(&png, StripFormat::Png, STRIP_METADATA_ALL_EXIF).unwrap();
+        assert!(!contains(&all, b"secret"));
+        assert!(!contains(&all, b"eXIf"));
+        assert!(contains(&all, &idat));
+    }
+
+    #[test]
+    fn test_strip_image_metadata_files() {
+        let (jpeg, pixels) = fixture_jpeg();
+        let source = temp_path("source.jpg");
+        let dest = temp_path("dest.jpg");
+        fs::write(&source, &jpeg).unwrap();
+        let source_c = CString::new(source.to_str().unwrap()).unwrap();
+        let dest_c = CString::new(dest.to_str().unwrap()).unwrap();
+
+        assert_eq!(strip_image_metadata(source_c.as_ptr(), dest_c.as_ptr(), STRIP_METADATA_GPS_ONLY), SUCCESS);
+        assert!(fs::read(&dest).unwrap().ends_with(&pixels));
+        assert_eq!(strip_image_metadata(source_c.as_ptr(), dest_c.as_ptr(), 9), ERROR_INVALID_STRIP_POLICY);
+
+        // A truncated JPEG fails and leaves no output behind
+        let _ = fs::remove_file(&dest);
+        fs::write(&source, &jpeg[..40]).unwrap();
+        assert_eq!(
+            strip_image_metadata(source_c.as_ptr(), dest_c.as_ptr(), STRIP_METADATA_ALL_EXIF),
+            ERROR_IMAGE_PROCESSING_FAILED
+        );
+        assert!(!dest.exists());
+
+        // Non-images are copied unchanged
+        fs::write(&source, b"plain text, not an image").unwrap();
+        assert_eq!(strip_image_metadata(source_c.as_ptr(), dest_c.as_ptr(), STRIP_METADATA_ALL_EXIF), SUCCESS);
+        assert_eq!(fs::read(&dest).unwrap(), b"plain text, not an image");
+
+        let _ = fs::remove_file(&source);
+        let _ = fs::remove_file(&dest);
+    }
+
+    #[test]
+    fn test_upload_streams_stripped_copy() {
+        use crate::upload::{upload_finalize, upload_free, upload_get_total_bytes, upload_init, upload_process_chunk,
+                            upload_set_strip_metadata};
+
+        let (jpeg, pixels) = fixture_jpeg();
+        let source = temp_path("upload.jpg");
+        fs::write(&source, &jpeg).unwrap();
+        let source_c = CString::new(source.to_str().unwrap()).unwrap();
+
+        let ctx = upload_init(source_c.as_ptr(), std::ptr::null(), 0, 0, 0, None, None, std::ptr::null(), std::ptr::null_mut());
+        assert!(!ctx.is_null());
+        assert_eq!(upload_set_strip_metadata(ctx, 7), ERROR_INVALID_STRIP_POLICY);
+        assert_eq!(upload_set_strip_metadata(ctx, STRIP_METADATA_ALL_EXIF), SUCCESS);
+        let strip_temps = || {
+            let prefix = format!("upload-strip.{}-", std::process::id());
+            fs::read_dir(std::env::temp_dir()).unwrap()
+                .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with(&prefix))
+                .count()
+        };
+        assert_eq!(strip_temps(), 1);
+
+        let total = upload_get_total_bytes(ctx);
+        assert!(total < jpeg.len());
+        let mut buffer = vec![0u8; total + 64];
+        let n = upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, None, std::ptr::null_mut());
+        assert_eq!(n as usize, total);
+        assert!(buffer[..total].ends_with(&pixels));
+        assert!(!contains(&buffer[..total], b"Cam\0"));
+
+        assert_eq!(upload_finalize(ctx), SUCCESS);
+        assert_eq!(strip_temps(), 0);
+        upload_free(ctx);
+
+        // The source itself is never modified
+        assert_eq!(fs::read(&source).unwrap(), jpeg);
+        let _ = fs::remove_file(&source);
+    }
+}
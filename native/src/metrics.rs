@@ -0,0 +1,345 @@
+/// Anonymous operation counters for CloudNexus
+///
+/// Copies, uploads, downloads, encryption and search bump process-wide
+/// counters at fixed points (an operation finishing, a chunk passing through
+/// the cipher, a failure code being returned). Nothing is logged per event;
+/// the app reads the aggregated totals with metrics_snapshot_json when it
+/// wants to report them, and metrics_reset starts a new period. Counters are
+/// relaxed atomics, so they cost one uncontended add on the hot paths.
+use std::collections::BTreeMap;
+use std::ffi::c_char;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(not(test))]
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::ffi_util::json_envelope;
+
+/// A named counter in the registry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Counter {
+    /// Files copied successfully (single-file and folder copies)
+    CopiesCompleted,
+    /// File copies that returned an error, including cancellation
+    CopiesFailed,
+    /// Bytes of files copied successfully
+    BytesCopied,
+    /// Uploads finalized after reading the whole source
+    UploadsCompleted,
+    /// Plaintext bytes of completed uploads
+    UploadBytes,
+    /// Downloads finalized and moved into place
+    DownloadsCompleted,
+    /// Bytes written by completed downloads
+    DownloadBytes,
+    /// Chunk records encrypted
+    ChunksEncrypted,
+    /// Plaintext bytes encrypted (chunk records and whole-buffer calls)
+    BytesEncrypted,
+    /// Chunk records decrypted
+    ChunksDecrypted,
+    /// Plaintext bytes produced by decryption
+    BytesDecrypted,
+    /// Chunks or buffers that failed to decrypt
+    DecryptFailures,
+    /// Index queries answered
+    Searches,
+    /// Index queries answered from the query cache
+    SearchCacheHits,
+}
+
+impl Counter {
+    const ALL: [Counter; 14] = [
+        Counter::CopiesCompleted,
+        Counter::CopiesFailed,
+        Counter::BytesCopied,
+        Counter::UploadsCompleted,
+        Counter::UploadBytes,
+        Counter::DownloadsCompleted,
+        Counter::DownloadBytes,
+        Counter::ChunksEncrypted,
+        Counter::BytesEncrypted,
+        Counter::ChunksDecrypted,
+        Counter::BytesDecrypted,
+        Counter::DecryptFailures,
+        Counter::Searches,
+        Counter::SearchCacheHits,
+    ];
+
+    /// Key of the counter in the snapshot JSON
+    fn name(self) -> &'static str {
+        match self {
+            Counter::CopiesCompleted => "copies_completed",
+            Counter::CopiesFailed => "copies_failed",
+            Counter::BytesCopied => "bytes_copied",
+            Counter::UploadsCompleted => "uploads_completed",
+            Counter::UploadBytes => "upload_bytes",
+            Counter::DownloadsCompleted => "downloads_completed",
+            Counter::DownloadBytes => "download_bytes",
+            Counter::ChunksEncrypted => "chunks_encrypted",
+            Counter::BytesEncrypted => "bytes_encrypted",
+            Counter::ChunksDecrypted => "chunks_decrypted",
+            Counter::BytesDecrypted => "bytes_decrypted",
+            Counter::DecryptFailures => "decrypt_failures",
+            Counter::Searches => "searches",
+            Counter::SearchCacheHits => "search_cache_hits",
+        }
+    }
+}
+
+/// Error codes counted individually run from -1 down to -(ERROR_SLOTS - 1);
+/// slot 0 collects any other code
+const ERROR_SLOTS: usize = 96;
+
+/// Process-wide counter registry
+struct Metrics {
+    started: Instant,
+    /// Milliseconds after `started` of the last metrics_reset
+    reset_at_ms: AtomicU64,
+    counters: [AtomicU64; Counter::ALL.len()],
+    errors: [AtomicU64; ERROR_SLOTS],
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            started: Instant::now(),
+            reset_at_ms: AtomicU64::new(0),
+            counters: std::array::from_fn(|_| AtomicU64::new(0)),
+            errors: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    fn snapshot(&self) -> MetricsSnapshot {
+        let uptime_ms = self.started.elapsed().as_millis() as u64;
+        let mut errors = BTreeMap::new();
+        let mut other_errors = 0;
+        for (slot, count) in self.errors.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count == 0 {
+                continue;
+            }
+            if slot == 0 {
+                other_errors = count;
+            } else {
+                errors.insert(-(slot as i32), count);
+            }
+        }
+
+        MetricsSnapshot {
+            uptime_ms,
+            since_reset_ms: uptime_ms.saturating_sub(self.reset_at_ms.load(Ordering::Relaxed)),
+            counters: Counter::ALL
+                .iter()
+                .map(|&counter| (counter.name(), self.counters[counter as usize].load(Ordering::Relaxed)))
+                .collect(),
+            errors,
+            other_errors,
+        }
+    }
+
+    fn reset(&self) {
+        for counter in self.counters.iter().chain(self.errors.iter()) {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.reset_at_ms.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(test))]
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Each test thread counts into its own registry, so tests running in
+/// parallel only see the operations they performed themselves
+#[cfg(test)]
+fn metrics() -> &'static Metrics {
+    thread_local! {
+        static METRICS: &'static Metrics = Box::leak(Box::new(Metrics::new()));
+    }
+    METRICS.with(|metrics| *metrics)
+}
+
+/// Add `amount` to a counter
+pub(crate) fn count(counter: Counter, amount: u64) {
+    metrics().counters[counter as usize].fetch_add(amount, Ordering::Relaxed);
+}
+
+/// Count one occurrence of a negative error code
+pub(crate) fn count_error(code: i32) {
+    let slot = code.checked_neg().map(|slot| slot as usize).filter(|&slot| slot < ERROR_SLOTS).unwrap_or(0);
+    metrics().errors[slot].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count the outcome of a file copy: bytes copied, or the error code
+pub(crate) fn count_copy(result: Result<u64, i32>) {
+    match result {
+        Ok(bytes) => {
+            count(Counter::CopiesCompleted, 1);
+            count(Counter::BytesCopied, bytes);
+        }
+        Err(code) => {
+            count(Counter::CopiesFailed, 1);
+            count_error(code);
+        }
+    }
+}
+
+/// Count the outcome of decrypting a chunk or buffer: plaintext bytes, or the error code
+pub(crate) fn count_decrypt(result: Result<u64, i32>) {
+    match result {
+        Ok(bytes) => {
+            count(Counter::ChunksDecrypted, 1);
+            count(Counter::BytesDecrypted, bytes);
+        }
+        Err(code) => {
+            count(Counter::DecryptFailures, 1);
+            count_error(code);
+        }
+    }
+}
+
+/// Totals returned by metrics_snapshot_json
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    /// Milliseconds since the registry was first used
+    pub uptime_ms: u64,
+    /// Milliseconds the counters have been accumulating since the last reset
+    pub since_reset_ms: u64,
+    /// Counter values by name
+    pub counters: BTreeMap<&'static str, u64>,
+    /// How often each error code was returned, for codes seen at least once
+    pub errors: BTreeMap<i32, u64>,
+    /// Errors with codes outside the counted range
+    pub other_errors: u64,
+}
+
+/// Get all operation counters
+///
+/// Counters cover the whole process since start or the last metrics_reset.
+/// `errors` maps error codes (as strings, e.g. "-6") returned by copies,
+/// uploads, downloads and decryption to how often they occurred.
+///
+/// # Arguments
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope (see ffi_util.rs) whose `data` is
+/// `{"uptime_ms", "since_reset_ms", "counters": {...}, "errors": {...}, "other_errors"}`.
+/// Caller must free with scan_folder_free_string.
+#[no_mangle]
+pub extern "C" fn metrics_snapshot_json(out_len: *mut usize) -> *mut c_char {
+    json_envelope(Ok(metrics().snapshot()), out_len)
+}
+
+/// Set all operation counters back to zero
+///
+/// Uptime keeps counting; `since_reset_ms` in later snapshots starts over.
+#[no_mangle]
+pub extern "C" fn metrics_reset() {
+    metrics().reset();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::fs;
+    use std::ptr;
+
+    use crate::errors::ERROR_DECRYPTION_FAILED as DECRYPTION_FAILED;
+    use crate::file_io::{ERROR_FILE_NOT_FOUND, SUCCESS};
+    use crate::{copy_file_streaming, decrypt_chunk, decrypt_file_finalize, decrypt_file_init, free_buffer};
+
+    fn snapshot() -> serde_json::Value {
+        let mut len = 0usize;
+        let ptr = metrics_snapshot_json(&mut len);
+        let json = unsafe { CString::from_raw(ptr) }.into_string().unwrap();
+        assert_eq!(json.len(), len);
+        let envelope: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(envelope["ok"], true);
+        envelope["data"].clone()
+    }
+
+    fn counter(snapshot: &serde_json::Value, name: &str) -> u64 {
+        snapshot["counters"][name].as_u64().unwrap()
+    }
+
+    fn error_count(snapshot: &serde_json::Value, code: i32) -> u64 {
+        snapshot["errors"][code.to_string()].as_u64().unwrap_or(0)
+    }
+
+    #[test]
+    fn test_copy_and_failed_decrypt_move_counters() {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_metrics_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let src = CString::new(root.join("src.bin").to_string_lossy().to_string()).unwrap();
+        let dst = CString::new(root.join("dst.bin").to_string_lossy().to_string()).unwrap();
+        let missing = CString::new(root.join("missing.bin").to_string_lossy().to_string()).unwrap();
+        fs::write(root.join("src.bin"), vec![5u8; 3000]).unwrap();
+
+        metrics_reset();
+        let before = snapshot();
+        assert!(before["counters"].as_object().unwrap().values().all(|v| v == 0));
+        assert!(before["uptime_ms"].as_u64().unwrap() >= before["since_reset_ms"].as_u64().unwrap());
+
+        // One copy that succeeds and one whose source is missing
+        assert_eq!(copy_file_streaming(src.as_ptr(), dst.as_ptr(), 1024, None, ptr::null(), ptr::null_mut()), SUCCESS);
+        assert_eq!(copy_file_streaming(missing.as_ptr(), dst.as_ptr(), 1024, None, ptr::null(), ptr::null_mut()),
+                   ERROR_FILE_NOT_FOUND);
+
+        // Encrypt two chunks, then decrypt one intact and one tampered with
+        let key = [7u8; 32];
+        let (mut container, offsets) = crate::tests::small_chunk_container(&key, &[&[1u8; 100], &[2u8; 50]], false);
+        let ctx = decrypt_file_init(container.as_ptr(), offsets[0], key.as_ptr(), key.len());
+        assert!(!ctx.is_null());
+        let mut out_len = 0usize;
+        let plain = decrypt_chunk(ctx, container[offsets[0]..].as_ptr(), offsets[1] - offsets[0], &mut out_len);
+        assert_eq!(out_len, 100);
+        free_buffer(plain);
+        let last = container.len() - 1;
+        container[last] ^= 0xFF;
+        assert!(decrypt_chunk(ctx, container[offsets[1]..].as_ptr(), container.len() - offsets[1], &mut out_len).is_null());
+        decrypt_file_finalize(ctx);
+
+        let after = snapshot();
+        assert_eq!(counter(&after, "copies_completed"), 1);
+        assert_eq!(counter(&after, "copies_failed"), 1);
+        assert_eq!(counter(&after, "bytes_copied"), 3000);
+        assert_eq!(counter(&after, "chunks_encrypted"), 2);
+        assert_eq!(counter(&after, "bytes_encrypted"), 150);
+        assert_eq!(counter(&after, "chunks_decrypted"), 1);
+        assert_eq!(counter(&after, "bytes_decrypted"), 100);
+        assert_eq!(counter(&after, "decrypt_failures"), 1);
+        assert_eq!(error_count(&after, ERROR_FILE_NOT_FOUND), 1);
+        assert_eq!(error_count(&after, DECRYPTION_FAILED), 1);
+        assert_eq!(after["other_errors"], 0);
+
+        // Reset starts a new period
+        metrics_reset();
+        let reset = snapshot();
+        assert_eq!(counter(&reset, "copies_completed"), 0);
+        assert_eq!(reset["errors"], serde_json::json!({}));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_error_codes_outside_the_counted_range() {
+        metrics_reset();
+        count_error(-1);
+        count_error(-(ERROR_SLOTS as i32 - 1));
+        count_error(-(ERROR_SLOTS as i32));
+        count_error(i32::MIN);
+        count_error(5);
+        let snapshot = snapshot();
+        assert_eq!(error_count(&snapshot, -1), 1);
+        assert_eq!(error_count(&snapshot, -(ERROR_SLOTS as i32 - 1)), 1);
+        assert_eq!(snapshot["other_errors"], 3);
+    }
+}
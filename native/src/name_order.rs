@@ -0,0 +1,241 @@
+/// File name ordering shared by folder scans, directory listings and folder copies
+///
+/// Three orders are available, all total and deterministic (two names compare
+/// equal only if they are byte-for-byte identical):
+/// - BYTE compares the raw name bytes, so "Banana" sorts before "apple".
+/// - CASE_INSENSITIVE compares the Unicode lowercase of each character, then
+///   falls back to BYTE, so "apple" < "Banana" < "cherry".
+/// - NATURAL also compares runs of ASCII digits by numeric value ("file2"
+///   before "file10", digits before letters), and ignores accents on Latin
+///   letters as well as combining marks, so "éclair" sorts next to "eclair" and
+///   a decomposed "e\u{301}" matches a precomposed "é". Names equal under these
+///   rules fall back to BYTE ("file02" before "file2", "Resume" before "résumé").
+///
+/// The orders do not depend on the system locale; NATURAL approximates the
+/// order of the platform file managers for Latin-script names.
+use std::cmp::Ordering;
+use std::ffi::OsStr;
+
+use serde::Deserialize;
+
+/// Sort names by their raw bytes
+pub const SORT_LOCALE_BYTE: i32 = 0;
+/// Sort names ignoring case
+pub const SORT_LOCALE_CASE_INSENSITIVE: i32 = 1;
+/// Sort names ignoring case and accents, comparing digit runs as numbers
+pub const SORT_LOCALE_NATURAL: i32 = 2;
+
+pub use crate::errors::ERROR_INVALID_SORT_LOCALE;
+
+/// Name order selected by a SORT_LOCALE_* code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "i32")]
+pub enum SortLocale {
+    #[default]
+    Byte,
+    CaseInsensitive,
+    Natural,
+}
+
+impl SortLocale {
+    /// Order for a SORT_LOCALE_* code, None for unknown codes
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            SORT_LOCALE_BYTE => Some(SortLocale::Byte),
+            SORT_LOCALE_CASE_INSENSITIVE => Some(SortLocale::CaseInsensitive),
+            SORT_LOCALE_NATURAL => Some(SortLocale::Natural),
+            _ => None,
+        }
+    }
+
+    /// Compare two file names
+    ///
+    /// Names that are not valid UTF-8 are folded from their lossy conversion
+    /// and still fall back to their raw bytes.
+    pub fn compare(self, a: &OsStr, b: &OsStr) -> Ordering {
+        let bytes = || a.as_encoded_bytes().cmp(b.as_encoded_bytes());
+        match self {
+            SortLocale::Byte => bytes(),
+            _ => self.compare_folded(&a.to_string_lossy(), &b.to_string_lossy()).then_with(bytes),
+        }
+    }
+
+    /// Compare two UTF-8 names
+    pub fn compare_str(self, a: &str, b: &str) -> Ordering {
+        self.compare(OsStr::new(a), OsStr::new(b))
+    }
+
+    fn compare_folded(self, a: &str, b: &str) -> Ordering {
+        match self {
+            SortLocale::Byte => Ordering::Equal,
+            SortLocale::CaseInsensitive => a.chars().flat_map(char::to_lowercase).cmp(b.chars().flat_map(char::to_lowercase)),
+            SortLocale::Natural => compare_natural(a, b),
+        }
+    }
+}
+
+impl TryFrom<i32> for SortLocale {
+    type Error = String;
+
+    fn try_from(code: i32) -> Result<Self, Self::Error> {
+        SortLocale::from_code(code).ok_or_else(|| format!("unknown sort locale {}", code))
+    }
+}
+
+/// Primary NATURAL comparison, before the byte fallback
+fn compare_natural(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        a = a.trim_start_matches(is_combining_mark);
+        b = b.trim_start_matches(is_combining_mark);
+        let (a_digit, b_digit) = (starts_with_digit(a), starts_with_digit(b));
+        let step = match (a_digit, b_digit) {
+            (true, true) => {
+                let (a_run, a_rest) = split_digits(a);
+                let (b_run, b_rest) = split_digits(b);
+                a = a_rest;
+                b = b_rest;
+                compare_numbers(a_run, b_run)
+            }
+            (true, false) => return number_against(b),
+            (false, true) => return number_against(a).reverse(),
+            (false, false) => {
+                let (Some(a_char), Some(b_char)) = (a.chars().next(), b.chars().next()) else {
+                    return a.is_empty().cmp(&b.is_empty()).reverse();
+                };
+                a = &a[a_char.len_utf8()..];
+                b = &b[b_char.len_utf8()..];
+                fold(a_char).cmp(fold(b_char))
+            }
+        };
+        if step != Ordering::Equal {
+            return step;
+        }
+    }
+}
+
+/// Compare a number with the rest of a name that does not start with a digit
+///
+/// The number sorts like the character '0': after spaces and most punctuation,
+/// before letters.
+fn number_against(other: &str) -> Ordering {
+    match other.chars().next() {
+        Some(c) => std::iter::once('0').cmp(fold(c)).then(Ordering::Less),
+        None => Ordering::Greater,
+    }
+}
+
+fn starts_with_digit(s: &str) -> bool {
+    s.as_bytes().first().is_some_and(u8::is_ascii_digit)
+}
+
+/// Split off the leading run of ASCII digits
+fn split_digits(s: &str) -> (&str, &str) {
+    let end = s.bytes().position(|b| !b.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+/// Compare digit runs by value, without parsing (runs may exceed any integer type)
+fn compare_numbers(a: &str, b: &str) -> Ordering {
+    let a = a.trim_start_matches('0');
+    let b = b.trim_start_matches('0');
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// Combining diacritical marks, ignored by NATURAL (they follow the letter in decomposed names)
+fn is_combining_mark(c: char) -> bool {
+    matches!(c, '\u{0300}'..='\u{036F}')
+}
+
+/// Lowercase a character and strip the accent from Latin letters
+fn fold(c: char) -> impl Iterator<Item = char> {
+    c.to_lowercase().map(strip_accent).filter(|c| !is_combining_mark(*c))
+}
+
+/// Base letter of a lowercase Latin-1 or Latin Extended-A letter
+fn strip_accent(c: char) -> char {
+    match c {
+        'à'..='å' | 'ā' | 'ă' | 'ą' => 'a',
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => 'c',
+        'ď' | 'đ' => 'd',
+        'è'..='ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => 'e',
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => 'g',
+        'ĥ' | 'ħ' => 'h',
+        'ì'..='ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => 'i',
+        'ĵ' => 'j',
+        'ķ' => 'k',
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => 'l',
+        'ñ' | 'ń' | 'ņ' | 'ň' => 'n',
+        'ò'..='ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => 'o',
+        'ŕ' | 'ŗ' | 'ř' => 'r',
+        'ś' | 'ŝ' | 'ş' | 'š' => 's',
+        'ţ' | 'ť' | 'ŧ' => 't',
+        'ù'..='ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => 'u',
+        'ŵ' => 'w',
+        'ý' | 'ÿ' | 'ŷ' => 'y',
+        'ź' | 'ż' | 'ž' => 'z',
+        _ => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(locale: SortLocale, names: &[&str]) -> Vec<String> {
+        let mut names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        names.sort_by(|a, b| locale.compare_str(a, b));
+        names
+    }
+
+    #[test]
+    fn test_natural_order_compares_numbers_by_value() {
+        let names = ["file10.txt", "file2.txt", "file1.txt", "file02.txt", "file", "file 3.txt", "file.txt",
+                     "2024 report", "10 report", "a100000000000000000000000", "a99999999999999999999999"];
+        assert_eq!(sorted(SortLocale::Natural, &names), [
+            "10 report", "2024 report", "a99999999999999999999999", "a100000000000000000000000",
+            "file", "file 3.txt", "file.txt", "file1.txt", "file02.txt", "file2.txt", "file10.txt",
+        ]);
+        assert_eq!(sorted(SortLocale::Byte, &["file10", "file2"]), ["file10", "file2"]);
+        assert_eq!(sorted(SortLocale::CaseInsensitive, &["file10", "file2"]), ["file10", "file2"]);
+    }
+
+    #[test]
+    fn test_case_insensitive_orders_mixed_case() {
+        let names = ["cherry", "Banana", "apple", "readme", "README", "ReadMe"];
+        assert_eq!(sorted(SortLocale::Byte, &names), ["Banana", "README", "ReadMe", "apple", "cherry", "readme"]);
+        let expected = ["apple", "Banana", "cherry", "README", "ReadMe", "readme"];
+        assert_eq!(sorted(SortLocale::CaseInsensitive, &names), expected);
+        assert_eq!(sorted(SortLocale::Natural, &names), expected);
+        assert_eq!(sorted(SortLocale::CaseInsensitive, &["Ärger", "ärger", "zebra"]), ["zebra", "Ärger", "ärger"]);
+    }
+
+    #[test]
+    fn test_natural_order_ignores_accents() {
+        let names = ["zebra", "éclair", "eclipse", "Eclair", "ecole", "e\u{301}clat", "Résumé", "resume", "Resume"];
+        assert_eq!(sorted(SortLocale::Natural, &names), [
+            "Eclair", "éclair", "e\u{301}clat", "eclipse", "ecole", "Resume", "Résumé", "resume", "zebra",
+        ]);
+        // Without accent folding, accented letters sort after 'z'
+        let case_insensitive = sorted(SortLocale::CaseInsensitive, &names);
+        assert_eq!(case_insensitive.last().unwrap(), "éclair");
+    }
+
+    #[test]
+    fn test_orders_are_total() {
+        let names = ["a", "A", "á", "a\u{301}", "a1", "a01", "a001", "a 1", "b", ""];
+        for locale in [SortLocale::Byte, SortLocale::CaseInsensitive, SortLocale::Natural] {
+            for a in names {
+                for b in names {
+                    let order = locale.compare_str(a, b);
+                    assert_eq!(order == Ordering::Equal, a == b, "{:?} {:?} {:?}", locale, a, b);
+                    assert_eq!(order, locale.compare_str(b, a).reverse());
+                }
+            }
+        }
+        assert_eq!(SortLocale::from_code(SORT_LOCALE_NATURAL), Some(SortLocale::Natural));
+        assert_eq!(SortLocale::from_code(3), None);
+        assert_eq!(serde_json::from_str::<SortLocale>("1").unwrap(), SortLocale::CaseInsensitive);
+        assert!(serde_json::from_str::<SortLocale>("7").is_err());
+    }
+}
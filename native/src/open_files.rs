@@ -0,0 +1,159 @@
+/// Open file handle limit for CloudNexus
+/// Copies, uploads and downloads each keep one or two files open; many of them
+/// at once can exhaust the process's descriptors (EMFILE). Every such file
+/// takes a permit from one process-wide semaphore before it is opened and
+/// returns it when closed, so at most `max_open_files` (set with
+/// native_runtime_configure_ex, 0 for no limit) are open at a time. An open
+/// the system still refuses for lack of descriptors is retried once after a
+/// short wait, then reported as ERROR_TOO_MANY_OPEN_FILES.
+use std::io;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub use crate::errors::ERROR_TOO_MANY_OPEN_FILES;
+
+/// How long an open waits for a permit before failing
+pub const OPEN_FILE_PERMIT_WAIT: Duration = Duration::from_secs(30);
+
+/// Pause before the single retry of an open that ran out of descriptors
+pub const OPEN_FILE_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+struct OpenFileLimiter {
+    /// Most permits handed out at once, 0 for no limit
+    limit: u32,
+    in_use: usize,
+    peak: usize,
+}
+
+impl OpenFileLimiter {
+    /// Whether `count` more permits fit; an operation needing more than the
+    /// whole limit still runs, alone
+    fn admits(&self, count: usize) -> bool {
+        self.limit == 0 || self.in_use == 0 || self.in_use + count <= self.limit as usize
+    }
+}
+
+static LIMITER: Mutex<OpenFileLimiter> = Mutex::new(OpenFileLimiter { limit: 0, in_use: 0, peak: 0 });
+static RELEASED: Condvar = Condvar::new();
+
+fn limiter() -> MutexGuard<'static, OpenFileLimiter> {
+    LIMITER.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Set the most files open at once (0 for no limit) and restart the peak count
+///
+/// Files already open keep their permits; with a lower limit new opens wait
+/// until enough of them are closed.
+pub(crate) fn set_open_file_limit(limit: u32) {
+    let mut limiter = limiter();
+    limiter.limit = limit;
+    limiter.peak = limiter.in_use;
+    drop(limiter);
+    RELEASED.notify_all();
+}
+
+/// Configured limit, permits in use and the most in use at once
+pub(crate) fn open_file_usage() -> (u32, usize, usize) {
+    let limiter = limiter();
+    (limiter.limit, limiter.in_use, limiter.peak)
+}
+
+/// Permits for open files, returned when dropped
+///
+/// Keep it alongside the files it was taken for and drop it once they are closed.
+#[derive(Debug)]
+pub(crate) struct OpenFilePermit {
+    count: usize,
+}
+
+impl Drop for OpenFilePermit {
+    fn drop(&mut self) {
+        limiter().in_use -= self.count;
+        RELEASED.notify_all();
+    }
+}
+
+/// Take permits for `count` files, waiting up to OPEN_FILE_PERMIT_WAIT
+///
+/// An operation that keeps several files open at once takes all its permits
+/// in one call, so two of them can never each hold half of what they need.
+///
+/// # Returns
+/// The permit, or ERROR_TOO_MANY_OPEN_FILES if none freed up in time
+pub(crate) fn acquire_open_files(count: usize) -> Result<OpenFilePermit, i32> {
+    let deadline = Instant::now() + OPEN_FILE_PERMIT_WAIT;
+    let mut limiter = limiter();
+    while !limiter.admits(count) {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(ERROR_TOO_MANY_OPEN_FILES);
+        }
+        limiter = RELEASED.wait_timeout(limiter, deadline - now).unwrap_or_else(PoisonError::into_inner).0;
+    }
+    limiter.in_use += count;
+    limiter.peak = limiter.peak.max(limiter.in_use);
+    Ok(OpenFilePermit { count })
+}
+
+/// Whether an open failed because the process or system is out of descriptors
+pub(crate) fn is_out_of_descriptors(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    let codes = [libc::EMFILE, libc::ENFILE];
+    #[cfg(windows)]
+    let codes = [4]; // ERROR_TOO_MANY_OPEN_FILES
+    #[cfg(not(any(unix, windows)))]
+    let codes: [i32; 0] = [];
+    e.raw_os_error().is_some_and(|code| codes.contains(&code))
+}
+
+/// Run `open`, once more after OPEN_FILE_RETRY_DELAY if it ran out of descriptors
+pub(crate) fn retry_open<T>(mut open: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    match open() {
+        Err(e) if is_out_of_descriptors(&e) => {
+            thread::sleep(OPEN_FILE_RETRY_DELAY);
+            open()
+        }
+        result => result,
+    }
+}
+
+/// Status code of a failed open: ERROR_TOO_MANY_OPEN_FILES when out of
+/// descriptors, `fallback` otherwise
+pub(crate) fn open_error_code(e: &io::Error, fallback: i32) -> i32 {
+    if is_out_of_descriptors(e) { ERROR_TOO_MANY_OPEN_FILES } else { fallback }
+}
+
+/// Open one file under a new permit, mapping failures like open_error_code
+pub(crate) fn open_limited<T>(open: impl FnMut() -> io::Result<T>, fallback: i32)
+                              -> Result<(T, OpenFilePermit), i32> {
+    let permit = acquire_open_files(1)?;
+    let file = retry_open(open).map_err(|e| open_error_code(&e, fallback))?;
+    Ok((file, permit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_out_of_descriptors_is_retried_once() {
+        let attempts = Cell::new(0);
+        let result: io::Result<()> = retry_open(|| {
+            attempts.set(attempts.get() + 1);
+            Err(io::Error::from_raw_os_error(libc::EMFILE))
+        });
+        assert_eq!(attempts.get(), 2);
+        assert_eq!(open_error_code(&result.unwrap_err(), crate::errors::ERROR_IO_FAILED), ERROR_TOO_MANY_OPEN_FILES);
+
+        attempts.set(0);
+        let result: io::Result<()> = retry_open(|| {
+            attempts.set(attempts.get() + 1);
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert_eq!(attempts.get(), 1);
+        assert_eq!(open_error_code(&result.unwrap_err(), crate::errors::ERROR_IO_FAILED), crate::errors::ERROR_IO_FAILED);
+    }
+}
@@ -0,0 +1,430 @@
+/// Active operation registry for CloudNexus
+/// Every transfer context (upload, download, chunked, folder, unified and cloud
+/// copy) registers itself when created and leaves the registry when freed, so
+/// the app can list running transfers, read their progress, and cancel one by
+/// id without holding its context pointer. Contexts can also report into the
+/// polled progress event stream (see progress_events.rs).
+use std::collections::HashMap;
+use std::ffi::c_char;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::ffi_util::json_envelope;
+use crate::file_io::SUCCESS;
+use crate::progress_events::{push_progress_event, ProgressEventKind};
+
+pub use crate::errors::ERROR_OPERATION_NOT_FOUND;
+
+/// Kind of context behind an operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationKind {
+    Upload,
+    Download,
+    ChunkedCopy,
+    FolderCopy,
+    UnifiedCopy,
+    CloudCopy,
+}
+
+/// Progress shared between a context and its registry entry
+#[derive(Default)]
+struct OperationProgress {
+    bytes_done: AtomicU64,
+    total_bytes: AtomicU64,
+    files_done: AtomicU64,
+    total_files: AtomicU64,
+    /// Cancellation flag of operations started without a caller flag
+    cancelled: AtomicBool,
+}
+
+struct OperationInfo {
+    kind: OperationKind,
+    source: String,
+    dest: String,
+    started_ms: u64,
+    progress: Arc<OperationProgress>,
+    // Caller-owned cancel flag, stored as an address so the entry can live in a static
+    caller_cancel_flag: usize,
+}
+
+impl OperationInfo {
+    fn is_cancelled(&self) -> bool {
+        self.progress.cancelled.load(Ordering::Relaxed)
+            || (self.caller_cancel_flag != 0
+                && unsafe { (*(self.caller_cancel_flag as *const AtomicBool)).load(Ordering::Relaxed) })
+    }
+}
+
+/// Point-in-time view of one operation, as listed by list_active_operations_json
+#[derive(Debug, Clone, Serialize)]
+pub struct OperationSnapshot {
+    pub id: u64,
+    pub kind: OperationKind,
+    /// Source path, empty for callback-driven and descriptor sources
+    pub source: String,
+    /// Destination path, empty for callback-driven and descriptor destinations
+    pub dest: String,
+    pub started_ms: u64,
+    pub bytes_done: u64,
+    pub total_bytes: u64,
+    pub files_done: u64,
+    pub total_files: u64,
+    pub cancelled: bool,
+}
+
+/// Operations whose contexts have not been freed, by id
+static ACTIVE_OPERATIONS: Mutex<Option<HashMap<u64, OperationInfo>>> = Mutex::new(None);
+
+/// Last operation id handed out (ids start at 1)
+static OPERATION_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A context's entry in the active operation registry
+///
+/// Held by the context; dropping it when the context is freed removes the entry.
+pub struct Operation {
+    id: u64,
+    progress: Arc<OperationProgress>,
+    cancel_flag: *const AtomicBool,
+    /// Push progress into the event stream
+    use_event_stream: AtomicBool,
+    /// A terminal event was pushed
+    finished: AtomicBool,
+}
+
+impl Operation {
+    /// Register a new operation
+    ///
+    /// The context must use `cancel_flag()` from then on instead of the caller's
+    /// flag, so cancel_operation reaches its chunk loops even when the caller
+    /// passed none.
+    pub fn register(kind: OperationKind, source: &Path, dest: &Path, total_bytes: u64, total_files: u64,
+                    cancel_flag: *const AtomicBool) -> Self {
+        let id = OPERATION_ID_COUNTER.fetch_add(1, Ordering::Relaxed) + 1;
+        let progress = Arc::new(OperationProgress::default());
+        progress.total_bytes.store(total_bytes, Ordering::Relaxed);
+        progress.total_files.store(total_files, Ordering::Relaxed);
+
+        let started_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let info = OperationInfo {
+            kind,
+            source: source.to_string_lossy().to_string(),
+            dest: dest.to_string_lossy().to_string(),
+            started_ms,
+            progress: Arc::clone(&progress),
+            caller_cancel_flag: cancel_flag as usize,
+        };
+        if let Ok(mut active) = ACTIVE_OPERATIONS.lock() {
+            active.get_or_insert_with(HashMap::new).insert(id, info);
+        }
+
+        // Without a caller flag, the registry's own flag is the one the context checks
+        let cancel_flag = if cancel_flag.is_null() {
+            &progress.cancelled as *const AtomicBool
+        } else {
+            cancel_flag
+        };
+        Operation {
+            id,
+            progress,
+            cancel_flag,
+            use_event_stream: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    /// Registry id of this operation
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Cancellation flag the context must check
+    pub fn cancel_flag(&self) -> *const AtomicBool {
+        self.cancel_flag
+    }
+
+    /// Record the bytes transferred so far
+    pub fn set_bytes_done(&self, bytes: u64) {
+        self.progress.bytes_done.store(bytes, Ordering::Relaxed);
+        self.emit(ProgressEventKind::Progress);
+    }
+
+    /// Record the total bytes, once known or when it changes
+    pub fn set_total_bytes(&self, bytes: u64) {
+        self.progress.total_bytes.store(bytes, Ordering::Relaxed);
+        self.emit(ProgressEventKind::Progress);
+    }
+
+    /// Record the files completed so far
+    pub fn set_files_done(&self, files: u64) {
+        self.progress.files_done.store(files, Ordering::Relaxed);
+        self.emit(ProgressEventKind::Progress);
+    }
+
+    /// Record the total files, once known
+    pub fn set_total_files(&self, files: u64) {
+        self.progress.total_files.store(files, Ordering::Relaxed);
+        self.emit(ProgressEventKind::Progress);
+    }
+
+    /// Push this operation's progress into the event stream from now on
+    ///
+    /// Enabling pushes the current counters right away.
+    pub fn set_use_event_stream(&self, enabled: bool) {
+        self.use_event_stream.store(enabled, Ordering::Relaxed);
+        self.emit(ProgressEventKind::Progress);
+    }
+
+    /// Record successful completion (pushes the terminal "completed" event)
+    pub fn complete(&self) {
+        self.emit(ProgressEventKind::Completed);
+    }
+
+    /// Push the current counters as an event of `kind`, if the stream is enabled
+    /// and no terminal event was pushed yet
+    fn emit(&self, kind: ProgressEventKind) {
+        if !self.use_event_stream.load(Ordering::Relaxed) || self.finished.load(Ordering::Relaxed) {
+            return;
+        }
+        if kind.is_terminal() && self.finished.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        push_progress_event(
+            self.id,
+            kind,
+            self.progress.bytes_done.load(Ordering::Relaxed),
+            self.progress.total_bytes.load(Ordering::Relaxed),
+            self.progress.files_done.load(Ordering::Relaxed),
+            self.progress.total_files.load(Ordering::Relaxed),
+        );
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        // A context freed before completing ends its stream too
+        if self.use_event_stream.load(Ordering::Relaxed) {
+            let cancelled = unsafe { (*self.cancel_flag).load(Ordering::Relaxed) };
+            self.emit(if cancelled { ProgressEventKind::Cancelled } else { ProgressEventKind::Failed });
+        }
+
+        if let Ok(mut active) = ACTIVE_OPERATIONS.lock() {
+            if let Some(map) = active.as_mut() {
+                map.remove(&self.id);
+            }
+        }
+    }
+}
+
+/// Snapshots of all active operations, oldest first
+pub fn active_operations() -> Vec<OperationSnapshot> {
+    let active = match ACTIVE_OPERATIONS.lock() {
+        Ok(active) => active,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut snapshots: Vec<OperationSnapshot> = active
+        .iter()
+        .flatten()
+        .map(|(&id, info)| OperationSnapshot {
+            id,
+            kind: info.kind,
+            source: info.source.clone(),
+            dest: info.dest.clone(),
+            started_ms: info.started_ms,
+            bytes_done: info.progress.bytes_done.load(Ordering::Relaxed),
+            total_bytes: info.progress.total_bytes.load(Ordering::Relaxed),
+            files_done: info.progress.files_done.load(Ordering::Relaxed),
+            total_files: info.progress.total_files.load(Ordering::Relaxed),
+            cancelled: info.is_cancelled(),
+        })
+        .collect();
+    snapshots.sort_by_key(|s| s.id);
+    snapshots
+}
+
+/// List active transfer operations
+///
+/// Returns a JSON envelope (see ffi_util.rs) whose `data` is an array, oldest
+/// first, of objects with id, kind ("upload", "download", "chunked_copy",
+/// "folder_copy", "unified_copy" or "cloud_copy"), source, dest, started_ms,
+/// bytes_done, total_bytes, files_done, total_files and cancelled.
+///
+/// # Arguments
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope (caller must free with scan_folder_free_string), or null if it
+/// cannot be allocated
+#[no_mangle]
+pub extern "C" fn list_active_operations_json(out_len: *mut usize) -> *mut c_char {
+    json_envelope(Ok(active_operations()), out_len)
+}
+
+/// Get the progress of an active operation
+///
+/// # Arguments
+/// * `operation_id` - Id from list_active_operations_json or a *_get_operation_id function
+/// * `bytes_done` - Pointer to store bytes transferred (can be null)
+/// * `total_bytes` - Pointer to store total bytes, 0 if unknown (can be null)
+/// * `files_done` - Pointer to store files completed (can be null)
+/// * `total_files` - Pointer to store total files (can be null)
+///
+/// # Returns
+/// 0 on success, ERROR_OPERATION_NOT_FOUND if no active operation has this id
+#[no_mangle]
+pub extern "C" fn get_operation_progress(
+    operation_id: u64,
+    bytes_done: *mut u64,
+    total_bytes: *mut u64,
+    files_done: *mut u64,
+    total_files: *mut u64,
+) -> i32 {
+    let active = match ACTIVE_OPERATIONS.lock() {
+        Ok(active) => active,
+        Err(_) => return ERROR_OPERATION_NOT_FOUND,
+    };
+    let info = match active.as_ref().and_then(|map| map.get(&operation_id)) {
+        Some(info) => info,
+        None => return ERROR_OPERATION_NOT_FOUND,
+    };
+
+    let outputs = [
+        (bytes_done, &info.progress.bytes_done),
+        (total_bytes, &info.progress.total_bytes),
+        (files_done, &info.progress.files_done),
+        (total_files, &info.progress.total_files),
+    ];
+    for (out, value) in outputs {
+        if !out.is_null() {
+            unsafe { *out = value.load(Ordering::Relaxed); }
+        }
+    }
+    SUCCESS
+}
+
+/// Cancel an active operation
+///
+/// Sets the cancellation flag the operation's context checks: the caller's flag
+/// passed at init (which must still be valid, as for the context itself), or
+/// the registry's own flag if none was passed. The running call returns
+/// ERROR_CANCELLED at its next check; the context must still be freed.
+///
+/// # Arguments
+/// * `operation_id` - Id of the operation to cancel
+///
+/// # Returns
+/// 0 on success, ERROR_OPERATION_NOT_FOUND if no active operation has this id
+#[no_mangle]
+pub extern "C" fn cancel_operation(operation_id: u64) -> i32 {
+    let active = match ACTIVE_OPERATIONS.lock() {
+        Ok(active) => active,
+        Err(_) => return ERROR_OPERATION_NOT_FOUND,
+    };
+    let info = match active.as_ref().and_then(|map| map.get(&operation_id)) {
+        Some(info) => info,
+        None => return ERROR_OPERATION_NOT_FOUND,
+    };
+
+    info.progress.cancelled.store(true, Ordering::SeqCst);
+    if info.caller_cancel_flag != 0 {
+        unsafe { (*(info.caller_cancel_flag as *const AtomicBool)).store(true, Ordering::SeqCst); }
+    }
+    SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_io::ERROR_CANCELLED;
+    use crate::unified_copy::{unified_copy_file, unified_copy_free, unified_copy_get_operation_id, unified_copy_init};
+    use std::ffi::{c_void, CStr, CString};
+    use std::ptr;
+
+    struct MemoryCopy {
+        source: Vec<u8>,
+        dest: Vec<u8>,
+    }
+
+    extern "C" fn read_memory(buffer: *mut u8, buffer_size: usize, offset: u64, user_data: *mut c_void) -> isize {
+        let copy = unsafe { &*(user_data as *const MemoryCopy) };
+        let start = (offset as usize).min(copy.source.len());
+        let n = buffer_size.min(copy.source.len() - start);
+        unsafe { ptr::copy_nonoverlapping(copy.source[start..].as_ptr(), buffer, n); }
+        n as isize
+    }
+
+    extern "C" fn write_memory(data: *const u8, data_len: usize, _offset: u64, user_data: *mut c_void) -> i32 {
+        let copy = unsafe { &mut *(user_data as *mut MemoryCopy) };
+        copy.dest.extend_from_slice(unsafe { std::slice::from_raw_parts(data, data_len) });
+        0
+    }
+
+    fn listed_ids() -> Vec<u64> {
+        let mut len = 0usize;
+        let json = list_active_operations_json(&mut len);
+        assert!(!json.is_null());
+        let text = unsafe { CStr::from_ptr(json) }.to_str().unwrap().to_string();
+        assert_eq!(text.len(), len);
+        unsafe { drop(CString::from_raw(json)); }
+
+        let envelope: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(envelope["ok"], true);
+        let list = envelope["data"].as_array().unwrap();
+        list.iter().map(|op| op["id"].as_u64().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_cancel_one_of_two_operations_by_id() {
+        let size = 300 * 1024;
+        let mut copies: Vec<MemoryCopy> = (0..2)
+            .map(|i| MemoryCopy { source: vec![i as u8 + 1; size], dest: Vec::new() })
+            .collect();
+
+        // One copy with a caller flag, one without
+        let caller_flag = AtomicBool::new(false);
+        let first = unified_copy_init(size as u64, 1, 64 * 1024, &caller_flag);
+        let second = unified_copy_init(size as u64, 1, 64 * 1024, ptr::null());
+        let first_id = unified_copy_get_operation_id(first);
+        let second_id = unified_copy_get_operation_id(second);
+        assert_ne!(first_id, second_id);
+
+        let ids = listed_ids();
+        assert!(ids.contains(&first_id) && ids.contains(&second_id));
+
+        assert_eq!(cancel_operation(second_id), SUCCESS);
+        let mut buffer = vec![0u8; 64 * 1024];
+        let result = unified_copy_file(second, buffer.as_mut_ptr(), buffer.len(), size as u64,
+            Some(read_memory), Some(write_memory), None, &mut copies[1] as *mut MemoryCopy as *mut c_void);
+        assert_eq!(result, ERROR_CANCELLED);
+        assert!(copies[1].dest.is_empty());
+
+        let result = unified_copy_file(first, buffer.as_mut_ptr(), buffer.len(), size as u64,
+            Some(read_memory), Some(write_memory), None, &mut copies[0] as *mut MemoryCopy as *mut c_void);
+        assert_eq!(result, 0);
+        assert_eq!(copies[0].dest, copies[0].source);
+
+        let (mut bytes, mut total, mut files, mut total_files) = (0u64, 0u64, 0u64, 0u64);
+        assert_eq!(get_operation_progress(first_id, &mut bytes, &mut total, &mut files, &mut total_files), SUCCESS);
+        assert_eq!((bytes, total, files, total_files), (size as u64, size as u64, 1, 1));
+
+        // Cancelling through the registry also sets the caller's flag
+        assert_eq!(cancel_operation(first_id), SUCCESS);
+        assert!(caller_flag.load(Ordering::SeqCst));
+
+        unified_copy_free(first);
+        unified_copy_free(second);
+        let ids = listed_ids();
+        assert!(!ids.contains(&first_id) && !ids.contains(&second_id));
+        assert_eq!(get_operation_progress(first_id, ptr::null_mut(), ptr::null_mut(), ptr::null_mut(), ptr::null_mut()),
+                   ERROR_OPERATION_NOT_FOUND);
+        assert_eq!(cancel_operation(second_id), ERROR_OPERATION_NOT_FOUND);
+    }
+}
@@ -0,0 +1,391 @@
+/// Phased progress reporting for CloudNexus
+/// The plain progress callbacks report one bytes/total pair, so a UI cannot
+/// tell a verification pass from the encryption before it and shows a stuck
+/// bar. The `_phased` entry points report (phase, bytes_done, bytes_total)
+/// instead: every phase counts its own bytes, starting with a report of 0 and
+/// ending with a report of its total. A PhaseAggregator folds those reports
+/// into one overall percentage with caller-chosen weights.
+use std::ffi::c_void;
+
+use crate::ffi_util::ERROR_NOT_FOUND;
+use crate::file_io::{ProgressThrottler, ERROR_NULL_POINTER, SUCCESS};
+
+/// Phased progress callback
+/// Parameters: phase (one of the PHASE_* constants), bytes done in this phase,
+/// bytes the phase will process (0 if unknown), user_data
+pub type PhaseProgressCallback = extern "C" fn(phase: u32, bytes_done: u64, bytes_total: u64, user_data: *mut c_void);
+
+/// Plaintext chunks are fingerprinted for deduplication
+pub const PHASE_HASHING: u32 = 1;
+/// Plaintext is encrypted into container chunks
+pub const PHASE_ENCRYPTING: u32 = 2;
+/// Emitted chunks are handed to the upload data callback
+pub const PHASE_UPLOADING: u32 = 3;
+/// The written output is read back and checked against the source
+pub const PHASE_VERIFYING: u32 = 4;
+/// Source bytes are written to the destination unchanged
+pub const PHASE_COPYING: u32 = 5;
+/// Bytes are received by the download context
+pub const PHASE_DOWNLOADING: u32 = 6;
+/// Received containers are decrypted into the destination
+pub const PHASE_DECRYPTING: u32 = 7;
+
+/// Reporting state of one phase
+struct PhaseState {
+    phase: u32,
+    throttler: ProgressThrottler,
+    /// Last bytes_done delivered to the callback
+    reported: u64,
+}
+
+/// Throttled phase reporting for one operation
+///
+/// Phases may interleave (an upload hashes, encrypts and emits every chunk in
+/// turn), so each phase keeps its own throttler.
+pub(crate) struct PhaseTracker {
+    states: Vec<PhaseState>,
+}
+
+impl PhaseTracker {
+    pub fn new() -> Self {
+        Self { states: Vec::new() }
+    }
+
+    /// Report `done` of `total` bytes for `phase`
+    ///
+    /// The first report of a phase is preceded by (phase, 0, total). Reports
+    /// are throttled, never go backwards, and the one reaching `total` is
+    /// always delivered.
+    pub fn report(&mut self, callback: Option<PhaseProgressCallback>, user_data: *mut c_void,
+                  phase: u32, done: u64, total: u64) {
+        if let Some(cb) = callback {
+            for (phase, done, total) in self.due(phase, done, total) {
+                cb(phase, done, total, user_data);
+            }
+        }
+    }
+
+    /// The (phase, done, total) reports `report` would deliver, for a caller
+    /// that invokes the callback itself once its context is no longer borrowed
+    pub fn due(&mut self, phase: u32, done: u64, total: u64) -> Vec<(u32, u64, u64)> {
+        let mut reports = Vec::new();
+        let state = match self.states.iter().position(|s| s.phase == phase) {
+            Some(index) => &mut self.states[index],
+            None => {
+                self.states.push(PhaseState { phase, throttler: ProgressThrottler::new(500), reported: 0 });
+                reports.push((phase, 0, total));
+                self.states.last_mut().unwrap()
+            }
+        };
+
+        let done = done.max(state.reported);
+        if done > state.reported && state.throttler.should_update(done as usize, total as usize) {
+            state.reported = done;
+            reports.push((phase, done, total));
+        }
+        reports
+    }
+
+    /// The reports that deliver the final (total, total) report of `phase`
+    /// unless it was already delivered, as for due
+    pub fn finish_due(&mut self, phase: u32, total: u64) -> Vec<(u32, u64, u64)> {
+        let mut reports = self.due(phase, total, total);
+        if let Some(state) = self.states.iter_mut().find(|s| s.phase == phase) {
+            if state.reported < total {
+                state.reported = total;
+                reports.push((phase, total, total));
+            }
+        }
+        reports
+    }
+
+    /// Phases reported so far, in the order they started
+    pub fn started_phases(&self) -> Vec<u32> {
+        self.states.iter().map(|s| s.phase).collect()
+    }
+}
+
+/// Weighted overall progress across the phases of one operation
+pub struct PhaseAggregator {
+    /// (phase, normalized weight, completed fraction)
+    phases: Vec<(u32, f64, f64)>,
+}
+
+impl PhaseAggregator {
+    /// Create an aggregator; `weights` must be finite, non-negative and not all zero
+    pub fn new(phases: &[u32], weights: &[f64]) -> Option<Self> {
+        if phases.is_empty() || phases.len() != weights.len() {
+            return None;
+        }
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+            return None;
+        }
+        let sum: f64 = weights.iter().sum();
+        if sum <= 0.0 {
+            return None;
+        }
+        Some(Self { phases: phases.iter().zip(weights).map(|(&p, &w)| (p, w / sum, 0.0)).collect() })
+    }
+
+    /// Record a phase report, returning false for a phase this aggregator does not weigh
+    ///
+    /// A report with an unknown total (0) leaves the phase's fraction unchanged,
+    /// and a phase's fraction never goes backwards.
+    pub fn update(&mut self, phase: u32, bytes_done: u64, bytes_total: u64) -> bool {
+        let entry = match self.phases.iter_mut().find(|(p, _, _)| *p == phase) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if bytes_total > 0 {
+            let fraction = (bytes_done as f64 / bytes_total as f64).min(1.0);
+            entry.2 = entry.2.max(fraction);
+        }
+        true
+    }
+
+    /// Overall progress in percent (0 to 100)
+    pub fn percent(&self) -> f64 {
+        let overall: f64 = self.phases.iter().map(|(_, weight, fraction)| weight * fraction).sum();
+        (overall * 100.0).min(100.0)
+    }
+}
+
+/// Create an aggregator mapping phase reports onto an overall percentage
+///
+/// Each phase contributes its weight times the fraction of its bytes done;
+/// weights are normalized, so (1, 3) and (25, 75) are equivalent. Phases run
+/// in any order, and a phase that never reports contributes nothing.
+///
+/// # Arguments
+/// * `phases` - Pointer to `count` PHASE_* constants the operation reports
+/// * `weights` - Pointer to `count` weights, or null for equal weights
+/// * `count` - Number of phases
+///
+/// # Returns
+/// Pointer to PhaseAggregator (free with phase_aggregator_free), or null if the
+/// phases or weights are invalid (negative, not finite, or all zero)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn phase_aggregator_new(phases: *const u32, weights: *const f64, count: usize) -> *mut PhaseAggregator {
+    if phases.is_null() || count == 0 {
+        return std::ptr::null_mut();
+    }
+    let phases = unsafe { std::slice::from_raw_parts(phases, count) };
+    let weights = if weights.is_null() {
+        vec![1.0; count]
+    } else {
+        unsafe { std::slice::from_raw_parts(weights, count) }.to_vec()
+    };
+
+    match PhaseAggregator::new(phases, &weights) {
+        Some(aggregator) => Box::into_raw(Box::new(aggregator)),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Feed a phase report to an aggregator and read the overall percentage
+///
+/// Typically called from a PhaseProgressCallback with its arguments.
+///
+/// # Arguments
+/// * `aggregator` - Pointer to PhaseAggregator from phase_aggregator_new()
+/// * `phase` - Phase of the report
+/// * `bytes_done` - Bytes done in the phase
+/// * `bytes_total` - Bytes the phase will process (0 if unknown)
+/// * `out_percent` - Optional pointer receiving the overall progress (0 to 100)
+///
+/// # Returns
+/// 0 on success, ERROR_NOT_FOUND if the aggregator does not weigh `phase`
+/// (the percentage is still stored), or ERROR_NULL_POINTER
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn phase_aggregator_update(
+    aggregator: *mut PhaseAggregator,
+    phase: u32,
+    bytes_done: u64,
+    bytes_total: u64,
+    out_percent: *mut f64,
+) -> i32 {
+    if aggregator.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+    let aggregator = unsafe { &mut *aggregator };
+    let known = aggregator.update(phase, bytes_done, bytes_total);
+    if !out_percent.is_null() {
+        unsafe { *out_percent = aggregator.percent(); }
+    }
+    if known { SUCCESS } else { ERROR_NOT_FOUND }
+}
+
+/// Free an aggregator
+///
+/// # Arguments
+/// * `aggregator` - Pointer to PhaseAggregator from phase_aggregator_new()
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn phase_aggregator_free(aggregator: *mut PhaseAggregator) {
+    if !aggregator.is_null() {
+        unsafe {
+            let _ = Box::from_raw(aggregator);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Phase reports and emitted upload data, shared through one user_data pointer
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<(u32, u64, u64)>,
+        stream: Vec<u8>,
+    }
+
+    extern "C" fn record_phase(phase: u32, bytes_done: u64, bytes_total: u64, user_data: *mut c_void) {
+        let recorder = unsafe { &mut *(user_data as *mut Recorder) };
+        recorder.calls.push((phase, bytes_done, bytes_total));
+    }
+
+    #[test]
+    fn test_tracker_marks_phase_starts_and_never_goes_backwards() {
+        let mut recorder = Recorder::default();
+        let user_data = &mut recorder as *mut Recorder as *mut c_void;
+        let mut tracker = PhaseTracker::new();
+
+        tracker.report(Some(record_phase), user_data, PHASE_ENCRYPTING, 10, 100);
+        tracker.report(Some(record_phase), user_data, PHASE_UPLOADING, 10, 100);
+        // Throttled, then a stale value that must not be reported
+        tracker.report(Some(record_phase), user_data, PHASE_ENCRYPTING, 50, 100);
+        tracker.report(Some(record_phase), user_data, PHASE_ENCRYPTING, 40, 100);
+        tracker.report(Some(record_phase), user_data, PHASE_ENCRYPTING, 100, 100);
+        let mut finishes = tracker.finish_due(PHASE_ENCRYPTING, 100);
+        finishes.extend(tracker.finish_due(PHASE_UPLOADING, 100));
+        // Empty phases report their start and nothing else
+        finishes.extend(tracker.finish_due(PHASE_VERIFYING, 0));
+        recorder.calls.extend(finishes);
+
+        assert_eq!(recorder.calls, vec![
+            (PHASE_ENCRYPTING, 0, 100),
+            (PHASE_UPLOADING, 0, 100),
+            (PHASE_ENCRYPTING, 100, 100),
+            (PHASE_UPLOADING, 100, 100),
+            (PHASE_VERIFYING, 0, 0),
+        ]);
+        assert_eq!(tracker.started_phases(), vec![PHASE_ENCRYPTING, PHASE_UPLOADING, PHASE_VERIFYING]);
+    }
+
+    extern "C" fn always_upload(_chunk_index: u32, _hash: *const u8, _user_data: *mut c_void) -> i32 {
+        crate::dedup::CHUNK_FINGERPRINT_UPLOAD
+    }
+
+    extern "C" fn collect_stream(data: *const u8, data_len: usize, _chunk_index: u32, user_data: *mut c_void) {
+        let recorder = unsafe { &mut *(user_data as *mut Recorder) };
+        recorder.stream.extend_from_slice(unsafe { std::slice::from_raw_parts(data, data_len) });
+    }
+
+    /// Per phase: the done values, which must start at 0 and never go backwards
+    fn phase_progress(calls: &[(u32, u64, u64)], phase: u32) -> Vec<u64> {
+        let done: Vec<u64> = calls.iter().filter(|c| c.0 == phase).map(|c| c.1).collect();
+        assert_eq!(done.first(), Some(&0), "phase {}", phase);
+        assert!(done.windows(2).all(|w| w[0] <= w[1]), "phase {}: {:?}", phase, done);
+        done
+    }
+
+    #[test]
+    fn test_upload_and_download_report_their_phases() {
+        use crate::download::{download_append_chunk_phased, download_finalize, download_free, download_init,
+                              download_set_total_bytes};
+        use crate::upload::{upload_free, upload_init_ex, upload_process_chunk_phased};
+        use std::ffi::CString;
+
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_phases_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let content: Vec<u8> = (0..200 * 1024u32).map(|i| (i % 241) as u8).collect();
+        std::fs::write(&source, &content).unwrap();
+        let size = content.len() as u64;
+        let key = [0x18u8; 32];
+
+        // Upload: every chunk is hashed, encrypted and emitted in turn
+        let source_c = CString::new(source.to_string_lossy().to_string()).unwrap();
+        let ctx = upload_init_ex(source_c.as_ptr(), key.as_ptr(), key.len(), 64 * 1024, 1, Some(always_upload),
+                                 std::ptr::null_mut(), std::ptr::null());
+        assert!(!ctx.is_null());
+        let mut upload = Recorder::default();
+        let mut buffer = vec![0u8; 64 * 1024 + 64];
+        let user_data = &mut upload as *mut Recorder as *mut c_void;
+        while upload_process_chunk_phased(ctx, buffer.as_mut_ptr(), buffer.len(), Some(record_phase),
+                                          Some(collect_stream), user_data) > 0 {}
+        upload_free(ctx);
+
+        let (calls, stream) = (upload.calls, upload.stream);
+        let mut started: Vec<u32> = Vec::new();
+        for call in &calls {
+            if !started.contains(&call.0) {
+                started.push(call.0);
+            }
+        }
+        assert_eq!(started, vec![PHASE_HASHING, PHASE_ENCRYPTING, PHASE_UPLOADING]);
+        for phase in started {
+            assert_eq!(phase_progress(&calls, phase).last(), Some(&size));
+            assert!(calls.iter().filter(|c| c.0 == phase).all(|c| c.2 == size));
+        }
+
+        // Download: bytes received against an unknown total, plaintext against the file size
+        let dest = dir.join("dest.bin");
+        let dest_c = CString::new(dest.to_string_lossy().to_string()).unwrap();
+        let ctx = download_init(dest_c.as_ptr(), key.as_ptr(), key.len(), 1, None, std::ptr::null(), std::ptr::null_mut());
+        download_set_total_bytes(ctx, content.len());
+        let mut download = Recorder::default();
+        for piece in stream.chunks(50_000) {
+            assert_eq!(download_append_chunk_phased(ctx, piece.as_ptr(), piece.len(), Some(record_phase),
+                                                    &mut download as *mut Recorder as *mut c_void), SUCCESS);
+        }
+        assert_eq!(download_finalize(ctx), SUCCESS);
+        download_free(ctx);
+        assert_eq!(std::fs::read(&dest).unwrap(), content);
+
+        let calls = download.calls;
+        assert_eq!(calls[0], (PHASE_DOWNLOADING, 0, 0));
+        assert!(phase_progress(&calls, PHASE_DOWNLOADING).iter().all(|&done| done <= stream.len() as u64));
+        assert_eq!(phase_progress(&calls, PHASE_DECRYPTING).last(), Some(&size));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_aggregator_weights_phases() {
+        let phases = [PHASE_ENCRYPTING, PHASE_VERIFYING];
+        let weights = [1.0, 3.0];
+        let aggregator = phase_aggregator_new(phases.as_ptr(), weights.as_ptr(), phases.len());
+        assert!(!aggregator.is_null());
+
+        let mut percent = -1.0;
+        assert_eq!(phase_aggregator_update(aggregator, PHASE_ENCRYPTING, 50, 100, &mut percent), SUCCESS);
+        assert!((percent - 12.5).abs() < 1e-9);
+        // Going backwards and unknown totals leave the phase where it was
+        assert_eq!(phase_aggregator_update(aggregator, PHASE_ENCRYPTING, 10, 100, &mut percent), SUCCESS);
+        assert_eq!(phase_aggregator_update(aggregator, PHASE_ENCRYPTING, 90, 0, &mut percent), SUCCESS);
+        assert!((percent - 12.5).abs() < 1e-9);
+        assert_eq!(phase_aggregator_update(aggregator, PHASE_COPYING, 1, 1, &mut percent), ERROR_NOT_FOUND);
+        assert!((percent - 12.5).abs() < 1e-9);
+
+        assert_eq!(phase_aggregator_update(aggregator, PHASE_VERIFYING, 100, 100, &mut percent), SUCCESS);
+        assert!((percent - 87.5).abs() < 1e-9);
+        assert_eq!(phase_aggregator_update(aggregator, PHASE_ENCRYPTING, 100, 100, &mut percent), SUCCESS);
+        assert!((percent - 100.0).abs() < 1e-9);
+        phase_aggregator_free(aggregator);
+
+        // Null weights are equal weights; invalid weights are refused
+        let equal = phase_aggregator_new(phases.as_ptr(), std::ptr::null(), phases.len());
+        assert_eq!(phase_aggregator_update(equal, PHASE_VERIFYING, 1, 2, &mut percent), SUCCESS);
+        assert!((percent - 25.0).abs() < 1e-9);
+        phase_aggregator_free(equal);
+        for bad in [[0.0, 0.0], [-1.0, 2.0], [f64::NAN, 1.0]] {
+            assert!(phase_aggregator_new(phases.as_ptr(), bad.as_ptr(), phases.len()).is_null());
+        }
+        assert_eq!(phase_aggregator_update(std::ptr::null_mut(), PHASE_COPYING, 0, 0, &mut percent), ERROR_NULL_POINTER);
+    }
+}
@@ -0,0 +1,298 @@
+/// Polled progress event stream for CloudNexus
+/// Transfer contexts with the event stream enabled push their progress into a
+/// global ring instead of (or besides) calling back on native threads; the app
+/// drains the ring on a timer with drain_progress_events_json. The ring keeps
+/// only the latest progress record per operation plus every terminal record,
+/// so a slow poller sees current values and never misses how an operation ended.
+use std::collections::VecDeque;
+use std::ffi::c_char;
+use std::sync::{Mutex, Once};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::ffi_util::json_envelope;
+use crate::runtime::{register_trim_handler, TrimLevel};
+
+/// Most records the ring holds; when full the oldest progress record is dropped
+pub const PROGRESS_EVENT_CAPACITY: usize = 1024;
+
+/// What a progress record reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressEventKind {
+    /// Latest counters of a running operation
+    Progress,
+    /// The operation was finalized successfully
+    Completed,
+    /// The context was freed without completing
+    Failed,
+    /// The context was freed after cancellation
+    Cancelled,
+}
+
+impl ProgressEventKind {
+    /// Whether this record ends the operation's stream
+    pub fn is_terminal(self) -> bool {
+        self != ProgressEventKind::Progress
+    }
+}
+
+/// One record in the progress ring
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProgressEvent {
+    pub operation_id: u64,
+    pub kind: ProgressEventKind,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    pub files_done: u64,
+    pub files_total: u64,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+/// Records drained by one drain_progress_events_json call
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgressEventBatch {
+    /// Oldest first
+    pub events: Vec<ProgressEvent>,
+    /// Records dropped because the ring was full since the previous drain
+    pub dropped: u64,
+}
+
+struct EventRing {
+    events: VecDeque<ProgressEvent>,
+    dropped: u64,
+}
+
+static EVENT_RING: Mutex<EventRing> = Mutex::new(EventRing { events: VecDeque::new(), dropped: 0 });
+
+/// Push a record, replacing the operation's pending progress record
+///
+/// A terminal record also replaces the pending progress record, since it
+/// carries the final counters.
+pub fn push_progress_event(
+    operation_id: u64,
+    kind: ProgressEventKind,
+    bytes_done: u64,
+    bytes_total: u64,
+    files_done: u64,
+    files_total: u64,
+) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let event = ProgressEvent { operation_id, kind, bytes_done, bytes_total, files_done, files_total, timestamp };
+
+    static REGISTER_TRIM: Once = Once::new();
+    REGISTER_TRIM.call_once(|| register_trim_handler("progress_events", trim_event_ring));
+
+    let mut ring = match EVENT_RING.lock() {
+        Ok(ring) => ring,
+        Err(_) => return,
+    };
+    if let Some(pos) = ring.events.iter()
+        .position(|e| e.operation_id == operation_id && e.kind == ProgressEventKind::Progress)
+    {
+        ring.events.remove(pos);
+    }
+    if ring.events.len() >= PROGRESS_EVENT_CAPACITY {
+        let oldest = ring.events.iter().position(|e| !e.kind.is_terminal()).unwrap_or(0);
+        ring.events.remove(oldest);
+        ring.dropped += 1;
+    }
+    ring.events.push_back(event);
+}
+
+/// Trim handler of the ring: give back the room a burst of operations left behind
+fn trim_event_ring(level: TrimLevel) -> u64 {
+    if level < TrimLevel::Moderate {
+        return 0;
+    }
+    let Ok(mut ring) = EVENT_RING.lock() else {
+        return 0;
+    };
+    let before = ring.events.capacity();
+    ring.events.shrink_to_fit();
+    ((before - ring.events.capacity()) * std::mem::size_of::<ProgressEvent>()) as u64
+}
+
+/// Remove up to `max_events` records (all when 0), oldest first
+pub fn drain_progress_events(max_events: usize) -> ProgressEventBatch {
+    let mut ring = match EVENT_RING.lock() {
+        Ok(ring) => ring,
+        Err(_) => return ProgressEventBatch { events: Vec::new(), dropped: 0 },
+    };
+    let count = if max_events == 0 { ring.events.len() } else { max_events.min(ring.events.len()) };
+    let events = ring.events.drain(..count).collect();
+    let dropped = std::mem::take(&mut ring.dropped);
+    ProgressEventBatch { events, dropped }
+}
+
+/// Drain progress records pushed by contexts with the event stream enabled
+///
+/// Returns a JSON envelope (see ffi_util.rs) whose `data` is {events, dropped}.
+/// Each event has operation_id, kind ("progress", "completed", "failed" or
+/// "cancelled"), bytes_done, bytes_total, files_done, files_total and timestamp
+/// (ms since the Unix epoch). Only the latest "progress" record of an operation
+/// is kept between drains; terminal records are always kept. `dropped` counts
+/// records lost to a full ring since the previous drain.
+///
+/// # Arguments
+/// * `max_events` - Most records to return (0 for all)
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope (caller must free with scan_folder_free_string), or null if it
+/// cannot be allocated
+#[no_mangle]
+pub extern "C" fn drain_progress_events_json(max_events: usize, out_len: *mut usize) -> *mut c_char {
+    json_envelope(Ok(drain_progress_events(max_events)), out_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unified_copy::{
+        unified_copy_file, unified_copy_finalize, unified_copy_free, unified_copy_get_operation_id,
+        unified_copy_init, unified_copy_set_use_event_stream,
+    };
+    use crate::operations::cancel_operation;
+    use std::ffi::{c_void, CStr, CString};
+    use std::ptr;
+
+    struct MemoryCopy {
+        source: Vec<u8>,
+        dest: Vec<u8>,
+    }
+
+    extern "C" fn read_memory(buffer: *mut u8, buffer_size: usize, offset: u64, user_data: *mut c_void) -> isize {
+        let copy = unsafe { &*(user_data as *const MemoryCopy) };
+        let start = (offset as usize).min(copy.source.len());
+        let n = buffer_size.min(copy.source.len() - start);
+        unsafe { ptr::copy_nonoverlapping(copy.source[start..].as_ptr(), buffer, n); }
+        n as isize
+    }
+
+    extern "C" fn write_memory(data: *const u8, data_len: usize, _offset: u64, user_data: *mut c_void) -> i32 {
+        let copy = unsafe { &mut *(user_data as *mut MemoryCopy) };
+        copy.dest.extend_from_slice(unsafe { std::slice::from_raw_parts(data, data_len) });
+        0
+    }
+
+    /// Tests drain the shared ring, so they run one at a time
+    static RING_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    fn drain_json(max_events: usize) -> serde_json::Value {
+        let mut len = 0usize;
+        let json = drain_progress_events_json(max_events, &mut len);
+        assert!(!json.is_null());
+        let text = unsafe { CStr::from_ptr(json) }.to_str().unwrap().to_string();
+        assert_eq!(text.len(), len);
+        unsafe { drop(CString::from_raw(json)); }
+        let envelope: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(envelope["ok"], true);
+        envelope["data"].clone()
+    }
+
+    #[test]
+    fn test_concurrent_copies_stream_coalesced_events() {
+        let _guard = RING_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let size = 2 * 1024 * 1024;
+        let copies: Vec<_> = (0..2u8)
+            .map(|i| {
+                let ctx = unified_copy_init(size as u64, 1, 64 * 1024, ptr::null());
+                assert_eq!(unified_copy_set_use_event_stream(ctx, 1), 0);
+                (ctx as usize, unified_copy_get_operation_id(ctx), i + 1)
+            })
+            .collect();
+        // A context without the stream pushes nothing
+        let silent = unified_copy_init(size as u64, 1, 64 * 1024, ptr::null());
+        let silent_id = unified_copy_get_operation_id(silent);
+
+        let workers: Vec<_> = copies.iter()
+            .map(|&(ctx, _, fill)| std::thread::spawn(move || {
+                let ctx = ctx as *mut crate::unified_copy::UnifiedCopyContext;
+                let mut copy = MemoryCopy { source: vec![fill; size], dest: Vec::new() };
+                let mut buffer = vec![0u8; 64 * 1024];
+                let result = unified_copy_file(ctx, buffer.as_mut_ptr(), buffer.len(), size as u64,
+                    Some(read_memory), Some(write_memory), None, &mut copy as *mut MemoryCopy as *mut c_void);
+                assert_eq!(result, 0);
+                assert_eq!(copy.dest, copy.source);
+                assert_eq!(unified_copy_finalize(ctx, None, ptr::null_mut()), 0);
+            }))
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        // A freed context that was cancelled ends with "cancelled"
+        let cancelled = unified_copy_init(size as u64, 1, 64 * 1024, ptr::null());
+        let cancelled_id = unified_copy_get_operation_id(cancelled);
+        assert_eq!(unified_copy_set_use_event_stream(cancelled, 1), 0);
+        assert_eq!(cancel_operation(cancelled_id), 0);
+        unified_copy_free(cancelled);
+
+        let mut events = Vec::new();
+        loop {
+            let batch = drain_json(3);
+            let page = batch["events"].as_array().unwrap().clone();
+            assert!(page.len() <= 3);
+            if page.is_empty() {
+                break;
+            }
+            events.extend(page);
+        }
+        let ours = |id: u64| -> Vec<serde_json::Value> {
+            events.iter().filter(|e| e["operation_id"] == id).cloned().collect()
+        };
+
+        for &(ctx, id, _) in &copies {
+            let stream = ours(id);
+            // Coalescing leaves at most the latest progress record before the terminal one
+            assert!(!stream.is_empty() && stream.len() <= 2, "{:?}", stream);
+            let last = stream.last().unwrap();
+            assert_eq!(last["kind"], "completed");
+            assert_eq!(last["bytes_done"], size as u64);
+            assert_eq!(last["bytes_total"], size as u64);
+            assert_eq!((last["files_done"].as_u64(), last["files_total"].as_u64()), (Some(1), Some(1)));
+            assert!(last["timestamp"].as_u64().unwrap() > 0);
+            unified_copy_free(ctx as *mut crate::unified_copy::UnifiedCopyContext);
+        }
+        let stream = ours(cancelled_id);
+        assert_eq!(stream.last().unwrap()["kind"], "cancelled");
+        assert!(ours(silent_id).is_empty());
+        unified_copy_free(silent);
+
+        // Freeing after completion pushes nothing more
+        let leftover = drain_progress_events(0);
+        assert!(leftover.events.iter().all(|e| copies.iter().all(|&(_, id, _)| e.operation_id != id)));
+    }
+
+    #[test]
+    fn test_full_ring_drops_progress_before_terminal_records() {
+        let _guard = RING_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        // Ids far above any registered operation
+        let base = u64::MAX - 2 * PROGRESS_EVENT_CAPACITY as u64;
+        push_progress_event(base, ProgressEventKind::Completed, 1, 1, 1, 1);
+        for i in 1..=PROGRESS_EVENT_CAPACITY as u64 {
+            push_progress_event(base + i, ProgressEventKind::Progress, i, 0, 0, 0);
+            push_progress_event(base + i, ProgressEventKind::Progress, i + 1, 0, 0, 0);
+        }
+
+        let batch = drain_progress_events(0);
+        let ours: Vec<&ProgressEvent> = batch.events.iter().filter(|e| e.operation_id >= base).collect();
+        assert!(batch.dropped >= 1);
+        assert_eq!(ours[0].operation_id, base);
+        assert_eq!(ours[0].kind, ProgressEventKind::Completed);
+        assert_eq!(ours.last().unwrap().bytes_done, PROGRESS_EVENT_CAPACITY as u64 + 1);
+        assert!(ours.iter().skip(1).all(|e| e.bytes_done == e.operation_id - base + 1));
+
+        // The drained ring gives its room back under memory pressure, and keeps working
+        assert_eq!(trim_event_ring(TrimLevel::Light), 0);
+        assert!(trim_event_ring(TrimLevel::Moderate) > 0);
+        push_progress_event(base, ProgressEventKind::Completed, 2, 2, 1, 1);
+        assert!(drain_progress_events(0).events.iter().any(|e| e.operation_id == base && e.bytes_done == 2));
+    }
+}
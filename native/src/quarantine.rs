@@ -0,0 +1,133 @@
+/// Quarantine of downloads that failed to decrypt
+///
+/// When a chunk of a decrypting download fails authentication, the plaintext
+/// of the chunks before it has already been written. Instead of leaving that
+/// half of a document where it can be opened, the download moves it into a
+/// quarantine directory as `<name>.corrupt-<timestamp>` and appends an entry
+/// describing the failure to the directory's `quarantine.json` manifest, which
+/// list_quarantine_json returns for the UI.
+use std::ffi::c_char;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ffi_util::{envelope_str, json_envelope, ErrorEnvelope, ERROR_INVALID_JSON};
+use crate::file_io::{ERROR_INVALID_PATH, ERROR_IO_FAILED};
+use crate::trash::{move_path, now_ms, unique_path};
+
+/// Name of the manifest file inside a quarantine directory
+pub const QUARANTINE_MANIFEST_FILE_NAME: &str = "quarantine.json";
+
+/// Serializes manifest updates from concurrent downloads
+static MANIFEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// One quarantined download
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QuarantineEntry {
+    /// Destination the download was meant for
+    pub original_path: String,
+    /// Where the partial output was moved
+    pub quarantine_path: String,
+    /// Index of the chunk that failed to decrypt
+    pub chunk_index: u32,
+    /// Offset of that chunk in the encrypted stream
+    pub stream_offset: u64,
+    /// Plaintext bytes written before the failure
+    pub bytes_written: u64,
+    /// Error code of the failed chunk (-4 authentication failed, -22 CRC mismatch)
+    pub error_code: i32,
+    /// Human-readable description of the failure
+    pub error: String,
+    /// Time of the quarantine in milliseconds since the Unix epoch
+    pub quarantined_ms: u64,
+}
+
+/// Failure that sends a download's partial output into quarantine
+pub(crate) struct ChunkFailure {
+    pub chunk_index: u32,
+    pub stream_offset: u64,
+    pub bytes_written: u64,
+    pub error_code: i32,
+}
+
+fn failure_message(error_code: i32) -> String {
+    match error_code {
+        crate::ERROR_DECRYPTION_FAILED => "chunk failed authentication".to_string(),
+        crate::ERROR_CHUNK_CRC_MISMATCH => "chunk ciphertext does not match its CRC".to_string(),
+        code => format!("chunk could not be decrypted (error {})", code),
+    }
+}
+
+/// Move `partial`, the output of a download to `original`, into the quarantine `dir`
+///
+/// The entry is appended to the directory's manifest before it is returned.
+pub(crate) fn quarantine_partial_output(partial: &Path, original: &Path, dir: &Path, failure: &ChunkFailure)
+    -> Result<QuarantineEntry, i32> {
+    let name = original.file_name().ok_or(ERROR_INVALID_PATH)?;
+    let quarantined_ms = now_ms();
+
+    let _guard = MANIFEST_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    fs::create_dir_all(dir).map_err(|_| ERROR_IO_FAILED)?;
+    let target = unique_path(&dir.join(format!("{}.corrupt-{}", name.to_string_lossy(), quarantined_ms)));
+    move_path(partial, &target)?;
+
+    let entry = QuarantineEntry {
+        original_path: original.to_string_lossy().to_string(),
+        quarantine_path: target.to_string_lossy().to_string(),
+        chunk_index: failure.chunk_index,
+        stream_offset: failure.stream_offset,
+        bytes_written: failure.bytes_written,
+        error_code: failure.error_code,
+        error: failure_message(failure.error_code),
+        quarantined_ms,
+    };
+
+    // A damaged manifest is replaced rather than blocking new entries
+    let manifest_path = dir.join(QUARANTINE_MANIFEST_FILE_NAME);
+    let mut entries: Vec<QuarantineEntry> = fs::read(&manifest_path)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or_default();
+    entries.push(entry.clone());
+    let json = serde_json::to_string_pretty(&entries).map_err(|_| ERROR_IO_FAILED)?;
+    fs::write(&manifest_path, json).map_err(|_| ERROR_IO_FAILED)?;
+
+    Ok(entry)
+}
+
+/// Read the entries of a quarantine directory's manifest
+///
+/// A directory without a manifest has no entries.
+pub fn list_quarantine_impl(dir: &Path) -> Result<Vec<QuarantineEntry>, ErrorEnvelope> {
+    let manifest_path = dir.join(QUARANTINE_MANIFEST_FILE_NAME);
+    let json = match fs::read(&manifest_path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(ErrorEnvelope::new(ERROR_IO_FAILED, format!("Failed to read quarantine manifest: {}", e))
+                .with_context(manifest_path.to_string_lossy()))
+        }
+    };
+    serde_json::from_slice(&json).map_err(|e| {
+        ErrorEnvelope::new(ERROR_INVALID_JSON, format!("Quarantine manifest is not valid JSON: {}", e))
+            .with_context(manifest_path.to_string_lossy())
+    })
+}
+
+/// List the downloads quarantined in a directory
+///
+/// # Arguments
+/// * `dir` - Quarantine directory (as passed to download_set_quarantine_dir)
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope whose `data` is the array of QuarantineEntry records, oldest
+/// first (caller must free with scan_folder_free_string)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn list_quarantine_json(dir: *const c_char, out_len: *mut usize) -> *mut c_char {
+    let result = unsafe { envelope_str(dir, "dir") }.and_then(|dir| list_quarantine_impl(Path::new(dir)));
+    json_envelope(result, out_len)
+}
@@ -0,0 +1,263 @@
+/// Randomness source for CloudNexus
+/// Every FEK, nonce, salt and generated key comes from `fill_random`, which
+/// reads the operating system generator.
+///
+/// Builds with the `insecure-test-rng` feature (and the crate's own tests) can
+/// replace it with a seeded ChaCha20 stream, so golden-file tests can pin the
+/// container format byte for byte. With a test RNG installed every key and
+/// nonce is predictable: the feature only compiles into debug builds and must
+/// never ship.
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+#[cfg(all(feature = "insecure-test-rng", not(debug_assertions)))]
+compile_error!("the insecure-test-rng feature makes keys and nonces predictable and is only allowed in debug builds");
+
+/// Fill `buf` with random bytes, from the test RNG when one is installed
+pub(crate) fn fill_random(buf: &mut [u8]) {
+    #[cfg(any(test, feature = "insecure-test-rng"))]
+    if deterministic::fill(buf) {
+        return;
+    }
+    OsRng.fill_bytes(buf);
+}
+
+#[cfg(any(test, feature = "insecure-test-rng"))]
+mod deterministic {
+    use rand::{RngCore, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+    use std::cell::RefCell;
+    use std::sync::{Mutex, PoisonError};
+
+    /// Process-wide stream installed through set_insecure_test_rng
+    static GLOBAL_RNG: Mutex<Option<ChaCha20Rng>> = Mutex::new(None);
+
+    thread_local! {
+        /// Stream of one test thread; takes precedence so parallel tests do
+        /// not consume each other's bytes
+        static THREAD_RNG: RefCell<Option<ChaCha20Rng>> = const { RefCell::new(None) };
+    }
+
+    pub(super) fn fill(buf: &mut [u8]) -> bool {
+        let filled = THREAD_RNG.with(|rng| match rng.borrow_mut().as_mut() {
+            Some(rng) => {
+                rng.fill_bytes(buf);
+                true
+            }
+            None => false,
+        });
+        if filled {
+            return true;
+        }
+        match GLOBAL_RNG.lock().unwrap_or_else(PoisonError::into_inner).as_mut() {
+            Some(rng) => {
+                rng.fill_bytes(buf);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[cfg(feature = "insecure-test-rng")]
+    pub(super) fn set_global(seed: Option<u64>) {
+        *GLOBAL_RNG.lock().unwrap_or_else(PoisonError::into_inner) = seed.map(ChaCha20Rng::seed_from_u64);
+    }
+
+    /// Run `f` with this thread's randomness drawn from a stream seeded with `seed`
+    #[cfg(test)]
+    pub(crate) fn with_seeded_rng<T>(seed: u64, f: impl FnOnce() -> T) -> T {
+        struct Restore;
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                THREAD_RNG.with(|rng| *rng.borrow_mut() = None);
+            }
+        }
+        THREAD_RNG.with(|rng| *rng.borrow_mut() = Some(ChaCha20Rng::seed_from_u64(seed)));
+        let _restore = Restore;
+        f()
+    }
+}
+
+#[cfg(test)]
+pub(crate) use deterministic::with_seeded_rng;
+
+/// Replace the system RNG with a ChaCha20 stream seeded with `seed`
+///
+/// INSECURE: every FEK, nonce and salt generated afterwards, on any thread, is
+/// predictable. Only exported by debug builds with the `insecure-test-rng`
+/// feature, for golden-file tests of the container format.
+///
+/// # Arguments
+/// * `seed` - Seed of the deterministic stream
+///
+/// # Returns
+/// SUCCESS
+#[cfg(feature = "insecure-test-rng")]
+#[no_mangle]
+pub extern "C" fn set_insecure_test_rng(seed: u64) -> i32 {
+    deterministic::set_global(Some(seed));
+    crate::errors::SUCCESS
+}
+
+/// Go back to the system RNG after set_insecure_test_rng
+///
+/// # Returns
+/// SUCCESS
+#[cfg(feature = "insecure-test-rng")]
+#[no_mangle]
+pub extern "C" fn clear_insecure_test_rng() -> i32 {
+    deterministic::set_global(None);
+    crate::errors::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::{c_void, CString};
+    use std::fs;
+    use std::path::PathBuf;
+    use std::ptr;
+    use std::slice;
+
+    use crate::dedup::{CHUNK_FINGERPRINT_SKIP, CHUNK_FINGERPRINT_UPLOAD};
+    use crate::errors::SUCCESS;
+    use crate::upload::{upload_free, upload_get_header, upload_init_ex, upload_process_chunk};
+    use crate::{decrypt_chunk, decrypt_file_finalize, decrypt_file_init, encrypt_chunk, encrypt_file_finalize,
+                encrypt_file_get_wrapped_fek, encrypt_file_init, free_buffer};
+
+    const SEED: u64 = 1214;
+    const KEY: [u8; 32] = [7u8; 32];
+    const CHUNK_SIZE: usize = 128;
+
+    fn content() -> Vec<u8> {
+        (0..300).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Compare against tests/fixtures/`name`; CLOUDNEXUS_UPDATE_FIXTURES=1 rewrites it
+    fn assert_fixture(name: &str, actual: &[u8]) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name);
+        if std::env::var_os("CLOUDNEXUS_UPDATE_FIXTURES").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, actual).unwrap();
+        }
+        let expected = fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        assert!(expected == actual, "{} does not match the container written with seed {}", name, SEED);
+    }
+
+    fn decrypt_container(container: &[u8], header_len: usize) -> Vec<u8> {
+        let ctx = decrypt_file_init(container.as_ptr(), header_len, KEY.as_ptr(), KEY.len());
+        assert!(!ctx.is_null());
+        let mut plaintext = Vec::new();
+        let mut offset = header_len;
+        while offset < container.len() {
+            let record_len = 4 + 4 + 12 + u32::from_le_bytes(container[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            let mut out_len = 0usize;
+            let output = decrypt_chunk(ctx, container[offset..].as_ptr(), record_len, &mut out_len);
+            assert!(!output.is_null());
+            plaintext.extend_from_slice(unsafe { slice::from_raw_parts(output, out_len) });
+            free_buffer(output);
+            offset += record_len;
+        }
+        decrypt_file_finalize(ctx);
+        plaintext
+    }
+
+    fn write_v1_container() -> Vec<u8> {
+        let mut header_len = 0usize;
+        let ctx = encrypt_file_init(KEY.as_ptr(), KEY.len(), &mut header_len);
+        assert!(!ctx.is_null());
+        let mut container = unsafe { (*ctx).header }.to_vec();
+        let mut fek_len = 0usize;
+        let fek = encrypt_file_get_wrapped_fek(ctx, &mut fek_len);
+        container.extend_from_slice(unsafe { slice::from_raw_parts(fek, fek_len) });
+        free_buffer(fek);
+        for (index, piece) in content().chunks(CHUNK_SIZE).enumerate() {
+            let mut len = 0usize;
+            let chunk = encrypt_chunk(ctx, piece.as_ptr(), piece.len(), index as u32, &mut len);
+            container.extend_from_slice(unsafe { slice::from_raw_parts(chunk, len) });
+            free_buffer(chunk);
+        }
+        encrypt_file_finalize(ctx);
+        assert_eq!(container.len() - 3 * (4 + 4 + 12 + 16), header_len + content().len());
+        container
+    }
+
+    /// Upload chunks are at least 64 KiB; the server already holds the first
+    /// one, so the container keeps only its reference record
+    const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+    fn upload_content() -> Vec<u8> {
+        (0..UPLOAD_CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect()
+    }
+
+    extern "C" fn skip_first_chunk(chunk_index: u32, _hash: *const u8, _user_data: *mut c_void) -> i32 {
+        if chunk_index == 0 { CHUNK_FINGERPRINT_SKIP } else { CHUNK_FINGERPRINT_UPLOAD }
+    }
+
+    extern "C" fn append_record(data: *const u8, data_len: usize, _chunk_index: u32, user_data: *mut c_void) {
+        let container = unsafe { &mut *(user_data as *mut Vec<u8>) };
+        container.extend_from_slice(unsafe { slice::from_raw_parts(data, data_len) });
+    }
+
+    fn write_v2_container(source: &CString) -> Vec<u8> {
+        let ctx = upload_init_ex(source.as_ptr(), KEY.as_ptr(), KEY.len(), UPLOAD_CHUNK_SIZE, 1, Some(skip_first_chunk),
+                                 ptr::null_mut(), ptr::null());
+        assert!(!ctx.is_null());
+        let mut header = [0u8; 12];
+        let mut fek = [0u8; 256];
+        let mut fek_len = 0usize;
+        assert_eq!(upload_get_header(ctx, header.as_mut_ptr(), fek.as_mut_ptr(), fek.len(), &mut fek_len), SUCCESS);
+        let mut container = header.to_vec();
+        container.extend_from_slice(&fek[..fek_len]);
+        let mut buffer = vec![0u8; UPLOAD_CHUNK_SIZE + 64];
+        let user_data = &mut container as *mut Vec<u8> as *mut c_void;
+        while upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(append_record), user_data) > 0 {}
+        upload_free(ctx);
+        container
+    }
+
+    #[test]
+    fn test_seeded_rng_is_deterministic_and_thread_local() {
+        let draw = || {
+            let mut bytes = [0u8; 32];
+            fill_random(&mut bytes);
+            bytes
+        };
+        let first = with_seeded_rng(SEED, || (draw(), draw()));
+        let second = with_seeded_rng(SEED, || (draw(), draw()));
+        assert_eq!(first, second);
+        assert_ne!(first.0, first.1);
+        assert_ne!(with_seeded_rng(SEED + 1, draw), first.0);
+
+        // Outside the scope, and on other threads, the system RNG is back
+        assert_ne!(draw(), first.0);
+        with_seeded_rng(SEED, || assert_ne!(std::thread::spawn(draw).join().unwrap(), first.0));
+    }
+
+    #[test]
+    fn test_v1_container_matches_fixture() {
+        let container = with_seeded_rng(SEED, write_v1_container);
+        assert_eq!(container, with_seeded_rng(SEED, write_v1_container));
+        assert_eq!(container[4], crate::VERSION);
+        assert_fixture("container_v1.bin", &container);
+        assert_eq!(decrypt_container(&container, crate::HEADER_SIZE + crate::WRAPPED_FEK_SIZE), content());
+    }
+
+    #[test]
+    fn test_v2_container_matches_fixture() {
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_golden_v2_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        fs::write(&source, upload_content()).unwrap();
+        let source = CString::new(source.to_string_lossy().to_string()).unwrap();
+
+        let container = with_seeded_rng(SEED, || write_v2_container(&source));
+        assert_eq!(container, with_seeded_rng(SEED, || write_v2_container(&source)));
+        assert_eq!(container[4], crate::FORMAT_VERSION_DEDUP);
+        assert_fixture("container_v2.bin", &container);
+        let skipped = crate::dedup::chunk_fingerprint(&upload_content()[..UPLOAD_CHUNK_SIZE]);
+        let reference = &container[crate::HEADER_SIZE + crate::WRAPPED_FEK_SIZE..][..4 + 4 + 12 + 32];
+        assert_eq!(reference, crate::dedup::build_reference_record(0, &skipped));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
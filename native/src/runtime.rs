@@ -0,0 +1,746 @@
+/// Native runtime for CloudNexus
+/// One lazily started pool of worker threads runs the library's background
+/// work (transfer queue jobs), so the host controls how many native threads
+/// exist in total. The pool size and thread stack can be set before first use,
+/// and native_runtime_shutdown stops the pool, e.g. before the library is
+/// unloaded. A later submission starts a fresh pool.
+///
+/// Subsystems holding caches or grown tables register a trim handler here;
+/// native_trim_memory runs them all when the app is asked to release memory.
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::ffi::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Once, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::file_io::{ERROR_CANCELLED, ERROR_IO_FAILED, SUCCESS};
+use crate::ffi_util::json_envelope;
+use crate::open_files::{open_file_usage, set_open_file_limit};
+
+pub use crate::errors::ERROR_RUNTIME_STARTED;
+
+/// Most worker threads the pool may run
+const MAX_RUNTIME_THREADS: u32 = 64;
+
+/// Worker threads when the pool is not configured; jobs are mostly I/O bound
+const DEFAULT_RUNTIME_THREADS: u32 = 16;
+
+type Task = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Default)]
+struct PoolState {
+    tasks: VecDeque<Task>,
+    busy: usize,
+    shutdown: bool,
+}
+
+/// Fixed set of worker threads running submitted tasks in FIFO order
+struct WorkerPool {
+    state: Mutex<PoolState>,
+    /// Signalled when a task is queued or the pool shuts down
+    work: Condvar,
+    /// Signalled when the pool runs out of queued and running tasks
+    idle: Condvar,
+    threads: u32,
+    stack_kb: u32,
+    completed: AtomicU64,
+    peak_busy: AtomicUsize,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl WorkerPool {
+    fn start(threads: u32, stack_kb: u32) -> Result<Arc<Self>, i32> {
+        let pool = Arc::new(WorkerPool {
+            state: Mutex::new(PoolState::default()),
+            work: Condvar::new(),
+            idle: Condvar::new(),
+            threads,
+            stack_kb,
+            completed: AtomicU64::new(0),
+            peak_busy: AtomicUsize::new(0),
+            workers: Mutex::new(Vec::new()),
+        });
+
+        for index in 0..threads {
+            let mut builder = thread::Builder::new().name(format!("cloud-nexus-worker-{}", index));
+            if stack_kb > 0 {
+                builder = builder.stack_size(stack_kb as usize * 1024);
+            }
+            let worker = Arc::clone(&pool);
+            match builder.spawn(move || worker.worker_loop()) {
+                Ok(handle) => pool.workers.lock().unwrap_or_else(PoisonError::into_inner).push(handle),
+                Err(_) => {
+                    // Stop the threads already started
+                    pool.shutdown(Duration::ZERO);
+                    return Err(ERROR_IO_FAILED);
+                }
+            }
+        }
+
+        Ok(pool)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn submit(&self, task: Task) -> Result<(), i32> {
+        let mut state = self.lock();
+        if state.shutdown {
+            return Err(ERROR_CANCELLED);
+        }
+        state.tasks.push_back(task);
+        drop(state);
+
+        self.work.notify_one();
+        Ok(())
+    }
+
+    fn worker_loop(&self) {
+        ON_WORKER.with(|on_worker| on_worker.set(true));
+        loop {
+            let task = {
+                let mut state = self.lock();
+                loop {
+                    if let Some(task) = state.tasks.pop_front() {
+                        state.busy += 1;
+                        self.peak_busy.fetch_max(state.busy, Ordering::SeqCst);
+                        break task;
+                    }
+                    // Queued tasks are drained before a shut down pool exits
+                    if state.shutdown {
+                        return;
+                    }
+                    state = self.work.wait(state).unwrap_or_else(PoisonError::into_inner);
+                }
+            };
+
+            // A panicking task must not take its worker down with it
+            let _ = catch_unwind(AssertUnwindSafe(task));
+            self.completed.fetch_add(1, Ordering::SeqCst);
+
+            let mut state = self.lock();
+            state.busy -= 1;
+            if state.busy == 0 && state.tasks.is_empty() {
+                self.idle.notify_all();
+            }
+        }
+    }
+
+    /// Stop accepting tasks and wait up to `timeout` for outstanding ones
+    ///
+    /// Returns true if every task finished. Otherwise tasks still queued are
+    /// dropped without running and the running ones finish in the background.
+    fn shutdown(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        state.shutdown = true;
+        self.work.notify_all();
+
+        while state.busy > 0 || !state.tasks.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                let aborted = std::mem::take(&mut state.tasks);
+                drop(state);
+                // Dropped outside the lock: a task may release resources that submit more work
+                drop(aborted);
+                return false;
+            }
+            state = self.idle.wait_timeout(state, deadline - now).unwrap_or_else(PoisonError::into_inner).0;
+        }
+        drop(state);
+
+        for worker in self.workers.lock().unwrap_or_else(PoisonError::into_inner).drain(..) {
+            if worker.thread().id() != thread::current().id() {
+                let _ = worker.join();
+            }
+        }
+        true
+    }
+
+    fn stats(&self) -> RuntimeStats {
+        let state = self.lock();
+        RuntimeStats {
+            running: true,
+            threads: self.threads,
+            stack_kb: self.stack_kb,
+            queue_depth: state.tasks.len(),
+            busy_workers: state.busy,
+            peak_busy_workers: self.peak_busy.load(Ordering::SeqCst),
+            completed_tasks: self.completed.load(Ordering::SeqCst),
+            ..Default::default()
+        }
+    }
+}
+
+/// Pool state, as reported by native_runtime_stats_json
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RuntimeStats {
+    /// False until the first task is submitted and after native_runtime_shutdown
+    pub running: bool,
+    pub threads: u32,
+    /// Worker stack size in KiB, 0 for the platform default
+    pub stack_kb: u32,
+    /// Tasks waiting for a free worker
+    pub queue_depth: usize,
+    /// Workers currently running a task
+    pub busy_workers: usize,
+    /// Most workers that have been busy at once
+    pub peak_busy_workers: usize,
+    pub completed_tasks: u64,
+    /// Most files open at once, 0 for no limit
+    pub max_open_files: u32,
+    /// Files currently open under an open file permit
+    pub open_files: usize,
+    /// Most files open at once since the limit was last configured
+    pub peak_open_files: usize,
+}
+
+struct Runtime {
+    /// Threads and stack size set by native_runtime_configure, 0 for defaults
+    threads: u32,
+    stack_kb: u32,
+    pool: Option<Arc<WorkerPool>>,
+}
+
+static RUNTIME: Mutex<Runtime> = Mutex::new(Runtime { threads: 0, stack_kb: 0, pool: None });
+
+fn runtime() -> MutexGuard<'static, Runtime> {
+    RUNTIME.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The running pool, started with the configured size on first use
+fn pool() -> Result<Arc<WorkerPool>, i32> {
+    let mut runtime = runtime();
+    if let Some(pool) = &runtime.pool {
+        return Ok(Arc::clone(pool));
+    }
+
+    let threads = match runtime.threads {
+        0 => DEFAULT_RUNTIME_THREADS,
+        threads => threads,
+    };
+    let pool = WorkerPool::start(threads, runtime.stack_kb)?;
+    runtime.pool = Some(Arc::clone(&pool));
+
+    static REGISTER_TRIM: Once = Once::new();
+    REGISTER_TRIM.call_once(|| register_trim_handler("runtime", trim_task_queue));
+    Ok(pool)
+}
+
+/// Trim handler of the pool: give back the task queue room a burst of submissions left behind
+fn trim_task_queue(level: TrimLevel) -> u64 {
+    if level < TrimLevel::Moderate {
+        return 0;
+    }
+    let Some(pool) = runtime().pool.clone() else {
+        return 0;
+    };
+    let mut state = pool.lock();
+    let before = state.tasks.capacity();
+    state.tasks.shrink_to_fit();
+    ((before - state.tasks.capacity()) * std::mem::size_of::<Task>()) as u64
+}
+
+/// Most chunk buffers kept for reuse between jobs
+const MAX_POOLED_CHUNK_BUFFERS: usize = DEFAULT_RUNTIME_THREADS as usize;
+
+/// Chunk buffers returned by finished copies, reused by the next ones
+static CHUNK_BUFFERS: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// Zeroed chunk buffer from the shared pool, handed back to it when dropped
+pub(crate) struct PooledBuffer(Vec<u8>);
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        static REGISTER_TRIM: Once = Once::new();
+        REGISTER_TRIM.call_once(|| register_trim_handler("chunk_buffers", trim_chunk_buffers));
+
+        let mut buffers = CHUNK_BUFFERS.lock().unwrap_or_else(PoisonError::into_inner);
+        let buffer = std::mem::take(&mut self.0);
+        if buffers.len() < MAX_POOLED_CHUNK_BUFFERS {
+            buffers.push(buffer);
+        } else if let Some(smallest) = buffers.iter_mut().min_by_key(|b| b.capacity()) {
+            // Full: keep the larger buffers, they are the costly ones to allocate again
+            if smallest.capacity() < buffer.capacity() {
+                *smallest = buffer;
+            }
+        }
+    }
+}
+
+/// Take a zeroed buffer of `len` bytes, reusing the smallest pooled one that is large enough
+pub(crate) fn take_chunk_buffer(len: usize) -> PooledBuffer {
+    let pooled = {
+        let mut buffers = CHUNK_BUFFERS.lock().unwrap_or_else(PoisonError::into_inner);
+        let best = buffers.iter()
+            .enumerate()
+            .filter(|(_, b)| b.capacity() >= len)
+            .min_by_key(|(_, b)| b.capacity())
+            .map(|(i, _)| i);
+        best.map(|i| buffers.swap_remove(i))
+    };
+    let mut buffer = pooled.unwrap_or_default();
+    buffer.clear();
+    buffer.resize(len, 0);
+    PooledBuffer(buffer)
+}
+
+/// Trim handler of the chunk buffer pool: drop every pooled buffer
+fn trim_chunk_buffers(_level: TrimLevel) -> u64 {
+    let buffers = std::mem::take(&mut *CHUNK_BUFFERS.lock().unwrap_or_else(PoisonError::into_inner));
+    buffers.iter().map(|b| b.capacity() as u64).sum()
+}
+
+thread_local! {
+    /// Set on the pool's worker threads
+    static ON_WORKER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the current thread is a worker of the runtime pool
+///
+/// Work that waits for another pool task must not do so from a worker, where
+/// it could hold up the very task it waits for.
+pub fn on_worker_thread() -> bool {
+    ON_WORKER.with(|on_worker| on_worker.get())
+}
+
+/// Run a task on the runtime pool
+///
+/// Tasks run in the order submitted. If the task cannot be queued it is
+/// dropped without running.
+pub fn spawn(task: impl FnOnce() + Send + 'static) -> Result<(), i32> {
+    pool()?.submit(Box::new(task))
+}
+
+/// Set the size of the runtime pool
+///
+/// Only possible while the pool is not running: before the first task is
+/// submitted, or after native_runtime_shutdown.
+///
+/// # Arguments
+/// * `threads` - Number of worker threads (0 for the default of 16, clamped to 64)
+/// * `stack_kb` - Stack size of each worker in KiB (0 for the platform default)
+///
+/// # Returns
+/// 0 on success, ERROR_RUNTIME_STARTED if the pool is already running
+#[no_mangle]
+pub extern "C" fn native_runtime_configure(threads: u32, stack_kb: u32) -> i32 {
+    let mut runtime = runtime();
+    if runtime.pool.is_some() {
+        return ERROR_RUNTIME_STARTED;
+    }
+
+    runtime.threads = threads.min(MAX_RUNTIME_THREADS);
+    runtime.stack_kb = stack_kb;
+    SUCCESS
+}
+
+/// Set the size of the runtime pool and the open file limit
+///
+/// Like native_runtime_configure, only possible while the pool is not running.
+/// Copies, uploads and downloads wait for a free slot before opening a file
+/// once `max_open_files` are open (see open_files.rs).
+///
+/// # Arguments
+/// * `threads` - Number of worker threads (0 for the default of 16, clamped to 64)
+/// * `stack_kb` - Stack size of each worker in KiB (0 for the platform default)
+/// * `max_open_files` - Most files open at once (0 for no limit)
+///
+/// # Returns
+/// 0 on success, ERROR_RUNTIME_STARTED if the pool is already running
+#[no_mangle]
+pub extern "C" fn native_runtime_configure_ex(threads: u32, stack_kb: u32, max_open_files: u32) -> i32 {
+    let mut runtime = runtime();
+    if runtime.pool.is_some() {
+        return ERROR_RUNTIME_STARTED;
+    }
+
+    runtime.threads = threads.min(MAX_RUNTIME_THREADS);
+    runtime.stack_kb = stack_kb;
+    set_open_file_limit(max_open_files);
+    SUCCESS
+}
+
+/// Get the state of the runtime pool
+///
+/// Returns a JSON envelope (see ffi_util.rs) whose `data` has running,
+/// threads, stack_kb, queue_depth, busy_workers, peak_busy_workers,
+/// completed_tasks, max_open_files, open_files and peak_open_files. Before the
+/// pool starts, threads and stack_kb are the configured values and the pool
+/// counters are 0.
+///
+/// # Arguments
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope (caller must free with scan_folder_free_string), or null if it
+/// cannot be allocated
+#[no_mangle]
+pub extern "C" fn native_runtime_stats_json(out_len: *mut usize) -> *mut c_char {
+    let mut stats = {
+        let runtime = runtime();
+        match &runtime.pool {
+            Some(pool) => pool.stats(),
+            None => RuntimeStats {
+                threads: if runtime.threads == 0 { DEFAULT_RUNTIME_THREADS } else { runtime.threads },
+                stack_kb: runtime.stack_kb,
+                ..Default::default()
+            },
+        }
+    };
+    (stats.max_open_files, stats.open_files, stats.peak_open_files) = open_file_usage();
+
+    json_envelope(Ok(stats), out_len)
+}
+
+/// Stop the runtime pool
+///
+/// New tasks are no longer accepted by the stopped pool; the next submission
+/// starts a new one. Queued and running tasks get `timeout_ms` to finish.
+/// After that, queued tasks are dropped without running, and running tasks
+/// finish in the background.
+///
+/// # Arguments
+/// * `timeout_ms` - How long to wait for outstanding tasks
+///
+/// # Returns
+/// 0 if every task finished (or the pool was not running), ERROR_CANCELLED if
+/// outstanding tasks were aborted
+#[no_mangle]
+pub extern "C" fn native_runtime_shutdown(timeout_ms: u32) -> i32 {
+    // Taken out first so new submissions do not wait for the drain
+    let pool = runtime().pool.take();
+    match pool {
+        Some(pool) if !pool.shutdown(Duration::from_millis(timeout_ms as u64)) => ERROR_CANCELLED,
+        _ => SUCCESS,
+    }
+}
+
+/// Clear caches that are cheap to rebuild (search query caches, pooled chunk buffers)
+pub const TRIM_MEMORY_LIGHT: i32 = 0;
+/// Also shrink grown tables, rings and maps to fit their contents
+pub const TRIM_MEMORY_MODERATE: i32 = 1;
+/// Also return free heap pages to the operating system where the allocator allows it
+pub const TRIM_MEMORY_CRITICAL: i32 = 2;
+
+/// How much native_trim_memory releases, by TRIM_MEMORY_* code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TrimLevel {
+    Light,
+    Moderate,
+    Critical,
+}
+
+impl TrimLevel {
+    /// Level of a TRIM_MEMORY_* code; codes outside the range are clamped
+    pub fn from_code(code: i32) -> Self {
+        match code {
+            i32::MIN..=TRIM_MEMORY_LIGHT => TrimLevel::Light,
+            TRIM_MEMORY_MODERATE => TrimLevel::Moderate,
+            _ => TrimLevel::Critical,
+        }
+    }
+
+    /// TRIM_MEMORY_* code of the level
+    pub fn code(self) -> i32 {
+        match self {
+            TrimLevel::Light => TRIM_MEMORY_LIGHT,
+            TrimLevel::Moderate => TRIM_MEMORY_MODERATE,
+            TrimLevel::Critical => TRIM_MEMORY_CRITICAL,
+        }
+    }
+}
+
+/// Releases what a subsystem can spare at a level, returning an estimate of the bytes freed
+pub(crate) type TrimHandler = fn(TrimLevel) -> u64;
+
+static TRIM_HANDLERS: Mutex<Vec<(&'static str, TrimHandler)>> = Mutex::new(Vec::new());
+
+/// Make native_trim_memory call `handler`
+///
+/// Subsystems register on first use; registering a name again replaces its handler.
+pub(crate) fn register_trim_handler(name: &'static str, handler: TrimHandler) {
+    let mut handlers = TRIM_HANDLERS.lock().unwrap_or_else(PoisonError::into_inner);
+    match handlers.iter_mut().find(|(registered, _)| *registered == name) {
+        Some(entry) => entry.1 = handler,
+        None => handlers.push((name, handler)),
+    }
+}
+
+/// Hand free heap pages back to the operating system
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn release_free_heap() {
+    extern "C" {
+        fn malloc_trim(pad: usize) -> i32;
+    }
+    unsafe { malloc_trim(0); }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn release_free_heap() {}
+
+/// Release memory retained after a burst of activity
+///
+/// Meant for the app's memory-pressure signal. Every registered subsystem
+/// releases what it can spare at `level`: TRIM_MEMORY_LIGHT clears search
+/// query caches and drops pooled chunk buffers, TRIM_MEMORY_MODERATE also
+/// shrinks search index tables, the runtime task queue and internal rings and
+/// maps to fit, TRIM_MEMORY_CRITICAL also calls malloc_trim on glibc.
+/// Everything released is rebuilt on demand, so later operations work as
+/// before, only slower for a while.
+///
+/// Safe to call from any thread. Search indexes are not touched here: each one
+/// applies the trim on its next call, and that is not part of the estimate.
+///
+/// # Arguments
+/// * `level` - TRIM_MEMORY_LIGHT, TRIM_MEMORY_MODERATE or TRIM_MEMORY_CRITICAL (other values are clamped)
+///
+/// # Returns
+/// Estimate of the bytes released (not counting what malloc_trim returns to the system)
+#[no_mangle]
+pub extern "C" fn native_trim_memory(level: i32) -> u64 {
+    let level = TrimLevel::from_code(level);
+    let released = run_trim_handlers(level, |_| true);
+    if level >= TrimLevel::Critical {
+        release_free_heap();
+    }
+    released
+}
+
+/// Run the registered trim handlers whose name passes `filter`, summing their estimates
+fn run_trim_handlers(level: TrimLevel, filter: impl Fn(&str) -> bool) -> u64 {
+    // Copied out so a handler may register another one
+    let handlers = TRIM_HANDLERS.lock().unwrap_or_else(PoisonError::into_inner).clone();
+    handlers.iter().filter(|(name, _)| filter(name)).map(|(_, handler)| handler(level)).sum()
+}
+
+/// Serializes tests that reconfigure or depend on the global pool
+#[cfg(test)]
+pub(crate) static RUNTIME_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer_queue::*;
+    use std::ffi::{c_void, CString};
+    use std::fs;
+
+    fn stats() -> serde_json::Value {
+        let mut len = 0usize;
+        let json = native_runtime_stats_json(&mut len);
+        let text = unsafe { CString::from_raw(json) }.into_string().unwrap();
+        assert_eq!(text.len(), len);
+        let envelope: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(envelope["ok"], true);
+        envelope["data"].clone()
+    }
+
+    /// Highest number of queue jobs seen running at once
+    struct RunningWatch {
+        queue: *mut TransferQueue,
+        peak: AtomicUsize,
+    }
+
+    extern "C" fn watch_running(_bytes_done: u64, _total_bytes: u64, _jobs_done: u32, _total_jobs: u32,
+                                user_data: *mut c_void) {
+        let watch = unsafe { &*(user_data as *const RunningWatch) };
+        let running = unsafe { &*watch.queue }.jobs()
+            .iter()
+            .filter(|job| job.status == TransferJobStatus::Running)
+            .count();
+        watch.peak.fetch_max(running, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_two_thread_pool_limits_parallel_folder_copies() {
+        let _guard = RUNTIME_TEST_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        assert_eq!(native_runtime_shutdown(10_000), SUCCESS);
+        assert_eq!(native_runtime_configure(2, 512), SUCCESS);
+        assert_eq!(stats()["running"], false);
+        assert_eq!(stats()["threads"], 2);
+
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_runtime_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for folder in 0..6 {
+            let source = dir.join(format!("source_{}", folder));
+            fs::create_dir_all(&source).unwrap();
+            for file in 0..4 {
+                fs::write(source.join(format!("{}.bin", file)), vec![folder as u8; 64 * 1024]).unwrap();
+            }
+        }
+
+        // The queue allows more jobs than the pool has threads
+        let queue = transfer_queue_create(6);
+        let watch = RunningWatch { queue, peak: AtomicUsize::new(0) };
+        assert_eq!(transfer_queue_set_progress_callback(queue, Some(watch_running),
+                                                        &watch as *const RunningWatch as *mut c_void), SUCCESS);
+        assert_eq!(transfer_queue_pause(queue), SUCCESS);
+        for folder in 0..6 {
+            let source = CString::new(dir.join(format!("source_{}", folder)).to_string_lossy().to_string()).unwrap();
+            let dest = CString::new(dir.join(format!("dest_{}", folder)).to_string_lossy().to_string()).unwrap();
+            assert_ne!(transfer_queue_add_folder_copy(queue, source.as_ptr(), dest.as_ptr(), 0), 0);
+        }
+        assert_eq!(transfer_queue_resume(queue), SUCCESS);
+        assert_eq!(native_runtime_configure(4, 0), ERROR_RUNTIME_STARTED);
+
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while !unsafe { &*queue }.jobs().iter().all(|job| job.status == TransferJobStatus::Completed) {
+            assert!(Instant::now() < deadline, "queue did not finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+        transfer_queue_free(queue);
+
+        let peak = watch.peak.load(Ordering::SeqCst);
+        assert!((1..=2).contains(&peak), "{} folder copies ran at once", peak);
+        let stats = stats();
+        assert_eq!(stats["running"], true);
+        assert_eq!(stats["threads"], 2);
+        assert!(stats["peak_busy_workers"].as_u64().unwrap() <= 2);
+        for folder in 0..6 {
+            assert_eq!(fs::read(dir.join(format!("dest_{}/3.bin", folder))).unwrap(), vec![folder as u8; 64 * 1024]);
+        }
+
+        // Restore the default pool for other tests
+        assert_eq!(native_runtime_shutdown(10_000), SUCCESS);
+        assert_eq!(native_runtime_configure(0, 0), SUCCESS);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_shutdown_drains_or_aborts() {
+        let _guard = RUNTIME_TEST_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let pool = WorkerPool::start(1, 0).unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        let (started_tx, started_rx) = std::sync::mpsc::channel::<()>();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        pool.submit(Box::new(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        })).unwrap();
+        started_rx.recv().unwrap();
+        let counter = Arc::clone(&ran);
+        pool.submit(Box::new(move || { counter.fetch_add(1, Ordering::SeqCst); })).unwrap();
+        assert_eq!(pool.stats().queue_depth, 1);
+
+        // The blocked task keeps the queue from draining, so the queued one is dropped
+        assert!(!pool.shutdown(Duration::from_millis(50)));
+        assert!(pool.submit(Box::new(|| {})).is_err());
+        release_tx.send(()).unwrap();
+        assert!(pool.shutdown(Duration::from_secs(10)));
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+
+        let pool = WorkerPool::start(2, 0).unwrap();
+        for _ in 0..8 {
+            let counter = Arc::clone(&ran);
+            pool.submit(Box::new(move || { counter.fetch_add(1, Ordering::SeqCst); })).unwrap();
+        }
+        pool.submit(Box::new(|| panic!("task panic"))).unwrap();
+        assert!(pool.shutdown(Duration::from_secs(10)));
+        assert_eq!(ran.load(Ordering::SeqCst), 8);
+        assert_eq!(pool.stats().completed_tasks, 9);
+    }
+
+    static TRIM_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn counting_trim(level: TrimLevel) -> u64 {
+        TRIM_CALLS.fetch_add(1, Ordering::SeqCst);
+        if level >= TrimLevel::Moderate { 100 } else { 1 }
+    }
+
+    fn replaced_trim(_level: TrimLevel) -> u64 {
+        7
+    }
+
+    #[test]
+    fn test_trim_handlers_run_by_level() {
+        assert_eq!(TrimLevel::from_code(-5), TrimLevel::Light);
+        assert_eq!(TrimLevel::from_code(TRIM_MEMORY_MODERATE), TrimLevel::Moderate);
+        assert_eq!(TrimLevel::from_code(99), TrimLevel::Critical);
+
+        // Filtered by name: other modules' handlers trim state other tests are using
+        let ours = |name: &str| name.starts_with("runtime_test");
+        register_trim_handler("runtime_test", counting_trim);
+        assert_eq!(run_trim_handlers(TrimLevel::Light, ours), 1);
+        assert_eq!(run_trim_handlers(TrimLevel::Critical, ours), 100);
+        assert_eq!(TRIM_CALLS.load(Ordering::SeqCst), 2);
+
+        register_trim_handler("runtime_test_other", counting_trim);
+        register_trim_handler("runtime_test", replaced_trim);
+        assert_eq!(run_trim_handlers(TrimLevel::Moderate, ours), 107);
+        assert_eq!(TRIM_CALLS.load(Ordering::SeqCst), 3);
+        let registered = TRIM_HANDLERS.lock().unwrap().iter().filter(|(name, _)| ours(name)).count();
+        assert_eq!(registered, 2);
+    }
+
+    #[test]
+    fn test_chunk_buffers_reused_and_trimmed() {
+        // Larger than any chunk other tests copy with, so none of them takes it
+        const LEN: usize = 12 * 1024 * 1024 + 17;
+        let mut buffer = take_chunk_buffer(LEN);
+        buffer.fill(0xAB);
+        drop(buffer);
+        assert!(CHUNK_BUFFERS.lock().unwrap().iter().any(|b| b.capacity() >= LEN));
+
+        let again = take_chunk_buffer(LEN);
+        assert_eq!(again.len(), LEN);
+        assert!(again.iter().all(|&b| b == 0));
+        drop(again);
+
+        assert!(run_trim_handlers(TrimLevel::Light, |name| name == "chunk_buffers") >= LEN as u64);
+        assert!(CHUNK_BUFFERS.lock().unwrap().iter().all(|b| b.capacity() < LEN));
+        assert_eq!(take_chunk_buffer(16).len(), 16);
+    }
+
+    #[test]
+    fn test_runtime_trim_keeps_pool_working() {
+        let _guard = RUNTIME_TEST_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let pool = pool().unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..64 {
+            let ran = Arc::clone(&ran);
+            pool.submit(Box::new(move || { ran.fetch_add(1, Ordering::SeqCst); })).unwrap();
+        }
+        assert_eq!(trim_task_queue(TrimLevel::Light), 0);
+        run_trim_handlers(TrimLevel::Critical, |name| name == "runtime");
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        pool.submit(Box::new(move || { tx.send(()).unwrap(); })).unwrap();
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(native_runtime_shutdown(10_000), SUCCESS);
+        assert_eq!(ran.load(Ordering::SeqCst), 64);
+    }
+
+    #[test]
+    fn test_configure_and_stats_before_start() {
+        let _guard = RUNTIME_TEST_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        assert_eq!(native_runtime_shutdown(10_000), SUCCESS);
+        assert_eq!(native_runtime_configure(500, 0), SUCCESS);
+        assert_eq!(stats()["threads"], MAX_RUNTIME_THREADS);
+        assert_eq!(native_runtime_configure(0, 0), SUCCESS);
+        assert_eq!(stats()["threads"], DEFAULT_RUNTIME_THREADS);
+        assert_eq!(stats()["queue_depth"], 0);
+        assert_eq!(native_runtime_shutdown(0), SUCCESS);
+    }
+}
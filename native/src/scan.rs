@@ -1,8 +1,17 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
 use std::time::Instant;
 
+use crate::errors::checked;
+use crate::file_io::{is_cancelled, ERROR_CANCELLED, ERROR_FILE_NOT_FOUND, ERROR_INVALID_PATH, ERROR_NULL_POINTER};
+use crate::archive::{is_zip_file, read_zip_listing};
+use crate::name_order::SortLocale;
+use crate::search::{SearchDocument, SearchIndex};
+use crate::temp::JsonLinesSpill;
+
 // ============================================================================
 // DATA STRUCTURES
 // ============================================================================
@@ -27,6 +36,11 @@ pub struct FolderScanResult {
     
     /// Duration of scan in milliseconds
     pub scan_duration_ms: u64,
+
+    /// Archives that could not be listed (see ScanOptions::expand_archives);
+    /// the scan goes on without their contents
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FolderScanError>,
 }
 
 /// Single item in folder scan
@@ -46,6 +60,25 @@ pub struct FolderScanItem {
     
     /// Absolute path
     pub absolute_path: String,
+
+    /// Device the item lives on (0 where unavailable)
+    #[serde(default)]
+    pub dev: u64,
+
+    /// Inode of the item on `dev` (0 where unavailable); hard links share it
+    #[serde(default)]
+    pub inode: u64,
+
+    /// Another path to the same file was already reported and counted toward
+    /// total_size (only set when the scan dedupes hard links)
+    #[serde(default)]
+    pub is_hardlink_duplicate: bool,
+
+    /// Entry listed from inside an archive rather than a path on disk; its
+    /// paths are the archive's followed by the archive separator and the entry
+    /// path. Counts toward none of the totals.
+    #[serde(default)]
+    pub is_virtual: bool,
 }
 
 /// Error result for folder scan
@@ -59,8 +92,78 @@ pub struct FolderScanError {
 // SYNC FOLDER SCANNING
 // ============================================================================
 
+/// Scan traversal order: items in pre-order, each folder followed by its subtree
+pub const SCAN_TRAVERSAL_DFS_PRE_ORDER: i32 = 0;
+
+/// Scan traversal order: level by level
+pub const SCAN_TRAVERSAL_BFS: i32 = 1;
+
+/// Order in which a scan visits, and reports, items
+///
+/// Within a folder, entries are always sorted folders first, then files, each
+/// by name (see ScanOptions::sort_locale), so both orders are deterministic for
+/// a given tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScanTraversal {
+    /// Each folder is immediately followed by its whole subtree, then its next sibling
+    #[default]
+    DepthFirstPreOrder,
+    /// All items at depth 1, then all items at depth 2 (grouped by parent in
+    /// the order the parents were reported), and so on
+    BreadthFirst,
+}
+
+impl ScanTraversal {
+    /// Traversal for a SCAN_TRAVERSAL_* code, None for unknown codes
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            SCAN_TRAVERSAL_DFS_PRE_ORDER => Some(ScanTraversal::DepthFirstPreOrder),
+            SCAN_TRAVERSAL_BFS => Some(ScanTraversal::BreadthFirst),
+            _ => None,
+        }
+    }
+}
+
+/// Separator between an archive's path and the path of an entry inside it
+pub const DEFAULT_ARCHIVE_SEPARATOR: &str = "!/";
+
+/// Options of a folder scan
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ScanOptions {
+    /// Order in which items are visited and reported
+    pub traversal: ScanTraversal,
+
+    /// Count a file with several hard links toward total_size only once; every
+    /// path is still reported, later ones with is_hardlink_duplicate set
+    pub dedupe_hardlinks: bool,
+
+    /// Fail with a max-depth error (see max_depth_error_path) when a folder at
+    /// max_depth has contents, instead of silently leaving them out
+    pub fail_beyond_max_depth: bool,
+
+    /// How names are ordered within a folder
+    pub sort_locale: SortLocale,
+
+    /// Follow every file with a ZIP signature by virtual items for its entries,
+    /// read from the central directory without extracting (max_depth does not
+    /// apply inside archives). Every file is opened to check its signature.
+    pub expand_archives: bool,
+
+    /// Separator of virtual item paths (None for DEFAULT_ARCHIVE_SEPARATOR);
+    /// pick one that cannot occur in the scanned file names
+    pub archive_separator: Option<String>,
+}
+
+impl From<ScanTraversal> for ScanOptions {
+    fn from(traversal: ScanTraversal) -> Self {
+        ScanOptions { traversal, ..Default::default() }
+    }
+}
+
 /// Scan folder synchronously with optimized directory traversal
 ///
+/// Items are in depth-first pre-order (see ScanTraversal).
+///
 /// # Arguments
 /// * `root_path` - Absolute path to the folder to scan
 /// * `max_depth` - Optional maximum depth to scan (None for unlimited)
@@ -71,6 +174,267 @@ pub fn scan_folder_sync(
     root_path: &str,
     max_depth: Option<u64>,
 ) -> Result<FolderScanResult, String> {
+    scan_folder_sync_ordered(root_path, max_depth, ScanTraversal::default())
+}
+
+/// Scan folder synchronously, reporting items in the given traversal order
+pub fn scan_folder_sync_ordered(
+    root_path: &str,
+    max_depth: Option<u64>,
+    traversal: ScanTraversal,
+) -> Result<FolderScanResult, String> {
+    scan_folder_sync_with(root_path, max_depth, &traversal.into())
+}
+
+/// Scan folder synchronously with the given options
+pub fn scan_folder_sync_with(
+    root_path: &str,
+    max_depth: Option<u64>,
+    options: &ScanOptions,
+) -> Result<FolderScanResult, String> {
+    let mut items = Vec::new();
+    let mut result = scan_folder_each_with(root_path, max_depth, options, |item| {
+        items.push(item);
+        Ok(())
+    })?;
+    result.items = items;
+    Ok(result)
+}
+
+/// Running totals of a scan
+#[derive(Default)]
+struct ScanCounters {
+    total_size: u64,
+    file_count: u64,
+    folder_count: u64,
+}
+
+/// Per-scan state: totals plus the filesystem identities seen so far
+struct ScanState {
+    counters: ScanCounters,
+    dedupe_hardlinks: bool,
+    /// (dev, inode) of folders already descended into; a folder reachable
+    /// twice (bind mounts, or loops through followed links) is only read once
+    visited_folders: HashSet<(u64, u64)>,
+    /// (dev, inode) of multiply-linked files already counted
+    counted_links: HashSet<(u64, u64)>,
+    /// Separator of virtual item paths, set when archives are expanded
+    archive_separator: Option<String>,
+    /// Archives that could not be listed
+    errors: Vec<FolderScanError>,
+}
+
+impl ScanState {
+    fn new(options: &ScanOptions) -> Self {
+        ScanState {
+            counters: ScanCounters::default(),
+            dedupe_hardlinks: options.dedupe_hardlinks,
+            visited_folders: HashSet::new(),
+            counted_links: HashSet::new(),
+            archive_separator: options.expand_archives.then(|| {
+                options.archive_separator.clone().unwrap_or_else(|| DEFAULT_ARCHIVE_SEPARATOR.to_string())
+            }),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Record a folder about to be read; false if it was read before
+    fn enter_folder(&mut self, identity: (u64, u64)) -> bool {
+        identity == (0, 0) || self.visited_folders.insert(identity)
+    }
+}
+
+/// Filesystem identity (dev, inode) of an item, (0, 0) where unavailable
+///
+/// On Windows the equivalent (volume serial, file index) is only exposed by
+/// the unstable `windows_by_handle` MetadataExt methods, so it reads as (0, 0)
+/// there and neither hard-link dedupe nor folder cycle checks apply.
+#[cfg(unix)]
+pub(crate) fn file_identity(metadata: &fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_identity(_metadata: &fs::Metadata) -> (u64, u64) {
+    (0, 0)
+}
+
+/// Number of hard links to a file (1 where unavailable)
+#[cfg(unix)]
+fn link_count(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+fn link_count(_metadata: &fs::Metadata) -> u64 {
+    1
+}
+
+/// Read a directory's entries, sorted folders first, then files, both by name
+///
+/// Unreadable directories are reported and treated as empty.
+fn read_dir_sorted(path: &Path, sort_locale: SortLocale) -> Vec<fs::DirEntry> {
+    let dir_entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read directory {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+    
+    let mut entries: Vec<_> = dir_entries
+        .filter_map(|e| e.ok())
+        .map(|e| (e.path().is_dir(), e))
+        .collect();
+    
+    entries.sort_by(|(a_is_dir, a), (b_is_dir, b)| {
+        b_is_dir.cmp(a_is_dir).then_with(|| sort_locale.compare(&a.file_name(), &b.file_name()))
+    });
+    entries.into_iter().map(|(_, e)| e).collect()
+}
+
+/// Report one directory entry to `on_item`
+///
+/// Returns the entry's path if it is a folder to descend into; a folder whose
+/// (dev, inode) was already descended into is reported but not read again.
+/// Symlinks are skipped to avoid infinite loops, as are files whose metadata
+/// can't be read.
+fn visit_entry<F>(
+    entry: &fs::DirEntry,
+    root: &Path,
+    state: &mut ScanState,
+    on_item: &mut F,
+) -> Result<Option<PathBuf>, String>
+where
+    F: FnMut(FolderScanItem) -> Result<(), String>,
+{
+    let entry_path = entry.path();
+    
+    if entry_path.is_symlink() {
+        return Ok(None);
+    }
+    
+    let is_folder = entry_path.is_dir();
+    let metadata = entry.metadata().ok();
+    if !is_folder && metadata.is_none() {
+        return Ok(None);
+    }
+    let mut item = entry_item(&entry_path, root, is_folder, metadata.as_ref());
+    let identity = (item.dev, item.inode);
+    if is_folder {
+        state.counters.folder_count += 1;
+    } else {
+        let multiply_linked = metadata.as_ref().is_some_and(|m| link_count(m) > 1);
+        if state.dedupe_hardlinks && multiply_linked && identity != (0, 0) {
+            item.is_hardlink_duplicate = !state.counted_links.insert(identity);
+        }
+        if !item.is_hardlink_duplicate {
+            state.counters.total_size += item.size;
+        }
+        state.counters.file_count += 1;
+    }
+    
+    let archive = (!is_folder && state.archive_separator.is_some() && is_zip_file(&entry_path))
+        .then(|| (item.relative_path.clone(), item.absolute_path.clone()));
+    on_item(item)?;
+    if let Some((relative_path, absolute_path)) = archive {
+        expand_archive(&entry_path, &relative_path, &absolute_path, state, on_item)?;
+    }
+    
+    Ok(if is_folder && state.enter_folder(identity) { Some(entry_path) } else { None })
+}
+
+/// Report the entries of a ZIP archive as virtual items after the archive itself
+///
+/// An archive that cannot be listed is recorded in the scan's errors instead.
+fn expand_archive<F>(
+    archive_path: &Path,
+    relative_path: &str,
+    absolute_path: &str,
+    state: &mut ScanState,
+    on_item: &mut F,
+) -> Result<(), String>
+where
+    F: FnMut(FolderScanItem) -> Result<(), String>,
+{
+    let listing = match read_zip_listing(archive_path) {
+        Ok(listing) => listing,
+        Err(error) => {
+            state.errors.push(FolderScanError { error_message: error.message, item_path: Some(absolute_path.to_string()) });
+            return Ok(());
+        }
+    };
+
+    let separator = state.archive_separator.as_deref().unwrap_or(DEFAULT_ARCHIVE_SEPARATOR);
+    for entry in listing.entries {
+        on_item(FolderScanItem {
+            relative_path: format!("{}{}{}", relative_path, separator, entry.path),
+            name: entry.path.rsplit('/').next().unwrap_or_default().to_string(),
+            is_folder: entry.is_folder,
+            size: entry.size,
+            absolute_path: format!("{}{}{}", absolute_path, separator, entry.path),
+            dev: 0,
+            inode: 0,
+            is_hardlink_duplicate: false,
+            is_virtual: true,
+        })?;
+    }
+    Ok(())
+}
+
+/// Shape a directory entry into a scan item, with is_hardlink_duplicate unset
+///
+/// `metadata` is the entry's metadata if it could be read; folders report size 0.
+fn entry_item(entry_path: &Path, root: &Path, is_folder: bool, metadata: Option<&fs::Metadata>) -> FolderScanItem {
+    let (dev, inode) = metadata.map(file_identity).unwrap_or((0, 0));
+    let size = if is_folder { 0 } else { metadata.map_or(0, |m| m.len()) };
+    let relative_path = entry_path
+        .strip_prefix(root)
+        .map(|p| p.to_string_lossy().replace('\\', "/"))
+        .unwrap_or_else(|_| entry_path.to_string_lossy().to_string());
+    
+    FolderScanItem {
+        name: entry_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+        relative_path,
+        is_folder,
+        size,
+        absolute_path: entry_path.to_string_lossy().to_string(),
+        dev,
+        inode,
+        is_hardlink_duplicate: false,
+        is_virtual: false,
+    }
+}
+
+/// Scan folder synchronously, handing each item to `on_item` instead of collecting them
+///
+/// Items are handed over in `traversal` order. The returned FolderScanResult
+/// carries the counters only (`items` is empty). An error returned by
+/// `on_item` aborts the scan.
+pub fn scan_folder_each<F>(
+    root_path: &str,
+    max_depth: Option<u64>,
+    traversal: ScanTraversal,
+    on_item: F,
+) -> Result<FolderScanResult, String>
+where
+    F: FnMut(FolderScanItem) -> Result<(), String>,
+{
+    scan_folder_each_with(root_path, max_depth, &traversal.into(), on_item)
+}
+
+/// Scan folder synchronously with the given options, handing each item to `on_item`
+pub fn scan_folder_each_with<F>(
+    root_path: &str,
+    max_depth: Option<u64>,
+    options: &ScanOptions,
+    mut on_item: F,
+) -> Result<FolderScanResult, String>
+where
+    F: FnMut(FolderScanItem) -> Result<(), String>,
+{
     let start_time = Instant::now();
     
     let root = Path::new(root_path);
@@ -84,112 +448,137 @@ pub fn scan_folder_sync(
         return Err(format!("Path is not a directory: {}", root_path));
     }
     
-    let mut items = Vec::new();
-    let mut total_size: u64 = 0;
-    let mut file_count: u64 = 0;
-    let mut folder_count: u64 = 0;
+    let mut state = ScanState::new(options);
+    if let Ok(metadata) = fs::metadata(root) {
+        state.enter_folder(file_identity(&metadata));
+    }
     
+    // Entries of the root are at depth 0; a folder's contents are read only
+    // while their depth is within max_depth
     let max_depth = max_depth.unwrap_or(u64::MAX);
     
-    // Use a stack for iterative depth-first traversal
-    // This avoids stack overflow on deep folder structures
-    let mut stack = vec![(PathBuf::from(root_path), 0u64)];
-    
-    while let Some((current_path, current_depth)) = stack.pop() {
-        if current_depth > max_depth {
-            continue;
-        }
-        
-        // Read directory entries
-        let dir_entries = match fs::read_dir(&current_path) {
-            Ok(entries) => entries,
-            Err(e) => {
-                eprintln!("Failed to read directory {}: {}", current_path.display(), e);
-                continue;
-            }
-        };
-        
-        // Collect entries first to sort them
-        let mut entries: Vec<_> = dir_entries
-            .filter_map(|e| e.ok())
-            .collect();
-        
-        // Sort entries: folders first, then files, both alphabetically
-        entries.sort_by(|a, b| {
-            let a_is_dir = a.path().is_dir();
-            let b_is_dir = b.path().is_dir();
-            
-            match (a_is_dir, b_is_dir) {
-                (true, false) => std::cmp::Ordering::Less,
-                (false, true) => std::cmp::Ordering::Greater,
-                _ => a.file_name().cmp(&b.file_name()),
-            }
-        });
-        
-        for entry in entries {
-            let entry_path = entry.path();
+    // Both traversals are iterative, which avoids stack overflow on deep folder structures
+    match options.traversal {
+        ScanTraversal::DepthFirstPreOrder => {
+            // Stack of entries still to report; children are pushed in reverse
+            // so they pop in sorted order, right after their parent
+            let mut stack: Vec<(fs::DirEntry, u64)> = read_dir_sorted(root, options.sort_locale).into_iter().rev().map(|e| (e, 0)).collect();
             
-            // Skip symlinks to avoid infinite loops
-            if entry_path.is_symlink() {
-                continue;
+            while let Some((entry, depth)) = stack.pop() {
+                if let Some(folder) = visit_entry(&entry, root, &mut state, &mut on_item)? {
+                    if depth < max_depth {
+                        stack.extend(read_dir_sorted(&folder, options.sort_locale).into_iter().rev().map(|e| (e, depth + 1)));
+                    } else if options.fail_beyond_max_depth && has_entries(&folder) {
+                        return Err(max_depth_error(root, &folder));
+                    }
+                }
             }
+        }
+        ScanTraversal::BreadthFirst => {
+            // Queue of folders whose contents are still to report
+            let mut queue = VecDeque::from([(PathBuf::from(root_path), 0u64)]);
             
-            if entry_path.is_dir() {
-                // It's a subfolder
-                folder_count += 1;
-                
-                let relative_path = entry_path
-                    .strip_prefix(root)
-                    .map(|p| p.to_string_lossy().replace('\\', "/"))
-                    .unwrap_or_else(|_| entry_path.to_string_lossy().to_string());
-                
-                items.push(FolderScanItem {
-                    name: entry.file_name().to_string_lossy().to_string(),
-                    relative_path: relative_path.clone(),
-                    is_folder: true,
-                    size: 0,
-                    absolute_path: entry_path.to_string_lossy().to_string(),
-                });
-                
-                // Add to stack for deeper traversal
-                stack.push((entry_path, current_depth + 1));
-            } else {
-                // It's a file
-                let metadata = match entry.metadata() {
-                    Ok(m) => m,
-                    Err(_) => continue,
-                };
-                
-                let size = metadata.len();
-                total_size += size;
-                file_count += 1;
-                
-                let relative_path = entry_path
-                    .strip_prefix(root)
-                    .map(|p| p.to_string_lossy().replace('\\', "/"))
-                    .unwrap_or_else(|_| entry_path.to_string_lossy().to_string());
-                
-                items.push(FolderScanItem {
-                    name: entry.file_name().to_string_lossy().to_string(),
-                    relative_path: relative_path.clone(),
-                    is_folder: false,
-                    size,
-                    absolute_path: entry_path.to_string_lossy().to_string(),
-                });
+            while let Some((folder, depth)) = queue.pop_front() {
+                for entry in read_dir_sorted(&folder, options.sort_locale) {
+                    if let Some(subfolder) = visit_entry(&entry, root, &mut state, &mut on_item)? {
+                        if depth < max_depth {
+                            queue.push_back((subfolder, depth + 1));
+                        } else if options.fail_beyond_max_depth && has_entries(&subfolder) {
+                            return Err(max_depth_error(root, &subfolder));
+                        }
+                    }
+                }
             }
         }
     }
     
+    let counters = state.counters;
     Ok(FolderScanResult {
         root_path: root_path.to_string(),
-        items,
-        total_size,
-        file_count,
-        folder_count,
+        items: Vec::new(),
+        total_size: counters.total_size,
+        file_count: counters.file_count,
+        folder_count: counters.folder_count,
         scan_duration_ms: start_time.elapsed().as_millis() as u64,
+        errors: state.errors,
     })
 }
 
+// ============================================================================
+// DISK SPILLING FOR LARGE SCANS
+// ============================================================================
+
+/// Default number of items kept in memory before a spilling scan moves to disk
+pub const DEFAULT_SCAN_SPILL_THRESHOLD: usize = 100_000;
+
+/// Message returned by scan_folder_get_error when results only exist on disk
+const SPILLED_RESULT_MESSAGE: &str =
+    "Scan results were spilled to disk; read them with scan_folder_read_items";
+
+/// Error used to abort a scan whose cancel flag was set
+const SCAN_CANCELLED_MESSAGE: &str = "Scan cancelled";
+
+/// Start of the error of a scan stopped by fail_beyond_max_depth, followed by
+/// ": " and the relative path of the folder whose contents are too deep
+const MAX_DEPTH_EXCEEDED_MESSAGE: &str = "Maximum folder depth exceeded";
+
+fn max_depth_error(root: &Path, folder: &Path) -> String {
+    let relative_path = folder.strip_prefix(root).unwrap_or(folder).to_string_lossy().replace('\\', "/");
+    format!("{}: {}", MAX_DEPTH_EXCEEDED_MESSAGE, relative_path)
+}
+
+/// Relative path of the offending folder if `error` is a max-depth error
+pub fn max_depth_error_path(error: &str) -> Option<&str> {
+    error.strip_prefix(MAX_DEPTH_EXCEEDED_MESSAGE)?.strip_prefix(": ")
+}
+
+/// Whether a folder holds anything a scan would report
+fn has_entries(folder: &Path) -> bool {
+    fs::read_dir(folder)
+        .map(|entries| entries.filter_map(|e| e.ok()).any(|e| !e.path().is_symlink()))
+        .unwrap_or(false)
+}
+
+/// Scan a folder, moving items to a spill file in `spill_dir` once more than
+/// `threshold` items have been found
+///
+/// Returns the scan result (with `items` empty if it spilled) and the spill, if any.
+pub fn scan_folder_spilling(
+    root_path: &str,
+    max_depth: Option<u64>,
+    options: &ScanOptions,
+    threshold: usize,
+    spill_dir: &Path,
+) -> Result<(FolderScanResult, Option<JsonLinesSpill>), String> {
+    let mut items = Vec::new();
+    let mut spill: Option<JsonLinesSpill> = None;
+
+    let mut result = scan_folder_each_with(root_path, max_depth, options, |item| {
+        if let Some(spill) = spill.as_mut() {
+            return spill.push(&item).map_err(|e| format!("Failed to write scan spill file: {}", e));
+        }
+
+        items.push(item);
+        if items.len() > threshold {
+            let mut new_spill = JsonLinesSpill::create(spill_dir, "scan")
+                .map_err(|e| format!("Failed to create scan spill file: {}", e))?;
+            for item in items.drain(..) {
+                new_spill.push(&item).map_err(|e| format!("Failed to write scan spill file: {}", e))?;
+            }
+            items.shrink_to_fit();
+            spill = Some(new_spill);
+        }
+        Ok(())
+    })?;
+
+    if let Some(spill) = spill.as_mut() {
+        spill.finish().map_err(|e| format!("Failed to write scan spill file: {}", e))?;
+    }
+
+    result.items = items;
+    Ok((result, spill))
+}
+
 // ============================================================================
 // C FFI INTERFACE
 // ============================================================================
@@ -198,6 +587,7 @@ pub fn scan_folder_sync(
 pub struct FolderScanContext {
     result: Option<FolderScanResult>,
     error: Option<String>,
+    spill: Option<JsonLinesSpill>,
 }
 
 impl FolderScanContext {
@@ -205,6 +595,24 @@ impl FolderScanContext {
         FolderScanContext {
             result: None,
             error: None,
+            spill: None,
+        }
+    }
+
+    pub fn set_spill(&mut self, spill: JsonLinesSpill) {
+        self.spill = Some(spill);
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    /// Total number of scanned items, whether in memory or spilled
+    pub fn item_count(&self) -> usize {
+        match (&self.spill, &self.result) {
+            (Some(spill), _) => spill.len(),
+            (None, Some(result)) => result.items.len(),
+            (None, None) => 0,
         }
     }
     
@@ -227,6 +635,8 @@ impl FolderScanContext {
 
 /// Initialize a folder scan operation
 ///
+/// Items are reported in depth-first pre-order; see scan_folder_init_with_options.
+///
 /// # Arguments
 /// * `folder_path` - Path to the folder to scan
 /// * `max_depth` - Maximum scan depth (0 for unlimited)
@@ -238,115 +648,402 @@ pub extern "C" fn scan_folder_init(
     folder_path: *const std::os::raw::c_char,
     max_depth: u32,
 ) -> *mut FolderScanContext {
-    if folder_path.is_null() {
-        return std::ptr::null_mut();
+    let options = CScanOptions { max_depth, ..Default::default() };
+    scan_folder_init_with_options(folder_path, &options)
+}
+
+/// Options of scan_folder_init_with_options
+///
+/// Zero-initialize, set `struct_size` to the size of the struct and fill in
+/// what differs from the defaults of scan_folder_init. Fields are only ever
+/// appended; those past a caller's `struct_size` keep their defaults, so
+/// callers built against an older layout keep working.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CScanOptions {
+    /// Size of the caller's struct in bytes
+    pub struct_size: u32,
+    /// Maximum scan depth (0 for unlimited; does not apply inside archives)
+    pub max_depth: u32,
+    /// SCAN_TRAVERSAL_DFS_PRE_ORDER or SCAN_TRAVERSAL_BFS
+    pub traversal: i32,
+    /// SORT_LOCALE_BYTE, SORT_LOCALE_CASE_INSENSITIVE or SORT_LOCALE_NATURAL
+    pub sort_locale: i32,
+    /// 1 to count multiply-linked files once, 0 to count every path
+    pub dedupe_hardlinks: u8,
+    /// 1 to fail on contents below max_depth, 0 to leave them out
+    pub fail_beyond_max_depth: u8,
+    /// 1 to list the entries of ZIP archives, 0 to report archives as plain files
+    pub expand_archives: u8,
+    /// 1 to allow spilling, 0 to keep all items in memory
+    pub spill_to_disk: u8,
+    /// Items kept in memory before spilling (0 for the default of 100000)
+    pub spill_threshold: u64,
+    /// Directory for the spill file (null for the system temp directory)
+    pub spill_dir: *const std::os::raw::c_char,
+    /// Separator of virtual paths (null for "!/"); must not be empty
+    pub archive_separator: *const std::os::raw::c_char,
+}
+
+impl Default for CScanOptions {
+    fn default() -> Self {
+        CScanOptions {
+            struct_size: std::mem::size_of::<CScanOptions>() as u32,
+            max_depth: 0,
+            traversal: SCAN_TRAVERSAL_DFS_PRE_ORDER,
+            sort_locale: crate::name_order::SORT_LOCALE_BYTE,
+            dedupe_hardlinks: 0,
+            fail_beyond_max_depth: 0,
+            expand_archives: 0,
+            spill_to_disk: 0,
+            spill_threshold: 0,
+            spill_dir: std::ptr::null(),
+            archive_separator: std::ptr::null(),
+        }
     }
-    
-    // Convert C string to Rust string
-    let path_str = unsafe {
-        std::ffi::CStr::from_ptr(folder_path)
-            .to_str()
-            .map(|s| s.to_string())
+}
+
+impl CScanOptions {
+    /// Copy the caller's options, defaulting fields past its `struct_size`
+    ///
+    /// Null options are the defaults; None if `struct_size` is too small to
+    /// hold itself.
+    ///
+    /// # Safety
+    /// `options` must be null or point to at least `struct_size` readable bytes.
+    unsafe fn read(options: *const CScanOptions) -> Option<CScanOptions> {
+        let mut read = CScanOptions::default();
+        if options.is_null() {
+            return Some(read);
+        }
+        let size = std::ptr::read_unaligned(options as *const u32) as usize;
+        if size < std::mem::size_of::<u32>() {
+            return None;
+        }
+        std::ptr::copy_nonoverlapping(options as *const u8, &mut read as *mut CScanOptions as *mut u8,
+                                      size.min(std::mem::size_of::<CScanOptions>()));
+        Some(read)
+    }
+}
+
+/// Initialize a folder scan operation with options
+///
+/// The `items` array (and spilled items) follow the traversal order:
+/// - SCAN_TRAVERSAL_DFS_PRE_ORDER (0): each folder is immediately followed by
+///   its contents, recursively, before its next sibling
+/// - SCAN_TRAVERSAL_BFS (1): all top-level items, then all items one level
+///   deeper, and so on; items of one level are grouped by parent folder, in the
+///   order the parents were reported
+///
+/// Within a folder, subfolders come first, then files, each ordered by
+/// `sort_locale`.
+///
+/// With `spill_to_disk`, once the scan finds more than `spill_threshold` items
+/// they are moved to a temp file and the rest of the scan streams there, while
+/// the counters stay in memory. Spilled results are read with
+/// scan_folder_read_items; the spill file is deleted by scan_folder_free.
+///
+/// With `dedupe_hardlinks`, a file with several hard links inside the folder
+/// counts toward `total_size` once: every path is still listed, and all but
+/// the first carry `is_hardlink_duplicate: true`. Every item reports its
+/// filesystem identity (`dev`, `inode`; 0 on Windows).
+///
+/// With `fail_beyond_max_depth`, a folder at max_depth that is not empty fails
+/// the scan: scan_folder_get_error returns "Maximum folder depth exceeded: "
+/// followed by the folder's relative path, and scan_folder_get_json an error
+/// envelope with code ERROR_MAX_DEPTH_EXCEEDED and the path as context.
+///
+/// With `expand_archives`, every file starting with a ZIP signature is
+/// followed by virtual items (`is_virtual: true`) for its entries, read from
+/// the central directory without extracting anything: `relative_path` and
+/// `absolute_path` are the archive's, then the separator, then the entry path
+/// (e.g. "docs/archive.zip!/inner/dir/file.txt"), and `size` is the
+/// uncompressed size. Archives inside archives are listed as plain virtual
+/// files. Virtual items count toward none of the totals. An archive that
+/// cannot be read is listed in the result's `errors` (error_message,
+/// item_path) and the scan goes on.
+///
+/// # Arguments
+/// * `folder_path` - Path to the folder to scan
+/// * `options` - Scan options (see CScanOptions; can be null for the defaults)
+///
+/// # Returns
+/// Pointer to FolderScanContext, or null on error (including an unknown traversal
+/// or sort locale, an empty separator or a `struct_size` below 4)
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn scan_folder_init_with_options(
+    folder_path: *const std::os::raw::c_char,
+    options: *const CScanOptions,
+) -> *mut FolderScanContext {
+    let Some(CScanOptions {
+        max_depth,
+        traversal,
+        sort_locale,
+        dedupe_hardlinks,
+        fail_beyond_max_depth,
+        expand_archives,
+        spill_to_disk,
+        spill_threshold,
+        spill_dir,
+        archive_separator,
+        ..
+    }) = (unsafe { CScanOptions::read(options) }) else {
+        return std::ptr::null_mut();
     };
-    
-    let path_str = match path_str {
+
+    let path_str = match unsafe { crate::ffi_util::ffi_str_in(folder_path, "folder_path") } {
         Ok(s) => s,
         Err(_) => return std::ptr::null_mut(),
     };
-    
+
+    let (traversal, sort_locale) = match (ScanTraversal::from_code(traversal), SortLocale::from_code(sort_locale)) {
+        (Some(traversal), Some(sort_locale)) => (traversal, sort_locale),
+        _ => return std::ptr::null_mut(),
+    };
+    let archive_separator = match unsafe { crate::ffi_util::ffi_opt_str_in(archive_separator, "archive_separator") } {
+        Ok(Some("")) | Err(_) => return std::ptr::null_mut(),
+        Ok(separator) => separator.map(str::to_string),
+    };
+
     // Perform the scan
+    let options = ScanOptions {
+        traversal,
+        dedupe_hardlinks: dedupe_hardlinks != 0,
+        fail_beyond_max_depth: fail_beyond_max_depth != 0,
+        sort_locale,
+        expand_archives: expand_archives != 0,
+        archive_separator,
+    };
     let max_depth = if max_depth == 0 { None } else { Some(max_depth as u64) };
-    let result = scan_folder_sync(&path_str, max_depth);
-    
+    let result = if spill_to_disk == 0 {
+        scan_folder_sync_with(path_str, max_depth, &options).map(|scan_result| (scan_result, None))
+    } else {
+        let spill_dir = match unsafe { crate::ffi_util::ffi_opt_str_in(spill_dir, "spill_dir") } {
+            Ok(Some(dir)) => std::path::PathBuf::from(dir),
+            Ok(None) => std::env::temp_dir(),
+            Err(_) => return std::ptr::null_mut(),
+        };
+
+        let threshold = if spill_threshold == 0 {
+            DEFAULT_SCAN_SPILL_THRESHOLD
+        } else {
+            spill_threshold as usize
+        };
+
+        scan_folder_spilling(path_str, max_depth, &options, threshold, &spill_dir)
+    };
+
     // Create context
     let mut context = Box::new(FolderScanContext::new());
-    
+
     match result {
-        Ok(scan_result) => context.set_result(scan_result),
+        Ok((scan_result, spill)) => {
+            context.set_result(scan_result);
+            if let Some(spill) = spill {
+                context.set_spill(spill);
+            }
+        }
         Err(error) => context.set_error(error),
     }
-    
+
     Box::leak(context) as *mut FolderScanContext
 }
 
 /// Get the JSON representation of scan results
 ///
+/// Returns a JSON envelope (see ffi_util.rs) whose `data` is the FolderScanResult.
+/// Scans that spilled to disk fail with ERROR_RESULT_UNAVAILABLE; use
+/// scan_folder_read_items to page through those instead. A failed scan fails
+/// with ERROR_FILE_NOT_FOUND and the scan error as message.
+///
 /// # Arguments
 /// * `context` - Pointer to FolderScanContext
-/// * `output_len` - Pointer to store output length
+/// * `output_len` - Pointer to store output length (can be null)
 ///
 /// # Returns
-/// Pointer to JSON string (caller must free), or null on error
+/// Pointer to JSON envelope (caller must free with scan_folder_free_string), or
+/// null if it cannot be allocated
 #[no_mangle]
 pub extern "C" fn scan_folder_get_json(
     context: *mut FolderScanContext,
     output_len: *mut usize,
 ) -> *mut std::os::raw::c_char {
-    if context.is_null() || output_len.is_null() {
-        return std::ptr::null_mut();
-    }
-    
-    let ctx = unsafe { &*context };
-    
-    let result = match ctx.get_result() {
-        Some(r) => r,
-        None => return std::ptr::null_mut(),
-    };
-    
-    // Serialize to JSON
-    let json_str = serde_json::to_string(result).unwrap_or_else(|_| "{}".to_string());
-    
-    // Allocate C string
-    let c_str = std::ffi::CString::new(json_str)
-        .unwrap_or_else(|_| std::ffi::CString::new("[]").unwrap());
-    
-    unsafe {
-        *output_len = c_str.as_bytes_with_nul().len();
-    }
-    
-    // Leak the CString and return raw pointer
-    c_str.into_raw()
+    crate::ffi_util::json_envelope(scan_result_for_json(context), output_len)
 }
 
-/// Get the error message if scan failed
+/// Get the JSON representation of scan results as a byte buffer
+///
+/// Writes the same envelope as scan_folder_get_json, but serializes it straight
+/// into one malloc'd buffer instead of building a String and copying it into a
+/// C string, so a large result is held in memory once. The buffer is not
+/// NUL-terminated.
 ///
 /// # Arguments
 /// * `context` - Pointer to FolderScanContext
-/// * `output_len` - Pointer to store output length
+/// * `out_ptr` - Receives the JSON buffer (caller must free with free_buffer), or null on error
+/// * `out_len` - Receives the JSON length in bytes
 ///
 /// # Returns
-/// Pointer to error string (caller must free), or null if no error
+/// 0 on success, or the error code scan_folder_get_json would report in its
+/// envelope (e.g. ERROR_RESULT_UNAVAILABLE for a spilled scan)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
-pub extern "C" fn scan_folder_get_error(
+pub extern "C" fn scan_folder_get_json_v2(
     context: *mut FolderScanContext,
-    output_len: *mut usize,
-) -> *mut std::os::raw::c_char {
-    if context.is_null() || output_len.is_null() {
-        return std::ptr::null_mut();
+    out_ptr: *mut *mut u8,
+    out_len: *mut usize,
+) -> i32 {
+    use crate::ffi_util::JsonEnvelope;
+    use crate::file_io::{ERROR_BUFFER_ALLOC_FAILED, ERROR_NULL_POINTER, SUCCESS};
+
+    if out_ptr.is_null() || out_len.is_null() {
+        return ERROR_NULL_POINTER;
     }
-    
-    let ctx = unsafe { &*context };
-    
-    let error = match ctx.get_error() {
-        Some(e) => e,
-        None => return std::ptr::null_mut(),
+    unsafe {
+        *out_ptr = std::ptr::null_mut();
+        *out_len = 0;
+    }
+
+    let result = match scan_result_for_json(context) {
+        Ok(result) => result,
+        Err(error) => return checked(error.code),
     };
-    
-    // Allocate C string
-    let c_str = std::ffi::CString::new(error)
-        .unwrap_or_else(|_| std::ffi::CString::new("Unknown error").unwrap());
-    
+
+    // Roughly the serialized size of an item, so most scans never regrow the buffer
+    let estimate = 256 + result.items.len() * 192;
+    let mut writer = match MallocWriter::with_capacity(estimate) {
+        Some(writer) => writer,
+        None => return ERROR_BUFFER_ALLOC_FAILED,
+    };
+    let envelope = JsonEnvelope { ok: true, error: None, data: Some(result), truncated: false };
+    if serde_json::to_writer(&mut writer, &envelope).is_err() {
+        return ERROR_BUFFER_ALLOC_FAILED;
+    }
+
+    let (ptr, len) = writer.into_raw();
     unsafe {
-        *output_len = c_str.as_bytes_with_nul().len();
+        *out_ptr = ptr;
+        *out_len = len;
     }
-    
-    // Leak the CString and return raw pointer
-    c_str.into_raw()
+    SUCCESS
 }
 
-/// Check if scan was successful
+/// Growable malloc'd buffer that serde_json writes into directly
 ///
-/// # Arguments
+/// Owns the allocation until into_raw, so a failed serialization frees it.
+struct MallocWriter {
+    ptr: *mut u8,
+    capacity: usize,
+    len: usize,
+}
+
+impl MallocWriter {
+    fn with_capacity(capacity: usize) -> Option<Self> {
+        let capacity = capacity.max(1);
+        let ptr = unsafe { libc::malloc(capacity) as *mut u8 };
+        if ptr.is_null() {
+            return None;
+        }
+        Some(MallocWriter { ptr, capacity, len: 0 })
+    }
+
+    fn into_raw(self) -> (*mut u8, usize) {
+        let raw = (self.ptr, self.len);
+        std::mem::forget(self);
+        raw
+    }
+}
+
+impl std::io::Write for MallocWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let needed = self.len + buf.len();
+        if needed > self.capacity {
+            let capacity = needed.max(self.capacity * 2);
+            let grown = unsafe { libc::realloc(self.ptr as *mut libc::c_void, capacity) as *mut u8 };
+            if grown.is_null() {
+                return Err(std::io::ErrorKind::OutOfMemory.into());
+            }
+            self.ptr = grown;
+            self.capacity = capacity;
+        }
+        unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr.add(self.len), buf.len()); }
+        self.len = needed;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MallocWriter {
+    fn drop(&mut self) {
+        unsafe { libc::free(self.ptr as *mut libc::c_void); }
+    }
+}
+
+fn scan_result_for_json<'a>(context: *mut FolderScanContext) -> Result<&'a FolderScanResult, crate::ffi_util::ErrorEnvelope> {
+    use crate::ffi_util::{ErrorEnvelope, ERROR_RESULT_UNAVAILABLE};
+
+    if context.is_null() {
+        return Err(ErrorEnvelope::null_argument("context"));
+    }
+
+    let ctx = unsafe { &*context };
+
+    if ctx.is_spilled() {
+        return Err(ErrorEnvelope::new(ERROR_RESULT_UNAVAILABLE, SPILLED_RESULT_MESSAGE).with_context("spilled"));
+    }
+
+    ctx.get_result().ok_or_else(|| {
+        let error = ctx.get_error().unwrap_or("Scan failed");
+        match max_depth_error_path(error) {
+            Some(path) => ErrorEnvelope::new(crate::file_io::ERROR_MAX_DEPTH_EXCEEDED, error).with_context(path),
+            None => ErrorEnvelope::new(ERROR_FILE_NOT_FOUND, error),
+        }
+    })
+}
+
+/// Get the error message if scan failed
+///
+/// # Arguments
+/// * `context` - Pointer to FolderScanContext
+/// * `output_len` - Pointer to store output length
+///
+/// # Returns
+/// Pointer to error string (caller must free), or null if no error
+#[no_mangle]
+pub extern "C" fn scan_folder_get_error(
+    context: *mut FolderScanContext,
+    output_len: *mut usize,
+) -> *mut std::os::raw::c_char {
+    if context.is_null() || output_len.is_null() {
+        return std::ptr::null_mut();
+    }
+    
+    let ctx = unsafe { &*context };
+    
+    let error = match ctx.get_error() {
+        Some(e) => e,
+        None if ctx.is_spilled() => SPILLED_RESULT_MESSAGE,
+        None => return std::ptr::null_mut(),
+    };
+    
+    // Allocate C string
+    let c_str = crate::ffi_util::ffi_cstring(error);
+    
+    unsafe {
+        *output_len = c_str.as_bytes_with_nul().len();
+    }
+    
+    // Leak the CString and return raw pointer
+    c_str.into_raw()
+}
+
+/// Check if scan was successful
+///
+/// # Arguments
 /// * `context` - Pointer to FolderScanContext
 ///
 /// # Returns
@@ -446,6 +1143,94 @@ pub extern "C" fn scan_folder_get_duration_ms(context: *mut FolderScanContext) -
         .unwrap_or(0)
 }
 
+/// Get the total number of scanned items
+///
+/// # Arguments
+/// * `context` - Pointer to FolderScanContext
+///
+/// # Returns
+/// Number of items (files and folders), in memory or spilled
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn scan_folder_get_item_count(context: *mut FolderScanContext) -> u64 {
+    if context.is_null() {
+        return 0;
+    }
+
+    let ctx = unsafe { &*context };
+
+    ctx.item_count() as u64
+}
+
+/// Check whether the scan results were spilled to disk
+///
+/// # Arguments
+/// * `context` - Pointer to FolderScanContext
+///
+/// # Returns
+/// 1 if spilled, 0 otherwise
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn scan_folder_is_spilled(context: *mut FolderScanContext) -> i32 {
+    if context.is_null() {
+        return 0;
+    }
+
+    let ctx = unsafe { &*context };
+
+    if ctx.is_spilled() { 1 } else { 0 }
+}
+
+/// Read a page of scan items as a JSON array
+///
+/// Works for in-memory and spilled results alike.
+///
+/// # Arguments
+/// * `context` - Pointer to FolderScanContext
+/// * `start_index` - Index of the first item to return
+/// * `count` - Maximum number of items to return
+/// * `out_len` - Pointer to store output length
+///
+/// # Returns
+/// Pointer to JSON array string (caller must free with scan_folder_free_string),
+/// empty array past the end, or null on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn scan_folder_read_items(
+    context: *mut FolderScanContext,
+    start_index: u64,
+    count: u64,
+    out_len: *mut usize,
+) -> *mut std::os::raw::c_char {
+    if context.is_null() || out_len.is_null() {
+        return std::ptr::null_mut();
+    }
+
+    let ctx = unsafe { &*context };
+    let start = start_index as usize;
+    let count = count as usize;
+
+    let json_str = match (&ctx.spill, ctx.get_result()) {
+        (Some(spill), _) => match spill.read_json_lines(start, count) {
+            Ok(lines) => format!("[{}]", lines.join(",")),
+            Err(_) => return std::ptr::null_mut(),
+        },
+        (None, Some(result)) => {
+            let page = result.items.iter().skip(start).take(count).collect::<Vec<_>>();
+            serde_json::to_string(&page).unwrap_or_else(|_| "[]".to_string())
+        }
+        (None, None) => return std::ptr::null_mut(),
+    };
+
+    let c_str = crate::ffi_util::ffi_cstring(&json_str);
+
+    unsafe {
+        *out_len = c_str.as_bytes_with_nul().len();
+    }
+
+    c_str.into_raw()
+}
+
 /// Free a string allocated by scan_folder_get_json or scan_folder_get_error
 #[no_mangle]
 pub extern "C" fn scan_folder_free_string(s: *mut std::os::raw::c_char) {
@@ -456,7 +1241,7 @@ pub extern "C" fn scan_folder_free_string(s: *mut std::os::raw::c_char) {
     }
 }
 
-/// Free the folder scan context
+/// Free the folder scan context (deletes the spill file, if any)
 ///
 /// # Arguments
 /// * `context` - Pointer to FolderScanContext to free
@@ -482,7 +1267,9 @@ pub extern "C" fn scan_folder_free(context: *mut FolderScanContext) {
 /// * `output_len` - Pointer to store output length
 ///
 /// # Returns
-/// Pointer to JSON string (caller must free with scan_folder_free_string), or null on error
+/// Pointer to JSON envelope as from scan_folder_get_json (caller must free with
+/// scan_folder_free_string), or null if it cannot be allocated
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn scan_folder_quick(
     folder_path: *const std::os::raw::c_char,
@@ -493,7 +1280,11 @@ pub extern "C" fn scan_folder_quick(
     let context = scan_folder_init(folder_path, max_depth);
     
     if context.is_null() {
-        return std::ptr::null_mut();
+        let error = match unsafe { crate::ffi_util::envelope_str(folder_path, "folder_path") } {
+            Err(error) => error,
+            Ok(_) => crate::ffi_util::ErrorEnvelope::new(crate::file_io::ERROR_IO_FAILED, "Scan failed"),
+        };
+        return crate::ffi_util::json_envelope::<()>(Err(error), output_len);
     }
     
     // Get JSON result
@@ -503,4 +1294,1537 @@ pub extern "C" fn scan_folder_quick(
     scan_folder_free(context);
     
     json_ptr
-}
\ No newline at end of file
+}
+
+/// Number of documents handed to the search index per batch in scan_folder_into_index
+const SCAN_INDEX_BATCH_SIZE: usize = 1000;
+
+/// Read an optional C string argument, treating null as empty
+fn optional_c_str(value: *const std::os::raw::c_char, name: &'static str) -> Result<String, i32> {
+    unsafe { crate::ffi_util::ffi_opt_str_in(value, name) }
+        .map(|s| s.unwrap_or_default().to_string())
+        .map_err(|e| e.code())
+}
+
+/// Convert scan items into search documents
+///
+/// node_id is the item's relative path (or its absolute path with
+/// `absolute_ids`) and parent_id the same kind of path of its parent folder
+/// (None for top-level items), so build_path reconstructs the tree. Virtual
+/// items (see ScanOptions::expand_archives) must follow their archive, as a
+/// scan reports them; an entry at the top of an archive has the archive as parent.
+fn scan_items_to_documents<'a>(
+    items: Vec<FolderScanItem>,
+    account_id: &'a str,
+    provider: &'a str,
+    email: &'a str,
+    absolute_ids: bool,
+) -> impl Iterator<Item = SearchDocument> + 'a {
+    // The archive whose entries may follow, then its virtual folders enclosing the last entry
+    let mut archive_parents: Vec<String> = Vec::new();
+    items.into_iter().map(move |item| {
+        let relative_path = item.relative_path.trim_matches('/');
+        let node_id = match absolute_ids {
+            false => relative_path.to_string(),
+            true => item.absolute_path.clone(),
+        };
+        let parent_id = if item.is_virtual && !archive_parents.is_empty() {
+            // The separator is unknown here, so parents are matched by prefix
+            while archive_parents.len() > 1
+                && !node_id.strip_prefix(archive_parents[archive_parents.len() - 1].as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+            {
+                archive_parents.pop();
+            }
+            archive_parents.last().cloned()
+        } else {
+            relative_path.rfind('/').map(|pos| match absolute_ids {
+                false => relative_path[..pos].to_string(),
+                true => Path::new(&item.absolute_path)
+                    .parent()
+                    .map(|parent| parent.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            })
+        };
+        if !item.is_virtual {
+            archive_parents.clear();
+        }
+        // Real files may be archives whose entries follow; virtual folders enclose later entries
+        if item.is_virtual == item.is_folder {
+            archive_parents.push(node_id.clone());
+        }
+        SearchDocument {
+            node_id,
+            account_id: account_id.to_string(),
+            provider: provider.to_string(),
+            email: email.to_string(),
+            name: item.name,
+            is_folder: item.is_folder,
+            parent_id,
+        }
+    })
+}
+
+/// Scan a folder and add every item directly to a search index
+///
+/// Documents are added in batches; cancellation is checked between batches, and
+/// documents added before cancellation remain in the index.
+///
+/// # Arguments
+/// * `folder_path` - Path to the folder to scan
+/// * `max_depth` - Maximum scan depth (0 for unlimited)
+/// * `index_ptr` - Pointer to the SearchIndex to populate
+/// * `account_id` - Account ID stored on every document (can be null)
+/// * `provider` - Provider stored on every document (can be null)
+/// * `email` - Email stored on every document (can be null)
+/// * `cancel_flag` - Pointer to atomic bool for cancellation (can be null)
+///
+/// # Returns
+/// Number of documents added, or negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn scan_folder_into_index(
+    folder_path: *const std::os::raw::c_char,
+    max_depth: u32,
+    index_ptr: *mut SearchIndex,
+    account_id: *const std::os::raw::c_char,
+    provider: *const std::os::raw::c_char,
+    email: *const std::os::raw::c_char,
+    cancel_flag: *const AtomicBool,
+) -> i64 {
+    if folder_path.is_null() || index_ptr.is_null() {
+        return ERROR_NULL_POINTER as i64;
+    }
+
+    let (path_str, account_id, provider, email) = match (
+        optional_c_str(folder_path, "folder_path"),
+        optional_c_str(account_id, "account_id"),
+        optional_c_str(provider, "provider"),
+        optional_c_str(email, "email"),
+    ) {
+        (Ok(p), Ok(a), Ok(pr), Ok(e)) => (p, a, pr, e),
+        _ => return ERROR_INVALID_PATH as i64,
+    };
+
+    let max_depth = if max_depth == 0 { None } else { Some(max_depth as u64) };
+    // The walk itself is cancellable too, checked once per item
+    let mut items = Vec::new();
+    let scanned = scan_folder_each(&path_str, max_depth, ScanTraversal::default(), |item| {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err(SCAN_CANCELLED_MESSAGE.to_string());
+        }
+        items.push(item);
+        Ok(())
+    });
+    match scanned {
+        Ok(_) => {}
+        Err(e) if e == SCAN_CANCELLED_MESSAGE => return ERROR_CANCELLED as i64,
+        Err(_) => return ERROR_FILE_NOT_FOUND as i64,
+    }
+
+    let index = unsafe { &mut *index_ptr };
+    index.apply_pending_trim();
+    let mut documents = scan_items_to_documents(items, &account_id, &provider, &email, false).peekable();
+    let mut added: i64 = 0;
+
+    while documents.peek().is_some() {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return ERROR_CANCELLED as i64;
+        }
+        added += index.add_documents(documents.by_ref().take(SCAN_INDEX_BATCH_SIZE)) as i64;
+    }
+
+    added
+}
+
+/// Parse the items of a scan result, as a scan_folder_get_json envelope or a
+/// bare FolderScanResult
+///
+/// A malformed item fails with the ERROR_INVALID_JSON envelope naming its
+/// position (`items[N]`) as context.
+fn parse_scan_items(json: &str) -> Result<Vec<FolderScanItem>, crate::ffi_util::ErrorEnvelope> {
+    use crate::ffi_util::{ErrorEnvelope, ERROR_INVALID_JSON};
+
+    let mut value: serde_json::Value =
+        serde_json::from_str(json).map_err(|e| ErrorEnvelope::invalid_json("scan_json", e))?;
+    if value.get("ok").is_some() {
+        if let Some(error) = value.get("error").filter(|error| !error.is_null()) {
+            return Err(serde_json::from_value(error.clone())
+                .unwrap_or_else(|e| ErrorEnvelope::invalid_json("scan_json", e)));
+        }
+        value = value["data"].take();
+    }
+    let entries = match value.get_mut("items").map(serde_json::Value::take) {
+        Some(serde_json::Value::Array(entries)) => entries,
+        _ => {
+            return Err(ErrorEnvelope::new(ERROR_INVALID_JSON, "scan_json has no items array")
+                .with_context("scan_json"))
+        }
+    };
+
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(position, entry)| {
+            let item = serde_json::from_value::<FolderScanItem>(entry).map_err(|e| e.to_string());
+            match item {
+                Ok(item) if !item.relative_path.trim_matches('/').is_empty() => Ok(item),
+                Ok(_) => Err("relative_path must not be empty".to_string()),
+                Err(reason) => Err(reason),
+            }
+            .map_err(|reason| {
+                ErrorEnvelope::new(ERROR_INVALID_JSON, format!("items[{}] is not a scan item: {}", position, reason))
+                    .with_context(format!("items[{}]", position))
+            })
+        })
+        .collect()
+}
+
+/// Add every item of a folder scan JSON blob to a search index
+///
+/// Same as add_scan_result_to_index_ex with relative path node ids.
+#[no_mangle]
+pub extern "C" fn add_scan_result_to_index(
+    index_ptr: *mut SearchIndex,
+    scan_json: *const std::os::raw::c_char,
+    account_id: *const std::os::raw::c_char,
+    provider: *const std::os::raw::c_char,
+    email: *const std::os::raw::c_char,
+) -> i64 {
+    add_scan_result_to_index_ex(index_ptr, scan_json, account_id, provider, email, 0)
+}
+
+/// Add every item of a folder scan JSON blob to a search index
+///
+/// Documents are mapped as by scan_folder_into_index and upserted, so importing
+/// the same scan twice replaces its documents instead of duplicating them.
+/// Every item is validated before any is added.
+///
+/// # Arguments
+/// * `index_ptr` - Pointer to the SearchIndex to populate
+/// * `scan_json` - A scan_folder_get_json envelope, or the FolderScanResult in its data
+/// * `account_id` - Account ID stored on every document (can be null)
+/// * `provider` - Provider stored on every document (can be null)
+/// * `email` - Email stored on every document (can be null)
+/// * `absolute_ids` - Non-zero to use absolute paths as node and parent ids
+///   instead of paths relative to the scan root
+///
+/// # Returns
+/// Number of documents added, or negative error code on failure:
+/// ERROR_INVALID_JSON for malformed JSON or items, in which case
+/// get_last_error_json reports the offending item as `items[N]`; the error
+/// code of a failed scan envelope, likewise recorded as the last error.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn add_scan_result_to_index_ex(
+    index_ptr: *mut SearchIndex,
+    scan_json: *const std::os::raw::c_char,
+    account_id: *const std::os::raw::c_char,
+    provider: *const std::os::raw::c_char,
+    email: *const std::os::raw::c_char,
+    absolute_ids: i32,
+) -> i64 {
+    use crate::ffi_util::{ffi_str_in, set_last_error_detail};
+
+    if index_ptr.is_null() {
+        return ERROR_NULL_POINTER as i64;
+    }
+    let (json, account_id, provider, email) = match (
+        unsafe { ffi_str_in(scan_json, "scan_json") }.map_err(|e| e.code()),
+        optional_c_str(account_id, "account_id"),
+        optional_c_str(provider, "provider"),
+        optional_c_str(email, "email"),
+    ) {
+        (Ok(j), Ok(a), Ok(pr), Ok(e)) => (j, a, pr, e),
+        (Err(code), ..) | (_, Err(code), ..) | (_, _, Err(code), _) | (.., Err(code)) => return code as i64,
+    };
+
+    let items = match parse_scan_items(json) {
+        Ok(items) => items,
+        Err(error) => return set_last_error_detail(error) as i64,
+    };
+
+    let index = unsafe { &mut *index_ptr };
+    index.apply_pending_trim();
+    index.reserve(items.len());
+    let mut added: i64 = 0;
+    for doc in scan_items_to_documents(items, &account_id, &provider, &email, absolute_ids != 0) {
+        index.upsert_document(doc);
+        added += 1;
+    }
+    added
+}
+
+// ============================================================================
+// FILE PICKER QUERY
+// ============================================================================
+
+/// Sort key for scan_folder_query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanSortKey {
+    #[default]
+    Name,
+    Size,
+    Mtime,
+}
+
+/// Sort direction for scan_folder_query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanSortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Options accepted by scan_folder_query (every field is optional)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ScanQueryOptions {
+    /// Maximum scan depth (0 or absent for unlimited)
+    pub max_depth: u64,
+
+    /// Globs a file must match (any of them); empty accepts every file
+    pub include: Vec<String>,
+
+    /// Globs excluding an item; excluding a folder also excludes its contents
+    pub exclude: Vec<String>,
+
+    /// File extensions to keep, with or without the leading dot; empty keeps all
+    pub extensions: Vec<String>,
+
+    /// Whether folders appear in the results
+    pub include_folders: bool,
+
+    /// List folders before files regardless of the sort key
+    pub folders_first: bool,
+
+    /// Sort key
+    pub sort: ScanSortKey,
+
+    /// Sort direction
+    pub order: ScanSortOrder,
+
+    /// Number of matches to skip
+    pub offset: usize,
+
+    /// Maximum number of matches returned (absent for all)
+    pub limit: Option<usize>,
+
+    /// Count a multiply-linked file toward matched_size once (see ScanOptions)
+    pub dedupe_hardlinks: bool,
+
+    /// Name order for the name sort key (a SORT_LOCALE_* code)
+    pub sort_locale: SortLocale,
+}
+
+impl Default for ScanQueryOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: 0,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            extensions: Vec::new(),
+            include_folders: true,
+            folders_first: false,
+            sort: ScanSortKey::Name,
+            order: ScanSortOrder::Asc,
+            offset: 0,
+            limit: None,
+            dedupe_hardlinks: false,
+            sort_locale: SortLocale::CaseInsensitive,
+        }
+    }
+}
+
+/// Single item returned by scan_folder_query
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanQueryItem {
+    #[serde(flatten)]
+    pub item: FolderScanItem,
+
+    /// Last modification time in milliseconds since the Unix epoch (0 if unknown)
+    pub modified_ms: u64,
+}
+
+/// One page of scan_folder_query results plus totals for the whole match set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanQueryResult {
+    /// Items in the requested page
+    pub items: Vec<ScanQueryItem>,
+
+    /// Number of items matching the filters
+    pub total_matches: u64,
+
+    /// Number of matching files
+    pub matched_files: u64,
+
+    /// Number of matching folders
+    pub matched_folders: u64,
+
+    /// Combined size of matching files in bytes
+    pub matched_size: u64,
+
+    /// Number of items visited by the scan before filtering
+    pub scanned_items: u64,
+
+    /// Offset the page starts at
+    pub offset: u64,
+}
+
+/// Match `text` against a glob pattern (ASCII case-insensitive)
+///
+/// `*` matches any run of characters except `/`, `**` matches across folders
+/// and `?` matches a single character other than `/`.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let mut rest = &pattern[2..];
+            // "**/" also matches zero folders
+            if rest.first() == Some(&b'/') && glob_match(&rest[1..], text) {
+                return true;
+            }
+            while rest.first() == Some(&b'*') {
+                rest = &rest[1..];
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some(b'?') => !text.is_empty() && text[0] != b'/' && glob_match(&pattern[1..], &text[1..]),
+        Some(&c) => {
+            !text.is_empty() && text[0].eq_ignore_ascii_case(&c) && glob_match(&pattern[1..], &text[1..])
+        }
+    }
+}
+
+/// Match a scan item against a glob; patterns without `/` match the item name
+fn item_matches_glob(pattern: &str, relative_path: &str, name: &str) -> bool {
+    let target = if pattern.contains('/') { relative_path } else { name };
+    glob_match(pattern.as_bytes(), target.as_bytes())
+}
+
+/// Whether an item or any folder above it matches one of the exclude globs
+fn is_excluded(exclude: &[String], relative_path: &str) -> bool {
+    if exclude.is_empty() {
+        return false;
+    }
+    let mut prefix_end = Some(relative_path.len());
+    while let Some(end) = prefix_end {
+        let path = &relative_path[..end];
+        let name = path.rsplit('/').next().unwrap_or(path);
+        if exclude.iter().any(|pattern| item_matches_glob(pattern, path, name)) {
+            return true;
+        }
+        prefix_end = path.rfind('/');
+    }
+    false
+}
+
+/// Whether an item passes the include, exclude and extension filters
+fn query_accepts(options: &ScanQueryOptions, item: &FolderScanItem) -> bool {
+    if is_excluded(&options.exclude, &item.relative_path) {
+        return false;
+    }
+    if item.is_folder {
+        return options.include_folders;
+    }
+    if !options.include.is_empty()
+        && !options.include.iter().any(|pattern| item_matches_glob(pattern, &item.relative_path, &item.name))
+    {
+        return false;
+    }
+    if !options.extensions.is_empty() {
+        let extension = match Path::new(&item.name).extension().and_then(|e| e.to_str()) {
+            Some(extension) => extension,
+            None => return false,
+        };
+        if !options
+            .extensions
+            .iter()
+            .any(|wanted| wanted.trim_start_matches('.').eq_ignore_ascii_case(extension))
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// Modification time of a path in milliseconds since the Unix epoch (0 if unknown)
+fn modified_ms(path: &str) -> u64 {
+    fs::metadata(path).map(|m| metadata_modified_ms(&m)).unwrap_or(0)
+}
+
+/// Modification time from already read metadata (0 if unknown)
+fn metadata_modified_ms(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Sort query items by `sort` and `order`, optionally listing folders first
+///
+/// Names compare by `sort_locale`. Equal keys are ordered by relative path, so
+/// the order is stable across calls.
+fn sort_query_items(items: &mut [ScanQueryItem], sort: ScanSortKey, order: ScanSortOrder, folders_first: bool,
+                    sort_locale: SortLocale) {
+    items.sort_by(|a, b| {
+        let folders = if folders_first { b.item.is_folder.cmp(&a.item.is_folder) } else { std::cmp::Ordering::Equal };
+        let key = match sort {
+            ScanSortKey::Name => sort_locale.compare_str(&a.item.name, &b.item.name),
+            ScanSortKey::Size => a.item.size.cmp(&b.item.size),
+            ScanSortKey::Mtime => a.modified_ms.cmp(&b.modified_ms),
+        };
+        let key = if order == ScanSortOrder::Desc { key.reverse() } else { key };
+        folders.then(key).then_with(|| a.item.relative_path.cmp(&b.item.relative_path))
+    });
+}
+
+/// Scan a folder, filter and sort the items, and return one page of results
+pub fn scan_folder_query_impl(root_path: &str, options: &ScanQueryOptions) -> Result<ScanQueryResult, String> {
+    let max_depth = if options.max_depth == 0 { None } else { Some(options.max_depth) };
+    let mut matches = Vec::new();
+    let scan_options = ScanOptions { dedupe_hardlinks: options.dedupe_hardlinks, ..Default::default() };
+    let scanned = scan_folder_each_with(root_path, max_depth, &scan_options, |item| {
+        if query_accepts(options, &item) {
+            let modified_ms = if options.sort == ScanSortKey::Mtime { modified_ms(&item.absolute_path) } else { 0 };
+            matches.push(ScanQueryItem { item, modified_ms });
+        }
+        Ok(())
+    })?;
+
+    sort_query_items(&mut matches, options.sort, options.order, options.folders_first, options.sort_locale);
+
+    let matched_folders = matches.iter().filter(|m| m.item.is_folder).count() as u64;
+    let matched_size = matches.iter().filter(|m| !m.item.is_hardlink_duplicate).map(|m| m.item.size).sum();
+    let total_matches = matches.len() as u64;
+    let limit = options.limit.unwrap_or(usize::MAX);
+    let mut items: Vec<ScanQueryItem> = matches.into_iter().skip(options.offset).take(limit).collect();
+
+    // Only the returned page needs a timestamp when it was not needed for sorting
+    if options.sort != ScanSortKey::Mtime {
+        for entry in &mut items {
+            entry.modified_ms = modified_ms(&entry.item.absolute_path);
+        }
+    }
+
+    Ok(ScanQueryResult {
+        items,
+        total_matches,
+        matched_files: total_matches - matched_folders,
+        matched_folders,
+        matched_size,
+        scanned_items: scanned.file_count + scanned.folder_count,
+        offset: options.offset as u64,
+    })
+}
+
+/// Scan, filter, sort and page a folder in one call for the file picker
+///
+/// `options_json` is an object with the optional fields `max_depth`, `include`,
+/// `exclude`, `extensions`, `include_folders` (default true), `folders_first`,
+/// `sort` ("name" | "size" | "mtime"), `order` ("asc" | "desc"), `offset`, `limit`,
+/// `dedupe_hardlinks` and `sort_locale` (a SORT_LOCALE_* code, default
+/// SORT_LOCALE_CASE_INSENSITIVE).
+///
+/// # Arguments
+/// * `folder_path` - Path to the folder to scan
+/// * `options_json` - Query options as JSON (null for defaults)
+/// * `out_len` - Pointer to store output length
+///
+/// # Returns
+/// Pointer to JSON ScanQueryResult (caller must free with scan_folder_free_string), or null on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn scan_folder_query(
+    folder_path: *const std::os::raw::c_char,
+    options_json: *const std::os::raw::c_char,
+    out_len: *mut usize,
+) -> *mut std::os::raw::c_char {
+    if folder_path.is_null() || out_len.is_null() {
+        return std::ptr::null_mut();
+    }
+
+    let (path_str, options_str) = match (optional_c_str(folder_path, "folder_path"), optional_c_str(options_json, "options_json")) {
+        (Ok(p), Ok(o)) => (p, o),
+        _ => return std::ptr::null_mut(),
+    };
+
+    let options: ScanQueryOptions = if options_str.trim().is_empty() {
+        ScanQueryOptions::default()
+    } else {
+        match serde_json::from_str(&options_str) {
+            Ok(options) => options,
+            Err(_) => return std::ptr::null_mut(),
+        }
+    };
+
+    let result = match scan_folder_query_impl(&path_str, &options) {
+        Ok(result) => result,
+        Err(_) => return std::ptr::null_mut(),
+    };
+
+    let json = match serde_json::to_string(&result) {
+        Ok(json) => json,
+        Err(_) => return std::ptr::null_mut(),
+    };
+
+    unsafe {
+        *out_len = json.len();
+    }
+
+    crate::ffi_util::ffi_string_out(&json)
+}
+
+// ============================================================================
+// DIRECTORY LISTING
+// ============================================================================
+
+/// Options accepted by list_directory (every field is optional)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ListDirectoryOptions {
+    /// Sort key
+    pub sort: ScanSortKey,
+
+    /// Sort direction
+    pub order: ScanSortOrder,
+
+    /// List folders before files regardless of the sort key
+    pub folders_first: bool,
+
+    /// Whether entries whose name starts with '.' are listed
+    pub include_hidden: bool,
+
+    /// Number of entries to skip
+    pub offset: usize,
+
+    /// Maximum number of entries returned (absent for all)
+    pub limit: Option<usize>,
+
+    /// Name order for the name sort key (a SORT_LOCALE_* code)
+    pub sort_locale: SortLocale,
+}
+
+impl Default for ListDirectoryOptions {
+    fn default() -> Self {
+        Self {
+            sort: ScanSortKey::Name,
+            order: ScanSortOrder::Asc,
+            folders_first: true,
+            include_hidden: false,
+            offset: 0,
+            limit: None,
+            sort_locale: SortLocale::CaseInsensitive,
+        }
+    }
+}
+
+/// One page of a directory's immediate children
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryListing {
+    /// Entries in the requested page
+    pub items: Vec<ScanQueryItem>,
+
+    /// Number of listed entries across all pages
+    pub total_count: u64,
+
+    /// Number of listed folders across all pages
+    pub folder_count: u64,
+
+    /// Number of listed files across all pages
+    pub file_count: u64,
+
+    /// Number of entries left out because they are hidden
+    pub hidden_count: u64,
+
+    /// Whether entries remain after this page
+    pub has_more: bool,
+
+    /// Offset the page starts at
+    pub offset: u64,
+
+    /// Entries that could not be read; they are left out of every count
+    pub errors: Vec<FolderScanError>,
+}
+
+/// List one level of a directory, sorted and paged
+///
+/// Each entry costs at most one metadata call. Unlike a scan, symlinks are
+/// followed, so a link to a folder is listed as a folder; a dangling link is
+/// reported in `errors`.
+pub fn list_directory_impl(folder_path: &str, options: &ListDirectoryOptions) -> Result<DirectoryListing, crate::ffi_util::ErrorEnvelope> {
+    use crate::ffi_util::ErrorEnvelope;
+
+    let root = Path::new(folder_path);
+    let entries = fs::read_dir(root).map_err(|e| {
+        ErrorEnvelope::new(crate::encrypt_copy::io_error_code(&e), format!("Failed to read directory: {}", e))
+            .with_context(folder_path)
+    })?;
+
+    let mut items = Vec::new();
+    let mut errors = Vec::new();
+    let mut hidden_count = 0u64;
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                errors.push(FolderScanError { error_message: e.to_string(), item_path: None });
+                continue;
+            }
+        };
+        if !options.include_hidden && entry.file_name().to_string_lossy().starts_with('.') {
+            hidden_count += 1;
+            continue;
+        }
+
+        let entry_path = entry.path();
+        let is_symlink = entry.file_type().map(|t| t.is_symlink()).unwrap_or(false);
+        let metadata = if is_symlink { fs::metadata(&entry_path) } else { entry.metadata() };
+        match metadata {
+            Ok(metadata) => items.push(ScanQueryItem {
+                item: entry_item(&entry_path, root, metadata.is_dir(), Some(&metadata)),
+                modified_ms: metadata_modified_ms(&metadata),
+            }),
+            Err(e) => errors.push(FolderScanError {
+                error_message: e.to_string(),
+                item_path: Some(entry_path.to_string_lossy().to_string()),
+            }),
+        }
+    }
+
+    sort_query_items(&mut items, options.sort, options.order, options.folders_first, options.sort_locale);
+
+    let total_count = items.len() as u64;
+    let folder_count = items.iter().filter(|i| i.item.is_folder).count() as u64;
+    let limit = options.limit.unwrap_or(usize::MAX);
+    let page: Vec<ScanQueryItem> = items.into_iter().skip(options.offset).take(limit).collect();
+    let has_more = (options.offset as u64).saturating_add(page.len() as u64) < total_count;
+
+    Ok(DirectoryListing {
+        items: page,
+        total_count,
+        folder_count,
+        file_count: total_count - folder_count,
+        hidden_count,
+        has_more,
+        offset: options.offset as u64,
+        errors,
+    })
+}
+
+/// List the immediate children of a directory for lazy folder browsing
+///
+/// `options_json` is an object with the optional fields `sort` ("name" |
+/// "size" | "mtime"), `order` ("asc" | "desc"), `folders_first` (default
+/// true), `include_hidden`, `offset`, `limit` and `sort_locale` (a
+/// SORT_LOCALE_* code, default SORT_LOCALE_CASE_INSENSITIVE). Children that cannot be
+/// read are reported in the listing's `errors` rather than failing the call.
+///
+/// # Arguments
+/// * `folder_path` - Path to the directory to list
+/// * `options_json` - Listing options as JSON (null for defaults)
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// Pointer to a JSON envelope of DirectoryListing (caller must free with
+/// scan_folder_free_string); ERROR_FILE_NOT_FOUND or ERROR_PERMISSION_DENIED
+/// if the directory itself cannot be read
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn list_directory(
+    folder_path: *const std::os::raw::c_char,
+    options_json: *const std::os::raw::c_char,
+    out_len: *mut usize,
+) -> *mut std::os::raw::c_char {
+    use crate::ffi_util::{envelope_str, json_envelope, ErrorEnvelope};
+
+    let result = (|| {
+        let path = unsafe { envelope_str(folder_path, "folder_path") }?;
+        let options: ListDirectoryOptions = if options_json.is_null() {
+            ListDirectoryOptions::default()
+        } else {
+            let json = unsafe { envelope_str(options_json, "options_json") }?;
+            if json.trim().is_empty() {
+                ListDirectoryOptions::default()
+            } else {
+                serde_json::from_str(json).map_err(|e| ErrorEnvelope::invalid_json("options_json", e))?
+            }
+        };
+        list_directory_impl(path, &options)
+    })();
+    json_envelope(result, out_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::{CStr, CString};
+
+    use crate::search::{build_path, free_c_string};
+
+    #[test]
+    fn test_scan_folder_into_index() {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_scan_index_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("projects/alpha/specs")).unwrap();
+        fs::write(root.join("projects/alpha/specs/design notes.txt"), b"x").unwrap();
+        fs::write(root.join("projects/readme.md"), b"y").unwrap();
+
+        let mut index = SearchIndex::new();
+        let root_c = CString::new(root.to_string_lossy().to_string()).unwrap();
+        let account = CString::new("local").unwrap();
+        let added = scan_folder_into_index(
+            root_c.as_ptr(), 0, &mut index, account.as_ptr(), std::ptr::null(), std::ptr::null(), std::ptr::null(),
+        );
+        assert_eq!(added, 5);
+        assert_eq!(index.len(), 5);
+
+        let results = index.search_exact("design", 10);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].node_id, "projects/alpha/specs/design notes.txt");
+        assert_eq!(results[0].account_id, "local");
+
+        let node = CString::new("projects/alpha/specs/design notes.txt").unwrap();
+        let sep = CString::new("/").unwrap();
+        let path_ptr = build_path(&mut index, node.as_ptr(), sep.as_ptr());
+        assert_eq!(unsafe { CStr::from_ptr(path_ptr) }.to_str().unwrap(), "projects/alpha/specs/design notes.txt");
+        free_c_string(path_ptr);
+
+        assert!(index.get("projects").unwrap().is_folder);
+        assert_eq!(index.get("projects/readme.md").unwrap().parent_id.as_deref(), Some("projects"));
+
+        // A cancelled scan adds nothing
+        let cancel = AtomicBool::new(true);
+        let mut other = SearchIndex::new();
+        let result = scan_folder_into_index(
+            root_c.as_ptr(), 0, &mut other, std::ptr::null(), std::ptr::null(), std::ptr::null(), &cancel,
+        );
+        assert_eq!(result, crate::file_io::ERROR_CANCELLED as i64);
+        assert!(other.is_empty());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    fn take_envelope(ptr: *mut std::os::raw::c_char, len: usize) -> serde_json::Value {
+        assert!(!ptr.is_null());
+        let json = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        scan_folder_free_string(ptr);
+        assert_eq!(json.len(), len);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_scan_json_envelopes() {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_scan_envelope_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a/file.txt"), b"abc").unwrap();
+
+        let mut len = 0usize;
+        let root_c = CString::new(root.to_string_lossy().to_string()).unwrap();
+        let envelope = take_envelope(scan_folder_quick(root_c.as_ptr(), 0, &mut len), len);
+        assert_eq!(envelope["ok"], true);
+        assert_eq!(envelope["data"]["file_count"], 1);
+        assert_eq!(envelope["data"]["total_size"], 3);
+
+        let missing = CString::new(root.join("missing").to_string_lossy().to_string()).unwrap();
+        let envelope = take_envelope(scan_folder_quick(missing.as_ptr(), 0, &mut len), len);
+        assert_eq!(envelope["ok"], false);
+        assert_eq!(envelope["error"]["code"], crate::file_io::ERROR_FILE_NOT_FOUND);
+        assert!(envelope["error"]["message"].as_str().unwrap().contains("does not exist"));
+        assert!(envelope["data"].is_null());
+
+        let envelope = take_envelope(scan_folder_quick(std::ptr::null(), 0, &mut len), len);
+        assert_eq!(envelope["error"]["code"], crate::file_io::ERROR_NULL_POINTER);
+        assert_eq!(envelope["error"]["context"], "folder_path");
+
+        let envelope = take_envelope(scan_folder_get_json(std::ptr::null_mut(), &mut len), len);
+        assert_eq!(envelope["error"]["code"], crate::file_io::ERROR_NULL_POINTER);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_add_scan_result_to_index() {
+        use crate::ffi_util::{get_last_error_json, ERROR_INVALID_JSON};
+
+        let root = std::env::temp_dir().join(format!("cloud_nexus_scan_import_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("photos/2024/trip")).unwrap();
+        fs::write(root.join("photos/2024/trip/beach sunset.jpg"), b"x").unwrap();
+        fs::write(root.join("photos/album.txt"), b"y").unwrap();
+
+        let mut len = 0usize;
+        let root_c = CString::new(root.to_string_lossy().to_string()).unwrap();
+        let scan_ptr = scan_folder_quick(root_c.as_ptr(), 0, &mut len);
+        let scan_json = unsafe { CStr::from_ptr(scan_ptr) }.to_owned();
+        scan_folder_free_string(scan_ptr);
+
+        let mut index = SearchIndex::new();
+        let account = CString::new("desktop").unwrap();
+        let added = add_scan_result_to_index(
+            &mut index, scan_json.as_ptr(), account.as_ptr(), std::ptr::null(), std::ptr::null(),
+        );
+        assert_eq!(added, 5);
+        let results = index.search_exact("sunset", 10);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].node_id, "photos/2024/trip/beach sunset.jpg");
+        assert_eq!(results[0].account_id, "desktop");
+        assert_eq!(index.get("photos/2024/trip/beach sunset.jpg").unwrap().parent_id.as_deref(), Some("photos/2024/trip"));
+        assert_eq!(index.get("photos").unwrap().parent_id, None);
+
+        let node = CString::new("photos/2024/trip/beach sunset.jpg").unwrap();
+        let sep = CString::new(" > ").unwrap();
+        let path_ptr = build_path(&mut index, node.as_ptr(), sep.as_ptr());
+        assert_eq!(unsafe { CStr::from_ptr(path_ptr) }.to_str().unwrap(), "photos > 2024 > trip > beach sunset.jpg");
+        free_c_string(path_ptr);
+
+        // Importing again replaces rather than duplicates
+        let added = add_scan_result_to_index(
+            &mut index, scan_json.as_ptr(), account.as_ptr(), std::ptr::null(), std::ptr::null(),
+        );
+        assert_eq!(added, 5);
+        assert_eq!(index.len(), 5);
+        assert_eq!(index.search_exact("sunset", 10).len(), 1);
+
+        // Absolute ids, from the bare FolderScanResult
+        let envelope: serde_json::Value = serde_json::from_str(scan_json.to_str().unwrap()).unwrap();
+        let bare = CString::new(envelope["data"].to_string()).unwrap();
+        let mut absolute = SearchIndex::new();
+        let added = add_scan_result_to_index_ex(
+            &mut absolute, bare.as_ptr(), std::ptr::null(), std::ptr::null(), std::ptr::null(), 1,
+        );
+        assert_eq!(added, 5);
+        let photo = root.join("photos/2024/trip/beach sunset.jpg");
+        let doc = absolute.get(&photo.to_string_lossy()).unwrap();
+        assert_eq!(doc.parent_id.as_deref(), Some(&*root.join("photos/2024/trip").to_string_lossy()));
+        assert_eq!(absolute.get(&root.join("photos").to_string_lossy()).unwrap().parent_id, None);
+
+        // A malformed item rejects the whole blob and names its position
+        let mut data = envelope["data"].clone();
+        data["items"][3]["is_folder"] = serde_json::json!("yes");
+        let malformed = CString::new(data.to_string()).unwrap();
+        let mut untouched = SearchIndex::new();
+        let result = add_scan_result_to_index(
+            &mut untouched, malformed.as_ptr(), std::ptr::null(), std::ptr::null(), std::ptr::null(),
+        );
+        assert_eq!(result, ERROR_INVALID_JSON as i64);
+        assert_eq!(untouched.len(), 0);
+        let error = take_envelope(get_last_error_json(&mut len), len);
+        assert_eq!(error["data"]["context"], "items[3]");
+
+        let failed_scan = CString::new(r#"{"ok":false,"error":{"code":-2,"message":"gone"},"data":null}"#).unwrap();
+        let result = add_scan_result_to_index(
+            &mut untouched, failed_scan.as_ptr(), std::ptr::null(), std::ptr::null(), std::ptr::null(),
+        );
+        assert_eq!(result, crate::file_io::ERROR_FILE_NOT_FOUND as i64);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    fn read_page(context: *mut FolderScanContext, start: u64, count: u64) -> Vec<FolderScanItem> {
+        let mut len = 0usize;
+        let ptr = scan_folder_read_items(context, start, count, &mut len);
+        assert!(!ptr.is_null());
+        let json = unsafe { CString::from_raw(ptr) }.into_string().unwrap();
+        assert_eq!(json.len() + 1, len);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_scan_folder_spill_to_disk() {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_scan_spill_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let tree = root.join("tree");
+        let spill_dir = root.join("spill");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::create_dir_all(&spill_dir).unwrap();
+        for i in 0..20 {
+            fs::write(tree.join(format!("file{:02}.txt", i)), b"abc").unwrap();
+            fs::write(tree.join("sub").join(format!("nested{:02}.txt", i)), b"z").unwrap();
+        }
+        let expected = scan_folder_sync(&tree.to_string_lossy(), None).unwrap();
+
+        let tree_c = CString::new(tree.to_string_lossy().to_string()).unwrap();
+        let spill_c = CString::new(spill_dir.to_string_lossy().to_string()).unwrap();
+        let context = scan_folder_init_with_options(tree_c.as_ptr(), &CScanOptions {
+            spill_to_disk: 1, spill_threshold: 5, spill_dir: spill_c.as_ptr(), ..Default::default()
+        });
+        assert!(!context.is_null());
+        assert_eq!(scan_folder_is_success(context), 1);
+        assert_eq!(scan_folder_is_spilled(context), 1);
+        assert_eq!(scan_folder_get_item_count(context), 41);
+        assert_eq!(scan_folder_get_file_count(context), 40);
+        assert_eq!(scan_folder_get_total_size(context), 80);
+        assert_eq!(fs::read_dir(&spill_dir).unwrap().count(), 1);
+
+        // The full JSON is not built for spilled scans; the error explains why
+        let mut len = 0usize;
+        let envelope = take_envelope(scan_folder_get_json(context, &mut len), len);
+        assert_eq!(envelope["ok"], false);
+        assert_eq!(envelope["error"]["code"], crate::ffi_util::ERROR_RESULT_UNAVAILABLE);
+        assert_eq!(envelope["error"]["context"], "spilled");
+        let error = scan_folder_get_error(context, &mut len);
+        assert!(!error.is_null());
+        assert!(unsafe { CStr::from_ptr(error) }.to_str().unwrap().contains("scan_folder_read_items"));
+        scan_folder_free_string(error);
+
+        // Paging returns every item exactly once, in scan order
+        let mut paged = Vec::new();
+        let mut start = 0;
+        loop {
+            let page = read_page(context, start, 7);
+            if page.is_empty() {
+                break;
+            }
+            start += page.len() as u64;
+            paged.extend(page);
+        }
+        let paths = |items: &[FolderScanItem]| items.iter().map(|i| i.relative_path.clone()).collect::<Vec<_>>();
+        assert_eq!(paths(&paged), paths(&expected.items));
+        assert!(read_page(context, 41, 10).is_empty());
+
+        scan_folder_free(context);
+        assert_eq!(fs::read_dir(&spill_dir).unwrap().count(), 0);
+
+        // Below the threshold nothing spills and both accessors agree
+        let context = scan_folder_init_with_options(tree_c.as_ptr(), &CScanOptions {
+            spill_to_disk: 1, spill_threshold: 1000, spill_dir: spill_c.as_ptr(), ..Default::default()
+        });
+        assert_eq!(scan_folder_is_spilled(context), 0);
+        assert_eq!(paths(&read_page(context, 40, 5)), paths(&expected.items[40..]));
+        let json = scan_folder_get_json(context, &mut len);
+        assert!(!json.is_null());
+        scan_folder_free_string(json);
+        scan_folder_free(context);
+        assert_eq!(fs::read_dir(&spill_dir).unwrap().count(), 0);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_scan_folder_query_pages_sorted_matches() {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_scan_query_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("photos/2024")).unwrap();
+        fs::create_dir_all(root.join("cache")).unwrap();
+        // Twelve photos of distinct sizes, split across folders and extension case
+        for i in 0..12usize {
+            let name = if i % 2 == 0 { format!("photos/img{:02}.jpg", i) } else { format!("photos/2024/IMG{:02}.JPG", i) };
+            fs::write(root.join(name), vec![0u8; (i + 1) * 10]).unwrap();
+        }
+        fs::write(root.join("photos/notes.txt"), vec![0u8; 5000]).unwrap();
+        fs::write(root.join("cache/thumb.jpg"), vec![0u8; 9000]).unwrap();
+
+        let root_c = CString::new(root.to_string_lossy().to_string()).unwrap();
+        let options = CString::new(
+            r#"{"include":["*.jpg"],"exclude":["cache"],"include_folders":false,"sort":"size","order":"desc","offset":4,"limit":4}"#,
+        )
+        .unwrap();
+        let mut len = 0usize;
+        let ptr = scan_folder_query(root_c.as_ptr(), options.as_ptr(), &mut len);
+        assert!(!ptr.is_null());
+        let json = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        assert_eq!(json.len(), len);
+        scan_folder_free_string(ptr);
+
+        let result: ScanQueryResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(result.total_matches, 12);
+        assert_eq!(result.matched_files, 12);
+        assert_eq!(result.matched_folders, 0);
+        assert_eq!(result.matched_size, (1..=12u64).map(|i| i * 10).sum::<u64>());
+        assert_eq!(result.offset, 4);
+        let sizes: Vec<u64> = result.items.iter().map(|i| i.item.size).collect();
+        assert_eq!(sizes, vec![80, 70, 60, 50]);
+        assert!(result.items.iter().all(|i| i.modified_ms > 0));
+
+        // Folders first with an extension filter keeps folders ahead of files
+        let options = ScanQueryOptions {
+            extensions: vec!["txt".to_string()],
+            folders_first: true,
+            ..Default::default()
+        };
+        let result = scan_folder_query_impl(&root.to_string_lossy(), &options).unwrap();
+        let names: Vec<&str> = result.items.iter().map(|i| i.item.relative_path.as_str()).collect();
+        assert_eq!(names, vec!["photos/2024", "cache", "photos", "photos/notes.txt"]);
+
+        assert!(glob_match(b"photos/**/*.jpg", b"photos/img00.jpg"));
+        assert!(glob_match(b"photos/**/*.jpg", b"photos/2024/img01.jpg"));
+        assert!(!glob_match(b"photos/*.jpg", b"photos/2024/img01.jpg"));
+        assert!(glob_match(b"img0?.JPG", b"img03.jpg"));
+
+        // Invalid options yield null
+        let bad = CString::new("{not json").unwrap();
+        assert!(scan_folder_query(root_c.as_ptr(), bad.as_ptr(), &mut len).is_null());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    fn relative_paths(result: &FolderScanResult) -> Vec<&str> {
+        result.items.iter().map(|item| item.relative_path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_scan_traversal_orders() {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_scan_order_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("b/inner")).unwrap();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("b/inner/deep.txt"), b"1").unwrap();
+        fs::write(root.join("b/x.txt"), b"22").unwrap();
+        fs::write(root.join("a/y.txt"), b"333").unwrap();
+        fs::write(root.join("z.txt"), b"4444").unwrap();
+        fs::write(root.join("c.txt"), b"55555").unwrap();
+        let root_str = root.to_string_lossy().to_string();
+
+        let dfs = scan_folder_sync_ordered(&root_str, None, ScanTraversal::DepthFirstPreOrder).unwrap();
+        assert_eq!(relative_paths(&dfs), vec![
+            "a", "a/y.txt", "b", "b/inner", "b/inner/deep.txt", "b/x.txt", "c.txt", "z.txt",
+        ]);
+        let bfs = scan_folder_sync_ordered(&root_str, None, ScanTraversal::BreadthFirst).unwrap();
+        assert_eq!(relative_paths(&bfs), vec![
+            "a", "b", "c.txt", "z.txt", "a/y.txt", "b/inner", "b/x.txt", "b/inner/deep.txt",
+        ]);
+        for result in [&dfs, &bfs] {
+            assert_eq!((result.folder_count, result.file_count, result.total_size), (3, 5, 15));
+        }
+        assert_eq!(relative_paths(&scan_folder_sync(&root_str, None).unwrap()), relative_paths(&dfs));
+
+        // max_depth limits both orders the same way
+        let shallow_dfs = scan_folder_sync_ordered(&root_str, Some(1), ScanTraversal::DepthFirstPreOrder).unwrap();
+        assert_eq!(relative_paths(&shallow_dfs), vec!["a", "a/y.txt", "b", "b/inner", "b/x.txt", "c.txt", "z.txt"]);
+        let shallow_bfs = scan_folder_sync_ordered(&root_str, Some(1), ScanTraversal::BreadthFirst).unwrap();
+        assert_eq!(shallow_bfs.items.len(), 7);
+        assert_eq!((shallow_bfs.folder_count, shallow_bfs.file_count), (shallow_dfs.folder_count, shallow_dfs.file_count));
+
+        // Through FFI, the spilled items keep the order
+        let root_c = CString::new(root_str.clone()).unwrap();
+        let spill_c = CString::new(std::env::temp_dir().to_string_lossy().to_string()).unwrap();
+        let context = scan_folder_init_with_options(root_c.as_ptr(), &CScanOptions {
+            traversal: SCAN_TRAVERSAL_BFS, spill_to_disk: 1, spill_threshold: 2, spill_dir: spill_c.as_ptr(),
+            ..Default::default()
+        });
+        assert_eq!(scan_folder_is_spilled(context), 1);
+        let spilled: Vec<String> = read_page(context, 0, 100).into_iter().map(|item| item.relative_path).collect();
+        assert_eq!(spilled, relative_paths(&bfs));
+        scan_folder_free(context);
+
+        let unknown = CScanOptions { traversal: 7, ..Default::default() };
+        assert!(scan_folder_init_with_options(root_c.as_ptr(), &unknown).is_null());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_links_counted_once_with_dedupe() {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_scan_links_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("copies")).unwrap();
+        fs::write(root.join("original.bin"), vec![7u8; 1000]).unwrap();
+        fs::hard_link(root.join("original.bin"), root.join("copies/link.bin")).unwrap();
+        fs::write(root.join("other.bin"), vec![1u8; 10]).unwrap();
+        let root_str = root.to_string_lossy().to_string();
+
+        // Without dedupe every path counts, but both report the same identity
+        let plain = scan_folder_sync(&root_str, None).unwrap();
+        assert_eq!((plain.file_count, plain.total_size), (3, 2010));
+        let find = |result: &FolderScanResult, path: &str| {
+            result.items.iter().find(|item| item.relative_path == path).cloned().unwrap()
+        };
+        let (original, link) = (find(&plain, "original.bin"), find(&plain, "copies/link.bin"));
+        assert_eq!((original.dev, original.inode), (link.dev, link.inode));
+        assert_ne!(original.inode, find(&plain, "other.bin").inode);
+        assert!(plain.items.iter().all(|item| !item.is_hardlink_duplicate));
+
+        // With dedupe both paths are listed and the content counts once
+        let options = ScanOptions { dedupe_hardlinks: true, ..Default::default() };
+        let deduped = scan_folder_sync_with(&root_str, None, &options).unwrap();
+        assert_eq!((deduped.file_count, deduped.total_size), (3, 1010));
+        assert_eq!(relative_paths(&deduped), relative_paths(&plain));
+        // Pre-order reports copies/link.bin first, so original.bin is the duplicate
+        assert!(!find(&deduped, "copies/link.bin").is_hardlink_duplicate);
+        assert!(find(&deduped, "original.bin").is_hardlink_duplicate);
+        assert!(!find(&deduped, "other.bin").is_hardlink_duplicate);
+
+        // Through FFI, in spilled items and in the file picker query
+        let root_c = CString::new(root_str.clone()).unwrap();
+        let spill_c = CString::new(std::env::temp_dir().to_string_lossy().to_string()).unwrap();
+        let context = scan_folder_init_with_options(root_c.as_ptr(), &CScanOptions {
+            dedupe_hardlinks: 1, spill_to_disk: 1, spill_threshold: 1, spill_dir: spill_c.as_ptr(), ..Default::default()
+        });
+        assert_eq!(scan_folder_get_total_size(context), 1010);
+        let spilled = read_page(context, 0, 100);
+        assert_eq!(spilled.iter().filter(|item| item.is_hardlink_duplicate).count(), 1);
+        scan_folder_free(context);
+
+        let query = ScanQueryOptions { dedupe_hardlinks: true, include_folders: false, ..Default::default() };
+        let result = scan_folder_query_impl(&root_str, &query).unwrap();
+        assert_eq!((result.total_matches, result.matched_size), (3, 1010));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_fail_beyond_max_depth() {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_scan_deep_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir(&root).unwrap();
+        let mut deepest = root.clone();
+        for _ in 0..200 {
+            deepest.push("d");
+            fs::create_dir(&deepest).unwrap();
+        }
+        fs::write(deepest.join("bottom.txt"), b"deep").unwrap();
+        let root_str = root.to_string_lossy().to_string();
+        let strict = ScanOptions { fail_beyond_max_depth: true, ..Default::default() };
+
+        // Within the limit a strict scan sees everything
+        let full = scan_folder_sync_with(&root_str, Some(250), &strict).unwrap();
+        assert_eq!(full.file_count, 1);
+
+        // Beyond it the plain scan truncates silently and the strict one names the folder
+        let truncated = scan_folder_sync_with(&root_str, Some(100), &ScanOptions::default()).unwrap();
+        assert_eq!(truncated.file_count, 0);
+        let error = scan_folder_sync_with(&root_str, Some(100), &strict).unwrap_err();
+        let offending = vec!["d"; 101].join("/");
+        assert_eq!(max_depth_error_path(&error), Some(offending.as_str()));
+        assert_eq!(max_depth_error_path("Folder does not exist"), None);
+
+        let mut len = 0usize;
+        let root_c = CString::new(root_str).unwrap();
+        let context = scan_folder_init_with_options(root_c.as_ptr(), &CScanOptions {
+            max_depth: 100, traversal: SCAN_TRAVERSAL_BFS, fail_beyond_max_depth: 1, ..Default::default()
+        });
+        let envelope = take_envelope(scan_folder_get_json(context, &mut len), len);
+        assert_eq!(envelope["ok"], false);
+        assert_eq!(envelope["error"]["code"], crate::file_io::ERROR_MAX_DEPTH_EXCEEDED);
+        assert_eq!(envelope["error"]["context"], offending.as_str());
+        scan_folder_free(context);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_folder_identity_read_once() {
+        let mut state = ScanState::new(&ScanOptions::default());
+        assert!(state.enter_folder((1, 42)));
+        assert!(!state.enter_folder((1, 42)));
+        assert!(state.enter_folder((2, 42)));
+        // Unknown identities are never treated as a revisit
+        assert!(state.enter_folder((0, 0)));
+        assert!(state.enter_folder((0, 0)));
+    }
+
+    #[test]
+    fn test_sort_locale_orders_scan_and_listing() {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_scan_locale_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("Docs")).unwrap();
+        fs::create_dir_all(root.join("archive")).unwrap();
+        for name in ["file10.txt", "file2.txt", "Banana.txt", "apple.txt", "éclair.txt"] {
+            fs::write(root.join(name), b"x").unwrap();
+        }
+        let root_str = root.to_string_lossy().to_string();
+        let scan_order = |sort_locale| {
+            let options = ScanOptions { sort_locale, ..Default::default() };
+            let result = scan_folder_sync_with(&root_str, None, &options).unwrap();
+            result.items.into_iter().map(|item| item.relative_path).collect::<Vec<_>>()
+        };
+
+        assert_eq!(scan_order(SortLocale::Byte),
+                   ["Docs", "archive", "Banana.txt", "apple.txt", "file10.txt", "file2.txt", "éclair.txt"]);
+        assert_eq!(scan_order(SortLocale::CaseInsensitive),
+                   ["archive", "Docs", "apple.txt", "Banana.txt", "file10.txt", "file2.txt", "éclair.txt"]);
+        let natural = ["archive", "Docs", "apple.txt", "Banana.txt", "éclair.txt", "file2.txt", "file10.txt"];
+        assert_eq!(scan_order(SortLocale::Natural), natural);
+
+        // Through FFI, and in directory listings
+        let root_c = CString::new(root_str.clone()).unwrap();
+        let context = scan_folder_init_with_options(root_c.as_ptr(), &CScanOptions {
+            traversal: SCAN_TRAVERSAL_BFS, sort_locale: crate::name_order::SORT_LOCALE_NATURAL, ..Default::default()
+        });
+        let result = unsafe { &*context }.get_result().unwrap().clone();
+        assert_eq!(relative_paths(&result), natural);
+        scan_folder_free(context);
+        let unknown = CScanOptions { sort_locale: 9, ..Default::default() };
+        assert!(scan_folder_init_with_options(root_c.as_ptr(), &unknown).is_null());
+
+        let options: ListDirectoryOptions = serde_json::from_str(r#"{"sort_locale": 2}"#).unwrap();
+        let listing = list_directory_impl(&root_str, &options).unwrap();
+        let listed: Vec<_> = listing.items.iter().map(|i| i.item.name.as_str()).collect();
+        assert_eq!(listed, natural);
+        assert!(serde_json::from_str::<ListDirectoryOptions>(r#"{"sort_locale": 5}"#).is_err());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_scan_expands_zip_archives_into_virtual_items() {
+        use std::io::Write;
+        use zip::write::SimpleFileOptions;
+
+        let root = std::env::temp_dir().join(format!("cloud_nexus_scan_zip_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("notes.txt"), b"plain").unwrap();
+        // An archive stored inside another one is listed, not opened
+        let mut nested = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        nested.start_file("deeper.txt", SimpleFileOptions::default()).unwrap();
+        nested.write_all(b"deeper").unwrap();
+        let nested = nested.finish().unwrap().into_inner();
+        // Nested inside the scanned tree, with folders only implied by entry paths
+        let mut writer = zip::ZipWriter::new(fs::File::create(root.join("docs/bundle.zip")).unwrap());
+        writer.start_file("inner/dir/file.txt", SimpleFileOptions::default()).unwrap();
+        writer.write_all(&[b'x'; 3000]).unwrap();
+        writer.start_file("inner/archive.zip", SimpleFileOptions::default()).unwrap();
+        writer.write_all(&nested).unwrap();
+        writer.add_directory("empty/", SimpleFileOptions::default()).unwrap();
+        writer.start_file("readme.md", SimpleFileOptions::default()).unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.finish().unwrap();
+        let bundle_size = fs::metadata(root.join("docs/bundle.zip")).unwrap().len();
+        fs::write(root.join("docs/broken.zip"), b"PK\x03\x04 not really an archive").unwrap();
+
+        let root_str = root.to_string_lossy().to_string();
+        let options = ScanOptions { expand_archives: true, ..Default::default() };
+        let result = scan_folder_sync_with(&root_str, None, &options).unwrap();
+        let virtual_items: Vec<(&str, bool, u64)> = result.items.iter()
+            .filter(|item| item.is_virtual)
+            .map(|item| (item.relative_path.as_str(), item.is_folder, item.size))
+            .collect();
+        assert_eq!(virtual_items, [
+            ("docs/bundle.zip!/empty", true, 0),
+            ("docs/bundle.zip!/inner", true, 0),
+            ("docs/bundle.zip!/inner/archive.zip", false, nested.len() as u64),
+            ("docs/bundle.zip!/inner/dir", true, 0),
+            ("docs/bundle.zip!/inner/dir/file.txt", false, 3000),
+            ("docs/bundle.zip!/readme.md", false, 5),
+        ]);
+        // Virtual items follow their archive and count toward no totals
+        let bundle = result.items.iter().position(|item| item.relative_path == "docs/bundle.zip").unwrap();
+        assert!(result.items[bundle + 1].is_virtual);
+        let file = result.items.iter().find(|item| item.name == "file.txt").unwrap();
+        assert_eq!(file.absolute_path, format!("{}!/inner/dir/file.txt", root.join("docs/bundle.zip").to_string_lossy()));
+        assert_eq!((result.file_count, result.folder_count), (3, 1));
+        let broken_size = fs::metadata(root.join("docs/broken.zip")).unwrap().len();
+        assert_eq!(result.total_size, 5 + bundle_size + broken_size);
+
+        // The corrupt archive is an error entry, not a failed scan
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].item_path.as_deref(), Some(root.join("docs/broken.zip").to_string_lossy().as_ref()));
+        assert!(result.errors[0].error_message.contains("Invalid ZIP archive"));
+
+        // Indexed, entries hang off their archive and virtual folders
+        let parents: std::collections::HashMap<String, Option<String>> =
+            scan_items_to_documents(result.items.clone(), "local", "local", "", false)
+                .map(|doc| (doc.node_id, doc.parent_id))
+                .collect();
+        let parent = |path: &str| parents[path].as_deref().map(str::to_string);
+        assert_eq!(parent("docs/bundle.zip!/readme.md").as_deref(), Some("docs/bundle.zip"));
+        assert_eq!(parent("docs/bundle.zip!/inner").as_deref(), Some("docs/bundle.zip"));
+        assert_eq!(parent("docs/bundle.zip!/inner/dir/file.txt").as_deref(), Some("docs/bundle.zip!/inner/dir"));
+        assert_eq!(parent("docs/broken.zip").as_deref(), Some("docs"));
+
+        // Without the option archives are plain files
+        let plain = scan_folder_sync(&root_str, None).unwrap();
+        assert!(plain.items.iter().all(|item| !item.is_virtual) && plain.errors.is_empty());
+
+        let root_c = CString::new(root_str.clone()).unwrap();
+        let separator = CString::new("::").unwrap();
+        let mut options = CScanOptions {
+            traversal: SCAN_TRAVERSAL_BFS,
+            expand_archives: 1,
+            archive_separator: separator.as_ptr(),
+            ..Default::default()
+        };
+        let context = scan_folder_init_with_options(root_c.as_ptr(), &options);
+        let result = unsafe { &*context }.get_result().unwrap().clone();
+        assert!(result.items.iter().any(|item| item.relative_path == "docs/bundle.zip::inner/dir/file.txt"));
+        scan_folder_free(context);
+        let empty = CString::new("").unwrap();
+        options.archive_separator = empty.as_ptr();
+        assert!(scan_folder_init_with_options(root_c.as_ptr(), &options).is_null());
+
+        // Fields past a shorter struct_size keep their defaults
+        options.struct_size = std::mem::offset_of!(CScanOptions, expand_archives) as u32;
+        let context = scan_folder_init_with_options(root_c.as_ptr(), &options);
+        let result = unsafe { &*context }.get_result().unwrap().clone();
+        assert!(result.items.iter().all(|item| !item.is_virtual));
+        scan_folder_free(context);
+        options.struct_size = 0;
+        assert!(scan_folder_init_with_options(root_c.as_ptr(), &options).is_null());
+        let context = scan_folder_init_with_options(root_c.as_ptr(), std::ptr::null());
+        assert_eq!(unsafe { &*context }.get_result().unwrap().items.len(), plain.items.len());
+        scan_folder_free(context);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_list_directory_pages_one_level() {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_list_dir_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("b_folder/nested")).unwrap();
+        fs::create_dir_all(root.join("a_folder")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("b_folder/nested/deep.txt"), b"deep").unwrap();
+        for (name, size) in [("c.txt", 3), ("A.txt", 1), ("b.txt", 2), (".hidden", 9)] {
+            fs::write(root.join(name), vec![0u8; size]).unwrap();
+        }
+        let root_str = root.to_string_lossy().to_string();
+        let names = |listing: &DirectoryListing| listing.items.iter().map(|i| i.item.name.clone()).collect::<Vec<_>>();
+
+        // Folders first by default, then files by case-insensitive name; nothing below one level
+        let all = list_directory_impl(&root_str, &ListDirectoryOptions::default()).unwrap();
+        assert_eq!(names(&all), ["a_folder", "b_folder", "empty", "A.txt", "b.txt", "c.txt"]);
+        assert_eq!((all.total_count, all.folder_count, all.file_count, all.hidden_count), (6, 3, 3, 1));
+        assert!(!all.has_more && all.errors.is_empty());
+        assert!(all.items.iter().all(|i| i.modified_ms > 0));
+        assert_eq!(all.items[4].item.relative_path, "b.txt");
+
+        // Page boundaries: a full first page, a last page ending exactly at the end, and past the end
+        let page = |offset, limit| {
+            let options = ListDirectoryOptions { offset, limit: Some(limit), ..Default::default() };
+            list_directory_impl(&root_str, &options).unwrap()
+        };
+        let first = page(0, 4);
+        assert_eq!(names(&first), ["a_folder", "b_folder", "empty", "A.txt"]);
+        assert!(first.has_more);
+        let last = page(4, 2);
+        assert_eq!(names(&last), ["b.txt", "c.txt"]);
+        assert!(!last.has_more);
+        let beyond = page(6, 2);
+        assert!(beyond.items.is_empty() && !beyond.has_more);
+        assert_eq!(beyond.total_count, 6);
+
+        let options = ListDirectoryOptions {
+            sort: ScanSortKey::Size,
+            order: ScanSortOrder::Desc,
+            folders_first: false,
+            include_hidden: true,
+            ..Default::default()
+        };
+        let by_size = list_directory_impl(&root_str, &options).unwrap();
+        assert_eq!(names(&by_size)[..4], [".hidden", "c.txt", "b.txt", "A.txt"]);
+        assert_eq!(by_size.hidden_count, 0);
+
+        // An empty directory is a valid empty page
+        let empty = list_directory_impl(&root.join("empty").to_string_lossy(), &ListDirectoryOptions::default()).unwrap();
+        assert!(empty.items.is_empty() && !empty.has_more);
+        assert_eq!(empty.total_count, 0);
+
+        // Through FFI, with a missing directory as an error envelope
+        let mut len = 0usize;
+        let root_c = CString::new(root_str.clone()).unwrap();
+        let options_c = CString::new(r#"{"offset": 1, "limit": 1, "folders_first": false}"#).unwrap();
+        let envelope = take_envelope(list_directory(root_c.as_ptr(), options_c.as_ptr(), &mut len), len);
+        assert_eq!(envelope["ok"], true);
+        assert_eq!(envelope["data"]["items"][0]["name"], "a_folder");
+        assert_eq!(envelope["data"]["has_more"], true);
+
+        let missing = CString::new(root.join("missing").to_string_lossy().to_string()).unwrap();
+        let envelope = take_envelope(list_directory(missing.as_ptr(), std::ptr::null(), &mut len), len);
+        assert_eq!(envelope["ok"], false);
+        assert_eq!(envelope["error"]["code"], crate::file_io::ERROR_FILE_NOT_FOUND);
+        let bad_options = CString::new("{").unwrap();
+        let envelope = take_envelope(list_directory(root_c.as_ptr(), bad_options.as_ptr(), &mut len), len);
+        assert_eq!(envelope["error"]["code"], crate::ffi_util::ERROR_INVALID_JSON);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_list_directory_reports_unreadable_child() {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_list_dir_broken_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("folder")).unwrap();
+        fs::write(root.join("file.txt"), b"x").unwrap();
+        std::os::unix::fs::symlink(root.join("folder"), root.join("folder link")).unwrap();
+        std::os::unix::fs::symlink(root.join("gone"), root.join("dangling")).unwrap();
+
+        let listing = list_directory_impl(&root.to_string_lossy(), &ListDirectoryOptions::default()).unwrap();
+        let names: Vec<_> = listing.items.iter().map(|i| (i.item.name.as_str(), i.item.is_folder)).collect();
+        assert_eq!(names, [("folder", true), ("folder link", true), ("file.txt", false)]);
+        assert_eq!(listing.total_count, 3);
+        assert_eq!(listing.errors.len(), 1);
+        assert_eq!(listing.errors[0].item_path.as_deref(), Some(root.join("dangling").to_string_lossy().as_ref()));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_scan_folder_get_json_v2_matches_v1() {
+        let items: Vec<FolderScanItem> = (0..50_000)
+            .map(|i| FolderScanItem {
+                relative_path: format!("folder {}/file \"{}\".txt", i / 100, i),
+                name: format!("file \"{}\".txt", i),
+                is_folder: false,
+                size: i as u64,
+                absolute_path: format!("/data/folder {}/file \"{}\".txt", i / 100, i),
+                dev: 1,
+                inode: i as u64,
+                is_hardlink_duplicate: false,
+                is_virtual: false,
+            })
+            .collect();
+        let mut context = FolderScanContext::new();
+        context.set_result(FolderScanResult {
+            root_path: "/data".to_string(),
+            total_size: items.iter().map(|item| item.size).sum(),
+            file_count: items.len() as u64,
+            folder_count: 0,
+            scan_duration_ms: 7,
+            items,
+            errors: Vec::new(),
+        });
+        let context = &mut context as *mut FolderScanContext;
+
+        let mut buffer: *mut u8 = std::ptr::null_mut();
+        let mut len = 0usize;
+        assert_eq!(scan_folder_get_json_v2(context, &mut buffer, &mut len), 0);
+        assert!(!buffer.is_null());
+        let bytes = unsafe { std::slice::from_raw_parts(buffer, len) }.to_vec();
+        crate::free_buffer(buffer);
+
+        // Byte for byte the envelope of scan_folder_get_json, parsed to the last item
+        let mut v1_len = 0usize;
+        let v1 = scan_folder_get_json(context, &mut v1_len);
+        let v1_bytes = unsafe { CStr::from_ptr(v1) }.to_bytes().to_vec();
+        scan_folder_free_string(v1);
+        assert_eq!(v1_len, len);
+        assert_eq!(bytes, v1_bytes);
+        assert!(!bytes.contains(&0));
+        let envelope: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(envelope["ok"], true);
+        let items = envelope["data"]["items"].as_array().unwrap();
+        assert_eq!(items.len(), 50_000);
+        assert_eq!(items[49_999]["name"], "file \"49999\".txt");
+
+        // A failed scan reports its code without allocating
+        let mut failed = FolderScanContext::new();
+        failed.set_error("no such folder".to_string());
+        let mut buffer: *mut u8 = std::ptr::null_mut();
+        assert_eq!(scan_folder_get_json_v2(&mut failed, &mut buffer, &mut len), crate::file_io::ERROR_FILE_NOT_FOUND);
+        assert!(buffer.is_null());
+        assert_eq!(len, 0);
+        assert_eq!(scan_folder_get_json_v2(context, std::ptr::null_mut(), &mut len), crate::file_io::ERROR_NULL_POINTER);
+    }
+}
@@ -53,10 +53,7 @@ impl BatchIndexer {
             return Ok(0);
         }
         
-        let count = self.current_batch.len();
-        for doc in self.current_batch.drain(..) {
-            self.index.add_document(doc);
-        }
+        let count = self.index.add_documents(self.current_batch.drain(..));
         
         self.total_indexed += count;
         Ok(count)
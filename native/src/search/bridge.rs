@@ -1,12 +1,24 @@
 // FFI bridge for search module
 // Phase 2: Full Rust FFI implementation - replaces Dart search service
 
-use std::ffi::{c_void, CString, CStr};
+use std::collections::HashMap;
+use std::ffi::{c_void, CString};
 use std::os::raw::c_char;
 use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU8};
+use std::sync::{Arc, Mutex, Once};
 
 use super::fuzzy::{fuzzy_match, jaro_winkler_similarity, levenshtein_distance, soundex, metaphone};
-use super::index::{SearchDocument, SearchIndex};
+use super::diagnostics::Pseudonymizer;
+use super::index::{MultiSearchQuery, SearchBudget, SearchDocument, SearchIndex, SearchMode, SearchResult,
+                   ERROR_TOO_MANY_QUERIES, MAX_MULTI_SEARCH_QUERIES};
+use super::history::SearchHistory;
+use super::scoring::{ScoringProfile, ERROR_INVALID_SCORING_PROFILE};
+use super::suggestions::{SuggestionEngine, SuggestionEngineState};
+use crate::ffi_util::{envelope_str, ffi_opt_str_in, ffi_str_in, ffi_string_out, json_envelope, json_envelope_partial,
+                      ErrorEnvelope, JsonEnvelope, ERROR_INVALID_JSON, ERROR_NOT_FOUND};
+use crate::errors::{checked, ERROR_INVALID_SORT_LOCALE, ERROR_MAX_DEPTH_EXCEEDED};
+use crate::runtime::{register_trim_handler, TrimLevel};
 
 /// C-compatible search result structure
 #[repr(C)]
@@ -18,6 +30,88 @@ pub struct CSearchResult {
     pub provider: *mut c_char,
 }
 
+/// Result arrays handed out by the search functions and not freed yet: address -> entry count
+static LIVE_RESULT_ARRAYS: Mutex<Option<HashMap<usize, usize>>> = Mutex::new(None);
+
+/// Indexes handed out by create_search_index and the indexer constructors and
+/// not freed yet: address -> the index's trim request flag
+static LIVE_INDEXES: Mutex<Option<HashMap<usize, Arc<AtomicU8>>>> = Mutex::new(None);
+
+/// Hand out a new index, letting native_trim_memory reach it until it is freed
+fn track_index(index: Box<SearchIndex>) -> *mut SearchIndex {
+    static REGISTER_TRIM: Once = Once::new();
+    REGISTER_TRIM.call_once(|| register_trim_handler("search", trim_search_memory));
+
+    let trim_request = index.trim_request();
+    let index_ptr = Box::into_raw(index);
+    if let Ok(mut live) = LIVE_INDEXES.lock() {
+        live.get_or_insert_with(HashMap::new).insert(index_ptr as usize, trim_request);
+    }
+    index_ptr
+}
+
+/// Free an index handed out by track_index
+fn free_tracked_index(index_ptr: *mut SearchIndex) {
+    if index_ptr.is_null() {
+        return;
+    }
+    if let Ok(mut live) = LIVE_INDEXES.lock() {
+        if let Some(live) = live.as_mut() {
+            live.remove(&(index_ptr as usize));
+        }
+    }
+    unsafe {
+        let _ = Box::from_raw(index_ptr);
+    }
+}
+
+/// Borrow an index for a call that changes it, first applying a trim
+/// native_trim_memory requested
+///
+/// # Safety
+/// `index_ptr` must be a live index that no other call is using.
+unsafe fn index_mut<'a>(index_ptr: *mut SearchIndex) -> &'a mut SearchIndex {
+    let index = &mut *index_ptr;
+    index.apply_pending_trim();
+    index
+}
+
+/// Borrow an index for a call that only reads it, first clearing its query
+/// cache if native_trim_memory asked for a trim
+///
+/// # Safety
+/// `index_ptr` must be a live index that no call changing it is using.
+unsafe fn index_ref<'a>(index_ptr: *const SearchIndex) -> &'a SearchIndex {
+    let index = &*index_ptr;
+    index.apply_pending_cache_trim();
+    index
+}
+
+/// Trim handler of the search module: the result array registry, and a trim
+/// request to every live index
+///
+/// Indexes may be in use on other threads, so they are never touched here:
+/// each applies the request on its next call, and what that releases is not
+/// part of the returned estimate.
+fn trim_search_memory(level: TrimLevel) -> u64 {
+    if let Ok(live) = LIVE_INDEXES.lock() {
+        for trim_request in live.iter().flat_map(|live| live.values()) {
+            SearchIndex::request_trim(trim_request, level);
+        }
+    }
+    let mut released = 0;
+    if level >= TrimLevel::Moderate {
+        if let Ok(mut live) = LIVE_RESULT_ARRAYS.lock() {
+            if let Some(arrays) = live.as_mut() {
+                let before = arrays.capacity();
+                arrays.shrink_to_fit();
+                released += ((before - arrays.capacity()) * std::mem::size_of::<(usize, usize)>()) as u64;
+            }
+        }
+    }
+    released
+}
+
 /// C-compatible search document structure
 #[repr(C)]
 pub struct CSearchDocument {
@@ -34,18 +128,13 @@ pub struct CSearchDocument {
 /// Returns pointer to index (null on error)
 #[no_mangle]
 pub extern "C" fn create_search_index() -> *mut SearchIndex {
-    let index = Box::new(SearchIndex::new());
-    Box::into_raw(index)
+    track_index(Box::default())
 }
 
 /// Free search index memory
 #[no_mangle]
 pub extern "C" fn free_search_index(index_ptr: *mut SearchIndex) {
-    if !index_ptr.is_null() {
-        unsafe {
-            let _ = Box::from_raw(index_ptr);
-        }
-    }
+    free_tracked_index(index_ptr);
 }
 
 /// Add document to search index
@@ -65,68 +154,44 @@ pub extern "C" fn add_document_to_index(
         return 0;
     }
     
-    let index = unsafe { &mut *index_ptr };
+    let index = unsafe { index_mut(index_ptr) };
     
-    let node_id_str = if node_id.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(node_id).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
-        }
+    let node_id_str = match unsafe { ffi_opt_str_in(node_id, "node_id") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
     };
     
-    let account_id_str = if account_id.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(account_id).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
-        }
+    let account_id_str = match unsafe { ffi_opt_str_in(account_id, "account_id") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
     };
     
-    let provider_str = if provider.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(provider).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
-        }
+    let provider_str = match unsafe { ffi_opt_str_in(provider, "provider") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
     };
     
-    let email_str = if email.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(email).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
-        }
+    let email_str = match unsafe { ffi_opt_str_in(email, "email") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
     };
     
-    let name_str = if name.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(name).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
-        }
+    let name_str = match unsafe { ffi_opt_str_in(name, "name") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
     };
     
-    let parent_id_opt = if parent_id.is_null() {
-        None
-    } else {
-        match unsafe { CStr::from_ptr(parent_id).to_str() } {
-            Ok(s) => Some(s.to_string()),
-            Err(_) => return 0,
-        }
+    let parent_id_opt = match unsafe { ffi_opt_str_in(parent_id, "parent_id") } {
+        Ok(s) => s.map(|s| s.to_string()),
+        Err(_) => return 0,
     };
     
     let doc = SearchDocument {
-        node_id: node_id_str,
-        account_id: account_id_str,
-        provider: provider_str,
-        email: email_str,
-        name: name_str,
+        node_id: node_id_str.to_string(),
+        account_id: account_id_str.to_string(),
+        provider: provider_str.to_string(),
+        email: email_str.to_string(),
+        name: name_str.to_string(),
         is_folder,
         parent_id: parent_id_opt,
     };
@@ -138,6 +203,11 @@ pub extern "C" fn add_document_to_index(
 /// Add multiple documents to search index in a single call
 /// More efficient than calling add_document_to_index multiple times
 /// Returns number of documents added successfully
+///
+/// Deprecated: documents with invalid strings are skipped without being
+/// reported, and the caller must keep every string alive for the call. Use
+/// add_documents_json instead
+#[deprecated(note = "use add_documents_json")]
 #[no_mangle]
 pub extern "C" fn add_documents_batch(
     index_ptr: *mut SearchIndex,
@@ -148,72 +218,48 @@ pub extern "C" fn add_documents_batch(
         return 0;
     }
     
-    let index = unsafe { &mut *index_ptr };
+    let index = unsafe { index_mut(index_ptr) };
     let mut added = 0;
     
     for i in 0..count {
         let doc_ref = unsafe { docs.offset(i as isize).read() };
         
-        let node_id_str = if doc_ref.node_id.is_null() {
-            String::new()
-        } else {
-            match unsafe { CStr::from_ptr(doc_ref.node_id).to_str() } {
-                Ok(s) => s.to_string(),
-                Err(_) => continue,
-            }
+        let node_id_str = match unsafe { ffi_opt_str_in(doc_ref.node_id, "docs.node_id") } {
+            Ok(s) => s.unwrap_or_default(),
+            Err(_) => continue,
         };
         
-        let account_id_str = if doc_ref.account_id.is_null() {
-            String::new()
-        } else {
-            match unsafe { CStr::from_ptr(doc_ref.account_id).to_str() } {
-                Ok(s) => s.to_string(),
-                Err(_) => continue,
-            }
+        let account_id_str = match unsafe { ffi_opt_str_in(doc_ref.account_id, "docs.account_id") } {
+            Ok(s) => s.unwrap_or_default(),
+            Err(_) => continue,
         };
         
-        let provider_str = if doc_ref.provider.is_null() {
-            String::new()
-        } else {
-            match unsafe { CStr::from_ptr(doc_ref.provider).to_str() } {
-                Ok(s) => s.to_string(),
-                Err(_) => continue,
-            }
+        let provider_str = match unsafe { ffi_opt_str_in(doc_ref.provider, "docs.provider") } {
+            Ok(s) => s.unwrap_or_default(),
+            Err(_) => continue,
         };
         
-        let email_str = if doc_ref.email.is_null() {
-            String::new()
-        } else {
-            match unsafe { CStr::from_ptr(doc_ref.email).to_str() } {
-                Ok(s) => s.to_string(),
-                Err(_) => continue,
-            }
+        let email_str = match unsafe { ffi_opt_str_in(doc_ref.email, "docs.email") } {
+            Ok(s) => s.unwrap_or_default(),
+            Err(_) => continue,
         };
         
-        let name_str = if doc_ref.name.is_null() {
-            String::new()
-        } else {
-            match unsafe { CStr::from_ptr(doc_ref.name).to_str() } {
-                Ok(s) => s.to_string(),
-                Err(_) => continue,
-            }
+        let name_str = match unsafe { ffi_opt_str_in(doc_ref.name, "docs.name") } {
+            Ok(s) => s.unwrap_or_default(),
+            Err(_) => continue,
         };
         
-        let parent_id_opt = if doc_ref.parent_id.is_null() {
-            None
-        } else {
-            match unsafe { CStr::from_ptr(doc_ref.parent_id).to_str() } {
-                Ok(s) => Some(s.to_string()),
-                Err(_) => continue,
-            }
+        let parent_id_opt = match unsafe { ffi_opt_str_in(doc_ref.parent_id, "docs.parent_id") } {
+            Ok(s) => s.map(|s| s.to_string()),
+            Err(_) => continue,
         };
         
         let doc = SearchDocument {
-            node_id: node_id_str,
-            account_id: account_id_str,
-            provider: provider_str,
-            email: email_str,
-            name: name_str,
+            node_id: node_id_str.to_string(),
+            account_id: account_id_str.to_string(),
+            provider: provider_str.to_string(),
+            email: email_str.to_string(),
+            name: name_str.to_string(),
             is_folder: doc_ref.is_folder,
             parent_id: parent_id_opt,
         };
@@ -225,67 +271,160 @@ pub extern "C" fn add_documents_batch(
     added
 }
 
-/// Search index with exact matching
-/// Returns number of results found (results_out must be freed with free_search_results)
+/// One entry of the add_documents_json array; missing strings default to empty
+#[derive(Debug, serde::Deserialize)]
+struct DocumentInput {
+    node_id: String,
+    #[serde(default)]
+    account_id: String,
+    #[serde(default)]
+    provider: String,
+    #[serde(default)]
+    email: String,
+    name: String,
+    #[serde(default)]
+    is_folder: bool,
+    #[serde(default)]
+    parent_id: Option<String>,
+}
+
+/// A document add_documents_json could not index
+#[derive(Debug, serde::Serialize)]
+pub struct DocumentBatchError {
+    /// Position in the input array
+    pub index: usize,
+    pub reason: String,
+}
+
+/// Outcome of add_documents_json
+#[derive(Debug, Default, serde::Serialize)]
+pub struct DocumentBatchReport {
+    pub added: usize,
+    pub failed: usize,
+    pub errors: Vec<DocumentBatchError>,
+}
+
+/// Add or replace documents from a JSON array
+///
+/// # Arguments
+/// * `index_ptr` - Search index
+/// * `docs_json` - JSON array of {node_id, name, account_id?, provider?, email?,
+///   is_folder?, parent_id?} objects
+///
+/// # Returns
+/// A JSON envelope (see ffi_util.rs) whose data is {added, failed, errors}, where
+/// errors lists {index, reason} for every entry that was not indexed; the other
+/// entries are still added. A document whose node_id is already indexed is
+/// replaced rather than duplicated. Fails with ERROR_INVALID_JSON if docs_json is
+/// not a JSON array. Must be freed with free_c_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
-pub extern "C" fn search_index(
-    index_ptr: *mut SearchIndex,
-    query: *const c_char,
-    limit: usize,
-    results_out: *mut *mut CSearchResult,
-    results_count: *mut usize,
-) -> i32 {
-    if index_ptr.is_null() || results_out.is_null() || results_count.is_null() {
-        return 0;
-    }
-    
-    let index = unsafe { &mut *index_ptr };
-    
-    let query_str = if query.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(query).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
+pub extern "C" fn add_documents_json(index_ptr: *mut SearchIndex, docs_json: *const c_char) -> *mut c_char {
+    let report = (|| {
+        if index_ptr.is_null() {
+            return Err(ErrorEnvelope::null_argument("index_ptr"));
         }
-    };
-    
-    let results = index.search_exact(&query_str, limit);
+        let json = unsafe { envelope_str(docs_json, "docs_json") }?;
+        let entries: Vec<serde_json::Value> =
+            serde_json::from_str(json).map_err(|e| ErrorEnvelope::invalid_json("docs_json", e))?;
+
+        let index = unsafe { index_mut(index_ptr) };
+        index.reserve(entries.len());
+        let mut report = DocumentBatchReport::default();
+        for (position, entry) in entries.into_iter().enumerate() {
+            let doc = match serde_json::from_value::<DocumentInput>(entry) {
+                Ok(doc) if doc.node_id.is_empty() => Err("node_id must not be empty".to_string()),
+                Ok(doc) => Ok(doc),
+                Err(e) => Err(e.to_string()),
+            };
+            match doc {
+                Ok(doc) => {
+                    index.upsert_document(SearchDocument {
+                        node_id: doc.node_id,
+                        account_id: doc.account_id,
+                        provider: doc.provider,
+                        email: doc.email,
+                        name: doc.name,
+                        is_folder: doc.is_folder,
+                        parent_id: doc.parent_id,
+                    });
+                    report.added += 1;
+                }
+                Err(reason) => {
+                    report.failed += 1;
+                    report.errors.push(DocumentBatchError { index: position, reason });
+                }
+            }
+        }
+        Ok(report)
+    })();
+    json_envelope(report, ptr::null_mut())
+}
+
+/// Copy search results into a malloc'ed CSearchResult array for the caller
+///
+/// NUL bytes in the strings become U+FFFD (see ffi_string_out). The array is
+/// recorded so free_search_results knows its length.
+fn write_results_out(results: Vec<SearchResult>, results_out: *mut *mut CSearchResult, results_count: *mut usize) -> i32 {
+    unsafe { *results_count = 0; }
     let count = results.len();
-    
-    // Allocate results array
-    let results_array = unsafe {
-        libc::malloc(count * std::mem::size_of::<CSearchResult>()) as *mut CSearchResult
-    };
-    
+    // At least one entry, so an empty result is not mistaken for a failed allocation
+    let results_array =
+        unsafe { libc::malloc(count.max(1) * std::mem::size_of::<CSearchResult>()) as *mut CSearchResult };
     if results_array.is_null() {
-        unsafe { *results_count = 0; }
         return 0;
     }
-    
-    // Fill results array
-    for (i, result) in results.iter().enumerate() {
+
+    for (i, result) in results.into_iter().enumerate() {
         let c_result = CSearchResult {
-            node_id: CString::new(result.node_id.clone()).unwrap().into_raw(),
-            name: CString::new(result.name.clone()).unwrap().into_raw(),
+            node_id: ffi_string_out(&result.node_id),
+            name: ffi_string_out(&result.name),
             score: result.score,
-            account_id: CString::new(result.account_id.clone()).unwrap().into_raw(),
-            provider: CString::new(result.provider.clone()).unwrap().into_raw(),
+            account_id: ffi_string_out(&result.account_id),
+            provider: ffi_string_out(&result.provider),
         };
-        unsafe { results_array.offset(i as isize).write(c_result); }
+        unsafe { results_array.add(i).write(c_result); }
+    }
+
+    if let Ok(mut live) = LIVE_RESULT_ARRAYS.lock() {
+        live.get_or_insert_with(HashMap::new).insert(results_array as usize, count);
     }
-    
     unsafe {
         *results_out = results_array;
         *results_count = count;
     }
-    
     1
 }
 
-/// Search index with prefix matching
+/// Free the strings of the first `count` entries of a result array, then the array
+///
+/// # Safety
+/// `results` must come from write_results_out with at least `count` entries written.
+unsafe fn free_result_entries(results: *mut CSearchResult, count: usize) {
+    for i in 0..count {
+        // One copy of the entry; each pointer in it is freed exactly once
+        let entry = results.add(i).read();
+        for field in [entry.node_id, entry.name, entry.account_id, entry.provider] {
+            if !field.is_null() {
+                drop(CString::from_raw(field));
+            }
+        }
+        #[cfg(debug_assertions)]
+        results.add(i).write(CSearchResult {
+            node_id: ptr::null_mut(),
+            name: ptr::null_mut(),
+            score: entry.score,
+            account_id: ptr::null_mut(),
+            provider: ptr::null_mut(),
+        });
+    }
+    libc::free(results as *mut c_void);
+}
+
+/// Search index with exact matching
+/// Returns number of results found (results_out must be freed with free_search_results)
 #[no_mangle]
-pub extern "C" fn search_index_prefix(
+pub extern "C" fn search_index(
     index_ptr: *mut SearchIndex,
     query: *const c_char,
     limit: usize,
@@ -296,46 +435,39 @@ pub extern "C" fn search_index_prefix(
         return 0;
     }
     
-    let index = unsafe { &mut *index_ptr };
-    
-    let query_str = if query.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(query).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
-        }
-    };
-    
-    let results = index.search_prefix(&query_str, limit);
-    let count = results.len();
+    let index = unsafe { index_mut(index_ptr) };
     
-    let results_array = unsafe {
-        libc::malloc(count * std::mem::size_of::<CSearchResult>()) as *mut CSearchResult
+    let query_str = match unsafe { ffi_opt_str_in(query, "query") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
     };
     
-    if results_array.is_null() {
-        unsafe { *results_count = 0; }
+    let results = index.search_exact(query_str, limit);
+    checked(write_results_out(results, results_out, results_count))
+}
+
+/// Search index with prefix matching
+#[no_mangle]
+pub extern "C" fn search_index_prefix(
+    index_ptr: *mut SearchIndex,
+    query: *const c_char,
+    limit: usize,
+    results_out: *mut *mut CSearchResult,
+    results_count: *mut usize,
+) -> i32 {
+    if index_ptr.is_null() || results_out.is_null() || results_count.is_null() {
         return 0;
     }
     
-    for (i, result) in results.iter().enumerate() {
-        let c_result = CSearchResult {
-            node_id: CString::new(result.node_id.clone()).unwrap().into_raw(),
-            name: CString::new(result.name.clone()).unwrap().into_raw(),
-            score: result.score,
-            account_id: CString::new(result.account_id.clone()).unwrap().into_raw(),
-            provider: CString::new(result.provider.clone()).unwrap().into_raw(),
-        };
-        unsafe { results_array.offset(i as isize).write(c_result); }
-    }
+    let index = unsafe { index_mut(index_ptr) };
     
-    unsafe {
-        *results_out = results_array;
-        *results_count = count;
-    }
+    let query_str = match unsafe { ffi_opt_str_in(query, "query") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
+    };
     
-    1
+    let results = index.search_prefix(query_str, limit);
+    checked(write_results_out(results, results_out, results_count))
 }
 
 /// Search index by account
@@ -352,81 +484,262 @@ pub extern "C" fn search_index_by_account(
         return 0;
     }
     
-    let index = unsafe { &mut *index_ptr };
-    
-    let query_str = if query.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(query).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
-        }
-    };
+    let index = unsafe { index_mut(index_ptr) };
     
-    let account_id_str = if account_id.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(account_id).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
-        }
+    let query_str = match unsafe { ffi_opt_str_in(query, "query") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
     };
     
-    let results = index.search_by_account(&query_str, &account_id_str, limit);
-    let count = results.len();
-    
-    let results_array = unsafe {
-        libc::malloc(count * std::mem::size_of::<CSearchResult>()) as *mut CSearchResult
+    let account_id_str = match unsafe { ffi_opt_str_in(account_id, "account_id") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
     };
     
-    if results_array.is_null() {
-        unsafe { *results_count = 0; }
+    let results = index.search_by_account(query_str, account_id_str, limit);
+    checked(write_results_out(results, results_out, results_count))
+}
+
+/// Shared argument handling of the `_ids` searches
+///
+/// The query is borrowed rather than copied (null reads as the empty query) and
+/// `out_scores` can be null. Returns 0 for a null index or ids buffer or a
+/// query that is not UTF-8.
+fn search_ids<F>(index_ptr: *mut SearchIndex, query: *const c_char, out_ids: *mut u32, out_scores: *mut f64,
+                 cap: usize, search: F) -> usize
+where
+    F: FnOnce(&SearchIndex, &str, &mut [u32], Option<&mut [f64]>) -> usize,
+{
+    if index_ptr.is_null() || out_ids.is_null() {
         return 0;
     }
-    
-    for (i, result) in results.iter().enumerate() {
-        let c_result = CSearchResult {
-            node_id: CString::new(result.node_id.clone()).unwrap().into_raw(),
-            name: CString::new(result.name.clone()).unwrap().into_raw(),
-            score: result.score,
-            account_id: CString::new(result.account_id.clone()).unwrap().into_raw(),
-            provider: CString::new(result.provider.clone()).unwrap().into_raw(),
-        };
-        unsafe { results_array.offset(i as isize).write(c_result); }
+    let query_str = match unsafe { ffi_opt_str_in(query, "query") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
+    };
+
+    let ids = unsafe { std::slice::from_raw_parts_mut(out_ids, cap) };
+    let scores = if out_scores.is_null() {
+        None
+    } else {
+        Some(unsafe { std::slice::from_raw_parts_mut(out_scores, cap) })
+    };
+    search(unsafe { index_ref(index_ptr) }, query_str, ids, scores)
+}
+
+/// Search index with exact matching, returning document ordinals instead of strings
+/// Writes up to min(limit, cap) ordinals to `out_ids` and their scores to `out_scores`
+/// (can be null), best first; resolve the visible page with get_documents_by_ordinals_json.
+/// Nothing is allocated for the caller, and a repeated lowercase query is answered
+/// from the query cache without any allocation
+/// Returns the number of results written (0 on error)
+#[no_mangle]
+pub extern "C" fn search_index_ids(
+    index_ptr: *mut SearchIndex,
+    query: *const c_char,
+    limit: usize,
+    out_ids: *mut u32,
+    out_scores: *mut f64,
+    cap: usize,
+) -> usize {
+    search_ids(index_ptr, query, out_ids, out_scores, cap, |index, query, ids, scores| {
+        index.search_exact_ids(query, limit, ids, scores)
+    })
+}
+
+/// Search index with prefix matching, returning document ordinals (see search_index_ids)
+/// Returns the number of results written (0 on error)
+#[no_mangle]
+pub extern "C" fn search_index_prefix_ids(
+    index_ptr: *mut SearchIndex,
+    query: *const c_char,
+    limit: usize,
+    out_ids: *mut u32,
+    out_scores: *mut f64,
+    cap: usize,
+) -> usize {
+    search_ids(index_ptr, query, out_ids, out_scores, cap, |index, query, ids, scores| {
+        index.search_prefix_ids(query, limit, ids, scores)
+    })
+}
+
+/// Search index by account, returning document ordinals (see search_index_ids)
+/// Returns the number of results written (0 on error)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn search_index_by_account_ids(
+    index_ptr: *mut SearchIndex,
+    query: *const c_char,
+    account_id: *const c_char,
+    limit: usize,
+    out_ids: *mut u32,
+    out_scores: *mut f64,
+    cap: usize,
+) -> usize {
+    let account_id_str = match unsafe { ffi_opt_str_in(account_id, "account_id") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
+    };
+    search_ids(index_ptr, query, out_ids, out_scores, cap, |index, query, ids, scores| {
+        index.search_by_account_ids(query, account_id_str, limit, ids, scores)
+    })
+}
+
+/// Run several named searches in one call, e.g. the fixed searches of dashboard widgets
+///
+/// # Arguments
+/// * `index_ptr` - Search index
+/// * `queries_json` - JSON array of at most 16 {name, query, mode?, account_id?,
+///   is_folder?, limit?, profile?} objects. mode is "exact" (default, as search_index),
+///   "prefix" (as search_index_prefix) or "account" (as search_index_by_account,
+///   requires account_id); is_folder keeps only folders (true) or files (false);
+///   limit defaults to 20; profile ranks exact and account queries, by built-in
+///   name ("default", "palette", "browser") or as an object of ScoringProfile weights
+/// * `out_len` - Receives the JSON length in bytes (can be null)
+///
+/// # Returns
+/// A JSON envelope (see ffi_util.rs) whose data maps each query name to its
+/// array of {node_id, name, score, account_id, provider} results, best first.
+/// The batch takes the index's query cache lock once, and queries differing
+/// only in is_folder or limit are scored once. Fails with ERROR_TOO_MANY_QUERIES
+/// (-54) beyond 16 queries, with ERROR_INVALID_JSON for a malformed query,
+/// an empty or repeated name, or an account query without account_id, and
+/// with ERROR_INVALID_SCORING_PROFILE (-62) for an unknown profile, a weight
+/// outside [0, 10] or a profile on a prefix query.
+/// Must be freed with free_c_string
+#[no_mangle]
+pub extern "C" fn search_index_multi_json(
+    index_ptr: *mut SearchIndex,
+    queries_json: *const c_char,
+    out_len: *mut usize,
+) -> *mut c_char {
+    search_index_multi_json_ex(index_ptr, queries_json, ptr::null(), 0, out_len)
+}
+
+/// Run several named searches in one call, stopping early when cancelled or
+/// out of time (e.g. a search-as-you-type query gone stale)
+///
+/// Scoring checks `cancel_flag` and the time budget every 1024 documents. A
+/// batch that stops early returns the best matches scored so far, sorted and
+/// without duplicates, with `"truncated": true` in the envelope; queries not
+/// reached return only what the query cache already holds. Partial results
+/// are never cached, and the index's query cache lock is released on return.
+///
+/// # Arguments
+/// * `index_ptr` - Search index
+/// * `queries_json` - Queries, as for search_index_multi_json
+/// * `cancel_flag` - Pointer to atomic bool for cancellation (can be null)
+/// * `time_budget_ms` - Scoring time allowed for the whole batch (0 for no limit)
+/// * `out_len` - Receives the JSON length in bytes (can be null)
+///
+/// # Returns
+/// A JSON envelope as search_index_multi_json returns, flagged `truncated`
+/// when cut short. Must be freed with free_c_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn search_index_multi_json_ex(
+    index_ptr: *mut SearchIndex,
+    queries_json: *const c_char,
+    cancel_flag: *const AtomicBool,
+    time_budget_ms: u32,
+    out_len: *mut usize,
+) -> *mut c_char {
+    let budget = SearchBudget::new(cancel_flag, time_budget_ms);
+    let results = (|| {
+        if index_ptr.is_null() {
+            return Err(ErrorEnvelope::null_argument("index_ptr"));
+        }
+        let json = unsafe { envelope_str(queries_json, "queries_json") }?;
+        let queries: Vec<MultiSearchQuery> =
+            serde_json::from_str(json).map_err(|e| ErrorEnvelope::invalid_json("queries_json", e))?;
+        if queries.len() > MAX_MULTI_SEARCH_QUERIES {
+            return Err(ErrorEnvelope::new(
+                ERROR_TOO_MANY_QUERIES,
+                format!("{} queries in one batch, at most {} allowed", queries.len(), MAX_MULTI_SEARCH_QUERIES),
+            ).with_context("queries_json"));
+        }
+        for (position, query) in queries.iter().enumerate() {
+            let invalid = |message: &str| {
+                Err(ErrorEnvelope::new(ERROR_INVALID_JSON, message).with_context(format!("queries_json[{}]", position)))
+            };
+            if query.name.is_empty() {
+                return invalid("query name must not be empty");
+            }
+            if queries[..position].iter().any(|other| other.name == query.name) {
+                return invalid("query name is used more than once");
+            }
+            if query.mode == SearchMode::Account && query.account_id.is_none() {
+                return invalid("account queries require account_id");
+            }
+            let invalid_profile = |message: String| {
+                ErrorEnvelope::new(ERROR_INVALID_SCORING_PROFILE, message)
+                    .with_context(format!("queries_json[{}].profile", position))
+            };
+            if query.mode == SearchMode::Prefix && query.profile.is_some() {
+                return Err(invalid_profile("prefix queries do not take a scoring profile".to_string()));
+            }
+            query.scoring_profile().map_err(invalid_profile)?;
+        }
+
+        let index = unsafe { index_ref(index_ptr) };
+        let results = index.search_multi_with_budget(&queries, &budget);
+        Ok(queries.into_iter().map(|query| query.name).zip(results).collect::<HashMap<_, _>>())
+    })();
+    json_envelope_partial(results, budget.truncated(), out_len)
+}
+
+/// Resolve document ordinals from the `_ids` searches to full documents
+/// Returns a JSON envelope (see ffi_util.rs) whose data is an array with one entry
+/// per ordinal, in order: the SearchDocument (with its "ordinal"), or null for an
+/// ordinal no longer in use; `out_len` receives the JSON length in bytes.
+/// Must be freed with free_c_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn get_documents_by_ordinals_json(
+    index_ptr: *mut SearchIndex,
+    ids: *const u32,
+    count: usize,
+    out_len: *mut usize,
+) -> *mut c_char {
+    if index_ptr.is_null() {
+        return json_error(ErrorEnvelope::null_argument("index_ptr"), out_len);
     }
-    
-    unsafe {
-        *results_out = results_array;
-        *results_count = count;
+    if ids.is_null() && count > 0 {
+        return json_error(ErrorEnvelope::null_argument("ids"), out_len);
     }
-    
-    1
+
+    #[derive(serde::Serialize)]
+    struct OrdinalDocument<'a> {
+        ordinal: u32,
+        #[serde(flatten)]
+        document: &'a SearchDocument,
+    }
+
+    let index = unsafe { index_ref(index_ptr) };
+    let ordinals: &[u32] = if count == 0 { &[] } else { unsafe { std::slice::from_raw_parts(ids, count) } };
+    let documents: Vec<Option<OrdinalDocument>> = ordinals
+        .iter()
+        .map(|&ordinal| index.get_by_ordinal(ordinal).map(|document| OrdinalDocument { ordinal, document }))
+        .collect();
+    json_out(&documents, out_len)
 }
 
 /// Free search results memory
+///
+/// `count` is capped at the number of results the array was allocated with;
+/// a pointer that is not a live result array (e.g. one already freed) is ignored.
 #[no_mangle]
 pub extern "C" fn free_search_results(results: *mut CSearchResult, count: usize) {
     if results.is_null() {
         return;
     }
-    
-    unsafe {
-        for i in 0..count {
-            let result = results.offset(i as isize);
-            if !result.read().node_id.is_null() {
-                let _ = CString::from_raw(result.read().node_id);
-            }
-            if !result.read().name.is_null() {
-                let _ = CString::from_raw(result.read().name);
-            }
-            if !result.read().account_id.is_null() {
-                let _ = CString::from_raw(result.read().account_id);
-            }
-            if !result.read().provider.is_null() {
-                let _ = CString::from_raw(result.read().provider);
-            }
-        }
-        libc::free(results as *mut c_void);
+
+    let allocated = LIVE_RESULT_ARRAYS
+        .lock()
+        .ok()
+        .and_then(|mut live| live.as_mut()?.remove(&(results as usize)));
+    if let Some(allocated) = allocated {
+        unsafe { free_result_entries(results, count.min(allocated)); }
     }
 }
 
@@ -436,7 +749,7 @@ pub extern "C" fn get_index_count(index_ptr: *mut SearchIndex) -> usize {
     if index_ptr.is_null() {
         return 0;
     }
-    unsafe { (*index_ptr).len() }
+    unsafe { index_ref(index_ptr).len() }
 }
 
 /// Clear search index
@@ -445,73 +758,360 @@ pub extern "C" fn clear_search_index(index_ptr: *mut SearchIndex) -> i32 {
     if index_ptr.is_null() {
         return 0;
     }
-    unsafe { (*index_ptr).clear(); }
+    unsafe { index_mut(index_ptr).clear(); }
     1
 }
 
-// ============================================================================
-// Fuzzy matching FFI functions (standalone - don't require index)
-// ============================================================================
-
-/// Fuzzy match two strings
-/// Returns 1 if match, 0 if not (threshold = 0.7)
+/// Mark a document as deleted in the cloud
+/// The document is excluded from search results but still counted and kept
+/// until purge_index_tombstones removes it
+/// Returns 1 on success, 0 on error or if the document is not indexed
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
-pub extern "C" fn fuzzy_match_strings(
-    query: *const c_char,
-    target: *const c_char,
-    threshold: f64,
+pub extern "C" fn mark_index_document_deleted(
+    index_ptr: *mut SearchIndex,
+    node_id: *const c_char,
+    deleted_at_ms: u64,
 ) -> i32 {
-    let query_str = if query.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(query).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
-        }
-    };
+    if index_ptr.is_null() {
+        return 0;
+    }
     
-    let target_str = if target.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(target).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
-        }
+    let node_id_str = match unsafe { ffi_str_in(node_id, "node_id") } {
+        Ok(s) => s,
+        Err(_) => return 0,
     };
     
-    if fuzzy_match(&query_str, &target_str, threshold) {
-        1
-    } else {
-        0
-    }
+    if unsafe { index_mut(index_ptr).mark_deleted(node_id_str, deleted_at_ms) } { 1 } else { 0 }
 }
 
-/// Calculate Jaro-Winkler similarity
-/// Returns similarity score (0.0 to 1.0)
+/// Rename an indexed document without re-sending it
+///
+/// # Arguments
+/// * `index_ptr` - Index holding the document
+/// * `node_id` - Document to rename
+/// * `new_name` - New display name
+///
+/// # Returns
+/// 1 if the document was renamed, 0 if it is not indexed or on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
-pub extern "C" fn similarity_score(
-    query: *const c_char,
-    target: *const c_char,
-) -> f64 {
-    let query_str = if query.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(query).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0.0,
-        }
-    };
-    
-    let target_str = if target.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(target).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0.0,
+pub extern "C" fn rename_document_in_index(
+    index_ptr: *mut SearchIndex,
+    node_id: *const c_char,
+    new_name: *const c_char,
+) -> i32 {
+    if index_ptr.is_null() {
+        return 0;
+    }
+    
+    let (node_id_str, new_name_str) = match unsafe {
+        (ffi_str_in(node_id, "node_id"), ffi_str_in(new_name, "new_name"))
+    } {
+        (Ok(id), Ok(name)) => (id, name),
+        _ => return 0,
+    };
+    
+    if unsafe { index_mut(index_ptr).rename_document(node_id_str, new_name_str) } { 1 } else { 0 }
+}
+
+/// Move an indexed document under a new parent without re-sending it
+///
+/// # Arguments
+/// * `index_ptr` - Index holding the document
+/// * `node_id` - Document to move
+/// * `new_parent_id` - New parent node_id (null to make it a root)
+///
+/// # Returns
+/// 1 if the document was moved, 0 if it is not indexed or on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn move_document_in_index(
+    index_ptr: *mut SearchIndex,
+    node_id: *const c_char,
+    new_parent_id: *const c_char,
+) -> i32 {
+    if index_ptr.is_null() {
+        return 0;
+    }
+    
+    let node_id_str = match unsafe { ffi_str_in(node_id, "node_id") } {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+    let parent = match unsafe { ffi_opt_str_in(new_parent_id, "new_parent_id") } {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+    
+    if unsafe { index_mut(index_ptr).move_document(node_id_str, parent) } { 1 } else { 0 }
+}
+
+/// Remove documents marked deleted before `older_than_ms` from the index
+/// Returns the number of documents removed
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn purge_index_tombstones(index_ptr: *mut SearchIndex, older_than_ms: u64) -> usize {
+    if index_ptr.is_null() {
+        return 0;
+    }
+    unsafe { index_mut(index_ptr).purge_tombstones(older_than_ms) }
+}
+
+/// Remove every document of an account from the index
+///
+/// Drops the account's shard as a whole; other accounts are not touched.
+///
+/// # Arguments
+/// * `index_ptr` - Index holding the documents
+/// * `account_id` - Account whose documents are removed
+///
+/// # Returns
+/// The number of documents removed, 0 on error or for an unknown account
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn remove_account_from_index(index_ptr: *mut SearchIndex, account_id: *const c_char) -> usize {
+    if index_ptr.is_null() {
+        return 0;
+    }
+    match unsafe { ffi_str_in(account_id, "account_id") } {
+        Ok(account_id) => unsafe { index_mut(index_ptr).remove_account(account_id) },
+        Err(_) => 0,
+    }
+}
+
+/// Get the number of documents marked deleted but not yet purged
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn get_tombstone_count(index_ptr: *mut SearchIndex) -> usize {
+    if index_ptr.is_null() {
+        return 0;
+    }
+    unsafe { index_ref(index_ptr).tombstone_count() }
+}
+
+/// Set how many recent queries the index caches (0 disables the cache)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn set_query_cache_size(index_ptr: *mut SearchIndex, entries: usize) -> i32 {
+    if index_ptr.is_null() {
+        return 0;
+    }
+    unsafe { index_mut(index_ptr).set_query_cache_size(entries); }
+    1
+}
+
+/// Reserve room for `additional` documents before a large load
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn reserve_search_index(index_ptr: *mut SearchIndex, additional: usize) -> i32 {
+    if index_ptr.is_null() {
+        return 0;
+    }
+    unsafe { index_mut(index_ptr).reserve(additional); }
+    1
+}
+
+/// Get index statistics as JSON, including query cache hit/miss counters
+/// Returns JSON string (must be freed with free_c_string), null on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn get_index_stats(index_ptr: *mut SearchIndex) -> *mut c_char {
+    if index_ptr.is_null() {
+        return ptr::null_mut();
+    }
+    
+    let stats = unsafe { index_ref(index_ptr).stats() };
+    match serde_json::to_string(&stats) {
+        Ok(json) => ffi_string_out(&json),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Set history term boosts from a JSON object of {"term": multiplier}
+/// Multipliers are clamped to 1.0..=1.2, so boosted partial matches never
+/// outrank exact matches; an empty object clears the boosts
+/// Returns 1 on success, 0 on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn set_term_boosts_json(index_ptr: *mut SearchIndex, json: *const c_char) -> i32 {
+    if index_ptr.is_null() {
+        return 0;
+    }
+    
+    let json_str = match unsafe { ffi_str_in(json, "json") } {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+    let boosts: HashMap<String, f64> = match serde_json::from_str(json_str) {
+        Ok(boosts) => boosts,
+        Err(_) => return 0,
+    };
+    
+    unsafe { index_mut(index_ptr).set_term_boosts(boosts); }
+    1
+}
+
+/// Explain the exact-search score of a document for a query
+/// Returns a JSON envelope (see ffi_util.rs) whose data is
+/// {node_id, name, base_score, term_boost, boosted_term, score}; fails with
+/// ERROR_NOT_FOUND if the document does not match. Must be freed with free_c_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn explain_search_score_json(
+    index_ptr: *mut SearchIndex,
+    node_id: *const c_char,
+    query: *const c_char,
+) -> *mut c_char {
+    let explanation = (|| {
+        if index_ptr.is_null() {
+            return Err(ErrorEnvelope::null_argument("index_ptr"));
+        }
+        let node_id_str = unsafe { envelope_str(node_id, "node_id") }?;
+        let query_str = unsafe { envelope_str(query, "query") }?;
+        unsafe { index_ref(index_ptr).explain_score(node_id_str, query_str) }.ok_or_else(|| {
+            ErrorEnvelope::new(ERROR_NOT_FOUND, "document does not match the query").with_context(node_id_str)
+        })
+    })();
+    json_envelope(explanation, ptr::null_mut())
+}
+
+/// Explain the exact-search score of a document under a scoring profile
+///
+/// # Arguments
+/// * `index_ptr` - Search index
+/// * `node_id` - Document to explain
+/// * `query` - Search query
+/// * `profile` - Built-in profile name ("default", "palette", "browser") or a
+///   JSON object of ScoringProfile weights (null for "default")
+/// * `out_len` - Receives the JSON length in bytes (can be null)
+///
+/// # Returns
+/// A JSON envelope (see ffi_util.rs) whose data is {node_id, name, profile,
+/// base_score, term_boost, boosted_term, folder_boost, score}. Fails with
+/// ERROR_INVALID_SCORING_PROFILE for an invalid profile and ERROR_NOT_FOUND
+/// if the document does not match. Must be freed with free_c_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn explain_search_score_with_profile_json(
+    index_ptr: *mut SearchIndex,
+    node_id: *const c_char,
+    query: *const c_char,
+    profile: *const c_char,
+    out_len: *mut usize,
+) -> *mut c_char {
+    let explanation = (|| {
+        if index_ptr.is_null() {
+            return Err(ErrorEnvelope::null_argument("index_ptr"));
         }
+        let node_id_str = unsafe { envelope_str(node_id, "node_id") }?;
+        let query_str = unsafe { envelope_str(query, "query") }?;
+        let profile = match unsafe { ffi_opt_str_in(profile, "profile") } {
+            Ok(Some(text)) => ScoringProfile::parse(text)
+                .map_err(|e| ErrorEnvelope::new(ERROR_INVALID_SCORING_PROFILE, e).with_context("profile"))?,
+            Ok(None) => ScoringProfile::DEFAULT,
+            Err(_) => return Err(ErrorEnvelope::invalid_string("profile")),
+        };
+        unsafe { index_ref(index_ptr).explain_score_with_profile(node_id_str, query_str, &profile) }.ok_or_else(|| {
+            ErrorEnvelope::new(ERROR_NOT_FOUND, "document does not match the query").with_context(node_id_str)
+        })
+    })();
+    json_envelope(explanation, out_len)
+}
+
+/// Group documents with the same name (case-insensitive) across accounts
+/// Returns a JSON envelope (see ffi_util.rs) whose data is an array of
+/// {name, documents} groups with at least `min_count` members, largest first,
+/// at most `limit` groups (0 for no limit); `out_len` receives the JSON length
+/// in bytes. Must be freed with free_c_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn find_duplicate_names_json(
+    index_ptr: *mut SearchIndex,
+    min_count: usize,
+    limit: usize,
+    out_len: *mut usize,
+) -> *mut c_char {
+    if index_ptr.is_null() {
+        return json_envelope::<()>(Err(ErrorEnvelope::null_argument("index_ptr")), out_len);
+    }
+    
+    let index = unsafe { index_ref(index_ptr) };
+    json_out(&index.find_name_duplicates(min_count, limit), out_len)
+}
+
+/// Export index diagnostics for bug reports
+///
+/// # Arguments
+/// * `index_ptr` - Search index
+/// * `redact` - 1 to replace names with pseudonyms, 0 to report them as is
+/// * `out_len` - Receives the JSON length in bytes (can be null)
+///
+/// # Returns
+/// A JSON envelope (see ffi_util.rs) whose data is {redacted, stats,
+/// token_length_histogram, posting_size_histogram, slowest_names, accounts}.
+/// Redacted names are HMAC-SHA256 pseudonyms under a key generated for this
+/// export: the same name gets the same pseudonym within the export, but
+/// pseudonyms cannot be matched across exports. Must be freed with free_c_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn export_index_diagnostics(index_ptr: *mut SearchIndex, redact: i32, out_len: *mut usize) -> *mut c_char {
+    if index_ptr.is_null() {
+        return json_error(ErrorEnvelope::null_argument("index_ptr"), out_len);
+    }
+
+    let index = unsafe { index_ref(index_ptr) };
+    let redactor = if redact != 0 { Some(Pseudonymizer::new()) } else { None };
+    json_out(&index.diagnostics(redactor.as_ref()), out_len)
+}
+
+// ============================================================================
+// Fuzzy matching FFI functions (standalone - don't require index)
+// ============================================================================
+
+/// Fuzzy match two strings
+/// Returns 1 if match, 0 if not (threshold = 0.7)
+#[no_mangle]
+pub extern "C" fn fuzzy_match_strings(
+    query: *const c_char,
+    target: *const c_char,
+    threshold: f64,
+) -> i32 {
+    let query_str = match unsafe { ffi_opt_str_in(query, "query") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
+    };
+    
+    let target_str = match unsafe { ffi_opt_str_in(target, "target") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
+    };
+    
+    if fuzzy_match(query_str, target_str, threshold) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Calculate Jaro-Winkler similarity
+/// Returns similarity score (0.0 to 1.0)
+#[no_mangle]
+pub extern "C" fn similarity_score(
+    query: *const c_char,
+    target: *const c_char,
+) -> f64 {
+    let query_str = match unsafe { ffi_opt_str_in(query, "query") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0.0,
+    };
+    
+    let target_str = match unsafe { ffi_opt_str_in(target, "target") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0.0,
     };
     
-    jaro_winkler_similarity(&query_str, &target_str)
+    jaro_winkler_similarity(query_str, target_str)
 }
 
 /// Calculate Levenshtein distance
@@ -520,59 +1120,100 @@ pub extern "C" fn levenshtein(
     s1: *const c_char,
     s2: *const c_char,
 ) -> usize {
-    let s1_str = if s1.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(s1).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
-        }
+    let s1_str = match unsafe { ffi_opt_str_in(s1, "s1") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
     };
     
-    let s2_str = if s2.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(s2).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return 0,
-        }
+    let s2_str = match unsafe { ffi_opt_str_in(s2, "s2") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
     };
     
-    levenshtein_distance(&s1_str, &s2_str)
+    levenshtein_distance(s1_str, s2_str)
 }
 
 /// Calculate Soundex code
 /// Returns pointer to Soundex code string (caller must free)
 #[no_mangle]
 pub extern "C" fn soundex_code(word: *const c_char) -> *mut c_char {
-    let word_str = if word.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(word).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return ptr::null_mut(),
-        }
+    let word_str = match unsafe { ffi_opt_str_in(word, "word") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return ptr::null_mut(),
     };
     
-    let code = soundex(&word_str);
-    CString::new(code).unwrap().into_raw()
+    code_to_c_string(soundex(word_str))
 }
 
 /// Calculate Metaphone code
 /// Returns pointer to Metaphone code string (caller must free)
 #[no_mangle]
 pub extern "C" fn metaphone_code(word: *const c_char) -> *mut c_char {
-    let word_str = if word.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(word).to_str() } {
-            Ok(s) => s.to_string(),
+    let word_str = match unsafe { ffi_opt_str_in(word, "word") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return ptr::null_mut(),
+    };
+    
+    code_to_c_string(metaphone(word_str))
+}
+
+/// phonetic_codes_batch algorithm: Soundex
+pub const PHONETIC_SOUNDEX: u32 = 0;
+/// phonetic_codes_batch algorithm: Metaphone
+pub const PHONETIC_METAPHONE: u32 = 1;
+
+/// Convert a phonetic code to a C string
+/// Codes keep the first letter of the word as given, so NUL bytes are dropped
+/// rather than failing the conversion
+fn code_to_c_string(code: String) -> *mut c_char {
+    let code = if code.contains('\0') { code.replace('\0', "") } else { code };
+    ffi_string_out(&code)
+}
+
+/// Calculate phonetic codes for many words in one call
+///
+/// `words_json` is a JSON array of strings; the result is a JSON array of codes
+/// in the same order. Every word yields a code:
+/// - Empty strings give "0000" (Soundex) or "" (Metaphone)
+/// - Only ASCII letters carry sounds; other characters are skipped, except that
+///   Soundex keeps the uppercased first character of the word as its leading
+///   letter, whatever it is (so "Émile" gives "É540")
+/// - NUL characters are dropped from the codes
+///
+/// `algorithm` is PHONETIC_SOUNDEX or PHONETIC_METAPHONE; `out_len` receives the
+/// JSON length in bytes.
+/// Returns JSON string (must be freed with free_c_string), null on invalid input
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn phonetic_codes_batch(
+    words_json: *const c_char,
+    algorithm: u32,
+    out_len: *mut usize,
+) -> *mut c_char {
+    let words: Vec<String> = match unsafe { ffi_str_in(words_json, "words_json") } {
+        Ok(s) => match serde_json::from_str(s) {
+            Ok(words) => words,
             Err(_) => return ptr::null_mut(),
-        }
+        },
+        Err(_) => return ptr::null_mut(),
+    };
+    
+    let encode: fn(&str) -> String = match algorithm {
+        PHONETIC_SOUNDEX => soundex,
+        PHONETIC_METAPHONE => metaphone,
+        _ => return ptr::null_mut(),
+    };
+    
+    let codes: Vec<String> = words.iter().map(|w| encode(w).replace('\0', "")).collect();
+    let json = match serde_json::to_string(&codes) {
+        Ok(json) => json,
+        Err(_) => return ptr::null_mut(),
     };
     
-    let code = metaphone(&word_str);
-    CString::new(code).unwrap().into_raw()
+    if !out_len.is_null() {
+        unsafe { *out_len = json.len(); }
+    }
+    ffi_string_out(&json)
 }
 
 /// Free a C string allocated by Rust
@@ -593,69 +1234,245 @@ pub extern "C" fn free_c_string(s: *mut c_char) {
 // Phase 2: Path Building FFI (using SearchIndex directly)
 // ============================================================================
 
+/// Deepest parent chain followed when building a path
+pub const MAX_PATH_DEPTH: usize = 256;
+
+/// build_path_ex status: the chain reached a root
+pub const PATH_COMPLETE: i32 = 0;
+
+/// build_path_ex status: the chain ended at a parent that is not in the index
+pub const PATH_TRUNCATED: i32 = 1;
+
+/// build_path_ex status: the chain is longer than MAX_PATH_DEPTH
+pub const PATH_TOO_DEEP: i32 = -1;
+
 /// Build path from node to root (uses SearchIndex directly)
+/// A null or invalid separator uses "/". Returns null on error
 #[no_mangle]
 pub extern "C" fn build_path(
     index_ptr: *mut SearchIndex,
     node_id: *const c_char,
     separator: *const c_char,
+) -> *mut c_char {
+    let sep = match unsafe { ffi_opt_str_in(separator, "separator") } {
+        Ok(_) => separator,
+        Err(_) => ptr::null(),
+    };
+    build_path_ex(index_ptr, node_id, sep, 0, ptr::null_mut())
+}
+
+/// Build path from node to root, reporting chains cut short by missing parents
+///
+/// # Arguments
+/// * `index_ptr` - Index to resolve parents in
+/// * `node_id` - Node to build the path of
+/// * `separator` - Separator between components (null for "/")
+/// * `include_account` - Non-zero to prefix "email (provider)" from the node's own document
+/// * `out_truncated` - Receives PATH_COMPLETE, PATH_TRUNCATED when the chain ended at a
+///   parent_id missing from the index (or at a parent cycle, or when the node itself is
+///   unknown), or PATH_TOO_DEEP (may be null)
+///
+/// # Returns
+/// Path string (free with free_c_string), or null on error, including a chain
+/// deeper than MAX_PATH_DEPTH
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn build_path_ex(
+    index_ptr: *mut SearchIndex,
+    node_id: *const c_char,
+    separator: *const c_char,
+    include_account: i32,
+    out_truncated: *mut i32,
 ) -> *mut c_char {
     if index_ptr.is_null() {
         return ptr::null_mut();
     }
     
-    let index = unsafe { &mut *index_ptr };
+    let index = unsafe { index_ref(index_ptr) };
     
-    let node_id_str = if node_id.is_null() {
-        String::new()
-    } else {
-        match unsafe { CStr::from_ptr(node_id).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => return ptr::null_mut(),
-        }
+    let node_id_str = match unsafe { ffi_opt_str_in(node_id, "node_id") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return ptr::null_mut(),
     };
     
-    let sep = if separator.is_null() {
-        String::from("/")
-    } else {
-        match unsafe { CStr::from_ptr(separator).to_str() } {
-            Ok(s) => s.to_string(),
-            Err(_) => String::from("/"),
+    let sep = match unsafe { ffi_opt_str_in(separator, "separator") } {
+        Ok(s) => s.unwrap_or("/"),
+        Err(_) => return ptr::null_mut(),
+    };
+    
+    let (chain, truncated) = match path_chain(index, node_id_str) {
+        Some(result) => result,
+        None => {
+            if !out_truncated.is_null() {
+                unsafe { *out_truncated = PATH_TOO_DEEP; }
+            }
+            return ptr::null_mut();
         }
     };
+    if !out_truncated.is_null() {
+        unsafe { *out_truncated = if truncated { PATH_TRUNCATED } else { PATH_COMPLETE }; }
+    }
     
-    // Build path by traversing parent relationships
-    let parts = build_path_from_index(index, &node_id_str);
-    let path = parts.join(&sep);
-    CString::new(path).unwrap().into_raw()
+    let mut parts: Vec<String> = Vec::with_capacity(chain.len() + 1);
+    if include_account != 0 {
+        if let Some(doc) = chain.first() {
+            parts.push(format!("{} ({})", doc.email, doc.provider));
+        }
+    }
+    parts.extend(chain.iter().rev().map(|doc| doc.name.clone()));
+    ffi_string_out(&parts.join(sep))
 }
 
-/// Helper function to build path from index
-fn build_path_from_index(index: &SearchIndex, node_id: &str) -> Vec<String> {
-    let mut parts = Vec::new();
-    let mut current_id = node_id;
+/// Documents from `node_id` up to its root, and whether the chain was cut short
+/// (missing node or parent, or a parent cycle). None when deeper than MAX_PATH_DEPTH
+fn path_chain<'a>(index: &'a SearchIndex, node_id: &'a str) -> Option<(Vec<&'a SearchDocument>, bool)> {
+    let mut chain = Vec::new();
     let mut visited = std::collections::HashSet::new();
+    let mut current_id = Some(node_id);
     
-    loop {
-        if visited.contains(current_id) {
-            break;
+    while let Some(id) = current_id {
+        let doc = match index.get(id) {
+            Some(doc) => doc,
+            None => return Some((chain, true)),
+        };
+        if !visited.insert(id) {
+            return Some((chain, true));
         }
-        visited.insert(current_id.to_string());
-        
-        if let Some(doc) = index.get(current_id) {
-            parts.push(doc.name.clone());
-            if let Some(parent_id) = &doc.parent_id {
-                current_id = parent_id;
-            } else {
-                break;
-            }
-        } else {
-            break;
+        if chain.len() == MAX_PATH_DEPTH {
+            return None;
         }
+        chain.push(doc);
+        current_id = doc.parent_id.as_deref();
     }
     
-    parts.reverse();
-    parts
+    Some((chain, false))
+}
+
+/// List the children of a folder, for browsing the index offline
+///
+/// # Arguments
+/// * `index_ptr` - Search index
+/// * `parent_id` - Folder whose children are listed (null for root documents)
+/// * `sort` - A SORT_LOCALE_* name order, optionally ORed with CHILDREN_FOLDERS_FIRST
+/// * `offset` - Number of sorted children to skip
+/// * `limit` - Largest number of children returned (0 for no limit)
+/// * `out_len` - Receives the JSON length in bytes (can be null)
+///
+/// # Returns
+/// A JSON envelope (see ffi_util.rs) whose data is {total, offset, children}:
+/// `total` counts every child not marked deleted, `children` holds the
+/// SearchDocuments of the page. Fails with ERROR_INVALID_SORT_LOCALE for an
+/// unknown sort. Must be freed with free_c_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn get_children_json(
+    index_ptr: *mut SearchIndex,
+    parent_id: *const c_char,
+    sort: i32,
+    offset: usize,
+    limit: usize,
+    out_len: *mut usize,
+) -> *mut c_char {
+    if index_ptr.is_null() {
+        return json_error(ErrorEnvelope::null_argument("index_ptr"), out_len);
+    }
+    let parent_id = match unsafe { ffi_opt_str_in(parent_id, "parent_id") } {
+        Ok(parent_id) => parent_id,
+        Err(_) => return json_error(ErrorEnvelope::invalid_string("parent_id"), out_len),
+    };
+
+    let index = unsafe { index_ref(index_ptr) };
+    match index.get_children(parent_id, sort, offset, limit) {
+        Some(page) => json_out(&page, out_len),
+        None => json_error(
+            ErrorEnvelope::new(ERROR_INVALID_SORT_LOCALE, format!("unknown children sort {}", sort)),
+            out_len,
+        ),
+    }
+}
+
+/// Export the indexed documents in a stable order, for backups and debugging
+///
+/// Documents are ordered by (account_id, parent_id, name, node_id), so two
+/// exports of the same index are byte-identical and pages never overlap.
+///
+/// # Arguments
+/// * `index_ptr` - Search index
+/// * `account_id` - Account whose documents are exported (null for every account)
+/// * `offset` - Number of sorted documents to skip
+/// * `limit` - Largest number of documents returned (0 for no limit)
+/// * `out_len` - Receives the JSON length in bytes (can be null)
+///
+/// # Returns
+/// A JSON envelope (see ffi_util.rs) whose data is {total, offset, documents}:
+/// `total` counts every exported document, including those marked deleted,
+/// and `documents` holds the SearchDocuments of the page. Must be freed with
+/// free_c_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn export_documents_json(
+    index_ptr: *mut SearchIndex,
+    account_id: *const c_char,
+    offset: usize,
+    limit: usize,
+    out_len: *mut usize,
+) -> *mut c_char {
+    if index_ptr.is_null() {
+        return json_error(ErrorEnvelope::null_argument("index_ptr"), out_len);
+    }
+    let account_id = match unsafe { ffi_opt_str_in(account_id, "account_id") } {
+        Ok(account_id) => account_id,
+        Err(_) => return json_error(ErrorEnvelope::invalid_string("account_id"), out_len),
+    };
+
+    let index = unsafe { index_ref(index_ptr) };
+    json_out(&index.export_documents(account_id, offset, limit), out_len)
+}
+
+/// List the ancestors of a node for a breadcrumb
+///
+/// Follows the same loop-protected parent walk as build_path_ex.
+///
+/// # Arguments
+/// * `index_ptr` - Search index
+/// * `node_id` - Node whose ancestors are listed
+/// * `out_len` - Receives the JSON length in bytes (can be null)
+///
+/// # Returns
+/// A JSON envelope (see ffi_util.rs) whose data is {ancestors, truncated}:
+/// the SearchDocuments of the node's parent, its parent and so on up to the
+/// root, and whether the walk stopped at a parent missing from the index or
+/// at a parent cycle. Fails with ERROR_NOT_FOUND for an unknown node and
+/// ERROR_MAX_DEPTH_EXCEEDED for a chain deeper than MAX_PATH_DEPTH.
+/// Must be freed with free_c_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn get_ancestors_json(index_ptr: *mut SearchIndex, node_id: *const c_char, out_len: *mut usize) -> *mut c_char {
+    #[derive(serde::Serialize)]
+    struct Ancestors<'a> {
+        ancestors: &'a [&'a SearchDocument],
+        truncated: bool,
+    }
+
+    if index_ptr.is_null() {
+        return json_error(ErrorEnvelope::null_argument("index_ptr"), out_len);
+    }
+    let node_id = match unsafe { envelope_str(node_id, "node_id") } {
+        Ok(node_id) => node_id,
+        Err(error) => return json_error(error, out_len),
+    };
+
+    let index = unsafe { index_ref(index_ptr) };
+    if index.get(node_id).is_none() {
+        return json_error(ErrorEnvelope::new(ERROR_NOT_FOUND, "node is not indexed").with_context(node_id), out_len);
+    }
+    match path_chain(index, node_id) {
+        Some((chain, truncated)) => json_out(&Ancestors { ancestors: &chain[1..], truncated }, out_len),
+        None => json_error(
+            ErrorEnvelope::new(ERROR_MAX_DEPTH_EXCEEDED, "parent chain is deeper than MAX_PATH_DEPTH").with_context(node_id),
+            out_len,
+        ),
+    }
 }
 
 // ============================================================================
@@ -666,18 +1483,13 @@ fn build_path_from_index(index: &SearchIndex, node_id: &str) -> Vec<String> {
 #[no_mangle]
 pub extern "C" fn create_batch_indexer(batch_size: usize) -> *mut SearchIndex {
     // Use SearchIndex directly for batch operations
-    let index = Box::new(SearchIndex::new());
-    Box::into_raw(index)
+    track_index(Box::default())
 }
 
 /// Free batch indexer
 #[no_mangle]
 pub extern "C" fn free_batch_indexer(indexer_ptr: *mut SearchIndex) {
-    if !indexer_ptr.is_null() {
-        unsafe {
-            let _ = Box::from_raw(indexer_ptr);
-        }
-    }
+    free_tracked_index(indexer_ptr);
 }
 
 /// Commit batch to search index (no-op since we use SearchIndex directly)
@@ -697,18 +1509,13 @@ pub extern "C" fn batch_indexer_commit(
 /// Create incremental indexer (uses SearchIndex directly)
 #[no_mangle]
 pub extern "C" fn create_incremental_indexer() -> *mut SearchIndex {
-    let index = Box::new(SearchIndex::new());
-    Box::into_raw(index)
+    track_index(Box::default())
 }
 
 /// Free incremental indexer
 #[no_mangle]
 pub extern "C" fn free_incremental_indexer(indexer_ptr: *mut SearchIndex) {
-    if !indexer_ptr.is_null() {
-        unsafe {
-            let _ = Box::from_raw(indexer_ptr);
-        }
-    }
+    free_tracked_index(indexer_ptr);
 }
 
 /// Mark document for re-indexing (no-op for SearchIndex)
@@ -732,106 +1539,1324 @@ pub extern "C" fn incremental_indexer_get_pending_count(_indexer_ptr: *mut Searc
 // Phase 2: Suggestions FFI
 // ============================================================================
 
-/// Create suggestion engine (simplified - returns null since we use Dart implementation)
+/// Create suggestion engine
+/// Pass 0 for either limit to use the defaults (10 suggestions, 20 prefix chars)
+/// Returns pointer to engine (free with free_suggestion_engine)
 #[no_mangle]
 pub extern "C" fn create_suggestion_engine(
-    _max_suggestions: usize,
-    _max_prefix_length: usize,
-) -> *mut c_void {
-    ptr::null_mut()
+    max_suggestions: usize,
+    max_prefix_length: usize,
+) -> *mut SuggestionEngine {
+    let engine = if max_suggestions == 0 || max_prefix_length == 0 {
+        SuggestionEngine::default()
+    } else {
+        SuggestionEngine::new(max_suggestions, max_prefix_length)
+    };
+    Box::into_raw(Box::new(engine))
 }
 
-/// Free suggestion engine (no-op)
+/// Free suggestion engine memory
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
-pub extern "C" fn free_suggestion_engine(_engine_ptr: *mut c_void) {
-    // No-op since we don't create a real engine
+pub extern "C" fn free_suggestion_engine(engine_ptr: *mut SuggestionEngine) {
+    if !engine_ptr.is_null() {
+        unsafe {
+            let _ = Box::from_raw(engine_ptr);
+        }
+    }
 }
 
-/// Add suggestion (no-op)
+/// Add suggestion
+/// Returns 1 on success, 0 on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn suggestion_engine_add_suggestion(
-    _engine_ptr: *mut c_void,
-    _text: *const c_char,
-    _frequency: usize,
+    engine_ptr: *mut SuggestionEngine,
+    text: *const c_char,
+    frequency: usize,
 ) -> i32 {
-    // No-op - suggestions are handled in Dart
+    if engine_ptr.is_null() {
+        return 0;
+    }
+    
+    let text_str = match unsafe { ffi_str_in(text, "text") } {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+    
+    unsafe { (*engine_ptr).add_suggestion(text_str, frequency); }
     1
 }
 
-/// Get suggestions (returns empty array)
+/// Record that a suggestion was used (recency boost)
+/// Returns 1 on success, 0 on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
-pub extern "C" fn suggestion_engine_get_suggestions(
-    _engine_ptr: *mut c_void,
-    _prefix: *const c_char,
-    _limit: usize,
-    results_out: *mut *mut c_char,
-    results_count: *mut usize,
+pub extern "C" fn suggestion_engine_record_usage(
+    engine_ptr: *mut SuggestionEngine,
+    text: *const c_char,
 ) -> i32 {
-    // Return empty results
-    unsafe {
-        *results_out = ptr::null_mut();
-        *results_count = 0;
+    if engine_ptr.is_null() {
+        return 0;
     }
+    
+    let text_str = match unsafe { ffi_str_in(text, "text") } {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+    
+    unsafe { (*engine_ptr).record_usage(text_str); }
     1
 }
 
-/// Free suggestion results
+/// Get suggestions for a prefix, best first
+/// Returns 1 on success, 0 on error (results_out must be freed with free_suggestion_results)
 #[no_mangle]
-pub extern "C" fn free_suggestion_results(results: *mut *mut c_char, _count: usize) {
-    if results.is_null() {
-        return;
+pub extern "C" fn suggestion_engine_get_suggestions(
+    engine_ptr: *mut SuggestionEngine,
+    prefix: *const c_char,
+    limit: usize,
+    results_out: *mut *mut *mut c_char,
+    results_count: *mut usize,
+) -> i32 {
+    if engine_ptr.is_null() || results_out.is_null() || results_count.is_null() {
+        return 0;
     }
-    unsafe {
-        libc::free(results as *mut c_void);
+    
+    let prefix_str = match unsafe { ffi_opt_str_in(prefix, "prefix") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return 0,
+    };
+    
+    let suggestions = unsafe { (*engine_ptr).get_prefix_suggestions(prefix_str, limit) };
+    checked(write_string_array(suggestions, results_out, results_count))
+}
+
+/// Get suggestions for a prefix, best first, as a JSON envelope (see ffi_util.rs)
+/// with data [{text, account_ids}]; account_ids lists the accounts the text was
+/// indexed from, for badging. `out_len` receives the JSON length in bytes.
+/// Must be freed with free_c_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn suggestion_engine_get_suggestions_json(
+    engine_ptr: *mut SuggestionEngine,
+    prefix: *const c_char,
+    limit: usize,
+    out_len: *mut usize,
+) -> *mut c_char {
+    if engine_ptr.is_null() {
+        return json_error(ErrorEnvelope::null_argument("engine_ptr"), out_len);
+    }
+    
+    let prefix_str = match unsafe { ffi_opt_str_in(prefix, "prefix") } {
+        Ok(s) => s.unwrap_or_default(),
+        Err(_) => return json_error(ErrorEnvelope::invalid_string("prefix"), out_len),
+    };
+    
+    let suggestions = unsafe { (*engine_ptr).get_account_suggestions(prefix_str, limit) };
+    json_out(&suggestions, out_len)
+}
+
+/// Clear the engine and repopulate it from the indexed documents of one account
+///
+/// Each document contributes its name and the names of its ancestor folders.
+/// Keep one engine per account to scope autocomplete to an account filter.
+///
+/// # Arguments
+/// * `engine_ptr` - Engine to rebuild
+/// * `index_ptr` - Index to read documents from
+/// * `account_id` - Account to take documents from, or null for all accounts
+///
+/// # Returns
+/// Number of distinct suggestions in the rebuilt engine, 0 on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn suggestion_engine_rebuild_from_index(
+    engine_ptr: *mut SuggestionEngine,
+    index_ptr: *mut SearchIndex,
+    account_id: *const c_char,
+) -> usize {
+    if engine_ptr.is_null() || index_ptr.is_null() {
+        return 0;
+    }
+    
+    let account_id_str = match unsafe { ffi_opt_str_in(account_id, "account_id") } {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+    
+    unsafe { (*engine_ptr).rebuild_from_index(index_ref(index_ptr), account_id_str) }
+}
+
+/// Hand `strings` to the caller as a malloc'd array of C strings
+/// (freed with free_suggestion_results). Returns 1 on success, 0 on error
+fn write_string_array(strings: Vec<String>, results_out: *mut *mut *mut c_char, results_count: *mut usize) -> i32 {
+    let count = strings.len();
+    
+    // Allocate results array
+    let results_array = unsafe {
+        libc::malloc(count.max(1) * std::mem::size_of::<*mut c_char>()) as *mut *mut c_char
+    };
+    
+    if results_array.is_null() {
+        unsafe { *results_count = 0; }
+        return 0;
+    }
+    
+    // Fill results array
+    for (i, text) in strings.into_iter().enumerate() {
+        let c_text = ffi_string_out(&text);
+        unsafe { results_array.add(i).write(c_text); }
+    }
+    
+    unsafe {
+        *results_out = results_array;
+        *results_count = count;
+    }
+    
+    1
+}
+
+/// Serialize `value` to a C string, storing its byte length in `out_len`
+/// Returns null on error
+fn json_out<T: serde::Serialize>(value: &T, out_len: *mut usize) -> *mut c_char {
+    json_envelope(Ok(value), out_len)
+}
+
+/// Error envelope for a null handle or an invalid string argument
+fn json_error(error: ErrorEnvelope, out_len: *mut usize) -> *mut c_char {
+    json_envelope::<()>(Err(error), out_len)
+}
+
+/// Free suggestion results
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn free_suggestion_results(results: *mut *mut c_char, count: usize) {
+    if results.is_null() {
+        return;
+    }
+    unsafe {
+        for i in 0..count {
+            let text = results.add(i).read();
+            if !text.is_null() {
+                let _ = CString::from_raw(text);
+            }
+        }
+        libc::free(results as *mut c_void);
+    }
+}
+
+/// Set the maximum number of entries kept by export and import (default 5000)
+/// Returns 1 on success, 0 on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn suggestion_engine_set_max_persisted(engine_ptr: *mut SuggestionEngine, max_entries: usize) -> i32 {
+    if engine_ptr.is_null() {
+        return 0;
+    }
+    unsafe { (*engine_ptr).set_max_persisted(max_entries); }
+    1
+}
+
+/// Export learned frequencies and the recently used list as a JSON envelope
+/// (see ffi_util.rs); keeps the most frequent entries up to the persisted cap.
+/// `out_len` receives the JSON length in bytes. Must be freed with free_c_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn suggestion_engine_export_json(
+    engine_ptr: *mut SuggestionEngine,
+    out_len: *mut usize,
+) -> *mut c_char {
+    if engine_ptr.is_null() {
+        return json_error(ErrorEnvelope::null_argument("engine_ptr"), out_len);
+    }
+    
+    let state = unsafe { (*engine_ptr).export_state() };
+    json_out(&state, out_len)
+}
+
+/// Import JSON from suggestion_engine_export_json (the envelope or its data)
+/// Frequencies are added to entries the engine already has
+/// Returns 1 on success, 0 on error (the engine is unchanged on error)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn suggestion_engine_import_json(
+    engine_ptr: *mut SuggestionEngine,
+    json: *const c_char,
+) -> i32 {
+    if engine_ptr.is_null() {
+        return 0;
     }
+    
+    let json_str = match unsafe { ffi_str_in(json, "json") } {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+    let state: SuggestionEngineState = match serde_json::from_str(json_str) {
+        Ok(state) => state,
+        Err(_) => match serde_json::from_str::<JsonEnvelope<SuggestionEngineState>>(json_str) {
+            Ok(JsonEnvelope { ok: true, data: Some(state), .. }) => state,
+            _ => return 0,
+        },
+    };
+    
+    unsafe { (*engine_ptr).import_state(state); }
+    1
 }
 
 // ============================================================================
 // Phase 2: Search History FFI
 // ============================================================================
 
-/// Create search history (simplified - returns null since we use Dart implementation)
+/// Create search history keeping at most `max_entries` searches (0 for 1000)
+/// Returns pointer to history (free with free_search_history)
 #[no_mangle]
-pub extern "C" fn create_search_history(_max_entries: usize) -> *mut c_void {
-    ptr::null_mut()
+pub extern "C" fn create_search_history(max_entries: usize) -> *mut SearchHistory {
+    let max_entries = if max_entries == 0 { 1000 } else { max_entries };
+    Box::into_raw(Box::new(SearchHistory::new(max_entries)))
 }
 
-/// Free search history (no-op)
+/// Free search history memory
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
-pub extern "C" fn free_search_history(_history_ptr: *mut c_void) {
-    // No-op
+pub extern "C" fn free_search_history(history_ptr: *mut SearchHistory) {
+    if !history_ptr.is_null() {
+        unsafe {
+            let _ = Box::from_raw(history_ptr);
+        }
+    }
 }
 
-/// Add search to history (no-op)
+/// Add search to history
+/// The scope is the account id, or "global" when null or empty
+/// Returns 1 on success, 0 on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn search_history_add(
-    _history_ptr: *mut c_void,
-    _query: *const c_char,
-    _account_id: *const c_char,
+    history_ptr: *mut SearchHistory,
+    query: *const c_char,
+    account_id: *const c_char,
 ) -> i32 {
-    // No-op - history is handled in Dart
+    if history_ptr.is_null() {
+        return 0;
+    }
+    
+    let query_str = match unsafe { ffi_str_in(query, "query") } {
+        Ok(s) => s.to_string(),
+        Err(_) => return 0,
+    };
+    let scope = match unsafe { ffi_opt_str_in(account_id, "account_id") } {
+        Ok(s) => s.unwrap_or_default().to_string(),
+        Err(_) => return 0,
+    };
+    let scope = if scope.is_empty() { "global".to_string() } else { scope };
+    
+    unsafe { (*history_ptr).record_search(query_str, 0, scope); }
     1
 }
 
-/// Get recent searches (returns empty array)
+/// Get recent search queries, newest first; `dedupe_recent` keeps each query once
+/// Returns 1 on success, 0 on error (results_out must be freed with free_suggestion_results)
 #[no_mangle]
 pub extern "C" fn search_history_get_recent(
-    _history_ptr: *mut c_void,
-    _limit: usize,
-    results_out: *mut *mut c_char,
+    history_ptr: *mut SearchHistory,
+    limit: usize,
+    dedupe_recent: bool,
+    results_out: *mut *mut *mut c_char,
     results_count: *mut usize,
 ) -> i32 {
-    // Return empty results
-    unsafe {
-        *results_out = ptr::null_mut();
-        *results_count = 0;
+    if history_ptr.is_null() || results_out.is_null() || results_count.is_null() {
+        return 0;
     }
-    1
+    
+    let queries = unsafe { (*history_ptr).get_recent(limit, dedupe_recent) }
+        .into_iter()
+        .map(|entry| entry.query.clone())
+        .collect();
+    checked(write_string_array(queries, results_out, results_count))
 }
 
-/// Clear search history (no-op)
+/// Get popular queries with time-decayed counts, best first, as a JSON
+/// envelope (see ffi_util.rs) with data [{query, score}]
+/// `half_life_days` <= 0 counts every stored search once
+/// Returns JSON string (must be freed with free_c_string)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
-pub extern "C" fn search_history_clear(_history_ptr: *mut c_void) -> i32 {
-    // No-op
+pub extern "C" fn search_history_get_popular_decayed_json(
+    history_ptr: *mut SearchHistory,
+    limit: usize,
+    half_life_days: f64,
+    out_len: *mut usize,
+) -> *mut c_char {
+    if history_ptr.is_null() {
+        return json_error(ErrorEnvelope::null_argument("history_ptr"), out_len);
+    }
+    
+    let popular = unsafe { (*history_ptr).get_popular_decayed(limit, half_life_days) };
+    json_out(&popular, out_len)
+}
+
+/// Get recent searches of one scope ("global" or an account id), newest first,
+/// as a JSON envelope (see ffi_util.rs) with data [{query, timestamp, result_count, scope}]
+/// Returns JSON string (must be freed with free_c_string)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn search_history_get_recent_for_scope_json(
+    history_ptr: *mut SearchHistory,
+    scope: *const c_char,
+    limit: usize,
+    out_len: *mut usize,
+) -> *mut c_char {
+    if history_ptr.is_null() {
+        return json_error(ErrorEnvelope::null_argument("history_ptr"), out_len);
+    }
+    
+    let scope_str = match unsafe { envelope_str(scope, "scope") } {
+        Ok(s) => s,
+        Err(e) => return json_error(e, out_len),
+    };
+    
+    let recent = unsafe { (*history_ptr).get_recent_for_scope(scope_str, limit) };
+    json_out(&recent, out_len)
+}
+
+/// Get popular queries of one scope, most searched first, as a JSON envelope
+/// (see ffi_util.rs) with data [{query, count}]
+/// Returns JSON string (must be freed with free_c_string)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn search_history_get_popular_for_scope_json(
+    history_ptr: *mut SearchHistory,
+    scope: *const c_char,
+    limit: usize,
+    out_len: *mut usize,
+) -> *mut c_char {
+    if history_ptr.is_null() {
+        return json_error(ErrorEnvelope::null_argument("history_ptr"), out_len);
+    }
+    
+    let scope_str = match unsafe { envelope_str(scope, "scope") } {
+        Ok(s) => s,
+        Err(e) => return json_error(e, out_len),
+    };
+    
+    let popular = unsafe { (*history_ptr).get_popular_for_scope(scope_str, limit) };
+    json_out(&popular, out_len)
+}
+
+/// Clear search history
+/// Returns 1 on success, 0 on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn search_history_clear(history_ptr: *mut SearchHistory) -> i32 {
+    if history_ptr.is_null() {
+        return 0;
+    }
+    unsafe { (*history_ptr).clear(); }
     1
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    fn batch(words_json: &str, algorithm: u32) -> Vec<String> {
+        let input = CString::new(words_json).unwrap();
+        let mut len = 0usize;
+        let out = phonetic_codes_batch(input.as_ptr(), algorithm, &mut len);
+        assert!(!out.is_null());
+        let json = unsafe { CStr::from_ptr(out) }.to_str().unwrap().to_string();
+        free_c_string(out);
+        assert_eq!(json.len(), len);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_phonetic_batch_keeps_order() {
+        let words = ["Robert", "Tymczak", "Ashcraft", "Knight", "Phone"];
+        let json = serde_json::to_string(&words).unwrap();
+
+        let soundex_codes = batch(&json, PHONETIC_SOUNDEX);
+        let expected: Vec<String> = words.iter().map(|w| soundex(w)).collect();
+        assert_eq!(soundex_codes, expected);
+
+        let metaphone_codes = batch(&json, PHONETIC_METAPHONE);
+        let expected: Vec<String> = words.iter().map(|w| metaphone(w)).collect();
+        assert_eq!(metaphone_codes, expected);
+
+        // Unknown algorithm and malformed input are rejected
+        let input = CString::new(json).unwrap();
+        assert!(phonetic_codes_batch(input.as_ptr(), 7, ptr::null_mut()).is_null());
+        let bad = CString::new("[1, 2]").unwrap();
+        assert!(phonetic_codes_batch(bad.as_ptr(), PHONETIC_SOUNDEX, ptr::null_mut()).is_null());
+    }
+
+    #[test]
+    fn test_phonetic_empty_and_unicode() {
+        assert_eq!(batch("[\"\"]", PHONETIC_SOUNDEX), vec!["0000"]);
+        assert_eq!(batch("[\"\"]", PHONETIC_METAPHONE), vec![""]);
+        assert!(batch("[]", PHONETIC_SOUNDEX).is_empty());
+
+        // Non-ASCII words never panic and give the same code every time
+        let json = r#"["Émile", "Zoë", "日本語", "😀smile", "straße", "a\u0000b"]"#;
+        for algorithm in [PHONETIC_SOUNDEX, PHONETIC_METAPHONE] {
+            let codes = batch(json, algorithm);
+            assert_eq!(codes.len(), 6);
+            assert_eq!(codes, batch(json, algorithm));
+            assert!(codes.iter().all(|c| !c.contains('\0')));
+        }
+        assert_eq!(batch(r#"["Émile"]"#, PHONETIC_SOUNDEX), vec!["É540"]);
+
+        // Single-word functions accept empty and null input
+        let empty = CString::new("").unwrap();
+        let code = soundex_code(empty.as_ptr());
+        assert_eq!(unsafe { CStr::from_ptr(code) }.to_str().unwrap(), "0000");
+        free_c_string(code);
+        let code = metaphone_code(ptr::null());
+        assert_eq!(unsafe { CStr::from_ptr(code) }.to_str().unwrap(), "");
+        free_c_string(code);
+    }
+
+    fn suggestions(engine: *mut SuggestionEngine, prefix: &str) -> Vec<String> {
+        let prefix = CString::new(prefix).unwrap();
+        let mut results: *mut *mut c_char = ptr::null_mut();
+        let mut count = 0usize;
+        assert_eq!(suggestion_engine_get_suggestions(engine, prefix.as_ptr(), 10, &mut results, &mut count), 1);
+        let texts = (0..count)
+            .map(|i| unsafe { CStr::from_ptr(*results.add(i)) }.to_str().unwrap().to_string())
+            .collect();
+        free_suggestion_results(results, count);
+        texts
+    }
+
+    #[test]
+    fn test_term_boosts_json_and_explain() {
+        let index = create_search_index();
+        unsafe {
+            (*index).add_document(SearchDocument {
+                node_id: "n1".to_string(),
+                account_id: "acc".to_string(),
+                provider: "gdrive".to_string(),
+                email: String::new(),
+                name: "Tax return 2024.pdf".to_string(),
+                is_folder: false,
+                parent_id: None,
+            });
+        }
+        let boosts = CString::new(r#"{"tax": 1.1}"#).unwrap();
+        assert_eq!(set_term_boosts_json(index, boosts.as_ptr()), 1);
+        let invalid = CString::new(r#"["tax"]"#).unwrap();
+        assert_eq!(set_term_boosts_json(index, invalid.as_ptr()), 0);
+
+        let node_id = CString::new("n1").unwrap();
+        let query = CString::new("ta").unwrap();
+        let out = explain_search_score_json(index, node_id.as_ptr(), query.as_ptr());
+        assert!(!out.is_null());
+        let envelope: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(out) }.to_str().unwrap()).unwrap();
+        free_c_string(out);
+        let json = &envelope["data"];
+        assert_eq!(json["boosted_term"], "tax");
+        assert_eq!(json["term_boost"], 1.1);
+        assert_eq!(json["base_score"], 0.9);
+
+        let query = CString::new("missing").unwrap();
+        let out = explain_search_score_json(index, node_id.as_ptr(), query.as_ptr());
+        let envelope: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(out) }.to_str().unwrap()).unwrap();
+        free_c_string(out);
+        assert_eq!(envelope["ok"], false);
+        assert_eq!(envelope["error"]["code"], ERROR_NOT_FOUND);
+        assert_eq!(envelope["error"]["context"], "n1");
+        free_search_index(index);
+    }
+
+    fn take_envelope(out: *mut c_char, len: usize) -> serde_json::Value {
+        assert!(!out.is_null());
+        let json = unsafe { CStr::from_ptr(out) }.to_str().unwrap().to_string();
+        free_c_string(out);
+        assert_eq!(json.len(), len);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    /// Envelope from a function without an out_len argument
+    fn take_unsized(out: *mut c_char) -> serde_json::Value {
+        let len = unsafe { CStr::from_ptr(out) }.to_bytes().len();
+        take_envelope(out, len)
+    }
+
+    /// Data of a successful envelope
+    fn take_json(out: *mut c_char, len: usize) -> serde_json::Value {
+        let mut envelope = take_envelope(out, len);
+        assert_eq!(envelope["ok"], true);
+        envelope["data"].take()
+    }
+
+    #[test]
+    fn test_search_history_ffi_scopes() {
+        let history = create_search_history(0);
+        for (query, account) in [("invoice", "acc1"), ("budget", "acc2"), ("invoice", "acc1"), ("photos", "")] {
+            let query = CString::new(query).unwrap();
+            let account = CString::new(account).unwrap();
+            assert_eq!(search_history_add(history, query.as_ptr(), account.as_ptr()), 1);
+        }
+
+        let mut results: *mut *mut c_char = ptr::null_mut();
+        let mut count = 0usize;
+        assert_eq!(search_history_get_recent(history, 2, false, &mut results, &mut count), 1);
+        let recent: Vec<String> = (0..count)
+            .map(|i| unsafe { CStr::from_ptr(*results.add(i)) }.to_str().unwrap().to_string())
+            .collect();
+        free_suggestion_results(results, count);
+        assert_eq!(recent, vec!["photos", "invoice"]);
+
+        let mut len = 0usize;
+        let scope = CString::new("acc1").unwrap();
+        let json = take_json(search_history_get_popular_for_scope_json(history, scope.as_ptr(), 10, &mut len), len);
+        assert_eq!(json, serde_json::json!([{"query": "invoice", "count": 2}]));
+
+        let scope = CString::new("global").unwrap();
+        let json = take_json(search_history_get_recent_for_scope_json(history, scope.as_ptr(), 10, &mut len), len);
+        assert_eq!(json.as_array().unwrap().len(), 1);
+        assert_eq!(json[0]["query"], "photos");
+
+        // Everything was just searched, so decay barely changes the counts
+        let json = take_json(search_history_get_popular_decayed_json(history, 1, 7.0, &mut len), len);
+        assert_eq!(json[0]["query"], "invoice");
+        assert!(json[0]["score"].as_f64().unwrap() > 1.99);
+
+        assert_eq!(search_history_clear(history), 1);
+        let json = take_json(search_history_get_popular_decayed_json(history, 10, 7.0, &mut len), len);
+        assert_eq!(json, serde_json::json!([]));
+
+        let envelope = take_envelope(search_history_get_recent_for_scope_json(history, ptr::null(), 10, &mut len), len);
+        assert_eq!(envelope["error"]["code"], crate::file_io::ERROR_NULL_POINTER);
+        assert_eq!(envelope["error"]["context"], "scope");
+        free_search_history(history);
+
+        let envelope = take_envelope(search_history_get_popular_decayed_json(ptr::null_mut(), 10, 7.0, &mut len), len);
+        assert_eq!(envelope["ok"], false);
+        assert_eq!(envelope["error"]["context"], "history_ptr");
+    }
+
+    #[test]
+    fn test_suggestion_engine_json_round_trip() {
+        let engine = create_suggestion_engine(5, 10);
+        for (text, frequency) in [("Budget.xlsx", 3), ("Build notes", 5), ("Bulk export.zip", 1), ("Beach.jpg", 2)] {
+            let text = CString::new(text).unwrap();
+            assert_eq!(suggestion_engine_add_suggestion(engine, text.as_ptr(), frequency), 1);
+        }
+        let used = CString::new("bulk export.zip").unwrap();
+        assert_eq!(suggestion_engine_record_usage(engine, used.as_ptr()), 1);
+
+        let mut len = 0usize;
+        let json_ptr = suggestion_engine_export_json(engine, &mut len);
+        assert!(!json_ptr.is_null());
+        let json = unsafe { CStr::from_ptr(json_ptr) }.to_owned();
+        free_c_string(json_ptr);
+        assert_eq!(json.as_bytes().len(), len);
+        assert!(json.to_str().unwrap().starts_with("{\"ok\":true,\"data\":"));
+
+        let restored = create_suggestion_engine(5, 10);
+        assert_eq!(suggestion_engine_import_json(restored, json.as_ptr()), 1);
+        for prefix in ["b", "bu", "bul", "be"] {
+            assert_eq!(suggestions(restored, prefix), suggestions(engine, prefix));
+        }
+        assert_eq!(suggestions(restored, "bu")[0], "Bulk export.zip");
+
+        let invalid = CString::new("{not json").unwrap();
+        assert_eq!(suggestion_engine_import_json(restored, invalid.as_ptr()), 0);
+
+        free_suggestion_engine(engine);
+        free_suggestion_engine(restored);
+    }
+    #[test]
+    fn test_suggestion_engine_rebuild_scoped_to_account() {
+        let index = create_search_index();
+        let docs = [
+            ("a1", "acc1", "Projects", None),
+            ("a2", "acc1", "Plan.docx", Some("a1")),
+            ("a3", "acc1", "Photos", None),
+            ("b1", "acc2", "Projects", None),
+            ("b2", "acc2", "Pitch deck.key", Some("b1")),
+        ];
+        for (node_id, account_id, name, parent_id) in docs {
+            unsafe {
+                (*index).add_document(SearchDocument {
+                    node_id: node_id.to_string(),
+                    account_id: account_id.to_string(),
+                    provider: "gdrive".to_string(),
+                    email: String::new(),
+                    name: name.to_string(),
+                    is_folder: parent_id.is_none(),
+                    parent_id: parent_id.map(str::to_string),
+                });
+            }
+        }
+        let sorted = |engine, prefix| {
+            let mut texts = suggestions(engine, prefix);
+            texts.sort();
+            texts
+        };
+
+        let acc1 = create_suggestion_engine(0, 0);
+        let acc2 = create_suggestion_engine(0, 0);
+        let all = create_suggestion_engine(0, 0);
+        let (id1, id2) = (CString::new("acc1").unwrap(), CString::new("acc2").unwrap());
+        assert_eq!(suggestion_engine_rebuild_from_index(acc1, index, id1.as_ptr()), 3);
+        assert_eq!(suggestion_engine_rebuild_from_index(acc2, index, id2.as_ptr()), 2);
+        assert_eq!(suggestion_engine_rebuild_from_index(all, index, ptr::null()), 4);
+        assert_eq!(sorted(acc1, "p"), vec!["Photos", "Plan.docx", "Projects"]);
+        assert_eq!(sorted(acc2, "p"), vec!["Pitch deck.key", "Projects"]);
+        assert_eq!(sorted(all, "p"), vec!["Photos", "Pitch deck.key", "Plan.docx", "Projects"]);
+
+        let prefix = CString::new("p").unwrap();
+        let mut len = 0usize;
+        let json_ptr = suggestion_engine_get_suggestions_json(all, prefix.as_ptr(), 10, &mut len);
+        let json = unsafe { CStr::from_ptr(json_ptr) }.to_str().unwrap().to_string();
+        free_c_string(json_ptr);
+        assert_eq!(json.len(), len);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let accounts = |text: &str| {
+            value["data"].as_array().unwrap().iter()
+                .find(|s| s["text"] == text)
+                .map(|s| s["account_ids"].clone())
+                .unwrap()
+        };
+        assert_eq!(accounts("Projects"), serde_json::json!(["acc1", "acc2"]));
+        assert_eq!(accounts("Photos"), serde_json::json!(["acc1"]));
+        assert_eq!(accounts("Pitch deck.key"), serde_json::json!(["acc2"]));
+
+        assert_eq!(suggestion_engine_rebuild_from_index(acc1, ptr::null_mut(), ptr::null()), 0);
+        free_suggestion_engine(acc1);
+        free_suggestion_engine(acc2);
+        free_suggestion_engine(all);
+        free_search_index(index);
+    }
+
+    fn path_doc(node_id: &str, name: &str, parent_id: Option<&str>) -> SearchDocument {
+        SearchDocument {
+            node_id: node_id.to_string(),
+            account_id: "acc1".to_string(),
+            provider: "gdrive".to_string(),
+            email: "ana@example.com".to_string(),
+            name: name.to_string(),
+            is_folder: true,
+            parent_id: parent_id.map(str::to_string),
+        }
+    }
+
+    fn envelope(ptr: *mut c_char, out_len: usize) -> serde_json::Value {
+        assert!(!ptr.is_null());
+        let json = unsafe { CString::from_raw(ptr) }.into_string().unwrap();
+        assert_eq!(json.len(), out_len);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn children(index: *mut SearchIndex, parent_id: Option<&str>, sort: i32, offset: usize, limit: usize) -> serde_json::Value {
+        let parent_id = parent_id.map(|id| CString::new(id).unwrap());
+        let mut len = 0usize;
+        let ptr = get_children_json(index, parent_id.as_ref().map_or(ptr::null(), |id| id.as_ptr()), sort, offset, limit, &mut len);
+        envelope(ptr, len)
+    }
+
+    fn names(page: &serde_json::Value) -> Vec<&str> {
+        page["data"]["children"].as_array().unwrap().iter().map(|doc| doc["name"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_children_and_ancestors_from_index() {
+        let index = create_search_index();
+        let file = |node_id: &str, name: &str, parent_id: &str| SearchDocument {
+            is_folder: false,
+            ..path_doc(node_id, name, Some(parent_id))
+        };
+        for doc in [
+            path_doc("root", "My Drive", None),
+            path_doc("photos", "Photos", Some("root")),
+            path_doc("2024", "2024", Some("photos")),
+            file("img10", "img10.jpg", "2024"),
+            file("img2", "img2.jpg", "2024"),
+            path_doc("docs", "docs", Some("root")),
+            file("notes", "Notes.txt", "root"),
+            file("a", "a.txt", "root"),
+            file("zeta", "zeta.md", "root"),
+        ] {
+            unsafe { (*index).add_document(doc); }
+        }
+
+        // Folders first, then files, each in case-insensitive order
+        let sort = crate::SORT_LOCALE_CASE_INSENSITIVE | crate::CHILDREN_FOLDERS_FIRST;
+        let page = children(index, Some("root"), sort, 0, 0);
+        assert_eq!(page["data"]["total"], 5);
+        assert_eq!(names(&page), ["docs", "Photos", "a.txt", "Notes.txt", "zeta.md"]);
+        assert_eq!(names(&children(index, Some("root"), crate::SORT_LOCALE_CASE_INSENSITIVE, 0, 0)),
+                   ["a.txt", "docs", "Notes.txt", "Photos", "zeta.md"]);
+        assert_eq!(names(&children(index, Some("2024"), crate::SORT_LOCALE_NATURAL, 0, 0)), ["img2.jpg", "img10.jpg"]);
+        assert_eq!(names(&children(index, None, sort, 0, 0)), ["My Drive"]);
+
+        // Pages cover the sorted list without gaps or overlap
+        let pages: Vec<Vec<String>> = (0..3)
+            .map(|n| names(&children(index, Some("root"), sort, n * 2, 2)).into_iter().map(str::to_string).collect())
+            .collect();
+        assert_eq!(pages, [vec!["docs", "Photos"], vec!["a.txt", "Notes.txt"], vec!["zeta.md"]]);
+        assert_eq!(children(index, Some("root"), sort, 2, 2)["data"]["offset"], 2);
+        assert_eq!(children(index, Some("root"), 7, 0, 0)["error"]["code"], ERROR_INVALID_SORT_LOCALE);
+
+        // Moves, removals and deletions keep the child lists current
+        unsafe {
+            (*index).move_document("notes", Some("docs"));
+            (*index).remove_document("zeta");
+            (*index).mark_deleted("a", 1);
+        }
+        assert_eq!(names(&children(index, Some("root"), sort, 0, 0)), ["docs", "Photos"]);
+        assert_eq!(names(&children(index, Some("docs"), sort, 0, 0)), ["Notes.txt"]);
+        let stats = unsafe { (*index).stats() };
+        assert_eq!(stats.child_lists, 5);
+        assert!(stats.child_list_bytes > 0);
+
+        // Ancestors run from the parent up to the root
+        let node = CString::new("img10").unwrap();
+        let mut len = 0usize;
+        let ancestors = envelope(get_ancestors_json(index, node.as_ptr(), &mut len), len);
+        let ids: Vec<&str> = ancestors["data"]["ancestors"].as_array().unwrap().iter()
+            .map(|doc| doc["node_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["2024", "photos", "root"]);
+        assert_eq!(ancestors["data"]["truncated"], false);
+        let node = CString::new("unknown").unwrap();
+        let missing = envelope(get_ancestors_json(index, node.as_ptr(), &mut len), len);
+        assert_eq!(missing["error"]["code"], ERROR_NOT_FOUND);
+        free_search_index(index);
+    }
+
+    #[test]
+    fn test_scoring_profiles_rank_per_query() {
+        let index = create_search_index();
+        for (node_id, name) in [("q1", "Report Q1.pdf"), ("annual", "Annual report.pdf"), ("reports", "Reports"),
+                                ("old", "Old reports"), ("typo", "reprot.txt")] {
+            unsafe {
+                (*index).add_document(SearchDocument {
+                    is_folder: !name.contains('.'),
+                    ..path_doc(node_id, name, None)
+                });
+            }
+        }
+
+        let queries = CString::new(r#"[
+            {"name": "default", "query": "report"},
+            {"name": "palette", "query": "report", "profile": "palette"},
+            {"name": "browser", "query": "report", "profile": "browser"},
+            {"name": "custom", "query": "report", "mode": "account", "account_id": "acc1",
+             "profile": {"name": "files", "substring": 2, "folder_boost": 0}}
+        ]"#).unwrap();
+        let mut len = 0usize;
+        let data = take_json(search_index_multi_json(index, queries.as_ptr(), &mut len), len);
+        let ranked = |name: &str| -> Vec<String> {
+            data[name].as_array().unwrap().iter().map(|r| r["node_id"].as_str().unwrap().to_string()).collect()
+        };
+        let position = |name: &str, node_id: &str| ranked(name).iter().position(|id| id == node_id);
+
+        // The palette puts a file starting with the query above a folder matching a later word;
+        // the browser boosts folders and tolerates the typo
+        assert!(position("palette", "q1") < position("palette", "old"));
+        assert!(position("browser", "old") < position("browser", "q1"));
+        assert_eq!(position("palette", "typo"), None);
+        assert_eq!(position("default", "typo"), None);
+        assert!(position("browser", "typo").is_some());
+        assert_eq!(ranked("default"), ["q1", "reports", "annual", "old"]);
+        assert_eq!(&ranked("custom")[..2], ["annual", "old"]);
+
+        // explain names the profile it scored with
+        let explain = |node_id: &str, profile: Option<&str>| {
+            let (node_id, query) = (CString::new(node_id).unwrap(), CString::new("report").unwrap());
+            let profile = profile.map(|p| CString::new(p).unwrap());
+            let mut len = 0usize;
+            envelope(explain_search_score_with_profile_json(index, node_id.as_ptr(), query.as_ptr(),
+                                                            profile.as_ref().map_or(ptr::null(), |p| p.as_ptr()), &mut len), len)
+        };
+        let explained = explain("old", Some("browser"));
+        assert_eq!(explained["data"]["profile"], "browser");
+        assert_eq!(explained["data"]["folder_boost"], 0.5);
+        assert_eq!(explained["data"]["score"], 1.25);
+        assert_eq!(explain("old", None)["data"]["profile"], "default");
+        assert_eq!(explain("old", Some(r#"{"name": "mine", "substring": 1}"#))["data"]["base_score"], 1.05);
+        let invalid = explain("old", Some(r#"{"prefix": 12}"#));
+        assert_eq!(invalid["error"]["code"], ERROR_INVALID_SCORING_PROFILE);
+        assert_eq!(invalid["error"]["message"], "weight `prefix` is 12, must be within [0, 10]");
+
+        for (invalid, context) in [
+            (r#"[{"name": "a", "query": "x", "profile": "fast"}]"#, "queries_json[0].profile"),
+            (r#"[{"name": "a", "query": "x"}, {"name": "b", "query": "x", "profile": {"fuzzy": -1}}]"#,
+             "queries_json[1].profile"),
+            (r#"[{"name": "a", "query": "x", "mode": "prefix", "profile": "palette"}]"#, "queries_json[0].profile"),
+        ] {
+            let invalid = CString::new(invalid).unwrap();
+            let error = take_unsized(search_index_multi_json(index, invalid.as_ptr(), ptr::null_mut()));
+            assert_eq!(error["error"]["code"], ERROR_INVALID_SCORING_PROFILE);
+            assert_eq!(error["error"]["context"], context);
+        }
+        free_search_index(index);
+    }
+
+    fn multi_ex(index: *mut SearchIndex, queries: &CString, cancel_flag: &AtomicBool, time_budget_ms: u32)
+        -> serde_json::Value {
+        let mut len = 0usize;
+        envelope(search_index_multi_json_ex(index, queries.as_ptr(), cancel_flag, time_budget_ms, &mut len), len)
+    }
+
+    /// Results of a query are sorted best first, each node once
+    fn assert_sorted_unique(results: &serde_json::Value) {
+        let results = results.as_array().unwrap();
+        let scores: Vec<f64> = results.iter().map(|r| r["score"].as_f64().unwrap()).collect();
+        assert!(scores.windows(2).all(|pair| pair[0] >= pair[1]));
+        let ids: std::collections::HashSet<&str> = results.iter().map(|r| r["node_id"].as_str().unwrap()).collect();
+        assert_eq!(ids.len(), results.len());
+    }
+
+    #[test]
+    fn test_multi_search_stops_on_time_budget_and_cancel() {
+        let index = create_search_index();
+        for i in 0..100_000 {
+            unsafe { (*index).add_document(path_doc(&format!("n{}", i), &format!("alpha {} a{}", i % 97, i), None)) };
+        }
+        unsafe { (*index).add_document(path_doc("twice", "alpha alpha", None)) };
+        let queries = CString::new(r#"[
+            {"name": "typed", "query": "a", "profile": "browser", "limit": 50},
+            {"name": "words", "query": "alpha alpha", "mode": "prefix"}
+        ]"#).unwrap();
+        let running = AtomicBool::new(false);
+
+        // A 1 ms budget cannot score 100k names: partial results, still ranked
+        let partial = multi_ex(index, &queries, &running, 1);
+        assert_eq!(partial["ok"], true);
+        assert_eq!(partial["truncated"], true);
+        assert_sorted_unique(&partial["data"]["typed"]);
+        assert_sorted_unique(&partial["data"]["words"]);
+
+        // A cancelled search scores nothing, and the partial run left nothing cached
+        let cancelled = multi_ex(index, &queries, &AtomicBool::new(true), 0);
+        assert_eq!(cancelled["truncated"], true);
+        assert_eq!(cancelled["data"]["typed"], serde_json::json!([]));
+
+        // Without limits every query completes; "twice" is listed by both query words but returned once
+        let full = multi_ex(index, &queries, &running, 0);
+        assert!(full.get("truncated").is_none());
+        assert_eq!(full["data"]["typed"].as_array().unwrap().len(), 50);
+        assert_sorted_unique(&full["data"]["typed"]);
+        assert_eq!(full["data"]["words"].as_array().unwrap().len(), 1);
+        assert_eq!(full["data"]["words"][0]["node_id"], "twice");
+
+        // Complete results are cached, so the budget no longer cuts them short
+        let cached = multi_ex(index, &queries, &running, 1);
+        assert!(cached.get("truncated").is_none());
+        assert_eq!(cached["data"], full["data"]);
+        free_search_index(index);
+    }
+
+    fn export(index: *mut SearchIndex, account_id: Option<&str>, offset: usize, limit: usize) -> String {
+        let account_id = account_id.map(|id| CString::new(id).unwrap());
+        let mut len = 0usize;
+        let ptr = export_documents_json(index, account_id.as_ref().map_or(ptr::null(), |id| id.as_ptr()), offset, limit,
+                                        &mut len);
+        assert!(!ptr.is_null());
+        let json = unsafe { CString::from_raw(ptr) }.into_string().unwrap();
+        assert_eq!(json.len(), len);
+        json
+    }
+
+    fn exported_ids(json: &str) -> Vec<String> {
+        let value: serde_json::Value = serde_json::from_str(json).unwrap();
+        value["data"]["documents"].as_array().unwrap().iter()
+            .map(|doc| doc["node_id"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_export_documents_is_sorted_and_paginated() {
+        let index = create_search_index();
+        let doc = |node_id: &str, account_id: &str, name: &str, parent_id: Option<&str>| SearchDocument {
+            account_id: account_id.to_string(),
+            ..path_doc(node_id, name, parent_id)
+        };
+        for doc in [
+            doc("b2", "acc2", "beta", Some("r2")),
+            doc("a3", "acc1", "zeta", Some("r1")),
+            doc("r2", "acc2", "Drive", None),
+            doc("a1", "acc1", "alpha", Some("r1")),
+            doc("a4", "acc1", "alpha", Some("r1")),
+            doc("r1", "acc1", "Drive", None),
+            doc("a2", "acc1", "inner", Some("a1")),
+        ] {
+            unsafe { (*index).add_document(doc); }
+        }
+
+        // (account, parent, name, node id); root documents come first in their account
+        let full = export(index, None, 0, 0);
+        assert_eq!(full, export(index, None, 0, 0));
+        assert_eq!(exported_ids(&full), ["r1", "a2", "a1", "a4", "a3", "r2", "b2"]);
+        let value: serde_json::Value = serde_json::from_str(&full).unwrap();
+        assert_eq!(value["data"]["total"], 7);
+
+        // Pages of three cover the export without gaps or overlap
+        let paged: Vec<String> = (0..3).flat_map(|n| exported_ids(&export(index, None, n * 3, 3))).collect();
+        assert_eq!(paged, exported_ids(&full));
+        assert!(exported_ids(&export(index, None, 7, 3)).is_empty());
+
+        assert_eq!(exported_ids(&export(index, Some("acc2"), 0, 0)), ["r2", "b2"]);
+        assert!(exported_ids(&export(index, Some("unknown"), 0, 0)).is_empty());
+        let ids: Vec<&str> = unsafe { (*index).get_by_account("acc1") }.iter().map(|doc| doc.node_id.as_str()).collect();
+        assert_eq!(ids, ["r1", "a2", "a1", "a4", "a3"]);
+
+        let mut len = 0usize;
+        let error = envelope(export_documents_json(ptr::null_mut(), ptr::null(), 0, 0, &mut len), len);
+        assert_eq!(error["error"]["code"], crate::ERROR_NULL_POINTER);
+        free_search_index(index);
+    }
+
+    fn path_ex(index: *mut SearchIndex, node_id: &str, separator: &str, include_account: i32) -> (Option<String>, i32) {
+        let node_id = CString::new(node_id).unwrap();
+        let separator = CString::new(separator).unwrap();
+        let mut truncated = 99;
+        let ptr = build_path_ex(index, node_id.as_ptr(), separator.as_ptr(), include_account, &mut truncated);
+        if ptr.is_null() {
+            return (None, truncated);
+        }
+        let path = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        free_c_string(ptr);
+        (Some(path), truncated)
+    }
+
+    #[test]
+    fn test_build_path_ex_chains_and_account_prefix() {
+        let index = create_search_index();
+        for doc in [
+            path_doc("root", "My Drive", None),
+            path_doc("docs", "Documents", Some("root")),
+            path_doc("file", "Résumé.pdf", Some("docs")),
+            path_doc("orphan", "Shared.txt", Some("gone")),
+        ] {
+            unsafe { (*index).add_document(doc); }
+        }
+
+        // A true root reports a complete chain
+        assert_eq!(path_ex(index, "file", "/", 0), (Some("My Drive/Documents/Résumé.pdf".to_string()), PATH_COMPLETE));
+        assert_eq!(path_ex(index, "root", "/", 0), (Some("My Drive".to_string()), PATH_COMPLETE));
+        // A parent missing from the index is not mistaken for a root
+        assert_eq!(path_ex(index, "orphan", "/", 0), (Some("Shared.txt".to_string()), PATH_TRUNCATED));
+        assert_eq!(path_ex(index, "unknown", "/", 0), (Some(String::new()), PATH_TRUNCATED));
+
+        // Multi-byte separators are used verbatim
+        assert_eq!(path_ex(index, "file", " › ", 0).0.unwrap(), "My Drive › Documents › Résumé.pdf");
+        assert_eq!(path_ex(index, "file", "→", 1).0.unwrap(), "ana@example.com (gdrive)→My Drive→Documents→Résumé.pdf");
+        assert_eq!(path_ex(index, "orphan", " / ", 1), (Some("ana@example.com (gdrive) / Shared.txt".to_string()), PATH_TRUNCATED));
+
+        // The plain variant matches the complete form without the status
+        let node = CString::new("file").unwrap();
+        let ptr = build_path(index, node.as_ptr(), ptr::null());
+        assert_eq!(unsafe { CStr::from_ptr(ptr) }.to_str().unwrap(), "My Drive/Documents/Résumé.pdf");
+        free_c_string(ptr);
+        free_search_index(index);
+    }
+
+    #[test]
+    fn test_build_path_ex_depth_cap_and_cycles() {
+        let index = create_search_index();
+        unsafe {
+            (*index).add_document(path_doc("n0", "n0", None));
+            for depth in 1..=MAX_PATH_DEPTH {
+                let parent = format!("n{}", depth - 1);
+                (*index).add_document(path_doc(&format!("n{}", depth), &format!("n{}", depth), Some(&parent)));
+            }
+            (*index).add_document(path_doc("a", "a", Some("b")));
+            (*index).add_document(path_doc("b", "b", Some("a")));
+        }
+
+        let (path, status) = path_ex(index, &format!("n{}", MAX_PATH_DEPTH - 1), "/", 0);
+        assert_eq!(status, PATH_COMPLETE);
+        assert_eq!(path.unwrap().split('/').count(), MAX_PATH_DEPTH);
+        assert_eq!(path_ex(index, &format!("n{}", MAX_PATH_DEPTH), "/", 0), (None, PATH_TOO_DEEP));
+
+        // A parent cycle stops at the first repeated node
+        assert_eq!(path_ex(index, "a", "/", 0), (Some("b/a".to_string()), PATH_TRUNCATED));
+        assert!(build_path_ex(ptr::null_mut(), ptr::null(), ptr::null(), 0, ptr::null_mut()).is_null());
+        free_search_index(index);
+    }
+
+    #[test]
+    fn test_export_index_diagnostics_redacts_names() {
+        let index = create_search_index();
+        let names = [
+            ("n1", "acc1", "Quarterly Passport Scan.pdf"),
+            ("n2", "acc1", "Kids Birthday Party.mov"),
+            ("n3", "acc2", "Quarterly Passport Scan.pdf"),
+            ("n4", "acc2", "Mortgage"),
+        ];
+        for (node_id, account_id, name) in names {
+            unsafe {
+                (*index).add_document(SearchDocument {
+                    node_id: node_id.to_string(),
+                    account_id: account_id.to_string(),
+                    provider: "gdrive".to_string(),
+                    email: String::new(),
+                    name: name.to_string(),
+                    is_folder: false,
+                    parent_id: None,
+                });
+            }
+        }
+
+        let mut len = 0usize;
+        let out = export_index_diagnostics(index, 1, &mut len);
+        let raw = unsafe { CStr::from_ptr(out) }.to_str().unwrap().to_lowercase();
+        for word in ["quarterly", "passport", "scan.pdf", "kids", "birthday", "party.mov", "mortgage"] {
+            assert!(!raw.contains(word), "{} leaked", word);
+        }
+        let redacted = take_json(out, len);
+        assert_eq!(redacted["redacted"], true);
+
+        // Counts are computed from the original names
+        assert_eq!(redacted["stats"]["documents"], 4);
+        assert_eq!(redacted["stats"]["words"], 7);
+        assert_eq!(redacted["accounts"], serde_json::json!([
+            {"account_id": "acc1", "documents": 2},
+            {"account_id": "acc2", "documents": 2},
+        ]));
+        let total = |histogram: &serde_json::Value| -> u64 {
+            histogram.as_array().unwrap().iter().map(|b| b["count"].as_u64().unwrap()).sum()
+        };
+        assert_eq!(total(&redacted["token_length_histogram"]), 7);
+        assert_eq!(total(&redacted["posting_size_histogram"]), 7);
+        assert_eq!(redacted["posting_size_histogram"], serde_json::json!([
+            {"min": 1, "max": 1, "count": 4},
+            {"min": 2, "max": 3, "count": 3},
+        ]));
+
+        // Longest first; the same name gets the same pseudonym within one export
+        let slowest = redacted["slowest_names"].as_array().unwrap();
+        assert_eq!(slowest.len(), 4);
+        assert_eq!(slowest[0]["chars"], 27);
+        assert_eq!(slowest[0]["words"], 3);
+        assert_eq!(slowest[0]["name"], slowest[1]["name"]);
+        assert_ne!(slowest[0]["name"], slowest[2]["name"]);
+
+        // A new export uses a new key
+        let again = take_json(export_index_diagnostics(index, 1, &mut len), len);
+        assert_ne!(again["slowest_names"][0]["name"], slowest[0]["name"]);
+
+        let plain = take_json(export_index_diagnostics(index, 0, &mut len), len);
+        assert_eq!(plain["redacted"], false);
+        assert_eq!(plain["slowest_names"][0]["name"], "Quarterly Passport Scan.pdf");
+        assert_eq!(plain["slowest_names"][3]["name"], "Mortgage");
+
+        let error = take_envelope(export_index_diagnostics(ptr::null_mut(), 1, &mut len), len);
+        assert_eq!(error["error"]["context"], "index_ptr");
+        free_search_index(index);
+    }
+
+    #[test]
+    fn test_add_documents_json_reports_bad_entries() {
+        let index = create_search_index();
+        let docs = CString::new(r#"[
+            {"node_id": "n1", "account_id": "acc1", "provider": "gdrive", "name": "Budget 2024.xlsx"},
+            {"node_id": "n2", "account_id": "acc1", "name": 42},
+            {"node_id": "n3", "account_id": "acc2", "name": "Holiday photos", "is_folder": true, "parent_id": "root"},
+            {"node_id": "", "name": "orphan"}
+        ]"#).unwrap();
+        let report = take_unsized(add_documents_json(index, docs.as_ptr()))["data"].take();
+        assert_eq!(report["added"], 2);
+        assert_eq!(report["failed"], 2);
+        let errors = report["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0]["index"], 1);
+        assert!(errors[0]["reason"].as_str().unwrap().contains("invalid type"));
+        assert_eq!(errors[1]["index"], 3);
+
+        let index_ref = unsafe { &*index };
+        assert_eq!(index_ref.len(), 2);
+        assert!(index_ref.get("n3").unwrap().is_folder);
+        assert_eq!(index_ref.search_exact("holiday", 10).len(), 1);
+
+        // Re-sending a document replaces it without duplicating postings
+        let resend = CString::new(r#"[{"node_id": "n1", "account_id": "acc1", "name": "Budget 2025.xlsx"}]"#).unwrap();
+        let report = take_unsized(add_documents_json(index, resend.as_ptr()))["data"].take();
+        assert_eq!((report["added"].as_u64(), report["failed"].as_u64()), (Some(1), Some(0)));
+        let index_ref = unsafe { &*index };
+        assert_eq!(index_ref.len(), 2);
+        assert_eq!(index_ref.stats().words, 4);
+        assert!(index_ref.search_exact("2024", 10).is_empty());
+        assert_eq!(index_ref.search_exact("budget", 10).len(), 1);
+
+        let invalid = CString::new(r#"{"node_id": "n9"}"#).unwrap();
+        let error = take_unsized(add_documents_json(index, invalid.as_ptr()));
+        assert_eq!(error["error"]["code"], crate::ffi_util::ERROR_INVALID_JSON);
+        free_search_index(index);
+    }
+
+    #[test]
+    fn test_id_search_path_resolves_ordinals() {
+        let index = create_search_index();
+        for i in 0..50 {
+            unsafe {
+                (*index).add_document(SearchDocument {
+                    node_id: format!("n{}", i),
+                    account_id: "acc1".to_string(),
+                    provider: "gdrive".to_string(),
+                    email: String::new(),
+                    name: format!("Report {}", i),
+                    is_folder: false,
+                    parent_id: None,
+                });
+            }
+        }
+        let query = CString::new("report").unwrap();
+        let mut out_ids = [0u32; 64];
+        let mut out_scores = [0.0f64; 64];
+        let search = |out_ids: &mut [u32], out_scores: &mut [f64]| {
+            search_index_ids(index, query.as_ptr(), 50, out_ids.as_mut_ptr(), out_scores.as_mut_ptr(), out_ids.len())
+        };
+
+        // Repeats are served from the query cache
+        assert_eq!(search(&mut out_ids, &mut out_scores), 50);
+        assert_eq!(search(&mut out_ids, &mut out_scores), 50);
+        let prefix = |out_ids: &mut [u32]| {
+            search_index_prefix_ids(index, query.as_ptr(), 50, out_ids.as_mut_ptr(), ptr::null_mut(), out_ids.len())
+        };
+        assert_eq!(prefix(&mut out_ids), 50);
+        assert_eq!(prefix(&mut out_ids), 50);
+
+        // Resolve a page; a freed ordinal resolves to null
+        let page = [out_ids[0], out_ids[1]];
+        let gone = unsafe { (*index).get_by_ordinal(page[1]) }.unwrap().node_id.clone();
+        unsafe { (*index).remove_document(&gone); }
+        let mut len = 0usize;
+        let resolved = take_json(get_documents_by_ordinals_json(index, page.as_ptr(), page.len(), &mut len), len);
+        let resolved = resolved.as_array().unwrap();
+        assert_eq!(resolved.len(), 2);
+        assert_eq!(resolved[0]["ordinal"], page[0]);
+        assert!(resolved[0]["name"].as_str().unwrap().starts_with("Report"));
+        assert!(resolved[1].is_null());
+
+        assert_eq!(search_index_ids(index, query.as_ptr(), 10, ptr::null_mut(), ptr::null_mut(), 0), 0);
+        assert_eq!(search_index_ids(ptr::null_mut(), query.as_ptr(), 10, out_ids.as_mut_ptr(), ptr::null_mut(), 1), 0);
+        free_search_index(index);
+    }
+
+    #[test]
+    fn test_multi_search_matches_individual_queries() {
+        use crate::search::DEFAULT_MULTI_SEARCH_LIMIT;
+
+        let index = create_search_index();
+        let names = ["Report Q1.pdf", "report draft.docx", "Reports", "Annual report.pdf", "Photos 2023",
+                     "photo.jpg", "Scans.pdf", "Old reports", "Reporting tools", "invoice.pdf"];
+        for (i, name) in names.iter().enumerate() {
+            unsafe {
+                (*index).add_document(SearchDocument {
+                    node_id: format!("n{}", i),
+                    account_id: if i % 2 == 0 { "acc1" } else { "acc2" }.to_string(),
+                    provider: "gdrive".to_string(),
+                    email: String::new(),
+                    name: name.to_string(),
+                    is_folder: !name.contains('.'),
+                    parent_id: None,
+                });
+            }
+        }
+
+        let queries = CString::new(r#"[
+            {"name": "pdfs", "query": "PDF", "limit": 3},
+            {"name": "reports", "query": "report", "mode": "prefix"},
+            {"name": "acc2_photos", "query": "photo", "mode": "account", "account_id": "acc2"},
+            {"name": "report_folders", "query": "report", "is_folder": true, "limit": 2},
+            {"name": "report_files", "query": "report", "is_folder": false}
+        ]"#).unwrap();
+        let mut len = 0usize;
+        let data = take_json(search_index_multi_json(index, queries.as_ptr(), &mut len), len);
+
+        let index_ref = unsafe { &*index };
+        let filtered = |is_folder: bool, limit: usize| {
+            let results: Vec<_> = index_ref.search_exact("report", usize::MAX)
+                .into_iter()
+                .filter(|r| index_ref.get(&r.node_id).unwrap().is_folder == is_folder)
+                .take(limit)
+                .collect();
+            results
+        };
+        let expected = [
+            ("pdfs", index_ref.search_exact("PDF", 3)),
+            ("reports", index_ref.search_prefix("report", DEFAULT_MULTI_SEARCH_LIMIT)),
+            ("acc2_photos", index_ref.search_by_account("photo", "acc2", DEFAULT_MULTI_SEARCH_LIMIT)),
+            ("report_folders", filtered(true, 2)),
+            ("report_files", filtered(false, DEFAULT_MULTI_SEARCH_LIMIT)),
+        ];
+        assert_eq!(data.as_object().unwrap().len(), expected.len());
+        for (name, results) in expected {
+            assert!(!results.is_empty(), "{}", name);
+            assert_eq!(data[name], serde_json::to_value(&results).unwrap(), "{}", name);
+        }
+        assert_eq!(data["report_folders"].as_array().unwrap().len(), 2);
+
+        // The three "report" exact queries were scored once; the rest hit the cache
+        let stats = index_ref.stats().query_cache;
+        assert_eq!(stats.misses, 4);
+
+        let too_many: Vec<serde_json::Value> = (0..=MAX_MULTI_SEARCH_QUERIES)
+            .map(|i| serde_json::json!({"name": format!("q{}", i), "query": "report"}))
+            .collect();
+        let too_many = CString::new(serde_json::to_string(&too_many).unwrap()).unwrap();
+        let error = take_unsized(search_index_multi_json(index, too_many.as_ptr(), ptr::null_mut()));
+        assert_eq!(error["error"]["code"], ERROR_TOO_MANY_QUERIES);
+        for invalid in [
+            r#"[{"name": "a", "query": "x"}, {"name": "a", "query": "y"}]"#,
+            r#"[{"name": "", "query": "x"}]"#,
+            r#"[{"name": "a", "query": "x", "mode": "account"}]"#,
+            r#"[{"name": "a", "query": "x", "mode": "fuzzy"}]"#,
+        ] {
+            let invalid = CString::new(invalid).unwrap();
+            let error = take_unsized(search_index_multi_json(index, invalid.as_ptr(), ptr::null_mut()));
+            assert_eq!(error["error"]["code"], ERROR_INVALID_JSON, "{}", invalid.to_str().unwrap());
+        }
+        free_search_index(index);
+    }
+
+    #[test]
+    fn test_invalid_strings_fail_gracefully() {
+        use crate::ffi_util::{clear_last_error, get_last_error_json};
+        use crate::file_io::ERROR_INVALID_PATH;
+
+        let last_error = || {
+            let mut len = 0usize;
+            take_json(get_last_error_json(&mut len), len)
+        };
+        let index = create_search_index();
+        let c = |s: &str| CString::new(s).unwrap();
+        let (root, folder) = (c("root"), c("Folder"));
+        assert_eq!(add_document_to_index(index, root.as_ptr(), ptr::null(), ptr::null(), ptr::null(),
+                                         folder.as_ptr(), true, ptr::null()), 1);
+
+        // Invalid UTF-8 is rejected and named in the last error
+        clear_last_error();
+        let node_id = c("child");
+        let invalid_name = CString::new(vec![b'R', b'e', 0xc3, 0x28]).unwrap();
+        assert_eq!(add_document_to_index(index, node_id.as_ptr(), ptr::null(), ptr::null(), ptr::null(),
+                                         invalid_name.as_ptr(), false, root.as_ptr()), 0);
+        assert_eq!(get_index_count(index), 1);
+        let error = last_error();
+        assert_eq!(error["code"], ERROR_INVALID_PATH);
+        assert_eq!(error["context"], "name");
+
+        let invalid_separator = CString::new(vec![0xff]).unwrap();
+        assert!(build_path_ex(index, root.as_ptr(), invalid_separator.as_ptr(), 0, ptr::null_mut()).is_null());
+        assert_eq!(last_error()["context"], "separator");
+
+        // A name holding a NUL byte comes back with U+FFFD instead of aborting
+        let docs = c(r#"[{"node_id": "child", "name": "Re\u0000port.pdf", "parent_id": "root"}]"#);
+        assert_eq!(take_unsized(add_documents_json(index, docs.as_ptr()))["data"]["added"], 1);
+        let path = build_path(index, node_id.as_ptr(), ptr::null());
+        assert!(!path.is_null());
+        let path = unsafe { CString::from_raw(path) }.into_string().unwrap();
+        assert_eq!(path, "Folder/Re\u{FFFD}port.pdf");
+
+        clear_last_error();
+        free_search_index(index);
+    }
+}
@@ -0,0 +1,147 @@
+// Index diagnostics export for CloudNexus
+// Lets users attach search index state to bug reports without sharing filenames
+
+use hmac::{Hmac, Mac};
+use crate::rng::fill_random;
+use serde::Serialize;
+use sha2::Sha256;
+
+use super::index::SearchIndexStats;
+
+/// Number of names reported in `slowest_names`
+pub const DIAGNOSTICS_SLOWEST_NAMES: usize = 50;
+
+/// Count of values falling in `min..=max`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HistogramBucket {
+    pub min: usize,
+    pub max: usize,
+    pub count: usize,
+}
+
+/// A name that is among the most expensive to score
+///
+/// Scoring cost grows with the name's length (substring and fuzzy matching
+/// walk every character), so the longest names are the slowest to score.
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowNameEntry {
+    /// Original name, or its pseudonym when redacted
+    pub name: String,
+    /// Length of the original name in characters
+    pub chars: usize,
+    /// Number of indexed words in the original name
+    pub words: usize,
+}
+
+/// Documents indexed for one account
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccountDocumentCount {
+    pub account_id: String,
+    pub documents: usize,
+}
+
+/// Index snapshot returned by export_index_diagnostics
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexDiagnostics {
+    pub redacted: bool,
+    pub stats: SearchIndexStats,
+    /// Indexed words by length in characters
+    pub token_length_histogram: Vec<HistogramBucket>,
+    /// Indexed words by number of documents containing them
+    pub posting_size_histogram: Vec<HistogramBucket>,
+    /// Longest names first, at most DIAGNOSTICS_SLOWEST_NAMES
+    pub slowest_names: Vec<SlowNameEntry>,
+    /// Ordered by account_id
+    pub accounts: Vec<AccountDocumentCount>,
+}
+
+/// Replaces names with HMAC-SHA256 pseudonyms under a random key
+///
+/// A pseudonymizer is used for a single export: the same name always maps to
+/// the same pseudonym within it, while the fresh key makes pseudonyms from
+/// different exports unlinkable.
+pub struct Pseudonymizer {
+    key: [u8; 32],
+}
+
+impl Pseudonymizer {
+    /// Create a pseudonymizer with a fresh random key
+    pub fn new() -> Self {
+        let mut key = [0u8; 32];
+        fill_random(&mut key);
+        Pseudonymizer { key }
+    }
+
+    /// Pseudonym for `name`: "anon-" followed by 16 hex digits of its HMAC
+    pub fn pseudonym(&self, name: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(name.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let hex: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        format!("anon-{}", hex)
+    }
+}
+
+impl Default for Pseudonymizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Histogram with power-of-two buckets (1, 2-3, 4-7, ...), empty buckets omitted
+pub fn power_of_two_histogram<I: IntoIterator<Item = usize>>(values: I) -> Vec<HistogramBucket> {
+    let mut counts: Vec<usize> = Vec::new();
+    let mut zeros = 0;
+    for value in values {
+        if value == 0 {
+            zeros += 1;
+            continue;
+        }
+        let bucket = (usize::BITS - 1 - value.leading_zeros()) as usize;
+        if counts.len() <= bucket {
+            counts.resize(bucket + 1, 0);
+        }
+        counts[bucket] += 1;
+    }
+
+    let mut buckets = Vec::new();
+    if zeros > 0 {
+        buckets.push(HistogramBucket { min: 0, max: 0, count: zeros });
+    }
+    for (bucket, count) in counts.into_iter().enumerate() {
+        if count > 0 {
+            let min = 1usize << bucket;
+            buckets.push(HistogramBucket { min, max: min.saturating_mul(2) - 1, count });
+        }
+    }
+    buckets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_power_of_two_histogram() {
+        let buckets = power_of_two_histogram([1, 2, 3, 4, 7, 8, 0, 100]);
+        assert_eq!(buckets, vec![
+            HistogramBucket { min: 0, max: 0, count: 1 },
+            HistogramBucket { min: 1, max: 1, count: 1 },
+            HistogramBucket { min: 2, max: 3, count: 2 },
+            HistogramBucket { min: 4, max: 7, count: 2 },
+            HistogramBucket { min: 8, max: 15, count: 1 },
+            HistogramBucket { min: 64, max: 127, count: 1 },
+        ]);
+        assert!(power_of_two_histogram(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_pseudonyms_stable_per_key() {
+        let first = Pseudonymizer::new();
+        let second = Pseudonymizer::new();
+        assert_eq!(first.pseudonym("report.pdf"), first.pseudonym("report.pdf"));
+        assert_ne!(first.pseudonym("report.pdf"), first.pseudonym("report.docx"));
+        assert_ne!(first.pseudonym("report.pdf"), second.pseudonym("report.pdf"));
+        assert_eq!(first.pseudonym("x").len(), "anon-".len() + 16);
+    }
+}
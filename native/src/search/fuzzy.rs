@@ -166,13 +166,14 @@ pub fn soundex(word: &str) -> String {
         }
         prev_code = code;
         
-        if result.len() >= 4 {
+        // Count characters, not bytes: the first letter may be non-ASCII
+        if result.chars().count() >= 4 {
             break;
         }
     }
     
     // Pad with zeros if necessary
-    while result.len() < 4 {
+    while result.chars().count() < 4 {
         result.push('0');
     }
     
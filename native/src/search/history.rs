@@ -5,6 +5,9 @@ use std::collections::{HashMap, VecDeque};
 use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
 
+/// Default window in which a repeated query updates the previous entry
+pub const DEFAULT_DEDUPE_WINDOW_SECS: i64 = 60;
+
 /// Search history entry
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HistoryEntry {
@@ -14,14 +17,30 @@ pub struct HistoryEntry {
     pub scope: String,
 }
 
+/// Query with its number of searches
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PopularQuery {
+    pub query: String,
+    pub count: usize,
+}
+
+/// Query with its time-decayed search count
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DecayedQuery {
+    pub query: String,
+    pub score: f64,
+}
+
 /// Search history manager
 pub struct SearchHistory {
     /// History entries (recent first)
     history: VecDeque<HistoryEntry>,
-    /// Query -> count (for popularity)
+    /// Query -> number of stored entries (for popularity)
     query_counts: HashMap<String, usize>,
     /// Maximum history entries to keep
     max_history: usize,
+    /// Seconds within which the same query in the same scope collapses into the previous entry
+    dedupe_window_secs: i64,
     /// Persistence path
     persistence_path: Option<PathBuf>,
 }
@@ -33,6 +52,7 @@ impl SearchHistory {
             history: VecDeque::new(),
             query_counts: HashMap::new(),
             max_history,
+            dedupe_window_secs: DEFAULT_DEDUPE_WINDOW_SECS,
             persistence_path: None,
         }
     }
@@ -50,16 +70,41 @@ impl SearchHistory {
         history
     }
     
+    /// Set the window in which a repeated query collapses into the previous entry (0 disables)
+    pub fn set_dedupe_window(&mut self, secs: i64) {
+        self.dedupe_window_secs = secs.max(0);
+    }
+    
     /// Record a search query
+    ///
+    /// Running the same query in the same scope again within the dedupe window
+    /// updates the newest entry's timestamp and result count instead of adding
+    /// another entry.
     pub fn record_search(
         &mut self,
         query: String,
         result_count: usize,
         scope: String,
     ) {
+        self.record_search_at(query, result_count, scope, chrono::Utc::now().timestamp());
+    }
+    
+    fn record_search_at(&mut self, query: String, result_count: usize, scope: String, timestamp: i64) {
+        if let Some(latest) = self.history.front_mut() {
+            if latest.query == query && latest.scope == scope
+                && (timestamp - latest.timestamp).abs() < self.dedupe_window_secs {
+                latest.timestamp = timestamp;
+                latest.result_count = result_count;
+                if self.persistence_path.is_some() {
+                    let _ = self.save();
+                }
+                return;
+            }
+        }
+        
         let entry = HistoryEntry {
             query: query.clone(),
-            timestamp: chrono::Utc::now().timestamp(),
+            timestamp,
             result_count,
             scope,
         };
@@ -70,9 +115,11 @@ impl SearchHistory {
         // Update counts
         *self.query_counts.entry(query).or_insert(0) += 1;
         
-        // Trim history
+        // Trim history, keeping counts in step with the stored entries
         while self.history.len() > self.max_history {
-            self.history.pop_back();
+            if let Some(evicted) = self.history.pop_back() {
+                self.decrement_count(&evicted.query);
+            }
         }
         
         // Auto-save if persistence is enabled
@@ -81,9 +128,28 @@ impl SearchHistory {
         }
     }
     
-    /// Get recent searches
-    pub fn get_recent(&self, limit: usize) -> Vec<&HistoryEntry> {
-        self.history.iter().take(limit).collect()
+    /// Drop one occurrence of `query` from the counts
+    fn decrement_count(&mut self, query: &str) {
+        if let Some(count) = self.query_counts.get_mut(query) {
+            *count -= 1;
+            if *count == 0 {
+                self.query_counts.remove(query);
+            }
+        }
+    }
+    
+    /// Get recent searches, optionally keeping only the newest entry of each query
+    pub fn get_recent(&self, limit: usize, dedupe_recent: bool) -> Vec<&HistoryEntry> {
+        if !dedupe_recent {
+            return self.history.iter().take(limit).collect();
+        }
+        
+        let mut seen = std::collections::HashSet::new();
+        self.history
+            .iter()
+            .filter(|entry| seen.insert(entry.query.as_str()))
+            .take(limit)
+            .collect()
     }
     
     /// Get popular searches
@@ -93,6 +159,58 @@ impl SearchHistory {
         entries.into_iter().take(limit).collect()
     }
     
+    /// Get popular searches with exponentially decayed counts
+    ///
+    /// Each recorded search counts 0.5^(age / half life), so old bursts fade
+    /// behind what is searched now. A half life of 0 or less counts every
+    /// search once. Computed from the stored entries without changing them.
+    pub fn get_popular_decayed(&self, limit: usize, half_life_days: f64) -> Vec<DecayedQuery> {
+        self.popular_decayed_at(limit, half_life_days, chrono::Utc::now().timestamp())
+    }
+    
+    fn popular_decayed_at(&self, limit: usize, half_life_days: f64, now: i64) -> Vec<DecayedQuery> {
+        let half_life_secs = half_life_days * 86_400.0;
+        let mut scores: HashMap<&str, f64> = HashMap::new();
+        for entry in &self.history {
+            let weight = if half_life_secs.is_finite() && half_life_secs > 0.0 {
+                let age = (now - entry.timestamp).max(0) as f64;
+                0.5f64.powf(age / half_life_secs)
+            } else {
+                1.0
+            };
+            *scores.entry(entry.query.as_str()).or_insert(0.0) += weight;
+        }
+        
+        let mut entries: Vec<DecayedQuery> = scores
+            .into_iter()
+            .map(|(query, score)| DecayedQuery { query: query.to_string(), score })
+            .collect();
+        entries.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap().then_with(|| a.query.cmp(&b.query)));
+        entries.truncate(limit);
+        entries
+    }
+    
+    /// Get recent searches recorded in one scope ("global" or an account id)
+    pub fn get_recent_for_scope(&self, scope: &str, limit: usize) -> Vec<&HistoryEntry> {
+        self.history.iter().filter(|entry| entry.scope == scope).take(limit).collect()
+    }
+    
+    /// Get popular searches recorded in one scope, most searched first
+    pub fn get_popular_for_scope(&self, scope: &str, limit: usize) -> Vec<PopularQuery> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for entry in self.history.iter().filter(|entry| entry.scope == scope) {
+            *counts.entry(entry.query.as_str()).or_insert(0) += 1;
+        }
+        
+        let mut entries: Vec<PopularQuery> = counts
+            .into_iter()
+            .map(|(query, count)| PopularQuery { query: query.to_string(), count })
+            .collect();
+        entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.query.cmp(&b.query)));
+        entries.truncate(limit);
+        entries
+    }
+    
     /// Get searches matching a prefix
     pub fn search_history(&self, prefix: &str) -> Vec<&HistoryEntry> {
         let prefix_lower = prefix.to_lowercase();
@@ -153,6 +271,7 @@ impl SearchHistory {
             let loaded: VecDeque<HistoryEntry> = serde_json::from_str(&data).map_err(|e| e.to_string())?;
             
             self.history = loaded;
+            self.history.truncate(self.max_history);
             
             // Rebuild counts
             self.query_counts.clear();
@@ -208,4 +327,118 @@ mod tests {
         history.clear();
         assert!(history.is_empty());
     }
+    
+    #[test]
+    fn test_search_history_counts_stay_bounded() {
+        let max_history = 20;
+        let mut history = SearchHistory::new(max_history);
+        for i in 0..2 * max_history {
+            history.record_search(format!("query {}", i), 1, "global".to_string());
+        }
+        
+        assert_eq!(history.len(), max_history);
+        assert_eq!(history.query_counts.len(), max_history);
+        assert!(!history.query_counts.contains_key("query 0"));
+        assert_eq!(history.query_counts.get("query 39"), Some(&1));
+        
+        // A query evicted once but still stored keeps its remaining count
+        let mut history = SearchHistory::new(3);
+        for query in ["a", "b", "a", "c", "d"] {
+            history.record_search(query.to_string(), 1, "global".to_string());
+        }
+        assert_eq!(history.query_counts.get("a"), Some(&1));
+        assert_eq!(history.query_counts.get("b"), None);
+        assert_eq!(history.query_counts.values().sum::<usize>(), history.len());
+    }
+    
+    #[test]
+    fn test_search_history_collapses_consecutive_duplicates() {
+        let now = 1_700_000_000;
+        let mut history = SearchHistory::new(10);
+        history.record_search_at("report".to_string(), 3, "global".to_string(), now);
+        history.record_search_at("report".to_string(), 5, "global".to_string(), now + 30);
+        assert_eq!(history.len(), 1);
+        let latest = &history.get_recent(1, false)[0];
+        assert_eq!((latest.timestamp, latest.result_count), (now + 30, 5));
+        assert_eq!(history.get_popular(1)[0].1, &1);
+        
+        // Outside the window, in another scope, or after another query: a new entry
+        history.record_search_at("report".to_string(), 5, "global".to_string(), now + 120);
+        history.record_search_at("report".to_string(), 5, "acc1".to_string(), now + 121);
+        history.record_search_at("photos".to_string(), 1, "global".to_string(), now + 122);
+        history.record_search_at("report".to_string(), 5, "global".to_string(), now + 123);
+        assert_eq!(history.len(), 5);
+        
+        let unique: Vec<&str> = history.get_recent(10, true).iter().map(|e| e.query.as_str()).collect();
+        assert_eq!(unique, vec!["report", "photos"]);
+        assert_eq!(history.get_recent(10, false).len(), 5);
+        
+        // A zero window never collapses
+        history.set_dedupe_window(0);
+        history.record_search_at("report".to_string(), 5, "global".to_string(), now + 123);
+        assert_eq!(history.len(), 6);
+    }
+    
+    const DAY: i64 = 86_400;
+    
+    /// Record a search with a synthetic timestamp (entries are kept newest first)
+    fn record_at(history: &mut SearchHistory, query: &str, scope: &str, timestamp: i64) {
+        let position = history.history.iter().position(|e| e.timestamp <= timestamp).unwrap_or(history.history.len());
+        history.history.insert(position, HistoryEntry {
+            query: query.to_string(),
+            timestamp,
+            result_count: 1,
+            scope: scope.to_string(),
+        });
+        *history.query_counts.entry(query.to_string()).or_insert(0) += 1;
+    }
+    
+    #[test]
+    fn test_search_history_decay_flips_ordering() {
+        let now = 1_700_000_000;
+        let mut history = SearchHistory::new(100);
+        for i in 0..10 {
+            record_at(&mut history, "tax forms", "global", now - 365 * DAY - i);
+        }
+        for i in 0..3 {
+            record_at(&mut history, "holiday photos", "global", now - DAY - i);
+        }
+        
+        // Raw counts keep last year's query on top
+        assert_eq!(history.get_popular(1)[0].0, "tax forms");
+        
+        let decayed = history.popular_decayed_at(5, 30.0, now);
+        let order: Vec<&str> = decayed.iter().map(|q| q.query.as_str()).collect();
+        assert_eq!(order, vec!["holiday photos", "tax forms"]);
+        assert!(decayed[0].score > 2.9 && decayed[0].score < 3.0);
+        assert!(decayed[1].score < 0.01);
+        
+        // No decay is the raw count over the stored entries
+        let flat = history.popular_decayed_at(5, 0.0, now);
+        assert_eq!((flat[0].query.as_str(), flat[0].score), ("tax forms", 10.0));
+        assert_eq!(history.len(), 13);
+    }
+    
+    #[test]
+    fn test_search_history_scope_filtering() {
+        let now = 1_700_000_000;
+        let mut history = SearchHistory::new(100);
+        record_at(&mut history, "invoice", "acc1", now - 30);
+        record_at(&mut history, "invoice", "acc1", now - 20);
+        record_at(&mut history, "budget", "acc1", now - 10);
+        record_at(&mut history, "budget", "acc2", now - 5);
+        record_at(&mut history, "budget", "acc2", now - 4);
+        record_at(&mut history, "photos", "global", now);
+        
+        let recent: Vec<&str> = history.get_recent_for_scope("acc1", 10).iter().map(|e| e.query.as_str()).collect();
+        assert_eq!(recent, vec!["budget", "invoice", "invoice"]);
+        assert_eq!(history.get_recent_for_scope("acc1", 1).len(), 1);
+        
+        assert_eq!(history.get_popular_for_scope("acc1", 10), vec![
+            PopularQuery { query: "invoice".to_string(), count: 2 },
+            PopularQuery { query: "budget".to_string(), count: 1 },
+        ]);
+        assert_eq!(history.get_popular_for_scope("acc2", 10), vec![PopularQuery { query: "budget".to_string(), count: 2 }]);
+        assert!(history.get_popular_for_scope("acc3", 10).is_empty());
+    }
 }
\ No newline at end of file
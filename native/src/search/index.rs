@@ -1,10 +1,46 @@
 // Search index module for CloudNexus
 // Phase 1: Simple in-memory index for fuzzy search
 
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
 
+use super::diagnostics::{
+    power_of_two_histogram, AccountDocumentCount, IndexDiagnostics, Pseudonymizer, SlowNameEntry,
+    DIAGNOSTICS_SLOWEST_NAMES,
+};
+use super::query_cache::{QueryCache, QueryCacheStats, QueryKind};
+use super::scoring::ScoringProfile;
+use crate::file_io::is_cancelled;
+use crate::metrics::{self, Counter};
+use crate::name_order::SortLocale;
+use crate::runtime::TrimLevel;
+
+/// Largest multiplier a history term boost can apply
+pub const MAX_TERM_BOOST: f64 = 1.2;
+
+/// Boosted partial matches are capped below an exact match (score 1.0)
+const BOOSTED_PARTIAL_CEILING: f64 = 0.99;
+
+/// Largest number of queries in one search_multi batch
+pub const MAX_MULTI_SEARCH_QUERIES: usize = 16;
+
+/// Results per query of a search_multi batch when the query sets no limit
+pub const DEFAULT_MULTI_SEARCH_LIMIT: usize = 20;
+
+/// Documents scored between two checks of a search's cancel flag and deadline
+pub const SEARCH_BUDGET_CHECK_INTERVAL: usize = 1024;
+
+pub use crate::errors::ERROR_TOO_MANY_QUERIES;
+
 /// Search document structure for indexing
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct SearchDocument {
@@ -18,7 +54,7 @@ pub struct SearchDocument {
 }
 
 /// Search result with score
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct SearchResult {
     pub node_id: String,
     pub name: String,
@@ -29,239 +65,1413 @@ pub struct SearchResult {
 
 /// In-memory search index for Phase 1
 /// Stores documents and provides fuzzy search capabilities
+///
+/// Documents and their postings live in one shard per account, so dropping,
+/// saving or reloading an account touches only that account's structures.
+/// Searches run across the shards and merge the matches by score.
 pub struct SearchIndex {
+    /// Documents and postings by account_id; a shard is dropped with its last document
+    shards: HashMap<String, IndexShard>,
+    /// Account (shard) of each node_id
+    accounts: HashMap<String, String>,
+    /// Bumped on every mutation; cached query results from older generations are dropped
+    generation: u64,
+    /// Recent query results
+    query_cache: Mutex<QueryCache>,
+    /// Lowercased name terms -> score multiplier (1.0 to MAX_TERM_BOOST), from search history
+    term_boosts: HashMap<String, f64>,
+    /// Ordinal of each document, assigned on insert and kept until it is removed
+    ordinals: HashMap<String, u32>,
+    /// node_id by ordinal; None for a freed ordinal
+    ordinal_ids: Vec<Option<String>>,
+    /// Ordinals freed by remove_document, reused oldest first
+    free_ordinals: VecDeque<u32>,
+    /// Child node ids by parent_id (None for root documents), in insertion order
+    children: HashMap<Option<String>, Vec<String>>,
+    /// Trim requested by native_trim_memory and not applied yet: 0 for none,
+    /// else the TRIM_MEMORY_* code plus one
+    pending_trim: Arc<AtomicU8>,
+}
+
+/// Documents and postings of one account
+#[derive(Default)]
+struct IndexShard {
     /// Main document storage by node_id
     documents: HashMap<String, SearchDocument>,
     /// Inverted index for fast name lookup
     name_index: HashMap<String, Vec<String>>,
-    /// Account index for filtering
-    account_index: HashMap<String, Vec<String>>,
+    /// Lowercased names by node_id, computed once at insert time
+    lower_names: HashMap<String, String>,
+    /// Node ids by normalized full name, for duplicate-name grouping
+    name_groups: HashMap<String, Vec<String>>,
+    /// Deleted cloud items kept for "recently deleted": node_id -> deletion time (ms)
+    tombstones: HashMap<String, u64>,
+}
+
+/// How a MultiSearchQuery matches names, as the single searches do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// search_exact: names containing the query
+    #[default]
+    Exact,
+    /// search_prefix: names starting with the query
+    Prefix,
+    /// search_by_account: names containing the query, within `account_id`
+    Account,
+}
+
+/// One named query of a search_multi batch
+#[derive(Debug, Clone, Deserialize)]
+pub struct MultiSearchQuery {
+    /// Key of the query's results in the batch output
+    pub name: String,
+    pub query: String,
+    #[serde(default)]
+    pub mode: SearchMode,
+    /// Account searched by the account mode (required there, ignored otherwise)
+    #[serde(default)]
+    pub account_id: Option<String>,
+    /// Keep only folders (true) or only files (false), applied before the limit
+    #[serde(default)]
+    pub is_folder: Option<bool>,
+    #[serde(default = "default_multi_search_limit")]
+    pub limit: usize,
+    /// Scoring profile of exact and account queries: a built-in name or an
+    /// object of weights (see ScoringProfile); DEFAULT when absent
+    #[serde(default)]
+    pub profile: Option<serde_json::Value>,
+}
+
+fn default_multi_search_limit() -> usize {
+    DEFAULT_MULTI_SEARCH_LIMIT
+}
+
+impl MultiSearchQuery {
+    /// Whether both queries score the same matches (they may differ in filter and limit)
+    fn same_matches(&self, other: &MultiSearchQuery) -> bool {
+        self.mode == other.mode
+            && (self.mode != SearchMode::Account || self.account_id == other.account_id)
+            && lowercase(&self.query) == lowercase(&other.query)
+            && self.profile == other.profile
+    }
+
+    /// The query's scoring profile, DEFAULT when it names none
+    pub fn scoring_profile(&self) -> Result<ScoringProfile, String> {
+        self.profile.as_ref().map_or(Ok(ScoringProfile::DEFAULT), ScoringProfile::from_json)
+    }
+}
+
+/// When a search stops scoring early: a cancel flag and a time budget
+///
+/// Scoring checks both every SEARCH_BUDGET_CHECK_INTERVAL documents. A search
+/// that runs out keeps the matches scored so far, sorted as usual, and does
+/// not cache them.
+pub struct SearchBudget {
+    cancel_flag: *const AtomicBool,
+    deadline: Option<Instant>,
+    truncated: Cell<bool>,
+}
+
+impl SearchBudget {
+    /// No cancel flag and no time limit
+    pub fn unlimited() -> Self {
+        Self::new(ptr::null(), 0)
+    }
+
+    /// Budget of `time_budget_ms` from now (0 for no limit), cancelled through
+    /// `cancel_flag` (can be null)
+    pub fn new(cancel_flag: *const AtomicBool, time_budget_ms: u32) -> Self {
+        let deadline = (time_budget_ms > 0).then(|| Instant::now() + Duration::from_millis(time_budget_ms as u64));
+        Self { cancel_flag, deadline, truncated: Cell::new(false) }
+    }
+
+    /// Whether the search must stop now, remembering that it did
+    fn exhausted(&self) -> bool {
+        if self.truncated.get() {
+            return true;
+        }
+        let exhausted = unsafe { is_cancelled(self.cancel_flag) }
+            || self.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        self.truncated.set(exhausted);
+        exhausted
+    }
+
+    /// Whether any search under this budget stopped early
+    pub fn truncated(&self) -> bool {
+        self.truncated.get()
+    }
+}
+
+/// Documents sharing one normalized name
+#[derive(Debug, Clone, Serialize)]
+pub struct NameDuplicateGroup {
+    /// Normalized (trimmed, lowercased) name shared by the group
+    pub name: String,
+    pub documents: Vec<SearchDocument>,
+}
+
+/// How a document's exact-search score was computed
+#[derive(Debug, Clone, Serialize)]
+pub struct ScoreExplanation {
+    pub node_id: String,
+    pub name: String,
+    /// Name of the scoring profile
+    pub profile: String,
+    /// Score before the term boost
+    pub base_score: f64,
+    /// Multiplier applied (1.0 if no boosted term matched), after the
+    /// profile's recency weight
+    pub term_boost: f64,
+    /// Name term the boost came from
+    pub boosted_term: Option<String>,
+    /// Added by the profile's folder_boost
+    pub folder_boost: f64,
+    pub score: f64,
+}
+
+/// Index statistics reported by get_index_stats
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchIndexStats {
+    pub documents: usize,
+    pub words: usize,
+    pub accounts: usize,
+    pub generation: u64,
+    pub query_cache: QueryCacheStats,
+    /// Parents with a child list (one Vec each, including the root list)
+    pub child_lists: usize,
+    /// Approximate heap bytes held by the child lists
+    pub child_list_bytes: usize,
+    /// Size of each account's shard, by account_id
+    pub shards: Vec<ShardStats>,
+    /// Approximate heap bytes of the index's tables by capacity, without the
+    /// strings they hold; drops when trim_memory shrinks them to fit
+    pub table_bytes: usize,
+}
+
+/// Size of one account's shard
+#[derive(Debug, Clone, Serialize)]
+pub struct ShardStats {
+    pub account_id: String,
+    pub documents: usize,
+    pub words: usize,
+    pub tombstones: usize,
+}
+
+/// Name order of get_children: a SORT_LOCALE_* code, optionally combined with
+/// CHILDREN_FOLDERS_FIRST
+pub const CHILDREN_FOLDERS_FIRST: i32 = 0x100;
+
+/// One page of a folder's children
+#[derive(Debug, Clone, Serialize)]
+pub struct ChildrenPage<'a> {
+    /// Children that are not marked deleted, before pagination
+    pub total: usize,
+    pub offset: usize,
+    pub children: Vec<&'a SearchDocument>,
+}
+
+/// One page of an index export
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentsPage<'a> {
+    /// Documents exported, before pagination
+    pub total: usize,
+    pub offset: usize,
+    pub documents: Vec<&'a SearchDocument>,
 }
 
 impl SearchIndex {
     /// Create a new empty search index
     pub fn new() -> Self {
         SearchIndex {
-            documents: HashMap::new(),
-            name_index: HashMap::new(),
-            account_index: HashMap::new(),
+            shards: HashMap::new(),
+            accounts: HashMap::new(),
+            generation: 0,
+            query_cache: Mutex::new(QueryCache::default()),
+            term_boosts: HashMap::new(),
+            ordinals: HashMap::new(),
+            ordinal_ids: Vec::new(),
+            free_ordinals: VecDeque::new(),
+            children: HashMap::new(),
+            pending_trim: Arc::new(AtomicU8::new(0)),
+        }
+    }
+
+    /// Replace the history term boosts (lowercased term -> multiplier)
+    ///
+    /// Multipliers are clamped to 1.0..=MAX_TERM_BOOST; terms are matched
+    /// against whole alphanumeric words of document names. An empty map
+    /// disables boosting.
+    pub fn set_term_boosts(&mut self, boosts: HashMap<String, f64>) {
+        self.term_boosts = boosts
+            .into_iter()
+            .filter(|(_, boost)| boost.is_finite())
+            .map(|(term, boost)| (term.to_lowercase(), boost.clamp(1.0, MAX_TERM_BOOST)))
+            .filter(|(term, boost)| !term.is_empty() && *boost > 1.0)
+            .collect();
+        // Scores change, so cached results are stale
+        self.generation += 1;
+    }
+
+    /// Reserve room for `additional` documents up front, so loading a large
+    /// index does not rehash the maps repeatedly
+    ///
+    /// Covers the maps shared by all accounts; each shard grows with its account.
+    pub fn reserve(&mut self, additional: usize) {
+        self.accounts.reserve(additional);
+        self.ordinals.reserve(additional);
+        self.ordinal_ids.reserve(additional);
+    }
+
+    /// Set the number of queries whose results are cached (0 disables the cache)
+    pub fn set_query_cache_size(&mut self, entries: usize) {
+        self.query_cache_mut().set_capacity(entries);
+    }
+
+    /// Document, word and query cache statistics
+    pub fn stats(&self) -> SearchIndexStats {
+        let query_cache = match self.query_cache.lock() {
+            Ok(cache) => cache.stats(),
+            Err(poisoned) => poisoned.into_inner().stats(),
+        };
+        let child_list_bytes = self.children
+            .iter()
+            .map(|(parent, ids)| {
+                parent.as_ref().map_or(0, String::capacity)
+                    + ids.capacity() * std::mem::size_of::<String>()
+                    + ids.iter().map(String::capacity).sum::<usize>()
+            })
+            .sum();
+        let mut shards: Vec<ShardStats> = self.shards
+            .iter()
+            .map(|(account_id, shard)| ShardStats {
+                account_id: account_id.clone(),
+                documents: shard.documents.len(),
+                words: shard.name_index.len(),
+                tombstones: shard.tombstones.len(),
+            })
+            .collect();
+        shards.sort_by(|a, b| a.account_id.cmp(&b.account_id));
+        SearchIndexStats {
+            documents: self.len(),
+            words: self.word_postings().len(),
+            accounts: self.shards.len(),
+            generation: self.generation,
+            query_cache,
+            child_lists: self.children.len(),
+            child_list_bytes,
+            shards,
+            table_bytes: self.table_bytes(),
+        }
+    }
+
+    /// Approximate heap bytes held by the index's tables and vectors by capacity
+    fn table_bytes(&self) -> usize {
+        let shards: usize = self.shards.values().map(IndexShard::table_bytes).sum();
+        shards
+            + map_bytes(&self.shards)
+            + map_bytes(&self.accounts)
+            + map_bytes(&self.term_boosts)
+            + map_bytes(&self.ordinals)
+            + self.ordinal_ids.capacity() * std::mem::size_of::<Option<String>>()
+            + self.free_ordinals.capacity() * std::mem::size_of::<u32>()
+            + map_bytes(&self.children)
+            + self.children.values().map(vec_bytes).sum::<usize>()
+    }
+
+    /// Release memory the index can rebuild or does not use
+    ///
+    /// Every level clears the query cache; from TrimLevel::Moderate the tables
+    /// are also shrunk to fit their contents. Returns an estimate of the bytes
+    /// released.
+    pub fn trim_memory(&mut self, level: TrimLevel) -> u64 {
+        let mut released = self.query_cache_mut().clear();
+        if level >= TrimLevel::Moderate {
+            let before = self.table_bytes();
+            for shard in self.shards.values_mut() {
+                shard.shrink_to_fit();
+            }
+            self.shards.shrink_to_fit();
+            self.accounts.shrink_to_fit();
+            self.term_boosts.shrink_to_fit();
+            self.ordinals.shrink_to_fit();
+            self.ordinal_ids.shrink_to_fit();
+            self.free_ordinals.shrink_to_fit();
+            for ids in self.children.values_mut() {
+                ids.shrink_to_fit();
+            }
+            self.children.shrink_to_fit();
+            released += before.saturating_sub(self.table_bytes());
+        }
+        released as u64
+    }
+
+    /// Flag through which another thread asks for a trim of this index
+    ///
+    /// The index is never touched from that thread: the next call on the index
+    /// applies the trim (see apply_pending_trim).
+    pub(crate) fn trim_request(&self) -> Arc<AtomicU8> {
+        Arc::clone(&self.pending_trim)
+    }
+
+    /// Ask, through a trim_request flag, for a trim at `level` or above
+    pub(crate) fn request_trim(flag: &AtomicU8, level: TrimLevel) {
+        flag.fetch_max(level.code() as u8 + 1, Ordering::AcqRel);
+    }
+
+    /// Apply a trim requested through trim_request, from a call that has the
+    /// index to itself. Returns an estimate of the bytes released.
+    pub fn apply_pending_trim(&mut self) -> u64 {
+        match self.pending_trim.swap(0, Ordering::AcqRel) {
+            0 => 0,
+            pending => self.trim_memory(TrimLevel::from_code(pending as i32 - 1)),
+        }
+    }
+
+    /// Apply what a shared call can of a requested trim: the query cache, which
+    /// has its own lock. Table shrinking stays pending for the next call that
+    /// changes the index.
+    pub fn apply_pending_cache_trim(&self) {
+        let pending = self.pending_trim.load(Ordering::Acquire);
+        if pending == 0 {
+            return;
+        }
+        if TrimLevel::from_code(pending as i32 - 1) == TrimLevel::Light {
+            let _ = self.pending_trim.compare_exchange(pending, 0, Ordering::AcqRel, Ordering::Acquire);
+        }
+        self.query_cache.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+
+    /// Number of postings of each distinct word, over all shards
+    fn word_postings(&self) -> HashMap<&str, usize> {
+        let mut words: HashMap<&str, usize> = HashMap::new();
+        for shard in self.shards.values() {
+            for (word, ids) in &shard.name_index {
+                *words.entry(word.as_str()).or_default() += ids.len();
+            }
+        }
+        words
+    }
+
+    /// Shard of an account
+    fn shard(&self, account_id: &str) -> Option<&IndexShard> {
+        self.shards.get(account_id)
+    }
+
+    /// Shard holding a document
+    fn shard_of(&self, node_id: &str) -> Option<&IndexShard> {
+        self.shard(self.accounts.get(node_id)?)
+    }
+
+    fn shard_of_mut(&mut self, node_id: &str) -> Option<&mut IndexShard> {
+        self.shards.get_mut(self.accounts.get(node_id)?)
+    }
+
+    fn query_cache_mut(&mut self) -> &mut QueryCache {
+        match self.query_cache.get_mut() {
+            Ok(cache) => cache,
+            Err(poisoned) => poisoned.into_inner(),
         }
     }
     
     /// Add a document to the index
+    ///
+    /// A new node_id gets the oldest freed ordinal, or the next unused one. A
+    /// node re-added under another account moves to that account's shard,
+    /// keeping its ordinal and tombstone.
     pub fn add_document(&mut self, doc: SearchDocument) {
         let node_id = doc.node_id.clone();
         let name_lower = doc.name.to_lowercase();
         let account_id = doc.account_id.clone();
-        
-        // Add to main document store
-        self.documents.insert(node_id.clone(), doc.clone());
-        
-        // Add to name inverted index (tokenized by word)
-        for word in name_lower.split_whitespace() {
-            if !word.is_empty() {
-                self.name_index
-                    .entry(word.to_string())
-                    .or_insert_with(Vec::new)
-                    .push(node_id.clone());
+
+        let mut tombstone = None;
+        if self.accounts.get(&node_id).is_some_and(|previous| *previous != account_id) {
+            tombstone = self.deleted_at(&node_id);
+            self.remove_postings(&node_id);
+        }
+
+        // Add to the account's shard
+        let parent_id = doc.parent_id.clone();
+        let shard = self.shards.entry(account_id.clone()).or_default();
+        let previous = shard.documents.insert(node_id.clone(), doc);
+        if let Some(deleted_at_ms) = tombstone {
+            shard.tombstones.insert(node_id.clone(), deleted_at_ms);
+        }
+        shard.add_name_postings(node_id.clone(), name_lower);
+        self.accounts.insert(node_id.clone(), account_id);
+        if let Some(previous) = previous {
+            self.unlink_child(&node_id, previous.parent_id);
+        }
+        self.children.entry(parent_id).or_default().push(node_id.clone());
+        self.generation += 1;
+
+        if !self.ordinals.contains_key(&node_id) {
+            let ordinal = match self.free_ordinals.pop_front() {
+                Some(ordinal) => ordinal,
+                None => {
+                    self.ordinal_ids.push(None);
+                    (self.ordinal_ids.len() - 1) as u32
+                }
+            };
+            self.ordinal_ids[ordinal as usize] = Some(node_id.clone());
+            self.ordinals.insert(node_id, ordinal);
+        }
+    }
+
+    /// Add a document, replacing any document with the same node_id
+    ///
+    /// Unlike add_document, re-sending a document does not duplicate its
+    /// postings. A replaced document keeps its ordinal. Returns true if an
+    /// existing document was replaced.
+    pub fn upsert_document(&mut self, doc: SearchDocument) -> bool {
+        let replaced = self.remove_postings(&doc.node_id).is_some();
+        self.add_document(doc);
+        replaced
+    }
+
+    /// Rename a document, replacing only its name postings
+    ///
+    /// The document keeps its ordinal, account postings and tombstone, so a
+    /// renamed deleted document stays excluded from results. Returns false if
+    /// the document is not indexed.
+    pub fn rename_document(&mut self, node_id: &str, new_name: &str) -> bool {
+        let Some(shard) = self.shard_of_mut(node_id) else {
+            return false;
+        };
+        let Some(doc) = shard.documents.get_mut(node_id) else {
+            return false;
+        };
+        let old_lower = shard.lower_names.remove(node_id).unwrap_or_else(|| doc.name.to_lowercase());
+        doc.name = new_name.to_string();
+
+        shard.remove_name_postings(node_id, &old_lower);
+        shard.add_name_postings(node_id.to_string(), new_name.to_lowercase());
+        self.generation += 1;
+        true
+    }
+
+    /// Move a document under `new_parent_id` (None for a root)
+    ///
+    /// Only the stored parent_id and the child lists change; no postings are
+    /// touched and cached query results stay valid. Returns false if the
+    /// document is not indexed.
+    pub fn move_document(&mut self, node_id: &str, new_parent_id: Option<&str>) -> bool {
+        let Some(doc) = self.shard_of_mut(node_id).and_then(|shard| shard.documents.get_mut(node_id)) else {
+            return false;
+        };
+        let new_parent_id = new_parent_id.map(str::to_string);
+        let old_parent_id = std::mem::replace(&mut doc.parent_id, new_parent_id.clone());
+        if old_parent_id != new_parent_id {
+            self.unlink_child(node_id, old_parent_id);
+            self.children.entry(new_parent_id).or_default().push(node_id.to_string());
+        }
+        true
+    }
+
+    /// Remove a node from its parent's child list, dropping the list once empty
+    fn unlink_child(&mut self, node_id: &str, parent_id: Option<String>) {
+        if let Some(ids) = self.children.get_mut(&parent_id) {
+            ids.retain(|id| id != node_id);
+            if ids.is_empty() {
+                self.children.remove(&parent_id);
             }
         }
+    }
+
+    /// Children of `parent_id` (None for root documents), sorted and paginated
+    ///
+    /// `sort` is a SORT_LOCALE_* code for the name order, optionally combined
+    /// with CHILDREN_FOLDERS_FIRST to list folders before files. Documents
+    /// marked deleted are left out. `limit` 0 returns every child after
+    /// `offset`. None for an unknown sort.
+    pub fn get_children(&self, parent_id: Option<&str>, sort: i32, offset: usize, limit: usize) -> Option<ChildrenPage<'_>> {
+        let locale = SortLocale::from_code(sort & !CHILDREN_FOLDERS_FIRST)?;
+        let folders_first = sort & CHILDREN_FOLDERS_FIRST != 0;
+
+        let mut children: Vec<&SearchDocument> = self.children
+            .get(&parent_id.map(str::to_string))
+            .into_iter()
+            .flatten()
+            .filter(|id| self.deleted_at(id).is_none())
+            .filter_map(|id| self.get(id))
+            .collect();
+        children.sort_by(|a, b| {
+            let folders = if folders_first { b.is_folder.cmp(&a.is_folder) } else { std::cmp::Ordering::Equal };
+            folders.then_with(|| locale.compare_str(&a.name, &b.name)).then_with(|| a.node_id.cmp(&b.node_id))
+        });
+
+        let total = children.len();
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let children = children.into_iter().skip(offset).take(limit).collect();
+        Some(ChildrenPage { total, offset, children })
+    }
+
+    /// Add a batch of documents to the index
+    /// Returns the number of documents added
+    pub fn add_documents<I: IntoIterator<Item = SearchDocument>>(&mut self, docs: I) -> usize {
+        let docs = docs.into_iter();
+        self.reserve(docs.size_hint().0);
         
-        // Add to account index
-        self.account_index
-            .entry(account_id)
-            .or_insert_with(Vec::new)
-            .push(node_id);
+        let mut count = 0;
+        for doc in docs {
+            self.add_document(doc);
+            count += 1;
+        }
+        count
     }
     
     /// Remove a document from the index
+    ///
+    /// Its ordinal is freed for reuse by a later insert; other documents keep theirs.
     pub fn remove_document(&mut self, node_id: &str) -> Option<SearchDocument> {
-        if let Some(doc) = self.documents.remove(node_id) {
-            let name_lower = doc.name.to_lowercase();
-            
-            // Remove from name index
-            for word in name_lower.split_whitespace() {
-                if let Some(ids) = self.name_index.get_mut(word) {
-                    ids.retain(|id| id != node_id);
-                    if ids.is_empty() {
-                        self.name_index.remove(word);
-                    }
-                }
-            }
-            
-            // Remove from account index
-            if let Some(ids) = self.account_index.get_mut(&doc.account_id) {
-                ids.retain(|id| id != node_id);
-                if ids.is_empty() {
-                    self.account_index.remove(&doc.account_id);
-                }
+        let doc = self.remove_postings(node_id)?;
+        if let Some(ordinal) = self.ordinals.remove(node_id) {
+            self.ordinal_ids[ordinal as usize] = None;
+            self.free_ordinals.push_back(ordinal);
+        }
+        Some(doc)
+    }
+
+    /// Remove a document and its postings, keeping its ordinal
+    fn remove_postings(&mut self, node_id: &str) -> Option<SearchDocument> {
+        let account_id = self.accounts.remove(node_id)?;
+        let shard = self.shards.get_mut(&account_id)?;
+        let doc = shard.remove(node_id)?;
+        if shard.documents.is_empty() {
+            self.shards.remove(&account_id);
+        }
+        self.generation += 1;
+        self.unlink_child(node_id, doc.parent_id.clone());
+        Some(doc)
+    }
+
+    /// Remove every document of an account by dropping its shard
+    ///
+    /// Other accounts' postings are not touched; only the ordinals and child
+    /// lists shared by all accounts are updated for the removed documents.
+    /// Returns the number of documents removed.
+    pub fn remove_account(&mut self, account_id: &str) -> usize {
+        let Some(shard) = self.shards.remove(account_id) else {
+            return 0;
+        };
+        let mut freed = Vec::with_capacity(shard.documents.len());
+        for (node_id, doc) in &shard.documents {
+            self.accounts.remove(node_id);
+            self.unlink_child(node_id, doc.parent_id.clone());
+            if let Some(ordinal) = self.ordinals.remove(node_id) {
+                self.ordinal_ids[ordinal as usize] = None;
+                freed.push(ordinal);
             }
-            
-            Some(doc)
-        } else {
-            None
         }
+        // Freed in ascending order, so reuse does not depend on hash order
+        freed.sort_unstable();
+        self.free_ordinals.extend(freed);
+        self.generation += 1;
+        shard.documents.len()
     }
     
     /// Clear all documents from the index
+    ///
+    /// Ordinals start again from 0.
     pub fn clear(&mut self) {
-        self.documents.clear();
-        self.name_index.clear();
-        self.account_index.clear();
+        self.shards.clear();
+        self.accounts.clear();
+        self.ordinals.clear();
+        self.ordinal_ids.clear();
+        self.free_ordinals.clear();
+        self.children.clear();
+        self.generation += 1;
+    }
+
+    /// Mark a document as deleted in the cloud at `deleted_at_ms`
+    ///
+    /// The document stays retrievable through get() but is excluded from all
+    /// search results until it is purged. Marking it again updates the deletion
+    /// time; re-adding it with upsert_document restores it. Returns false if
+    /// the document is not indexed.
+    pub fn mark_deleted(&mut self, node_id: &str, deleted_at_ms: u64) -> bool {
+        let Some(shard) = self.shard_of_mut(node_id) else {
+            return false;
+        };
+        shard.tombstones.insert(node_id.to_string(), deleted_at_ms);
+        self.generation += 1;
+        true
+    }
+
+    /// Physically remove documents marked deleted before `older_than_ms`
+    /// Returns the number of documents removed
+    pub fn purge_tombstones(&mut self, older_than_ms: u64) -> usize {
+        let expired: Vec<String> = self.shards
+            .values()
+            .flat_map(|shard| &shard.tombstones)
+            .filter(|(_, deleted_at_ms)| **deleted_at_ms < older_than_ms)
+            .map(|(node_id, _)| node_id.clone())
+            .collect();
+        for node_id in &expired {
+            self.remove_document(node_id);
+        }
+        expired.len()
+    }
+
+    /// Deletion time (ms) of a tombstoned document
+    pub fn deleted_at(&self, node_id: &str) -> Option<u64> {
+        self.shard_of(node_id)?.tombstones.get(node_id).copied()
+    }
+
+    /// Number of documents marked deleted but not yet purged
+    pub fn tombstone_count(&self) -> usize {
+        self.shards.values().map(|shard| shard.tombstones.len()).sum()
     }
     
-    /// Get document by node_id
+    /// Get document by node_id, including documents marked deleted
     pub fn get(&self, node_id: &str) -> Option<&SearchDocument> {
-        self.documents.get(node_id)
+        self.shard_of(node_id)?.documents.get(node_id)
     }
     
+    /// Ordinal of a document
+    ///
+    /// Ordinals are small integers standing in for node ids in the `_ids`
+    /// searches. A document keeps its ordinal while it is indexed, including
+    /// across upsert_document and mark_deleted, whatever happens to other
+    /// documents. After remove_document (or a purge) the ordinal is reused by a
+    /// later insert, so holders of an old ordinal should re-resolve it against
+    /// the current index. Ordinals are not persisted.
+    pub fn ordinal(&self, node_id: &str) -> Option<u32> {
+        self.ordinals.get(node_id).copied()
+    }
+
+    /// Document holding an ordinal, including documents marked deleted
+    pub fn get_by_ordinal(&self, ordinal: u32) -> Option<&SearchDocument> {
+        let node_id = self.ordinal_ids.get(ordinal as usize)?.as_ref()?;
+        self.get(node_id)
+    }
+
     /// Get number of documents in index
     pub fn len(&self) -> usize {
-        self.documents.len()
+        self.accounts.len()
     }
     
     /// Check if index is empty
     pub fn is_empty(&self) -> bool {
-        self.documents.is_empty()
+        self.accounts.is_empty()
     }
     
     /// Search with exact matching
     pub fn search_exact(&self, query: &str, limit: usize) -> Vec<SearchResult> {
-        let query_lower = query.to_lowercase();
-        let mut results = Vec::new();
-        
-        for (node_id, doc) in &self.documents {
-            if doc.name.to_lowercase().contains(&query_lower) {
-                let score = if doc.name.to_lowercase() == query_lower {
-                    1.0
-                } else if doc.name.to_lowercase().starts_with(&query_lower) {
-                    0.9
-                } else {
-                    // For partial matches, calculate a more refined score
-                    // based on how much of the query matches the name
-                    let name_lower = doc.name.to_lowercase();
-                    let match_position = name_lower.find(&query_lower).unwrap_or(0);
-                    
-                    // Calculate bonus for early match position
-                    let position_bonus = if match_position == 0 {
-                        0.1
-                    } else {
-                        0.0
-                    };
-                    
-                    // Calculate bonus based on query being a word boundary match
-                    let word_boundary_bonus = if match_position == 0 ||
-                        name_lower.chars().nth(match_position - 1) == Some(' ') {
-                        0.05
-                    } else {
-                        0.0
+        self.search_exact_with_profile(query, limit, &ScoringProfile::DEFAULT)
+    }
+
+    /// Search with exact matching, ranked by `profile`
+    pub fn search_exact_with_profile(&self, query: &str, limit: usize, profile: &ScoringProfile) -> Vec<SearchResult> {
+        self.with_exact_matches(&mut self.lock_query_cache(), query, profile, &SearchBudget::unlimited(),
+                                |scored| self.to_results(scored, limit))
+    }
+
+    /// Exact search writing result ordinals and scores into caller buffers
+    ///
+    /// Returns the number of results written, at most `limit` and the buffer
+    /// length (`out_scores`, if given, must be at least as long as `out_ids`).
+    /// No result strings are built; a repeated lowercase query is answered from
+    /// the query cache without allocating at all.
+    pub fn search_exact_ids(&self, query: &str, limit: usize, out_ids: &mut [u32], out_scores: Option<&mut [f64]>) -> usize {
+        self.with_exact_matches(&mut self.lock_query_cache(), query, &ScoringProfile::DEFAULT, &SearchBudget::unlimited(),
+                                |scored| {
+            self.write_ids(scored, limit, out_ids, out_scores)
+        })
+    }
+
+    fn with_exact_matches<R>(&self, cache: &mut QueryCache, query: &str, profile: &ScoringProfile,
+                             budget: &SearchBudget, f: impl FnOnce(&[(String, f64)]) -> R) -> R {
+        let query_lower = lowercase(query);
+        self.cached_search(
+            cache,
+            QueryKind::Exact,
+            &query_lower,
+            profile,
+            budget,
+            || self.accounts.keys().cloned().collect(),
+            |name_lower| profile.score_name(name_lower, &query_lower),
+            f,
+        )
+    }
+    
+    /// Search with prefix matching
+    pub fn search_prefix(&self, query: &str, limit: usize) -> Vec<SearchResult> {
+        self.with_prefix_matches(&mut self.lock_query_cache(), query, &SearchBudget::unlimited(),
+                                 |scored| self.to_results(scored, limit))
+    }
+
+    /// Prefix search writing result ordinals and scores into caller buffers
+    /// (see search_exact_ids)
+    pub fn search_prefix_ids(&self, query: &str, limit: usize, out_ids: &mut [u32], out_scores: Option<&mut [f64]>) -> usize {
+        self.with_prefix_matches(&mut self.lock_query_cache(), query, &SearchBudget::unlimited(), |scored| {
+            self.write_ids(scored, limit, out_ids, out_scores)
+        })
+    }
+
+    fn with_prefix_matches<R>(&self, cache: &mut QueryCache, query: &str, budget: &SearchBudget,
+                              f: impl FnOnce(&[(String, f64)]) -> R) -> R {
+        let query_lower = lowercase(query);
+        
+        // Candidates come from an exact prefix match in each shard's name index
+        let candidates = || {
+            let mut node_ids = Vec::new();
+            for word in query_lower.split_whitespace() {
+                for shard in self.shards.values() {
+                    if let Some(ids) = shard.name_index.get(word) {
+                        node_ids.extend(ids.iter().cloned());
+                    }
+                }
+            }
+            node_ids
+        };
+        
+        // Check if name starts with query
+        self.cached_search(
+            cache,
+            QueryKind::Prefix,
+            &query_lower,
+            &ScoringProfile::DEFAULT,
+            budget,
+            candidates,
+            |name_lower| if name_lower.starts_with(query_lower.as_ref()) { Some(0.95) } else { None },
+            f,
+        )
+    }
+    
+    /// Search within specific account
+    pub fn search_by_account(&self, query: &str, account_id: &str, limit: usize) -> Vec<SearchResult> {
+        self.search_by_account_with_profile(query, account_id, limit, &ScoringProfile::DEFAULT)
+    }
+
+    /// Search within specific account, ranked by `profile`
+    pub fn search_by_account_with_profile(&self, query: &str, account_id: &str, limit: usize,
+                                          profile: &ScoringProfile) -> Vec<SearchResult> {
+        self.with_account_matches(&mut self.lock_query_cache(), query, account_id, profile, &SearchBudget::unlimited(),
+                                  |scored| {
+            self.to_results(scored, limit)
+        })
+    }
+
+    /// Account search writing result ordinals and scores into caller buffers
+    /// (see search_exact_ids; the account filter key is the only allocation on a cache hit)
+    pub fn search_by_account_ids(&self, query: &str, account_id: &str, limit: usize, out_ids: &mut [u32],
+                                 out_scores: Option<&mut [f64]>) -> usize {
+        self.with_account_matches(&mut self.lock_query_cache(), query, account_id, &ScoringProfile::DEFAULT,
+                                  &SearchBudget::unlimited(), |scored| {
+            self.write_ids(scored, limit, out_ids, out_scores)
+        })
+    }
+
+    fn with_account_matches<R>(&self, cache: &mut QueryCache, query: &str, account_id: &str, profile: &ScoringProfile,
+                               budget: &SearchBudget, f: impl FnOnce(&[(String, f64)]) -> R) -> R {
+        let query_lower = lowercase(query);
+        self.cached_search(
+            cache,
+            QueryKind::Account(account_id.to_string()),
+            &query_lower,
+            profile,
+            budget,
+            || self.shard(account_id).map(|shard| shard.documents.keys().cloned().collect()).unwrap_or_default(),
+            |name_lower| profile.score_name(name_lower, &query_lower),
+            f,
+        )
+    }
+
+    /// Run a batch of named queries, taking the query cache lock once
+    ///
+    /// Returns the results of each query in order, the same the matching single
+    /// search (see SearchMode) returns, with the is_folder filter applied before
+    /// the limit. Queries differing only in filter or limit are scored once.
+    /// Exact and account queries are ranked by their profile; an invalid
+    /// profile ranks with DEFAULT (search_index_multi_json rejects it first).
+    pub fn search_multi(&self, queries: &[MultiSearchQuery]) -> Vec<Vec<SearchResult>> {
+        self.search_multi_with_budget(queries, &SearchBudget::unlimited())
+    }
+
+    /// search_multi that stops scoring once `budget` is cancelled or used up
+    ///
+    /// Queries cut short return their best matches so far (budget.truncated()
+    /// tells), and queries after that only get results already in the query
+    /// cache. The query cache lock is released as soon as the batch returns.
+    pub fn search_multi_with_budget(&self, queries: &[MultiSearchQuery], budget: &SearchBudget)
+        -> Vec<Vec<SearchResult>> {
+        let mut cache = self.lock_query_cache();
+        let mut shared: Vec<(&MultiSearchQuery, Vec<(String, f64)>)> = Vec::new();
+        let mut results = Vec::with_capacity(queries.len());
+
+        for query in queries {
+            let position = match shared.iter().position(|(other, _)| other.same_matches(query)) {
+                Some(position) => position,
+                None => {
+                    let profile = query.scoring_profile().unwrap_or_default();
+                    let scored = match query.mode {
+                        SearchMode::Exact => {
+                            self.with_exact_matches(&mut cache, &query.query, &profile, budget, <[_]>::to_vec)
+                        }
+                        SearchMode::Prefix => self.with_prefix_matches(&mut cache, &query.query, budget, <[_]>::to_vec),
+                        SearchMode::Account => {
+                            let account_id = query.account_id.as_deref().unwrap_or_default();
+                            self.with_account_matches(&mut cache, &query.query, account_id, &profile, budget,
+                                                      <[_]>::to_vec)
+                        }
                     };
-                    
-                    0.7 + position_bonus + word_boundary_bonus
-                };
-                
-                results.push(SearchResult {
-                    node_id: node_id.clone(),
+                    shared.push((query, scored));
+                    shared.len() - 1
+                }
+            };
+            let scored = &shared[position].1;
+            results.push(self.to_results_where(scored, query.limit, |doc| {
+                query.is_folder.is_none_or(|is_folder| doc.is_folder == is_folder)
+            }));
+        }
+        results
+    }
+
+    fn lock_query_cache(&self) -> MutexGuard<'_, QueryCache> {
+        match self.query_cache.lock() {
+            Ok(cache) => cache,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Score candidates for a query, going through the query cache, and hand
+    /// the sorted matches to `f`
+    ///
+    /// An identical cached query is returned as is. A query extending a cached
+    /// query of the same kind only re-scores that query's matches; otherwise all
+    /// `candidates` are scored. `profile` adds its folder and history boosts to
+    /// the `score` of a name; results of other profiles than DEFAULT are cached
+    /// apart. Matches from all shards are merged by score (descending) to
+    /// return most relevant results first, ties by node_id, each node once. Scoring stops when
+    /// `budget` runs out, and the partial results are not cached.
+    #[allow(clippy::too_many_arguments)]
+    fn cached_search<C, S, R>(&self, cache: &mut QueryCache, kind: QueryKind, query_lower: &str,
+                              profile: &ScoringProfile, budget: &SearchBudget, candidates: C, score: S,
+                              f: impl FnOnce(&[(String, f64)]) -> R) -> R
+    where
+        C: FnOnce() -> Vec<String>,
+        S: Fn(&str) -> Option<f64>,
+    {
+        metrics::count(Counter::Searches, 1);
+        let kind = if *profile == ScoringProfile::DEFAULT {
+            kind
+        } else {
+            QueryKind::Profiled(Box::new(kind), profile.cache_key())
+        };
+        if let Some(results) = cache.get(self.generation, &kind, query_lower) {
+            metrics::count(Counter::SearchCacheHits, 1);
+            return f(results);
+        }
+
+        let continuation = profile
+            .supports_continuation()
+            .then(|| cache.continuation_candidates(self.generation, &kind, query_lower))
+            .flatten();
+        let node_ids = continuation.unwrap_or_else(candidates);
+
+        let mut scored: Vec<(String, f64)> = Vec::new();
+        for (position, node_id) in node_ids.into_iter().enumerate() {
+            if position % SEARCH_BUDGET_CHECK_INTERVAL == 0 && budget.exhausted() {
+                break;
+            }
+            let Some(shard) = self.shard_of(&node_id) else { continue };
+            if shard.tombstones.contains_key(&node_id) {
+                continue;
+            }
+            let Some(name_lower) = shard.lower_names.get(&node_id) else { continue };
+            let Some(s) = score(name_lower) else { continue };
+            let boost = self.term_boost(name_lower, query_lower).map_or(1.0, |(_, boost)| profile.history_boost(boost));
+            let is_folder = profile.folder_boost > 0.0 && shard.documents.get(&node_id).is_some_and(|doc| doc.is_folder);
+            scored.push((node_id, boosted_score(s, boost, profile.exact) + profile.folder_bonus(is_folder)));
+        }
+        scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then_with(|| a.0.cmp(&b.0)));
+        // A node listed by several candidate sources (e.g. two prefix query words) scores the same each time
+        scored.dedup_by(|a, b| a.0 == b.0);
+
+        let result = f(&scored);
+        if !budget.truncated() {
+            cache.insert(self.generation, kind, query_lower.to_string(), scored);
+        }
+        result
+    }
+
+    /// Largest history boost of a name word that a query token is a prefix of
+    fn term_boost<'a>(&'a self, name_lower: &'a str, query_lower: &str) -> Option<(&'a str, f64)> {
+        if self.term_boosts.is_empty() {
+            return None;
+        }
+
+        let query_tokens: Vec<&str> = words(query_lower).collect();
+        words(name_lower)
+            .filter(|word| query_tokens.iter().any(|token| word.starts_with(token)))
+            .filter_map(|word| Some((word, *self.term_boosts.get(word)?)))
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+    }
+
+    /// Explain the exact-search score of one document, including any term boost
+    pub fn explain_score(&self, node_id: &str, query: &str) -> Option<ScoreExplanation> {
+        self.explain_score_with_profile(node_id, query, &ScoringProfile::DEFAULT)
+    }
+
+    /// Explain the exact-search score of one document under `profile`
+    pub fn explain_score_with_profile(&self, node_id: &str, query: &str, profile: &ScoringProfile)
+        -> Option<ScoreExplanation> {
+        let shard = self.shard_of(node_id)?;
+        if shard.tombstones.contains_key(node_id) {
+            return None;
+        }
+        let query_lower = query.to_lowercase();
+        let doc = shard.documents.get(node_id)?;
+        let name_lower = shard.lower_names.get(node_id)?;
+        let base_score = profile.score_name(name_lower, &query_lower)?;
+        let (boosted_term, term_boost) = match self.term_boost(name_lower, &query_lower) {
+            Some((term, boost)) => (Some(term.to_string()), profile.history_boost(boost)),
+            None => (None, 1.0),
+        };
+        let folder_boost = profile.folder_bonus(doc.is_folder);
+
+        Some(ScoreExplanation {
+            node_id: node_id.to_string(),
+            name: doc.name.clone(),
+            profile: profile.name.to_string(),
+            base_score,
+            term_boost,
+            boosted_term,
+            folder_boost,
+            score: boosted_score(base_score, term_boost, profile.exact) + folder_boost,
+        })
+    }
+
+    /// Build the top `limit` results from scored node ids
+    fn to_results(&self, scored: &[(String, f64)], limit: usize) -> Vec<SearchResult> {
+        self.to_results_where(scored, limit, |_| true)
+    }
+
+    /// Build the top `limit` results from the scored node ids whose document passes `keep`
+    fn to_results_where(&self, scored: &[(String, f64)], limit: usize, keep: impl Fn(&SearchDocument) -> bool)
+        -> Vec<SearchResult> {
+        scored
+            .iter()
+            .filter_map(|(node_id, score)| {
+                let doc = self.get(node_id).filter(|doc| keep(doc))?;
+                Some(SearchResult {
                     name: doc.name.clone(),
-                    score,
+                    score: *score,
                     account_id: doc.account_id.clone(),
                     provider: doc.provider.clone(),
-                });
+                    node_id: node_id.clone(),
+                })
+            })
+            .take(limit)
+            .collect()
+    }
+
+    /// Write the ordinals (and scores) of the top `limit` scored node ids
+    fn write_ids(&self, scored: &[(String, f64)], limit: usize, out_ids: &mut [u32], mut out_scores: Option<&mut [f64]>) -> usize {
+        let limit = limit.min(out_ids.len());
+        let matches = scored.iter().filter_map(|(node_id, score)| Some((self.ordinals.get(node_id)?, score)));
+        let mut written = 0;
+        for (ordinal, score) in matches.take(limit) {
+            out_ids[written] = *ordinal;
+            if let Some(scores) = out_scores.as_deref_mut() {
+                scores[written] = *score;
             }
+            written += 1;
         }
-        
-        // Sort by score (descending) to return most relevant results first
-        results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
-        
-        // Apply limit after sorting to get top results
-        results.into_iter().take(limit).collect()
+        written
     }
     
-    /// Search with prefix matching
-    pub fn search_prefix(&self, query: &str, limit: usize) -> Vec<SearchResult> {
-        let query_lower = query.to_lowercase();
-        let mut results = Vec::new();
-        
-        // First, try exact prefix match in name index
-        for word in query_lower.split_whitespace() {
-            if let Some(node_ids) = self.name_index.get(word) {
-                for node_id in node_ids {
-                    if let Some(doc) = self.documents.get(node_id) {
-                        // Check if name starts with query
-                        if doc.name.to_lowercase().starts_with(&query_lower) {
-                            results.push(SearchResult {
-                                node_id: node_id.clone(),
-                                name: doc.name.clone(),
-                                score: 0.95,
-                                account_id: doc.account_id.clone(),
-                                provider: doc.provider.clone(),
-                            });
-                        }
-                    }
-                }
+    /// Find files and folders that share the same name
+    ///
+    /// Names are compared after trimming and lowercasing. Only groups with at
+    /// least `min_count` members (minimum 2) are returned, largest first, ties by
+    /// name; members are ordered by account then node_id. At most `limit` groups
+    /// are returned (0 for no limit).
+    pub fn find_name_duplicates(&self, min_count: usize, limit: usize) -> Vec<NameDuplicateGroup> {
+        let min_count = min_count.max(2);
+
+        // A name's group spans every shard holding it
+        let mut members: HashMap<&str, Vec<&SearchDocument>> = HashMap::new();
+        for shard in self.shards.values() {
+            for (name, ids) in &shard.name_groups {
+                members.entry(name.as_str()).or_default().extend(ids.iter().filter_map(|id| shard.documents.get(id)));
+            }
+        }
+
+        let mut groups: Vec<NameDuplicateGroup> = members
+            .into_iter()
+            .filter(|(_, documents)| documents.len() >= min_count)
+            .map(|(name, documents)| {
+                let mut documents: Vec<SearchDocument> = documents.into_iter().cloned().collect();
+                documents.sort_by(|a, b| a.account_id.cmp(&b.account_id).then_with(|| a.node_id.cmp(&b.node_id)));
+                NameDuplicateGroup { name: name.to_string(), documents }
+            })
+            .collect();
+
+        groups.sort_by(|a, b| b.documents.len().cmp(&a.documents.len()).then_with(|| a.name.cmp(&b.name)));
+        if limit > 0 {
+            groups.truncate(limit);
+        }
+        groups
+    }
+
+    /// Snapshot of the index for bug reports
+    ///
+    /// With a pseudonymizer, reported names are replaced by their pseudonyms;
+    /// all counts are computed from the original names either way.
+    pub fn diagnostics(&self, redactor: Option<&Pseudonymizer>) -> IndexDiagnostics {
+        let words = self.word_postings();
+        let token_length_histogram = power_of_two_histogram(words.keys().map(|word| word.chars().count()));
+        let posting_size_histogram = power_of_two_histogram(words.values().copied());
+
+        let mut longest: Vec<&SearchDocument> = self.documents_for_account(None).collect();
+        longest.sort_by_cached_key(|doc| (std::cmp::Reverse(doc.name.chars().count()), doc.node_id.clone()));
+        let slowest_names = longest
+            .into_iter()
+            .take(DIAGNOSTICS_SLOWEST_NAMES)
+            .map(|doc| SlowNameEntry {
+                name: match redactor {
+                    Some(redactor) => redactor.pseudonym(&doc.name),
+                    None => doc.name.clone(),
+                },
+                chars: doc.name.chars().count(),
+                words: doc.name.split_whitespace().count(),
+            })
+            .collect();
+
+        let mut accounts: Vec<AccountDocumentCount> = self.shards
+            .iter()
+            .map(|(account_id, shard)| AccountDocumentCount {
+                account_id: account_id.clone(),
+                documents: shard.documents.len(),
+            })
+            .collect();
+        accounts.sort_by(|a, b| a.account_id.cmp(&b.account_id));
+
+        IndexDiagnostics {
+            redacted: redactor.is_some(),
+            stats: self.stats(),
+            token_length_histogram,
+            posting_size_histogram,
+            slowest_names,
+            accounts,
+        }
+    }
+
+    /// Get all documents for an account, in iter_sorted order
+    pub fn get_by_account(&self, account_id: &str) -> Vec<&SearchDocument> {
+        self.sorted_documents(Some(account_id))
+    }
+
+    /// Iterate every document ordered by (account_id, parent_id, name, node_id)
+    ///
+    /// The order is computed per call, so two dumps of the same index are
+    /// identical. Documents marked deleted are included.
+    pub fn iter_sorted(&self) -> impl Iterator<Item = &SearchDocument> {
+        self.sorted_documents(None).into_iter()
+    }
+
+    /// Documents of one account (all accounts for None) in iter_sorted order
+    pub fn sorted_documents(&self, account_id: Option<&str>) -> Vec<&SearchDocument> {
+        let mut documents: Vec<&SearchDocument> = self.documents_for_account(account_id).collect();
+        documents.sort_unstable_by(|a, b| {
+            (&a.account_id, &a.parent_id, &a.name, &a.node_id).cmp(&(&b.account_id, &b.parent_id, &b.name, &b.node_id))
+        });
+        documents
+    }
+
+    /// One page of sorted_documents; `limit` 0 returns every document after `offset`
+    pub fn export_documents(&self, account_id: Option<&str>, offset: usize, limit: usize) -> DocumentsPage<'_> {
+        let documents = self.sorted_documents(account_id);
+        let total = documents.len();
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let documents = documents.into_iter().skip(offset).take(limit).collect();
+        DocumentsPage { total, offset, documents }
+    }
+
+    /// Iterate documents of one account, or of all accounts when `account_id` is None,
+    /// without copying them
+    pub fn documents_for_account<'a>(&'a self, account_id: Option<&str>) -> Box<dyn Iterator<Item = &'a SearchDocument> + 'a> {
+        match account_id {
+            None => Box::new(self.shards.values().flat_map(|shard| shard.documents.values())),
+            Some(account_id) => match self.shard(account_id) {
+                Some(shard) => Box::new(shard.documents.values()),
+                None => Box::new(std::iter::empty()),
+            },
+        }
+    }
+}
+
+impl Default for SearchIndex {
+    fn default() -> Self {
+        SearchIndex::new()
+    }
+}
+
+/// Heap bytes of a hash map's table by capacity (entries plus one control byte each)
+fn map_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * (std::mem::size_of::<(K, V)>() + 1)
+}
+
+// Takes the Vec itself: a slice does not know the capacity
+#[allow(clippy::ptr_arg)]
+fn vec_bytes<T>(items: &Vec<T>) -> usize {
+    items.capacity() * std::mem::size_of::<T>()
+}
+
+impl IndexShard {
+    fn table_bytes(&self) -> usize {
+        map_bytes(&self.documents)
+            + map_bytes(&self.name_index)
+            + self.name_index.values().map(vec_bytes).sum::<usize>()
+            + map_bytes(&self.lower_names)
+            + map_bytes(&self.name_groups)
+            + self.name_groups.values().map(vec_bytes).sum::<usize>()
+            + map_bytes(&self.tombstones)
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.documents.shrink_to_fit();
+        for ids in self.name_index.values_mut() {
+            ids.shrink_to_fit();
+        }
+        self.name_index.shrink_to_fit();
+        self.lower_names.shrink_to_fit();
+        for ids in self.name_groups.values_mut() {
+            ids.shrink_to_fit();
+        }
+        self.name_groups.shrink_to_fit();
+        self.tombstones.shrink_to_fit();
+    }
+
+    /// Add the name postings (words and duplicate group) of a node
+    fn add_name_postings(&mut self, node_id: String, name_lower: String) {
+        // Add to name inverted index (tokenized by word)
+        for word in name_lower.split_whitespace() {
+            if !word.is_empty() {
+                self.name_index
+                    .entry(word.to_string())
+                    .or_default()
+                    .push(node_id.clone());
             }
         }
-        
-        // Remove duplicates and limit results
-        results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
-        results.into_iter().take(limit).collect()
+
+        self.name_groups
+            .entry(normalize_name(&name_lower))
+            .or_default()
+            .push(node_id.clone());
+        self.lower_names.insert(node_id, name_lower);
     }
-    
-    /// Search within specific account
-    pub fn search_by_account(&self, query: &str, account_id: &str, limit: usize) -> Vec<SearchResult> {
-        let query_lower = query.to_lowercase();
-        let mut results = Vec::new();
-        
-        if let Some(node_ids) = self.account_index.get(account_id) {
-            for node_id in node_ids {
-                if let Some(doc) = self.documents.get(node_id) {
-                    if doc.name.to_lowercase().contains(&query_lower) {
-                        let score = if doc.name.to_lowercase() == query_lower {
-                            1.0
-                        } else if doc.name.to_lowercase().starts_with(&query_lower) {
-                            0.9
-                        } else {
-                            0.7
-                        };
-                        
-                        results.push(SearchResult {
-                            node_id: node_id.clone(),
-                            name: doc.name.clone(),
-                            score,
-                            account_id: doc.account_id.clone(),
-                            provider: doc.provider.clone(),
-                        });
-                    }
+
+    /// Remove the name postings added by add_name_postings
+    fn remove_name_postings(&mut self, node_id: &str, name_lower: &str) {
+        // Remove from name index
+        for word in name_lower.split_whitespace() {
+            if let Some(ids) = self.name_index.get_mut(word) {
+                ids.retain(|id| id != node_id);
+                if ids.is_empty() {
+                    self.name_index.remove(word);
                 }
             }
         }
-        
-        results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
-        results.into_iter().take(limit).collect()
+
+        // Remove from name groups
+        let group_key = normalize_name(name_lower);
+        if let Some(ids) = self.name_groups.get_mut(&group_key) {
+            ids.retain(|id| id != node_id);
+            if ids.is_empty() {
+                self.name_groups.remove(&group_key);
+            }
+        }
     }
     
-    /// Get all documents for an account
-    pub fn get_by_account(&self, account_id: &str) -> Vec<&SearchDocument> {
-        if let Some(node_ids) = self.account_index.get(account_id) {
-            node_ids.iter()
-                .filter_map(|id| self.documents.get(id))
-                .collect()
-        } else {
-            Vec::new()
+
+    /// Remove a document with its postings and tombstone
+    fn remove(&mut self, node_id: &str) -> Option<SearchDocument> {
+        let doc = self.documents.remove(node_id)?;
+        let name_lower = self.lower_names.remove(node_id).unwrap_or_else(|| doc.name.to_lowercase());
+        self.remove_name_postings(node_id, &name_lower);
+        self.tombstones.remove(node_id);
+        Some(doc)
+    }
+}
+
+/// Lowercase a query, borrowing it when it is lowercase already
+fn lowercase(query: &str) -> Cow<'_, str> {
+    if query.chars().flat_map(char::to_lowercase).eq(query.chars()) {
+        Cow::Borrowed(query)
+    } else {
+        Cow::Owned(query.to_lowercase())
+    }
+}
+
+/// Normalize a lowercased name for duplicate grouping
+fn normalize_name(name_lower: &str) -> String {
+    name_lower.trim().to_string()
+}
+
+/// Alphanumeric words of a lowercased name or query
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty())
+}
+
+/// Apply a term boost; exact matches (scored `exact`) keep their score and
+/// boosted partial matches stay below them
+fn boosted_score(score: f64, boost: f64, exact: f64) -> f64 {
+    if score >= exact || boost <= 1.0 {
+        score
+    } else {
+        (score * boost).min(exact * BOOSTED_PARTIAL_CEILING).max(score)
+    }
+}
+
+/// On-disk form of a persistent index
+#[derive(Serialize, Deserialize)]
+struct PersistedIndex {
+    documents: HashMap<String, SearchDocument>,
+    #[serde(default)]
+    tombstones: HashMap<String, u64>,
+}
+
+/// Borrowed PersistedIndex, serialized without copying the documents
+#[derive(Serialize)]
+struct PersistedIndexRef<'a> {
+    documents: HashMap<&'a str, &'a SearchDocument>,
+    tombstones: HashMap<&'a str, u64>,
+}
+
+/// On-disk form of one account's shard (PersistentSearchIndex::save_account)
+#[derive(Deserialize)]
+struct PersistedShard {
+    account_id: String,
+    #[serde(flatten)]
+    index: PersistedIndex,
+}
+
+/// Borrowed PersistedShard
+#[derive(Serialize)]
+struct PersistedShardRef<'a> {
+    account_id: &'a str,
+    #[serde(flatten)]
+    index: PersistedIndexRef<'a>,
+}
+
+impl SearchIndex {
+    /// Documents and tombstones of the given shards, borrowed for saving
+    fn persisted<'a>(&'a self, shards: impl Iterator<Item = &'a IndexShard>) -> PersistedIndexRef<'a> {
+        let mut persisted = PersistedIndexRef { documents: HashMap::new(), tombstones: HashMap::new() };
+        for shard in shards {
+            persisted.documents.extend(shard.documents.iter().map(|(id, doc)| (id.as_str(), doc)));
+            persisted.tombstones.extend(shard.tombstones.iter().map(|(id, at)| (id.as_str(), *at)));
+        }
+        persisted
+    }
+
+    /// Add saved documents, then restore their tombstones
+    fn load_persisted(&mut self, persisted: PersistedIndex) {
+        self.add_documents(persisted.documents.into_values());
+        for (node_id, deleted_at_ms) in persisted.tombstones {
+            self.mark_deleted(&node_id, deleted_at_ms);
         }
     }
 }
 
-impl Default for SearchIndex {
-    fn default() -> Self {
-        SearchIndex::new()
+/// Persisted index, or the older bare document map written before tombstones
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PersistedFormat {
+    Current(PersistedIndex),
+    Legacy(HashMap<String, SearchDocument>),
+}
+
+/// Index and save bookkeeping shared with the autosave thread
+struct PersistentShared {
+    index: Mutex<SearchIndex>,
+    path: PathBuf,
+    /// Changed since the last save
+    dirty: AtomicBool,
+    /// Held for a whole save, so an older snapshot never replaces a newer one
+    save_lock: Mutex<Option<Instant>>,
+    /// Files written so far
+    saves: AtomicU64,
+    /// Set when the autosave thread should exit
+    stop: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl PersistentShared {
+    fn lock_index(&self) -> MutexGuard<'_, SearchIndex> {
+        self.index.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Save if dirty and, unless `force`, at least `interval` has passed since the last save
+    fn save(&self, interval: Duration, force: bool) -> Result<bool, std::io::Error> {
+        let mut last_save = self.save_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if !self.dirty.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+        if !force && last_save.is_some_and(|at| at.elapsed() < interval) {
+            return Ok(false);
+        }
+
+        // Snapshot under the index lock; changes made while writing mark it dirty again
+        let data = {
+            let index = self.lock_index();
+            self.dirty.store(false, Ordering::Release);
+            serde_json::to_vec_pretty(&index.persisted(index.shards.values()))?
+        };
+        if let Err(e) = write_index_file(&self.path, &data) {
+            self.dirty.store(true, Ordering::Release);
+            return Err(e);
+        }
+        *last_save = Some(Instant::now());
+        self.saves.fetch_add(1, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    fn autosave_loop(&self, interval: Duration) {
+        let mut stop = self.stop.lock().unwrap_or_else(PoisonError::into_inner);
+        while !*stop {
+            stop = self.wake.wait_timeout(stop, interval).unwrap_or_else(PoisonError::into_inner).0;
+            if *stop {
+                break;
+            }
+            drop(stop);
+            let _ = self.save(interval, false);
+            stop = self.stop.lock().unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Write through a temp file renamed over `path`, so a crash leaves the old or new file
+fn write_index_file(path: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
     }
+    let (temp_path, mut file) = crate::temp::create_temp_file_for(path)?;
+    if let Err(e) = file.write_all(data) {
+        drop(file);
+        let _ = crate::temp::discard_temp_file(&temp_path);
+        return Err(e);
+    }
+    drop(file);
+    crate::temp::commit_temp_file(&temp_path, path).inspect_err(|_| {
+        let _ = crate::temp::discard_temp_file(&temp_path);
+    })
 }
 
 /// Persistent search index that saves to disk
+///
+/// Mutations only mark the index dirty. It is written by save_if_dirty (at
+/// most once per save interval), flush, the autosave thread started with
+/// enable_autosave, and on drop. Each write replaces the file atomically, so
+/// a crash loses at most the changes since the last write: with autosave,
+/// at most interval_ms of changes.
 pub struct PersistentSearchIndex {
-    index: SearchIndex,
-    path: PathBuf,
+    shared: Arc<PersistentShared>,
+    interval: Duration,
+    autosave: Option<JoinHandle<()>>,
 }
 
 impl PersistentSearchIndex {
@@ -273,62 +1483,181 @@ impl PersistentSearchIndex {
             SearchIndex::new()
         };
         
-        PersistentSearchIndex { index, path }
-    }
-    
-    /// Save index to disk
-    fn save_to_disk(&self) -> Result<(), std::io::Error> {
-        // Create parent directories if needed
-        if let Some(parent) = self.path.parent() {
-            std::fs::create_dir_all(parent)?;
+        PersistentSearchIndex {
+            shared: Arc::new(PersistentShared {
+                index: Mutex::new(index),
+                path,
+                dirty: AtomicBool::new(false),
+                save_lock: Mutex::new(None),
+                saves: AtomicU64::new(0),
+                stop: Mutex::new(false),
+                wake: Condvar::new(),
+            }),
+            interval: Duration::ZERO,
+            autosave: None,
         }
-        
-        let data = serde_json::to_string_pretty(&self.index.documents)?;
-        std::fs::write(&self.path, data)?;
-        
-        Ok(())
     }
     
     /// Load index from disk
     fn load_from_disk(path: &PathBuf) -> Result<SearchIndex, std::io::Error> {
         let data = std::fs::read_to_string(path)?;
-        let documents: HashMap<String, SearchDocument> = serde_json::from_str(&data)?;
+        let persisted = match serde_json::from_str(&data)? {
+            PersistedFormat::Current(persisted) => persisted,
+            PersistedFormat::Legacy(documents) => PersistedIndex { documents, tombstones: HashMap::new() },
+        };
         
         let mut index = SearchIndex::new();
-        for (_, doc) in documents {
-            index.add_document(doc);
-        }
-        
+        index.load_persisted(persisted);
         Ok(index)
     }
     
-    /// Add document and persist
+    /// Run a mutation on the index, marking it dirty when `changed` says so
+    fn mutate<T>(&mut self, apply: impl FnOnce(&mut SearchIndex) -> T, changed: impl FnOnce(&T) -> bool) -> T {
+        let result = apply(&mut self.shared.lock_index());
+        if changed(&result) {
+            self.shared.dirty.store(true, Ordering::Release);
+        }
+        result
+    }
+    
+    /// Save to disk if changed and the save interval has passed since the last save
+    ///
+    /// Returns whether the file was written.
+    pub fn save_if_dirty(&self) -> Result<bool, std::io::Error> {
+        self.shared.save(self.interval, false)
+    }
+    
+    /// Save to disk now if changed
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        self.shared.save(self.interval, true).map(|_| ())
+    }
+    
+    /// Save in the background at most once every `interval_ms` while changed
+    ///
+    /// Also sets the interval save_if_dirty waits between saves. Calling it
+    /// again restarts the thread with the new interval; 0 stops autosaving.
+    pub fn enable_autosave(&mut self, interval_ms: u64) {
+        self.stop_autosave();
+        self.interval = Duration::from_millis(interval_ms);
+        if interval_ms == 0 {
+            return;
+        }
+        *self.shared.stop.lock().unwrap_or_else(PoisonError::into_inner) = false;
+        let shared = Arc::clone(&self.shared);
+        let interval = self.interval;
+        self.autosave = thread::Builder::new()
+            .name("cloud-nexus-index-autosave".to_string())
+            .spawn(move || shared.autosave_loop(interval))
+            .ok();
+    }
+    
+    fn stop_autosave(&mut self) {
+        if let Some(handle) = self.autosave.take() {
+            *self.shared.stop.lock().unwrap_or_else(PoisonError::into_inner) = true;
+            self.shared.wake.notify_all();
+            let _ = handle.join();
+        }
+    }
+    
+    /// Whether there are changes not yet saved
+    pub fn is_dirty(&self) -> bool {
+        self.shared.dirty.load(Ordering::Acquire)
+    }
+    
+    /// Number of times the index file has been written
+    pub fn save_count(&self) -> u64 {
+        self.shared.saves.load(Ordering::Relaxed)
+    }
+    
+    /// Add document
     pub fn add_document(&mut self, doc: SearchDocument) {
-        self.index.add_document(doc);
-        let _ = self.save_to_disk();
+        self.mutate(|index| index.add_document(doc), |_| true)
     }
     
-    /// Remove document and persist
+    /// Remove document
     pub fn remove_document(&mut self, node_id: &str) -> Option<SearchDocument> {
-        let result = self.index.remove_document(node_id);
-        let _ = self.save_to_disk();
-        result
+        self.mutate(|index| index.remove_document(node_id), |removed| removed.is_some())
+    }
+    
+    /// Mark document deleted
+    pub fn mark_deleted(&mut self, node_id: &str, deleted_at_ms: u64) -> bool {
+        self.mutate(|index| index.mark_deleted(node_id, deleted_at_ms), |&marked| marked)
+    }
+    
+    /// Rename document
+    pub fn rename_document(&mut self, node_id: &str, new_name: &str) -> bool {
+        self.mutate(|index| index.rename_document(node_id, new_name), |&renamed| renamed)
+    }
+    
+    /// Move document
+    pub fn move_document(&mut self, node_id: &str, new_parent_id: Option<&str>) -> bool {
+        self.mutate(|index| index.move_document(node_id, new_parent_id), |&moved| moved)
+    }
+    
+    /// Remove every document of an account
+    pub fn remove_account(&mut self, account_id: &str) -> usize {
+        self.mutate(|index| index.remove_account(account_id), |&removed| removed > 0)
+    }
+
+    /// Write one account's shard (documents and tombstones) to its own file
+    ///
+    /// Nothing else in the index is read, and the main index file is not
+    /// written. Returns the number of documents saved.
+    pub fn save_account(&self, account_id: &str, path: &Path) -> Result<usize, std::io::Error> {
+        let (data, documents) = {
+            let index = self.shared.lock_index();
+            let persisted = index.persisted(index.shards.get(account_id).into_iter());
+            let documents = persisted.documents.len();
+            (serde_json::to_vec_pretty(&PersistedShardRef { account_id, index: persisted })?, documents)
+        };
+        write_index_file(path, &data)?;
+        Ok(documents)
+    }
+
+    /// Replace an account's documents with a shard file written by save_account
+    ///
+    /// Only that account's shard is rebuilt. A file holding documents of
+    /// another account is rejected (InvalidData) without changing the index.
+    /// Returns the number of documents loaded.
+    pub fn load_account(&mut self, path: &Path) -> Result<usize, std::io::Error> {
+        let shard: PersistedShard = serde_json::from_slice(&std::fs::read(path)?)?;
+        if shard.index.documents.values().any(|doc| doc.account_id != shard.account_id) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "document of another account in shard file"));
+        }
+        let documents = shard.index.documents.len();
+        self.mutate(|index| {
+            index.remove_account(&shard.account_id);
+            index.load_persisted(shard.index);
+        }, |_| true);
+        Ok(documents)
+    }
+
+    /// Purge old tombstones
+    pub fn purge_tombstones(&mut self, older_than_ms: u64) -> usize {
+        self.mutate(|index| index.purge_tombstones(older_than_ms), |&purged| purged > 0)
     }
     
-    /// Clear index and persist
+    /// Clear index
     pub fn clear(&mut self) {
-        self.index.clear();
-        let _ = self.save_to_disk();
+        self.mutate(|index| index.clear(), |_| true)
     }
     
-    /// Get underlying index reference
-    pub fn inner(&self) -> &SearchIndex {
-        &self.index
+    /// Get underlying index
+    pub fn inner(&self) -> MutexGuard<'_, SearchIndex> {
+        self.shared.lock_index()
     }
     
-    /// Get mutable index reference
-    pub fn inner_mut(&mut self) -> &mut SearchIndex {
-        &mut self.index
+    /// Get mutable index; marks the index dirty
+    pub fn inner_mut(&mut self) -> MutexGuard<'_, SearchIndex> {
+        self.shared.dirty.store(true, Ordering::Release);
+        self.shared.lock_index()
+    }
+}
+
+impl Drop for PersistentSearchIndex {
+    fn drop(&mut self) {
+        self.stop_autosave();
+        let _ = self.flush();
     }
 }
 
@@ -403,4 +1732,523 @@ mod tests {
         let removed = index.remove_document("1");
         assert!(removed.is_none());
     }
-}
\ No newline at end of file
+    
+    fn doc(node_id: &str, account_id: &str, name: &str) -> SearchDocument {
+        SearchDocument {
+            node_id: node_id.to_string(),
+            account_id: account_id.to_string(),
+            provider: "gdrive".to_string(),
+            email: "test@example.com".to_string(),
+            name: name.to_string(),
+            is_folder: false,
+            parent_id: None,
+        }
+    }
+    
+    fn sample_index() -> SearchIndex {
+        let mut index = SearchIndex::new();
+        let names = ["Report 2023.pdf", "report draft", "Quarterly Report", "Repo notes", "Photos",
+                     "reporter list", "Old reports", "Budget report.xlsx", "readme", "Preport"];
+        for (i, name) in names.iter().enumerate() {
+            index.add_document(doc(&i.to_string(), if i % 2 == 0 { "acc1" } else { "acc2" }, name));
+        }
+        index
+    }
+    
+    fn ids(results: &[SearchResult]) -> Vec<(String, f64)> {
+        results.iter().map(|r| (r.node_id.clone(), r.score)).collect()
+    }
+    
+    #[test]
+    fn test_query_cache_hits_repeated_query() {
+        let index = sample_index();
+        
+        let first = index.search_exact("report", 10);
+        let stats = index.stats().query_cache;
+        assert_eq!((stats.hits, stats.misses), (0, 1));
+        
+        let second = index.search_exact("REPORT", 10);
+        let stats = index.stats().query_cache;
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!(ids(&first), ids(&second));
+        
+        // A different limit is served from the same cached list
+        assert_eq!(ids(&index.search_exact("report", 2)), ids(&first[..2]));
+        assert_eq!(index.stats().query_cache.hits, 2);
+        
+        // Stats JSON carries the counters
+        let json = serde_json::to_value(index.stats()).unwrap();
+        assert_eq!(json["query_cache"]["hits"], 2);
+        assert_eq!(json["query_cache"]["misses"], 1);
+    }
+    
+    #[test]
+    fn test_query_cache_invalidated_by_mutation() {
+        let mut index = sample_index();
+        let before = index.search_by_account("report", "acc1", 10);
+        
+        index.add_document(doc("new", "acc1", "report final"));
+        let after = index.search_by_account("report", "acc1", 10);
+        assert_eq!(after.len(), before.len() + 1);
+        assert_eq!(index.stats().query_cache.hits, 0);
+        
+        index.remove_document("new");
+        assert_eq!(ids(&index.search_by_account("report", "acc1", 10)), ids(&before));
+        assert_eq!(index.stats().query_cache.hits, 0);
+        
+        // Disabled cache never hits
+        index.set_query_cache_size(0);
+        index.search_exact("report", 10);
+        index.search_exact("report", 10);
+        let stats = index.stats().query_cache;
+        assert_eq!((stats.entries, stats.hits), (0, 0));
+    }
+    
+    #[test]
+    fn test_query_continuation_matches_full_search() {
+        let index = sample_index();
+        let mut uncached = sample_index();
+        uncached.set_query_cache_size(0);
+        
+        let mut typed = String::new();
+        for c in "reports".chars() {
+            typed.push(c);
+            assert_eq!(ids(&index.search_exact(&typed, 5)), ids(&uncached.search_exact(&typed, 5)));
+            assert_eq!(ids(&index.search_by_account(&typed, "acc1", 5)),
+                       ids(&uncached.search_by_account(&typed, "acc1", 5)));
+        }
+        
+        // Every query after the first letter extended a cached one
+        assert_eq!(index.stats().query_cache.continuations, 12);
+        assert_eq!(uncached.stats().query_cache.continuations, 0);
+    }
+    
+    #[test]
+    fn test_find_name_duplicates() {
+        let mut index = SearchIndex::new();
+        index.add_document(doc("a1", "acc1", "report.pdf"));
+        index.add_document(doc("a2", "acc1", "Report.PDF"));
+        index.add_document(doc("b1", "acc2", "report.pdf "));
+        index.add_document(doc("b2", "acc2", "report.docx"));
+        index.add_document(doc("b3", "acc2", "notes.txt"));
+        index.add_document(doc("c1", "acc3", "budget.xlsx"));
+        
+        let groups = index.find_name_duplicates(2, 10);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].name, "report.pdf");
+        let members: Vec<&str> = groups[0].documents.iter().map(|d| d.node_id.as_str()).collect();
+        assert_eq!(members, vec!["a1", "a2", "b1"]);
+        
+        assert!(index.find_name_duplicates(4, 10).is_empty());
+        
+        // Removing a member keeps the index in sync
+        index.remove_document("a2");
+        index.remove_document("b1");
+        assert!(index.find_name_duplicates(2, 10).is_empty());
+    }
+    
+    #[test]
+    fn test_term_boost_breaks_ties() {
+        let mut index = SearchIndex::new();
+        index.add_document(doc("a", "acc1", "inventory.xlsx"));
+        index.add_document(doc("b", "acc1", "invoice.pdf"));
+        index.add_document(doc("c", "acc1", "inv"));
+        
+        // Equal prefix scores fall back to node_id order
+        let results = index.search_exact("inv", 10);
+        assert_eq!(ids(&results), vec![("c".to_string(), 1.0), ("a".to_string(), 0.9), ("b".to_string(), 0.9)]);
+        
+        // A boosted term reorders the tie but never passes the exact match
+        index.set_term_boosts(HashMap::from([("Invoice".to_string(), 5.0)]));
+        let results = index.search_exact("inv", 10);
+        let order: Vec<&str> = results.iter().map(|r| r.node_id.as_str()).collect();
+        assert_eq!(order, vec!["c", "b", "a"]);
+        assert!(results[1].score > 0.9 && results[1].score < 1.0);
+        assert_eq!(results[2].score, 0.9);
+        
+        let explained = index.explain_score("b", "inv").unwrap();
+        assert_eq!(explained.boosted_term.as_deref(), Some("invoice"));
+        assert_eq!(explained.term_boost, MAX_TERM_BOOST);
+        assert_eq!((explained.base_score, explained.score), (0.9, results[1].score));
+        assert_eq!(index.explain_score("a", "inv").unwrap().term_boost, 1.0);
+        assert!(index.explain_score("a", "budget").is_none());
+        
+        // Query tokens must lead to the boosted word
+        assert_eq!(index.explain_score("b", "pdf").unwrap().term_boost, 1.0);
+        
+        index.set_term_boosts(HashMap::new());
+        assert_eq!(index.search_exact("inv", 10)[1].node_id, "a");
+    }
+
+    #[test]
+    fn test_tombstones_hidden_from_search_until_purged() {
+        let mut index = SearchIndex::new();
+        index.add_document(doc("a", "acc1", "report 2023.pdf"));
+        index.add_document(doc("b", "acc1", "report 2024.pdf"));
+        assert_eq!(index.search_exact("report", 10).len(), 2);
+        
+        assert!(index.mark_deleted("a", 1_000));
+        assert!(!index.mark_deleted("missing", 1_000));
+        assert_eq!(index.tombstone_count(), 1);
+        
+        // Hidden from every search, including cached and continued queries
+        let only_b = |results: Vec<SearchResult>| results.iter().map(|r| r.node_id.clone()).collect::<Vec<_>>() == vec!["b"];
+        assert!(only_b(index.search_exact("report", 10)));
+        assert!(only_b(index.search_exact("report 20", 10)));
+        assert!(only_b(index.search_prefix("report", 10)));
+        assert!(only_b(index.search_by_account("report", "acc1", 10)));
+        assert!(index.explain_score("a", "report").is_none());
+        
+        // Still retrievable for "recently deleted"
+        assert_eq!(index.get("a").unwrap().name, "report 2023.pdf");
+        assert_eq!(index.deleted_at("a"), Some(1_000));
+        assert_eq!(index.len(), 2);
+        
+        // Only tombstones older than the cutoff are purged
+        assert_eq!(index.purge_tombstones(1_000), 0);
+        assert_eq!(index.purge_tombstones(1_001), 1);
+        assert_eq!(index.tombstone_count(), 0);
+        assert!(index.get("a").is_none());
+        assert_eq!(index.len(), 1);
+        let shard = index.shard("acc1").unwrap();
+        assert_eq!(shard.name_index.get("report").unwrap(), &vec!["b".to_string()]);
+        assert!(!shard.name_index.contains_key("2023.pdf"));
+        assert_eq!(shard.documents.keys().collect::<Vec<_>>(), vec!["b"]);
+        assert!(!shard.lower_names.contains_key("a"));
+    }
+
+    #[test]
+    fn test_upsert_restores_tombstoned_document() {
+        let mut index = SearchIndex::new();
+        index.add_document(doc("a", "acc1", "notes.txt"));
+        index.mark_deleted("a", 5);
+        assert!(index.search_exact("notes", 10).is_empty());
+        
+        index.upsert_document(doc("a", "acc1", "notes.txt"));
+        assert_eq!(index.tombstone_count(), 0);
+        assert_eq!(index.search_exact("notes", 10).len(), 1);
+    }
+
+    #[test]
+    fn test_rename_and_move_update_only_affected_fields() {
+        use crate::search::{build_path, free_c_string, move_document_in_index, rename_document_in_index};
+        use std::ffi::{CStr, CString};
+        use std::ptr;
+
+        let mut index = SearchIndex::new();
+        index.add_document(SearchDocument { is_folder: true, ..doc("docs", "acc1", "Docs") });
+        index.add_document(SearchDocument { is_folder: true, ..doc("archive", "acc1", "Archive") });
+        index.add_document(SearchDocument { parent_id: Some("docs".to_string()), ..doc("f", "acc1", "draft notes.txt") });
+        index.add_document(doc("g", "acc1", "old draft"));
+        let ordinal = index.ordinal("f");
+        assert_eq!(index.search_exact("draft", 10).len(), 2);
+
+        assert!(index.rename_document("f", "Final Report.txt"));
+        assert!(!index.rename_document("missing", "x"));
+        assert_eq!(ids(&index.search_exact("draft", 10)).len(), 1);
+        assert!(index.search_exact("notes", 10).is_empty());
+        assert_eq!(index.search_exact("final report", 10)[0].node_id, "f");
+        assert_eq!(index.search_prefix("final", 10)[0].name, "Final Report.txt");
+        let shard = index.shard("acc1").unwrap();
+        assert!(!shard.name_index.contains_key("notes.txt"));
+        assert_eq!(shard.name_index.get("draft").unwrap(), &vec!["g".to_string()]);
+        assert!(index.find_name_duplicates(2, 0).is_empty());
+        assert_eq!(index.ordinal("f"), ordinal);
+        assert_eq!(shard.documents.len(), 4);
+
+        // A tombstoned document stays tombstoned under its new name
+        index.mark_deleted("g", 7);
+        assert!(index.rename_document("g", "Final Report.txt"));
+        assert_eq!(index.deleted_at("g"), Some(7));
+        assert_eq!(ids(&index.search_exact("final", 10)).len(), 1);
+        assert_eq!(index.get("g").unwrap().name, "Final Report.txt");
+
+        // Moving only changes the stored parent, which path building follows
+        let index_ptr = &mut index as *mut SearchIndex;
+        let node = CString::new("f").unwrap();
+        let path_of = |index_ptr: *mut SearchIndex| {
+            let path = build_path(index_ptr, node.as_ptr(), ptr::null());
+            let text = unsafe { CStr::from_ptr(path) }.to_str().unwrap().to_string();
+            free_c_string(path);
+            text
+        };
+        assert_eq!(path_of(index_ptr), "Docs/Final Report.txt");
+        let archive = CString::new("archive").unwrap();
+        assert_eq!(move_document_in_index(index_ptr, node.as_ptr(), archive.as_ptr()), 1);
+        assert_eq!(path_of(index_ptr), "Archive/Final Report.txt");
+        assert_eq!(move_document_in_index(index_ptr, node.as_ptr(), ptr::null()), 1);
+        assert_eq!(path_of(index_ptr), "Final Report.txt");
+
+        let missing = CString::new("missing").unwrap();
+        let name = CString::new("renamed.txt").unwrap();
+        assert_eq!(move_document_in_index(index_ptr, missing.as_ptr(), archive.as_ptr()), 0);
+        assert_eq!(rename_document_in_index(index_ptr, missing.as_ptr(), name.as_ptr()), 0);
+        assert_eq!(rename_document_in_index(index_ptr, node.as_ptr(), name.as_ptr()), 1);
+        assert_eq!(index.search_exact("renamed", 10)[0].node_id, "f");
+        assert!(index.search_exact("report", 10).is_empty());
+    }
+
+    #[test]
+    fn test_persistent_index_round_trips_tombstones() {
+        let path = std::env::temp_dir().join(format!("cloud_nexus_tombstones_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        
+        {
+            let mut index = PersistentSearchIndex::new(path.clone());
+            index.add_document(doc("a", "acc1", "old.txt"));
+            index.add_document(doc("b", "acc1", "new.txt"));
+            assert!(index.mark_deleted("a", 42));
+        }
+        
+        let reopened = PersistentSearchIndex::new(path.clone());
+        assert_eq!(reopened.inner().len(), 2);
+        assert_eq!(reopened.inner().deleted_at("a"), Some(42));
+        assert!(reopened.inner().search_exact("old", 10).is_empty());
+        assert_eq!(reopened.inner().search_exact("new", 10).len(), 1);
+        
+        // Indexes saved as a bare document map still load
+        let legacy = HashMap::from([("c".to_string(), doc("c", "acc1", "legacy.txt"))]);
+        std::fs::write(&path, serde_json::to_string(&legacy).unwrap()).unwrap();
+        let legacy_index = PersistentSearchIndex::new(path.clone());
+        assert_eq!(legacy_index.inner().len(), 1);
+        assert_eq!(legacy_index.inner().tombstone_count(), 0);
+        
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_persistent_index_autosave_debounces_writes() {
+        let path = std::env::temp_dir().join(format!("cloud_nexus_autosave_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut index = PersistentSearchIndex::new(path.clone());
+        index.enable_autosave(200);
+        let started = Instant::now();
+        for i in 0..1000 {
+            index.add_document(doc(&format!("n{}", i), "acc1", &format!("file {}.txt", i)));
+        }
+        let busy_ms = started.elapsed().as_millis() as u64;
+
+        // The last change is picked up within an interval or two
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while (index.is_dirty() || index.save_count() == 0) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(!index.is_dirty());
+        let saves = index.save_count();
+        assert!(saves >= 1 && saves <= busy_ms / 200 + 3, "{} saves in {} ms", saves, busy_ms);
+
+        // Nothing changed, so nothing is written
+        assert!(!index.save_if_dirty().unwrap());
+        let reopened = PersistentSearchIndex::new(path.clone());
+        assert_eq!(reopened.inner().len(), 1000);
+        drop(reopened);
+
+        // flush writes regardless of the interval; save_if_dirty waits for it
+        index.remove_document("n0");
+        index.flush().unwrap();
+        assert_eq!(index.save_count(), saves + 1);
+        index.remove_document("n1");
+        assert!(!index.save_if_dirty().unwrap());
+        drop(index);
+        assert_eq!(PersistentSearchIndex::new(path.clone()).inner().len(), 998);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_ordinals_survive_other_removals() {
+        let mut index = SearchIndex::new();
+        for id in ["a", "b", "c"] {
+            index.add_document(doc(id, "acc1", &format!("file {}", id)));
+        }
+        assert_eq!([index.ordinal("a"), index.ordinal("b"), index.ordinal("c")], [Some(0), Some(1), Some(2)]);
+
+        // Removing b frees its ordinal for the next insert; a and c keep theirs
+        index.remove_document("b");
+        assert_eq!(index.ordinal("b"), None);
+        assert!(index.get_by_ordinal(1).is_none());
+        index.add_document(doc("d", "acc1", "file d"));
+        index.add_document(doc("e", "acc1", "file e"));
+        assert_eq!([index.ordinal("d"), index.ordinal("e")], [Some(1), Some(3)]);
+        assert_eq!([index.ordinal("a"), index.ordinal("c")], [Some(0), Some(2)]);
+
+        // Replacing, tombstoning and re-adding other documents keeps an ordinal
+        assert!(index.upsert_document(doc("a", "acc2", "renamed a")));
+        assert!(index.mark_deleted("c", 10));
+        index.remove_document("d");
+        index.add_document(doc("b", "acc1", "file b"));
+        assert_eq!([index.ordinal("a"), index.ordinal("c"), index.ordinal("b")], [Some(0), Some(2), Some(1)]);
+        assert_eq!(index.get_by_ordinal(0).unwrap().name, "renamed a");
+        assert_eq!(index.get_by_ordinal(2).unwrap().node_id, "c");
+
+        // Purging a tombstone frees its ordinal like remove_document
+        assert_eq!(index.purge_tombstones(11), 1);
+        assert!(index.get_by_ordinal(2).is_none());
+
+        index.clear();
+        index.add_document(doc("z", "acc1", "file z"));
+        assert_eq!(index.ordinal("z"), Some(0));
+    }
+
+    #[test]
+    fn test_id_searches_match_string_searches() {
+        let index = sample_index();
+        let mut out_ids = [u32::MAX; 4];
+        let mut out_scores = [0.0f64; 4];
+
+        for query in ["report", "REPORT", "rep"] {
+            let expected = index.search_exact(query, 10);
+            let written = index.search_exact_ids(query, 10, &mut out_ids, Some(&mut out_scores));
+            assert_eq!(written, expected.len().min(out_ids.len()));
+            for (i, result) in expected.iter().take(written).enumerate() {
+                assert_eq!(index.get_by_ordinal(out_ids[i]).unwrap().node_id, result.node_id);
+                assert_eq!(out_scores[i], result.score);
+            }
+        }
+
+        let expected = index.search_prefix("report", 2);
+        assert_eq!(index.search_prefix_ids("report", 2, &mut out_ids, None), expected.len());
+        assert_eq!(index.get_by_ordinal(out_ids[0]).unwrap().node_id, expected[0].node_id);
+
+        let expected = index.search_by_account("report", "acc2", 10);
+        let written = index.search_by_account_ids("report", "acc2", 10, &mut out_ids, None);
+        let found: Vec<&str> = out_ids[..written].iter().map(|&o| index.get_by_ordinal(o).unwrap().node_id.as_str()).collect();
+        let expected: Vec<&str> = expected.iter().take(out_ids.len()).map(|r| r.node_id.as_str()).collect();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_remove_account_drops_only_its_shard() {
+        let mut index = sample_index();
+        index.add_document(SearchDocument { parent_id: Some("0".to_string()), ..doc("child", "acc2", "report child") });
+        let acc1_ordinals: Vec<u32> = ["0", "2", "4"].iter().map(|id| index.ordinal(id).unwrap()).collect();
+        let acc2_before = index.search_by_account("report", "acc2", 10);
+        let generation = index.stats().generation;
+
+        assert_eq!(index.remove_account("acc1"), 5);
+        assert_eq!(index.remove_account("acc1"), 0);
+        assert_eq!(index.len(), 6);
+        assert!(index.get("0").is_none());
+        assert_eq!(index.get_children(Some("0"), 0, 0, 0).unwrap().children.len(), 1);
+        assert!(index.stats().generation > generation);
+
+        // Only acc2 is left, ranked as before; acc1's ordinals are reused
+        assert!(index.search_exact("report", 10).iter().all(|r| r.account_id == "acc2"));
+        assert_eq!(ids(&index.search_by_account("report", "acc2", 10)), ids(&acc2_before));
+        let stats = index.stats();
+        assert_eq!((stats.accounts, stats.shards.len(), stats.shards[0].account_id.as_str()), (1, 1, "acc2"));
+        assert_eq!(stats.shards[0].documents, 6);
+        index.add_document(doc("new", "acc3", "new report"));
+        assert_eq!(index.ordinal("new"), Some(acc1_ordinals[0]));
+
+        // Re-adding a node under another account moves it between shards
+        index.mark_deleted("1", 9);
+        index.add_document(doc("1", "acc3", "report draft"));
+        assert_eq!(index.remove_account("acc2"), 5);
+        assert_eq!(index.get("1").unwrap().account_id, "acc3");
+        assert_eq!(index.deleted_at("1"), Some(9));
+        assert_eq!(index.len(), 2);
+    }
+
+    #[test]
+    fn test_trim_memory_shrinks_tables_and_keeps_search_working() {
+        let mut index = SearchIndex::new();
+        for i in 0..2000 {
+            index.add_document(doc(&format!("n{}", i), if i < 1900 { "bulk" } else { "kept" },
+                                   &format!("report {} draft", i)));
+        }
+        assert_eq!(index.search_exact("report", 5).len(), 5);
+        assert_eq!(index.search_exact("draft", 5).len(), 5);
+        assert_eq!(index.remove_account("bulk"), 1900);
+        let before = index.stats();
+        assert!(before.query_cache.entries > 0 || before.query_cache.capacity == 0);
+
+        // Light only drops the query cache
+        index.search_exact("report", 5);
+        assert!(index.trim_memory(TrimLevel::Light) > 0);
+        let light = index.stats();
+        assert_eq!(light.query_cache.entries, 0);
+        assert_eq!(light.table_bytes, before.table_bytes);
+
+        let released = index.trim_memory(TrimLevel::Moderate);
+        let trimmed = index.stats();
+        assert!(trimmed.table_bytes < before.table_bytes);
+        assert!(released >= (before.table_bytes - trimmed.table_bytes) as u64);
+        assert_eq!((trimmed.documents, trimmed.words), (before.documents, before.words));
+
+        // Everything still works on the shrunk tables
+        assert_eq!(index.search_exact("report", 200).len(), 100);
+
+        // A trim requested from another thread waits for the next call on the index
+        index.search_exact("draft", 5);
+        let flag = index.trim_request();
+        std::thread::spawn(move || SearchIndex::request_trim(&flag, TrimLevel::Moderate)).join().unwrap();
+        assert!(index.stats().query_cache.entries > 0);
+        index.apply_pending_cache_trim();
+        assert_eq!(index.stats().query_cache.entries, 0);
+        index.apply_pending_trim();
+        assert_eq!(index.apply_pending_trim(), 0);
+        index.add_document(doc("late", "kept", "late report"));
+        assert_eq!(index.search_exact("late", 5).len(), 1);
+        index.add_document(doc("n0", "bulk", "report again"));
+        assert_eq!(index.search_by_account("report", "bulk", 10).len(), 1);
+        assert_eq!(index.search_exact("report 1950", 1)[0].node_id, "n1950");
+    }
+
+    #[test]
+    fn test_cross_shard_ranking_ties_by_node_id() {
+        let mut index = SearchIndex::new();
+        for (node_id, account_id) in [("d", "acc1"), ("b", "acc2"), ("c", "acc3"), ("a", "acc1")] {
+            index.add_document(doc(node_id, account_id, "budget.xlsx"));
+        }
+        index.add_document(doc("e", "acc2", "old budget.xlsx"));
+
+        // Equal scores from different shards are ordered by node_id, the lower match last
+        let results = index.search_exact("budget", 10);
+        let order: Vec<&str> = results.iter().map(|r| r.node_id.as_str()).collect();
+        assert_eq!(order, vec!["a", "b", "c", "d", "e"]);
+        assert!(results[3].score > results[4].score);
+        assert_eq!(ids(&index.search_exact("budget", 3)), ids(&results[..3]));
+
+        // Name groups span shards
+        let groups = index.find_name_duplicates(2, 0);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].documents.len(), 4);
+        assert_eq!(index.stats().words, 2);
+    }
+
+    #[test]
+    fn test_persistent_index_saves_and_reloads_one_account() {
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_shards_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let shard_path = dir.join("acc1.json");
+
+        let mut index = PersistentSearchIndex::new(dir.join("index.json"));
+        index.inner_mut().add_documents(sample_index().iter_sorted().cloned().collect::<Vec<_>>());
+        index.mark_deleted("2", 40);
+        assert_eq!(index.save_account("acc1", &shard_path).unwrap(), 5);
+        assert_eq!(index.save_count(), 0);
+
+        // Reloading replaces only acc1's documents, tombstones included
+        index.inner_mut().add_document(doc("stale", "acc1", "stale report"));
+        assert_eq!(index.remove_account("acc1"), 6);
+        assert_eq!(index.load_account(&shard_path).unwrap(), 5);
+        let loaded = index.inner();
+        assert_eq!(loaded.len(), 10);
+        assert!(loaded.get("stale").is_none());
+        assert_eq!(loaded.deleted_at("2"), Some(40));
+        assert_eq!(loaded.get_by_account("acc1").len(), 5);
+        drop(loaded);
+
+        // A file naming one account but holding another's documents changes nothing
+        let wrong = r#"{"account_id": "acc2", "documents": {"x": {"node_id": "x", "account_id": "acc1",
+            "provider": "gdrive", "email": "", "name": "x", "is_folder": false, "parent_id": null}}, "tombstones": {}}"#;
+        std::fs::write(dir.join("wrong.json"), wrong).unwrap();
+        assert!(index.load_account(&dir.join("wrong.json")).is_err());
+        assert_eq!(index.inner().len(), 10);
+
+        drop(index);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -9,6 +9,9 @@ mod batch;
 mod incremental;
 mod suggestions;
 mod history;
+mod query_cache;
+mod scoring;
+mod diagnostics;
 mod bridge;
 
 pub use fuzzy::*;
@@ -18,4 +21,7 @@ pub use batch::*;
 pub use incremental::*;
 pub use suggestions::*;
 pub use history::*;
+pub use query_cache::*;
+pub use scoring::*;
+pub use diagnostics::*;
 pub use bridge::*;
\ No newline at end of file
@@ -0,0 +1,167 @@
+// Query result cache for SearchIndex
+// LRU of recent queries, invalidated whenever the index generation changes
+
+use std::collections::VecDeque;
+use serde::Serialize;
+
+/// Default number of cached queries per index
+pub const DEFAULT_QUERY_CACHE_SIZE: usize = 64;
+
+/// Kind of search a cached result list belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryKind {
+    Exact,
+    Prefix,
+    Account(String),
+    /// A search ranked by another scoring profile than DEFAULT (by cache key)
+    Profiled(Box<QueryKind>, String),
+}
+
+impl QueryKind {
+    /// Whether every match of a query is also a match of any shorter prefix of it,
+    /// so a cached prefix's results are a complete candidate set for the longer query
+    fn supports_continuation(&self) -> bool {
+        match self {
+            QueryKind::Profiled(kind, _) => kind.supports_continuation(),
+            kind => !matches!(kind, QueryKind::Prefix),
+        }
+    }
+}
+
+/// One cached query: the full, unlimited result list sorted best-first
+struct CacheEntry {
+    kind: QueryKind,
+    query: String,
+    results: Vec<(String, f64)>,
+}
+
+/// Cache hit/miss counters, reported in the index stats JSON
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct QueryCacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Misses answered by re-scoring a cached shorter query's results
+    pub continuations: u64,
+}
+
+/// LRU cache of query results (most recently used first)
+pub struct QueryCache {
+    entries: VecDeque<CacheEntry>,
+    capacity: usize,
+    generation: u64,
+    hits: u64,
+    misses: u64,
+    continuations: u64,
+}
+
+impl QueryCache {
+    /// Create a cache holding at most `capacity` queries (0 disables caching)
+    pub fn new(capacity: usize) -> Self {
+        QueryCache {
+            entries: VecDeque::new(),
+            capacity,
+            generation: 0,
+            hits: 0,
+            misses: 0,
+            continuations: 0,
+        }
+    }
+
+    /// Change the capacity, evicting the least recently used entries
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.entries.truncate(capacity);
+    }
+
+    /// Drop every entry cached for an older index generation
+    fn sync_generation(&mut self, generation: u64) {
+        if self.generation != generation {
+            self.entries.clear();
+            self.generation = generation;
+        }
+    }
+
+    /// Look up the results of `query`, counting a hit or miss
+    ///
+    /// A hit is borrowed rather than copied, so it allocates nothing.
+    pub fn get(&mut self, generation: u64, kind: &QueryKind, query: &str) -> Option<&[(String, f64)]> {
+        self.sync_generation(generation);
+
+        match self.entries.iter().position(|e| &e.kind == kind && e.query == query) {
+            Some(pos) => {
+                self.hits += 1;
+                let entry = self.entries.remove(pos)?;
+                self.entries.push_front(entry);
+                self.entries.front().map(|e| e.results.as_slice())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Find the candidate set of the longest cached query that `query` extends
+    pub fn continuation_candidates(&mut self, generation: u64, kind: &QueryKind, query: &str) -> Option<Vec<String>> {
+        self.sync_generation(generation);
+        if !kind.supports_continuation() {
+            return None;
+        }
+
+        let entry = self.entries
+            .iter()
+            .filter(|e| &e.kind == kind && !e.query.is_empty() && query.starts_with(e.query.as_str()))
+            .max_by_key(|e| e.query.len())?;
+
+        self.continuations += 1;
+        Some(entry.results.iter().map(|(node_id, _)| node_id.clone()).collect())
+    }
+
+    /// Store the full results of `query`
+    pub fn insert(&mut self, generation: u64, kind: QueryKind, query: String, results: Vec<(String, f64)>) {
+        self.sync_generation(generation);
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.entries.retain(|e| !(e.kind == kind && e.query == query));
+        self.entries.push_front(CacheEntry { kind, query, results });
+        self.entries.truncate(self.capacity);
+    }
+
+    /// Drop every cached query, keeping the capacity and counters
+    ///
+    /// Returns an estimate of the heap bytes released.
+    pub fn clear(&mut self) -> usize {
+        let released = self.entries.capacity() * std::mem::size_of::<CacheEntry>()
+            + self.entries
+                .iter()
+                .map(|e| {
+                    e.query.capacity()
+                        + e.results.capacity() * std::mem::size_of::<(String, f64)>()
+                        + e.results.iter().map(|(node_id, _)| node_id.capacity()).sum::<usize>()
+                })
+                .sum::<usize>();
+        self.entries = VecDeque::new();
+        released
+    }
+
+    /// Current counters
+    pub fn stats(&self) -> QueryCacheStats {
+        QueryCacheStats {
+            entries: self.entries.len(),
+            capacity: self.capacity,
+            hits: self.hits,
+            misses: self.misses,
+            continuations: self.continuations,
+        }
+    }
+}
+
+impl Default for QueryCache {
+    fn default() -> Self {
+        QueryCache::new(DEFAULT_QUERY_CACHE_SIZE)
+    }
+}
@@ -0,0 +1,280 @@
+// Scoring profiles for SearchIndex
+// The weights the exact, account and multi searches rank names with, so each
+// surface (command palette, global search, file picker) can pick its own
+// ranking per query
+
+use std::borrow::Cow;
+use serde::{Deserialize, Serialize};
+
+use super::fuzzy::{jaro_winkler_similarity, sounds_like};
+
+pub use crate::errors::ERROR_INVALID_SCORING_PROFILE;
+
+/// Largest weight a profile may set
+pub const MAX_PROFILE_WEIGHT: f64 = 10.0;
+
+/// Smallest Jaro-Winkler similarity between a query and a name word that
+/// counts as a fuzzy match
+pub const FUZZY_MIN_SIMILARITY: f64 = 0.85;
+
+/// Weights of one ranking
+///
+/// A name equal to the query scores `exact`, one starting with it `prefix`,
+/// one containing it elsewhere `substring` (plus `word_boundary` when the
+/// match starts a word). Names not containing the query score `fuzzy` times
+/// the best similarity of a name word to the query (from FUZZY_MIN_SIMILARITY)
+/// or `phonetic` when a name word sounds like a query word; a weight of 0
+/// turns either off. Folders get `folder_boost` added, and `recency` scales
+/// the boosts of recently searched terms (set_term_boosts_json): 0 ignores
+/// them, 1 applies them as given.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScoringProfile {
+    /// Reported by explain_search_score_json
+    #[serde(default = "custom_profile_name")]
+    pub name: Cow<'static, str>,
+    #[serde(default = "default_exact")]
+    pub exact: f64,
+    #[serde(default = "default_prefix")]
+    pub prefix: f64,
+    #[serde(default = "default_substring")]
+    pub substring: f64,
+    #[serde(default = "default_word_boundary")]
+    pub word_boundary: f64,
+    #[serde(default)]
+    pub fuzzy: f64,
+    #[serde(default)]
+    pub phonetic: f64,
+    #[serde(default)]
+    pub folder_boost: f64,
+    #[serde(default = "default_recency")]
+    pub recency: f64,
+}
+
+fn custom_profile_name() -> Cow<'static, str> {
+    Cow::Borrowed("custom")
+}
+
+fn default_exact() -> f64 {
+    ScoringProfile::DEFAULT.exact
+}
+
+fn default_prefix() -> f64 {
+    ScoringProfile::DEFAULT.prefix
+}
+
+fn default_substring() -> f64 {
+    ScoringProfile::DEFAULT.substring
+}
+
+fn default_word_boundary() -> f64 {
+    ScoringProfile::DEFAULT.word_boundary
+}
+
+fn default_recency() -> f64 {
+    ScoringProfile::DEFAULT.recency
+}
+
+impl ScoringProfile {
+    /// Global search: substring matches, no fuzzy fallback
+    pub const DEFAULT: ScoringProfile = ScoringProfile {
+        name: Cow::Borrowed("default"),
+        exact: 1.0,
+        prefix: 0.9,
+        substring: 0.7,
+        word_boundary: 0.05,
+        fuzzy: 0.0,
+        phonetic: 0.0,
+        folder_boost: 0.0,
+        recency: 1.0,
+    };
+
+    /// Command palette: names starting with the query, or a word of it, first
+    pub const PALETTE: ScoringProfile = ScoringProfile {
+        name: Cow::Borrowed("palette"),
+        exact: 1.0,
+        prefix: 0.95,
+        substring: 0.3,
+        word_boundary: 0.5,
+        fuzzy: 0.0,
+        phonetic: 0.0,
+        folder_boost: 0.0,
+        recency: 1.5,
+    };
+
+    /// File picker: folders first, tolerant of typos
+    pub const BROWSER: ScoringProfile = ScoringProfile {
+        name: Cow::Borrowed("browser"),
+        exact: 1.0,
+        prefix: 0.8,
+        substring: 0.7,
+        word_boundary: 0.05,
+        fuzzy: 0.6,
+        phonetic: 0.0,
+        folder_boost: 0.5,
+        recency: 0.5,
+    };
+
+    /// Built-in profile by name ("default", "palette" or "browser")
+    pub fn builtin(name: &str) -> Option<ScoringProfile> {
+        [Self::DEFAULT, Self::PALETTE, Self::BROWSER].into_iter().find(|profile| profile.name == name)
+    }
+
+    /// Profile from a JSON request value: a built-in name or a weights object
+    /// (missing weights default to DEFAULT's)
+    pub fn from_json(value: &serde_json::Value) -> Result<ScoringProfile, String> {
+        let profile = match value {
+            serde_json::Value::String(name) => {
+                return Self::builtin(name).ok_or_else(|| format!("unknown scoring profile `{}`", name));
+            }
+            serde_json::Value::Object(_) => {
+                ScoringProfile::deserialize(value).map_err(|e| format!("invalid scoring profile: {}", e))?
+            }
+            _ => return Err("a scoring profile is a built-in name or an object of weights".to_string()),
+        };
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    /// Profile from an FFI argument: a built-in name or a JSON weights object
+    pub fn parse(text: &str) -> Result<ScoringProfile, String> {
+        if text.trim_start().starts_with('{') {
+            let value = serde_json::from_str(text).map_err(|e| format!("invalid scoring profile: {}", e))?;
+            Self::from_json(&value)
+        } else {
+            Self::from_json(&serde_json::Value::String(text.to_string()))
+        }
+    }
+
+    /// Check that every weight is within [0, MAX_PROFILE_WEIGHT]
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("scoring profile name must not be empty".to_string());
+        }
+        for (weight, value) in self.weights() {
+            if !(0.0..=MAX_PROFILE_WEIGHT).contains(&value) {
+                return Err(format!("weight `{}` is {}, must be within [0, {}]", weight, value, MAX_PROFILE_WEIGHT));
+            }
+        }
+        Ok(())
+    }
+
+    fn weights(&self) -> [(&'static str, f64); 8] {
+        [
+            ("exact", self.exact),
+            ("prefix", self.prefix),
+            ("substring", self.substring),
+            ("word_boundary", self.word_boundary),
+            ("fuzzy", self.fuzzy),
+            ("phonetic", self.phonetic),
+            ("folder_boost", self.folder_boost),
+            ("recency", self.recency),
+        ]
+    }
+
+    /// Key telling profiles apart in the query cache
+    pub(crate) fn cache_key(&self) -> String {
+        let weights: Vec<String> = self.weights().iter().map(|(_, value)| value.to_bits().to_string()).collect();
+        format!("{}:{}", self.name, weights.join(","))
+    }
+
+    /// Whether every match of a query is also a match of its shorter prefixes,
+    /// which fuzzy and phonetic matches are not
+    pub(crate) fn supports_continuation(&self) -> bool {
+        self.fuzzy == 0.0 && self.phonetic == 0.0
+    }
+
+    /// Score a lowercased name against a lowercased query, before boosts
+    pub fn score_name(&self, name_lower: &str, query_lower: &str) -> Option<f64> {
+        match name_lower.find(query_lower) {
+            Some(_) if name_lower == query_lower => Some(self.exact),
+            Some(0) => Some(self.prefix),
+            Some(position) => {
+                let at_word_start = name_lower[..position].ends_with(' ');
+                Some(self.substring + if at_word_start { self.word_boundary } else { 0.0 })
+            }
+            None => self.score_similar(name_lower, query_lower),
+        }
+    }
+
+    /// Fuzzy and phonetic score of a name that does not contain the query
+    fn score_similar(&self, name_lower: &str, query_lower: &str) -> Option<f64> {
+        if !self.supports_continuation() && !query_lower.is_empty() {
+            let name_words = || name_lower.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty());
+            let fuzzy = if self.fuzzy > 0.0 {
+                name_words()
+                    .map(|word| jaro_winkler_similarity(query_lower, word))
+                    .filter(|similarity| *similarity >= FUZZY_MIN_SIMILARITY)
+                    .max_by(|a, b| a.total_cmp(b))
+                    .map(|similarity| self.fuzzy * similarity)
+            } else {
+                None
+            };
+            let phonetic = if self.phonetic > 0.0 {
+                let sounds_alike = query_lower
+                    .split_whitespace()
+                    .any(|token| name_words().any(|word| sounds_like(token, word)));
+                sounds_alike.then_some(self.phonetic)
+            } else {
+                None
+            };
+            return match (fuzzy, phonetic) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            };
+        }
+        None
+    }
+
+    /// Boost of a recently searched term, scaled by `recency`
+    pub fn history_boost(&self, boost: f64) -> f64 {
+        1.0 + (boost - 1.0) * self.recency
+    }
+
+    /// Amount added to a document's score for being a folder
+    pub fn folder_bonus(&self, is_folder: bool) -> f64 {
+        if is_folder { self.folder_boost } else { 0.0 }
+    }
+}
+
+impl Default for ScoringProfile {
+    fn default() -> Self {
+        ScoringProfile::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles_parse_and_validate() {
+        assert_eq!(ScoringProfile::parse("palette"), Ok(ScoringProfile::PALETTE));
+        assert_eq!(ScoringProfile::parse("nope").unwrap_err(), "unknown scoring profile `nope`");
+
+        let custom = ScoringProfile::parse(r#"{"prefix": 2.5, "folder_boost": 1}"#).unwrap();
+        assert_eq!(custom.name, "custom");
+        assert_eq!((custom.prefix, custom.folder_boost, custom.exact), (2.5, 1.0, 1.0));
+
+        assert_eq!(ScoringProfile::parse(r#"{"fuzzy": 11}"#).unwrap_err(), "weight `fuzzy` is 11, must be within [0, 10]");
+        assert!(ScoringProfile::parse(r#"{"exact": -1}"#).unwrap_err().contains("`exact`"));
+        assert!(ScoringProfile::parse(r#"{"prefx": 1}"#).unwrap_err().contains("prefx"));
+        assert!(ScoringProfile::from_json(&serde_json::json!(3)).is_err());
+    }
+
+    #[test]
+    fn test_default_profile_keeps_substring_scores() {
+        let profile = ScoringProfile::DEFAULT;
+        assert_eq!(profile.score_name("report", "report"), Some(1.0));
+        assert_eq!(profile.score_name("report 2024", "report"), Some(0.9));
+        assert_eq!(profile.score_name("annual report", "report"), Some(0.75));
+        assert_eq!(profile.score_name("reports", "port"), Some(0.7));
+        assert_eq!(profile.score_name("raport", "report"), None);
+
+        // Typos only match with a fuzzy weight
+        let fuzzy = ScoringProfile { fuzzy: 1.0, ..ScoringProfile::DEFAULT };
+        let score = fuzzy.score_name("annual raport", "report").unwrap();
+        assert!((FUZZY_MIN_SIMILARITY..1.0).contains(&score));
+        assert_eq!(fuzzy.score_name("invoice", "report"), None);
+    }
+}
@@ -1,7 +1,13 @@
 // Search suggestions module for CloudNexus
 // Phase 2: Autocomplete suggestions based on indexed content
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use serde::{Deserialize, Serialize};
+
+use super::index::SearchIndex;
+
+/// Default cap on entries written by export (highest frequency kept)
+pub const DEFAULT_MAX_PERSISTED_SUGGESTIONS: usize = 5000;
 
 /// Search suggestion with score
 #[derive(Debug, Clone)]
@@ -11,6 +17,34 @@ pub struct Suggestion {
     pub frequency: usize,
 }
 
+/// Suggested text with the accounts it was indexed from
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountSuggestion {
+    pub text: String,
+    /// Source account ids, sorted; empty for texts not added from an index
+    pub account_ids: Vec<String>,
+}
+
+/// One learned text in an exported engine state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistedSuggestion {
+    /// Lowercased text (frequency key)
+    pub text: String,
+    /// Text as suggested, absent for texts that were only recorded as used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+    pub frequency: usize,
+}
+
+/// Learned frequencies and recency list, as exported for persistence
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SuggestionEngineState {
+    pub entries: Vec<PersistedSuggestion>,
+    /// Recently used texts, most recent first
+    #[serde(default)]
+    pub recent: Vec<String>,
+}
+
 /// Suggestion engine that builds a prefix-based index for autocomplete
 pub struct SuggestionEngine {
     /// Prefix -> suggestions (sorted by score)
@@ -25,6 +59,12 @@ pub struct SuggestionEngine {
     recent_suggestions: VecDeque<String>,
     /// Maximum recent suggestions to track
     max_recent: usize,
+    /// Lowercased text -> text as last suggested
+    display_map: HashMap<String, String>,
+    /// Maximum entries kept by export and import
+    max_persisted: usize,
+    /// Lowercased text -> accounts it was indexed from
+    sources: HashMap<String, BTreeSet<String>>,
 }
 
 impl SuggestionEngine {
@@ -37,6 +77,9 @@ impl SuggestionEngine {
             max_prefix_length,
             recent_suggestions: VecDeque::new(),
             max_recent: 100,
+            display_map: HashMap::new(),
+            max_persisted: DEFAULT_MAX_PERSISTED_SUGGESTIONS,
+            sources: HashMap::new(),
         }
     }
     
@@ -55,6 +98,49 @@ impl SuggestionEngine {
         
         // Update frequency
         *self.frequency_map.entry(text_lower.clone()).or_insert(0) += frequency;
+        self.display_map.insert(text_lower, text.to_string());
+        
+        self.upsert_prefixes(text, frequency);
+    }
+    
+    /// Add a suggestion indexed from a document of `account_id`
+    pub fn add_account_suggestion(&mut self, text: &str, frequency: usize, account_id: &str) {
+        if text.is_empty() {
+            return;
+        }
+        self.sources.entry(text.to_lowercase()).or_default().insert(account_id.to_string());
+        self.add_suggestion(text, frequency);
+    }
+    
+    /// Clear the engine and repopulate it from the documents of one account
+    /// (all accounts when `account_id` is None)
+    ///
+    /// Every document adds its name, and the names of its ancestor folders
+    /// found in the index. Returns the number of distinct suggestions.
+    pub fn rebuild_from_index(&mut self, index: &SearchIndex, account_id: Option<&str>) -> usize {
+        self.clear();
+        for doc in index.documents_for_account(account_id) {
+            self.add_account_suggestion(&doc.name, 1, &doc.account_id);
+            
+            // Path components, guarding against parent cycles
+            let mut visited: HashSet<&str> = HashSet::new();
+            visited.insert(&doc.node_id);
+            let mut parent_id = doc.parent_id.as_deref();
+            while let Some(parent) = parent_id.and_then(|id| index.get(id)) {
+                if !visited.insert(&parent.node_id) {
+                    break;
+                }
+                self.add_account_suggestion(&parent.name, 1, &doc.account_id);
+                parent_id = parent.parent_id.as_deref();
+            }
+        }
+        self.len()
+    }
+    
+    /// Insert or rescore `text` in every prefix list, adding `added_frequency` to existing entries
+    fn upsert_prefixes(&mut self, text: &str, added_frequency: usize) {
+        let text_lower = text.to_lowercase();
+        let total_frequency = self.frequency_map.get(&text_lower).copied().unwrap_or(0);
         
         // Add all prefixes
         let chars: Vec<char> = text_lower.chars().collect();
@@ -73,14 +159,14 @@ impl SuggestionEngine {
             let suggestion = Suggestion {
                 text: text.to_string(),
                 score,
-                frequency,
+                frequency: total_frequency,
             };
             
             let suggestions = self.prefix_map.entry(prefix_str).or_insert_with(Vec::new);
             
             // Add or update suggestion
             if let Some(existing) = suggestions.iter_mut().find(|s| s.text == text) {
-                existing.frequency += frequency;
+                existing.frequency += added_frequency;
                 existing.score = score;
             } else {
                 suggestions.push(suggestion);
@@ -132,6 +218,21 @@ impl SuggestionEngine {
             .collect()
     }
     
+    /// Get prefix-based suggestions with the accounts each was indexed from
+    pub fn get_account_suggestions(&self, prefix: &str, limit: usize) -> Vec<AccountSuggestion> {
+        self.get_suggestions(prefix)
+            .into_iter()
+            .take(limit)
+            .map(|s| {
+                let account_ids = self.sources
+                    .get(&s.text.to_lowercase())
+                    .map(|ids| ids.iter().cloned().collect())
+                    .unwrap_or_default();
+                AccountSuggestion { text: s.text, account_ids }
+            })
+            .collect()
+    }
+    
     /// Record a suggestion was used (boosts recency)
     pub fn record_usage(&mut self, text: &str) {
         // Add to recent
@@ -140,12 +241,99 @@ impl SuggestionEngine {
         self.recent_suggestions.push_front(text_lower.clone());
         
         // Trim recent
+        let mut changed = vec![text_lower.clone()];
         while self.recent_suggestions.len() > self.max_recent {
-            self.recent_suggestions.pop_back();
+            changed.extend(self.recent_suggestions.pop_back());
         }
         
         // Boost frequency
         *self.frequency_map.entry(text_lower).or_insert(0) += 1;
+        
+        // Scores of the used text and any text that lost its boost changed
+        for text_lower in changed {
+            self.rescore(&text_lower);
+        }
+    }
+    
+    /// Recompute the prefix scores of a suggested text after its frequency or recency changed
+    fn rescore(&mut self, text_lower: &str) {
+        if let Some(display) = self.display_map.get(text_lower).cloned() {
+            self.upsert_prefixes(&display, 0);
+        }
+    }
+    
+    /// Set the maximum number of entries kept by export and import
+    pub fn set_max_persisted(&mut self, max_persisted: usize) {
+        self.max_persisted = max_persisted;
+    }
+    
+    /// Export learned frequencies and the recency list
+    ///
+    /// Only the `max_persisted` most frequent texts are kept (ties by text).
+    pub fn export_state(&self) -> SuggestionEngineState {
+        let mut entries: Vec<PersistedSuggestion> = self.frequency_map
+            .iter()
+            .map(|(text, &frequency)| PersistedSuggestion {
+                text: text.clone(),
+                display: self.display_map.get(text).cloned(),
+                frequency,
+            })
+            .collect();
+        entries.sort_by(|a, b| b.frequency.cmp(&a.frequency).then_with(|| a.text.cmp(&b.text)));
+        entries.truncate(self.max_persisted);
+        
+        SuggestionEngineState {
+            entries,
+            recent: self.recent_suggestions.iter().cloned().collect(),
+        }
+    }
+    
+    /// Restore an exported state
+    ///
+    /// Frequencies are added to those already learned. Imported recent texts
+    /// rank after this engine's own recent texts. Returns the number of
+    /// entries imported (at most `max_persisted`).
+    pub fn import_state(&mut self, state: SuggestionEngineState) -> usize {
+        let mut entries = state.entries;
+        entries.retain(|e| !e.text.is_empty());
+        entries.sort_by(|a, b| b.frequency.cmp(&a.frequency).then_with(|| a.text.cmp(&b.text)));
+        entries.truncate(self.max_persisted);
+        
+        // Restore recency first so suggestions are scored with their boost
+        let before: HashSet<String> = self.recent_suggestions.iter().cloned().collect();
+        for text in state.recent {
+            let text_lower = text.to_lowercase();
+            if !self.recent_suggestions.contains(&text_lower) {
+                self.recent_suggestions.push_back(text_lower);
+            }
+        }
+        self.recent_suggestions.truncate(self.max_recent);
+        let after: HashSet<String> = self.recent_suggestions.iter().cloned().collect();
+        
+        let imported = entries.len();
+        let mut rescored: HashSet<String> = HashSet::new();
+        for entry in entries {
+            let text_lower = entry.text.to_lowercase();
+            match entry.display {
+                Some(display) if display.to_lowercase() == text_lower => {
+                    self.add_suggestion(&display, entry.frequency);
+                }
+                _ => {
+                    *self.frequency_map.entry(text_lower.clone()).or_insert(0) += entry.frequency;
+                    self.rescore(&text_lower);
+                }
+            }
+            rescored.insert(text_lower);
+        }
+        
+        // Texts already suggested here whose recency changed without being imported
+        for text_lower in after.symmetric_difference(&before) {
+            if !rescored.contains(text_lower) {
+                self.rescore(text_lower);
+            }
+        }
+        
+        imported
     }
     
     /// Clear all suggestions
@@ -153,6 +341,8 @@ impl SuggestionEngine {
         self.prefix_map.clear();
         self.frequency_map.clear();
         self.recent_suggestions.clear();
+        self.display_map.clear();
+        self.sources.clear();
     }
     
     /// Calculate suggestion score
@@ -193,6 +383,7 @@ impl SuggestionEngine {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::search::SearchDocument;
     
     #[test]
     fn test_suggestion_engine_basic() {
@@ -234,4 +425,106 @@ mod tests {
         engine.clear();
         assert!(engine.is_empty());
     }
-}
\ No newline at end of file
+    
+    fn sample_engine() -> SuggestionEngine {
+        let mut engine = SuggestionEngine::new(5, 10);
+        engine.add_suggestion("Report 2023.pdf", 4);
+        engine.add_suggestion("Report 2024.pdf", 4);
+        engine.add_suggestion("Receipts", 2);
+        engine.add_suggestion("Recipes.docx", 1);
+        engine.add_suggestion("README.md", 3);
+        engine.record_usage("recipes.docx");
+        engine.record_usage("Receipts");
+        engine
+    }
+    
+    #[test]
+    fn test_suggestion_engine_export_import_round_trip() {
+        let engine = sample_engine();
+        let json = serde_json::to_string(&engine.export_state()).unwrap();
+        
+        let mut restored = SuggestionEngine::new(5, 10);
+        let state: SuggestionEngineState = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.import_state(state), 5);
+        
+        for prefix in ["r", "re", "rec", "rep", "read"] {
+            assert_eq!(restored.get_prefix_suggestions(prefix, 5), engine.get_prefix_suggestions(prefix, 5), "prefix {}", prefix);
+        }
+        // The recency boost puts the used texts first
+        assert_eq!(restored.get_prefix_suggestions("rec", 2), vec!["Receipts", "Recipes.docx"]);
+        assert_eq!(restored.export_state().recent, vec!["receipts", "recipes.docx"]);
+    }
+    
+    #[test]
+    fn test_suggestion_engine_import_merges_and_caps() {
+        let mut engine = sample_engine();
+        engine.set_max_persisted(2);
+        let state = engine.export_state();
+        let texts: Vec<&str> = state.entries.iter().map(|e| e.text.as_str()).collect();
+        assert_eq!(texts, vec!["report 2023.pdf", "report 2024.pdf"]);
+        
+        // Frequencies add up when the text already exists
+        let mut other = SuggestionEngine::new(5, 10);
+        other.add_suggestion("Report 2024.pdf", 1);
+        assert_eq!(other.import_state(state), 2);
+        let exported = other.export_state();
+        assert_eq!(exported.entries[0].text, "report 2024.pdf");
+        assert_eq!(exported.entries[0].frequency, 5);
+        assert_eq!(other.get_prefix_suggestions("rep", 5), vec!["Report 2024.pdf", "Report 2023.pdf"]);
+    }
+    
+    fn account_index() -> SearchIndex {
+        let mut index = SearchIndex::new();
+        let docs = [
+            ("a1", "acc1", "Reports", true, None),
+            ("a2", "acc1", "Report 2024.pdf", false, Some("a1")),
+            ("a3", "acc1", "Recipes.docx", false, None),
+            ("b1", "acc2", "Reports", true, None),
+            ("b2", "acc2", "Report 2023.pdf", false, Some("b1")),
+            ("b3", "acc2", "Receipts", false, Some("b1")),
+        ];
+        for (node_id, account_id, name, is_folder, parent_id) in docs {
+            index.add_document(SearchDocument {
+                node_id: node_id.to_string(),
+                account_id: account_id.to_string(),
+                provider: "gdrive".to_string(),
+                email: String::new(),
+                name: name.to_string(),
+                is_folder,
+                parent_id: parent_id.map(str::to_string),
+            });
+        }
+        index
+    }
+    
+    fn texts(engine: &SuggestionEngine, prefix: &str) -> BTreeSet<String> {
+        engine.get_prefix_suggestions(prefix, 10).into_iter().collect()
+    }
+    
+    #[test]
+    fn test_suggestion_engine_rebuild_from_index_per_account() {
+        let index = account_index();
+        let mut acc1 = SuggestionEngine::new(10, 10);
+        acc1.add_suggestion("Stale entry", 5);
+        assert_eq!(acc1.rebuild_from_index(&index, Some("acc1")), 3);
+        assert_eq!(texts(&acc1, "re"), ["Recipes.docx", "Report 2024.pdf", "Reports"].map(String::from).into());
+        assert!(acc1.get_prefix_suggestions("st", 10).is_empty());
+        
+        let mut acc2 = SuggestionEngine::new(10, 10);
+        assert_eq!(acc2.rebuild_from_index(&index, Some("acc2")), 3);
+        assert_eq!(texts(&acc2, "re"), ["Receipts", "Report 2023.pdf", "Reports"].map(String::from).into());
+        // The folder counts once for itself and once per document below it
+        assert_eq!(acc2.get_prefix_suggestions("re", 1), vec!["Reports"]);
+        
+        let mut combined = SuggestionEngine::new(10, 10);
+        assert_eq!(combined.rebuild_from_index(&index, None), 5);
+        let suggestions = combined.get_account_suggestions("re", 10);
+        let find = |text: &str| suggestions.iter().find(|s| s.text == text).unwrap().account_ids.clone();
+        assert_eq!(find("Reports"), vec!["acc1", "acc2"]);
+        assert_eq!(find("Report 2023.pdf"), vec!["acc2"]);
+        assert_eq!(find("Recipes.docx"), vec!["acc1"]);
+        
+        let mut unknown = SuggestionEngine::default();
+        assert_eq!(unknown.rebuild_from_index(&index, Some("acc3")), 0);
+    }
+}
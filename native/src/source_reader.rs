@@ -0,0 +1,337 @@
+/// Source file reader for CloudNexus
+/// Chunk loops that read a local file (copy, upload encryption, hashing) go
+/// through SourceReader, which either wraps a BufReader or serves the file from
+/// a read-only memory map. Both variants hand out at most `buf.len()` bytes per
+/// read, so callers keep the same chunk sizes and cancellation windows.
+///
+/// The memory map is only used when requested, when the file sits on a local,
+/// non-removable volume and when it has not been modified recently. If the file
+/// changes size while it is being read, the reader switches back to buffered
+/// reads at the current position.
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use memmap2::Mmap;
+
+/// Filesystem magic numbers (statfs f_type) of network, FUSE and removable-media
+/// filesystems that are never mapped
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const UNMAPPABLE_FS_TYPES: &[u32] = &[
+    0x6969,     // NFS
+    0x517B,     // SMB
+    0xFF53_4D42, // CIFS
+    0xFE53_4D42, // SMB2
+    0x0102_1997, // 9P
+    0x6573_5546, // FUSE
+    0x4D44,     // FAT (SD cards, USB sticks)
+    0x2011_BAB0, // exFAT
+];
+
+/// Files modified more recently than this may still be written to and are read
+/// through the buffered path
+const MAP_MIN_UNMODIFIED: Duration = Duration::from_secs(60);
+
+/// Reader over a local source file
+pub enum SourceReader {
+    Buffered(BufReader<File>),
+    Mapped {
+        file: File,
+        map: Mmap,
+        pos: usize,
+    },
+}
+
+impl SourceReader {
+    /// Open `path` for reading, memory-mapping it if `use_mmap` is set and the
+    /// file is suitable; otherwise (or if mapping fails) a BufReader is used
+    pub fn open(path: &Path, use_mmap: bool) -> io::Result<Self> {
+        let file = File::open(path)?;
+
+        if use_mmap {
+            let metadata = file.metadata()?;
+            let len = metadata.len();
+            if len > 0 && len <= usize::MAX as u64 && is_settled(&metadata) && is_mappable_volume(&file) {
+                // SAFETY: the map is read-only and only read through the Read impl.
+                // This is not a guarantee against faults: if another process
+                // truncates the file between the length check before a read and
+                // the copy out of the map, touching the lost pages raises SIGBUS.
+                // The length check only narrows that window, so mapping is opt-in
+                // and limited to files that have not been modified for a while,
+                // which are unlikely to be written to while we read them.
+                if let Ok(map) = unsafe { Mmap::map(&file) } {
+                    #[cfg(unix)]
+                    let _ = map.advise(memmap2::Advice::Sequential);
+                    return Ok(SourceReader::Mapped { file, map, pos: 0 });
+                }
+            }
+        }
+
+        Ok(SourceReader::Buffered(BufReader::new(file)))
+    }
+
+    /// Current size of the underlying file, which may differ from the size at open
+    pub fn current_len(&self) -> io::Result<u64> {
+        match self {
+            SourceReader::Buffered(reader) => Ok(reader.get_ref().metadata()?.len()),
+            SourceReader::Mapped { file, .. } => Ok(file.metadata()?.len()),
+        }
+    }
+
+    /// Whether reads are currently served from a memory map
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, SourceReader::Mapped { .. })
+    }
+
+    /// Switch a mapped reader back to buffered reads at the current position
+    fn fall_back(&mut self) -> io::Result<()> {
+        let (mut file, pos) = match self {
+            SourceReader::Mapped { file, pos, .. } => (file.try_clone()?, *pos),
+            SourceReader::Buffered(_) => return Ok(()),
+        };
+        file.seek(SeekFrom::Start(pos as u64))?;
+        *self = SourceReader::Buffered(BufReader::new(file));
+        Ok(())
+    }
+}
+
+impl Read for SourceReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let resized = match self {
+            SourceReader::Buffered(reader) => return reader.read(buf),
+            SourceReader::Mapped { file, map, .. } => file.metadata()?.len() != map.len() as u64,
+        };
+
+        // The file grew or shrank since it was mapped; touching the map past the
+        // new end would fault, and bytes past the old end are not in the map
+        if resized {
+            self.fall_back()?;
+            return self.read(buf);
+        }
+
+        match self {
+            SourceReader::Mapped { map, pos, .. } => {
+                let n = buf.len().min(map.len() - *pos);
+                buf[..n].copy_from_slice(&map[*pos..*pos + n]);
+                *pos += n;
+                Ok(n)
+            }
+            SourceReader::Buffered(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// Whether the file was last modified long enough ago that no one is likely
+/// still writing to it
+fn is_settled(metadata: &std::fs::Metadata) -> bool {
+    metadata.modified()
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age >= MAP_MIN_UNMODIFIED)
+}
+
+/// Whether the file lives on a volume that is safe and worthwhile to map
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn is_mappable_volume(file: &File) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut stat) } != 0 {
+        return false;
+    }
+    let fs_type = stat.f_type as u32;
+    !UNMAPPABLE_FS_TYPES.contains(&fs_type)
+}
+
+/// Whether the file lives on a volume that is safe and worthwhile to map
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn is_mappable_volume(file: &File) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut stat) } != 0 {
+        return false;
+    }
+    stat.f_flags & libc::MNT_LOCAL as u32 != 0
+}
+
+/// Whether the file lives on a volume that is safe and worthwhile to map
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+fn is_mappable_volume(_file: &File) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::{c_void, CString};
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::ptr;
+
+    use crate::copy::{copy_file_streaming_with_options, CCopyOptions};
+    use crate::dedup::hash_file;
+    use crate::file_io::ERROR_SOURCE_CHANGED;
+    use crate::upload::{upload_free, upload_get_total_bytes, upload_init, upload_process_chunk,
+                        upload_set_tolerate_growth, upload_set_use_mmap};
+
+    /// Backdate the modification time so the file is eligible for mapping
+    fn settle(path: &Path) {
+        let modified = SystemTime::now() - MAP_MIN_UNMODIFIED * 2;
+        OpenOptions::new().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    extern "C" fn collect_chunk(data: *const u8, data_len: usize, chunk_index: u32, user_data: *mut c_void) {
+        let chunks = unsafe { &mut *(user_data as *mut Vec<(u32, Vec<u8>)>) };
+        chunks.push((chunk_index, unsafe { std::slice::from_raw_parts(data, data_len) }.to_vec()));
+    }
+
+    fn upload_chunks(path: &CString, use_mmap: u8) -> Vec<(u32, Vec<u8>)> {
+        let chunk_size = 1024 * 1024;
+        let ctx = upload_init(path.as_ptr(), ptr::null(), 0, chunk_size, 0, None, None, ptr::null(), ptr::null_mut());
+        assert!(!ctx.is_null());
+        upload_set_use_mmap(ctx, use_mmap);
+
+        let mut chunks: Vec<(u32, Vec<u8>)> = Vec::new();
+        let mut buffer = vec![0u8; chunk_size + 64];
+        let user_data = &mut chunks as *mut Vec<(u32, Vec<u8>)> as *mut c_void;
+        while upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_chunk), user_data) > 0 {}
+        upload_free(ctx);
+        chunks
+    }
+
+    #[test]
+    fn test_mapped_and_buffered_paths_match() {
+        let dir = std::env::temp_dir().join(format!("cn_source_reader_match_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("fixture.bin");
+        // 50MB plus a partial chunk
+        let content: Vec<u8> = (0..50 * 1024 * 1024 + 4321u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        std::fs::write(&source, &content).unwrap();
+        settle(&source);
+        assert!(SourceReader::open(&source, true).unwrap().is_mapped());
+        let source_c = CString::new(source.to_string_lossy().to_string()).unwrap();
+
+        // Copy
+        let mut copies = Vec::new();
+        for use_mmap in [0u8, 1] {
+            let dest = dir.join(format!("copy_{}.bin", use_mmap));
+            let dest_c = CString::new(dest.to_string_lossy().to_string()).unwrap();
+            let options = CCopyOptions { use_mmap, ..Default::default() };
+            let result = copy_file_streaming_with_options(
+                source_c.as_ptr(), dest_c.as_ptr(), 1024 * 1024, &options, None, ptr::null(), ptr::null_mut(),
+            );
+            assert_eq!(result, 0);
+            copies.push(std::fs::read(&dest).unwrap());
+        }
+        assert!(copies[0] == content);
+        assert!(copies[1] == content);
+
+        // Hash
+        let mut buffered_hash = [0u8; 32];
+        let mut mapped_hash = [0u8; 32];
+        assert_eq!(hash_file(source_c.as_ptr(), 0, buffered_hash.as_mut_ptr(), ptr::null()), 0);
+        assert_eq!(hash_file(source_c.as_ptr(), 1, mapped_hash.as_mut_ptr(), ptr::null()), 0);
+        assert_eq!(buffered_hash, mapped_hash);
+        assert_eq!(&buffered_hash, blake3::hash(&content).as_bytes());
+
+        // Upload chunks
+        let buffered_chunks = upload_chunks(&source_c, 0);
+        let mapped_chunks = upload_chunks(&source_c, 1);
+        assert_eq!(buffered_chunks.len(), 51);
+        assert!(buffered_chunks == mapped_chunks);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mapped_reader_falls_back_on_growth() {
+        let dir = std::env::temp_dir().join(format!("cn_source_reader_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("growing.bin");
+        std::fs::write(&path, vec![1u8; 4096]).unwrap();
+
+        // Files still being written to are not mapped
+        assert!(!SourceReader::open(&path, true).unwrap().is_mapped());
+
+        settle(&path);
+        let mut reader = SourceReader::open(&path, true).unwrap();
+        assert!(reader.is_mapped());
+        let mut buf = vec![0u8; 1024];
+        assert_eq!(reader.read(&mut buf).unwrap(), 1024);
+
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[2u8; 1024]).unwrap();
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(!reader.is_mapped());
+        assert_eq!(rest.len(), 3072 + 1024);
+        assert!(rest[..3072].iter().all(|&b| b == 1));
+        assert!(rest[3072..].iter().all(|&b| b == 2));
+
+        // Empty files are never mapped
+        std::fs::write(&path, b"").unwrap();
+        assert!(!SourceReader::open(&path, true).unwrap().is_mapped());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upload_source_growth_and_shrink() {
+        let dir = std::env::temp_dir().join(format!("cn_source_reader_growth_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        let path_c = CString::new(path.to_string_lossy().to_string()).unwrap();
+        let chunk_size = 64 * 1024;
+
+        // Upload the first chunk, let `change` modify the file on another thread, then drain
+        let upload = |tolerate_growth: u8, change: fn(std::path::PathBuf)| -> (isize, usize, Vec<u8>) {
+            std::fs::write(&path, vec![1u8; 2 * chunk_size]).unwrap();
+            let ctx = upload_init(path_c.as_ptr(), ptr::null(), 0, chunk_size, 0, None, None, ptr::null(), ptr::null_mut());
+            upload_set_tolerate_growth(ctx, tolerate_growth);
+            let mut chunks: Vec<(u32, Vec<u8>)> = Vec::new();
+            let mut buffer = vec![0u8; chunk_size + 64];
+            let user_data = &mut chunks as *mut Vec<(u32, Vec<u8>)> as *mut c_void;
+
+            assert_eq!(upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_chunk), user_data),
+                       chunk_size as isize);
+            let changed = path.clone();
+            std::thread::spawn(move || change(changed)).join().unwrap();
+
+            let mut result;
+            loop {
+                result = upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_chunk), user_data);
+                if result <= 0 {
+                    break;
+                }
+            }
+            let total = upload_get_total_bytes(ctx);
+            upload_free(ctx);
+            (result, total, chunks.into_iter().flat_map(|(_, data)| data).collect())
+        };
+        fn append(path: std::path::PathBuf) {
+            OpenOptions::new().append(true).open(path).unwrap().write_all(&[2u8; 1000]).unwrap();
+        }
+        fn shrink(path: std::path::PathBuf) {
+            OpenOptions::new().write(true).open(path).unwrap().set_len(1000).unwrap();
+        }
+
+        // Growth fails by default instead of silently uploading a truncated file
+        let (result, total, _) = upload(0, append);
+        assert_eq!(result, ERROR_SOURCE_CHANGED as isize);
+        assert_eq!(total, 2 * chunk_size);
+
+        // With growth tolerated the appended bytes are uploaded and the total is corrected
+        let (result, total, uploaded) = upload(1, append);
+        assert_eq!(result, 0);
+        assert_eq!(total, 2 * chunk_size + 1000);
+        assert_eq!(uploaded, std::fs::read(&path).unwrap());
+
+        // A shrinking source always fails
+        let (result, _, _) = upload(1, shrink);
+        assert_eq!(result, ERROR_SOURCE_CHANGED as isize);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,477 @@
+/// Temporary file management for CloudNexus
+/// Operations that produce a file write it under a unique `.cnxtmp` name next to
+/// its destination and rename it into place once complete. Temp files left behind
+/// by a crash are reclaimed with temp_sweep.
+use std::collections::HashSet;
+use std::ffi::{c_char, CStr, CString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::errors::checked;
+use crate::file_io::{ERROR_NULL_POINTER, ERROR_FILE_NOT_FOUND, ERROR_INVALID_PATH, ERROR_IO_FAILED,
+                     SUCCESS, c_str_to_path};
+
+/// Suffix identifying temp files created by this module
+pub const TEMP_FILE_SUFFIX: &str = ".cnxtmp";
+
+/// Temp files created by this process that have not been committed or discarded
+static ACTIVE_TEMP_FILES: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// Counter making temp names unique within a process
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn register(path: &Path) {
+    if let Ok(mut active) = ACTIVE_TEMP_FILES.lock() {
+        active.get_or_insert_with(HashSet::new).insert(path.to_path_buf());
+    }
+}
+
+fn unregister(path: &Path) {
+    if let Ok(mut active) = ACTIVE_TEMP_FILES.lock() {
+        if let Some(set) = active.as_mut() {
+            set.remove(path);
+        }
+    }
+}
+
+fn is_active(path: &Path) -> bool {
+    ACTIVE_TEMP_FILES.lock()
+        .map(|active| active.as_ref().is_some_and(|set| set.contains(path)))
+        .unwrap_or(false)
+}
+
+/// Create a new, uniquely named temp file in `dir`
+///
+/// The name is `<prefix>.<pid>-<timestamp>-<counter>.cnxtmp`.
+pub fn create_temp_file(dir: &Path, prefix: &str) -> io::Result<(PathBuf, File)> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+
+    loop {
+        let name = format!(
+            "{}.{}-{}-{}{}",
+            prefix,
+            std::process::id(),
+            timestamp,
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+            TEMP_FILE_SUFFIX
+        );
+        let path = dir.join(name);
+
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => {
+                register(&path);
+                return Ok((path, file));
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Create a temp file next to `final_path`, so committing it is a same-directory rename
+pub fn create_temp_file_for(final_path: &Path) -> io::Result<(PathBuf, File)> {
+    let dir = match final_path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let prefix = final_path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "tmp".to_string());
+    create_temp_file(dir, &prefix)
+}
+
+/// Flush a temp file to disk and atomically rename it to `final_path`
+pub fn commit_temp_file(temp_path: &Path, final_path: &Path) -> io::Result<()> {
+    File::open(temp_path)?.sync_all()?;
+    fs::rename(temp_path, final_path)?;
+    unregister(temp_path);
+
+    // Persist the rename itself; not supported on every platform
+    #[cfg(unix)]
+    if let Some(parent) = final_path.parent() {
+        if let Ok(dir) = File::open(if parent.as_os_str().is_empty() { Path::new(".") } else { parent }) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete a temp file that will not be committed
+pub fn discard_temp_file(temp_path: &Path) -> io::Result<()> {
+    unregister(temp_path);
+    match fs::remove_file(temp_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Delete stale temp files in `dir`
+///
+/// Only files carrying the temp suffix, last modified more than `older_than_ms`
+/// ago, and not in use by this process are removed.
+///
+/// Returns the number of files removed and the bytes reclaimed.
+pub fn sweep_temp_files(dir: &Path, older_than_ms: u64) -> io::Result<(usize, u64)> {
+    let cutoff = SystemTime::now()
+        .checked_sub(Duration::from_millis(older_than_ms))
+        .unwrap_or(UNIX_EPOCH);
+
+    let mut removed = 0;
+    let mut reclaimed = 0u64;
+
+    for entry in fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let is_temp = path.file_name()
+            .map(|n| n.to_string_lossy().ends_with(TEMP_FILE_SUFFIX))
+            .unwrap_or(false);
+        if !is_temp || is_active(&path) {
+            continue;
+        }
+
+        let metadata = match entry.metadata() {
+            Ok(m) if m.is_file() => m,
+            _ => continue,
+        };
+
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        if modified > cutoff {
+            continue;
+        }
+
+        if fs::remove_file(&path).is_ok() {
+            removed += 1;
+            reclaimed += metadata.len();
+        }
+    }
+
+    Ok((removed, reclaimed))
+}
+
+/// Create a uniquely named temp file
+///
+/// # Arguments
+/// * `dir` - Directory to create the temp file in
+/// * `prefix` - Name prefix (can be null)
+/// * `out_path` - Pointer receiving the temp file path (caller must free with scan_folder_free_string)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn temp_file_create(
+    dir: *const c_char,
+    prefix: *const c_char,
+    out_path: *mut *mut c_char,
+) -> i32 {
+    if out_path.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let dir = match unsafe { c_str_to_path(dir) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+
+    let prefix = if prefix.is_null() {
+        "tmp".to_string()
+    } else {
+        match unsafe { CStr::from_ptr(prefix) }.to_str() {
+            Ok(p) => p.to_string(),
+            Err(_) => return ERROR_INVALID_PATH,
+        }
+    };
+
+    if prefix.contains('/') || prefix.contains('\\') {
+        return ERROR_INVALID_PATH;
+    }
+
+    let path = match create_temp_file(&dir, &prefix) {
+        Ok((path, _)) => path,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return ERROR_FILE_NOT_FOUND,
+        Err(_) => return ERROR_IO_FAILED,
+    };
+
+    match CString::new(path.to_string_lossy().to_string()) {
+        Ok(s) => {
+            unsafe { *out_path = s.into_raw(); }
+            SUCCESS
+        }
+        Err(_) => {
+            let _ = discard_temp_file(&path);
+            unsafe { *out_path = ptr::null_mut(); }
+            ERROR_INVALID_PATH
+        }
+    }
+}
+
+/// Flush a temp file to disk and rename it to its final path
+///
+/// # Arguments
+/// * `path` - Temp file path from temp_file_create
+/// * `final_path` - Destination path (replaced if it exists)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn temp_file_commit(path: *const c_char, final_path: *const c_char) -> i32 {
+    let temp_path = match unsafe { c_str_to_path(path) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+    let final_path = match unsafe { c_str_to_path(final_path) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+
+    match commit_temp_file(&temp_path, &final_path) {
+        Ok(()) => SUCCESS,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => ERROR_FILE_NOT_FOUND,
+        Err(_) => ERROR_IO_FAILED,
+    }
+}
+
+/// Delete a temp file without committing it
+///
+/// # Arguments
+/// * `path` - Temp file path from temp_file_create
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn temp_file_discard(path: *const c_char) -> i32 {
+    let temp_path = match unsafe { c_str_to_path(path) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+
+    match discard_temp_file(&temp_path) {
+        Ok(()) => SUCCESS,
+        Err(_) => ERROR_IO_FAILED,
+    }
+}
+
+/// Delete stale temp files left behind in a directory (e.g. after a crash)
+///
+/// # Arguments
+/// * `dir` - Directory to sweep (not recursive)
+/// * `older_than_ms` - Only remove temp files last modified at least this long ago
+/// * `reclaimed_bytes` - Optional pointer receiving the number of bytes freed
+///
+/// # Returns
+/// Number of files removed, or negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn temp_sweep(
+    dir: *const c_char,
+    older_than_ms: u64,
+    reclaimed_bytes: *mut u64,
+) -> i32 {
+    let dir = match unsafe { c_str_to_path(dir) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+
+    match sweep_temp_files(&dir, older_than_ms) {
+        Ok((removed, reclaimed)) => {
+            if !reclaimed_bytes.is_null() {
+                unsafe { *reclaimed_bytes = reclaimed; }
+            }
+            removed as i32
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => ERROR_FILE_NOT_FOUND,
+        Err(_) => ERROR_IO_FAILED,
+    }
+}
+
+/// A byte offset is remembered for every this many spilled records, so paging
+/// skips at most this many lines
+const SPILL_CHECKPOINT_INTERVAL: usize = 1024;
+
+/// Records stored as newline-delimited JSON in a temp file, for result sets too
+/// large to keep in memory
+///
+/// The file is deleted when the spill is dropped.
+pub struct JsonLinesSpill {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    checkpoints: Vec<u64>,
+    item_count: usize,
+    bytes_written: u64,
+}
+
+impl JsonLinesSpill {
+    pub fn create(dir: &Path, prefix: &str) -> io::Result<Self> {
+        let (path, file) = create_temp_file(dir, prefix)?;
+        Ok(JsonLinesSpill {
+            path,
+            writer: Some(BufWriter::new(file)),
+            checkpoints: Vec::new(),
+            item_count: 0,
+            bytes_written: 0,
+        })
+    }
+
+    pub fn push<T: Serialize>(&mut self, item: &T) -> io::Result<()> {
+        let writer = self.writer.as_mut()
+            .ok_or_else(|| io::Error::other("spill already finished"))?;
+        if self.item_count.is_multiple_of(SPILL_CHECKPOINT_INTERVAL) {
+            self.checkpoints.push(self.bytes_written);
+        }
+
+        let mut line = serde_json::to_vec(item)?;
+        line.push(b'\n');
+        writer.write_all(&line)?;
+
+        self.bytes_written += line.len() as u64;
+        self.item_count += 1;
+        Ok(())
+    }
+
+    /// Flush and close the writer; the spill is read-only afterwards
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.writer.take() {
+            Some(mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.item_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.item_count == 0
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read up to `count` records starting at `start`, as raw JSON objects
+    ///
+    /// The spill must be finished first.
+    pub fn read_json_lines(&self, start: usize, count: usize) -> io::Result<Vec<String>> {
+        if start >= self.item_count || count == 0 {
+            return Ok(Vec::new());
+        }
+
+        let checkpoint = start / SPILL_CHECKPOINT_INTERVAL;
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.checkpoints[checkpoint]))?;
+
+        BufReader::new(file)
+            .lines()
+            .skip(start - checkpoint * SPILL_CHECKPOINT_INTERVAL)
+            .take(count.min(self.item_count - start))
+            .collect()
+    }
+}
+
+impl Drop for JsonLinesSpill {
+    fn drop(&mut self) {
+        self.writer = None;
+        let _ = discard_temp_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn age(path: &Path, by: Duration) {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - by).unwrap();
+    }
+
+    #[test]
+    fn test_sweep_age_filter() {
+        let dir = temp_dir("temp_sweep_age");
+
+        // Simulate temp files left behind by a crashed process
+        let stale = dir.join(format!("download.1-1-0{}", TEMP_FILE_SUFFIX));
+        let recent = dir.join(format!("download.1-1-1{}", TEMP_FILE_SUFFIX));
+        let unrelated = dir.join("notes.txt");
+        fs::write(&stale, vec![0u8; 1000]).unwrap();
+        fs::write(&recent, vec![0u8; 10]).unwrap();
+        fs::write(&unrelated, b"keep").unwrap();
+        age(&stale, Duration::from_secs(3600));
+        age(&unrelated, Duration::from_secs(3600));
+
+        // A temp file that is still in use by this process is never swept
+        let (active, _) = create_temp_file(&dir, "active").unwrap();
+        age(&active, Duration::from_secs(3600));
+
+        let dir_c = CString::new(dir.to_string_lossy().to_string()).unwrap();
+        let mut reclaimed = 0u64;
+        assert_eq!(temp_sweep(dir_c.as_ptr(), 60_000, &mut reclaimed), 1);
+        assert_eq!(reclaimed, 1000);
+        assert!(!stale.exists());
+        assert!(recent.exists());
+        assert!(unrelated.exists());
+        assert!(active.exists());
+
+        discard_temp_file(&active).unwrap();
+        assert!(!active.exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_committed_files_are_never_swept() {
+        let dir = temp_dir("temp_sweep_commit");
+        let final_path = dir.join("report.pdf");
+
+        let (temp_path, _) = create_temp_file_for(&final_path).unwrap();
+        assert!(temp_path.to_string_lossy().ends_with(TEMP_FILE_SUFFIX));
+        fs::write(&temp_path, b"complete").unwrap();
+        commit_temp_file(&temp_path, &final_path).unwrap();
+        assert!(!temp_path.exists());
+
+        age(&final_path, Duration::from_secs(3600));
+        assert_eq!(sweep_temp_files(&dir, 0).unwrap(), (0, 0));
+        assert_eq!(fs::read(&final_path).unwrap(), b"complete");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_json_lines_spill_pages_across_checkpoints() {
+        let dir = temp_dir("spill_pages");
+
+        let mut spill = JsonLinesSpill::create(&dir, "pages").unwrap();
+        let total = SPILL_CHECKPOINT_INTERVAL * 2 + 10;
+        for i in 0..total {
+            spill.push(&serde_json::json!({ "index": i })).unwrap();
+        }
+        spill.finish().unwrap();
+        assert_eq!(spill.len(), total);
+        let spill_path = spill.path().to_path_buf();
+
+        let start = SPILL_CHECKPOINT_INTERVAL - 3;
+        let lines = spill.read_json_lines(start, SPILL_CHECKPOINT_INTERVAL + 6).unwrap();
+        assert_eq!(lines.len(), SPILL_CHECKPOINT_INTERVAL + 6);
+        for (offset, line) in lines.iter().enumerate() {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(record["index"], start + offset);
+        }
+        assert_eq!(spill.read_json_lines(total - 2, 100).unwrap().len(), 2);
+
+        drop(spill);
+        assert!(!spill_path.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,19 @@
+/// Fixtures shared by the unit tests
+use std::ffi::CString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Fresh, empty directory under the system temp directory
+///
+/// Names must be unique across the crate's tests, which run in parallel.
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cloud_nexus_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Path as a C string for the FFI functions
+pub(crate) fn c_path(path: &Path) -> CString {
+    CString::new(path.to_string_lossy().to_string()).unwrap()
+}
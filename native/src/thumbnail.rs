@@ -0,0 +1,209 @@
+/// Encrypted thumbnail sidecars for CloudNexus
+/// Generates downscaled previews of local images and stores them in the standard
+/// encrypted container so the gallery never has to decrypt full files
+use std::ffi::c_char;
+use std::io::Cursor;
+use std::panic;
+use std::ptr;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageReader};
+
+use crate::file_io::{ERROR_NULL_POINTER, ERROR_INVALID_PATH, ERROR_FILE_NOT_FOUND, SUCCESS, c_str_to_path};
+use crate::{decrypt_file, encrypt_file, KEY_SIZE};
+
+pub use crate::errors::{
+    ERROR_UNSUPPORTED_IMAGE_FORMAT,
+    ERROR_IMAGE_PROCESSING_FAILED,
+    ERROR_THUMBNAIL_CRYPTO_FAILED,
+};
+
+/// JPEG quality used for thumbnails
+const THUMBNAIL_JPEG_QUALITY: u8 = 80;
+
+/// Decode an image file and produce JPEG bytes no larger than max_dimension on either side
+pub fn render_thumbnail(path: &std::path::Path, max_dimension: u32) -> Result<Vec<u8>, i32> {
+    let reader = ImageReader::open(path)
+        .map_err(|_| ERROR_FILE_NOT_FOUND)?
+        .with_guessed_format()
+        .map_err(|_| ERROR_FILE_NOT_FOUND)?;
+
+    if reader.format().is_none() {
+        return Err(ERROR_UNSUPPORTED_IMAGE_FORMAT);
+    }
+
+    // Decoders for malformed input should return errors, but never let a panic cross FFI
+    let decoded = panic::catch_unwind(panic::AssertUnwindSafe(|| reader.decode()))
+        .map_err(|_| ERROR_IMAGE_PROCESSING_FAILED)?
+        .map_err(|e| match e {
+            image::ImageError::Unsupported(_) => ERROR_UNSUPPORTED_IMAGE_FORMAT,
+            _ => ERROR_IMAGE_PROCESSING_FAILED,
+        })?;
+
+    let max_dimension = max_dimension.max(1);
+    let thumbnail = if decoded.width() > max_dimension || decoded.height() > max_dimension {
+        decoded.thumbnail(max_dimension, max_dimension)
+    } else {
+        decoded
+    };
+
+    // JPEG has no alpha channel
+    let rgb = DynamicImage::ImageRgb8(thumbnail.to_rgb8());
+
+    let mut output = Cursor::new(Vec::new());
+    let encoder = JpegEncoder::new_with_quality(&mut output, THUMBNAIL_JPEG_QUALITY);
+    rgb.write_with_encoder(encoder)
+        .map_err(|_| ERROR_IMAGE_PROCESSING_FAILED)?;
+
+    Ok(output.into_inner())
+}
+
+fn set_error(out_error: *mut i32, code: i32) {
+    if !out_error.is_null() {
+        unsafe { *out_error = code; }
+    }
+}
+
+/// Generate an encrypted thumbnail sidecar for a local image
+///
+/// # Arguments
+/// * `image_path` - Path to the source image
+/// * `max_dimension` - Maximum width/height of the thumbnail (aspect ratio is preserved)
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `output_len` - Pointer to store output length
+/// * `out_error` - Optional pointer receiving 0 or an error code (can be null)
+///
+/// # Returns
+/// Pointer to the encrypted sidecar in the standard CNER format (caller must free
+/// with free_buffer), or null on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn generate_encrypted_thumbnail(
+    image_path: *const c_char,
+    max_dimension: u32,
+    master_key: *const u8,
+    master_key_len: usize,
+    output_len: *mut usize,
+    out_error: *mut i32,
+) -> *mut u8 {
+    if image_path.is_null() || master_key.is_null() || output_len.is_null() {
+        set_error(out_error, ERROR_NULL_POINTER);
+        return ptr::null_mut();
+    }
+
+    if master_key_len != KEY_SIZE {
+        set_error(out_error, ERROR_THUMBNAIL_CRYPTO_FAILED);
+        return ptr::null_mut();
+    }
+
+    let path = match unsafe { c_str_to_path(image_path) } {
+        Ok(p) => p,
+        Err(_) => {
+            set_error(out_error, ERROR_INVALID_PATH);
+            return ptr::null_mut();
+        }
+    };
+
+    let jpeg = match render_thumbnail(&path, max_dimension) {
+        Ok(bytes) => bytes,
+        Err(code) => {
+            set_error(out_error, code);
+            return ptr::null_mut();
+        }
+    };
+
+    let sidecar = encrypt_file(jpeg.as_ptr(), jpeg.len(), master_key, master_key_len, output_len);
+    if sidecar.is_null() {
+        set_error(out_error, ERROR_THUMBNAIL_CRYPTO_FAILED);
+        return ptr::null_mut();
+    }
+
+    set_error(out_error, SUCCESS);
+    sidecar
+}
+
+/// Decrypt a thumbnail sidecar produced by generate_encrypted_thumbnail
+///
+/// # Arguments
+/// * `data` - Pointer to the encrypted sidecar
+/// * `data_len` - Length of the sidecar
+/// * `master_key` - Pointer to 32-byte Master Key
+/// * `master_key_len` - Length of master key (must be 32)
+/// * `output_len` - Pointer to store output length
+///
+/// # Returns
+/// Pointer to the raw image bytes (caller must free with free_buffer), or null on error
+#[no_mangle]
+pub extern "C" fn decrypt_thumbnail(
+    data: *const u8,
+    data_len: usize,
+    master_key: *const u8,
+    master_key_len: usize,
+    output_len: *mut usize,
+) -> *mut u8 {
+    decrypt_file(data, data_len, master_key, master_key_len, output_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::fs;
+    use std::slice;
+
+    use image::{ImageFormat, RgbaImage};
+
+    use crate::{free_buffer, MAGIC};
+
+    fn temp_file(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("cloud_nexus_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_thumbnail_round_trip() {
+        let path = temp_file("thumb_source.png");
+        RgbaImage::from_pixel(300, 150, image::Rgba([10, 200, 30, 128]))
+            .save_with_format(&path, ImageFormat::Png)
+            .unwrap();
+
+        let key = [9u8; 32];
+        let path_c = CString::new(path.to_string_lossy().to_string()).unwrap();
+        let mut sidecar_len = 0usize;
+        let mut error = -1;
+        let sidecar = generate_encrypted_thumbnail(path_c.as_ptr(), 100, key.as_ptr(), 32, &mut sidecar_len, &mut error);
+        assert!(!sidecar.is_null());
+        assert_eq!(error, SUCCESS);
+
+        let sidecar_bytes = unsafe { slice::from_raw_parts(sidecar, sidecar_len) };
+        assert_eq!(&sidecar_bytes[..4], &MAGIC.to_le_bytes());
+
+        let mut image_len = 0usize;
+        let image_ptr = decrypt_thumbnail(sidecar, sidecar_len, key.as_ptr(), 32, &mut image_len);
+        assert!(!image_ptr.is_null());
+        let image_bytes = unsafe { slice::from_raw_parts(image_ptr, image_len) }.to_vec();
+
+        let thumb = image::load_from_memory(&image_bytes).unwrap();
+        assert_eq!((thumb.width(), thumb.height()), (100, 50));
+
+        free_buffer(image_ptr);
+        free_buffer(sidecar);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_thumbnail_unsupported_format() {
+        let path = temp_file("thumb_source.txt");
+        fs::write(&path, b"definitely not an image").unwrap();
+
+        let key = [9u8; 32];
+        let path_c = CString::new(path.to_string_lossy().to_string()).unwrap();
+        let mut sidecar_len = 0usize;
+        let mut error = 0;
+        let sidecar = generate_encrypted_thumbnail(path_c.as_ptr(), 100, key.as_ptr(), 32, &mut sidecar_len, &mut error);
+        assert!(sidecar.is_null());
+        assert_eq!(error, ERROR_UNSUPPORTED_IMAGE_FORMAT);
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,754 @@
+/// Transfer queue for CloudNexus
+/// Queued file and folder copies run on the native runtime pool (see
+/// runtime.rs), highest priority first, with at most `max_concurrent` jobs in
+/// flight. Each running job is a regular
+/// chunked or folder copy context, so it also shows up in the active operation
+/// registry (see list_active_operations_json).
+use std::ffi::{c_char, c_void, CString};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+use serde::Serialize;
+
+use crate::errors::checked;
+use crate::copy::{chunked_copy_finalize, chunked_copy_free, chunked_copy_get_operation_id, chunked_copy_init,
+                  chunked_copy_open_source, chunked_copy_read_chunk, chunked_copy_write_chunk,
+                  folder_copy_finalize, folder_copy_free, folder_copy_get_operation_id, folder_copy_init_with_options,
+                  folder_copy_next_file};
+use crate::file_io::{c_str_to_path, ERROR_CANCELLED, ERROR_FILE_NOT_FOUND, ERROR_INVALID_PATH, ERROR_NULL_POINTER,
+                     SUCCESS};
+use crate::ffi_util::{json_envelope, ErrorEnvelope};
+use crate::operations::get_operation_progress;
+use crate::runtime;
+
+pub use crate::errors::ERROR_TRANSFER_JOB_NOT_FOUND;
+
+/// Most jobs a queue may run at once
+const MAX_QUEUE_CONCURRENCY: u32 = 16;
+
+/// Chunk size of queued file copies
+const QUEUE_COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Aggregated progress callback for a transfer queue
+/// Parameters: bytes copied and total bytes over all jobs, finished jobs, total jobs, user_data
+pub type TransferQueueProgressCallback = extern "C" fn(bytes_done: u64, total_bytes: u64, jobs_done: u32,
+                                                       total_jobs: u32, user_data: *mut c_void);
+
+/// What a queued job copies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferJobKind {
+    File,
+    Folder,
+}
+
+/// Lifecycle of a queued job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferJobStatus {
+    Pending,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// A queued job, as reported by transfer_queue_get_status_json
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferJob {
+    pub id: u64,
+    pub kind: TransferJobKind,
+    pub source: String,
+    pub dest: String,
+    /// Higher priorities start first; equal priorities start in the order added
+    pub priority: i32,
+    pub status: TransferJobStatus,
+    /// Error code of a failed job, 0 otherwise
+    pub error_code: i32,
+    /// Position in which the job started (1 for the first), null while pending
+    pub start_order: Option<u64>,
+    /// Active operation id while the job runs, 0 before
+    pub operation_id: u64,
+    pub bytes_done: u64,
+    pub total_bytes: u64,
+    pub files_done: u64,
+    pub total_files: u64,
+    #[serde(skip)]
+    cancel_flag: Arc<AtomicBool>,
+}
+
+impl TransferJob {
+    fn is_finished(&self) -> bool {
+        matches!(self.status, TransferJobStatus::Completed | TransferJobStatus::Failed | TransferJobStatus::Cancelled)
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// Jobs in the order added
+    jobs: Vec<TransferJob>,
+    paused: bool,
+    shutdown: bool,
+    next_id: u64,
+    started: u64,
+    max_concurrent: usize,
+    /// Jobs running on the runtime pool
+    running: usize,
+    /// Slots submitted to the runtime pool that have not started yet
+    waiting: usize,
+    progress_callback: Option<TransferQueueProgressCallback>,
+    // Stored as an address so the state can be shared with the workers
+    user_data: usize,
+}
+
+impl QueueState {
+    fn job_mut(&mut self, id: u64) -> Option<&mut TransferJob> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    /// Mark the highest priority pending job running and return a copy of it
+    fn start_next(&mut self) -> Option<TransferJob> {
+        let next = self.jobs
+            .iter()
+            .enumerate()
+            .filter(|(_, job)| job.status == TransferJobStatus::Pending)
+            // Highest priority, then earliest added
+            .max_by_key(|(index, job)| (job.priority, std::cmp::Reverse(*index)))
+            .map(|(index, _)| index)?;
+
+        self.started += 1;
+        let job = &mut self.jobs[next];
+        job.status = TransferJobStatus::Running;
+        job.start_order = Some(self.started);
+        Some(job.clone())
+    }
+
+    /// Reserve runtime slots for the pending jobs that may start now
+    fn reserve_slots(&mut self) -> usize {
+        if self.paused || self.shutdown {
+            return 0;
+        }
+        let pending = self.jobs.iter().filter(|job| job.status == TransferJobStatus::Pending).count();
+        let free = self.max_concurrent.saturating_sub(self.running + self.waiting);
+        let slots = free.min(pending.saturating_sub(self.waiting));
+        self.waiting += slots;
+        slots
+    }
+
+    /// Aggregated progress: bytes done, total bytes, finished jobs, total jobs
+    fn totals(&self) -> (u64, u64, u32, u32) {
+        let bytes_done = self.jobs.iter().map(|job| job.bytes_done).sum();
+        let total_bytes = self.jobs.iter().map(|job| job.total_bytes).sum();
+        let jobs_done = self.jobs.iter().filter(|job| job.is_finished()).count() as u32;
+        (bytes_done, total_bytes, jobs_done, self.jobs.len() as u32)
+    }
+}
+
+struct QueueShared {
+    state: Mutex<QueueState>,
+    /// Signalled whenever a slot ends
+    slot_done: Condvar,
+}
+
+impl QueueShared {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Update one job and report aggregated progress
+    fn update_job(&self, id: u64, update: impl FnOnce(&mut TransferJob)) {
+        let (callback, user_data, totals) = {
+            let mut state = self.lock();
+            if let Some(job) = state.job_mut(id) {
+                update(job);
+            }
+            (state.progress_callback, state.user_data, state.totals())
+        };
+
+        // Called without the lock so the callback may query the queue
+        if let Some(cb) = callback {
+            cb(totals.0, totals.1, totals.2, totals.3, user_data as *mut c_void);
+        }
+    }
+
+    /// Submit a runtime task for each pending job that may start now
+    fn dispatch(self: &Arc<Self>) {
+        let slots = self.lock().reserve_slots();
+        for _ in 0..slots {
+            let slot = JobSlot { shared: Arc::clone(self), started: false };
+            // A slot the runtime cannot take is dropped, which releases its reservation
+            let _ = runtime::spawn(move || slot.run());
+        }
+    }
+}
+
+/// A reservation for one job on the runtime pool
+///
+/// The job is picked when the slot starts, so priority changes made while the
+/// slot waits for a free worker still apply.
+struct JobSlot {
+    shared: Arc<QueueShared>,
+    started: bool,
+}
+
+impl JobSlot {
+    fn run(mut self) {
+        let job = {
+            let mut state = self.shared.lock();
+            self.started = true;
+            state.waiting -= 1;
+            let job = if state.paused || state.shutdown { None } else { state.start_next() };
+            if job.is_some() {
+                state.running += 1;
+            }
+            job
+        };
+
+        if let Some(job) = job {
+            let result = run_job(&self.shared, &job);
+            self.shared.update_job(job.id, |job| {
+                job.error_code = if result == SUCCESS || result == ERROR_CANCELLED { 0 } else { result };
+                job.status = match result {
+                    SUCCESS => TransferJobStatus::Completed,
+                    ERROR_CANCELLED => TransferJobStatus::Cancelled,
+                    _ => TransferJobStatus::Failed,
+                };
+            });
+            self.shared.lock().running -= 1;
+        }
+
+        self.shared.slot_done.notify_all();
+        self.shared.dispatch();
+    }
+}
+
+impl Drop for JobSlot {
+    fn drop(&mut self) {
+        // Dropped by the runtime without running (runtime shutdown)
+        if !self.started {
+            self.shared.lock().waiting -= 1;
+            self.shared.slot_done.notify_all();
+        }
+    }
+}
+
+/// Queue of copy jobs run on the native runtime pool
+pub struct TransferQueue {
+    shared: Arc<QueueShared>,
+}
+
+impl TransferQueue {
+    /// Create a queue running at most `max_concurrent` jobs at once (clamped to 1..=16)
+    ///
+    /// Queues share the runtime pool, so fewer jobs run when the pool has
+    /// fewer free workers.
+    pub fn new(max_concurrent: u32) -> Self {
+        let shared = Arc::new(QueueShared {
+            state: Mutex::new(QueueState {
+                next_id: 1,
+                max_concurrent: max_concurrent.clamp(1, MAX_QUEUE_CONCURRENCY) as usize,
+                ..Default::default()
+            }),
+            slot_done: Condvar::new(),
+        });
+
+        TransferQueue { shared }
+    }
+
+    /// Add a job and return its id
+    pub fn add(&self, kind: TransferJobKind, source: String, dest: String, priority: i32) -> u64 {
+        let mut state = self.shared.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.jobs.push(TransferJob {
+            id,
+            kind,
+            source,
+            dest,
+            priority,
+            status: TransferJobStatus::Pending,
+            error_code: 0,
+            start_order: None,
+            operation_id: 0,
+            bytes_done: 0,
+            total_bytes: 0,
+            files_done: 0,
+            total_files: 0,
+            cancel_flag: Arc::new(AtomicBool::new(false)),
+        });
+        drop(state);
+
+        self.shared.dispatch();
+        id
+    }
+
+    /// Change the priority of a pending job
+    pub fn reorder(&self, id: u64, priority: i32) -> Result<(), i32> {
+        let mut state = self.shared.lock();
+        match state.job_mut(id) {
+            Some(job) if job.status == TransferJobStatus::Pending => {
+                job.priority = priority;
+                Ok(())
+            }
+            _ => Err(ERROR_TRANSFER_JOB_NOT_FOUND),
+        }
+    }
+
+    /// Stop starting pending jobs; running jobs finish
+    pub fn pause(&self) {
+        self.shared.lock().paused = true;
+    }
+
+    /// Start pending jobs again
+    pub fn resume(&self) {
+        self.shared.lock().paused = false;
+        self.shared.dispatch();
+    }
+
+    /// Snapshot of all jobs in the order added
+    pub fn jobs(&self) -> Vec<TransferJob> {
+        self.shared.lock().jobs.clone()
+    }
+
+    fn set_progress_callback(&self, callback: Option<TransferQueueProgressCallback>, user_data: *mut c_void) {
+        let mut state = self.shared.lock();
+        state.progress_callback = callback;
+        state.user_data = user_data as usize;
+    }
+}
+
+impl Drop for TransferQueue {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.shutdown = true;
+        for job in &state.jobs {
+            job.cancel_flag.store(true, Ordering::SeqCst);
+        }
+
+        // Slots still waiting for a worker end without starting a job
+        while state.running > 0 || state.waiting > 0 {
+            state = self.shared.slot_done.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Progress of one running job, passed to the copy callbacks as user_data
+struct JobSink<'a> {
+    shared: &'a QueueShared,
+    id: u64,
+}
+
+extern "C" fn job_progress(bytes_copied: usize, total_bytes: usize, files_processed: usize, total_files: usize,
+                           user_data: *mut c_void) {
+    let sink = unsafe { &*(user_data as *const JobSink) };
+    sink.shared.update_job(sink.id, |job| {
+        job.bytes_done = bytes_copied as u64;
+        job.total_bytes = total_bytes as u64;
+        job.files_done = files_processed as u64;
+        job.total_files = total_files as u64;
+    });
+}
+
+/// Record the operation id of a job and its totals from the registry
+fn attach_operation(sink: &JobSink, operation_id: u64) {
+    let (mut bytes, mut total, mut files, mut total_files) = (0u64, 0u64, 0u64, 0u64);
+    get_operation_progress(operation_id, &mut bytes, &mut total, &mut files, &mut total_files);
+    sink.shared.update_job(sink.id, |job| {
+        job.operation_id = operation_id;
+        job.bytes_done = bytes;
+        job.total_bytes = total;
+        job.files_done = files;
+        job.total_files = total_files;
+    });
+}
+
+fn run_job(shared: &QueueShared, job: &TransferJob) -> i32 {
+    let (source, dest) = match (CString::new(job.source.as_str()), CString::new(job.dest.as_str())) {
+        (Ok(source), Ok(dest)) => (source, dest),
+        _ => return ERROR_INVALID_PATH,
+    };
+    let sink = JobSink { shared, id: job.id };
+    let cancel_flag = Arc::as_ptr(&job.cancel_flag);
+
+    match job.kind {
+        TransferJobKind::File => run_file_copy(&source, &dest, cancel_flag, &sink),
+        TransferJobKind::Folder => run_folder_copy(&source, &dest, cancel_flag, &sink),
+    }
+}
+
+fn run_file_copy(source: &CString, dest: &CString, cancel_flag: *const AtomicBool, sink: &JobSink) -> i32 {
+    let ctx = chunked_copy_init(source.as_ptr(), dest.as_ptr(), QUEUE_COPY_CHUNK_SIZE, cancel_flag);
+    if ctx.is_null() {
+        return ERROR_FILE_NOT_FOUND;
+    }
+    attach_operation(sink, chunked_copy_get_operation_id(ctx));
+    let user_data = sink as *const JobSink as *mut c_void;
+
+    let mut buffer = runtime::take_chunk_buffer(QUEUE_COPY_CHUNK_SIZE);
+    let mut copied = 0usize;
+    let mut result = chunked_copy_open_source(ctx);
+    while result == SUCCESS {
+        let n = chunked_copy_read_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, ptr::null_mut());
+        if n <= 0 {
+            result = n as i32;
+            break;
+        }
+        result = chunked_copy_write_chunk(ctx, buffer.as_ptr(), n as usize, Some(job_progress), user_data);
+        copied += n as usize;
+    }
+
+    // An empty source still creates the destination
+    if result == SUCCESS && copied == 0 {
+        result = chunked_copy_write_chunk(ctx, buffer.as_ptr(), 0, None, ptr::null_mut());
+    }
+    if result == SUCCESS {
+        result = chunked_copy_finalize(ctx, Some(job_progress), user_data);
+    }
+
+    chunked_copy_free(ctx);
+    result
+}
+
+fn run_folder_copy(source: &CString, dest: &CString, cancel_flag: *const AtomicBool, sink: &JobSink) -> i32 {
+    let mut status = SUCCESS;
+    let ctx = folder_copy_init_with_options(source.as_ptr(), dest.as_ptr(), ptr::null(), cancel_flag, &mut status);
+    if ctx.is_null() {
+        return status;
+    }
+    attach_operation(sink, folder_copy_get_operation_id(ctx));
+    let user_data = sink as *const JobSink as *mut c_void;
+
+    let mut result = 1;
+    while result > 0 {
+        result = folder_copy_next_file(ctx, Some(job_progress), user_data);
+    }
+    if result == SUCCESS {
+        result = folder_copy_finalize(ctx, Some(job_progress), user_data);
+    }
+
+    folder_copy_free(ctx);
+    result
+}
+
+/// Create a transfer queue
+///
+/// # Arguments
+/// * `max_concurrent` - Number of jobs that may run at once (clamped to 1..=16)
+///
+/// # Returns
+/// Pointer to TransferQueue (free with transfer_queue_free)
+#[no_mangle]
+pub extern "C" fn transfer_queue_create(max_concurrent: u32) -> *mut TransferQueue {
+    Box::leak(Box::new(TransferQueue::new(max_concurrent))) as *mut TransferQueue
+}
+
+/// Set the aggregated progress callback of a queue
+///
+/// The callback runs on the runtime pool threads whenever a job reports
+/// progress or finishes.
+///
+/// # Arguments
+/// * `queue` - Pointer to TransferQueue
+/// * `progress_callback` - Aggregated progress callback (null to remove)
+/// * `user_data` - User data passed to the callback
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn transfer_queue_set_progress_callback(
+    queue: *mut TransferQueue,
+    progress_callback: Option<TransferQueueProgressCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    if queue.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    unsafe { (&*queue).set_progress_callback(progress_callback, user_data); }
+    SUCCESS
+}
+
+fn add_job(queue: *mut TransferQueue, kind: TransferJobKind, source: *const c_char, dest: *const c_char,
+           priority: i32) -> u64 {
+    if queue.is_null() || source.is_null() || dest.is_null() {
+        return 0;
+    }
+
+    let (source, dest) = match unsafe { (c_str_to_path(source), c_str_to_path(dest)) } {
+        (Ok(source), Ok(dest)) => (source, dest),
+        _ => return 0,
+    };
+
+    let queue = unsafe { &*queue };
+    queue.add(kind, source.to_string_lossy().to_string(), dest.to_string_lossy().to_string(), priority)
+}
+
+/// Queue a file copy
+///
+/// # Arguments
+/// * `queue` - Pointer to TransferQueue
+/// * `source` - Source file path
+/// * `dest` - Destination file path
+/// * `priority` - Higher priorities start first; equal priorities start in the order added
+///
+/// # Returns
+/// Job id, or 0 on error
+#[no_mangle]
+pub extern "C" fn transfer_queue_add_copy(
+    queue: *mut TransferQueue,
+    source: *const c_char,
+    dest: *const c_char,
+    priority: i32,
+) -> u64 {
+    add_job(queue, TransferJobKind::File, source, dest, priority)
+}
+
+/// Queue a folder copy (same behavior as folder_copy_init/folder_copy_next_file)
+///
+/// # Arguments
+/// * `queue` - Pointer to TransferQueue
+/// * `source` - Source folder path
+/// * `dest` - Destination folder path
+/// * `priority` - Higher priorities start first; equal priorities start in the order added
+///
+/// # Returns
+/// Job id, or 0 on error
+#[no_mangle]
+pub extern "C" fn transfer_queue_add_folder_copy(
+    queue: *mut TransferQueue,
+    source: *const c_char,
+    dest: *const c_char,
+    priority: i32,
+) -> u64 {
+    add_job(queue, TransferJobKind::Folder, source, dest, priority)
+}
+
+/// Change the priority of a pending job
+///
+/// # Arguments
+/// * `queue` - Pointer to TransferQueue
+/// * `job_id` - Id from transfer_queue_add_copy or transfer_queue_add_folder_copy
+/// * `new_priority` - New priority
+///
+/// # Returns
+/// 0 on success, ERROR_TRANSFER_JOB_NOT_FOUND if no pending job has this id
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn transfer_queue_reorder(queue: *mut TransferQueue, job_id: u64, new_priority: i32) -> i32 {
+    if queue.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    match unsafe { (&*queue).reorder(job_id, new_priority) } {
+        Ok(()) => SUCCESS,
+        Err(code) => checked(code),
+    }
+}
+
+/// Pause a queue: pending jobs wait, running jobs finish
+///
+/// # Arguments
+/// * `queue` - Pointer to TransferQueue
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn transfer_queue_pause(queue: *mut TransferQueue) -> i32 {
+    if queue.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    unsafe { (&*queue).pause(); }
+    SUCCESS
+}
+
+/// Resume a paused queue
+///
+/// # Arguments
+/// * `queue` - Pointer to TransferQueue
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn transfer_queue_resume(queue: *mut TransferQueue) -> i32 {
+    if queue.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    unsafe { (&*queue).resume(); }
+    SUCCESS
+}
+
+/// Get the status of every job in a queue
+///
+/// Returns a JSON envelope (see ffi_util.rs) whose `data` is an array, in the
+/// order added, of objects with id, kind ("file" or "folder"), source, dest,
+/// priority, status ("pending", "running", "completed", "failed" or
+/// "cancelled"), error_code, start_order, operation_id, bytes_done,
+/// total_bytes, files_done and total_files.
+///
+/// # Arguments
+/// * `queue` - Pointer to TransferQueue
+/// * `out_len` - Pointer to store output length (can be null)
+///
+/// # Returns
+/// JSON envelope (caller must free with scan_folder_free_string), or null if it
+/// cannot be allocated
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn transfer_queue_get_status_json(queue: *mut TransferQueue, out_len: *mut usize) -> *mut c_char {
+    if queue.is_null() {
+        return json_envelope::<()>(Err(ErrorEnvelope::null_argument("queue")), out_len);
+    }
+
+    json_envelope(Ok(unsafe { &*queue }.jobs()), out_len)
+}
+
+/// Free a transfer queue
+///
+/// Running jobs are cancelled and pending jobs dropped; returns once no job of
+/// the queue is running.
+///
+/// # Arguments
+/// * `queue` - Pointer to TransferQueue to free
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn transfer_queue_free(queue: *mut TransferQueue) {
+    if !queue.is_null() {
+        unsafe {
+            let _ = Box::from_raw(queue);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::runtime::RUNTIME_TEST_LOCK;
+    use crate::test_util::{c_path, temp_dir};
+
+    fn wait_until_finished(queue: &TransferQueue) -> Vec<TransferJob> {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        loop {
+            let jobs = queue.jobs();
+            if jobs.iter().all(|job| job.is_finished()) {
+                return jobs;
+            }
+            assert!(Instant::now() < deadline, "queue did not finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[derive(Default)]
+    struct Aggregate {
+        last: Mutex<(u64, u64, u32, u32)>,
+    }
+
+    extern "C" fn record_aggregate(bytes_done: u64, total_bytes: u64, jobs_done: u32, total_jobs: u32,
+                                   user_data: *mut c_void) {
+        let aggregate = unsafe { &*(user_data as *const Aggregate) };
+        *aggregate.last.lock().unwrap() = (bytes_done, total_bytes, jobs_done, total_jobs);
+    }
+
+    #[test]
+    fn test_high_priority_job_added_last_runs_first() {
+        let _guard = RUNTIME_TEST_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let dir = temp_dir("priority");
+        let sizes = [3 * 1024 * 1024, 2 * 1024 * 1024, 1000];
+        for (i, size) in sizes.iter().enumerate() {
+            fs::write(dir.join(format!("source_{}.bin", i)), vec![i as u8; *size]).unwrap();
+        }
+
+        let queue = transfer_queue_create(1);
+        let aggregate = Aggregate::default();
+        assert_eq!(transfer_queue_set_progress_callback(queue, Some(record_aggregate),
+                                                        &aggregate as *const Aggregate as *mut c_void), SUCCESS);
+
+        // Hold the queue so all three jobs are pending when it starts
+        assert_eq!(transfer_queue_pause(queue), SUCCESS);
+        let ids: Vec<u64> = [0, 0, 10].iter().enumerate()
+            .map(|(i, priority)| {
+                let source = c_path(&dir.join(format!("source_{}.bin", i)));
+                let dest = c_path(&dir.join(format!("dest_{}.bin", i)));
+                transfer_queue_add_copy(queue, source.as_ptr(), dest.as_ptr(), *priority)
+            })
+            .collect();
+        assert!(ids.iter().all(|id| *id != 0));
+        assert_eq!(transfer_queue_resume(queue), SUCCESS);
+
+        let jobs = wait_until_finished(unsafe { &*queue });
+        let order: Vec<Option<u64>> = jobs.iter().map(|job| job.start_order).collect();
+        assert_eq!(order, vec![Some(2), Some(3), Some(1)]);
+        for (i, job) in jobs.iter().enumerate() {
+            assert_eq!(job.status, TransferJobStatus::Completed);
+            assert_ne!(job.operation_id, 0);
+            assert_eq!(fs::read(dir.join(format!("dest_{}.bin", i))).unwrap(), vec![i as u8; sizes[i]]);
+        }
+
+        let total: u64 = sizes.iter().map(|s| *s as u64).sum();
+        assert_eq!(*aggregate.last.lock().unwrap(), (total, total, 3, 3));
+
+        let mut len = 0usize;
+        let json = transfer_queue_get_status_json(queue, &mut len);
+        assert!(!json.is_null());
+        let text = unsafe { CString::from_raw(json) }.into_string().unwrap();
+        assert_eq!(text.len(), len);
+        assert!(text.starts_with("{\"ok\":true"));
+        assert!(text.contains("\"status\":\"completed\""));
+
+        let json = transfer_queue_get_status_json(ptr::null_mut(), &mut len);
+        let envelope: serde_json::Value =
+            serde_json::from_str(&unsafe { CString::from_raw(json) }.into_string().unwrap()).unwrap();
+        assert_eq!(envelope["error"]["code"], ERROR_NULL_POINTER);
+        assert_eq!(envelope["error"]["context"], "queue");
+
+        transfer_queue_free(queue);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_reorder_and_folder_copy() {
+        let _guard = RUNTIME_TEST_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let dir = temp_dir("reorder");
+        fs::create_dir_all(dir.join("folder/sub")).unwrap();
+        fs::write(dir.join("folder/a.txt"), b"alpha").unwrap();
+        fs::write(dir.join("folder/sub/b.txt"), b"beta").unwrap();
+        fs::write(dir.join("file.bin"), b"").unwrap();
+
+        let queue = TransferQueue::new(1);
+        queue.pause();
+        let file_id = queue.add(TransferJobKind::File, dir.join("file.bin").to_string_lossy().to_string(),
+                                dir.join("file_copy.bin").to_string_lossy().to_string(), 0);
+        let folder_id = queue.add(TransferJobKind::Folder, dir.join("folder").to_string_lossy().to_string(),
+                                  dir.join("folder_copy").to_string_lossy().to_string(), 0);
+        let missing_id = queue.add(TransferJobKind::File, dir.join("missing.bin").to_string_lossy().to_string(),
+                                   dir.join("missing_copy.bin").to_string_lossy().to_string(), -5);
+
+        assert_eq!(queue.reorder(folder_id, 1), Ok(()));
+        assert_eq!(queue.reorder(12345, 1), Err(ERROR_TRANSFER_JOB_NOT_FOUND));
+        queue.resume();
+
+        let jobs = wait_until_finished(&queue);
+        let job = |id: u64| jobs.iter().find(|job| job.id == id).unwrap().clone();
+        assert_eq!(job(folder_id).start_order, Some(1));
+        assert_eq!(job(file_id).start_order, Some(2));
+        assert_eq!(job(missing_id).status, TransferJobStatus::Failed);
+        assert_eq!(job(missing_id).error_code, ERROR_FILE_NOT_FOUND);
+        assert_eq!((job(folder_id).files_done, job(folder_id).total_files), (2, 2));
+
+        // Finished jobs can no longer be reordered
+        assert_eq!(queue.reorder(file_id, 3), Err(ERROR_TRANSFER_JOB_NOT_FOUND));
+        assert_eq!(fs::read(dir.join("folder_copy/sub/b.txt")).unwrap(), b"beta");
+        assert_eq!(fs::read(dir.join("file_copy.bin")).unwrap(), b"");
+
+        drop(queue);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
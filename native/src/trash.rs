@@ -0,0 +1,549 @@
+/// Trash support for CloudNexus
+/// Destructive native operations (recursive delete, overwriting copies) can move the
+/// affected files into a trash directory instead of removing them, so they can be
+/// restored during an undo window
+///
+/// Layout of the trash directory:
+/// - <trash>/<operation_id>/manifest.json - maps trashed items back to their original paths
+/// - <trash>/<operation_id>/items/...     - the trashed files and folders
+use std::ffi::{c_char, CString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::checked;
+use crate::copy::check_not_nested;
+use crate::file_io::{ERROR_NULL_POINTER, ERROR_FILE_NOT_FOUND, ERROR_INVALID_PATH, ERROR_IO_FAILED,
+                     SUCCESS, c_str_to_path};
+
+pub use crate::errors::{ERROR_TRASH_NOT_CONFIGURED, ERROR_RESTORE_CONFLICT};
+
+/// Name of the manifest file inside each operation folder
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+/// Folder holding the trashed items inside each operation folder
+const ITEMS_DIR_NAME: &str = "items";
+
+/// Trash directory configured by set_trash_directory
+static TRASH_DIRECTORY: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Counter making operation IDs unique within a process
+static OPERATION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A single trashed item
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub original_path: String,
+    pub trash_path: String,
+}
+
+/// Manifest describing one trash operation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashManifest {
+    pub operation_id: String,
+    pub created_ms: u64,
+    pub entries: Vec<TrashEntry>,
+}
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Get the configured trash directory
+pub fn trash_directory() -> Option<PathBuf> {
+    TRASH_DIRECTORY.lock().ok().and_then(|dir| dir.clone())
+}
+
+/// One destructive operation whose victims are collected in the trash
+///
+/// The manifest is rewritten after every move so the trash stays restorable
+/// even if the operation is interrupted.
+pub struct TrashOperation {
+    dir: PathBuf,
+    manifest: TrashManifest,
+}
+
+impl TrashOperation {
+    /// Start a new trash operation in the configured trash directory
+    pub fn begin() -> Result<Self, i32> {
+        let root = trash_directory().ok_or(ERROR_TRASH_NOT_CONFIGURED)?;
+
+        let created_ms = now_ms();
+        let operation_id = format!(
+            "{}-{}-{}",
+            created_ms,
+            std::process::id(),
+            OPERATION_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+
+        let dir = root.join(&operation_id);
+        fs::create_dir_all(dir.join(ITEMS_DIR_NAME)).map_err(|_| ERROR_IO_FAILED)?;
+
+        let operation = Self {
+            dir,
+            manifest: TrashManifest {
+                operation_id,
+                created_ms,
+                entries: Vec::new(),
+            },
+        };
+        operation.write_manifest()?;
+        Ok(operation)
+    }
+
+    /// Path of this operation's manifest file
+    pub fn manifest_path(&self) -> PathBuf {
+        self.dir.join(MANIFEST_FILE_NAME)
+    }
+
+    /// Move a file or folder into the trash
+    pub fn move_to_trash(&mut self, path: &Path) -> Result<(), i32> {
+        if fs::symlink_metadata(path).is_err() {
+            return Err(ERROR_FILE_NOT_FOUND);
+        }
+
+        let name = path.file_name().ok_or(ERROR_INVALID_PATH)?;
+        let target = unique_path(&self.dir.join(ITEMS_DIR_NAME).join(name));
+
+        move_path(path, &target)?;
+
+        self.manifest.entries.push(TrashEntry {
+            original_path: path.to_string_lossy().to_string(),
+            trash_path: target.to_string_lossy().to_string(),
+        });
+        self.write_manifest()
+    }
+
+    fn write_manifest(&self) -> Result<(), i32> {
+        let json = serde_json::to_string_pretty(&self.manifest).map_err(|_| ERROR_IO_FAILED)?;
+        fs::write(self.manifest_path(), json).map_err(|_| ERROR_IO_FAILED)
+    }
+}
+
+/// Find a path that does not exist yet by appending " (n)" to the file stem
+pub(crate) fn unique_path(path: &Path) -> PathBuf {
+    if fs::symlink_metadata(path).is_err() {
+        return path.to_path_buf();
+    }
+
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+
+    let mut n = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{} ({}){}", stem, n, extension));
+        if fs::symlink_metadata(&candidate).is_err() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// Move a file or folder, falling back to copy + delete across filesystems
+///
+/// Moving a folder onto itself or into one of its descendants fails with
+/// ERROR_SAME_PATH or ERROR_DEST_INSIDE_SOURCE before anything is touched.
+pub(crate) fn move_path(from: &Path, to: &Path) -> Result<(), i32> {
+    check_not_nested(from, to)?;
+    move_path_unchecked(from, to).map_err(|_| ERROR_IO_FAILED)
+}
+
+fn move_path_unchecked(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    copy_recursive(from, to)?;
+    if from.is_dir() {
+        fs::remove_dir_all(from)
+    } else {
+        fs::remove_file(from)
+    }
+}
+
+fn copy_recursive(from: &Path, to: &Path) -> io::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        fs::copy(from, to).map(|_| ())
+    }
+}
+
+/// Delete a file or folder, moving it into a new trash operation when requested
+///
+/// Returns the manifest path of the trash operation when the item was trashed.
+pub fn delete_path_impl(path: &Path, use_trash: bool) -> Result<Option<PathBuf>, i32> {
+    let metadata = fs::symlink_metadata(path).map_err(|_| ERROR_FILE_NOT_FOUND)?;
+
+    if use_trash {
+        let mut operation = TrashOperation::begin()?;
+        operation.move_to_trash(path)?;
+        return Ok(Some(operation.manifest_path()));
+    }
+
+    let result = if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    result.map_err(|_| ERROR_IO_FAILED)?;
+    Ok(None)
+}
+
+/// Whether `name` has the `{ms}-{pid}-{n}` form of the IDs TrashOperation::begin creates
+fn is_operation_id(name: &str) -> bool {
+    let parts: Vec<&str> = name.split('-').collect();
+    parts.len() == 3 && parts.iter().all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Operation folder of a manifest, which must be the manifest file of an
+/// operation folder directly inside the configured trash directory
+///
+/// Both paths are resolved first, so symlinks cannot lead outside the trash.
+fn operation_dir_of(manifest_path: &Path) -> Result<PathBuf, i32> {
+    let root = trash_directory().ok_or(ERROR_TRASH_NOT_CONFIGURED)?;
+    let root = fs::canonicalize(root).map_err(|_| ERROR_TRASH_NOT_CONFIGURED)?;
+    let manifest_path = fs::canonicalize(manifest_path).map_err(|_| ERROR_FILE_NOT_FOUND)?;
+
+    let operation_dir = manifest_path.parent().ok_or(ERROR_INVALID_PATH)?;
+    let in_trash = manifest_path.file_name().is_some_and(|name| name == MANIFEST_FILE_NAME)
+        && operation_dir.parent() == Some(root.as_path())
+        && operation_dir.file_name().and_then(|name| name.to_str()).is_some_and(is_operation_id);
+    if !in_trash {
+        return Err(ERROR_INVALID_PATH);
+    }
+    Ok(operation_dir.to_path_buf())
+}
+
+/// Restore every item of a trash operation to its original location
+///
+/// Existing files at the original locations are replaced (undoing an overwrite);
+/// existing directories are never replaced. The manifest must belong to an
+/// operation in the configured trash directory and may only name items inside
+/// that operation; anything else fails with ERROR_INVALID_PATH before a file
+/// is moved or deleted.
+pub fn restore_trash_operation_impl(manifest_path: &Path) -> Result<(), i32> {
+    let operation_dir = operation_dir_of(manifest_path)?;
+    let items_dir = operation_dir.join(ITEMS_DIR_NAME);
+    let json = fs::read_to_string(operation_dir.join(MANIFEST_FILE_NAME)).map_err(|_| ERROR_FILE_NOT_FOUND)?;
+    let manifest: TrashManifest = serde_json::from_str(&json).map_err(|_| ERROR_INVALID_PATH)?;
+
+    // Check every entry before moving anything so a conflict leaves the trash intact
+    for entry in &manifest.entries {
+        let original = Path::new(&entry.original_path);
+        if original.is_dir() {
+            return Err(ERROR_RESTORE_CONFLICT);
+        }
+        let trashed = Path::new(&entry.trash_path);
+        if fs::symlink_metadata(trashed).is_err() {
+            return Err(ERROR_FILE_NOT_FOUND);
+        }
+        // The item itself may be a symlink, so only its folder is resolved
+        let in_items = trashed.file_name().is_some()
+            && trashed.parent().and_then(|parent| fs::canonicalize(parent).ok()) == Some(items_dir.clone());
+        if !in_items {
+            return Err(ERROR_INVALID_PATH);
+        }
+    }
+
+    // Restore in reverse so later moves of the same path are undone first
+    for entry in manifest.entries.iter().rev() {
+        let original = Path::new(&entry.original_path);
+        if original.is_file() {
+            fs::remove_file(original).map_err(|_| ERROR_IO_FAILED)?;
+        }
+        move_path(Path::new(&entry.trash_path), original)?;
+    }
+
+    let _ = fs::remove_dir_all(&operation_dir);
+
+    Ok(())
+}
+
+/// Permanently delete trash operations older than `older_than_ms`
+///
+/// Only folders named like an operation ID and holding a manifest are
+/// operations; anything else in the trash directory is left alone. Returns the
+/// number of operations purged.
+pub fn purge_trash_impl(older_than_ms: u64) -> Result<usize, i32> {
+    let root = trash_directory().ok_or(ERROR_TRASH_NOT_CONFIGURED)?;
+    let cutoff = now_ms().saturating_sub(older_than_ms);
+
+    let entries = match fs::read_dir(&root) {
+        Ok(e) => e,
+        Err(_) => return Ok(0),
+    };
+
+    let mut purged = 0;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let is_operation = entry.file_type().is_ok_and(|t| t.is_dir())
+            && entry.file_name().to_str().is_some_and(is_operation_id);
+        let operation_dir = entry.path();
+        if !is_operation || !operation_dir.join(MANIFEST_FILE_NAME).is_file() {
+            continue;
+        }
+
+        // A manifest that cannot be read may be mid-write, so age the folder by
+        // its modification time instead
+        let created_ms = fs::read_to_string(operation_dir.join(MANIFEST_FILE_NAME))
+            .ok()
+            .and_then(|json| serde_json::from_str::<TrashManifest>(&json).ok())
+            .map(|m| m.created_ms)
+            .or_else(|| modified_ms(&operation_dir));
+
+        if created_ms.is_some_and(|created| created <= cutoff) {
+            if fs::remove_dir_all(&operation_dir).is_err() {
+                return Err(ERROR_IO_FAILED);
+            }
+            purged += 1;
+        }
+    }
+
+    Ok(purged)
+}
+
+/// Modification time of `path` in milliseconds since the epoch
+fn modified_ms(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64)
+}
+
+fn path_to_c_string(path: &Path) -> *mut c_char {
+    match CString::new(path.to_string_lossy().to_string()) {
+        Ok(s) => s.into_raw(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Set the trash directory used by destructive operations
+///
+/// # Arguments
+/// * `path` - Path to the trash directory (created if missing), or null to disable the trash
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn set_trash_directory(path: *const c_char) -> i32 {
+    let dir = if path.is_null() {
+        None
+    } else {
+        let dir = match unsafe { c_str_to_path(path) } {
+            Ok(p) => p,
+            Err(e) => return checked(e),
+        };
+        if fs::create_dir_all(&dir).is_err() {
+            return ERROR_IO_FAILED;
+        }
+        Some(dir)
+    };
+
+    match TRASH_DIRECTORY.lock() {
+        Ok(mut current) => {
+            *current = dir;
+            SUCCESS
+        }
+        Err(_) => ERROR_IO_FAILED,
+    }
+}
+
+/// Recursively delete a file or folder
+///
+/// # Arguments
+/// * `path` - Path to the file or folder to delete
+/// * `use_trash` - 1 to move the item into the trash instead of removing it
+/// * `manifest_out` - Optional pointer receiving the trash manifest path when trashed
+///   (caller must free with scan_folder_free_string)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn delete_path(
+    path: *const c_char,
+    use_trash: u8,
+    manifest_out: *mut *mut c_char,
+) -> i32 {
+    if !manifest_out.is_null() {
+        unsafe { *manifest_out = ptr::null_mut(); }
+    }
+
+    let path = match unsafe { c_str_to_path(path) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+
+    match delete_path_impl(&path, use_trash != 0) {
+        Ok(manifest) => {
+            if let (Some(manifest), false) = (manifest, manifest_out.is_null()) {
+                unsafe { *manifest_out = path_to_c_string(&manifest); }
+            }
+            SUCCESS
+        }
+        Err(code) => checked(code),
+    }
+}
+
+/// Restore a trash operation
+///
+/// # Arguments
+/// * `manifest_path` - Path to the operation's manifest.json
+///
+/// # Returns
+/// 0 on success, ERROR_INVALID_PATH for a manifest outside the configured trash
+/// directory or naming items outside its operation, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn restore_trash_operation(manifest_path: *const c_char) -> i32 {
+    if manifest_path.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    let manifest_path = match unsafe { c_str_to_path(manifest_path) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+
+    match restore_trash_operation_impl(&manifest_path) {
+        Ok(()) => SUCCESS,
+        Err(code) => checked(code),
+    }
+}
+
+/// Permanently delete trash operations older than the given age
+///
+/// Folders in the trash directory that are not trash operations are never deleted.
+///
+/// # Arguments
+/// * `older_than_ms` - Minimum age in milliseconds (0 purges every operation)
+///
+/// # Returns
+/// Number of operations purged, or negative error code on failure
+#[no_mangle]
+pub extern "C" fn purge_trash(older_than_ms: u64) -> i32 {
+    match purge_trash_impl(older_than_ms) {
+        Ok(count) => count as i32,
+        Err(code) => checked(code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::scan_folder_sync;
+    use crate::test_util::temp_dir;
+
+    fn snapshot(root: &Path) -> Vec<(String, Option<Vec<u8>>)> {
+        let scan = scan_folder_sync(root.to_str().unwrap(), None).unwrap();
+        let mut items: Vec<_> = scan.items.into_iter()
+            .map(|item| {
+                let content = if item.is_folder { None } else { Some(fs::read(&item.absolute_path).unwrap()) };
+                (item.relative_path, content)
+            })
+            .collect();
+        items.sort();
+        items
+    }
+
+    // The trash directory is process-wide, so the whole lifecycle runs in one test
+    #[test]
+    fn test_trash_lifecycle() {
+        let root = temp_dir("trash");
+        let tree = root.join("tree");
+        fs::create_dir_all(tree.join("a/b")).unwrap();
+        fs::write(tree.join("top.txt"), b"top").unwrap();
+        fs::write(tree.join("a/b/deep.txt"), b"deep").unwrap();
+        let before = snapshot(&tree);
+
+        // Trashing without a configured directory must not delete anything
+        set_trash_directory(ptr::null());
+        assert_eq!(delete_path_impl(&tree, true), Err(ERROR_TRASH_NOT_CONFIGURED));
+        assert!(tree.exists());
+
+        let trash = root.join("trash");
+        let trash_c = CString::new(trash.to_string_lossy().to_string()).unwrap();
+        assert_eq!(set_trash_directory(trash_c.as_ptr()), SUCCESS);
+
+        // Delete with trash, then restore and compare
+        let manifest = delete_path_impl(&tree, true).unwrap().unwrap();
+        assert!(!tree.exists());
+        assert_eq!(restore_trash_operation_impl(&manifest), Ok(()));
+        assert_eq!(snapshot(&tree), before);
+        assert!(!manifest.exists());
+
+        // Same-named items in one operation get unique names inside the trash
+        let mut operation = TrashOperation::begin().unwrap();
+        fs::write(tree.join("a/top.txt"), b"second").unwrap();
+        operation.move_to_trash(&tree.join("top.txt")).unwrap();
+        operation.move_to_trash(&tree.join("a/top.txt")).unwrap();
+        let trashed: Vec<_> = operation.manifest.entries.iter().map(|e| e.trash_path.clone()).collect();
+        assert_ne!(trashed[0], trashed[1]);
+        assert!(trashed[1].ends_with("top (1).txt"));
+
+        // Restoring replaces a file recreated at the original location
+        fs::write(tree.join("top.txt"), b"overwritten").unwrap();
+        restore_trash_operation_impl(&operation.manifest_path()).unwrap();
+        assert_eq!(fs::read(tree.join("top.txt")).unwrap(), b"top");
+        assert_eq!(fs::read(tree.join("a/top.txt")).unwrap(), b"second");
+
+        // A manifest outside the trash, or one naming items outside its operation, is refused untouched
+        let outside = root.join("outside");
+        fs::create_dir_all(outside.join("items")).unwrap();
+        fs::write(outside.join("items/keep.txt"), b"keep").unwrap();
+        let forged = |trash_path: &Path| TrashManifest {
+            operation_id: "1-1-1".to_string(),
+            created_ms: 1,
+            entries: vec![TrashEntry {
+                original_path: tree.join("moved.txt").to_string_lossy().to_string(),
+                trash_path: trash_path.to_string_lossy().to_string(),
+            }],
+        };
+        let forged_json = |trash_path: &Path| serde_json::to_string(&forged(trash_path)).unwrap();
+        fs::write(outside.join(MANIFEST_FILE_NAME), forged_json(&outside.join("items/keep.txt"))).unwrap();
+        assert_eq!(restore_trash_operation_impl(&outside.join(MANIFEST_FILE_NAME)), Err(ERROR_INVALID_PATH));
+        let operation = TrashOperation::begin().unwrap();
+        fs::write(operation.manifest_path(), forged_json(&outside.join("items/keep.txt"))).unwrap();
+        assert_eq!(restore_trash_operation_impl(&operation.manifest_path()), Err(ERROR_INVALID_PATH));
+        assert!(operation.manifest_path().exists());
+        assert_eq!(fs::read(outside.join("items/keep.txt")).unwrap(), b"keep");
+        assert!(!tree.join("moved.txt").exists());
+        fs::remove_dir_all(&operation.dir).unwrap();
+
+        // Purging removes old operations, aged by their mtime while the manifest is unreadable
+        delete_path_impl(&tree.join("a"), true).unwrap();
+        let unreadable = trash.join("1-2-3");
+        fs::create_dir(&unreadable).unwrap();
+        fs::write(unreadable.join(MANIFEST_FILE_NAME), b"{").unwrap();
+        // Anything that is not an operation stays, whatever its age
+        fs::create_dir(trash.join("9-9-9")).unwrap();
+        fs::create_dir_all(trash.join("Documents")).unwrap();
+        fs::write(trash.join("Documents").join(MANIFEST_FILE_NAME), forged_json(&outside)).unwrap();
+        fs::write(trash.join("notes.txt"), b"notes").unwrap();
+        assert_eq!(purge_trash_impl(u64::MAX), Ok(0));
+        assert_eq!(purge_trash_impl(60_000), Ok(0));
+        assert_eq!(purge_trash_impl(0), Ok(2));
+        let mut left: Vec<_> = fs::read_dir(&trash).unwrap().map(|e| e.unwrap().file_name()).collect();
+        left.sort();
+        assert_eq!(left, ["9-9-9", "Documents", "notes.txt"]);
+
+        set_trash_directory(ptr::null());
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,465 @@
+/// Folder tree digests for CloudNexus sync comparison
+///
+/// A Merkle-style digest of a local tree: every file gets a BLAKE3 leaf digest
+/// (of its content, or of size + mtime in fast mode), and every directory is the
+/// BLAKE3 of its children's (kind, name, digest) records in byte order of name.
+/// Comparing root digests tells whether a mirror is up to date; the optional
+/// per-directory map narrows a mismatch down to the subtrees that differ.
+///
+/// Symlinks are never followed: their leaf digest covers the link target.
+/// Entries that cannot be read get a fixed marker digest and are listed in
+/// `unreadable` instead of failing the whole computation.
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_void, CString};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::checked;
+use crate::copy::CopyProgressCallback;
+use crate::dedup::{hash_file_impl, FINGERPRINT_SIZE};
+use crate::file_io::{ProgressThrottler, ERROR_CANCELLED, ERROR_FILE_NOT_FOUND, ERROR_INVALID_PATH,
+                     ERROR_NULL_POINTER, SUCCESS, c_str_to_path, is_cancelled};
+
+/// Hash file contents
+pub const TREE_DIGEST_MODE_CONTENT: i32 = 0;
+/// Hash file size and modification time only
+pub const TREE_DIGEST_MODE_FAST: i32 = 1;
+
+pub use crate::errors::ERROR_INVALID_DIGEST_MODE;
+
+const KIND_FILE: u8 = b'F';
+const KIND_DIRECTORY: u8 = b'D';
+const KIND_SYMLINK: u8 = b'L';
+const KIND_UNREADABLE: u8 = b'E';
+
+type Digest = [u8; FINGERPRINT_SIZE];
+
+/// Entry that could not be read while computing a tree digest
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TreeDigestIssue {
+    /// Path relative to the root, '/'-separated
+    pub path: String,
+    /// Error message
+    pub error: String,
+}
+
+/// Full result of a tree digest computation
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TreeDigestResult {
+    /// Hex digest of the root directory
+    pub root: String,
+    /// Hex digest of every directory, keyed by relative path ("" is the root)
+    pub directories: BTreeMap<String, String>,
+    /// Symlinks recorded by target instead of being followed
+    pub symlinks: Vec<String>,
+    /// Entries that could not be read
+    pub unreadable: Vec<TreeDigestIssue>,
+    /// Number of regular files digested
+    pub file_count: u64,
+    /// Combined size of regular files in bytes
+    pub total_bytes: u64,
+}
+
+/// Tree snapshot taken before hashing, so progress has totals up front
+enum TreeNode {
+    File { size: u64, mtime_ns: u128 },
+    Symlink { target: String },
+    Directory { children: Vec<(String, TreeNode)> },
+    Unreadable { error: String },
+}
+
+fn join_relative(parent: &str, name: &str) -> String {
+    if parent.is_empty() { name.to_string() } else { format!("{}/{}", parent, name) }
+}
+
+/// Walk a directory without following symlinks, children sorted by name bytes
+fn snapshot_directory(
+    path: &Path,
+    cancel_flag: *const AtomicBool,
+    files: &mut u64,
+    bytes: &mut u64,
+) -> Result<TreeNode, i32> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => return Ok(TreeNode::Unreadable { error: e.to_string() }),
+    };
+
+    let mut children = Vec::new();
+    for entry in entries {
+        if unsafe { is_cancelled(cancel_flag) } {
+            return Err(ERROR_CANCELLED);
+        }
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let node = match entry.file_type() {
+            Ok(kind) if kind.is_symlink() => match fs::read_link(entry.path()) {
+                Ok(target) => TreeNode::Symlink { target: target.to_string_lossy().into_owned() },
+                Err(e) => TreeNode::Unreadable { error: e.to_string() },
+            },
+            Ok(kind) if kind.is_dir() => snapshot_directory(&entry.path(), cancel_flag, files, bytes)?,
+            Ok(_) => match entry.metadata() {
+                Ok(metadata) => {
+                    let mtime_ns = metadata.modified().ok()
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_nanos())
+                        .unwrap_or(0);
+                    *files += 1;
+                    *bytes += metadata.len();
+                    TreeNode::File { size: metadata.len(), mtime_ns }
+                }
+                Err(e) => TreeNode::Unreadable { error: e.to_string() },
+            },
+            Err(e) => TreeNode::Unreadable { error: e.to_string() },
+        };
+        children.push((name, node));
+    }
+
+    children.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+    Ok(TreeNode::Directory { children })
+}
+
+struct DigestState {
+    mode: i32,
+    cancel_flag: *const AtomicBool,
+    progress_callback: Option<CopyProgressCallback>,
+    user_data: *mut c_void,
+    throttler: ProgressThrottler,
+    bytes_done: u64,
+    files_done: u64,
+    total_bytes: u64,
+    total_files: u64,
+    result: TreeDigestResult,
+}
+
+impl DigestState {
+    fn report(&mut self) {
+        if let Some(callback) = self.progress_callback {
+            if self.throttler.should_update(self.bytes_done as usize, self.total_bytes as usize) {
+                callback(self.bytes_done as usize, self.total_bytes as usize,
+                         self.files_done as usize, self.total_files as usize, self.user_data);
+            }
+        }
+    }
+}
+
+fn marker_digest(kind: u8, payload: &[u8]) -> Digest {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[kind]);
+    hasher.update(payload);
+    *hasher.finalize().as_bytes()
+}
+
+/// Digest a node; returns the child kind recorded in the parent and the digest
+fn digest_node(
+    node: &TreeNode,
+    full_path: &Path,
+    relative_path: &str,
+    state: &mut DigestState,
+) -> Result<(u8, Digest), i32> {
+    if unsafe { is_cancelled(state.cancel_flag) } {
+        return Err(ERROR_CANCELLED);
+    }
+
+    match node {
+        TreeNode::File { size, mtime_ns } => {
+            let digest = if state.mode == TREE_DIGEST_MODE_FAST {
+                let mut payload = size.to_le_bytes().to_vec();
+                payload.extend_from_slice(&mtime_ns.to_le_bytes());
+                marker_digest(KIND_FILE, &payload)
+            } else {
+                match hash_file_impl(full_path, false, state.cancel_flag) {
+                    Ok(hash) => hash,
+                    Err(ERROR_CANCELLED) => return Err(ERROR_CANCELLED),
+                    Err(e) => {
+                        state.result.unreadable.push(TreeDigestIssue {
+                            path: relative_path.to_string(),
+                            error: format!("read failed ({})", e),
+                        });
+                        return Ok((KIND_UNREADABLE, marker_digest(KIND_UNREADABLE, &[])));
+                    }
+                }
+            };
+            state.files_done += 1;
+            state.bytes_done += size;
+            state.result.file_count += 1;
+            state.result.total_bytes += size;
+            state.report();
+            Ok((KIND_FILE, digest))
+        }
+        TreeNode::Symlink { target } => {
+            state.result.symlinks.push(relative_path.to_string());
+            Ok((KIND_SYMLINK, marker_digest(KIND_SYMLINK, target.as_bytes())))
+        }
+        TreeNode::Unreadable { error } => {
+            state.result.unreadable.push(TreeDigestIssue {
+                path: relative_path.to_string(),
+                error: error.clone(),
+            });
+            Ok((KIND_UNREADABLE, marker_digest(KIND_UNREADABLE, &[])))
+        }
+        TreeNode::Directory { children } => {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(&[KIND_DIRECTORY]);
+            for (name, child) in children {
+                let child_relative = join_relative(relative_path, name);
+                let (kind, digest) = digest_node(child, &full_path.join(name), &child_relative, state)?;
+                hasher.update(&[kind]);
+                hasher.update(&(name.len() as u64).to_le_bytes());
+                hasher.update(name.as_bytes());
+                hasher.update(&digest);
+            }
+            let digest = *hasher.finalize().as_bytes();
+            state.result.directories.insert(relative_path.to_string(), to_hex(&digest));
+            Ok((KIND_DIRECTORY, digest))
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compute the digest of a folder tree
+pub fn folder_tree_digest_impl(
+    root: &Path,
+    mode: i32,
+    cancel_flag: *const AtomicBool,
+    progress_callback: Option<CopyProgressCallback>,
+    user_data: *mut c_void,
+) -> Result<TreeDigestResult, i32> {
+    if mode != TREE_DIGEST_MODE_CONTENT && mode != TREE_DIGEST_MODE_FAST {
+        return Err(ERROR_INVALID_DIGEST_MODE);
+    }
+
+    let metadata = fs::metadata(root).map_err(|_| ERROR_FILE_NOT_FOUND)?;
+    if !metadata.is_dir() {
+        return Err(ERROR_INVALID_PATH);
+    }
+
+    let mut total_files = 0u64;
+    let mut total_bytes = 0u64;
+    let tree = snapshot_directory(root, cancel_flag, &mut total_files, &mut total_bytes)?;
+    if let TreeNode::Unreadable { .. } = tree {
+        return Err(ERROR_FILE_NOT_FOUND);
+    }
+
+    let mut state = DigestState {
+        mode,
+        cancel_flag,
+        progress_callback,
+        user_data,
+        throttler: ProgressThrottler::new(500),
+        bytes_done: 0,
+        files_done: 0,
+        total_bytes,
+        total_files,
+        result: TreeDigestResult::default(),
+    };
+
+    let (_, digest) = digest_node(&tree, root, "", &mut state)?;
+
+    if let Some(callback) = progress_callback {
+        if state.throttler.finish() {
+            callback(state.bytes_done as usize, state.total_bytes as usize,
+                     state.files_done as usize, state.total_files as usize, user_data);
+        }
+    }
+
+    let mut result = state.result;
+    result.root = to_hex(&digest);
+    Ok(result)
+}
+
+fn into_c_string(value: String) -> *mut c_char {
+    CString::new(value).map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+}
+
+/// Compute a Merkle-style digest of a local folder tree
+///
+/// # Arguments
+/// * `path` - Path to the root folder
+/// * `mode` - TREE_DIGEST_MODE_CONTENT (0) or TREE_DIGEST_MODE_FAST (1)
+/// * `cancel_flag` - Pointer to atomic bool for cancellation (can be null)
+/// * `progress_callback` - Progress callback (bytes, total bytes, files, total files, user_data) (can be null)
+/// * `user_data` - User data passed to the callback
+/// * `out_hex` - Receives the root digest as hex (caller must free with scan_folder_free_string)
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[no_mangle]
+pub extern "C" fn folder_tree_digest(
+    path: *const c_char,
+    mode: i32,
+    cancel_flag: *const AtomicBool,
+    progress_callback: Option<CopyProgressCallback>,
+    user_data: *mut c_void,
+    out_hex: *mut *mut c_char,
+) -> i32 {
+    folder_tree_digest_ex(path, mode, cancel_flag, progress_callback, user_data, out_hex, std::ptr::null_mut())
+}
+
+/// Compute a folder tree digest, optionally returning the per-directory digest map
+///
+/// The JSON is a TreeDigestResult: `root`, `directories` (relative path -> hex
+/// digest, "" for the root), `symlinks`, `unreadable`, `file_count` and `total_bytes`.
+///
+/// # Arguments
+/// * `path` - Path to the root folder
+/// * `mode` - TREE_DIGEST_MODE_CONTENT (0) or TREE_DIGEST_MODE_FAST (1)
+/// * `cancel_flag` - Pointer to atomic bool for cancellation (can be null)
+/// * `progress_callback` - Progress callback (can be null)
+/// * `user_data` - User data passed to the callback
+/// * `out_hex` - Receives the root digest as hex (can be null)
+/// * `out_json` - Receives the full result as JSON (can be null)
+///
+/// # Returns
+/// 0 on success, error code on failure. Strings must be freed with scan_folder_free_string.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn folder_tree_digest_ex(
+    path: *const c_char,
+    mode: i32,
+    cancel_flag: *const AtomicBool,
+    progress_callback: Option<CopyProgressCallback>,
+    user_data: *mut c_void,
+    out_hex: *mut *mut c_char,
+    out_json: *mut *mut c_char,
+) -> i32 {
+    if path.is_null() || (out_hex.is_null() && out_json.is_null()) {
+        return ERROR_NULL_POINTER;
+    }
+
+    let root = match unsafe { c_str_to_path(path) } {
+        Ok(p) => p,
+        Err(e) => return checked(e),
+    };
+
+    let result = match folder_tree_digest_impl(&root, mode, cancel_flag, progress_callback, user_data) {
+        Ok(result) => result,
+        Err(e) => return checked(e),
+    };
+
+    unsafe {
+        if !out_hex.is_null() {
+            *out_hex = into_c_string(result.root.clone());
+        }
+        if !out_json.is_null() {
+            *out_json = into_c_string(serde_json::to_string(&result).unwrap_or_else(|_| "{}".to_string()));
+        }
+    }
+
+    SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::time::{Duration, SystemTime};
+
+    use crate::scan::scan_folder_free_string;
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        let root = std::env::temp_dir().join(format!("cloud_nexus_tree_digest_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs/drafts")).unwrap();
+        fs::write(root.join("docs/readme.md"), b"hello").unwrap();
+        fs::write(root.join("docs/drafts/plan.txt"), b"plan v1").unwrap();
+        fs::write(root.join("top.bin"), vec![7u8; 4096]).unwrap();
+        root
+    }
+
+    fn set_mtime(path: &Path, time: SystemTime) {
+        fs::File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    fn digest(root: &Path, mode: i32) -> TreeDigestResult {
+        folder_tree_digest_impl(root, mode, std::ptr::null(), None, std::ptr::null_mut()).unwrap()
+    }
+
+    #[test]
+    fn test_identical_trees_and_renames() {
+        let a = fixture("a");
+        let b = fixture("b");
+        let epoch = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for root in [&a, &b] {
+            for file in ["docs/readme.md", "docs/drafts/plan.txt", "top.bin"] {
+                set_mtime(&root.join(file), epoch);
+            }
+        }
+
+        for mode in [TREE_DIGEST_MODE_CONTENT, TREE_DIGEST_MODE_FAST] {
+            let (da, db) = (digest(&a, mode), digest(&b, mode));
+            assert_eq!(da.root, db.root);
+            assert_eq!(da.directories, db.directories);
+            assert_eq!(da.file_count, 3);
+        }
+
+        let before = digest(&b, TREE_DIGEST_MODE_CONTENT);
+        fs::rename(b.join("docs/drafts/plan.txt"), b.join("docs/drafts/plan-renamed.txt")).unwrap();
+        let after = digest(&b, TREE_DIGEST_MODE_CONTENT);
+        assert_ne!(before.root, after.root);
+        // Only the directories on the path to the rename change
+        assert_ne!(before.directories["docs/drafts"], after.directories["docs/drafts"]);
+        assert_ne!(before.directories["docs"], after.directories["docs"]);
+        assert_eq!(digest(&a, TREE_DIGEST_MODE_CONTENT).directories.len(), after.directories.len());
+
+        let _ = fs::remove_dir_all(&a);
+        let _ = fs::remove_dir_all(&b);
+    }
+
+    #[test]
+    fn test_mtime_only_changes_fast_digest() {
+        let root = fixture("mtime");
+        let file = root.join("docs/readme.md");
+        set_mtime(&file, UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let content_before = digest(&root, TREE_DIGEST_MODE_CONTENT).root;
+        let fast_before = digest(&root, TREE_DIGEST_MODE_FAST).root;
+
+        set_mtime(&file, UNIX_EPOCH + Duration::from_secs(1_650_000_000));
+        assert_eq!(digest(&root, TREE_DIGEST_MODE_CONTENT).root, content_before);
+        assert_ne!(digest(&root, TREE_DIGEST_MODE_FAST).root, fast_before);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_recorded_not_followed() {
+        let root = fixture("links");
+        std::os::unix::fs::symlink(root.join("docs"), root.join("docs-link")).unwrap();
+        std::os::unix::fs::symlink(root.join("missing"), root.join("dangling")).unwrap();
+
+        let path_c = CString::new(root.to_string_lossy().to_string()).unwrap();
+        let mut hex: *mut c_char = std::ptr::null_mut();
+        let mut json: *mut c_char = std::ptr::null_mut();
+        let status = folder_tree_digest_ex(
+            path_c.as_ptr(), TREE_DIGEST_MODE_CONTENT, std::ptr::null(), None, std::ptr::null_mut(), &mut hex, &mut json,
+        );
+        assert_eq!(status, SUCCESS);
+        let hex_str = unsafe { CStr::from_ptr(hex) }.to_str().unwrap().to_string();
+        let result: TreeDigestResult = serde_json::from_str(unsafe { CStr::from_ptr(json) }.to_str().unwrap()).unwrap();
+        scan_folder_free_string(hex);
+        scan_folder_free_string(json);
+
+        assert_eq!(hex_str.len(), 64);
+        assert_eq!(result.root, hex_str);
+        assert_eq!(result.symlinks, vec!["dangling".to_string(), "docs-link".to_string()]);
+        assert_eq!(result.file_count, 3);
+        assert!(!result.directories.contains_key("docs-link"));
+
+        assert_eq!(
+            folder_tree_digest(path_c.as_ptr(), 7, std::ptr::null(), None, std::ptr::null_mut(), &mut hex),
+            ERROR_INVALID_DIGEST_MODE
+        );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
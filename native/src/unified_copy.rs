@@ -14,7 +14,14 @@
 
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::ffi::{c_char, c_void};
+use std::path::Path;
 use std::ptr;
+use std::sync::mpsc;
+
+use crate::errors::checked;
+use crate::file_io::{ERROR_CANCELLED, ERROR_IO_FAILED, ERROR_NULL_POINTER, SUCCESS};
+use crate::governor::TransferPacer;
+use crate::operations::{Operation, OperationKind};
 
 /// Progress callback type for copy operations
 /// Parameters: bytes_copied, total_bytes, files_processed, total_files, user_data
@@ -44,10 +51,20 @@ pub type UnifiedWriteCallback = extern "C" fn(
     user_data: *mut c_void,    // User data
 ) -> i32;
 
-/// Error codes
-const SUCCESS: i32 = 0;
-const ERROR_NULL_POINTER: i32 = -1;
-const ERROR_CANCELLED: i32 = -10;
+/// Write callback for destinations that may accept part of a chunk
+/// (e.g. a resumable upload committing a shorter Range than was sent)
+/// Returns: number of bytes accepted from the start of data, negative on error
+pub type UnifiedWriteCallbackV2 = extern "C" fn(
+    data: *const u8,           // Pointer to chunk data in RAM
+    data_len: usize,           // Length of data
+    offset: u64,               // File offset to write to
+    user_data: *mut c_void,    // User data
+) -> isize;
+
+pub use crate::errors::ERROR_WRITE_STALLED;
+
+/// Consecutive writes accepting nothing before a copy fails with ERROR_WRITE_STALLED
+const MAX_STALLED_WRITES: u32 = 8;
 
 /// Unified copy context - works for ANY source/destination combination
 #[repr(C)]
@@ -66,6 +83,16 @@ pub struct UnifiedCopyContext {
     cancel_flag: *const AtomicBool,
     /// Current file offset
     file_offset: u64,
+    /// Speed governor and rate limit pacing
+    pacer: TransferPacer,
+    /// Read the next chunk while the current one is written (see unified_copy_init_ex)
+    pipeline: bool,
+    /// The read and write callbacks may run at the same time on different threads
+    callbacks_thread_safe: bool,
+    /// Second chunk buffer of a pipelined copy, allocated on first use
+    prefetch_buffer: Vec<u8>,
+    /// Entry in the active operation registry
+    operation: Operation,
 }
 
 impl UnifiedCopyContext {
@@ -76,14 +103,21 @@ impl UnifiedCopyContext {
         chunk_size: usize,
         cancel_flag: *const AtomicBool,
     ) -> Self {
+        let operation = Operation::register(OperationKind::UnifiedCopy, Path::new(""), Path::new(""),
+                                            total_bytes, total_files as u64, cancel_flag);
         Self {
             total_bytes,
             bytes_copied: 0,
             chunk_size,
             files_processed: 0,
             total_files,
-            cancel_flag,
+            cancel_flag: operation.cancel_flag(),
             file_offset: 0,
+            pacer: TransferPacer::new(),
+            pipeline: false,
+            callbacks_thread_safe: false,
+            prefetch_buffer: Vec::new(),
+            operation,
         }
     }
     
@@ -94,6 +128,53 @@ impl UnifiedCopyContext {
         }
         unsafe { (*self.cancel_flag).load(Ordering::SeqCst) }
     }
+
+    /// Whether files are copied with read-ahead
+    ///
+    /// Copies running on a runtime pool worker stay serial, as waiting there
+    /// for the prefetch task could starve the pool.
+    fn is_pipelined(&self) -> bool {
+        self.pipeline && self.callbacks_thread_safe && !crate::runtime::on_worker_thread()
+    }
+
+    /// Count bytes the destination accepted and report progress
+    ///
+    /// Returns false if the copy was cancelled while pacing.
+    fn record_accepted(&mut self, accepted: usize, progress_callback: Option<UnifiedProgressCallback>,
+                       user_data: *mut c_void) -> bool {
+        self.bytes_copied += accepted as u64;
+        self.operation.set_bytes_done(self.bytes_copied);
+
+        // Slow down under thermal/battery pressure or an explicit rate limit
+        if !self.pacer.pace(accepted, self.cancel_flag) {
+            return false;
+        }
+
+        // Progress callback (throttled by Dart if needed)
+        if let Some(cb) = progress_callback {
+            cb(
+                self.bytes_copied,
+                self.total_bytes,
+                self.files_processed + 1,
+                self.total_files,
+                user_data,
+            );
+        }
+        true
+    }
+
+    /// Count a copied file; returns 1 if more files are to be copied, 0 if done
+    fn finish_file(&mut self) -> i32 {
+        self.files_processed += 1;
+        self.operation.set_files_done(self.files_processed as u64);
+        self.file_offset = 0;
+
+        if self.files_processed < self.total_files {
+            1
+        } else {
+            0
+        }
+    }
 }
 
 /// Initialize unified copy context
@@ -127,6 +208,45 @@ pub extern "C" fn unified_copy_init(
     Box::leak(context) as *mut UnifiedCopyContext
 }
 
+/// Initialize unified copy context, optionally reading ahead while writing
+///
+/// With `pipeline`, a second chunk buffer is allocated internally and the read
+/// callback for the next chunk runs on a runtime pool worker while the write
+/// callback for the current chunk runs on the calling thread, so a file takes
+/// about max(read, write) rather than read + write. Writes stay in order and
+/// progress still counts only written bytes. This needs callbacks that may
+/// run at the same time on different threads: unless `callbacks_thread_safe`
+/// is set, or when called from a runtime pool worker, files are copied
+/// serially as with unified_copy_init.
+///
+/// # Arguments
+/// * `total_bytes` - Total bytes to copy across all files
+/// * `total_files` - Total number of files to copy
+/// * `chunk_size` - Size of chunks in bytes (64KB minimum, 10MB maximum)
+/// * `cancel_flag` - Pointer to AtomicBool for cancellation (can be null)
+/// * `pipeline` - 1 to read the next chunk while writing the current one
+/// * `callbacks_thread_safe` - 1 if the read and write callbacks may run concurrently
+///
+/// # Returns
+/// Pointer to UnifiedCopyContext, or null on error
+#[no_mangle]
+pub extern "C" fn unified_copy_init_ex(
+    total_bytes: u64,
+    total_files: u32,
+    chunk_size: usize,
+    cancel_flag: *const AtomicBool,
+    pipeline: u8,
+    callbacks_thread_safe: u8,
+) -> *mut UnifiedCopyContext {
+    let context = unified_copy_init(total_bytes, total_files, chunk_size, cancel_flag);
+    if !context.is_null() {
+        let ctx = unsafe { &mut *context };
+        ctx.pipeline = pipeline != 0;
+        ctx.callbacks_thread_safe = callbacks_thread_safe != 0;
+    }
+    context
+}
+
 /// Process one file copy operation
 ///
 /// This function orchestrates the download→upload→clear loop:
@@ -136,6 +256,9 @@ pub extern "C" fn unified_copy_init(
 /// 4. Clear RAM buffer (automatic - buffer reused for next chunk)
 /// 5. Repeat until EOF
 ///
+/// The write callback's success means the whole chunk was accepted; use
+/// unified_copy_file_v2 for destinations that can accept part of a chunk.
+///
 /// # Arguments
 /// * `context` - Pointer to UnifiedCopyContext
 /// * `read_buffer` - Pre-allocated RAM buffer for chunk data
@@ -159,6 +282,76 @@ pub extern "C" fn unified_copy_file(
     progress_callback: Option<UnifiedProgressCallback>,
     user_data: *mut c_void,
 ) -> i32 {
+    let write_cb = match write_callback {
+        Some(cb) => cb,
+        None => return ERROR_NULL_POINTER,
+    };
+
+    copy_file_with(context, read_buffer, buffer_size, file_size, read_callback, progress_callback, user_data,
+                   |data, data_len, offset| {
+        let result = write_cb(data, data_len, offset, user_data);
+        if result < 0 { result as isize } else { data_len as isize }
+    })
+}
+
+/// Process one file copy operation, advancing only by the bytes the destination accepted
+///
+/// Like unified_copy_file, but the write callback returns how many bytes it
+/// accepted (e.g. the committed Range of a resumable upload). The unaccepted
+/// tail is kept at the start of the buffer and re-sent at the next offset,
+/// together with newly read data, so the destination receives one contiguous
+/// stream. Progress never counts bytes that were not accepted. Fails with
+/// ERROR_WRITE_STALLED if the destination accepts nothing several times in a row.
+///
+/// # Arguments
+/// * `context` - Pointer to UnifiedCopyContext
+/// * `read_buffer` - Pre-allocated RAM buffer for chunk data
+/// * `buffer_size` - Size of the buffer (should match chunk_size)
+/// * `file_size` - Size of the file being copied
+/// * `read_callback` - Callback to download chunk from source
+/// * `write_callback` - Callback to upload chunk to destination, returning bytes accepted
+/// * `progress_callback` - Optional progress callback
+/// * `user_data` - User data for callbacks
+///
+/// # Returns
+/// 1 if more files to process, 0 if done, negative error code on failure
+#[no_mangle]
+pub extern "C" fn unified_copy_file_v2(
+    context: *mut UnifiedCopyContext,
+    read_buffer: *mut u8,
+    buffer_size: usize,
+    file_size: u64,
+    read_callback: Option<UnifiedReadCallback>,
+    write_callback: Option<UnifiedWriteCallbackV2>,
+    progress_callback: Option<UnifiedProgressCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    let write_cb = match write_callback {
+        Some(cb) => cb,
+        None => return ERROR_NULL_POINTER,
+    };
+
+    checked(copy_file_with(context, read_buffer, buffer_size, file_size, read_callback, progress_callback, user_data,
+                           |data, data_len, offset| write_cb(data, data_len, offset, user_data)))
+}
+
+/// Copy loop shared by unified_copy_file and unified_copy_file_v2
+///
+/// `write` returns the number of bytes accepted, negative on error.
+#[allow(clippy::too_many_arguments)]
+fn copy_file_with<W>(
+    context: *mut UnifiedCopyContext,
+    read_buffer: *mut u8,
+    buffer_size: usize,
+    file_size: u64,
+    read_callback: Option<UnifiedReadCallback>,
+    progress_callback: Option<UnifiedProgressCallback>,
+    user_data: *mut c_void,
+    mut write: W,
+) -> i32
+where
+    W: FnMut(*const u8, usize, u64) -> isize,
+{
     // Validate inputs
     if context.is_null() {
         return ERROR_NULL_POINTER;
@@ -176,14 +369,23 @@ pub extern "C" fn unified_copy_file(
         None => return ERROR_NULL_POINTER,
     };
     
-    let write_cb = match write_callback {
-        Some(cb) => cb,
-        None => return ERROR_NULL_POINTER,
-    };
-    
     // Initialize file offset
     let mut file_offset = 0u64;
     let mut bytes_copied_this_file = 0u64;
+    ctx.pacer.reset();
+
+    // Bytes at the start of the buffer that were read but not yet accepted
+    let mut pending = 0usize;
+    let mut stalled_writes = 0u32;
+    let window = ctx.chunk_size.min(buffer_size);
+
+    if ctx.is_pipelined() {
+        return match copy_chunks_pipelined(ctx, read_buffer, window, file_size, read_cb, progress_callback,
+                                           user_data, &mut write) {
+            Ok(()) => ctx.finish_file(),
+            Err(code) => code,
+        };
+    }
     
     // Download → Upload → Clear loop
     // This loop processes the file in chunks, keeping memory usage constant
@@ -193,80 +395,201 @@ pub extern "C" fn unified_copy_file(
             return ERROR_CANCELLED;
         }
         
-        // Calculate bytes to read for this chunk
-        let bytes_to_read = ((file_size - bytes_copied_this_file) as usize)
-            .min(ctx.chunk_size)
-            .min(buffer_size);
+        // Calculate bytes to read for this chunk, after any unaccepted tail
+        let read_offset = file_offset + pending as u64;
+        let bytes_to_read = (file_size.saturating_sub(read_offset) as usize).min(window - pending);
         
         // === STEP 1: Download chunk from source into RAM ===
         // Dart reads from cloud API (e.g., GET with Range header)
         // The buffer is filled with downloaded data
-        let bytes_read = read_cb(
-            read_buffer,
-            bytes_to_read,
-            file_offset,
-            user_data,
-        );
-        
-        if bytes_read < 0 {
-            // Error from read callback
-            return bytes_read as i32;
+        if bytes_to_read > 0 {
+            let bytes_read = read_cb(
+                unsafe { read_buffer.add(pending) },
+                bytes_to_read,
+                read_offset,
+                user_data,
+            );
+            
+            if bytes_read < 0 {
+                // Error from read callback
+                return bytes_read as i32;
+            }
+            pending += (bytes_read as usize).min(bytes_to_read);
         }
         
-        if bytes_read == 0 {
+        if pending == 0 {
             // EOF - file copy complete
             break;
         }
         
         // === CHUNK NOW IN RAM ===
-        // read_buffer contains [bytes_read] bytes of data
-        // This is the only time the buffer contains data
+        // read_buffer contains [pending] bytes of data
         
         // === STEP 2: Upload chunk from RAM to destination ===
         // Dart uploads to cloud API (e.g., PATCH with Content-Range)
-        let write_result = write_cb(
-            read_buffer,
-            bytes_read as usize,
-            file_offset,
-            user_data,
-        );
+        let accepted = write(read_buffer, pending, file_offset);
         
-        if write_result < 0 {
+        if accepted < 0 {
             // Error from write callback
-            return write_result;
+            return accepted as i32;
+        }
+        let accepted = accepted as usize;
+        if accepted > pending {
+            return ERROR_IO_FAILED;
+        }
+        
+        if accepted == 0 {
+            stalled_writes += 1;
+            if stalled_writes >= MAX_STALLED_WRITES {
+                return ERROR_WRITE_STALLED;
+            }
+            continue;
         }
+        stalled_writes = 0;
         
         // === STEP 3: Clear RAM buffer (automatic) ===
-        // The buffer will be overwritten in the next iteration
-        // No explicit clear needed - this is the key memory optimization
+        // The buffer will be overwritten in the next iteration; an unaccepted
+        // tail moves to the front so it is re-sent first
+        pending -= accepted;
+        if pending > 0 {
+            unsafe { ptr::copy(read_buffer.add(accepted), read_buffer, pending); }
+        }
         
         // Update progress
-        file_offset += bytes_read as u64;
-        bytes_copied_this_file += bytes_read as u64;
-        ctx.bytes_copied += bytes_read as u64;
-        
-        // Progress callback (throttled by Dart if needed)
-        if let Some(cb) = progress_callback {
-            cb(
-                ctx.bytes_copied,
-                ctx.total_bytes,
-                ctx.files_processed + 1,
-                ctx.total_files,
-                user_data,
-            );
+        file_offset += accepted as u64;
+        bytes_copied_this_file += accepted as u64;
+        if !ctx.record_accepted(accepted, progress_callback, user_data) {
+            return ERROR_CANCELLED;
         }
     }
     
-    // Mark file as processed
-    ctx.files_processed += 1;
-    ctx.file_offset = 0;
-    
-    // Return 1 if more files to copy, 0 if done
-    if ctx.files_processed < ctx.total_files {
-        1
-    } else {
-        0
+    // Mark file as processed; 1 if more files to copy, 0 if done
+    ctx.finish_file()
+}
+
+/// A read of the next chunk, running on the runtime pool or deferred
+enum Prefetch {
+    Running(mpsc::Receiver<isize>),
+    /// The pool could not take the read; it runs after the current write
+    Deferred,
+}
+
+/// Read `len` bytes at `offset` into `buffer` on the runtime pool
+fn start_prefetch(read_cb: UnifiedReadCallback, buffer: *mut u8, len: usize, offset: u64,
+                  user_data: *mut c_void) -> Prefetch {
+    let (sender, receiver) = mpsc::channel();
+    let (buffer_addr, user_data_addr) = (buffer as usize, user_data as usize);
+    let task = move || {
+        let _ = sender.send(read_cb(buffer_addr as *mut u8, len, offset, user_data_addr as *mut c_void));
+    };
+    match crate::runtime::spawn(task) {
+        Ok(()) => Prefetch::Running(receiver),
+        Err(_) => Prefetch::Deferred,
+    }
+}
+
+/// Copy loop of a pipelined copy: chunk N+1 is read into the other buffer
+/// while chunk N is written
+///
+/// Every started read is waited for before returning, so no read is still
+/// filling a buffer when the caller gets its buffer back. A partly accepted
+/// chunk has its tail re-sent before the next chunk.
+#[allow(clippy::too_many_arguments)]
+fn copy_chunks_pipelined<W>(
+    ctx: &mut UnifiedCopyContext,
+    read_buffer: *mut u8,
+    window: usize,
+    file_size: u64,
+    read_cb: UnifiedReadCallback,
+    progress_callback: Option<UnifiedProgressCallback>,
+    user_data: *mut c_void,
+    write: &mut W,
+) -> Result<(), i32>
+where
+    W: FnMut(*const u8, usize, u64) -> isize,
+{
+    if ctx.prefetch_buffer.len() < window {
+        ctx.prefetch_buffer.resize(window, 0);
+    }
+    let buffers = [read_buffer, ctx.prefetch_buffer.as_mut_ptr()];
+    let read_len = |offset: u64| (file_size.saturating_sub(offset) as usize).min(window);
+    let checked = |bytes_read: isize, requested: usize| {
+        if bytes_read < 0 { Err(bytes_read as i32) } else { Ok((bytes_read as usize).min(requested)) }
+    };
+
+    let mut current = 0;
+    let mut file_offset = 0u64;
+    let first = read_len(0);
+    let mut chunk_len = if first > 0 { checked(read_cb(buffers[0], first, 0, user_data), first)? } else { 0 };
+
+    while chunk_len > 0 {
+        if ctx.is_cancelled() {
+            return Err(ERROR_CANCELLED);
+        }
+
+        // Read ahead into the other buffer while this chunk is written
+        let next_offset = file_offset + chunk_len as u64;
+        let next_len = read_len(next_offset);
+        let next = buffers[1 - current];
+        let prefetch = (next_len > 0).then(|| start_prefetch(read_cb, next, next_len, next_offset, user_data));
+
+        let written = write_chunk_fully(ctx, buffers[current], chunk_len, file_offset, progress_callback,
+                                        user_data, write);
+
+        let next_read = match prefetch {
+            Some(Prefetch::Running(receiver)) => receiver.recv().map_or(Err(ERROR_IO_FAILED), |n| checked(n, next_len)),
+            Some(Prefetch::Deferred) if written.is_ok() => checked(read_cb(next, next_len, next_offset, user_data), next_len),
+            _ => Ok(0),
+        };
+        written?;
+
+        file_offset = next_offset;
+        chunk_len = next_read?;
+        current = 1 - current;
+    }
+    Ok(())
+}
+
+/// Write one chunk, re-sending any unaccepted tail until all of it is accepted
+#[allow(clippy::too_many_arguments)]
+fn write_chunk_fully<W>(
+    ctx: &mut UnifiedCopyContext,
+    chunk: *const u8,
+    len: usize,
+    offset: u64,
+    progress_callback: Option<UnifiedProgressCallback>,
+    user_data: *mut c_void,
+    write: &mut W,
+) -> Result<(), i32>
+where
+    W: FnMut(*const u8, usize, u64) -> isize,
+{
+    let mut done = 0usize;
+    let mut stalled_writes = 0u32;
+    while done < len {
+        let accepted = write(unsafe { chunk.add(done) }, len - done, offset + done as u64);
+        if accepted < 0 {
+            return Err(accepted as i32);
+        }
+        let accepted = accepted as usize;
+        if accepted > len - done {
+            return Err(ERROR_IO_FAILED);
+        }
+        if accepted == 0 {
+            stalled_writes += 1;
+            if stalled_writes >= MAX_STALLED_WRITES {
+                return Err(ERROR_WRITE_STALLED);
+            }
+            continue;
+        }
+        stalled_writes = 0;
+
+        done += accepted;
+        if !ctx.record_accepted(accepted, progress_callback, user_data) {
+            return Err(ERROR_CANCELLED);
+        }
     }
+    Ok(())
 }
 
 /// Finalize copy operation and send final progress update
@@ -300,10 +623,56 @@ pub extern "C" fn unified_copy_finalize(
             user_data,
         );
     }
+    ctx.operation.complete();
     
     SUCCESS
 }
 
+/// Limit the copy speed of this context
+///
+/// Combined with the speed governor (see set_speed_governor), the lower speed wins.
+///
+/// # Arguments
+/// * `context` - Pointer to UnifiedCopyContext
+/// * `bytes_per_sec` - Maximum speed in bytes per second (0 for unlimited)
+///
+/// # Returns
+/// 0 on success, error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn unified_copy_set_rate_limit(context: *mut UnifiedCopyContext, bytes_per_sec: u64) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    unsafe { (*context).pacer.set_rate_limit(bytes_per_sec); }
+    SUCCESS
+}
+
+/// Report this unified copy's progress into the polled event stream
+///
+/// Progress records go to drain_progress_events_json in addition to the
+/// progress callback (pass no callback to use the stream instead). unified_copy_finalize
+/// pushes the terminal "completed" record; freeing an unfinished unified copy pushes
+/// "failed" or "cancelled".
+///
+/// # Arguments
+/// * `context` - Pointer to UnifiedCopyContext
+/// * `use_event_stream` - 1 to push progress events, 0 to stop
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn unified_copy_set_use_event_stream(context: *mut UnifiedCopyContext, use_event_stream: u8) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+
+    unsafe { (*context).operation.set_use_event_stream(use_event_stream != 0); }
+    SUCCESS
+}
+
 /// Free unified copy context
 ///
 /// # Arguments
@@ -412,4 +781,214 @@ pub extern "C" fn unified_copy_get_total_files(context: *mut UnifiedCopyContext)
         return 0;
     }
     unsafe { (&*context).total_files }
-}
\ No newline at end of file
+}
+
+/// Get the active operation id of a unified copy
+///
+/// # Arguments
+/// * `context` - Pointer to UnifiedCopyContext
+///
+/// # Returns
+/// Operation id for get_operation_progress and cancel_operation, or 0 if invalid context
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn unified_copy_get_operation_id(context: *mut UnifiedCopyContext) -> u64 {
+    if context.is_null() {
+        return 0;
+    }
+    unsafe { (&*context).operation.id() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Source and destination shared with the test callbacks through user_data
+    struct Transfer {
+        source: Vec<u8>,
+        dest: Vec<u8>,
+        /// Bytes reported by each progress callback, with the destination length at the time
+        progress: Vec<(u64, usize)>,
+    }
+
+    // Callbacks borrow only the field they use, as pipelined reads and writes run concurrently
+    extern "C" fn read_source(buffer: *mut u8, buffer_size: usize, offset: u64, user_data: *mut c_void) -> isize {
+        let source = unsafe { &(*(user_data as *const Transfer)).source };
+        let start = (offset as usize).min(source.len());
+        let chunk = &source[start..(start + buffer_size).min(source.len())];
+        unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), buffer, chunk.len()); }
+        chunk.len() as isize
+    }
+
+    /// Accepts the first half of every write (at least one byte)
+    extern "C" fn write_half(data: *const u8, data_len: usize, offset: u64, user_data: *mut c_void) -> isize {
+        let dest = unsafe { &mut (*(user_data as *mut Transfer)).dest };
+        assert_eq!(offset as usize, dest.len(), "writes must be contiguous");
+        let accepted = (data_len / 2).max(1);
+        dest.extend_from_slice(unsafe { std::slice::from_raw_parts(data, accepted) });
+        accepted as isize
+    }
+
+    extern "C" fn write_all(data: *const u8, data_len: usize, offset: u64, user_data: *mut c_void) -> i32 {
+        let dest = unsafe { &mut (*(user_data as *mut Transfer)).dest };
+        assert_eq!(offset as usize, dest.len());
+        dest.extend_from_slice(unsafe { std::slice::from_raw_parts(data, data_len) });
+        0
+    }
+
+    extern "C" fn write_nothing(_data: *const u8, _data_len: usize, _offset: u64, _user_data: *mut c_void) -> isize {
+        0
+    }
+
+    extern "C" fn record_progress(bytes: u64, _total: u64, _files: u32, _total_files: u32, user_data: *mut c_void) {
+        let transfer = unsafe { &mut *(user_data as *mut Transfer) };
+        transfer.progress.push((bytes, transfer.dest.len()));
+    }
+
+    fn transfer(size: usize) -> Transfer {
+        let source = (0..size).map(|i| (i % 251) as u8).collect();
+        Transfer { source, dest: Vec::new(), progress: Vec::new() }
+    }
+
+    #[test]
+    fn test_partial_writes_resend_tail() {
+        let size = 300_000;
+        let mut transfer = transfer(size);
+        let ctx = unified_copy_init(size as u64, 1, 64 * 1024, ptr::null());
+        let mut buffer = vec![0u8; 64 * 1024];
+        let user_data = &mut transfer as *mut Transfer as *mut c_void;
+
+        let result = unified_copy_file_v2(ctx, buffer.as_mut_ptr(), buffer.len(), size as u64,
+                                          Some(read_source), Some(write_half), Some(record_progress), user_data);
+        assert_eq!(result, 0);
+        assert_eq!(transfer.dest, transfer.source);
+        assert_eq!(unified_copy_get_bytes_copied(ctx), size as u64);
+
+        // Progress only ever reports accepted bytes
+        assert!(transfer.progress.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(transfer.progress.iter().all(|&(bytes, accepted)| bytes == accepted as u64));
+        assert_eq!(transfer.progress.last().unwrap().0, size as u64);
+        unified_copy_free(ctx);
+    }
+
+    #[test]
+    fn test_full_writes_and_stalled_destination() {
+        let size = 100_000;
+        let mut transfer = transfer(size);
+        let ctx = unified_copy_init(size as u64, 2, 64 * 1024, ptr::null());
+        let mut buffer = vec![0u8; 64 * 1024];
+        let user_data = &mut transfer as *mut Transfer as *mut c_void;
+
+        let result = unified_copy_file(ctx, buffer.as_mut_ptr(), buffer.len(), size as u64,
+                                       Some(read_source), Some(write_all), None, user_data);
+        assert_eq!(result, 1);
+        assert_eq!(transfer.dest, transfer.source);
+
+        // A destination that never accepts anything fails instead of looping forever
+        let result = unified_copy_file_v2(ctx, buffer.as_mut_ptr(), buffer.len(), size as u64,
+                                          Some(read_source), Some(write_nothing), None, user_data);
+        assert_eq!(result, ERROR_WRITE_STALLED);
+        assert_eq!(unified_copy_get_bytes_copied(ctx), size as u64);
+        unified_copy_free(ctx);
+    }
+
+    /// Transfer whose callbacks sleep and may run concurrently
+    struct SlowTransfer {
+        source: Vec<u8>,
+        dest: std::sync::Mutex<Vec<u8>>,
+        delay: std::time::Duration,
+        /// Bytes reported by the progress callback, with the destination length at the time
+        progress: std::sync::Mutex<Vec<(u64, usize)>>,
+    }
+
+    extern "C" fn read_slow(buffer: *mut u8, buffer_size: usize, offset: u64, user_data: *mut c_void) -> isize {
+        let transfer = unsafe { &*(user_data as *const SlowTransfer) };
+        std::thread::sleep(transfer.delay);
+        let start = (offset as usize).min(transfer.source.len());
+        let chunk = &transfer.source[start..(start + buffer_size).min(transfer.source.len())];
+        unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), buffer, chunk.len()); }
+        chunk.len() as isize
+    }
+
+    extern "C" fn write_slow(data: *const u8, data_len: usize, offset: u64, user_data: *mut c_void) -> i32 {
+        let transfer = unsafe { &*(user_data as *const SlowTransfer) };
+        std::thread::sleep(transfer.delay);
+        let mut dest = transfer.dest.lock().unwrap();
+        assert_eq!(offset as usize, dest.len());
+        dest.extend_from_slice(unsafe { std::slice::from_raw_parts(data, data_len) });
+        0
+    }
+
+    extern "C" fn record_slow_progress(bytes: u64, _total: u64, _files: u32, _total_files: u32, user_data: *mut c_void) {
+        let transfer = unsafe { &*(user_data as *const SlowTransfer) };
+        let written = transfer.dest.lock().unwrap().len();
+        transfer.progress.lock().unwrap().push((bytes, written));
+    }
+
+    #[test]
+    fn test_pipelined_copy_overlaps_read_and_write() {
+        let chunk = 64 * 1024;
+        let size = chunk * 10 + 1234;
+        let copy = |pipeline: u8, thread_safe: u8| {
+            let transfer = SlowTransfer {
+                source: (0..size).map(|i| (i % 253) as u8).collect(),
+                dest: std::sync::Mutex::new(Vec::new()),
+                delay: std::time::Duration::from_millis(25),
+                progress: std::sync::Mutex::new(Vec::new()),
+            };
+            let ctx = unified_copy_init_ex(size as u64, 1, chunk, ptr::null(), pipeline, thread_safe);
+            let mut buffer = vec![0u8; chunk];
+            let user_data = &transfer as *const SlowTransfer as *mut c_void;
+            let started = std::time::Instant::now();
+            let result = unified_copy_file(ctx, buffer.as_mut_ptr(), buffer.len(), size as u64,
+                                           Some(read_slow), Some(write_slow), Some(record_slow_progress), user_data);
+            let elapsed = started.elapsed();
+            assert_eq!(result, 0);
+            assert_eq!(unified_copy_get_bytes_copied(ctx), size as u64);
+            unified_copy_free(ctx);
+            (transfer, elapsed)
+        };
+
+        let (serial, serial_time) = copy(0, 1);
+        let (pipelined, pipelined_time) = copy(1, 1);
+        let (unsafe_callbacks, unsafe_time) = copy(1, 0);
+
+        // 11 chunks: serial pays 22 delays, pipelined about 12
+        assert!(pipelined_time.as_secs_f64() < serial_time.as_secs_f64() * 0.75,
+                "pipelined {:?}, serial {:?}", pipelined_time, serial_time);
+        assert!(unsafe_time.as_secs_f64() > pipelined_time.as_secs_f64());
+
+        for transfer in [&serial, &pipelined, &unsafe_callbacks] {
+            assert_eq!(*transfer.dest.lock().unwrap(), transfer.source);
+            // Progress only counts written bytes
+            let progress = transfer.progress.lock().unwrap();
+            assert!(progress.iter().all(|&(bytes, written)| bytes == written as u64));
+            assert_eq!(progress.last().unwrap().0, size as u64);
+        }
+    }
+
+    #[test]
+    fn test_pipelined_copy_cancels_and_resends_tails() {
+        let size = 300_000;
+        let mut transfer = transfer(size);
+        let ctx = unified_copy_init_ex(size as u64, 1, 64 * 1024, ptr::null(), 1, 1);
+        let mut buffer = vec![0u8; 64 * 1024];
+        let user_data = &mut transfer as *mut Transfer as *mut c_void;
+
+        // Partial writes re-send the rest of each chunk in order
+        let result = unified_copy_file_v2(ctx, buffer.as_mut_ptr(), buffer.len(), size as u64,
+                                          Some(read_source), Some(write_half), None, user_data);
+        assert_eq!(result, 0);
+        assert_eq!(transfer.dest, transfer.source);
+
+        // Cancellation stops the next chunk
+        let operation_id = unified_copy_get_operation_id(ctx);
+        assert_eq!(crate::operations::cancel_operation(operation_id), SUCCESS);
+        transfer.dest.clear();
+        let result = unified_copy_file(ctx, buffer.as_mut_ptr(), buffer.len(), size as u64,
+                                       Some(read_source), Some(write_all), None, user_data);
+        assert_eq!(result, ERROR_CANCELLED);
+        assert!(transfer.dest.is_empty());
+        unified_copy_free(ctx);
+    }
+}
@@ -1,18 +1,42 @@
 /// Upload operations for CloudNexus
 /// Handles streaming file uploads with optional encryption and progress reporting
 use std::fs::File;
-use std::io::{Read, Write, BufReader, BufWriter};
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::ffi::{c_char, c_void, CStr};
+#[cfg(unix)]
+use std::ffi::c_int;
 use std::ptr;
 use std::slice;
 
+use serde::{Deserialize, Serialize};
+
 use crate::file_io::{ProgressThrottler, ERROR_NULL_POINTER, ERROR_FILE_NOT_FOUND,
-                     ERROR_PERMISSION_DENIED, ERROR_IO_FAILED, ERROR_CANCELLED,
-                     ERROR_INVALID_PATH, SUCCESS, c_str_to_path, is_cancelled, string_to_c_char};
-use crate::{EncryptionContext, encrypt_chunk, encrypt_file_init,
-                        encrypt_file_get_wrapped_fek, encrypt_file_finalize, MAGIC, VERSION};
+                     ERROR_IO_FAILED, ERROR_CANCELLED,
+                     ERROR_INVALID_PATH, ERROR_SOURCE_CHANGED, SUCCESS, c_str_to_path, is_cancelled};
+use crate::{EncryptionContext, encrypt_chunk, encrypt_file_init, encrypt_file_finalize, encrypt_file_set_chunk_crc,
+                        legacy_encryption_mode, master_key_for_mode, master_key_error, ERROR_MASTER_KEY_REQUIRED,
+                        ENCRYPTION_MODE_NONE, ENCRYPTION_MODE_REQUIRED, HEADER_SIZE};
+use crate::errors::{checked, ERROR_INVALID_CHUNK_SIZE, ERROR_UNSUPPORTED_VERSION};
+use crate::ffi_util::{ffi_str_in, json_envelope, set_last_error_detail, ErrorEnvelope, ERROR_INVALID_JSON,
+                      ERROR_RESULT_UNAVAILABLE};
+#[cfg(unix)]
+use crate::file_io::file_from_fd;
+#[cfg(windows)]
+use crate::file_io::file_from_handle;
+use crate::governor::TransferPacer;
+use crate::source_reader::SourceReader;
+use crate::operations::{Operation, OperationKind};
+use crate::dedup::{chunk_fingerprint, build_reference_record, CHUNK_FINGERPRINT_SKIP, FINGERPRINT_SIZE,
+                   FORMAT_VERSION_DEDUP};
+use crate::metadata_strip::{strip_image_file, is_valid_strip_policy, ERROR_INVALID_STRIP_POLICY, STRIP_METADATA_NONE};
+use crate::temp::{create_temp_file, discard_temp_file};
+use crate::open_files::{open_limited, OpenFilePermit};
+use crate::codec::{base64_engine, decode_base64, encode_base64_into, ERROR_OUTPUT_TOO_SMALL};
+use crate::phase_progress::{PhaseProgressCallback, PhaseTracker, PHASE_ENCRYPTING, PHASE_HASHING, PHASE_UPLOADING};
+use crate::metrics::{self, Counter};
+use crate::copy::CallbackGuard;
 
 /// Progress callback for upload operations
 pub type UploadProgressCallback = extern "C" fn(bytes_processed: usize, total_bytes: usize, user_data: *mut c_void);
@@ -21,10 +45,26 @@ pub type UploadProgressCallback = extern "C" fn(bytes_processed: usize, total_by
 /// Parameters: encrypted_data pointer, data length, chunk index, user_data pointer
 pub type UploadDataCallback = extern "C" fn(data: *const u8, data_len: usize, chunk_index: u32, user_data: *mut c_void);
 
+/// Fingerprint callback for upload deduplication
+/// Parameters: chunk index, pointer to 32-byte BLAKE3 hash of the plaintext chunk, user_data
+/// Returns CHUNK_FINGERPRINT_SKIP if the server already has the chunk, CHUNK_FINGERPRINT_UPLOAD otherwise
+pub type ChunkFingerprintCallback = extern "C" fn(chunk_index: u32, hash: *const u8, user_data: *mut c_void) -> i32;
+
+/// Default plaintext chunk size for uploads
+pub const DEFAULT_UPLOAD_CHUNK_SIZE: usize = 1024 * 1024; // 1MB
+
+/// Smallest plaintext chunk size an upload accepts
+pub const MIN_UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest plaintext chunk size an upload accepts
+pub const MAX_UPLOAD_CHUNK_SIZE: usize = 10 * 1024 * 1024;
+
 /// Upload context for streaming operations
 #[repr(C)]
 pub struct UploadContext {
-    input_file: *mut BufReader<File>,
+    input_file: *mut SourceReader,
+    /// Open file permit of input_file, when this upload opened it
+    input_permit: Option<OpenFilePermit>,
     file_path: PathBuf,
     encryption_context: Option<*mut EncryptionContext>,
     master_key: Vec<u8>,
@@ -32,16 +72,60 @@ pub struct UploadContext {
     total_bytes: usize,
     chunk_index: u32,
     should_encrypt: bool,
+    chunk_size: usize,
+    use_mmap: bool,
+    chunk_crc: bool,
+    /// Keep reading when the source grows during the upload instead of failing
+    tolerate_growth: bool,
+    /// Stripped copy of the source that is streamed instead of it, deleted on finalize or free
+    stripped_temp: Option<PathBuf>,
+    /// Emit base64 instead of binary data; Some(true) for the URL-safe alphabet
+    emit_base64: Option<bool>,
+    fingerprint_callback: Option<ChunkFingerprintCallback>,
+    fingerprint_user_data: *mut c_void,
     cancel_flag: *const AtomicBool,
     progress_throttler: ProgressThrottler,
+    phase_tracker: PhaseTracker,
+    pacer: TransferPacer,
     is_finalized: bool,
+    /// The container header has been handed out (upload_get_header or the data callback)
+    header_emitted: bool,
+    /// Emit output in blocks of a multiple of this many bytes (0 emits every chunk as is)
+    output_alignment: usize,
+    /// Emitted bytes waiting to fill an aligned block
+    aligned_pending: Vec<u8>,
+    /// Aligned blocks handed to the data callback so far
+    aligned_blocks: u32,
+    /// Modification time of the source when the upload started, recorded in exported sessions
+    source_mtime_ms: Option<u64>,
+    callback_guard: CallbackGuard<UploadProgress>,
+    operation: Operation,
+}
+
+/// Progress of an upload, as upload_get_bytes_processed and upload_get_total_bytes report it
+///
+/// upload_process_chunk and upload_process_chunk_phased invoke their progress,
+/// phase, data and fingerprint callbacks only while no reference to the context
+/// is held, so a callback may call back into the library. While it runs,
+/// upload_get_bytes_processed and upload_get_total_bytes return the values the
+/// progress callback was given (for the other callbacks, the progress before the
+/// chunk), upload_get_operation_id and get_operation_progress work as usual, and
+/// cancel_operation takes effect at the next chunk. A callback must not free the
+/// context or call a function that advances or configures it.
+#[derive(Debug, Clone, Copy, Default)]
+struct UploadProgress {
+    bytes_processed: usize,
+    total_bytes: usize,
 }
 
 impl UploadContext {
     pub fn new(file_path: PathBuf, total_bytes: usize, should_encrypt: bool, 
                master_key: Vec<u8>, cancel_flag: *const AtomicBool) -> Self {
+        let operation = Operation::register(OperationKind::Upload, &file_path, Path::new(""),
+                                            total_bytes as u64, 1, cancel_flag);
         Self {
             input_file: ptr::null_mut(),
+            input_permit: None,
             file_path,
             encryption_context: None,
             master_key,
@@ -49,20 +133,181 @@ impl UploadContext {
             total_bytes,
             chunk_index: 0,
             should_encrypt,
-            cancel_flag,
+            chunk_size: DEFAULT_UPLOAD_CHUNK_SIZE,
+            use_mmap: false,
+            chunk_crc: false,
+            tolerate_growth: false,
+            stripped_temp: None,
+            emit_base64: None,
+            fingerprint_callback: None,
+            fingerprint_user_data: ptr::null_mut(),
+            cancel_flag: operation.cancel_flag(),
             progress_throttler: ProgressThrottler::new(500), // 500ms interval
+            phase_tracker: PhaseTracker::new(),
+            pacer: TransferPacer::new(),
             is_finalized: false,
+            header_emitted: false,
+            output_alignment: 0,
+            aligned_pending: Vec::new(),
+            aligned_blocks: 0,
+            source_mtime_ms: None,
+            callback_guard: CallbackGuard::default(),
+            operation,
+        }
+    }
+
+    fn is_encrypting(&self) -> bool {
+        self.should_encrypt && !self.master_key.is_empty()
+    }
+
+    /// Encryption was requested without a key; nothing may be emitted in plaintext
+    fn is_missing_key(&self) -> bool {
+        self.should_encrypt && self.master_key.is_empty()
+    }
+
+    /// Place emitted bytes in the chunk buffer, base64-encoded if requested
+    ///
+    /// Returns the number of bytes placed, 0 if they don't fit.
+    fn write_emitted(&self, data: &[u8], buffer: *mut u8, buffer_size: usize) -> usize {
+        match self.emit_base64 {
+            Some(url_safe) => {
+                let dst = unsafe { slice::from_raw_parts_mut(buffer, buffer_size) };
+                encode_base64_into(data, dst, url_safe).unwrap_or(0)
+            }
+            None if data.len() <= buffer_size => {
+                unsafe { ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len()); }
+                data.len()
+            }
+            None => 0,
+        }
+    }
+
+    /// Place emitted bytes in the chunk buffer, or queue them when output is aligned
+    ///
+    /// Returns the number of bytes placed in the buffer.
+    fn emit(&mut self, data: &[u8], buffer: *mut u8, buffer_size: usize) -> usize {
+        if self.output_alignment == 0 {
+            return self.write_emitted(data, buffer, buffer_size);
+        }
+        match self.emit_base64 {
+            Some(url_safe) => self.aligned_pending.extend(base64_engine(url_safe).encode_type::<Vec<u8>>(data)),
+            None => self.aligned_pending.extend_from_slice(data),
+        }
+        0
+    }
+
+    /// Move queued output into the buffer: the largest multiple of the alignment
+    /// that is queued and fits, or with `final_block` everything left if it fits
+    ///
+    /// Returns the number of bytes placed in the buffer.
+    fn take_aligned(&mut self, buffer: *mut u8, buffer_size: usize, final_block: bool) -> usize {
+        let pending = self.aligned_pending.len();
+        let len = if final_block && pending <= buffer_size {
+            pending
+        } else {
+            pending.min(buffer_size) / self.output_alignment * self.output_alignment
+        };
+        if len == 0 {
+            return 0;
+        }
+        unsafe { ptr::copy_nonoverlapping(self.aligned_pending.as_ptr(), buffer, len); }
+        self.aligned_pending.drain(..len);
+        self.aligned_blocks += 1;
+        len
+    }
+
+    /// Encryption context of this upload, created on first use
+    fn encryption_context(&mut self) -> Result<*mut EncryptionContext, i32> {
+        if let Some(enc_ctx) = self.encryption_context {
+            return Ok(enc_ctx);
+        }
+
+        let mut output_len: usize = 0;
+        let enc_ctx = unsafe { encrypt_file_init(self.master_key.as_ptr(), self.master_key.len(), &mut output_len) };
+        if enc_ctx.is_null() {
+            return Err(ERROR_IO_FAILED);
+        }
+        encrypt_file_set_chunk_crc(enc_ctx, self.chunk_crc as u8);
+        self.encryption_context = Some(enc_ctx);
+        Ok(enc_ctx)
+    }
+
+    /// Container preamble: the main header followed by the wrapped FEK
+    fn preamble(&mut self) -> Result<Vec<u8>, i32> {
+        let enc_ctx = unsafe { &mut *self.encryption_context()? };
+        if self.fingerprint_callback.is_some() {
+            enc_ctx.set_format_version(FORMAT_VERSION_DEDUP);
+        }
+        Ok(enc_ctx.preamble())
+    }
+
+    /// Check the source size once everything expected has been read
+    ///
+    /// A source that grew extends total_bytes when growth is tolerated, so the
+    /// caller keeps reading; any other size change is ERROR_SOURCE_CHANGED.
+    fn check_source_end(&mut self) -> Result<(), i32> {
+        let current = if !self.input_file.is_null() {
+            unsafe { &*self.input_file }.current_len()
+        } else if !self.file_path.as_os_str().is_empty() {
+            std::fs::metadata(&self.file_path).map(|m| m.len())
+        } else {
+            return Ok(());
+        };
+        let current = current.map_err(|_| ERROR_SOURCE_CHANGED)? as usize;
+
+        if current == self.bytes_read {
+            Ok(())
+        } else if current > self.bytes_read && self.tolerate_growth {
+            self.total_bytes = current;
+            self.operation.set_total_bytes(current as u64);
+            // The old total may already have been reported as final
+            self.progress_throttler = ProgressThrottler::new(500);
+            Ok(())
+        } else {
+            Err(ERROR_SOURCE_CHANGED)
+        }
+    }
+
+    /// Delete the stripped copy of the source, if one was made
+    fn discard_stripped_temp(&mut self) {
+        if let Some(temp_path) = self.stripped_temp.take() {
+            let _ = discard_temp_file(&temp_path);
         }
     }
+
+    /// Set the plaintext chunk size (0 keeps the default), checked with check_chunk_size
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        debug_assert!(check_chunk_size(chunk_size).is_ok());
+        if chunk_size > 0 {
+            self.chunk_size = chunk_size;
+        }
+    }
+}
+
+/// Check a caller's plaintext chunk size (0 for the default)
+///
+/// Sizes outside MIN_UPLOAD_CHUNK_SIZE..=MAX_UPLOAD_CHUNK_SIZE fail with
+/// ERROR_INVALID_CHUNK_SIZE.
+pub fn check_chunk_size(chunk_size: usize) -> Result<(), ErrorEnvelope> {
+    match chunk_size {
+        0 | MIN_UPLOAD_CHUNK_SIZE..=MAX_UPLOAD_CHUNK_SIZE => Ok(()),
+        _ => Err(ErrorEnvelope::new(ERROR_INVALID_CHUNK_SIZE,
+                                    format!("chunk size {} is outside {}..={}", chunk_size,
+                                            MIN_UPLOAD_CHUNK_SIZE, MAX_UPLOAD_CHUNK_SIZE))
+            .with_context("chunk_size")),
+    }
 }
 
 /// Initialize upload context
 ///
+/// With `should_encrypt` set, a null master key or one that is not 32 bytes
+/// long fails the init instead of starting a plaintext upload.
+///
 /// # Arguments
 /// * `local_file_path` - Path to the local file to upload
 /// * `master_key` - Pointer to 32-byte master encryption key (can be null for no encryption)
 /// * `master_key_len` - Length of master key (must be 0 or 32)
-/// * `chunk_size` - Size of chunks in bytes
+/// * `chunk_size` - Size of plaintext chunks in bytes (0 for default, else 64KB to 10MB)
 /// * `should_encrypt` - 1 if encryption should be used, 0 otherwise
 /// * `progress_callback` - Optional progress callback
 /// * `data_callback` - Callback for receiving encrypted data chunks
@@ -83,48 +328,277 @@ pub extern "C" fn upload_init(
     cancel_flag: *const AtomicBool,
     user_data: *mut c_void,
 ) -> *mut UploadContext {
+    upload_init_v2(
+        local_file_path,
+        master_key,
+        master_key_len,
+        chunk_size,
+        legacy_encryption_mode(should_encrypt),
+        cancel_flag,
+        ptr::null_mut(),
+    )
+}
+
+/// Initialize upload context with an explicit encryption mode
+///
+/// The master key is checked before the source is opened: ENCRYPTION_MODE_REQUIRED
+/// fails with ERROR_MASTER_KEY_REQUIRED unless a 32-byte key is given, and
+/// ENCRYPTION_MODE_OPTIONAL uploads in plaintext only when the key is null or
+/// empty. Callbacks are passed to upload_process_chunk.
+///
+/// # Arguments
+/// * `local_file_path` - Path to the local file to upload
+/// * `master_key` - Pointer to 32-byte master encryption key (can be null)
+/// * `master_key_len` - Length of master key
+/// * `chunk_size` - Size of plaintext chunks in bytes (0 for default, else 64KB to 10MB)
+/// * `encryption_mode` - ENCRYPTION_MODE_NONE, ENCRYPTION_MODE_REQUIRED or ENCRYPTION_MODE_OPTIONAL
+/// * `cancel_flag` - Pointer to atomic bool for cancellation
+/// * `status` - Optional pointer receiving 0, or the error code when null is returned
+///   (ERROR_MASTER_KEY_REQUIRED, ERROR_INVALID_ENCRYPTION_MODE, ERROR_INVALID_CHUNK_SIZE,
+///   ERROR_FILE_NOT_FOUND, ...)
+///
+/// # Returns
+/// Pointer to UploadContext, or null on error
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_init_v2(
+    local_file_path: *const c_char,
+    master_key: *const u8,
+    master_key_len: usize,
+    chunk_size: usize,
+    encryption_mode: i32,
+    cancel_flag: *const AtomicBool,
+    status: *mut i32,
+) -> *mut UploadContext {
+    let fail = |error: ErrorEnvelope| {
+        let code = set_last_error_detail(error);
+        if !status.is_null() {
+            unsafe { *status = code; }
+        }
+        ptr::null_mut()
+    };
+
+    let key = match master_key_for_mode(master_key, master_key_len, encryption_mode) {
+        Ok(key) => key,
+        Err(code) => return fail(master_key_error(code)),
+    };
+    if let Err(error) = check_chunk_size(chunk_size) {
+        return fail(error);
+    }
+
     if local_file_path.is_null() {
-        return ptr::null_mut();
+        return fail(ErrorEnvelope::null_argument("local_file_path"));
     }
 
     // Convert path
     let path = match unsafe { c_str_to_path(local_file_path) } {
         Ok(p) => p,
-        Err(e) => return ptr::null_mut(),
+        Err(code) => return fail(ErrorEnvelope::new(code, "invalid source path").with_context("local_file_path")),
     };
 
     // Open file
-    let file = match File::open(&path) {
-        Ok(f) => f,
-        Err(_) => return ptr::null_mut(),
+    let (file, _open_files) = match open_limited(|| File::open(&path), ERROR_FILE_NOT_FOUND) {
+        Ok(opened) => opened,
+        Err(code) => {
+            return fail(ErrorEnvelope::new(code, "cannot open the source file").with_context(path.to_string_lossy()));
+        }
     };
 
     // Get file size
     let metadata = match file.metadata() {
         Ok(m) => m,
-        Err(_) => return ptr::null_mut(),
+        Err(e) => {
+            return fail(ErrorEnvelope::new(ERROR_IO_FAILED, format!("cannot read the source file size: {}", e))
+                .with_context(path.to_string_lossy()));
+        }
     };
     let total_bytes = metadata.len() as usize;
 
-    // Get master key
-    let key = if !master_key.is_null() && master_key_len == 32 {
-        unsafe { slice::from_raw_parts(master_key, 32).to_vec() }
-    } else {
-        Vec::new()
-    };
-
     // Create context
-    let context = Box::new(UploadContext::new(
+    let should_encrypt = !key.is_empty();
+    let mut context = Box::new(UploadContext::new(
         path,
         total_bytes,
-        should_encrypt == 1,
+        should_encrypt,
+        key,
+        cancel_flag,
+    ));
+    context.set_chunk_size(chunk_size);
+    context.source_mtime_ms = Some(mtime_ms(&metadata));
+
+    if !status.is_null() {
+        unsafe { *status = SUCCESS; }
+    }
+    Box::leak(context) as *mut UploadContext
+}
+
+/// Create an upload context that reads from a caller-opened file
+fn upload_init_direct(
+    file: std::io::Result<File>,
+    master_key: *const u8,
+    master_key_len: usize,
+    chunk_size: usize,
+    should_encrypt: i32,
+    cancel_flag: *const AtomicBool,
+) -> *mut UploadContext {
+    let key = match master_key_for_mode(master_key, master_key_len, legacy_encryption_mode(should_encrypt)) {
+        Ok(key) => key,
+        Err(_) => return ptr::null_mut(),
+    };
+    if let Err(error) = check_chunk_size(chunk_size) {
+        set_last_error_detail(error);
+        return ptr::null_mut();
+    }
+
+    let mut file = match file {
+        Ok(f) => f,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    // The whole file is uploaded, wherever the caller left the offset
+    let total_bytes = match file.metadata() {
+        Ok(m) => m.len() as usize,
+        Err(_) => return ptr::null_mut(),
+    };
+    if file.seek(SeekFrom::Start(0)).is_err() {
+        return ptr::null_mut();
+    }
+
+    let should_encrypt = !key.is_empty();
+    let mut context = Box::new(UploadContext::new(
+        PathBuf::new(),
+        total_bytes,
+        should_encrypt,
         key,
         cancel_flag,
     ));
+    context.set_chunk_size(chunk_size);
+    context.input_file = Box::into_raw(Box::new(SourceReader::Buffered(BufReader::new(file))));
 
     Box::leak(context) as *mut UploadContext
 }
 
+/// Initialize upload context from an open file descriptor (e.g. an Android SAF source)
+///
+/// The descriptor is duplicated, so the caller keeps ownership of `source_fd` and
+/// must close it. The descriptor must refer to a seekable file; it is read from
+/// the start. Memory-mapped reads are not used for descriptor sources.
+///
+/// # Arguments
+/// * `source_fd` - Readable file descriptor owned by the caller
+/// * `master_key` - Pointer to 32-byte master encryption key (can be null for no encryption)
+/// * `master_key_len` - Length of master key (must be 0 or 32)
+/// * `chunk_size` - Size of plaintext chunks in bytes (0 for default, else 64KB to 10MB)
+/// * `should_encrypt` - 1 if encryption should be used, 0 otherwise
+/// * `progress_callback` - Optional progress callback
+/// * `data_callback` - Callback to receive encrypted data
+/// * `cancel_flag` - Pointer to atomic bool for cancellation
+/// * `user_data` - User data pointer passed to callbacks
+///
+/// # Returns
+/// Pointer to UploadContext, or null on error
+#[cfg(unix)]
+#[no_mangle]
+pub extern "C" fn upload_init_fd(
+    source_fd: c_int,
+    master_key: *const u8,
+    master_key_len: usize,
+    chunk_size: usize,
+    should_encrypt: i32,
+    _progress_callback: Option<UploadProgressCallback>,
+    _data_callback: Option<UploadDataCallback>,
+    cancel_flag: *const AtomicBool,
+    _user_data: *mut c_void,
+) -> *mut UploadContext {
+    upload_init_direct(file_from_fd(source_fd), master_key, master_key_len, chunk_size, should_encrypt, cancel_flag)
+}
+
+/// Initialize upload context from an open file HANDLE
+///
+/// Windows equivalent of upload_init_fd. The handle is duplicated, so the caller
+/// keeps ownership of `source_handle` and must close it.
+///
+/// # Arguments
+/// * `source_handle` - Readable file HANDLE owned by the caller
+/// * `master_key` - Pointer to 32-byte master encryption key (can be null for no encryption)
+/// * `master_key_len` - Length of master key (must be 0 or 32)
+/// * `chunk_size` - Size of plaintext chunks in bytes (0 for default, else 64KB to 10MB)
+/// * `should_encrypt` - 1 if encryption should be used, 0 otherwise
+/// * `progress_callback` - Optional progress callback
+/// * `data_callback` - Callback to receive encrypted data
+/// * `cancel_flag` - Pointer to atomic bool for cancellation
+/// * `user_data` - User data pointer passed to callbacks
+///
+/// # Returns
+/// Pointer to UploadContext, or null on error
+#[cfg(windows)]
+#[no_mangle]
+pub extern "C" fn upload_init_handle(
+    source_handle: *mut c_void,
+    master_key: *const u8,
+    master_key_len: usize,
+    chunk_size: usize,
+    should_encrypt: i32,
+    _progress_callback: Option<UploadProgressCallback>,
+    _data_callback: Option<UploadDataCallback>,
+    cancel_flag: *const AtomicBool,
+    _user_data: *mut c_void,
+) -> *mut UploadContext {
+    upload_init_direct(file_from_handle(source_handle), master_key, master_key_len, chunk_size, should_encrypt, cancel_flag)
+}
+
+/// Initialize upload context with chunk fingerprinting for deduplication
+///
+/// Same as upload_init, but every plaintext chunk is hashed with BLAKE3 before
+/// encryption and the hash is passed to `fingerprint_callback`. If the callback
+/// returns CHUNK_FINGERPRINT_SKIP, the chunk is not encrypted: a reference record
+/// carrying the fingerprint is emitted in its place (encrypted uploads) or nothing
+/// is emitted (plain uploads). Encrypted containers are marked FORMAT_VERSION_DEDUP.
+///
+/// # Arguments
+/// * `local_file_path` - Path to the local file to upload
+/// * `master_key` - Pointer to 32-byte master encryption key (can be null for no encryption)
+/// * `master_key_len` - Length of master key (must be 0 or 32)
+/// * `chunk_size` - Size of plaintext chunks in bytes (0 for default, else 64KB to 10MB)
+/// * `should_encrypt` - 1 if encryption should be used, 0 otherwise
+/// * `fingerprint_callback` - Optional fingerprint callback (null disables fingerprinting)
+/// * `fingerprint_user_data` - User data passed to the fingerprint callback
+/// * `cancel_flag` - Pointer to atomic bool for cancellation
+///
+/// # Returns
+/// Pointer to UploadContext, or null on error
+#[no_mangle]
+pub extern "C" fn upload_init_ex(
+    local_file_path: *const c_char,
+    master_key: *const u8,
+    master_key_len: usize,
+    chunk_size: usize,
+    should_encrypt: i32,
+    fingerprint_callback: Option<ChunkFingerprintCallback>,
+    fingerprint_user_data: *mut c_void,
+    cancel_flag: *const AtomicBool,
+) -> *mut UploadContext {
+    let context = upload_init(
+        local_file_path,
+        master_key,
+        master_key_len,
+        chunk_size,
+        should_encrypt,
+        None,
+        None,
+        cancel_flag,
+        ptr::null_mut(),
+    );
+
+    if !context.is_null() {
+        let ctx = unsafe { &mut *context };
+        ctx.fingerprint_callback = fingerprint_callback;
+        ctx.fingerprint_user_data = fingerprint_user_data;
+    }
+
+    context
+}
+
 /// Process next chunk of upload
 /// Reads from file, optionally encrypts, and calls data callback
 ///
@@ -137,7 +611,8 @@ pub extern "C" fn upload_init(
 /// * `user_data` - User data
 ///
 /// # Returns
-/// Number of bytes in chunk (0 if done), or negative error code
+/// Number of bytes in chunk (0 if done), or negative error code (ERROR_SOURCE_CHANGED
+/// if the file changed size while it was read, see upload_set_tolerate_growth)
 #[no_mangle]
 pub extern "C" fn upload_process_chunk(
     context: *mut UploadContext,
@@ -147,136 +622,387 @@ pub extern "C" fn upload_process_chunk(
     data_callback: Option<UploadDataCallback>,
     user_data: *mut c_void,
 ) -> isize {
-    if context.is_null() {
-        return ERROR_NULL_POINTER as isize;
-    }
-
-    let ctx = unsafe { &mut *context };
+    counted(upload_process_chunk_impl(context, buffer, buffer_size, progress_callback, None, data_callback, user_data))
+}
 
-    // Check if already done
-    if ctx.bytes_read >= ctx.total_bytes {
-        return 0;
-    }
+/// Process next chunk of upload, reporting progress per phase
+///
+/// Same as upload_process_chunk, with progress reported through a
+/// PhaseProgressCallback. Every chunk passes through up to three phases in
+/// turn, each counting plaintext bytes against the file size: PHASE_HASHING
+/// (only with a fingerprint callback, see upload_init_ex), PHASE_ENCRYPTING
+/// (only for encrypted uploads) and PHASE_UPLOADING once the chunk was handed
+/// to the data callback. Chunks the server already has count as done in every
+/// phase. The call returning 0 delivers the final report of each phase.
+///
+/// # Arguments
+/// * `context` - Pointer to UploadContext
+/// * `buffer` - Buffer to store encrypted chunk data
+/// * `buffer_size` - Size of buffer
+/// * `phase_callback` - Phased progress callback
+/// * `data_callback` - Data callback
+/// * `user_data` - User data
+///
+/// # Returns
+/// Number of bytes in chunk (0 if done), or negative error code
+#[no_mangle]
+pub extern "C" fn upload_process_chunk_phased(
+    context: *mut UploadContext,
+    buffer: *mut u8,
+    buffer_size: usize,
+    phase_callback: Option<PhaseProgressCallback>,
+    data_callback: Option<UploadDataCallback>,
+    user_data: *mut c_void,
+) -> isize {
+    counted(upload_process_chunk_impl(context, buffer, buffer_size, None, phase_callback, data_callback, user_data))
+}
 
-    // Check cancellation
-    if unsafe { is_cancelled(ctx.cancel_flag) } {
-        return ERROR_CANCELLED as isize;
+/// Count an upload_process_chunk error code in the metrics
+fn counted(result: isize) -> isize {
+    if result < 0 {
+        metrics::count_error(result as i32);
     }
+    result
+}
 
-    // Open file on first call
-    if ctx.input_file.is_null() {
-        let file = match File::open(&ctx.file_path) {
-            Ok(f) => f,
-            Err(_) => return ERROR_IO_FAILED as isize,
-        };
-        ctx.input_file = Box::into_raw(Box::new(BufReader::new(file)));
-    }
+/// The callbacks of one upload_process_chunk call
+#[derive(Clone, Copy)]
+struct ChunkCallbacks {
+    progress: Option<UploadProgressCallback>,
+    phase: Option<PhaseProgressCallback>,
+    data: Option<UploadDataCallback>,
+    user_data: *mut c_void,
+}
 
-    // Determine chunk size
-    let chunk_size = (ctx.total_bytes - ctx.bytes_read).min(1024 * 1024); // 1MB default
+/// A callback due while the context is borrowed, invoked once it no longer is
+enum PendingCallback {
+    /// The container header and wrapped FEK, ahead of the chunk with this index
+    Preamble(Vec<u8>, u32),
+    /// This many emitted bytes in the chunk buffer, with their chunk index
+    Emitted(usize, u32),
+    Progress,
+    Phase(u32, u64, u64),
+}
 
-    // Read chunk from file
-    let mut chunk_data = vec![0u8; chunk_size];
-    let reader = unsafe { &mut *ctx.input_file };
-    
-    match reader.read(&mut chunk_data) {
-        Ok(0) => return 0, // EOF
-        Ok(n) if n < chunk_size => {
-            chunk_data.truncate(n);
-        }
-        Ok(_) => {}
-        Err(_) => return ERROR_IO_FAILED as isize,
-    }
+/// Pending callbacks, each with the progress the accessors report while it runs
+type Pending = Vec<(UploadProgress, PendingCallback)>;
 
-    let actual_size = chunk_data.len();
-    let mut encrypted_data = chunk_data;
-    let mut chunk_index = ctx.chunk_index;
+/// Run a callback with the guard raised, the accessors reporting `progress`
+///
+/// # Safety
+/// `context` must be live and not borrowed.
+unsafe fn invoke_guarded<T>(context: *mut UploadContext, progress: UploadProgress, callback: impl FnOnce() -> T) -> T {
+    (*context).callback_guard.capture(progress);
+    CallbackGuard::invoke(ptr::addr_of!((*context).callback_guard), callback)
+}
 
-    // Encrypt if needed
-    if ctx.should_encrypt && !ctx.master_key.is_empty() {
-        // Initialize encryption on first chunk
-        if ctx.encryption_context.is_none() {
-            let output_len: usize = 0;
-            let enc_ctx = unsafe { 
-                encrypt_file_init(
-                    ctx.master_key.as_ptr(),
-                    ctx.master_key.len(),
-                    &output_len as *const usize as *mut usize,
-                )
-            };
-            
-            if enc_ctx.is_null() {
-                return ERROR_IO_FAILED as isize;
+/// Invoke pending callbacks in order
+///
+/// # Safety
+/// As for invoke_guarded; `buffer` holds the emitted bytes of any Emitted.
+unsafe fn deliver(context: *mut UploadContext, callbacks: ChunkCallbacks, buffer: *mut u8, pending: Pending) {
+    let user_data = callbacks.user_data;
+    for (progress, pending) in pending {
+        invoke_guarded(context, progress, || match pending {
+            PendingCallback::Preamble(data, index) => {
+                if let Some(cb) = callbacks.data {
+                    cb(data.as_ptr(), data.len(), index, user_data);
+                }
             }
-            ctx.encryption_context = Some(enc_ctx);
-
-            // Get wrapped FEK and write it first (not returned here, handled separately)
-            let wrapped_fek_len: usize = 0;
-            let wrapped_fek = unsafe { encrypt_file_get_wrapped_fek(enc_ctx, &wrapped_fek_len as *const usize as *mut usize) };
-            
-            // Write header + wrapped FEK to buffer first
-            if wrapped_fek_len + 12 <= buffer_size {
-                unsafe {
-                    // Header will be written by the caller
-                    ptr::copy_nonoverlapping(wrapped_fek, buffer.add(12), wrapped_fek_len);
+            PendingCallback::Emitted(len, index) => {
+                if let Some(cb) = callbacks.data {
+                    cb(buffer, len, index, user_data);
                 }
-                unsafe { libc::free(wrapped_fek as *mut c_void); }
             }
-        }
+            PendingCallback::Progress => {
+                if let Some(cb) = callbacks.progress {
+                    cb(progress.bytes_processed, progress.total_bytes, user_data);
+                }
+            }
+            PendingCallback::Phase(phase, done, total) => {
+                if let Some(cb) = callbacks.phase {
+                    cb(phase, done, total, user_data);
+                }
+            }
+        });
+    }
+}
 
-        // Encrypt chunk
-        let enc_ctx = ctx.encryption_context.unwrap();
-        let output_len: usize = 0;
-        let encrypted = unsafe { 
-            encrypt_chunk(
-                enc_ctx,
-                encrypted_data.as_ptr(),
-                encrypted_data.len(),
-                chunk_index,
-                &output_len as *const usize as *mut usize,
-            )
-        };
+impl UploadContext {
+    /// Progress as the accessors report it outside callbacks
+    fn progress(&self) -> UploadProgress {
+        UploadProgress { bytes_processed: self.bytes_read, total_bytes: self.total_bytes }
+    }
 
-        if encrypted.is_null() {
-            return ERROR_IO_FAILED as isize;
-        }
+    /// Queue a callback, reporting the current progress while it runs
+    fn queue(&self, pending: &mut Pending, callback: PendingCallback) {
+        pending.push((self.progress(), callback));
+    }
 
-        // Get encrypted data size
-        let encrypted_size = unsafe { *(&output_len as *const usize as *const usize) };
-        
-        // Copy to buffer
-        if encrypted_size <= buffer_size {
-            unsafe {
-                ptr::copy_nonoverlapping(encrypted, buffer, encrypted_size);
+    /// Queue the phase reports due for `done` of `total` bytes
+    fn queue_phase(&mut self, pending: &mut Pending, callbacks: ChunkCallbacks, phase: u32, done: u64, total: u64) {
+        if callbacks.phase.is_some() {
+            for (phase, done, total) in self.phase_tracker.due(phase, done, total) {
+                self.queue(pending, PendingCallback::Phase(phase, done, total));
             }
         }
-        
-        unsafe { libc::free(encrypted as *mut c_void); }
-    } else {
-        // No encryption - copy raw data
-        if actual_size <= buffer_size {
-            unsafe {
-                ptr::copy_nonoverlapping(encrypted_data.as_ptr(), buffer, actual_size);
-            }
+    }
+}
+
+// Callbacks run once the context is no longer borrowed (see UploadProgress),
+// so every step that may queue one borrows it only for its own duration
+fn upload_process_chunk_impl(
+    context: *mut UploadContext,
+    buffer: *mut u8,
+    buffer_size: usize,
+    progress_callback: Option<UploadProgressCallback>,
+    phase_callback: Option<PhaseProgressCallback>,
+    data_callback: Option<UploadDataCallback>,
+    user_data: *mut c_void,
+) -> isize {
+    if context.is_null() {
+        return ERROR_NULL_POINTER as isize;
+    }
+
+    let callbacks = ChunkCallbacks { progress: progress_callback, phase: phase_callback, data: data_callback, user_data };
+    let mut pending = Vec::new();
+    let chunk = upload_read_chunk(unsafe { &mut *context }, buffer, buffer_size, callbacks, &mut pending);
+    unsafe { deliver(context, callbacks, buffer, pending) };
+
+    match chunk {
+        Ok(Some(chunk_data)) => upload_emit_chunk(context, chunk_data, buffer, buffer_size, callbacks),
+        Ok(None) => 0,
+        Err(code) => code as isize,
+    }
+}
+
+/// Read the next plaintext chunk, None once the upload is done
+fn upload_read_chunk(
+    ctx: &mut UploadContext,
+    buffer: *mut u8,
+    buffer_size: usize,
+    callbacks: ChunkCallbacks,
+    pending: &mut Pending,
+) -> Result<Option<Vec<u8>>, i32> {
+    if ctx.is_missing_key() {
+        return Err(ERROR_MASTER_KEY_REQUIRED);
+    }
+
+    // Aligned blocks are built in the chunk buffer, so it must hold at least one
+    if ctx.output_alignment > 0 && buffer_size < ctx.output_alignment {
+        return Err(ERROR_OUTPUT_TOO_SMALL);
+    }
+
+    // An empty file still yields one explicit empty chunk when encrypted, so the
+    // container decrypts to an empty file rather than looking truncated
+    let empty_chunk_due = ctx.is_encrypting() && ctx.total_bytes == 0 && ctx.chunk_index == 0;
+
+    // Check if already done, unless the source grew in the meantime
+    if ctx.bytes_read >= ctx.total_bytes && !empty_chunk_due {
+        ctx.check_source_end()?;
+        if ctx.bytes_read >= ctx.total_bytes {
+            // Done: deliver the terminal update unless the last chunk already did
+            if callbacks.progress.is_some() && ctx.progress_throttler.finish() {
+                ctx.queue(pending, PendingCallback::Progress);
+            }
+            if callbacks.phase.is_some() {
+                for phase in ctx.phase_tracker.started_phases() {
+                    for (phase, done, total) in ctx.phase_tracker.finish_due(phase, ctx.total_bytes as u64) {
+                        ctx.queue(pending, PendingCallback::Phase(phase, done, total));
+                    }
+                }
+            }
+            return Ok(None);
+        }
+    }
+
+    // Check cancellation
+    if unsafe { is_cancelled(ctx.cancel_flag) } {
+        return Err(ERROR_CANCELLED);
+    }
+
+    // Deliver the header and wrapped FEK first if upload_get_header was not called
+    if ctx.is_encrypting() && !ctx.header_emitted {
+        let mut preamble = ctx.preamble()?;
+        ctx.header_emitted = true;
+        if ctx.output_alignment > 0 {
+            // The header starts the aligned stream, emitted with the first block
+            ctx.emit(&preamble, buffer, buffer_size);
+        } else if callbacks.data.is_some() {
+            if let Some(url_safe) = ctx.emit_base64 {
+                preamble = base64_engine(url_safe).encode_type::<Vec<u8>>(&preamble);
+            }
+            ctx.queue(pending, PendingCallback::Preamble(preamble, ctx.chunk_index));
         }
     }
 
+    if empty_chunk_due {
+        return Ok(Some(Vec::new()));
+    }
+
+    // Open file on first call
+    if ctx.input_file.is_null() {
+        let (reader, permit) = open_limited(|| SourceReader::open(&ctx.file_path, ctx.use_mmap), ERROR_IO_FAILED)?;
+        ctx.input_file = Box::into_raw(Box::new(reader));
+        ctx.input_permit = Some(permit);
+    }
+
+    // Determine chunk size
+    let chunk_size = (ctx.total_bytes - ctx.bytes_read).min(ctx.chunk_size);
+
+    // Read chunk from file
+    let mut chunk_data = vec![0u8; chunk_size];
+    let reader = unsafe { &mut *ctx.input_file };
+    
+    match reader.read(&mut chunk_data) {
+        // EOF before the expected size: the source shrank
+        Ok(0) => return Err(ERROR_SOURCE_CHANGED),
+        Ok(n) if n < chunk_size => {
+            chunk_data.truncate(n);
+        }
+        Ok(_) => {}
+        Err(_) => return Err(ERROR_IO_FAILED),
+    }
+
+    Ok(Some(chunk_data))
+}
+
+/// Encrypt (or fingerprint) one plaintext chunk, emit it and update progress
+fn upload_emit_chunk(
+    context: *mut UploadContext,
+    chunk_data: Vec<u8>,
+    buffer: *mut u8,
+    buffer_size: usize,
+    callbacks: ChunkCallbacks,
+) -> isize {
+    // Ask the fingerprint callback whether the server already has this chunk
+    // (the explicit empty chunk of an empty file is always sent)
+    let (fingerprint_callback, fingerprint_user_data, chunk_index, progress) = {
+        let ctx = unsafe { &*context };
+        (ctx.fingerprint_callback.filter(|_| !chunk_data.is_empty()), ctx.fingerprint_user_data, ctx.chunk_index,
+         ctx.progress())
+    };
+    let mut fingerprint = None;
+    if let Some(cb) = fingerprint_callback {
+        let hash = chunk_fingerprint(&chunk_data);
+        let skip = unsafe {
+            invoke_guarded(context, progress, || cb(chunk_index, hash.as_ptr(), fingerprint_user_data))
+        } == CHUNK_FINGERPRINT_SKIP;
+        fingerprint = Some((hash, skip));
+    }
+
+    let actual_size = chunk_data.len();
+    let mut pending = Vec::new();
+    let emitted = upload_encrypt_chunk(unsafe { &mut *context }, chunk_data, fingerprint, buffer, buffer_size,
+                                       callbacks, &mut pending);
+    unsafe { deliver(context, callbacks, buffer, pending) };
+    if let Err(code) = emitted {
+        return code as isize;
+    }
+
+    let mut pending = Vec::new();
+    let counted = upload_count_chunk(unsafe { &mut *context }, actual_size, callbacks, &mut pending);
+    unsafe { deliver(context, callbacks, buffer, pending) };
+    match counted {
+        Ok(()) => actual_size as isize,
+        Err(code) => code as isize,
+    }
+}
+
+/// Encrypt a chunk (or its reference record, when `fingerprint` says the server
+/// has it) into the chunk buffer and queue it for the data callback
+fn upload_encrypt_chunk(
+    ctx: &mut UploadContext,
+    chunk_data: Vec<u8>,
+    fingerprint: Option<([u8; FINGERPRINT_SIZE], bool)>,
+    buffer: *mut u8,
+    buffer_size: usize,
+    callbacks: ChunkCallbacks,
+    pending: &mut Pending,
+) -> Result<(), i32> {
+    let actual_size = chunk_data.len();
+    let phase_done = (ctx.bytes_read + actual_size) as u64;
+    let phase_total = ctx.total_bytes as u64;
+    let mut chunk_index = ctx.chunk_index;
+    let mut emitted_size = 0;
+
+    if fingerprint.is_some() {
+        ctx.queue_phase(pending, callbacks, PHASE_HASHING, phase_done, phase_total);
+    }
+
+    if let Some((hash, true)) = fingerprint {
+        // Encrypted streams carry a reference record in place of the ciphertext
+        if ctx.should_encrypt && !ctx.master_key.is_empty() {
+            let record = build_reference_record(chunk_index, &hash);
+            emitted_size = ctx.emit(&record, buffer, buffer_size);
+        }
+    } else if ctx.is_encrypting() {
+        let enc_ctx = ctx.encryption_context()?;
+
+        // Encrypt chunk
+        let mut encrypted_size: usize = 0;
+        let encrypted = encrypt_chunk(
+            enc_ctx,
+            chunk_data.as_ptr(),
+            chunk_data.len(),
+            chunk_index,
+            &mut encrypted_size,
+        );
+
+        if encrypted.is_null() {
+            return Err(ERROR_IO_FAILED);
+        }
+        
+        // Copy (or encode) to buffer
+        emitted_size = ctx.emit(unsafe { slice::from_raw_parts(encrypted, encrypted_size) }, buffer, buffer_size);
+        
+        unsafe { libc::free(encrypted as *mut c_void); }
+    } else {
+        // No encryption - copy (or encode) raw data
+        emitted_size = ctx.emit(&chunk_data, buffer, buffer_size);
+    }
+
+    // Aligned output hands out whole blocks, independent of the chunk boundaries
+    if ctx.output_alignment > 0 {
+        chunk_index = ctx.aligned_blocks;
+        emitted_size = ctx.take_aligned(buffer, buffer_size, false);
+    }
+
+    if ctx.is_encrypting() {
+        ctx.queue_phase(pending, callbacks, PHASE_ENCRYPTING, phase_done, phase_total);
+    }
+
+    // Hand the emitted chunk to the data callback
+    if callbacks.data.is_some() && emitted_size > 0 {
+        ctx.queue(pending, PendingCallback::Emitted(emitted_size, chunk_index));
+    }
+    ctx.queue_phase(pending, callbacks, PHASE_UPLOADING, phase_done, phase_total);
+    Ok(())
+}
+
+/// Count an emitted chunk, pace the upload and queue the progress update
+fn upload_count_chunk(ctx: &mut UploadContext, actual_size: usize, callbacks: ChunkCallbacks,
+                      pending: &mut Pending) -> Result<(), i32> {
     // Update progress
     ctx.bytes_read += actual_size;
+    ctx.operation.set_bytes_done(ctx.bytes_read as u64);
     ctx.chunk_index += 1;
 
-    // Call progress callback if throttled
-    if let Some(cb) = progress_callback {
-        if ctx.progress_throttler.should_update(ctx.bytes_read, ctx.total_bytes) {
-            cb(ctx.bytes_read, ctx.total_bytes, user_data);
-        }
+    // Slow down under thermal/battery pressure or an explicit rate limit
+    if actual_size > 0 && !ctx.pacer.pace(actual_size, ctx.cancel_flag) {
+        return Err(ERROR_CANCELLED);
     }
 
-    actual_size as isize
+    // Call progress callback if throttled
+    if callbacks.progress.is_some() && ctx.progress_throttler.should_update(ctx.bytes_read, ctx.total_bytes) {
+        ctx.queue(pending, PendingCallback::Progress);
+    }
+    Ok(())
 }
 
 /// Get header and wrapped FEK for upload
-/// Must be called before processing chunks if encryption is enabled
+/// Call before the first upload_process_chunk when encryption is enabled; an
+/// upload that skips it gets them through the data callback ahead of chunk 0
 ///
 /// # Arguments
 /// * `context` - Pointer to UploadContext
@@ -286,7 +1012,9 @@ pub extern "C" fn upload_process_chunk(
 /// * `fek_len` - Pointer to store actual wrapped FEK length
 ///
 /// # Returns
-/// 0 on success, error code on failure
+/// 0 on success, ERROR_MASTER_KEY_REQUIRED if encryption was requested without a
+/// usable master key, ERROR_OUTPUT_TOO_SMALL if `fek_buffer` can't hold the
+/// wrapped FEK (`fek_len` is still set), other error code on failure
 #[no_mangle]
 pub extern "C" fn upload_get_header(
     context: *mut UploadContext,
@@ -301,7 +1029,12 @@ pub extern "C" fn upload_get_header(
 
     let ctx = unsafe { &mut *context };
 
-    if !ctx.should_encrypt || ctx.master_key.is_empty() {
+    // Never hand out a plaintext header for an upload that was meant to be encrypted
+    if ctx.is_missing_key() {
+        return ERROR_MASTER_KEY_REQUIRED;
+    }
+
+    if !ctx.is_encrypting() {
         // No encryption - write empty header
         unsafe {
             ptr::write_bytes(header_buffer, 0, 12);
@@ -310,60 +1043,22 @@ pub extern "C" fn upload_get_header(
         return SUCCESS;
     }
 
-    // Initialize encryption if not already done
-    if ctx.encryption_context.is_none() {
-        let output_len: usize = 0;
-        let enc_ctx = unsafe { 
-            encrypt_file_init(
-                ctx.master_key.as_ptr(),
-                ctx.master_key.len(),
-                &output_len as *const usize as *mut usize,
-            )
-        };
-        
-        if enc_ctx.is_null() {
-            return ERROR_IO_FAILED;
-        }
-        ctx.encryption_context = Some(enc_ctx);
-    }
-
-    // Get wrapped FEK
-    let wrapped_fek_len: usize = 0;
-    let wrapped_fek = unsafe { 
-        encrypt_file_get_wrapped_fek(
-            ctx.encryption_context.unwrap(),
-            &wrapped_fek_len as *const usize as *mut usize,
-        )
+    let preamble = match ctx.preamble() {
+        Ok(p) => p,
+        Err(code) => return checked(code),
     };
-
-    if wrapped_fek.is_null() {
-        return ERROR_IO_FAILED;
+    let (header, fek) = preamble.split_at(HEADER_SIZE);
+    unsafe { *fek_len = fek.len(); }
+    if fek.len() > fek_buffer_size {
+        return ERROR_OUTPUT_TOO_SMALL;
     }
+    ctx.header_emitted = true;
 
     unsafe {
-        // Write header: magic (4) + version (1) + reserved (3) + fek_len (4)
-        const MAGIC: u32 = 0x434E4552; // "CNER"
-        const VERSION: u8 = 1;
-        
-        let magic_bytes = MAGIC.to_le_bytes();
-        let fek_len_bytes = (wrapped_fek_len as u32).to_le_bytes();
-        
-        ptr::copy_nonoverlapping(magic_bytes.as_ptr(), header_buffer, 4);
-        header_buffer.add(4).write(VERSION);
-        header_buffer.add(5).write(0);
-        header_buffer.add(6).write(0);
-        header_buffer.add(7).write(0);
-        ptr::copy_nonoverlapping(fek_len_bytes.as_ptr(), header_buffer.add(8), 4);
-
-        // Copy wrapped FEK
-        if wrapped_fek_len <= fek_buffer_size {
-            ptr::copy_nonoverlapping(wrapped_fek, fek_buffer, wrapped_fek_len);
-        }
-        *fek_len = wrapped_fek_len;
+        ptr::copy_nonoverlapping(header.as_ptr(), header_buffer, header.len());
+        ptr::copy_nonoverlapping(fek.as_ptr(), fek_buffer, fek.len());
     }
 
-    unsafe { libc::free(wrapped_fek as *mut c_void); }
-
     SUCCESS
 }
 
@@ -393,9 +1088,17 @@ pub extern "C" fn upload_finalize(context: *mut UploadContext) -> i32 {
             let _ = Box::from_raw(ctx.input_file);
         }
         ctx.input_file = ptr::null_mut();
+        ctx.input_permit = None;
     }
+    ctx.discard_stripped_temp();
 
+    if !ctx.is_finalized && ctx.bytes_read >= ctx.total_bytes {
+        metrics::count(Counter::UploadsCompleted, 1);
+        metrics::count(Counter::UploadBytes, ctx.bytes_read as u64);
+    }
     ctx.is_finalized = true;
+    ctx.operation.set_files_done(1);
+    ctx.operation.complete();
 
     SUCCESS
 }
@@ -418,6 +1121,7 @@ pub extern "C" fn upload_free(context: *mut UploadContext) {
                     if !ctx.input_file.is_null() {
                         let _ = Box::from_raw(ctx.input_file);
                     }
+                    ctx.discard_stripped_temp();
                 }
             }
             let _ = Box::from_raw(context);
@@ -437,7 +1141,8 @@ pub extern "C" fn upload_get_total_bytes(context: *mut UploadContext) -> usize {
     if context.is_null() {
         return 0;
     }
-    unsafe { (&*context).total_bytes }
+    let ctx = unsafe { &*context };
+    ctx.callback_guard.read(ctx.progress()).total_bytes
 }
 
 /// Get bytes processed
@@ -452,5 +1157,735 @@ pub extern "C" fn upload_get_bytes_processed(context: *mut UploadContext) -> usi
     if context.is_null() {
         return 0;
     }
-    unsafe { (&*context).bytes_read }
+    let ctx = unsafe { &*context };
+    ctx.callback_guard.read(ctx.progress()).bytes_processed
+}
+
+/// Get the active operation id of an upload
+///
+/// # Arguments
+/// * `context` - Pointer to UploadContext
+///
+/// # Returns
+/// Operation id for get_operation_progress and cancel_operation, or 0 if invalid
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_get_operation_id(context: *mut UploadContext) -> u64 {
+    if context.is_null() {
+        return 0;
+    }
+    unsafe { (&*context).operation.id() }
+}
+
+/// Limit the upload speed of this context
+///
+/// Combined with the speed governor (see set_speed_governor), the lower speed wins.
+///
+/// # Arguments
+/// * `context` - Pointer to UploadContext
+/// * `bytes_per_sec` - Maximum speed in bytes per second (0 for unlimited)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_set_rate_limit(context: *mut UploadContext, bytes_per_sec: u64) {
+    if !context.is_null() {
+        unsafe { (&mut *context).pacer.set_rate_limit(bytes_per_sec); }
+    }
+}
+
+/// Read the local file through a memory map instead of buffered reads
+///
+/// Must be called before the first upload_process_chunk. The map is skipped for
+/// network and removable volumes and abandoned if the file changes size; the
+/// emitted chunks are identical either way.
+///
+/// # Arguments
+/// * `context` - Pointer to UploadContext
+/// * `use_mmap` - 1 to memory-map the file when possible, 0 for buffered reads
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_set_use_mmap(context: *mut UploadContext, use_mmap: u8) {
+    if !context.is_null() {
+        unsafe { (&mut *context).use_mmap = use_mmap != 0; }
+    }
+}
+
+/// Store a CRC32C of every encrypted chunk in its chunk header
+///
+/// Must be called before upload_get_header and the first upload_process_chunk.
+/// The CRCs let verify_container_crc locate corrupted chunks without the key.
+/// Has no effect on unencrypted uploads.
+///
+/// # Arguments
+/// * `context` - Pointer to UploadContext
+/// * `enabled` - 1 to add chunk CRCs, 0 to disable
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_set_chunk_crc(context: *mut UploadContext, enabled: u8) {
+    if !context.is_null() {
+        unsafe { (&mut *context).chunk_crc = enabled != 0; }
+    }
+}
+
+/// Strip image metadata from the file before it is uploaded
+///
+/// JPEG and PNG files are copied to a temp file with their metadata removed (see
+/// strip_image_metadata) and the copy is streamed instead; other files are
+/// uploaded unchanged. Must be called before upload_get_total_bytes and the first
+/// upload_process_chunk. The temp file is deleted by upload_finalize or upload_free.
+///
+/// # Arguments
+/// * `context` - Pointer to UploadContext
+/// * `policy` - STRIP_METADATA_NONE, STRIP_METADATA_GPS_ONLY or STRIP_METADATA_ALL_EXIF
+///
+/// # Returns
+/// 0 on success, ERROR_INVALID_PATH if the upload reads from a descriptor, has
+/// already started or was already stripped, ERROR_IMAGE_PROCESSING_FAILED if the
+/// image is corrupt, or another error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_set_strip_metadata(context: *mut UploadContext, policy: i32) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+    let ctx = unsafe { &mut *context };
+
+    if !is_valid_strip_policy(policy) {
+        return ERROR_INVALID_STRIP_POLICY;
+    }
+    if !ctx.input_file.is_null() || ctx.bytes_read > 0 || ctx.stripped_temp.is_some() {
+        return ERROR_INVALID_PATH;
+    }
+    if policy == STRIP_METADATA_NONE {
+        return SUCCESS;
+    }
+
+    let temp_path = match create_temp_file(&std::env::temp_dir(), "upload-strip") {
+        Ok((path, _)) => path,
+        Err(_) => return ERROR_IO_FAILED,
+    };
+    let stripped_len = strip_image_file(&ctx.file_path, &temp_path, policy).and_then(|stripped| {
+        if !stripped {
+            return Ok(None);
+        }
+        std::fs::metadata(&temp_path).map(|m| Some(m.len())).map_err(|_| ERROR_IO_FAILED)
+    });
+    let total_bytes = match stripped_len {
+        Ok(Some(len)) => len,
+        Ok(None) => {
+            // Not an image: stream the original file
+            let _ = discard_temp_file(&temp_path);
+            return SUCCESS;
+        }
+        Err(code) => {
+            let _ = discard_temp_file(&temp_path);
+            return checked(code);
+        }
+    };
+
+    ctx.file_path = temp_path.clone();
+    ctx.stripped_temp = Some(temp_path);
+    ctx.total_bytes = total_bytes as usize;
+    ctx.operation.set_total_bytes(total_bytes);
+    SUCCESS
+}
+
+/// Keep uploading data appended to the source while it is read
+///
+/// Without it, an upload whose source grows or shrinks fails with
+/// ERROR_SOURCE_CHANGED instead of silently sending a truncated or stale file.
+/// With it, a source that grew is read to its new end: upload_get_total_bytes
+/// and the progress callbacks report the corrected total, which is the final
+/// size once upload_process_chunk returns 0. A shrinking source always fails.
+///
+/// # Arguments
+/// * `context` - Pointer to UploadContext
+/// * `tolerate_growth` - 1 to upload appended data, 0 to fail on any size change
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_set_tolerate_growth(context: *mut UploadContext, tolerate_growth: u8) {
+    if !context.is_null() {
+        unsafe { (&mut *context).tolerate_growth = tolerate_growth != 0; }
+    }
+}
+
+/// Emit base64 text instead of binary data
+///
+/// Every payload handed to the data callback (header, chunks and dedup reference
+/// records) and written to the chunk buffer is the padded base64 encoding of the
+/// bytes it would otherwise carry. Chunks are encoded straight into the chunk
+/// buffer, which must hold base64_encoded_len of the encrypted chunk; a chunk
+/// that doesn't fit is not emitted, as with binary output. Progress and the
+/// return value of upload_process_chunk still count plaintext bytes.
+///
+/// # Arguments
+/// * `context` - Pointer to UploadContext
+/// * `emit_base64` - 1 to emit base64, 0 for binary
+/// * `url_safe` - 1 for the URL-safe alphabet ("-_"), 0 for the standard one ("+/")
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_set_emit_base64(context: *mut UploadContext, emit_base64: i32, url_safe: i32) {
+    if !context.is_null() {
+        unsafe { (&mut *context).emit_base64 = (emit_base64 != 0).then_some(url_safe != 0); }
+    }
+}
+
+/// Emit upload output in blocks aligned to a provider's chunk granularity
+///
+/// Resumable upload sessions take chunks in multiples of a fixed size (256 KiB
+/// for Google Drive, 320 KiB for OneDrive), which encrypted chunks never are.
+/// With an alignment, upload_process_chunk still encrypts chunk_size plaintext
+/// bytes per call, but queues the output and writes to the buffer (and hands
+/// to the data callback) only blocks whose size is a multiple of
+/// `alignment_bytes`, as many whole multiples as are queued and fit the buffer.
+/// The container header goes first into the aligned stream unless
+/// upload_get_header already handed it out. The data callback's chunk index
+/// counts aligned blocks. Once upload_process_chunk returns 0, drain the rest
+/// with upload_flush_aligned.
+///
+/// # Arguments
+/// * `context` - Pointer to UploadContext
+/// * `alignment_bytes` - Block granularity in bytes (0 to emit each chunk as is, the default)
+///
+/// # Returns
+/// 0 on success, ERROR_INVALID_PATH if chunks were already processed, other
+/// error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_set_output_alignment(context: *mut UploadContext, alignment_bytes: u32) -> i32 {
+    if context.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+    let ctx = unsafe { &mut *context };
+
+    if ctx.chunk_index > 0 || !ctx.aligned_pending.is_empty() {
+        return ERROR_INVALID_PATH;
+    }
+    ctx.output_alignment = alignment_bytes as usize;
+    SUCCESS
+}
+
+/// Drain aligned upload output queued by upload_process_chunk
+///
+/// Call repeatedly after upload_process_chunk returned 0 until it returns 0.
+/// Every block is a multiple of the alignment except the final one, which
+/// holds whatever is left once it fits the buffer. Before the source has been
+/// read completely, only whole multiples are drained.
+///
+/// # Arguments
+/// * `context` - Pointer to UploadContext
+/// * `buffer` - Buffer receiving the block
+/// * `buffer_size` - Size of buffer (at least the alignment)
+///
+/// # Returns
+/// Number of bytes written to buffer (0 when nothing is left), or negative
+/// error code (ERROR_OUTPUT_TOO_SMALL if the buffer is smaller than the alignment)
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_flush_aligned(context: *mut UploadContext, buffer: *mut u8, buffer_size: usize) -> isize {
+    if context.is_null() || buffer.is_null() {
+        return ERROR_NULL_POINTER as isize;
+    }
+    let ctx = unsafe { &mut *context };
+
+    if ctx.output_alignment == 0 {
+        return 0;
+    }
+    if buffer_size < ctx.output_alignment {
+        return ERROR_OUTPUT_TOO_SMALL as isize;
+    }
+
+    let finished = ctx.bytes_read >= ctx.total_bytes && (ctx.chunk_index > 0 || !ctx.is_encrypting());
+    ctx.take_aligned(buffer, buffer_size, finished) as isize
+}
+
+/// Report this upload's progress into the polled event stream
+///
+/// Progress records go to drain_progress_events_json in addition to the
+/// progress callback (pass no callback to use the stream instead). upload_finalize pushes the terminal "completed" record; freeing an unfinished upload pushes "failed" or "cancelled".
+///
+/// # Arguments
+/// * `context` - Pointer to UploadContext
+/// * `use_event_stream` - 1 to push progress events, 0 to stop
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_set_use_event_stream(context: *mut UploadContext, use_event_stream: u8) {
+    if !context.is_null() {
+        unsafe { (&*context).operation.set_use_event_stream(use_event_stream != 0); }
+    }
+}
+
+/// Version of the upload session JSON
+pub const UPLOAD_SESSION_VERSION: u32 = 1;
+
+/// Everything needed to resume a paused upload in a later process
+///
+/// Exported by upload_export_session_json and read back by
+/// upload_restore_session_json. No raw key material appears in it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub version: u32,
+    /// Source file, reopened on restore
+    pub file_path: String,
+    /// Size and modification time of the source when the upload started
+    pub file_size: u64,
+    pub file_mtime_ms: u64,
+    /// Plaintext bytes handed to the data callback so far
+    pub bytes_confirmed: u64,
+    /// Index of the next chunk
+    pub chunk_index: u32,
+    pub chunk_size: usize,
+    pub chunk_crc: bool,
+    /// The container header was already handed out
+    pub header_emitted: bool,
+    /// Container header followed by the wrapped FEK, base64 (absent for plain uploads)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+    /// Encryption context sealed with the master key, base64 (absent for plain uploads)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_state: Option<String>,
+}
+
+/// Modification time in milliseconds since the epoch (0 where unavailable)
+fn mtime_ms(metadata: &std::fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn export_session(ctx: &mut UploadContext, master_key: &[u8]) -> Result<UploadSession, ErrorEnvelope> {
+    if ctx.is_missing_key() {
+        return Err(ErrorEnvelope::new(ERROR_MASTER_KEY_REQUIRED, "upload has no master key"));
+    }
+    if ctx.file_path.as_os_str().is_empty() || ctx.stripped_temp.is_some() {
+        return Err(ErrorEnvelope::new(ERROR_RESULT_UNAVAILABLE,
+                                      "upload does not read from a file path that can be reopened"));
+    }
+    if !ctx.aligned_pending.is_empty() {
+        return Err(ErrorEnvelope::new(ERROR_RESULT_UNAVAILABLE, "upload has aligned output waiting to be flushed"));
+    }
+    let file_mtime_ms = match ctx.source_mtime_ms {
+        Some(mtime) => mtime,
+        None => std::fs::metadata(&ctx.file_path).map(|m| mtime_ms(&m)).unwrap_or(0),
+    };
+
+    let (mut header, mut encryption_state) = (None, None);
+    if ctx.is_encrypting() {
+        if master_key.is_empty() {
+            return Err(ErrorEnvelope::new(ERROR_MASTER_KEY_REQUIRED, "the upload's master key is required")
+                .with_context("master_key"));
+        }
+        let preamble = ctx.preamble().map_err(|code| ErrorEnvelope::new(code, "encryption context unavailable"))?;
+        let enc_ctx = ctx.encryption_context().map_err(|code| ErrorEnvelope::new(code, "encryption context unavailable"))?;
+        let sealed = unsafe { &*enc_ctx }.export_sealed(master_key).map_err(|code| {
+            ErrorEnvelope::new(code, "master_key is not the key of this upload").with_context("master_key")
+        })?;
+        header = Some(base64_engine(false).encode_to_string(&preamble));
+        encryption_state = Some(base64_engine(false).encode_to_string(&sealed));
+    }
+
+    Ok(UploadSession {
+        version: UPLOAD_SESSION_VERSION,
+        file_path: ctx.file_path.to_string_lossy().to_string(),
+        file_size: ctx.total_bytes as u64,
+        file_mtime_ms,
+        bytes_confirmed: ctx.bytes_read as u64,
+        chunk_index: ctx.chunk_index,
+        chunk_size: ctx.chunk_size,
+        chunk_crc: ctx.chunk_crc,
+        header_emitted: ctx.header_emitted,
+        header,
+        encryption_state,
+    })
+}
+
+/// Export what is needed to resume a paused upload later
+///
+/// Call between chunks, e.g. when the user pauses the upload. The session
+/// records the bytes confirmed so far, the next chunk index, the container
+/// header and wrapped FEK, and the encryption context sealed with the master
+/// key, so no raw FEK appears in it. Fingerprint callbacks and output
+/// settings (base64, alignment, rate limit) are not part of the session and
+/// must be set again after restoring.
+///
+/// # Arguments
+/// * `context` - Pointer to UploadContext
+/// * `master_key` - The upload's 32-byte master key (can be null for plain uploads)
+/// * `key_len` - Length of master key
+/// * `out_len` - Receives the JSON length in bytes (can be null)
+///
+/// # Returns
+/// A JSON envelope (see ffi_util.rs) whose data is the UploadSession. Fails with
+/// ERROR_MASTER_KEY_REQUIRED or ERROR_DECRYPTION_FAILED when the key does not
+/// belong to an encrypted upload, and ERROR_RESULT_UNAVAILABLE for uploads from
+/// a descriptor or stripped copy, which cannot be reopened. Must be freed with
+/// scan_folder_free_string
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_export_session_json(
+    context: *mut UploadContext,
+    master_key: *const u8,
+    key_len: usize,
+    out_len: *mut usize,
+) -> *mut c_char {
+    let session = (|| {
+        if context.is_null() {
+            return Err(ErrorEnvelope::null_argument("context"));
+        }
+        let key = if master_key.is_null() { &[][..] } else { unsafe { slice::from_raw_parts(master_key, key_len) } };
+        export_session(unsafe { &mut *context }, key)
+    })();
+    json_envelope(session, out_len)
+}
+
+fn restore_session(
+    json: &str,
+    master_key: *const u8,
+    key_len: usize,
+    cancel_flag: *const AtomicBool,
+) -> Result<Box<UploadContext>, ErrorEnvelope> {
+    let mut value: serde_json::Value =
+        serde_json::from_str(json).map_err(|e| ErrorEnvelope::invalid_json("json", e))?;
+    // Accept the envelope upload_export_session_json returns as well as its data
+    if value.get("ok").is_some() {
+        value = value["data"].take();
+    }
+    let session: UploadSession = serde_json::from_value(value).map_err(|e| ErrorEnvelope::invalid_json("json", e))?;
+    if session.version != UPLOAD_SESSION_VERSION {
+        return Err(ErrorEnvelope::new(ERROR_UNSUPPORTED_VERSION,
+                                      format!("upload session version {} is not supported", session.version)));
+    }
+    if session.bytes_confirmed > session.file_size {
+        return Err(ErrorEnvelope::new(ERROR_INVALID_JSON, "bytes_confirmed exceeds file_size").with_context("json"));
+    }
+    check_chunk_size(session.chunk_size)?;
+
+    let mode = if session.encryption_state.is_some() { ENCRYPTION_MODE_REQUIRED } else { ENCRYPTION_MODE_NONE };
+    let key = master_key_for_mode(master_key, key_len, mode)
+        .map_err(|code| ErrorEnvelope::new(code, "the upload's master key is required").with_context("master_key"))?;
+
+    let path = PathBuf::from(&session.file_path);
+    let (mut file, permit) = open_limited(|| File::open(&path), ERROR_FILE_NOT_FOUND)
+        .map_err(|code| {
+            let message = if code == ERROR_FILE_NOT_FOUND { "source file is gone" } else { "source file cannot be opened" };
+            ErrorEnvelope::new(code, message).with_context(&session.file_path)
+        })?;
+    let metadata = file.metadata()
+        .map_err(|_| ErrorEnvelope::new(ERROR_IO_FAILED, "source file cannot be read").with_context(&session.file_path))?;
+    if metadata.len() != session.file_size || mtime_ms(&metadata) != session.file_mtime_ms {
+        return Err(ErrorEnvelope::new(ERROR_SOURCE_CHANGED, "source file changed since the session was exported")
+            .with_context(&session.file_path));
+    }
+    file.seek(SeekFrom::Start(session.bytes_confirmed))
+        .map_err(|_| ErrorEnvelope::new(ERROR_IO_FAILED, "source file cannot be read").with_context(&session.file_path))?;
+
+    let encryption_context = match &session.encryption_state {
+        Some(state) => {
+            let sealed = decode_base64(state.as_bytes())
+                .map_err(|code| ErrorEnvelope::new(code, "encryption_state is not base64").with_context("json"))?;
+            let enc_ctx = EncryptionContext::import_sealed(&sealed, &key).map_err(|code| {
+                ErrorEnvelope::new(code, "encryption_state cannot be opened with this master key")
+                    .with_context("master_key")
+            })?;
+            Some(Box::new(enc_ctx))
+        }
+        None => None,
+    };
+
+    let should_encrypt = !key.is_empty();
+    let mut context = Box::new(UploadContext::new(path, session.file_size as usize, should_encrypt, key, cancel_flag));
+    context.set_chunk_size(session.chunk_size);
+    context.chunk_crc = session.chunk_crc;
+    context.encryption_context = encryption_context.map(Box::into_raw);
+    context.input_file = Box::into_raw(Box::new(SourceReader::Buffered(BufReader::new(file))));
+    context.input_permit = Some(permit);
+    context.bytes_read = session.bytes_confirmed as usize;
+    context.chunk_index = session.chunk_index;
+    context.header_emitted = session.header_emitted;
+    context.source_mtime_ms = Some(session.file_mtime_ms);
+    context.operation.set_bytes_done(session.bytes_confirmed);
+    Ok(context)
+}
+
+/// Resume an upload from a session exported by upload_export_session_json
+///
+/// Reopens the source, checks that its size and modification time still match
+/// the session, seeks to the bytes confirmed, and restores the counters and the
+/// encryption context, so the next upload_process_chunk continues with the next
+/// chunk under the same FEK. On failure the reason is recorded as the thread's
+/// last error (see get_last_error_json).
+///
+/// # Arguments
+/// * `json` - The session JSON (its envelope or the UploadSession in its data)
+/// * `master_key` - The upload's 32-byte master key (can be null for plain uploads)
+/// * `key_len` - Length of master key
+/// * `cancel_flag` - Pointer to atomic bool for cancellation (can be null)
+///
+/// # Returns
+/// Pointer to UploadContext (free with upload_free), or null on error: the last
+/// error is ERROR_SOURCE_CHANGED if the file was modified, ERROR_FILE_NOT_FOUND
+/// if it is gone, ERROR_MASTER_KEY_REQUIRED or ERROR_DECRYPTION_FAILED for a
+/// missing or wrong key, ERROR_INVALID_JSON for a malformed session
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn upload_restore_session_json(
+    json: *const c_char,
+    master_key: *const u8,
+    key_len: usize,
+    cancel_flag: *const AtomicBool,
+) -> *mut UploadContext {
+    let json = match unsafe { ffi_str_in(json, "json") } {
+        Ok(json) => json,
+        Err(_) => return ptr::null_mut(),
+    };
+    match restore_session(json, master_key, key_len, cancel_flag) {
+        Ok(context) => Box::leak(context) as *mut UploadContext,
+        Err(error) => {
+            set_last_error_detail(error);
+            ptr::null_mut()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    extern "C" fn collect_block(data: *const u8, data_len: usize, _chunk_index: u32, user_data: *mut c_void) {
+        let blocks = unsafe { &mut *(user_data as *mut Vec<Vec<u8>>) };
+        blocks.push(unsafe { slice::from_raw_parts(data, data_len) }.to_vec());
+    }
+
+    #[test]
+    fn test_upload_output_alignment() {
+        let path = std::env::temp_dir().join(format!("cloud_nexus_upload_aligned_{}", std::process::id()));
+        let data: Vec<u8> = (0..1_500_000u32).map(|i| (i * 13 % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let path_c = CString::new(path.to_str().unwrap()).unwrap();
+        let key = [5u8; 32];
+        let alignment = 320 * 1024;
+        let mut buffer = vec![0u8; 1024 * 1024];
+
+        let ctx = upload_init(path_c.as_ptr(), key.as_ptr(), key.len(), 64 * 1024, 1, None, None, ptr::null(), ptr::null_mut());
+        assert_eq!(upload_set_output_alignment(ctx, alignment as u32), SUCCESS);
+
+        // The buffer has to hold a whole block
+        let mut small = vec![0u8; alignment - 1];
+        assert_eq!(upload_process_chunk(ctx, small.as_mut_ptr(), small.len(), None, None, ptr::null_mut()), ERROR_OUTPUT_TOO_SMALL as isize);
+
+        let mut blocks: Vec<Vec<u8>> = Vec::new();
+        let user_data = &mut blocks as *mut Vec<Vec<u8>> as *mut c_void;
+        let mut plain = 0usize;
+        loop {
+            let n = upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_block), user_data);
+            assert!(n >= 0);
+            if n == 0 {
+                break;
+            }
+            plain += n as usize;
+        }
+        assert_eq!(plain, data.len());
+        assert_eq!(upload_set_output_alignment(ctx, 0), ERROR_INVALID_PATH);
+
+        loop {
+            let n = upload_flush_aligned(ctx, buffer.as_mut_ptr(), buffer.len());
+            assert!(n >= 0);
+            if n == 0 {
+                break;
+            }
+            blocks.push(buffer[..n as usize].to_vec());
+        }
+        upload_free(ctx);
+
+        assert!(blocks.len() > 1);
+        for block in &blocks[..blocks.len() - 1] {
+            assert_eq!(block.len() % alignment, 0);
+        }
+
+        let container: Vec<u8> = blocks.concat();
+        let mut plain_len = 0usize;
+        let decrypted = crate::decrypt_file(container.as_ptr(), container.len(), key.as_ptr(), key.len(), &mut plain_len);
+        assert!(!decrypted.is_null());
+        assert_eq!(unsafe { slice::from_raw_parts(decrypted, plain_len) }, &data[..]);
+        crate::free_buffer(decrypted);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Upload a reentrant callback calls back into
+    struct Reentry {
+        ctx: *mut UploadContext,
+        operation_id: u64,
+        /// Callback next to what upload_get_bytes_processed returned during it
+        seen: Vec<(&'static str, usize)>,
+    }
+
+    fn reentry<'a>(user_data: *mut c_void) -> &'a mut Reentry {
+        unsafe { &mut *(user_data as *mut Reentry) }
+    }
+
+    extern "C" fn reentrant_fingerprint(_chunk_index: u32, _hash: *const u8, user_data: *mut c_void) -> i32 {
+        let reentry = reentry(user_data);
+        reentry.seen.push(("fingerprint", upload_get_bytes_processed(reentry.ctx)));
+        crate::dedup::CHUNK_FINGERPRINT_UPLOAD
+    }
+
+    extern "C" fn reentrant_data(_data: *const u8, _data_len: usize, _chunk_index: u32, user_data: *mut c_void) {
+        let reentry = reentry(user_data);
+        reentry.seen.push(("data", upload_get_bytes_processed(reentry.ctx)));
+    }
+
+    extern "C" fn reentrant_progress(bytes_processed: usize, total_bytes: usize, user_data: *mut c_void) {
+        let reentry = reentry(user_data);
+        assert_eq!(upload_get_total_bytes(reentry.ctx), total_bytes);
+        reentry.seen.push(("progress", upload_get_bytes_processed(reentry.ctx)));
+        assert_eq!(reentry.seen.last().unwrap().1, bytes_processed);
+        assert_eq!(crate::operations::cancel_operation(reentry.operation_id), SUCCESS);
+    }
+
+    #[test]
+    fn test_callbacks_reenter_and_cancel() {
+        let path = std::env::temp_dir().join(format!("cloud_nexus_upload_reentry_{}", std::process::id()));
+        std::fs::write(&path, vec![3u8; 3 * 64 * 1024]).unwrap();
+        let path_c = CString::new(path.to_str().unwrap()).unwrap();
+        let mut reentry = Reentry { ctx: ptr::null_mut(), operation_id: 0, seen: Vec::new() };
+        let user_data = &mut reentry as *mut Reentry as *mut c_void;
+
+        let ctx = upload_init_ex(path_c.as_ptr(), ptr::null(), 0, 64 * 1024, 0, Some(reentrant_fingerprint), user_data,
+                                 ptr::null());
+        assert!(!ctx.is_null());
+        unsafe { (*ctx).progress_throttler = ProgressThrottler::with_percent_delta(1); }
+        reentry.ctx = ctx;
+        reentry.operation_id = upload_get_operation_id(ctx);
+
+        // Every callback reads progress; the progress callback cancels and the next chunk fails
+        let mut buffer = vec![0u8; 64 * 1024];
+        let n = upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), Some(reentrant_progress), Some(reentrant_data),
+                                     user_data);
+        assert_eq!(n, 64 * 1024);
+        assert_eq!(reentry.seen, vec![("fingerprint", 0), ("data", 0), ("progress", 64 * 1024)]);
+        assert_eq!(upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), Some(reentrant_progress),
+                                        Some(reentrant_data), user_data), ERROR_CANCELLED as isize);
+        assert_eq!(reentry.seen.len(), 3);
+        upload_free(ctx);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    fn drain(ctx: *mut UploadContext, chunks: Option<usize>, blocks: &mut Vec<Vec<u8>>) {
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let user_data = blocks as *mut Vec<Vec<u8>> as *mut c_void;
+        for _ in 0..chunks.unwrap_or(usize::MAX) {
+            let n = upload_process_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, Some(collect_block), user_data);
+            assert!(n >= 0);
+            if n == 0 {
+                break;
+            }
+        }
+    }
+
+    fn export(ctx: *mut UploadContext, key: &[u8]) -> serde_json::Value {
+        let json = unsafe { CString::from_raw(upload_export_session_json(ctx, key.as_ptr(), key.len(), ptr::null_mut())) };
+        serde_json::from_str(json.to_str().unwrap()).unwrap()
+    }
+
+    fn last_error_code() -> i64 {
+        let json = unsafe { CString::from_raw(crate::ffi_util::get_last_error_json(ptr::null_mut())) };
+        let envelope: serde_json::Value = serde_json::from_str(json.to_str().unwrap()).unwrap();
+        envelope["data"]["code"].as_i64().unwrap()
+    }
+
+    #[test]
+    fn test_upload_session_export_and_restore() {
+        let path = std::env::temp_dir().join(format!("cloud_nexus_upload_session_{}", std::process::id()));
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 253) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let path_c = CString::new(path.to_str().unwrap()).unwrap();
+        let key = [9u8; 32];
+
+        // Pause after two chunks
+        let ctx = upload_init(path_c.as_ptr(), key.as_ptr(), key.len(), 64 * 1024, 1, None, None, ptr::null(), ptr::null_mut());
+        let mut blocks: Vec<Vec<u8>> = Vec::new();
+        drain(ctx, Some(2), &mut blocks);
+        let wrong_key = [1u8; 32];
+        assert_eq!(export(ctx, &wrong_key)["error"]["code"], crate::ERROR_DECRYPTION_FAILED);
+        let envelope = export(ctx, &key);
+        upload_free(ctx);
+
+        let session = &envelope["data"];
+        assert_eq!(session["bytes_confirmed"], 128 * 1024);
+        assert_eq!(session["chunk_index"], 2);
+        assert_eq!(session["header_emitted"], true);
+        let header = decode_base64(session["header"].as_str().unwrap().as_bytes()).unwrap();
+        assert_eq!(header, blocks[0]);
+        // Only the sealed state travels, never the FEK
+        assert!(session.get("fek").is_none());
+        assert!(session["encryption_state"].is_string());
+
+        let session_c = CString::new(envelope.to_string()).unwrap();
+        assert!(upload_restore_session_json(session_c.as_ptr(), wrong_key.as_ptr(), wrong_key.len(), ptr::null()).is_null());
+        assert_eq!(last_error_code(), crate::ERROR_DECRYPTION_FAILED as i64);
+        assert!(upload_restore_session_json(session_c.as_ptr(), ptr::null(), 0, ptr::null()).is_null());
+        assert_eq!(last_error_code(), ERROR_MASTER_KEY_REQUIRED as i64);
+        let mut bad_chunk = envelope.clone();
+        bad_chunk["data"]["chunk_size"] = (MAX_UPLOAD_CHUNK_SIZE + 1).into();
+        let bad_chunk_c = CString::new(bad_chunk.to_string()).unwrap();
+        assert!(upload_restore_session_json(bad_chunk_c.as_ptr(), key.as_ptr(), key.len(), ptr::null()).is_null());
+        assert_eq!(last_error_code(), ERROR_INVALID_CHUNK_SIZE as i64);
+
+        // Resume in a new context and finish the container
+        let ctx = upload_restore_session_json(session_c.as_ptr(), key.as_ptr(), key.len(), ptr::null());
+        assert!(!ctx.is_null());
+        assert_eq!(upload_get_bytes_processed(ctx), 128 * 1024);
+        drain(ctx, None, &mut blocks);
+        assert_eq!(upload_finalize(ctx), SUCCESS);
+        upload_free(ctx);
+
+        let container: Vec<u8> = blocks.concat();
+        let mut plain_len = 0usize;
+        let decrypted = crate::decrypt_file(container.as_ptr(), container.len(), key.as_ptr(), key.len(), &mut plain_len);
+        assert!(!decrypted.is_null());
+        assert_eq!(unsafe { slice::from_raw_parts(decrypted, plain_len) }, &data[..]);
+        crate::free_buffer(decrypted);
+
+        // A source rewritten in place, even at the same size, cannot be resumed
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60)).unwrap();
+        drop(file);
+        assert!(upload_restore_session_json(session_c.as_ptr(), key.as_ptr(), key.len(), ptr::null()).is_null());
+        assert_eq!(last_error_code(), ERROR_SOURCE_CHANGED as i64);
+
+        std::fs::write(&path, &data[..1000]).unwrap();
+        assert!(upload_restore_session_json(session_c.as_ptr(), key.as_ptr(), key.len(), ptr::null()).is_null());
+        assert_eq!(last_error_code(), ERROR_SOURCE_CHANGED as i64);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_plain_upload_session_round_trip() {
+        let path = std::env::temp_dir().join(format!("cloud_nexus_upload_session_plain_{}", std::process::id()));
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 241) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let path_c = CString::new(path.to_str().unwrap()).unwrap();
+
+        let ctx = upload_init(path_c.as_ptr(), ptr::null(), 0, 64 * 1024, 0, None, None, ptr::null(), ptr::null_mut());
+        let mut blocks: Vec<Vec<u8>> = Vec::new();
+        drain(ctx, Some(1), &mut blocks);
+        let envelope = export(ctx, &[]);
+        upload_free(ctx);
+        assert!(envelope["data"].get("encryption_state").is_none());
+
+        let session_c = CString::new(envelope["data"].to_string()).unwrap();
+        let ctx = upload_restore_session_json(session_c.as_ptr(), ptr::null(), 0, ptr::null());
+        assert!(!ctx.is_null());
+        drain(ctx, None, &mut blocks);
+        upload_free(ctx);
+        assert_eq!(blocks.concat(), data);
+
+        let _ = std::fs::remove_file(&path);
+    }
 }
@@ -0,0 +1,914 @@
+/// Master key vault for CloudNexus
+/// Stores the random 32-byte master key wrapped with a password-derived key
+/// (Argon2id by default, PBKDF2-HMAC-SHA256 as fallback)
+///
+/// Vault file layout (all integers little-endian):
+/// - magic "CNVK" (4), version (1), kdf id (1), reserved (2)
+/// - kdf memory KiB (4), kdf iterations (4), kdf parallelism (4)
+/// - salt (16)
+/// - nonce (12) + AES-256-GCM wrapped master key and tag (48), authenticated with
+///   everything above as associated data
+/// - SHA-256 checksum of everything above (32), to tell corruption from a wrong password
+use std::ffi::{c_char, CStr, CString};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::time::{Duration, Instant};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use pbkdf2::pbkdf2_hmac;
+use crate::errors::checked;
+use crate::rng::fill_random;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::encryption::{KEY_SIZE, MAC_SIZE, NONCE_SIZE};
+use crate::file_io::{ERROR_NULL_POINTER, ERROR_FILE_NOT_FOUND, ERROR_INVALID_PATH, ERROR_IO_FAILED,
+                     SUCCESS, c_str_to_path};
+use crate::temp::{create_temp_file_for, commit_temp_file, discard_temp_file};
+
+pub use crate::errors::{ERROR_VAULT_WRONG_PASSWORD, ERROR_INVALID_VAULT, ERROR_VAULT_EXISTS};
+
+const VAULT_MAGIC: &[u8; 4] = b"CNVK";
+const VAULT_VERSION: u8 = 1;
+/// Argon2id, as stored in the vault header and passed to calibrate_kdf
+pub const KDF_ARGON2ID: u8 = 1;
+/// PBKDF2-HMAC-SHA256, as stored in the vault header and passed to calibrate_kdf
+pub const KDF_PBKDF2_SHA256: u8 = 2;
+const SALT_SIZE: usize = 16;
+const VAULT_HEADER_SIZE: usize = 4 + 1 + 1 + 2 + 4 + 4 + 4 + SALT_SIZE;
+const WRAPPED_KEY_SIZE: usize = NONCE_SIZE + KEY_SIZE + MAC_SIZE;
+const CHECKSUM_SIZE: usize = 32;
+const VAULT_FILE_SIZE: usize = VAULT_HEADER_SIZE + WRAPPED_KEY_SIZE + CHECKSUM_SIZE;
+
+// Argon2id defaults (OWASP minimum: 19 MiB, 2 passes, 1 lane)
+const DEFAULT_ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const DEFAULT_ARGON2_ITERATIONS: u32 = 2;
+const DEFAULT_ARGON2_PARALLELISM: u32 = 1;
+const DEFAULT_PBKDF2_ITERATIONS: u32 = 600_000;
+
+// Floors for new vaults (OWASP minimums); vaults written with weaker
+// parameters before these applied still open
+const MIN_ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const MIN_PBKDF2_ITERATIONS: u32 = 100_000;
+
+// Upper bounds so a crafted vault file cannot stall or exhaust the device on open
+const MAX_ARGON2_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ARGON2_ITERATIONS: u32 = 64;
+const MAX_ARGON2_PARALLELISM: u32 = 16;
+const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+// calibrate_kdf limits: total measuring time, the shortest probe worth timing,
+// and how many probes the binary search may run
+const CALIBRATION_BUDGET: Duration = Duration::from_millis(2000);
+const CALIBRATION_MIN_PROBE: Duration = Duration::from_millis(20);
+const CALIBRATION_MAX_STEPS: u32 = 8;
+// Calibrated parameters never go below the floors for new vaults, whatever the target
+const CALIBRATE_PBKDF2_MIN_ITERATIONS: u32 = MIN_PBKDF2_ITERATIONS;
+const CALIBRATE_ARGON2_MIN_MEMORY_KIB: u32 = MIN_ARGON2_MEMORY_KIB;
+// Calibrated Argon2id memory stops here (phones kill apps well before the vault
+// limit); a slower target adds passes instead
+const CALIBRATE_ARGON2_MAX_MEMORY_KIB: u32 = 64 * 1024;
+
+/// Key derivation function used to wrap the master key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VaultKdf {
+    Argon2id,
+    Pbkdf2,
+}
+
+/// KDF options accepted by vault_create, e.g.
+/// `{"kdf": "argon2id", "memory_kib": 19456, "iterations": 2, "parallelism": 1}`
+/// or `{"kdf": "pbkdf2", "iterations": 600000}`; omitted fields use defaults
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VaultKdfOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<VaultKdf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_kib: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iterations: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parallelism: Option<u32>,
+}
+
+/// KDF parameters as stored in the vault header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KdfParams {
+    kdf: VaultKdf,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+impl KdfParams {
+    fn from_options(options: &VaultKdfOptions) -> Result<Self, i32> {
+        let params = match options.kdf.unwrap_or(VaultKdf::Argon2id) {
+            VaultKdf::Argon2id => KdfParams {
+                kdf: VaultKdf::Argon2id,
+                memory_kib: options.memory_kib.unwrap_or(DEFAULT_ARGON2_MEMORY_KIB),
+                iterations: options.iterations.unwrap_or(DEFAULT_ARGON2_ITERATIONS),
+                parallelism: options.parallelism.unwrap_or(DEFAULT_ARGON2_PARALLELISM),
+            },
+            VaultKdf::Pbkdf2 => KdfParams {
+                kdf: VaultKdf::Pbkdf2,
+                memory_kib: 0,
+                iterations: options.iterations.unwrap_or(DEFAULT_PBKDF2_ITERATIONS),
+                parallelism: 0,
+            },
+        };
+        params.validate()?;
+        Ok(params)
+    }
+
+    /// Parameters costing about `work` units, clamped to the calibration range
+    ///
+    /// PBKDF2 work is its iteration count; Argon2id work is memory KiB times
+    /// passes, spent on memory first and on extra passes past the memory cap.
+    fn for_work(kdf: VaultKdf, work: u64) -> Self {
+        match kdf {
+            VaultKdf::Argon2id => {
+                let iterations = work
+                    .div_ceil(CALIBRATE_ARGON2_MAX_MEMORY_KIB as u64)
+                    .clamp(DEFAULT_ARGON2_ITERATIONS as u64, MAX_ARGON2_ITERATIONS as u64);
+                let memory_kib = (work / iterations)
+                    .clamp(CALIBRATE_ARGON2_MIN_MEMORY_KIB as u64, CALIBRATE_ARGON2_MAX_MEMORY_KIB as u64);
+                KdfParams {
+                    kdf,
+                    memory_kib: memory_kib as u32,
+                    iterations: iterations as u32,
+                    parallelism: DEFAULT_ARGON2_PARALLELISM,
+                }
+            }
+            VaultKdf::Pbkdf2 => KdfParams {
+                kdf,
+                memory_kib: 0,
+                iterations: work.clamp(CALIBRATE_PBKDF2_MIN_ITERATIONS as u64, MAX_PBKDF2_ITERATIONS as u64) as u32,
+                parallelism: 0,
+            },
+        }
+    }
+
+    /// Cost in the units of for_work
+    fn work(&self) -> u64 {
+        match self.kdf {
+            VaultKdf::Argon2id => self.memory_kib as u64 * self.iterations as u64,
+            VaultKdf::Pbkdf2 => self.iterations as u64,
+        }
+    }
+
+    /// Options JSON accepted by vault_create
+    fn to_options(self) -> VaultKdfOptions {
+        match self.kdf {
+            VaultKdf::Argon2id => VaultKdfOptions {
+                kdf: Some(self.kdf),
+                memory_kib: Some(self.memory_kib),
+                iterations: Some(self.iterations),
+                parallelism: Some(self.parallelism),
+            },
+            VaultKdf::Pbkdf2 => VaultKdfOptions {
+                kdf: Some(self.kdf),
+                iterations: Some(self.iterations),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn validate(&self) -> Result<(), i32> {
+        let valid = match self.kdf {
+            VaultKdf::Argon2id => {
+                self.memory_kib <= MAX_ARGON2_MEMORY_KIB
+                    && (1..=MAX_ARGON2_ITERATIONS).contains(&self.iterations)
+                    && (1..=MAX_ARGON2_PARALLELISM).contains(&self.parallelism)
+                    && self.argon2_params().is_ok()
+            }
+            VaultKdf::Pbkdf2 => (1..=MAX_PBKDF2_ITERATIONS).contains(&self.iterations),
+        };
+        if valid { Ok(()) } else { Err(ERROR_INVALID_VAULT) }
+    }
+
+    /// Check the security floors a new vault must meet
+    ///
+    /// Only vault creation applies them; validate alone decides whether an
+    /// existing vault file can be opened.
+    fn check_floors(&self) -> Result<(), i32> {
+        let strong = match self.kdf {
+            VaultKdf::Argon2id => self.memory_kib >= MIN_ARGON2_MEMORY_KIB,
+            VaultKdf::Pbkdf2 => self.iterations >= MIN_PBKDF2_ITERATIONS,
+        };
+        if strong { Ok(()) } else { Err(ERROR_INVALID_VAULT) }
+    }
+
+    fn argon2_params(&self) -> Result<Params, argon2::Error> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, Some(KEY_SIZE))
+    }
+
+    fn kdf_id(&self) -> u8 {
+        match self.kdf {
+            VaultKdf::Argon2id => KDF_ARGON2ID,
+            VaultKdf::Pbkdf2 => KDF_PBKDF2_SHA256,
+        }
+    }
+
+    /// Derive the key-wrapping key from the password
+    fn derive(&self, password: &[u8], salt: &[u8]) -> Result<Zeroizing<[u8; KEY_SIZE]>, i32> {
+        let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+        match self.kdf {
+            VaultKdf::Argon2id => {
+                let params = self.argon2_params().map_err(|_| ERROR_INVALID_VAULT)?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(password, salt, key.as_mut())
+                    .map_err(|_| ERROR_INVALID_VAULT)?;
+            }
+            VaultKdf::Pbkdf2 => {
+                pbkdf2_hmac::<Sha256>(password, salt, self.iterations, key.as_mut());
+            }
+        }
+        Ok(key)
+    }
+}
+
+/// Parsed vault file
+struct Vault {
+    params: KdfParams,
+    salt: [u8; SALT_SIZE],
+    wrapped_key: [u8; WRAPPED_KEY_SIZE],
+}
+
+impl Vault {
+    /// Wrap `master_key` under a freshly salted key derived from `password`
+    fn seal(params: KdfParams, password: &[u8], master_key: &[u8; KEY_SIZE]) -> Result<Self, i32> {
+        let mut salt = [0u8; SALT_SIZE];
+        fill_random(&mut salt);
+        let mut vault = Vault { params, salt, wrapped_key: [0u8; WRAPPED_KEY_SIZE] };
+
+        let wrapping_key = params.derive(password, &salt)?;
+        let mut nonce_bytes = [0u8; NONCE_SIZE];
+        fill_random(&mut nonce_bytes);
+        let cipher = Aes256Gcm::new_from_slice(wrapping_key.as_ref()).map_err(|_| ERROR_INVALID_VAULT)?;
+        let header = vault.header();
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: master_key, aad: &header })
+            .map_err(|_| ERROR_INVALID_VAULT)?;
+
+        vault.wrapped_key[..NONCE_SIZE].copy_from_slice(&nonce_bytes);
+        vault.wrapped_key[NONCE_SIZE..].copy_from_slice(&ciphertext);
+        Ok(vault)
+    }
+
+    /// Unwrap the master key, failing with ERROR_VAULT_WRONG_PASSWORD if the tag does not verify
+    fn unseal(&self, password: &[u8]) -> Result<Zeroizing<[u8; KEY_SIZE]>, i32> {
+        let wrapping_key = self.params.derive(password, &self.salt)?;
+        let cipher = Aes256Gcm::new_from_slice(wrapping_key.as_ref()).map_err(|_| ERROR_INVALID_VAULT)?;
+        let header = self.header();
+        let mut plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(&self.wrapped_key[..NONCE_SIZE]),
+                Payload { msg: &self.wrapped_key[NONCE_SIZE..], aad: &header },
+            )
+            .map_err(|_| ERROR_VAULT_WRONG_PASSWORD)?;
+
+        let mut master_key = Zeroizing::new([0u8; KEY_SIZE]);
+        master_key.copy_from_slice(&plaintext);
+        plaintext.zeroize();
+        Ok(master_key)
+    }
+
+    fn header(&self) -> [u8; VAULT_HEADER_SIZE] {
+        let mut header = [0u8; VAULT_HEADER_SIZE];
+        header[0..4].copy_from_slice(VAULT_MAGIC);
+        header[4] = VAULT_VERSION;
+        header[5] = self.params.kdf_id();
+        // Reserved bytes (6-7) - zero
+        header[8..12].copy_from_slice(&self.params.memory_kib.to_le_bytes());
+        header[12..16].copy_from_slice(&self.params.iterations.to_le_bytes());
+        header[16..20].copy_from_slice(&self.params.parallelism.to_le_bytes());
+        header[20..].copy_from_slice(&self.salt);
+        header
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(VAULT_FILE_SIZE);
+        bytes.extend_from_slice(&self.header());
+        bytes.extend_from_slice(&self.wrapped_key);
+        let checksum = Sha256::digest(&bytes);
+        bytes.extend_from_slice(&checksum);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, i32> {
+        if bytes.len() != VAULT_FILE_SIZE || &bytes[0..4] != VAULT_MAGIC || bytes[4] != VAULT_VERSION {
+            return Err(ERROR_INVALID_VAULT);
+        }
+        let (body, checksum) = bytes.split_at(VAULT_FILE_SIZE - CHECKSUM_SIZE);
+        if Sha256::digest(body).as_slice() != checksum {
+            return Err(ERROR_INVALID_VAULT);
+        }
+
+        let read_u32 = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let kdf = match bytes[5] {
+            KDF_ARGON2ID => VaultKdf::Argon2id,
+            KDF_PBKDF2_SHA256 => VaultKdf::Pbkdf2,
+            _ => return Err(ERROR_INVALID_VAULT),
+        };
+        let params = KdfParams {
+            kdf,
+            memory_kib: read_u32(8),
+            iterations: read_u32(12),
+            parallelism: read_u32(16),
+        };
+        params.validate()?;
+
+        let mut salt = [0u8; SALT_SIZE];
+        salt.copy_from_slice(&bytes[20..VAULT_HEADER_SIZE]);
+        let mut wrapped_key = [0u8; WRAPPED_KEY_SIZE];
+        wrapped_key.copy_from_slice(&bytes[VAULT_HEADER_SIZE..VAULT_HEADER_SIZE + WRAPPED_KEY_SIZE]);
+        Ok(Vault { params, salt, wrapped_key })
+    }
+}
+
+fn read_vault(path: &Path) -> Result<Vault, i32> {
+    match fs::read(path) {
+        Ok(bytes) => Vault::from_bytes(&bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(ERROR_FILE_NOT_FOUND),
+        Err(_) => Err(ERROR_IO_FAILED),
+    }
+}
+
+/// Write the vault through a temp file so a crash never leaves a half-written key
+fn write_vault(path: &Path, vault: &Vault) -> Result<(), i32> {
+    let (temp_path, mut file) = create_temp_file_for(path).map_err(|_| ERROR_IO_FAILED)?;
+    let written = file.write_all(&vault.to_bytes()).and_then(|_| file.sync_all());
+    drop(file);
+    if written.is_err() || commit_temp_file(&temp_path, path).is_err() {
+        let _ = discard_temp_file(&temp_path);
+        return Err(ERROR_IO_FAILED);
+    }
+    Ok(())
+}
+
+/// Create a vault holding a new random master key
+///
+/// The file is created with create_new, so a vault that appears while the key
+/// is being sealed is never replaced.
+pub fn create_vault(path: &Path, password: &[u8], options: &VaultKdfOptions) -> Result<(), i32> {
+    // Only saves the key derivation; the create_new below is what decides
+    if path.exists() {
+        return Err(ERROR_VAULT_EXISTS);
+    }
+    let params = KdfParams::from_options(options)?;
+    params.check_floors()?;
+
+    let mut master_key = Zeroizing::new([0u8; KEY_SIZE]);
+    fill_random(master_key.as_mut());
+
+    let vault = Vault::seal(params, password, &master_key)?;
+    let mut file = match fs::OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(ERROR_VAULT_EXISTS),
+        Err(_) => return Err(ERROR_IO_FAILED),
+    };
+    if file.write_all(&vault.to_bytes()).and_then(|_| file.sync_all()).is_err() {
+        drop(file);
+        let _ = fs::remove_file(path);
+        return Err(ERROR_IO_FAILED);
+    }
+    Ok(())
+}
+
+/// Unlock a vault and return its master key
+pub fn open_vault(path: &Path, password: &[u8]) -> Result<Zeroizing<[u8; KEY_SIZE]>, i32> {
+    read_vault(path)?.unseal(password)
+}
+
+/// Rewrap the master key under a new password, keeping the KDF parameters
+pub fn change_vault_password(path: &Path, old_password: &[u8], new_password: &[u8]) -> Result<(), i32> {
+    let vault = read_vault(path)?;
+    let master_key = vault.unseal(old_password)?;
+    let rewrapped = Vault::seal(vault.params, new_password, &master_key)?;
+    write_vault(path, &rewrapped)
+}
+
+/// Format a master key as a base32 recovery code in dash-separated groups of four
+///
+/// Built in place with room for a trailing NUL, so handing it to CString never
+/// leaves an unwiped copy behind.
+pub fn recovery_code(master_key: &[u8]) -> Zeroizing<String> {
+    let encoded = Zeroizing::new(data_encoding::BASE32_NOPAD.encode(master_key));
+    let groups = encoded.len().div_ceil(4);
+    let mut code = Zeroizing::new(String::with_capacity(encoded.len() + groups.saturating_sub(1) + 1));
+    for (i, group) in encoded.as_bytes().chunks(4).enumerate() {
+        if i > 0 {
+            code.push('-');
+        }
+        code.push_str(std::str::from_utf8(group).unwrap());
+    }
+    code
+}
+
+/// Time one derivation with these parameters
+fn time_derivation(params: &KdfParams) -> Duration {
+    let started = Instant::now();
+    let _ = params.derive(b"calibration password", &[0u8; SALT_SIZE]);
+    started.elapsed()
+}
+
+/// Find KDF parameters whose derivation takes about `target` on this device
+///
+/// After a warm-up run, probes with doubling cost until one is long enough to
+/// time, then binary-searches around the extrapolated cost while the budget
+/// allows another probe. Targets longer than the budget are extrapolated from
+/// the last probe's throughput.
+fn calibrate(kdf: VaultKdf, target: Duration, budget: Duration) -> KdfParams {
+    let started = Instant::now();
+    let remaining = || budget.saturating_sub(started.elapsed());
+    let throughput = |params: &KdfParams, elapsed: Duration| params.work() as f64 / elapsed.as_secs_f64().max(1e-6);
+
+    let mut params = KdfParams::for_work(kdf, 0);
+    time_derivation(&params);
+    let mut elapsed = time_derivation(&params);
+    while elapsed < CALIBRATION_MIN_PROBE.min(target) && elapsed * 2 < remaining() {
+        let next = KdfParams::for_work(kdf, params.work() * 2);
+        if next == params {
+            break;
+        }
+        params = next;
+        elapsed = time_derivation(&params);
+    }
+
+    let mut rate = throughput(&params, elapsed);
+    let estimate = (rate * target.as_secs_f64()) as u64;
+    let (mut low, mut high) = (estimate / 2, estimate.saturating_mul(2));
+    for _ in 0..CALIBRATION_MAX_STEPS {
+        // Within 5% of each other is as close as timing noise allows
+        if high - low <= low / 20 {
+            break;
+        }
+        let probe = KdfParams::for_work(kdf, low + (high - low) / 2);
+        if probe.work() <= low || probe.work() >= high
+            || Duration::from_secs_f64(probe.work() as f64 / rate) > remaining() {
+            break;
+        }
+        let elapsed = time_derivation(&probe);
+        rate = throughput(&probe, elapsed);
+        if elapsed < target {
+            low = probe.work();
+        } else {
+            high = probe.work();
+        }
+    }
+
+    KdfParams::for_work(kdf, ((rate * target.as_secs_f64()) as u64).clamp(low, high))
+}
+
+/// Calibrate KDF parameters to this device and return them as options JSON
+pub fn calibrate_kdf_options(kdf: VaultKdf, target_ms: u32) -> Result<String, i32> {
+    if target_ms == 0 {
+        return Err(ERROR_INVALID_VAULT);
+    }
+    let params = calibrate(kdf, Duration::from_millis(target_ms as u64), CALIBRATION_BUDGET);
+    serde_json::to_string(&params.to_options()).map_err(|_| ERROR_INVALID_VAULT)
+}
+
+/// Read a password argument as bytes
+fn password_bytes<'a>(password: *const c_char) -> Result<&'a [u8], i32> {
+    if password.is_null() {
+        return Err(ERROR_NULL_POINTER);
+    }
+    Ok(unsafe { CStr::from_ptr(password) }.to_bytes())
+}
+
+/// Create a new vault file with a random master key
+///
+/// # Arguments
+/// * `path` - Vault file path (must not exist yet)
+/// * `password` - Password protecting the vault (null-terminated)
+/// * `pbkdf_params_json` - Optional KDF options, e.g. `{"kdf": "argon2id", "memory_kib": 19456,
+///   "iterations": 2, "parallelism": 1}` or `{"kdf": "pbkdf2", "iterations": 600000}` (can be null)
+///
+/// # Returns
+/// 0 on success, ERROR_INVALID_VAULT for options below the floors for new
+/// vaults (19 MiB of Argon2id memory, 100,000 PBKDF2 iterations) or above the
+/// upper bounds, other negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn vault_create(
+    path: *const c_char,
+    password: *const c_char,
+    pbkdf_params_json: *const c_char,
+) -> i32 {
+    let path = match unsafe { c_str_to_path(path) } {
+        Ok(p) => p,
+        Err(code) => return checked(code),
+    };
+    let password = match password_bytes(password) {
+        Ok(p) => p,
+        Err(code) => return checked(code),
+    };
+
+    let options = if pbkdf_params_json.is_null() {
+        VaultKdfOptions::default()
+    } else {
+        let json = match unsafe { CStr::from_ptr(pbkdf_params_json) }.to_str() {
+            Ok(s) => s,
+            Err(_) => return ERROR_INVALID_PATH,
+        };
+        match serde_json::from_str(json) {
+            Ok(o) => o,
+            Err(_) => return ERROR_INVALID_VAULT,
+        }
+    };
+
+    match create_vault(&path, password, &options) {
+        Ok(()) => SUCCESS,
+        Err(code) => checked(code),
+    }
+}
+
+/// Measure this device and pick KDF parameters that take about `target_ms` to derive
+///
+/// Spends at most about two seconds measuring, whatever the target. The JSON has a
+/// fixed field order and is accepted by vault_create, e.g.
+/// `{"kdf":"pbkdf2","iterations":310000}` or
+/// `{"kdf":"argon2id","memory_kib":65536,"iterations":3,"parallelism":1}`.
+///
+/// # Arguments
+/// * `target_ms` - Desired unlock latency in milliseconds (must be non-zero)
+/// * `kdf` - KDF_ARGON2ID (1) or KDF_PBKDF2_SHA256 (2)
+/// * `out_params_json` - Receives the parameters (caller must free with scan_folder_free_string)
+///
+/// # Returns
+/// 0 on success, ERROR_INVALID_VAULT for an unknown KDF or zero target, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn calibrate_kdf(target_ms: u32, kdf: u32, out_params_json: *mut *mut c_char) -> i32 {
+    if out_params_json.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+    let kdf = match u8::try_from(kdf) {
+        Ok(KDF_ARGON2ID) => VaultKdf::Argon2id,
+        Ok(KDF_PBKDF2_SHA256) => VaultKdf::Pbkdf2,
+        _ => return ERROR_INVALID_VAULT,
+    };
+
+    let json = match calibrate_kdf_options(kdf, target_ms) {
+        Ok(json) => json,
+        Err(code) => return checked(code),
+    };
+    match CString::new(json) {
+        Ok(s) => {
+            unsafe { *out_params_json = s.into_raw(); }
+            SUCCESS
+        }
+        Err(_) => ERROR_INVALID_VAULT,
+    }
+}
+
+/// Unlock a vault and copy its master key out
+///
+/// # Arguments
+/// * `path` - Vault file path
+/// * `password` - Vault password (null-terminated)
+/// * `out_master_key` - Buffer of 32 bytes receiving the master key; release it with vault_clear_key
+///
+/// # Returns
+/// 0 on success, ERROR_VAULT_WRONG_PASSWORD if the password is wrong, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn vault_open(
+    path: *const c_char,
+    password: *const c_char,
+    out_master_key: *mut u8,
+) -> i32 {
+    if out_master_key.is_null() {
+        return ERROR_NULL_POINTER;
+    }
+    let path = match unsafe { c_str_to_path(path) } {
+        Ok(p) => p,
+        Err(code) => return checked(code),
+    };
+    let password = match password_bytes(password) {
+        Ok(p) => p,
+        Err(code) => return checked(code),
+    };
+
+    match open_vault(&path, password) {
+        Ok(master_key) => {
+            unsafe { ptr::copy_nonoverlapping(master_key.as_ptr(), out_master_key, KEY_SIZE); }
+            SUCCESS
+        }
+        Err(code) => checked(code),
+    }
+}
+
+/// Change the vault password without changing the master key
+///
+/// # Arguments
+/// * `path` - Vault file path
+/// * `old_password` - Current password (null-terminated)
+/// * `new_password` - New password (null-terminated)
+///
+/// # Returns
+/// 0 on success, ERROR_VAULT_WRONG_PASSWORD if the old password is wrong, negative error code on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn vault_change_password(
+    path: *const c_char,
+    old_password: *const c_char,
+    new_password: *const c_char,
+) -> i32 {
+    let path = match unsafe { c_str_to_path(path) } {
+        Ok(p) => p,
+        Err(code) => return checked(code),
+    };
+    let (old_password, new_password) = match (password_bytes(old_password), password_bytes(new_password)) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(code), _) | (_, Err(code)) => return checked(code),
+    };
+
+    match change_vault_password(&path, old_password, new_password) {
+        Ok(()) => SUCCESS,
+        Err(code) => checked(code),
+    }
+}
+
+/// Export the master key as a base32 recovery code (e.g. "ABCD-EFGH-...")
+///
+/// # Arguments
+/// * `path` - Vault file path
+/// * `password` - Vault password (null-terminated)
+/// * `out_len` - Pointer to store the code length
+///
+/// # Returns
+/// Recovery code string (caller must free with vault_free_recovery_code as soon as it
+/// has been shown), or null on failure
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn vault_export_recovery_code(
+    path: *const c_char,
+    password: *const c_char,
+    out_len: *mut usize,
+) -> *mut c_char {
+    if out_len.is_null() {
+        return ptr::null_mut();
+    }
+    let path = match unsafe { c_str_to_path(path) } {
+        Ok(p) => p,
+        Err(_) => return ptr::null_mut(),
+    };
+    let password = match password_bytes(password) {
+        Ok(p) => p,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    let master_key = match open_vault(&path, password) {
+        Ok(k) => k,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    let mut code = recovery_code(master_key.as_ref());
+    unsafe { *out_len = code.len(); }
+    // Moved out whole: the capacity already has room for the NUL, so no copy is made
+    match CString::new(std::mem::take(&mut *code)) {
+        Ok(s) => s.into_raw(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Wipe and free a recovery code returned by vault_export_recovery_code
+///
+/// # Arguments
+/// * `code` - The recovery code string, or null
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn vault_free_recovery_code(code: *mut c_char) {
+    if code.is_null() {
+        return;
+    }
+    let code = unsafe { CString::from_raw(code) };
+    drop(Zeroizing::new(code.into_bytes_with_nul()));
+}
+
+/// Zeroize a master key buffer filled by vault_open
+///
+/// # Arguments
+/// * `key_ptr` - Pointer to the 32-byte key buffer
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn vault_clear_key(key_ptr: *mut u8) {
+    if !key_ptr.is_null() {
+        let key = unsafe { slice::from_raw_parts_mut(key_ptr, KEY_SIZE) };
+        key.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{c_path, temp_dir};
+
+    const FAST_ARGON2: &str = r#"{"kdf": "argon2id", "memory_kib": 64, "iterations": 1, "parallelism": 1}"#;
+    const FAST_PBKDF2: &str = r#"{"kdf": "pbkdf2", "iterations": 1000}"#;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    /// Write a vault with `params` as given, skipping the floors for new vaults
+    /// like a vault created before they applied; keeps the tests fast
+    fn create_weak(path: &Path, password: &str, params: &str) {
+        let options: VaultKdfOptions = serde_json::from_str(params).unwrap();
+        let mut master_key = [0u8; KEY_SIZE];
+        fill_random(&mut master_key);
+        let vault = Vault::seal(KdfParams::from_options(&options).unwrap(), password.as_bytes(), &master_key).unwrap();
+        write_vault(path, &vault).unwrap();
+    }
+
+    fn open(path: &Path, password: &str) -> Result<[u8; KEY_SIZE], i32> {
+        let mut key = [0u8; KEY_SIZE];
+        match vault_open(c_path(path).as_ptr(), c(password).as_ptr(), key.as_mut_ptr()) {
+            SUCCESS => Ok(key),
+            code => Err(code),
+        }
+    }
+
+    #[test]
+    fn test_vault_round_trip() {
+        let root = temp_dir("vault_round_trip");
+
+        for (name, params) in [("argon2.vault", FAST_ARGON2), ("pbkdf2.vault", FAST_PBKDF2)] {
+            let path = root.join(name);
+            create_weak(&path, "hunter2", params);
+            assert_eq!(fs::metadata(&path).unwrap().len(), VAULT_FILE_SIZE as u64);
+
+            // Creating again must not replace the existing master key
+            assert_eq!(vault_create(c_path(&path).as_ptr(), c("other").as_ptr(), c(params).as_ptr()), ERROR_VAULT_EXISTS);
+
+            let key = open(&path, "hunter2").unwrap();
+            assert_ne!(key, [0u8; KEY_SIZE]);
+            assert_eq!(open(&path, "hunter3"), Err(ERROR_VAULT_WRONG_PASSWORD));
+
+            // Changing the password keeps the master key
+            assert_eq!(vault_change_password(c_path(&path).as_ptr(), c("wrong").as_ptr(), c("new").as_ptr()),
+                       ERROR_VAULT_WRONG_PASSWORD);
+            assert_eq!(vault_change_password(c_path(&path).as_ptr(), c("hunter2").as_ptr(), c("new").as_ptr()), SUCCESS);
+            assert_eq!(open(&path, "hunter2"), Err(ERROR_VAULT_WRONG_PASSWORD));
+            assert_eq!(open(&path, "new").unwrap(), key);
+
+            // The recovery code decodes back to the master key
+            let mut len = 0usize;
+            let code_ptr = vault_export_recovery_code(c_path(&path).as_ptr(), c("new").as_ptr(), &mut len);
+            assert!(!code_ptr.is_null());
+            let code = unsafe { CStr::from_ptr(code_ptr) }.to_str().unwrap().to_string();
+            assert_eq!(code.len(), len);
+            let decoded = data_encoding::BASE32_NOPAD.decode(code.replace('-', "").as_bytes()).unwrap();
+            assert_eq!(decoded, key);
+            vault_free_recovery_code(code_ptr);
+            vault_free_recovery_code(ptr::null_mut());
+            assert!(vault_export_recovery_code(c_path(&path).as_ptr(), c("hunter2").as_ptr(), &mut len).is_null());
+
+            let mut key = key;
+            vault_clear_key(key.as_mut_ptr());
+            assert_eq!(key, [0u8; KEY_SIZE]);
+        }
+
+        assert_eq!(open(&root.join("missing.vault"), "x"), Err(ERROR_FILE_NOT_FOUND));
+        assert_eq!(vault_create(c_path(&root.join("bad.vault")).as_ptr(), c("x").as_ptr(),
+                                c(r#"{"kdf": "pbkdf2", "iterations": 0}"#).as_ptr()), ERROR_INVALID_VAULT);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_new_vaults_enforce_kdf_floors() {
+        let root = temp_dir("vault_floors");
+        let path = root.join("master.vault");
+
+        // Weak parameters are refused for a new vault before anything is written
+        for params in [FAST_ARGON2, FAST_PBKDF2, r#"{"kdf": "pbkdf2", "iterations": 99999}"#,
+                       r#"{"kdf": "argon2id", "memory_kib": 19455, "iterations": 2, "parallelism": 1}"#] {
+            assert_eq!(vault_create(c_path(&path).as_ptr(), c("pw").as_ptr(), c(params).as_ptr()), ERROR_INVALID_VAULT,
+                       "{}", params);
+            assert!(!path.exists());
+        }
+
+        // The floor itself is accepted
+        let floor = r#"{"kdf": "argon2id", "memory_kib": 19456, "iterations": 1, "parallelism": 1}"#;
+        assert_eq!(vault_create(c_path(&path).as_ptr(), c("pw").as_ptr(), c(floor).as_ptr()), SUCCESS);
+        assert!(open(&path, "pw").is_ok());
+
+        // An existing vault below the floors still opens and keeps its parameters on a password change
+        let weak = root.join("weak.vault");
+        create_weak(&weak, "pw", FAST_PBKDF2);
+        let key = open(&weak, "pw").unwrap();
+        assert_eq!(vault_change_password(c_path(&weak).as_ptr(), c("pw").as_ptr(), c("new").as_ptr()), SUCCESS);
+        assert_eq!(open(&weak, "new").unwrap(), key);
+        assert_eq!(read_vault(&weak).unwrap().params.iterations, 1000);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_vault_tamper_detection() {
+        let root = temp_dir("vault_tamper");
+        let path = root.join("master.vault");
+        create_weak(&path, "pw", FAST_ARGON2);
+        let original = fs::read(&path).unwrap();
+
+        // Any flipped byte is reported as a damaged vault, never as a wrong password
+        for offset in [0, 4, 5, 12, 25, VAULT_HEADER_SIZE + 3, VAULT_FILE_SIZE - 40, VAULT_FILE_SIZE - 1] {
+            let mut tampered = original.clone();
+            tampered[offset] ^= 0x01;
+            fs::write(&path, &tampered).unwrap();
+            assert_eq!(open(&path, "pw"), Err(ERROR_INVALID_VAULT), "offset {}", offset);
+        }
+
+        // Truncation and trailing garbage
+        fs::write(&path, &original[..VAULT_FILE_SIZE - 1]).unwrap();
+        assert_eq!(open(&path, "pw"), Err(ERROR_INVALID_VAULT));
+        let mut extended = original.clone();
+        extended.push(0);
+        fs::write(&path, &extended).unwrap();
+        assert_eq!(open(&path, "pw"), Err(ERROR_INVALID_VAULT));
+
+        // A forged checksum still fails authentication of the wrapped key
+        let mut forged = original.clone();
+        forged[VAULT_HEADER_SIZE + NONCE_SIZE] ^= 0x01;
+        let checksum = Sha256::digest(&forged[..VAULT_FILE_SIZE - CHECKSUM_SIZE]);
+        forged[VAULT_FILE_SIZE - CHECKSUM_SIZE..].copy_from_slice(&checksum);
+        fs::write(&path, &forged).unwrap();
+        assert_eq!(open(&path, "pw"), Err(ERROR_VAULT_WRONG_PASSWORD));
+
+        fs::write(&path, &original).unwrap();
+        assert!(open(&path, "pw").is_ok());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    fn calibrate_json(target_ms: u32, kdf: u8) -> Result<String, i32> {
+        let mut out: *mut c_char = ptr::null_mut();
+        match calibrate_kdf(target_ms, kdf as u32, &mut out) {
+            SUCCESS => Ok(unsafe { CString::from_raw(out) }.into_string().unwrap()),
+            code => Err(code),
+        }
+    }
+
+    #[test]
+    fn test_calibrated_params_land_near_target() {
+        let target = Duration::from_millis(150);
+        for (kdf, prefix) in [(KDF_PBKDF2_SHA256, r#"{"kdf":"pbkdf2","iterations":"#),
+                              (KDF_ARGON2ID, r#"{"kdf":"argon2id","memory_kib":"#)] {
+            let json = calibrate_json(target.as_millis() as u32, kdf).unwrap();
+            assert!(json.starts_with(prefix), "{}", json);
+
+            let options: VaultKdfOptions = serde_json::from_str(&json).unwrap();
+            let params = KdfParams::from_options(&options).unwrap();
+            assert_eq!(serde_json::to_string(&params.to_options()).unwrap(), json);
+            assert!(params.check_floors().is_ok());
+
+            // A device too slow to reach the floors within the target gets the floors
+            let floor = KdfParams::for_work(params.kdf, 0);
+            if time_derivation(&floor) > target {
+                assert_eq!(params, floor);
+                continue;
+            }
+
+            // Best of three, since other tests share the machine
+            let elapsed = (0..3).map(|_| time_derivation(&params)).min().unwrap();
+            assert!(elapsed > target / 3 && elapsed < target * 3, "{} took {:?}", json, elapsed);
+        }
+    }
+
+    #[test]
+    fn test_calibration_time_is_capped() {
+        let root = temp_dir("vault_calibrate");
+
+        // A ten-second target is extrapolated rather than measured
+        let started = Instant::now();
+        let json = calibrate_json(10_000, KDF_ARGON2ID).unwrap();
+        assert!(started.elapsed() < CALIBRATION_BUDGET + Duration::from_millis(500), "took {:?}", started.elapsed());
+        let options: VaultKdfOptions = serde_json::from_str(&json).unwrap();
+        assert!(options.memory_kib.unwrap() >= CALIBRATE_ARGON2_MIN_MEMORY_KIB);
+
+        // The output is accepted by vault_create as-is
+        let fast = calibrate_json(20, KDF_ARGON2ID).unwrap();
+        let path = root.join("calibrated.vault");
+        assert_eq!(vault_create(c_path(&path).as_ptr(), c("pw").as_ptr(), c(&fast).as_ptr()), SUCCESS);
+        assert!(open(&path, "pw").is_ok());
+
+        assert_eq!(calibrate_json(0, KDF_PBKDF2_SHA256), Err(ERROR_INVALID_VAULT));
+        assert_eq!(calibrate_json(100, 7), Err(ERROR_INVALID_VAULT));
+        assert_eq!(calibrate_kdf(100, KDF_ARGON2ID as u32, ptr::null_mut()), ERROR_NULL_POINTER);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,247 @@
+/// Extended attribute handling for copies
+///
+/// Files can carry data outside their contents: extended attributes on Linux
+/// and macOS (including the macOS resource fork, com.apple.ResourceFork) and
+/// named alternate data streams on Windows. A plain streaming copy drops them.
+/// The XATTR_POLICY_* codes choose what copy_file_streaming_with_options and
+/// folder copies (folder_copy_set_xattr_policy) do with them:
+/// - IGNORE (the default) copies the contents only.
+/// - PRESERVE copies every attribute or named stream the destination accepts.
+/// - STRIP_QUARANTINE_ONLY preserves everything except the download markers
+///   com.apple.quarantine and Zone.Identifier, also removing them where the
+///   copy itself carried them over (a reflink on macOS).
+///
+/// An attribute that cannot be read or written does not fail the copy; it is
+/// reported by name with the reason.
+use std::path::Path;
+
+/// Copy file contents only
+pub const XATTR_POLICY_IGNORE: i32 = 0;
+/// Copy extended attributes and named streams along with the contents
+pub const XATTR_POLICY_PRESERVE: i32 = 1;
+/// Copy extended attributes and named streams except quarantine markers
+pub const XATTR_POLICY_STRIP_QUARANTINE_ONLY: i32 = 2;
+
+pub use crate::errors::ERROR_INVALID_XATTR_POLICY;
+
+/// Attributes marking a file as downloaded from the internet
+const QUARANTINE_ATTRIBUTES: [&str; 2] = ["com.apple.quarantine", "Zone.Identifier"];
+
+/// What a copy does with extended attributes, selected by an XATTR_POLICY_* code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum XattrPolicy {
+    #[default]
+    Ignore,
+    Preserve,
+    StripQuarantine,
+}
+
+impl XattrPolicy {
+    /// Policy of an XATTR_POLICY_* code, None for an unknown code
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            XATTR_POLICY_IGNORE => Some(XattrPolicy::Ignore),
+            XATTR_POLICY_PRESERVE => Some(XattrPolicy::Preserve),
+            XATTR_POLICY_STRIP_QUARANTINE_ONLY => Some(XattrPolicy::StripQuarantine),
+            _ => None,
+        }
+    }
+
+    /// Whether the attribute `name` is copied under this policy
+    fn copies(self, name: &str) -> bool {
+        match self {
+            XattrPolicy::Ignore => false,
+            XattrPolicy::Preserve => true,
+            XattrPolicy::StripQuarantine => !is_quarantine(name),
+        }
+    }
+}
+
+fn is_quarantine(name: &str) -> bool {
+    QUARANTINE_ATTRIBUTES.contains(&name)
+}
+
+/// Apply `policy` to the attributes of a finished copy from `src` to `dst`
+///
+/// # Returns
+/// One "name: reason" message per attribute that could not be copied or
+/// removed (empty when everything went through)
+pub(crate) fn copy_attributes(src: &Path, dst: &Path, policy: XattrPolicy) -> Vec<String> {
+    if policy == XattrPolicy::Ignore {
+        return Vec::new();
+    }
+    let mut failures = Vec::new();
+    platform::copy_attributes(src, dst, policy, &mut failures);
+    failures
+}
+
+#[cfg(unix)]
+mod platform {
+    use super::{is_quarantine, XattrPolicy};
+    use std::path::Path;
+
+    pub(super) fn copy_attributes(src: &Path, dst: &Path, policy: XattrPolicy, failures: &mut Vec<String>) {
+        if !xattr::SUPPORTED_PLATFORM {
+            return;
+        }
+        let names = match xattr::list_deref(src) {
+            Ok(names) => names,
+            Err(e) => {
+                failures.push(format!("*: {}", e));
+                return;
+            }
+        };
+        for name in names {
+            let display = name.to_string_lossy().into_owned();
+            if !policy.copies(&display) {
+                continue;
+            }
+            let result = xattr::get_deref(src, &name)
+                .and_then(|value| xattr::set_deref(dst, &name, &value.unwrap_or_default()));
+            if let Err(e) = result {
+                failures.push(format!("{}: {}", display, e));
+            }
+        }
+
+        // A clone carries the source's attributes over whatever the policy
+        if policy == XattrPolicy::StripQuarantine {
+            if let Ok(names) = xattr::list_deref(dst) {
+                for name in names.filter(|name| is_quarantine(&name.to_string_lossy())) {
+                    if let Err(e) = xattr::remove_deref(dst, &name) {
+                        failures.push(format!("{}: {}", name.to_string_lossy(), e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::XattrPolicy;
+    use std::ffi::c_void;
+    use std::fs::{File, OpenOptions};
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+
+    /// WIN32_FIND_STREAM_DATA
+    #[repr(C)]
+    struct FindStreamData {
+        stream_size: i64,
+        stream_name: [u16; 296],
+    }
+
+    const FIND_STREAM_INFO_STANDARD: i32 = 0;
+    const INVALID_HANDLE_VALUE: isize = -1;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn FindFirstStreamW(file_name: *const u16, info_level: i32, data: *mut c_void, flags: u32) -> isize;
+        fn FindNextStreamW(handle: isize, data: *mut c_void) -> i32;
+        fn FindClose(handle: isize) -> i32;
+    }
+
+    /// Names of the named data streams of a file (without the ":$DATA" suffix)
+    fn named_streams(path: &Path) -> std::io::Result<Vec<String>> {
+        let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        let mut data = FindStreamData { stream_size: 0, stream_name: [0; 296] };
+        let handle = unsafe {
+            FindFirstStreamW(wide.as_ptr(), FIND_STREAM_INFO_STANDARD, &mut data as *mut _ as *mut c_void, 0)
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut names = Vec::new();
+        loop {
+            let len = data.stream_name.iter().position(|&c| c == 0).unwrap_or(data.stream_name.len());
+            let name = String::from_utf16_lossy(&data.stream_name[..len]);
+            // ":name:$DATA"; the unnamed main stream is "::$DATA"
+            if let Some(name) = name.strip_prefix(':').and_then(|n| n.strip_suffix(":$DATA")) {
+                if !name.is_empty() {
+                    names.push(name.to_string());
+                }
+            }
+            if unsafe { FindNextStreamW(handle, &mut data as *mut _ as *mut c_void) } == 0 {
+                break;
+            }
+        }
+        unsafe { FindClose(handle) };
+        Ok(names)
+    }
+
+    fn stream_path(path: &Path, name: &str) -> std::path::PathBuf {
+        let mut stream = path.as_os_str().to_os_string();
+        stream.push(":");
+        stream.push(name);
+        stream.into()
+    }
+
+    pub(super) fn copy_attributes(src: &Path, dst: &Path, policy: XattrPolicy, failures: &mut Vec<String>) {
+        let names = match named_streams(src) {
+            Ok(names) => names,
+            Err(e) => {
+                failures.push(format!("*: {}", e));
+                return;
+            }
+        };
+        for name in names.iter().filter(|name| policy.copies(name)) {
+            let result = File::open(stream_path(src, name)).and_then(|mut input| {
+                let mut output = OpenOptions::new().write(true).create(true).truncate(true)
+                    .open(stream_path(dst, name))?;
+                std::io::copy(&mut input, &mut output)
+            });
+            if let Err(e) = result {
+                failures.push(format!("{}: {}", name, e));
+            }
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    use super::XattrPolicy;
+    use std::path::Path;
+
+    pub(super) fn copy_attributes(_src: &Path, _dst: &Path, _policy: XattrPolicy, _failures: &mut Vec<String>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_policy_codes() {
+        assert_eq!(XattrPolicy::from_code(XATTR_POLICY_IGNORE), Some(XattrPolicy::Ignore));
+        assert_eq!(XattrPolicy::from_code(XATTR_POLICY_STRIP_QUARANTINE_ONLY), Some(XattrPolicy::StripQuarantine));
+        assert_eq!(XattrPolicy::from_code(3), None);
+        assert!(XattrPolicy::Preserve.copies("com.apple.quarantine"));
+        assert!(!XattrPolicy::StripQuarantine.copies("Zone.Identifier"));
+        assert!(XattrPolicy::StripQuarantine.copies("user.tag"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_attributes_follows_policy() {
+        let dir = std::env::temp_dir().join(format!("cloud_nexus_xattrs_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("source.txt");
+        fs::write(&src, b"data").unwrap();
+        // tmpfs or a filesystem without user attributes: nothing to test here
+        if xattr::set(&src, "user.cloud_nexus.tag", b"blue").is_err() {
+            let _ = fs::remove_dir_all(&dir);
+            return;
+        }
+        let _ = xattr::set(&src, "user.com.apple.quarantine", b"0081;");
+
+        for (policy, expected) in [(XattrPolicy::Ignore, None), (XattrPolicy::Preserve, Some(b"blue".to_vec()))] {
+            let dst = dir.join(format!("{:?}.txt", policy));
+            fs::write(&dst, b"data").unwrap();
+            assert!(copy_attributes(&src, &dst, policy).is_empty());
+            assert_eq!(xattr::get(&dst, "user.cloud_nexus.tag").unwrap(), expected);
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
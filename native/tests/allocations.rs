@@ -0,0 +1,387 @@
+// Heap allocation bounds of decryption and search
+// These tests measure allocations through a tracking global allocator. It is
+// installed only in this test binary, so the unit tests and the library keep
+// the system allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::ffi::{c_int, c_void, CStr, CString};
+use std::ptr;
+use std::slice;
+
+use rand::{Rng, SeedableRng};
+
+use cloud_nexus_encryption::{
+    create_search_index, decrypt_file_streaming, decrypt_file_streaming_cb, decrypt_file_streaming_ex,
+    encrypt_chunk, encrypt_file_finalize, encrypt_file_get_preamble, encrypt_file_init, encrypt_file_set_chunk_crc,
+    encrypt_file_streaming, encrypt_file_streaming_cb, free_buffer, free_search_index, free_search_results,
+    search_index, search_index_ids, search_index_prefix_ids, CSearchResult, SearchDocument, SearchIndex,
+    ERROR_MALFORMED_CONTAINER, SUCCESS,
+};
+
+const KEY_SIZE: usize = 32;
+const HEADER_SIZE: usize = 12;
+/// Plaintext chunk size of the whole-buffer and `_cb` streaming functions
+const STREAMING_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Tracks the current thread's allocations while one of the helpers below runs
+struct TrackingAllocator;
+
+thread_local! {
+    /// Largest single allocation made while tracking
+    static ALLOCATION_PEAK: Cell<Option<usize>> = const { Cell::new(None) };
+    /// (bytes live, peak bytes live) of allocations made while tracking
+    static LIVE_BYTES: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+    /// Number of allocations made while tracking
+    static ALLOCATION_COUNT: Cell<Option<usize>> = const { Cell::new(None) };
+    /// Allocations minus deallocations made while tracking
+    static NET_ALLOCATIONS: Cell<Option<isize>> = const { Cell::new(None) };
+}
+
+fn note_allocation(size: usize) {
+    let _ = ALLOCATION_PEAK.try_with(|peak| {
+        if let Some(largest) = peak.get() {
+            peak.set(Some(largest.max(size)));
+        }
+    });
+    let _ = LIVE_BYTES.try_with(|live| {
+        if let Some((current, peak)) = live.get() {
+            live.set(Some((current + size, peak.max(current + size))));
+        }
+    });
+    let _ = ALLOCATION_COUNT.try_with(|count| {
+        if let Some(n) = count.get() {
+            count.set(Some(n + 1));
+        }
+    });
+    let _ = NET_ALLOCATIONS.try_with(|net| {
+        if let Some(n) = net.get() {
+            net.set(Some(n + 1));
+        }
+    });
+}
+
+fn note_deallocation(size: usize) {
+    let _ = LIVE_BYTES.try_with(|live| {
+        if let Some((current, peak)) = live.get() {
+            live.set(Some((current.saturating_sub(size), peak)));
+        }
+    });
+    let _ = NET_ALLOCATIONS.try_with(|net| {
+        if let Some(n) = net.get() {
+            net.set(Some(n - 1));
+        }
+    });
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        note_allocation(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        note_allocation(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        note_deallocation(layout.size());
+        note_allocation(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        note_deallocation(layout.size());
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+/// Run `f`, returning its result and the largest single allocation it made
+fn largest_allocation<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    ALLOCATION_PEAK.with(|peak| peak.set(Some(0)));
+    let result = f();
+    (result, ALLOCATION_PEAK.with(|peak| peak.replace(None)).unwrap_or(0))
+}
+
+/// Run `f`, returning its result and the most heap bytes it held at once
+fn peak_live_bytes<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    LIVE_BYTES.with(|live| live.set(Some((0, 0))));
+    let result = f();
+    let (_, peak) = LIVE_BYTES.with(|live| live.replace(None)).unwrap_or((0, 0));
+    (result, peak)
+}
+
+/// Run `f`, returning its result and the number of heap allocations it made
+/// (reallocations included)
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    ALLOCATION_COUNT.with(|count| count.set(Some(0)));
+    let result = f();
+    (result, ALLOCATION_COUNT.with(|count| count.replace(None)).unwrap_or(0))
+}
+
+/// Run `f`, returning its result and the number of Rust heap allocations it
+/// left live (negative if it freed more than it allocated)
+fn net_allocations<T>(f: impl FnOnce() -> T) -> (T, isize) {
+    NET_ALLOCATIONS.with(|net| net.set(Some(0)));
+    let result = f();
+    (result, NET_ALLOCATIONS.with(|net| net.replace(None)).unwrap_or(0))
+}
+
+/// Copy a library-allocated buffer out and free it
+fn take_buffer(output: *mut u8, output_len: usize) -> Vec<u8> {
+    assert!(!output.is_null());
+    let bytes = unsafe { slice::from_raw_parts(output, output_len) }.to_vec();
+    free_buffer(output);
+    bytes
+}
+
+/// Build a container from small chunks, returning it with its chunk offsets
+fn small_chunk_container(key: &[u8; KEY_SIZE], chunks: &[&[u8]], with_crc: bool) -> (Vec<u8>, Vec<usize>) {
+    let mut header_len = 0usize;
+    let ctx = encrypt_file_init(key.as_ptr(), KEY_SIZE, &mut header_len);
+    assert!(!ctx.is_null());
+    encrypt_file_set_chunk_crc(ctx, with_crc as u8);
+
+    let mut preamble_len = 0usize;
+    encrypt_file_get_preamble(ctx, ptr::null_mut(), 0, &mut preamble_len);
+    let mut container = vec![0u8; preamble_len];
+    assert_eq!(encrypt_file_get_preamble(ctx, container.as_mut_ptr(), container.len(), &mut preamble_len), SUCCESS);
+
+    let mut offsets = Vec::new();
+    for (index, plaintext) in chunks.iter().enumerate() {
+        offsets.push(container.len());
+        let mut chunk_len = 0usize;
+        let chunk = encrypt_chunk(ctx, plaintext.as_ptr(), plaintext.len(), index as u32, &mut chunk_len);
+        container.extend(take_buffer(chunk, chunk_len));
+    }
+    encrypt_file_finalize(ctx);
+    (container, offsets)
+}
+
+/// Decrypt `container`, returning the status and the largest allocation made
+fn decrypt_tracked(container: &[u8], key: &[u8; KEY_SIZE]) -> (c_int, usize) {
+    largest_allocation(|| {
+        let mut output_len = 0usize;
+        let mut status: c_int = 1;
+        let output = decrypt_file_streaming_ex(container.as_ptr(), container.len(), key.as_ptr(), KEY_SIZE,
+                                               &mut output_len, None, ptr::null_mut(), ptr::null(), &mut status);
+        if !output.is_null() {
+            free_buffer(output);
+        }
+        status
+    })
+}
+
+#[test]
+fn test_corrupt_containers_allocate_at_most_their_size() {
+    let key = [0x79u8; KEY_SIZE];
+    let chunks: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; if i == 3 { 100 } else { 512 }]).collect();
+    let chunk_refs: Vec<&[u8]> = chunks.iter().map(|c| c.as_slice()).collect();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1179);
+
+    // An error for corrupt input, and no allocation beyond the input's own size
+    let rejects = |container: &[u8]| -> c_int {
+        let (status, peak) = decrypt_tracked(container, &key);
+        assert_ne!(status, SUCCESS);
+        assert!(peak <= container.len() + 1024, "allocated {} for {} input bytes", peak, container.len());
+        status
+    };
+
+    for with_crc in [false, true] {
+        let (container, offsets) = small_chunk_container(&key, &chunk_refs, with_crc);
+        let fek_end = offsets[0];
+        // The intact container decrypts, and the tracker sees its plaintext buffers
+        let (status, peak) = decrypt_tracked(&container, &key);
+        assert_eq!(status, SUCCESS);
+        assert!(peak >= 512);
+
+        // Random buffers, half of them behind the container's own magic and version
+        for round in 0..200 {
+            let mut buffer = vec![0u8; rng.gen_range(0..2048)];
+            rng.fill(buffer.as_mut_slice());
+            if round % 2 == 0 && buffer.len() >= HEADER_SIZE {
+                buffer[..5].copy_from_slice(&container[..5]);
+            }
+            rejects(&buffer);
+        }
+
+        // Single bit flips anywhere but the version, flags and reserved header bytes
+        // and the unchecked chunk indexes
+        for _ in 0..500 {
+            let mut damaged = container.clone();
+            let offset = loop {
+                let offset = rng.gen_range(0..damaged.len());
+                let in_chunk_index = offsets.iter().any(|&start| (start..start + 4).contains(&offset));
+                if !(4..8).contains(&offset) && !in_chunk_index {
+                    break offset;
+                }
+            };
+            damaged[offset] ^= 1 << rng.gen_range(0..8);
+            rejects(&damaged);
+        }
+
+        // Truncation anywhere except a chunk boundary
+        for cut in 0..container.len() {
+            if cut != fek_end && !offsets.contains(&cut) {
+                rejects(&container[..cut]);
+            }
+        }
+
+        // Huge FEK region and chunk size fields are refused before anything is allocated
+        for fek_len in [0x7FFF_FFFFu32, u32::MAX] {
+            let mut damaged = container.clone();
+            damaged[8..12].copy_from_slice(&fek_len.to_le_bytes());
+            assert_eq!(rejects(&damaged), ERROR_MALFORMED_CONTAINER);
+        }
+        for size_field in [u32::MAX, 0x7FFF_FFFF] {
+            for &chunk_offset in &offsets {
+                let mut damaged = container.clone();
+                damaged[chunk_offset + 4..chunk_offset + 8].copy_from_slice(&size_field.to_le_bytes());
+                assert_eq!(rejects(&damaged), ERROR_MALFORMED_CONTAINER);
+            }
+        }
+    }
+}
+
+extern "C" fn discard_sink(_data: *const u8, _data_len: usize, _user_data: *mut c_void) -> i32 {
+    0
+}
+
+#[test]
+fn test_streaming_holds_about_one_chunk_at_a_time() {
+    let key = [8u8; KEY_SIZE];
+    let plaintext = vec![0x5au8; STREAMING_CHUNK_SIZE * 8];
+    let mut output_len = 0usize;
+    let container = take_buffer(
+        encrypt_file_streaming(plaintext.as_ptr(), plaintext.len(), key.as_ptr(), KEY_SIZE, &mut output_len,
+                               None, ptr::null_mut()),
+        output_len,
+    );
+    let bound = 3 * STREAMING_CHUNK_SIZE;
+
+    // Each chunk reaches the sink before the next one is processed
+    let (total, peak) = peak_live_bytes(|| {
+        decrypt_file_streaming_cb(container.as_ptr(), container.len(), key.as_ptr(), KEY_SIZE, Some(discard_sink),
+                                  None, ptr::null(), ptr::null_mut())
+    });
+    assert_eq!(total, plaintext.len() as i64);
+    assert!(peak < bound, "decrypt held {} bytes at once", peak);
+
+    let (total, peak) = peak_live_bytes(|| {
+        encrypt_file_streaming_cb(plaintext.as_ptr(), plaintext.len(), key.as_ptr(), KEY_SIZE, Some(discard_sink),
+                                  None, ptr::null(), ptr::null_mut())
+    });
+    assert_eq!(total, container.len() as i64);
+    assert!(peak < bound, "encrypt held {} bytes at once", peak);
+
+    // The buffer functions write chunks straight into their one output allocation
+    let (output, peak) = peak_live_bytes(|| {
+        decrypt_file_streaming(container.as_ptr(), container.len(), key.as_ptr(), KEY_SIZE, &mut output_len,
+                               None, ptr::null_mut())
+    });
+    assert_eq!(take_buffer(output, output_len), plaintext);
+    assert!(peak < bound, "decrypt_file_streaming held {} bytes besides its output", peak);
+
+    let (output, peak) = peak_live_bytes(|| {
+        encrypt_file_streaming(plaintext.as_ptr(), plaintext.len(), key.as_ptr(), KEY_SIZE, &mut output_len,
+                               None, ptr::null_mut())
+    });
+    assert_eq!(take_buffer(output, output_len).len(), container.len());
+    assert!(peak < bound, "encrypt_file_streaming held {} bytes besides its output", peak);
+}
+
+fn report(i: usize, name: &str) -> SearchDocument {
+    SearchDocument {
+        node_id: format!("n{}", i),
+        account_id: "acc1".to_string(),
+        provider: "gdrive".to_string(),
+        email: String::new(),
+        name: name.to_string(),
+        is_folder: false,
+        parent_id: None,
+    }
+}
+
+fn report_index(count: usize) -> *mut SearchIndex {
+    let index = create_search_index();
+    let docs: Vec<_> = (0..count).map(|i| report(i, &format!("Report {}", i))).collect();
+    unsafe { (*index).add_documents(docs) };
+    index
+}
+
+#[test]
+fn test_id_search_path_allocates_nothing_when_cached() {
+    let index = report_index(50);
+    let query = CString::new("report").unwrap();
+    let mut out_ids = [0u32; 64];
+    let mut out_scores = [0.0f64; 64];
+    let search = |out_ids: &mut [u32], out_scores: &mut [f64]| {
+        search_index_ids(index, query.as_ptr(), 50, out_ids.as_mut_ptr(), out_scores.as_mut_ptr(), out_ids.len())
+    };
+
+    // The first query fills the cache; repeats hand out 50 results without allocating
+    assert_eq!(search(&mut out_ids, &mut out_scores), 50);
+    assert_eq!(count_allocations(|| search(&mut out_ids, &mut out_scores)), (50, 0));
+    let prefix = |out_ids: &mut [u32]| {
+        search_index_prefix_ids(index, query.as_ptr(), 50, out_ids.as_mut_ptr(), ptr::null_mut(), out_ids.len())
+    };
+    assert_eq!(prefix(&mut out_ids), 50);
+    assert_eq!(count_allocations(|| prefix(&mut out_ids)), (50, 0));
+
+    free_search_index(index);
+}
+
+#[test]
+fn test_search_results_free_without_leaks() {
+    let index = report_index(1000);
+    let query = CString::new("report").unwrap();
+    let search = || {
+        let mut results: *mut CSearchResult = ptr::null_mut();
+        let mut count = 0usize;
+        let found = search_index(index, query.as_ptr(), 2000, &mut results, &mut count);
+        (found, results, count)
+    };
+
+    // Warm the query cache so it does not count as a leak
+    let (_, results, count) = search();
+    free_search_results(results, count);
+
+    let ((found, count), net) = net_allocations(|| {
+        let (found, results, count) = search();
+        free_search_results(results, count);
+        (found, count)
+    });
+    assert_eq!((found, count), (1, 1000));
+    assert_eq!(net, 0);
+
+    // A count larger than the array and a second free are ignored
+    let (_, results, count) = search();
+    let (_, net) = net_allocations(|| {
+        free_search_results(results, count + 10);
+        free_search_results(results, count);
+    });
+    assert!(net <= 0);
+
+    // A name holding a NUL byte is returned with U+FFFD in its place, and freed like the rest
+    unsafe { (*index).add_document(report(1000, "Report\0bad")) };
+    let (_, results, count) = search();
+    free_search_results(results, count);
+    let ((found, count, replaced), net) = net_allocations(|| {
+        let (found, results, count) = search();
+        let replaced = (0..count)
+            .map(|i| unsafe { &*results.add(i) })
+            .find(|r| unsafe { CStr::from_ptr(r.node_id) }.to_bytes() == b"n1000")
+            .is_some_and(|r| unsafe { CStr::from_ptr(r.name) }.to_bytes() == "Report\u{FFFD}bad".as_bytes());
+        free_search_results(results, count);
+        (found, count, replaced)
+    });
+    assert_eq!((found, count), (1, 1001));
+    assert!(replaced);
+    assert_eq!(net, 0);
+
+    free_search_index(index);
+}
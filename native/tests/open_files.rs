@@ -0,0 +1,142 @@
+// Process-wide open file limit
+// The limit applies to every open in the process, so these tests run in their
+// own binary, one at a time: in the unit tests they would hold up every other
+// test that opens files.
+
+use std::ffi::CString;
+use std::fs;
+use std::ptr;
+use std::sync::mpsc;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cloud_nexus_encryption::{
+    chunked_copy_finalize, chunked_copy_free, chunked_copy_init, chunked_copy_open_source, chunked_copy_read_chunk,
+    chunked_copy_write_chunk, native_runtime_configure_ex, native_runtime_shutdown, native_runtime_stats_json,
+    transfer_queue_add_folder_copy, transfer_queue_create, transfer_queue_free, transfer_queue_pause,
+    transfer_queue_resume, TransferJobStatus, SUCCESS,
+};
+
+static LIMIT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Run alone and under a limit of two open files
+fn limit_to_two() -> MutexGuard<'static, ()> {
+    let guard = LIMIT_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    assert_eq!(native_runtime_shutdown(10_000), SUCCESS);
+    assert_eq!(native_runtime_configure_ex(4, 0, 2), SUCCESS);
+    assert_eq!(stats()["max_open_files"], 2);
+    guard
+}
+
+fn stats() -> serde_json::Value {
+    let mut len = 0usize;
+    let json = native_runtime_stats_json(&mut len);
+    let text = unsafe { CString::from_raw(json) }.into_string().unwrap();
+    let envelope: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(envelope["ok"], true);
+    envelope["data"].clone()
+}
+
+#[test]
+fn test_open_file_limit_caps_parallel_folder_copies() {
+    let _limit = limit_to_two();
+
+    let dir = std::env::temp_dir().join(format!("cloud_nexus_open_files_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    for folder in 0..4 {
+        let source = dir.join(format!("source_{}", folder));
+        fs::create_dir_all(&source).unwrap();
+        for file in 0..4 {
+            fs::write(source.join(format!("{}.bin", file)), vec![folder as u8; 64 * 1024]).unwrap();
+        }
+    }
+
+    // Four copies run at once, each wanting a source and a destination open
+    let queue = transfer_queue_create(4);
+    assert_eq!(transfer_queue_pause(queue), SUCCESS);
+    for folder in 0..4 {
+        let source = CString::new(dir.join(format!("source_{}", folder)).to_string_lossy().to_string()).unwrap();
+        let dest = CString::new(dir.join(format!("dest_{}", folder)).to_string_lossy().to_string()).unwrap();
+        assert_ne!(transfer_queue_add_folder_copy(queue, source.as_ptr(), dest.as_ptr(), 0), 0);
+    }
+    assert_eq!(transfer_queue_resume(queue), SUCCESS);
+
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while !unsafe { &*queue }.jobs().iter().all(|job| job.status == TransferJobStatus::Completed) {
+        assert!(unsafe { &*queue }.jobs().iter().all(|job| job.status != TransferJobStatus::Failed));
+        assert!(Instant::now() < deadline, "queue did not finish");
+        thread::sleep(Duration::from_millis(10));
+    }
+    transfer_queue_free(queue);
+
+    let usage = stats();
+    assert_eq!(usage["open_files"], 0);
+    assert_eq!(usage["peak_open_files"], 2);
+    for folder in 0..4 {
+        for file in 0..4 {
+            assert_eq!(fs::read(dir.join(format!("dest_{}/{}.bin", folder, file))).unwrap(),
+                       vec![folder as u8; 64 * 1024]);
+        }
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Copy `source` to `dest` in 64 KB chunks, telling `opened` once the source is open
+fn chunked_copy(source: &CString, dest: &CString, opened: mpsc::Sender<()>) -> i32 {
+    let ctx = chunked_copy_init(source.as_ptr(), dest.as_ptr(), 64 * 1024, ptr::null());
+    assert!(!ctx.is_null());
+    let mut status = chunked_copy_open_source(ctx);
+    let _ = opened.send(());
+    let mut buffer = vec![0u8; 64 * 1024];
+    while status == SUCCESS {
+        let n = chunked_copy_read_chunk(ctx, buffer.as_mut_ptr(), buffer.len(), None, ptr::null_mut());
+        if n <= 0 {
+            status = n as i32;
+            break;
+        }
+        // Give the other copy time to ask for its files while this one holds both
+        thread::sleep(Duration::from_millis(20));
+        status = chunked_copy_write_chunk(ctx, buffer.as_ptr(), n as usize, None, ptr::null_mut());
+    }
+    if status == SUCCESS {
+        status = chunked_copy_finalize(ctx, None, ptr::null_mut());
+    }
+    chunked_copy_free(ctx);
+    status
+}
+
+#[test]
+fn test_interleaved_chunked_copies_share_the_limit() {
+    let _limit = limit_to_two();
+
+    let dir = std::env::temp_dir().join(format!("cloud_nexus_open_files_chunked_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| CString::new(dir.join(name).to_string_lossy().to_string()).unwrap();
+    for (copy, name) in ["a.bin", "b.bin"].iter().enumerate() {
+        fs::write(dir.join(name), vec![copy as u8 + 1; 256 * 1024]).unwrap();
+    }
+
+    // The second copy opens its source while the first holds its source and
+    // destination, so it waits for both of the first copy's files instead of
+    // leaving each copy holding one file and waiting for the other
+    let started = Instant::now();
+    let (opened, first_open) = mpsc::channel();
+    let (first_source, first_dest) = (path("a.bin"), path("a.copy"));
+    let first = thread::spawn(move || chunked_copy(&first_source, &first_dest, opened));
+    first_open.recv().unwrap();
+    let (opened, _) = mpsc::channel();
+    assert_eq!(chunked_copy(&path("b.bin"), &path("b.copy"), opened), 0);
+    assert_eq!(first.join().unwrap(), 0);
+    assert!(started.elapsed() < Duration::from_secs(10), "copies waited {:?}", started.elapsed());
+
+    let usage = stats();
+    assert_eq!(usage["open_files"], 0);
+    assert_eq!(usage["peak_open_files"], 2);
+    assert_eq!(fs::read(dir.join("a.copy")).unwrap(), vec![1u8; 256 * 1024]);
+    assert_eq!(fs::read(dir.join("b.copy")).unwrap(), vec![2u8; 256 * 1024]);
+
+    let _ = fs::remove_dir_all(&dir);
+}